@@ -0,0 +1,245 @@
+//! Audit records of the tools which change the database.
+//!
+//! A tool run with --audit-file or --audit-db is recorded when it
+//! ends: the tool, its options, who ran it and where, its row counts
+//! from count(), how long it took and any error.  --audit-file appends
+//! the record as a JSON line, and --audit-db inserts it into
+//! egutil.audit_log, creating the table if needed.
+//!
+//! The database record is written on a connection of its own, after
+//! the run, so it is kept when a dry run rolls back, and is written
+//! for runs which fail.  Values of password options are not recorded.
+use crate::completion::{self, Argument};
+use crate::date::UtcTime;
+use crate::db::DatabaseConnection;
+use getopts::{Matches, Options};
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The run being audited, from start() until finish().
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+struct Run {
+    tool: String,
+    options: Value,
+    operator: String,
+    host: String,
+    dry_run: bool,
+    start_time: UtcTime,
+    start: Instant,
+    /// In the order first counted.
+    counts: Vec<(String, u64)>,
+    file: Option<String>,
+    database: Option<DatabaseConnection>,
+}
+
+/// Create egutil.audit_log if needed.
+pub fn create_table(con: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.audit_log (
+            id          BIGSERIAL PRIMARY KEY,
+            tool        TEXT NOT NULL,
+            options     JSONB NOT NULL,
+            operator    TEXT NOT NULL,
+            host        TEXT NOT NULL,
+            dry_run     BOOL NOT NULL,
+            counts      JSONB NOT NULL,
+            error       TEXT,
+            seconds     FLOAT8 NOT NULL,
+            start_time  TIMESTAMPTZ NOT NULL,
+            end_time    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        CREATE INDEX IF NOT EXISTS audit_log_tool_time_idx
+            ON egutil.audit_log (tool, start_time);
+    "#;
+
+    match con.client().batch_execute(sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot create egutil.audit_log: {e}")),
+    }
+}
+
+/// Start auditing this run, if --audit-file or --audit-db is set.
+/// Called by cli::parse_or_exit() for tools with the audit options.
+pub fn start(opts: &Options, params: &Matches, tool: &str) {
+    let file = params.opt_str("audit-file");
+    let database = match params.opt_present("audit-db") {
+        true => Some(DatabaseConnection::new_from_options(params)),
+        false => None,
+    };
+
+    if file.is_none() && database.is_none() {
+        return;
+    }
+
+    let run = Run {
+        tool: tool.to_string(),
+        options: options_json(opts, params),
+        operator: operator(),
+        host: fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .unwrap_or_else(|_| "localhost".to_string()),
+        dry_run: params.opt_defined("dry-run") && params.opt_present("dry-run"),
+        start_time: UtcTime::now(),
+        start: Instant::now(),
+        counts: Vec::new(),
+        file,
+        database,
+    };
+
+    *RUN.lock().unwrap() = Some(run);
+}
+
+/// Add to a row count for the audit record, e.g. count("deleted", 40).
+/// Does nothing when the run is not audited.
+pub fn count(name: &str, rows: u64) {
+    let mut run = RUN.lock().unwrap();
+
+    let counts = match run.as_mut() {
+        Some(r) => &mut r.counts,
+        None => return,
+    };
+
+    match counts.iter_mut().find(|(n, _)| n == name) {
+        Some((_, total)) => *total += rows,
+        None => counts.push((name.to_string(), rows)),
+    }
+}
+
+/// Write the audit record for the tool's result, and return it.
+///
+/// Failing to write the record fails a run which otherwise succeeded,
+/// so an unaudited change is not mistaken for an audited one.
+pub fn finish(result: Result<(), String>) -> Result<(), String> {
+    let run = match RUN.lock().unwrap().take() {
+        Some(r) => r,
+        None => return result,
+    };
+
+    let error = result.as_ref().err().map(|e| e.as_str());
+
+    let written = run.write(error);
+
+    match (result, written) {
+        (Ok(()), Err(e)) => Err(e),
+        (Err(e), Err(audit_error)) => {
+            error!("{audit_error}");
+            Err(e)
+        }
+        (result, Ok(())) => result,
+    }
+}
+
+impl Run {
+    fn write(mut self, error: Option<&str>) -> Result<(), String> {
+        let seconds = self.start.elapsed().as_secs_f64();
+
+        let counts: Map<String, Value> = self
+            .counts
+            .iter()
+            .map(|(name, rows)| (name.to_string(), json!(rows)))
+            .collect();
+        let counts = Value::Object(counts);
+
+        if let Some(path) = &self.file {
+            let line = json!({
+                "tool": self.tool,
+                "options": self.options,
+                "operator": self.operator,
+                "host": self.host,
+                "dry_run": self.dry_run,
+                "counts": counts,
+                "error": error,
+                "seconds": seconds,
+                "start_time": self.start_time.to_iso8601(),
+                "end_time": UtcTime::now().to_iso8601(),
+            });
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Cannot open audit file {path}: {e}"))?;
+
+            writeln!(file, "{line}").map_err(|e| format!("Cannot write audit file {path}: {e}"))?;
+        }
+
+        if let Some(mut con) = self.database.take() {
+            con.connect()?;
+            create_table(&mut con)?;
+
+            let sql = r#"
+                INSERT INTO egutil.audit_log
+                    (tool, options, operator, host, dry_run,
+                    counts, error, seconds, start_time)
+                VALUES ($1, $2::TEXT::JSONB, $3, $4, $5, $6::TEXT::JSONB, $7, $8::FLOAT8,
+                    NOW() - $8 * INTERVAL '1 second')
+            "#;
+
+            con.client()
+                .execute(
+                    sql,
+                    &[
+                        &self.tool,
+                        &self.options.to_string(),
+                        &self.operator,
+                        &self.host,
+                        &self.dry_run,
+                        &counts.to_string(),
+                        &error,
+                        &seconds,
+                    ],
+                )
+                .map_err(|e| format!("Cannot write egutil.audit_log: {e}"))?;
+
+            con.disconnect();
+        }
+
+        info!("Audited {} run in {seconds:.1}s", self.tool);
+
+        Ok(())
+    }
+}
+
+/// Options given, by long name, including those from a config file.
+/// Flags are true, or a count when repeatable.
+fn options_json(opts: &Options, params: &Matches) -> Value {
+    let mut options = Map::new();
+
+    for info in completion::describe(opts) {
+        let name = match info.long {
+            Some(n) => n,
+            None => continue,
+        };
+
+        if !params.opt_present(&name) {
+            continue;
+        }
+
+        let value = match info.argument {
+            Argument::None if info.multiple => json!(params.opt_count(&name)),
+            Argument::None => json!(true),
+            _ if name.contains("password") => json!("********"),
+            _ if info.multiple => json!(params.opt_strs(&name)),
+            _ => json!(params.opt_str(&name)),
+        };
+
+        options.insert(name, value);
+    }
+
+    Value::Object(options)
+}
+
+/// The login behind any sudo.
+fn operator() -> String {
+    ["SUDO_USER", "USER", "LOGNAME"]
+        .iter()
+        .find_map(|v| env::var(v).ok().filter(|u| !u.is_empty()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
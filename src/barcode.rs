@@ -0,0 +1,298 @@
+//! Barcode check digits and sequential barcode generation.
+use postgres as pg;
+use std::collections::HashSet;
+
+/// Code 39 character set, in mod 43 value order.
+const MOD43_CHARS: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ-. $/+%";
+
+/// Length of a 14-digit library Codabar barcode.
+const CODABAR_LENGTH: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckDigit {
+    /// Luhn mod 10 over any number of digits.
+    Mod10,
+    /// Code 39 mod 43.
+    Mod43,
+    /// 14-digit library Codabar: type digit (2 = patron, 3 = item),
+    /// 4-digit institution code, 8-digit sequence and a mod 10
+    /// check digit.
+    Codabar,
+}
+
+impl CheckDigit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "mod10" | "luhn" => Some(CheckDigit::Mod10),
+            "mod43" => Some(CheckDigit::Mod43),
+            "codabar" => Some(CheckDigit::Codabar),
+            _ => None,
+        }
+    }
+
+    /// Check digit for a barcode minus its check digit, or None if
+    /// the value contains characters invalid for the algorithm.
+    pub fn compute(&self, payload: &str) -> Option<char> {
+        match self {
+            CheckDigit::Mod10 | CheckDigit::Codabar => luhn(payload),
+            CheckDigit::Mod43 => mod43(payload),
+        }
+    }
+
+    /// True if the final character is the correct check digit.
+    pub fn validate(&self, barcode: &str) -> bool {
+        if *self == CheckDigit::Codabar
+            && (barcode.len() != CODABAR_LENGTH || !barcode.starts_with(['2', '3']))
+        {
+            return false;
+        }
+
+        let mut chars = barcode.chars();
+        let check = match chars.next_back() {
+            Some(c) => c,
+            None => return false,
+        };
+
+        let payload = chars.as_str();
+        !payload.is_empty() && self.compute(payload) == Some(check.to_ascii_uppercase())
+    }
+}
+
+fn luhn(payload: &str) -> Option<char> {
+    let mut sum = 0;
+
+    // Double every other digit, starting with the rightmost.
+    for (idx, c) in payload.chars().rev().enumerate() {
+        let mut d = c.to_digit(10)?;
+        if idx % 2 == 0 {
+            d *= 2;
+            if d > 9 {
+                d -= 9;
+            }
+        }
+        sum += d;
+    }
+
+    char::from_digit((10 - sum % 10) % 10, 10)
+}
+
+fn mod43(payload: &str) -> Option<char> {
+    let mut sum = 0;
+
+    for c in payload.chars() {
+        sum += MOD43_CHARS.find(c.to_ascii_uppercase())?;
+    }
+
+    MOD43_CHARS.chars().nth(sum % 43)
+}
+
+/// Where generated barcodes will be used.
+///
+/// Both tables are checked for collisions regardless, since a
+/// barcode shared by a card and an item is ambiguous at checkout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Patron,
+    Item,
+}
+
+impl Target {
+    /// Codabar type digit.
+    pub fn codabar_type(&self) -> char {
+        match self {
+            Target::Patron => '2',
+            Target::Item => '3',
+        }
+    }
+}
+
+/// Barcodes from the list already in use by a card or a
+/// non-deleted item.
+pub fn existing_barcodes<C: pg::GenericClient>(
+    client: &mut C,
+    barcodes: &[String],
+) -> Result<HashSet<String>, String> {
+    let sql = r#"
+        SELECT barcode FROM actor.card WHERE barcode = ANY($1)
+        UNION
+        SELECT barcode FROM asset.copy WHERE NOT deleted AND barcode = ANY($1)
+    "#;
+
+    let barcodes = barcodes.to_vec();
+    match client.query(sql, &[&barcodes]) {
+        Ok(rows) => Ok(rows.iter().map(|r| r.get("barcode")).collect()),
+        Err(e) => Err(format!("Error checking barcodes: {e}")),
+    }
+}
+
+/// Generates barcodes as PREFIX + zero-padded sequence + optional
+/// check digit.
+pub struct Generator {
+    prefix: String,
+    width: usize,
+    check: Option<CheckDigit>,
+    next: u64,
+}
+
+impl Generator {
+    /// For Codabar, the prefix is the type digit plus institution
+    /// code and must leave room for the sequence, e.g. "31234" with
+    /// a width of 8.
+    pub fn new(
+        prefix: &str,
+        width: usize,
+        check: Option<CheckDigit>,
+        start: u64,
+    ) -> Result<Self, String> {
+        if check == Some(CheckDigit::Codabar) && prefix.len() + width + 1 != CODABAR_LENGTH {
+            return Err(format!(
+                "Codabar barcodes are {CODABAR_LENGTH} digits; prefix {prefix} and width {width} do not fit"
+            ));
+        }
+
+        let generator = Generator {
+            prefix: prefix.to_string(),
+            width,
+            check,
+            next: start,
+        };
+
+        if generator.format(start).is_none() {
+            return Err(format!("Prefix {prefix} is invalid for {check:?}"));
+        }
+
+        Ok(generator)
+    }
+
+    /// Barcode for a sequence number, or None if the sequence does not
+    /// fit the width.
+    pub fn format(&self, seq: u64) -> Option<String> {
+        let digits = seq.to_string();
+        if digits.len() > self.width {
+            return None;
+        }
+
+        let mut barcode = format!("{}{digits:0>width$}", self.prefix, width = self.width);
+
+        if let Some(check) = self.check {
+            barcode.push(check.compute(&barcode)?);
+        }
+
+        Some(barcode)
+    }
+
+    /// Continue after the highest sequence number already in use for
+    /// this prefix and width.
+    pub fn resume<C: pg::GenericClient>(&mut self, client: &mut C) -> Result<u64, String> {
+        let length = (self.prefix.len() + self.width + self.check.map_or(0, |_| 1)) as i32;
+        let start = self.prefix.len() as i32 + 1;
+        let width = self.width as i32;
+
+        let sql = r#"
+            SELECT MAX(SUBSTRING(barcode, $2, $3)::NUMERIC)::TEXT AS seq
+            FROM (
+                SELECT barcode FROM actor.card
+                UNION ALL
+                SELECT barcode FROM asset.copy WHERE NOT deleted
+            ) b
+            WHERE LENGTH(barcode) = $4
+                AND STARTS_WITH(barcode, $1)
+                AND SUBSTRING(barcode, $2, $3) ~ '^[0-9]+$'
+        "#;
+
+        let rows = client
+            .query(sql, &[&self.prefix, &start, &width, &length])
+            .map_err(|e| format!("Error finding last barcode: {e}"))?;
+
+        let max: Option<String> = rows.first().and_then(|r| r.get("seq"));
+        if let Some(max) = max {
+            let max: u64 = max
+                .parse()
+                .map_err(|e| format!("Invalid sequence {max}: {e}"))?;
+            self.next = self.next.max(max + 1);
+        }
+
+        Ok(self.next)
+    }
+
+    /// Next `count` unused barcodes, skipping any already in use.
+    pub fn generate<C: pg::GenericClient>(
+        &mut self,
+        client: &mut C,
+        count: usize,
+    ) -> Result<Vec<String>, String> {
+        let mut barcodes = Vec::new();
+
+        while barcodes.len() < count {
+            let mut batch = Vec::new();
+
+            while batch.len() < count - barcodes.len() {
+                match self.format(self.next) {
+                    Some(b) => batch.push(b),
+                    None => return Err(format!("Barcode sequence exhausted at {}", self.next)),
+                }
+                self.next += 1;
+            }
+
+            let existing = existing_barcodes(client, &batch)?;
+            barcodes.extend(batch.into_iter().filter(|b| !existing.contains(b)));
+        }
+
+        Ok(barcodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_check_digits() {
+        assert_eq!(CheckDigit::Mod10.compute("7992739871"), Some('3'));
+        assert_eq!(CheckDigit::Mod43.compute("CODE39"), Some('W'));
+        assert_eq!(CheckDigit::Mod43.compute("ABC-123"), Some('W'));
+        assert_eq!(CheckDigit::Codabar.compute("3123400000001"), Some('6'));
+
+        assert_eq!(CheckDigit::Mod10.compute("12A4"), None);
+        assert_eq!(CheckDigit::Mod43.compute("AB*C"), None);
+    }
+
+    #[test]
+    fn validates_check_digits() {
+        assert!(CheckDigit::Mod10.validate("79927398713"));
+        assert!(!CheckDigit::Mod10.validate("79927398710"));
+        assert!(!CheckDigit::Mod10.validate("3"));
+
+        assert!(CheckDigit::Mod43.validate("CODE39W"));
+        assert!(CheckDigit::Mod43.validate("code39w"));
+        assert!(!CheckDigit::Mod43.validate("CODE39X"));
+
+        assert!(CheckDigit::Codabar.validate("31234000000016"));
+        assert!(CheckDigit::Codabar.validate("21234000012344"));
+        // Wrong check digit, length or type digit.
+        assert!(!CheckDigit::Codabar.validate("31234000000017"));
+        assert!(!CheckDigit::Codabar.validate("3123400000016"));
+        assert!(!CheckDigit::Codabar.validate("41234000000013"));
+    }
+
+    #[test]
+    fn check_digit_names() {
+        assert_eq!(CheckDigit::from_name("Luhn"), Some(CheckDigit::Mod10));
+        assert_eq!(CheckDigit::from_name("mod43"), Some(CheckDigit::Mod43));
+        assert_eq!(CheckDigit::from_name("CODABAR"), Some(CheckDigit::Codabar));
+        assert_eq!(CheckDigit::from_name("mod11"), None);
+    }
+
+    #[test]
+    fn formats_barcodes() {
+        let generator = Generator::new("31234", 8, Some(CheckDigit::Codabar), 1).unwrap();
+        assert_eq!(generator.format(1), Some("31234000000016".to_string()));
+        assert_eq!(generator.format(100_000_000), None);
+
+        let generator = Generator::new("BR", 4, None, 1).unwrap();
+        assert_eq!(generator.format(42), Some("BR0042".to_string()));
+
+        assert!(Generator::new("3123", 8, Some(CheckDigit::Codabar), 1).is_err());
+        assert!(Generator::new("BR", 4, Some(CheckDigit::Mod10), 1).is_err());
+    }
+}
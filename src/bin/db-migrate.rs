@@ -0,0 +1,3 @@
+fn main() -> Result<(), String> {
+    egutil::tools::db_migrate::main()
+}
@@ -0,0 +1,183 @@
+use egutil::cli;
+use egutil::completion::{self, Command, OptionInfo, Shell};
+use egutil::tools::{self, Tool};
+use serde_json::{json, Value};
+use std::env;
+use std::process;
+
+/// Commands of egutil itself, before the tools.
+const COMMANDS: &[(&str, &str)] = &[
+    ("completions", "Print a shell completion script"),
+    ("help", "Print a tool's help message"),
+    ("list", "List the tools"),
+];
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    egutil TOOL [OPTIONS]
+    egutil ingest --do-attrs --max-threads 8
+    egutil help marc-export
+    egutil list
+    egutil completions bash > /etc/bash_completion.d/egutil
+
+Runs any egutil tool as a subcommand.  Each tool takes the same
+options as its standalone binary, and reads the [TOOL] section of a
+--config file under its full name, e.g. [parallel-ingest] for ingest.
+
+Commands
+
+    TOOL
+        Run a tool by name or alias.
+
+    help TOOL
+        Print a tool's help message.
+
+    list
+        List the tools and their aliases.
+
+    completions bash|zsh|fish
+        Print a completion script for egutil, covering every tool's
+        options.
+
+Options
+
+    --help-json
+        Print the tools, their aliases and summaries as JSON.  Each
+        tool prints its options as JSON with egutil TOOL --help-json.
+
+    --help Print help message
+
+    "#
+    );
+}
+
+fn list() {
+    for tool in tools::TOOLS {
+        let name = match tool.aliases {
+            [] => tool.name.to_string(),
+            aliases => format!("{} ({})", tool.name, aliases.join(", ")),
+        };
+        println!("{name:<32}{}", tool.summary);
+    }
+}
+
+fn help_json() {
+    let tools: Vec<Value> = tools::TOOLS
+        .iter()
+        .map(|t| {
+            json!({
+                "name": t.name,
+                "aliases": t.aliases,
+                "summary": t.summary,
+            })
+        })
+        .collect();
+
+    println!("{}", json!({"tool": "egutil", "tools": tools}));
+}
+
+/// Each tool's options, from running it with --help-json.
+fn completions(shell: &str) -> Result<(), String> {
+    let shell = match Shell::from_name(shell) {
+        Some(s) => s,
+        None => return Err(format!("Invalid shell: {shell}")),
+    };
+
+    let exe = env::current_exe().map_err(|e| format!("Cannot find egutil executable: {e}"))?;
+
+    let mut commands = Vec::new();
+
+    for tool in tools::TOOLS {
+        let output = process::Command::new(&exe)
+            .args([tool.name, "--help-json"])
+            .output()
+            .map_err(|e| format!("Cannot run {}: {e}", tool.name))?;
+
+        let value: Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| format!("Invalid --help-json from {}: {e}", tool.name))?;
+
+        let options = match value["options"].as_array() {
+            Some(list) => list.iter().map(OptionInfo::from_json).collect(),
+            None => Vec::new(),
+        };
+
+        commands.push(Command {
+            name: tool.name.to_string(),
+            aliases: tool.aliases.iter().map(|a| a.to_string()).collect(),
+            summary: tool.summary.to_string(),
+            options,
+        });
+    }
+
+    print!(
+        "{}",
+        completion::subcommand_script(shell, "egutil", COMMANDS, &commands)
+    );
+
+    Ok(())
+}
+
+fn find(name: &str) -> &'static Tool {
+    match tools::find(name) {
+        Some(t) => t,
+        None => {
+            eprintln!("No such tool: {name}\nSee egutil list for the tools.");
+            process::exit(2);
+        }
+    }
+}
+
+fn main() -> Result<(), String> {
+    let mut args = env::args().skip(1);
+
+    let tool = match args.next().as_deref() {
+        None => {
+            print_help();
+            process::exit(2);
+        }
+        Some("-h" | "--help") => {
+            print_help();
+            return Ok(());
+        }
+        Some("--help-json") => {
+            help_json();
+            return Ok(());
+        }
+        Some("list") => {
+            list();
+            return Ok(());
+        }
+        Some("completions") => match args.next() {
+            Some(shell) => return completions(&shell),
+            None => {
+                eprintln!("completions requires a shell: bash, zsh or fish");
+                process::exit(2);
+            }
+        },
+        Some("help") => match args.next() {
+            Some(name) => {
+                let tool = find(&name);
+                cli::set_command_line(vec![tool.name.to_string(), "--help".to_string()]);
+                return (tool.run)();
+            }
+            None => {
+                print_help();
+                return Ok(());
+            }
+        },
+        Some(name) => find(name),
+    };
+
+    // The tool name as argv[0] selects its config file section and
+    // log name.
+    let mut command_line = vec![tool.name.to_string()];
+    command_line.extend(args);
+
+    cli::set_command_line(command_line);
+
+    (tool.run)()
+}
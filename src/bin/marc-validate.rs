@@ -0,0 +1,3 @@
+fn main() -> Result<(), String> {
+    egutil::tools::marc_validate::main()
+}
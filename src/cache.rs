@@ -0,0 +1,321 @@
+//! Evergreen's memcached (or Redis) cache.
+//!
+//! OpenSRF caches values as JSON, e.g. session data and org unit
+//! settings.  Tools which change the database under a live system can
+//! delete stale entries here so the change takes effect before the
+//! entries expire.
+//!
+//! With several memcached servers, keys are assigned to servers as
+//! Perl's Cache::Memcached does, so both see the same entries.
+//!
+//! Requires the cache feature.
+use flate2::Crc;
+use getopts::{Matches, Options};
+use log::debug;
+use serde_json::Value;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// memcached's key length limit.
+const MAX_KEY_LENGTH: usize = 250;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Server {
+    Memcached(String),
+    Redis(String),
+}
+
+impl Server {
+    /// HOST:PORT for memcached, or redis://HOST:PORT.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (redis, addr) = match value.strip_prefix("redis://") {
+            Some(a) => (true, a),
+            None => (false, value.strip_prefix("memcached://").unwrap_or(value)),
+        };
+
+        let addr = addr.trim_end_matches('/');
+
+        if addr.is_empty() {
+            return Err(format!("Invalid cache server: {value}"));
+        }
+
+        let addr = match addr.contains(':') {
+            true => addr.to_string(),
+            false if redis => format!("{addr}:6379"),
+            false => format!("{addr}:11211"),
+        };
+
+        match redis {
+            true => Ok(Server::Redis(addr)),
+            false => Ok(Server::Memcached(addr)),
+        }
+    }
+
+    fn addr(&self) -> &str {
+        match self {
+            Server::Memcached(a) | Server::Redis(a) => a,
+        }
+    }
+}
+
+/// Add --cache-server.
+pub fn append_options(opts: &mut Options) {
+    opts.optmulti("", "cache-server", "Evergreen Cache Server", "HOST:PORT");
+}
+
+/// Cache from --cache-server, or None when unset.
+pub fn from_options(params: &Matches) -> Result<Option<Cache>, String> {
+    let servers = params.opt_strs("cache-server");
+
+    if servers.is_empty() {
+        return Ok(None);
+    }
+
+    let servers = servers
+        .iter()
+        .map(|s| Server::parse(s))
+        .collect::<Result<Vec<Server>, String>>()?;
+
+    Ok(Some(Cache::new(servers)))
+}
+
+/// Connects per request, since tools touch the cache rarely.
+pub struct Cache {
+    servers: Vec<Server>,
+}
+
+impl Cache {
+    pub fn new(servers: Vec<Server>) -> Self {
+        Cache { servers }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Value>, String> {
+        let key = clean_key(key);
+        let server = self.server(&key)?;
+
+        let mut con = Connection::open(server)?;
+
+        let data = match server {
+            Server::Memcached(_) => {
+                con.send(format!("get {key}\r\n").as_bytes())?;
+                con.read_memcached_value()?
+            }
+            Server::Redis(_) => {
+                con.send(&redis_command(&["GET", &key]))?;
+                con.read_redis_bulk()?
+            }
+        };
+
+        match data {
+            Some(d) => match serde_json::from_slice(&d) {
+                Ok(v) => Ok(Some(v)),
+                Err(e) => Err(format!("Cached value for {key} is not JSON: {e}")),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Store a value, expiring after ttl seconds, or never for 0.
+    pub fn set(&self, key: &str, value: &Value, ttl: u64) -> Result<(), String> {
+        let key = clean_key(key);
+        let server = self.server(&key)?;
+        let data = value.to_string();
+
+        let mut con = Connection::open(server)?;
+
+        let reply = match server {
+            Server::Memcached(_) => {
+                let mut cmd = format!("set {key} 0 {ttl} {}\r\n", data.len()).into_bytes();
+                cmd.extend(data.as_bytes());
+                cmd.extend(b"\r\n");
+                con.send(&cmd)?;
+                con.read_line()?
+            }
+            Server::Redis(_) => {
+                let cmd = match ttl {
+                    0 => redis_command(&["SET", &key, &data]),
+                    _ => redis_command(&["SET", &key, &data, "EX", &ttl.to_string()]),
+                };
+                con.send(&cmd)?;
+                con.read_line()?
+            }
+        };
+
+        match reply.as_str() {
+            "STORED" | "+OK" => Ok(()),
+            _ => Err(format!("Cannot cache {key}: {reply}")),
+        }
+    }
+
+    /// Returns true if the key was cached.
+    pub fn delete(&self, key: &str) -> Result<bool, String> {
+        let key = clean_key(key);
+        let server = self.server(&key)?;
+
+        let mut con = Connection::open(server)?;
+
+        let reply = match server {
+            Server::Memcached(_) => {
+                con.send(format!("delete {key}\r\n").as_bytes())?;
+                con.read_line()?
+            }
+            Server::Redis(_) => {
+                con.send(&redis_command(&["DEL", &key]))?;
+                con.read_line()?
+            }
+        };
+
+        debug!("Cache delete {key}: {reply}");
+
+        match reply.as_str() {
+            "DELETED" | ":1" => Ok(true),
+            "NOT_FOUND" | ":0" => Ok(false),
+            _ => Err(format!("Cannot delete {key}: {reply}")),
+        }
+    }
+
+    /// Delete several keys, e.g. after a bulk change.  Returns the
+    /// number which were cached.
+    pub fn delete_all(&self, keys: &[String]) -> Result<usize, String> {
+        let mut deleted = 0;
+        for key in keys {
+            if self.delete(key)? {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Server for a key, using Cache::Memcached's CRC32 hashing.
+    fn server(&self, key: &str) -> Result<&Server, String> {
+        if self.servers.is_empty() {
+            return Err("No cache servers configured".to_string());
+        }
+
+        let mut crc = Crc::new();
+        crc.update(key.as_bytes());
+        let hash = ((crc.sum() >> 16) & 0x7fff) as usize;
+
+        Ok(&self.servers[hash % self.servers.len()])
+    }
+}
+
+/// memcached keys may not contain whitespace or control characters.
+/// Overlong keys are truncated.
+fn clean_key(key: &str) -> String {
+    let mut key: String = key
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect();
+
+    while key.len() > MAX_KEY_LENGTH {
+        key.pop();
+    }
+
+    key
+}
+
+fn redis_command(args: &[&str]) -> Vec<u8> {
+    let mut cmd = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        cmd.extend(format!("${}\r\n", arg.len()).as_bytes());
+        cmd.extend(arg.as_bytes());
+        cmd.extend(b"\r\n");
+    }
+    cmd
+}
+
+struct Connection {
+    addr: String,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(server: &Server) -> Result<Self, String> {
+        let addr = server.addr().to_string();
+
+        let stream = match TcpStream::connect(&addr) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot connect to cache at {addr}: {e}")),
+        };
+
+        stream.set_read_timeout(Some(TIMEOUT)).ok();
+        stream.set_write_timeout(Some(TIMEOUT)).ok();
+
+        Ok(Connection {
+            addr,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    fn err(&self, e: std::io::Error) -> String {
+        format!("Cache error at {}: {e}", self.addr)
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let result = self.reader.get_mut().write_all(bytes);
+        result.map_err(|e| self.err(e))
+    }
+
+    /// One reply line minus its CRLF.
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err(format!("Cache at {} closed the connection", self.addr)),
+            Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => Err(self.err(e)),
+        }
+    }
+
+    fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        // Plus the trailing CRLF.
+        let mut data = vec![0; len + 2];
+        if let Err(e) = self.reader.read_exact(&mut data) {
+            return Err(self.err(e));
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// VALUE <key> <flags> <bytes> ... END
+    fn read_memcached_value(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let line = self.read_line()?;
+
+        if line == "END" {
+            return Ok(None);
+        }
+
+        let len = match line.split(' ').collect::<Vec<&str>>()[..] {
+            ["VALUE", _, _, len, ..] => len.parse().ok(),
+            _ => None,
+        };
+
+        let len = match len {
+            Some(l) => l,
+            None => return Err(format!("Unexpected cache reply: {line}")),
+        };
+
+        let data = self.read_exact(len)?;
+        self.read_line()?; // END
+
+        Ok(Some(data))
+    }
+
+    /// $<bytes> ... or $-1 for no value.
+    fn read_redis_bulk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let line = self.read_line()?;
+
+        if line == "$-1" {
+            return Ok(None);
+        }
+
+        match line.strip_prefix('$').and_then(|l| l.parse().ok()) {
+            Some(len) => Ok(Some(self.read_exact(len)?)),
+            None => Err(format!("Unexpected cache reply: {line}")),
+        }
+    }
+}
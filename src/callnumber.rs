@@ -0,0 +1,483 @@
+//! Call number parsing and shelf-order sort keys.
+//!
+//! Sort keys compare correctly as plain strings, e.g. with ORDER BY on
+//! a TEXT column using the C collation.
+
+/// Width numbers are zero-padded to in sort keys.
+const NUMBER_WIDTH: usize = 6;
+
+/// Call number classification scheme.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    Generic,
+    Dewey,
+    Lc,
+    Sudoc,
+}
+
+impl Scheme {
+    /// Scheme for an asset.call_number_class normalizer function name,
+    /// e.g. asset.label_normalizer_lc
+    pub fn from_normalizer(normalizer: Option<&str>) -> Self {
+        match normalizer {
+            Some(n) if n.ends_with("_lc") => Scheme::Lc,
+            Some(n) if n.ends_with("_dewey") => Scheme::Dewey,
+            Some(n) if n.ends_with("_sudoc") => Scheme::Sudoc,
+            _ => Scheme::Generic,
+        }
+    }
+
+    /// Scheme from a name like "lc", "dewey", "sudoc" or "generic".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "generic" => Some(Scheme::Generic),
+            "dewey" | "ddc" => Some(Scheme::Dewey),
+            "lc" | "lcc" => Some(Scheme::Lc),
+            "sudoc" => Some(Scheme::Sudoc),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scheme::Generic => "generic",
+            Scheme::Dewey => "dewey",
+            Scheme::Lc => "lc",
+            Scheme::Sudoc => "sudoc",
+        }
+    }
+
+    /// Sort key for a call number label.  Labels which do not parse
+    /// under the scheme get a generic sort key.
+    pub fn sortkey(&self, label: &str) -> String {
+        let key = match self {
+            Scheme::Lc => LcCallNumber::parse(label).map(|c| c.sortkey()),
+            Scheme::Dewey => DeweyCallNumber::parse(label).map(|c| c.sortkey()),
+            Scheme::Sudoc => SudocCallNumber::parse(label).map(|c| c.sortkey()),
+            Scheme::Generic => None,
+        };
+
+        key.unwrap_or_else(|| generic_sortkey(label))
+    }
+}
+
+/// Library of Congress call number, e.g. QA76.73.R87 B34 2005
+#[derive(Debug)]
+pub struct LcCallNumber {
+    /// Class letters, e.g. QA
+    pub class_letters: String,
+    /// Class number, e.g. 76.73
+    pub class_number: String,
+    /// Cutters without their leading period, e.g. R87 and B34
+    pub cutters: Vec<String>,
+    /// Anything after the cutters, e.g. dates and volumes.
+    pub remainder: Vec<String>,
+}
+
+impl LcCallNumber {
+    pub fn parse(label: &str) -> Option<Self> {
+        let label = label.trim().to_uppercase();
+
+        let letters: String = label
+            .chars()
+            .take_while(|c| c.is_ascii_alphabetic())
+            .collect();
+
+        if letters.is_empty() || letters.len() > 3 {
+            return None;
+        }
+
+        let rest = label[letters.len()..].trim_start();
+        let (class_number, rest) = split_number(rest)?;
+
+        let mut cutters = Vec::new();
+        let mut remainder = Vec::new();
+
+        for token in rest.split_whitespace() {
+            for part in split_cutters(token) {
+                let cutter = part.strip_prefix('.').unwrap_or(&part);
+                if remainder.is_empty() && is_cutter(cutter) {
+                    cutters.push(cutter.to_string());
+                } else {
+                    remainder.push(part);
+                }
+            }
+        }
+
+        Some(LcCallNumber {
+            class_letters: letters,
+            class_number,
+            cutters,
+            remainder,
+        })
+    }
+
+    /// Cutter numbers are decimal values so they sort as written.
+    ///
+    /// ```
+    /// use egutil::callnumber::LcCallNumber;
+    ///
+    /// let lc = LcCallNumber::parse("QA76.73.R87 B34 2005").unwrap();
+    /// assert_eq!(lc.sortkey(), "QA 0076.73 R87 B34 002005");
+    /// ```
+    pub fn sortkey(&self) -> String {
+        let mut key = format!(
+            "{:<3}{}",
+            self.class_letters,
+            pad_class_number(&self.class_number, 4)
+        );
+
+        for cutter in &self.cutters {
+            key += " ";
+            key += cutter;
+        }
+
+        for part in &self.remainder {
+            key += " ";
+            key += &pad_numbers(part);
+        }
+
+        key
+    }
+}
+
+/// Dewey Decimal call number, e.g. 813.54 SMI v.2
+#[derive(Debug)]
+pub struct DeweyCallNumber {
+    /// Class number, e.g. 813.54
+    pub class_number: String,
+    /// Anything after the class number, e.g. cutters and volumes.
+    pub remainder: Vec<String>,
+}
+
+impl DeweyCallNumber {
+    pub fn parse(label: &str) -> Option<Self> {
+        let label = label.trim().to_uppercase();
+        let (class_number, rest) = split_number(&label)?;
+
+        if class_number.split('.').next().unwrap_or("").len() > 3 {
+            return None;
+        }
+
+        Some(DeweyCallNumber {
+            class_number,
+            remainder: rest.split_whitespace().map(|s| s.to_string()).collect(),
+        })
+    }
+
+    /// E.g. "813.54 SMI V.000002"
+    pub fn sortkey(&self) -> String {
+        let mut key = pad_class_number(&self.class_number, 3);
+
+        for part in &self.remainder {
+            key += " ";
+            key += &pad_numbers(part);
+        }
+
+        key
+    }
+}
+
+/// Component of a SuDoc call number.
+#[derive(Debug, PartialEq)]
+pub enum SudocToken {
+    Alpha(String),
+    Number(String),
+    /// One of . / - :
+    Separator(char),
+}
+
+/// Superintendent of Documents call number, e.g. Y 4.F 76/1:AF 8/2
+#[derive(Debug)]
+pub struct SudocCallNumber {
+    pub tokens: Vec<SudocToken>,
+}
+
+impl SudocCallNumber {
+    pub fn parse(label: &str) -> Option<Self> {
+        let label = label.trim().to_uppercase();
+
+        // Author symbols start with agency letters.
+        if !label.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
+        }
+
+        let mut tokens = Vec::new();
+        let mut current = String::new();
+
+        let flush = |current: &mut String, tokens: &mut Vec<SudocToken>| {
+            if current.is_empty() {
+                return;
+            }
+            let value = std::mem::take(current);
+            match value.starts_with(|c: char| c.is_ascii_digit()) {
+                true => tokens.push(SudocToken::Number(value)),
+                false => tokens.push(SudocToken::Alpha(value)),
+            }
+        };
+
+        for c in label.chars() {
+            if c.is_ascii_alphanumeric() {
+                let same_kind = current
+                    .chars()
+                    .last()
+                    .is_none_or(|p| p.is_ascii_digit() == c.is_ascii_digit());
+                if !same_kind {
+                    flush(&mut current, &mut tokens);
+                }
+                current.push(c);
+            } else {
+                flush(&mut current, &mut tokens);
+                if matches!(c, '.' | '/' | '-' | ':') {
+                    tokens.push(SudocToken::Separator(c));
+                }
+            }
+        }
+
+        flush(&mut current, &mut tokens);
+
+        if !tokens.iter().any(|t| matches!(t, SudocToken::Number(_))) {
+            return None;
+        }
+
+        Some(SudocCallNumber { tokens })
+    }
+
+    /// Separators sort by their place in the hierarchy, so e.g.
+    /// Y 4.F 76:AF sorts before Y 4.F 76/1:AF
+    pub fn sortkey(&self) -> String {
+        let mut key = String::new();
+
+        for token in &self.tokens {
+            match token {
+                SudocToken::Alpha(s) => {
+                    if !key.is_empty() && !key.ends_with(['!', '#', '%', '&']) {
+                        key += " ";
+                    }
+                    key += s;
+                }
+                SudocToken::Number(n) => {
+                    if !key.is_empty() && !key.ends_with(['!', '#', '%', '&']) {
+                        key += " ";
+                    }
+                    key += &format!("{:0>NUMBER_WIDTH$}", n.trim_start_matches('0'));
+                }
+                SudocToken::Separator(c) => {
+                    key.push(match c {
+                        ':' => '!',
+                        '.' => '#',
+                        '/' => '%',
+                        _ => '&',
+                    });
+                }
+            }
+        }
+
+        key
+    }
+}
+
+/// Uppercase with whitespace collapsed and numbers zero-padded, so
+/// e.g. V.2 sorts before V.10
+pub fn generic_sortkey(label: &str) -> String {
+    label
+        .split_whitespace()
+        .map(|s| pad_numbers(&s.to_uppercase()))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Split a leading number with an optional decimal part from the
+/// rest of the string, e.g. "76.73.R87" => ("76.73", ".R87")
+fn split_number(s: &str) -> Option<(String, &str)> {
+    let int_len = s.chars().take_while(|c| c.is_ascii_digit()).count();
+    if int_len == 0 {
+        return None;
+    }
+
+    let mut len = int_len;
+    let rest = &s[int_len..];
+
+    if let Some(dec) = rest.strip_prefix('.') {
+        let dec_len = dec.chars().take_while(|c| c.is_ascii_digit()).count();
+        if dec_len > 0 {
+            len += 1 + dec_len;
+        }
+    }
+
+    Some((s[..len].to_string(), &s[len..]))
+}
+
+/// Split a token before each period followed by a letter, e.g.
+/// ".R87.B34" => [".R87", ".B34"]
+fn split_cutters(token: &str) -> Vec<String> {
+    let chars: Vec<char> = token.chars().collect();
+    let mut parts = Vec::new();
+    let mut current = String::new();
+
+    for (idx, c) in chars.iter().enumerate() {
+        let starts_cutter =
+            *c == '.' && chars.get(idx + 1).is_some_and(|n| n.is_ascii_alphabetic());
+
+        if starts_cutter && !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+        }
+
+        current.push(*c);
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+/// A letter followed by digits, e.g. R87
+fn is_cutter(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && !chars.as_str().is_empty()
+        && chars.all(|c| c.is_ascii_digit())
+}
+
+/// Zero-pad the integer part of a class number, e.g. 76.73 => 0076.73
+fn pad_class_number(number: &str, width: usize) -> String {
+    match number.split_once('.') {
+        Some((int, dec)) => format!("{int:0>width$}.{dec}"),
+        None => format!("{number:0>width$}"),
+    }
+}
+
+/// Zero-pad each run of digits, e.g. V.2 => V.000002
+fn pad_numbers(s: &str) -> String {
+    let mut out = String::new();
+    let mut digits = String::new();
+
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        if !digits.is_empty() {
+            out += &format!("{:0>NUMBER_WIDTH$}", std::mem::take(&mut digits));
+        }
+        out.push(c);
+    }
+
+    if !digits.is_empty() {
+        out += &format!("{digits:0>NUMBER_WIDTH$}");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_sorted(scheme: Scheme, labels: &[&str]) {
+        let keys: Vec<String> = labels.iter().map(|l| scheme.sortkey(l)).collect();
+        for pair in keys.windows(2) {
+            assert!(
+                pair[0] < pair[1],
+                "{:?} should sort before {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    #[test]
+    fn lc_sortkeys() {
+        let lc = LcCallNumber::parse("qa76.73.R87 B34 2005").unwrap();
+        assert_eq!(lc.class_letters, "QA");
+        assert_eq!(lc.class_number, "76.73");
+        assert_eq!(lc.cutters, vec!["R87", "B34"]);
+        assert_eq!(lc.remainder, vec!["2005"]);
+        assert_eq!(lc.sortkey(), "QA 0076.73 R87 B34 002005");
+
+        assert_sorted(
+            Scheme::Lc,
+            &[
+                "Q 180 .A1",
+                "QA9 .B2",
+                "QA76.73 .R87",
+                "QA76.9 .A43",
+                "QA76.9 .A43 2001",
+                "QA76.9 .A43 2010",
+                "QB 1",
+            ],
+        );
+
+        assert!(LcCallNumber::parse("ABCD 12").is_none());
+        assert!(LcCallNumber::parse("QA .R87").is_none());
+    }
+
+    #[test]
+    fn dewey_sortkeys() {
+        let ddc = DeweyCallNumber::parse("813.54 Smi v.2").unwrap();
+        assert_eq!(ddc.sortkey(), "813.54 SMI V.000002");
+        assert_eq!(Scheme::Dewey.sortkey("92 SMI"), "092 SMI");
+
+        assert_sorted(
+            Scheme::Dewey,
+            &[
+                "92 SMI",
+                "813.5 ABC",
+                "813.54 SMI v.2",
+                "813.54 SMI v.10",
+                "813.6 ABC",
+            ],
+        );
+
+        assert!(DeweyCallNumber::parse("1234 ABC").is_none());
+    }
+
+    #[test]
+    fn sudoc_sortkeys() {
+        assert_eq!(
+            Scheme::Sudoc.sortkey("Y 4.F 76/1:AF 8/2"),
+            "Y 000004#F 000076%000001!AF 000008%000002"
+        );
+
+        assert_sorted(
+            Scheme::Sudoc,
+            &[
+                "Y 4.F 76:AF",
+                "Y 4.F 76/1:AF",
+                "Y 4.F 76/10:AF",
+                "Y 4.G 1:AF",
+            ],
+        );
+
+        assert!(SudocCallNumber::parse("4.F 76").is_none());
+        assert!(SudocCallNumber::parse("Y AF").is_none());
+    }
+
+    #[test]
+    fn generic_sortkeys() {
+        assert_eq!(generic_sortkey(" fic  smith v.10 "), "FIC SMITH V.000010");
+        assert_sorted(Scheme::Generic, &["FIC SMITH v.2", "FIC SMITH v.10"]);
+
+        // Labels which do not parse under their scheme sort generically.
+        assert_eq!(Scheme::Lc.sortkey("123 ABC"), "000123 ABC");
+        assert_eq!(Scheme::Sudoc.sortkey("Y AF"), "Y AF");
+    }
+
+    #[test]
+    fn scheme_names() {
+        assert_eq!(
+            Scheme::from_normalizer(Some("asset.label_normalizer_lc")),
+            Scheme::Lc
+        );
+        assert_eq!(
+            Scheme::from_normalizer(Some("asset.label_normalizer_dewey")),
+            Scheme::Dewey
+        );
+        assert_eq!(Scheme::from_normalizer(None), Scheme::Generic);
+        assert_eq!(Scheme::from_name("LCC"), Some(Scheme::Lc));
+        assert_eq!(Scheme::from_name("ddc").map(|s| s.name()), Some("dewey"));
+        assert_eq!(Scheme::from_name("nlm"), None);
+    }
+}
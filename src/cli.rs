@@ -0,0 +1,382 @@
+//! Command line handling shared by the egutil binaries.
+use crate::audit;
+use crate::completion;
+use crate::conf::{self, Config};
+use crate::daemon;
+use crate::db::DatabaseConnection;
+use crate::log;
+use crate::tools;
+use ::log::LevelFilter;
+use getopts::{Matches, Options};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::{env, process};
+
+/// Arguments given by the egutil binary in place of the process's own.
+static COMMAND_LINE: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Parse these arguments, starting with the tool name, in place of
+/// the process's own, as when a tool runs as an egutil subcommand.
+/// Only the first call applies.
+pub fn set_command_line(args: Vec<String>) {
+    let _ = COMMAND_LINE.set(args);
+}
+
+fn command_line() -> Vec<String> {
+    match COMMAND_LINE.get() {
+        Some(args) => args.clone(),
+        None => env::args().collect(),
+    }
+}
+
+/// Options with --help, --config and the logging options.
+pub fn options() -> Options {
+    let mut opts = Options::new();
+
+    opts.optflag("h", "help", "Help");
+    opts.optflag("", "help-json", "Print Options as JSON");
+    opts.optopt(
+        "",
+        "completions",
+        "Print Shell Completion Script",
+        "bash|zsh|fish",
+    );
+    opts.optflagmulti("v", "verbose", "Log More Detail, Repeatable");
+    opts.optflag("q", "quiet", "Log Errors Only");
+    opts.optopt("", "config", "TOML Config File", "FILE");
+    opts.optopt("", "log-level", "Log Level or Filter", "LEVEL");
+    opts.optopt("", "log-file", "Log File or syslog[:FACILITY]", "FILE");
+    opts.optopt("", "log-format", "Log Line Format", "text|json");
+    #[cfg(feature = "metrics")]
+    opts.optopt(
+        "",
+        "metrics-listen",
+        "Serve Metrics on Address",
+        "HOST:PORT",
+    );
+
+    opts
+}
+
+/// Help for the options every tool has, after options(), for the end
+/// of each tool's print_help(), e.g.
+///
+/// ```text
+/// println!(r#"... Options ... {}"#, cli::COMMON_HELP);
+/// ```
+pub const COMMON_HELP: &str = r#"    --config
+        TOML config file of option values, with a [database]
+        section and a section named for the tool.  Defaults to the
+        EGUTIL_CONFIG environment variable.
+
+    --verbose, -v
+        Log more detail.  Repeatable.
+
+    --quiet, -q
+        Log errors only.
+
+    --log-level
+        Log level, or a RUST_LOG style filter such as
+        warn,egutil=debug.  Overrides --verbose and --quiet.
+
+    --log-file
+        Append log lines to this file, or send them to syslog with
+        syslog or syslog:FACILITY, e.g. syslog:local0.
+
+    --log-format
+        text or json.  Defaults to text.
+
+    --help-json
+        Print this tool's options as JSON, for wrapper scripts.
+
+    --completions
+        Print a bash, zsh or fish completion script.
+
+    --help Print help message
+"#;
+
+/// Help for append_dry_run(), placed like COMMON_HELP.  Tools whose
+/// dry run needs more explaining write their own.
+pub const DRY_RUN_HELP: &str = r#"    --dry-run
+        Make the changes, then roll them back, reporting what a real
+        run would do.
+"#;
+
+/// Help for append_audit().
+pub const AUDIT_HELP: &str = r#"    --audit-file
+        Append a JSON record of this run, its options and its row
+        counts to this file.
+
+    --audit-db
+        Record this run, its options and its row counts in
+        egutil.audit_log.
+"#;
+
+/// Help for append_lockfile().
+pub const LOCKFILE_HELP: &str = r#"    --lockfile
+        Exit if another run holds this lock file, e.g. when a cron
+        job outlasts its interval.  Stale locks are replaced.
+"#;
+
+/// Common options plus the --db-* connection options.
+pub fn database_options() -> Options {
+    let mut opts = options();
+    DatabaseConnection::append_options(&mut opts);
+    opts
+}
+
+/// Add --min-id and --max-id.  Callers choose their own defaults.
+pub fn append_id_range(opts: &mut Options) {
+    opts.optopt("", "min-id", "Minimum Record ID", "MIN_REC_ID");
+    opts.optopt("", "max-id", "Maximum Record ID", "MAX_REC_ID");
+}
+
+/// Add --lockfile.  Callers hold lockfile::acquire() for the run.
+pub fn append_lockfile(opts: &mut Options) {
+    opts.optopt(
+        "",
+        "lockfile",
+        "Exit If Another Run Holds This Lock",
+        "FILE",
+    );
+}
+
+/// Add --dry-run.  Callers read it with DryRun::from_params().
+pub fn append_dry_run(opts: &mut Options) {
+    opts.optflag("", "dry-run", "Roll Back All Changes");
+}
+
+/// Add --snapshot and --snapshot-file.  Callers read them with
+/// Snapshot::from_params().
+pub fn append_snapshot(opts: &mut Options) {
+    opts.optflag("", "snapshot", "Save Changed Rows for Rollback");
+    opts.optopt(
+        "",
+        "snapshot-file",
+        "Save Changed Rows for Rollback to File",
+        "FILE",
+    );
+}
+
+/// Add --audit-file and --audit-db.  parse_or_exit() starts the
+/// audit, and callers end it with audit::finish().
+pub fn append_audit(opts: &mut Options) {
+    opts.optopt("", "audit-file", "Append Audit Record to File", "FILE");
+    opts.optflag("", "audit-db", "Record Run in egutil.audit_log");
+}
+
+/// Parse the command line, merged over any config file, initialize
+/// logging, start the daemon for binaries with daemon options, start
+/// any metrics endpoint and start the audit for binaries with audit
+/// options.
+///
+/// Exits with status 2 on invalid options and with status 0 after
+/// calling print_help() for --help.
+pub fn parse_or_exit(opts: &Options, print_help: impl Fn()) -> Matches {
+    let args = command_line();
+
+    let params = match opts.parse(&args[1..]) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Error processing options: {e}\nSee --help for usage.");
+            process::exit(2);
+        }
+    };
+
+    if params.opt_present("help") {
+        print_help();
+        process::exit(0);
+    }
+
+    if params.opt_present("help-json") || params.opt_present("completions") {
+        describe_and_exit(opts, &params, &tool_name(&args[0]));
+    }
+
+    let params = match apply_config(opts, &args, params) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(2);
+        }
+    };
+
+    let tool = tool_name(&args[0]);
+
+    if let Err(e) = init_logging(&params, &tool) {
+        eprintln!("{e}");
+        process::exit(2);
+    }
+
+    // Before any threads start.
+    if params.opt_defined("detach") {
+        if let Err(e) = daemon::start(&params) {
+            eprintln!("{e}");
+            process::exit(2);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(address) = params.opt_str("metrics-listen") {
+        if let Err(e) = crate::metrics::serve_in_background(&address) {
+            eprintln!("{e}");
+            process::exit(2);
+        }
+    }
+
+    if params.opt_defined("audit-file") {
+        audit::start(opts, &params, &tool);
+    }
+
+    params
+}
+
+/// Value of an option the tool cannot run without.  Prints a usage
+/// error and exits with status 2 when it is missing or invalid.
+pub fn required<T>(params: &Matches, name: &str) -> T
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match params.opt_get::<T>(name) {
+        Ok(Some(value)) => value,
+        Ok(None) => {
+            eprintln!("--{name} is required");
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("Invalid --{name}: {e}");
+            process::exit(2);
+        }
+    }
+}
+
+/// Print --help-json or --completions output and exit.
+fn describe_and_exit(opts: &Options, params: &Matches, tool: &str) -> ! {
+    let options = completion::describe(opts);
+
+    if let Some(name) = params.opt_str("completions") {
+        match completion::Shell::from_name(&name) {
+            Some(shell) => print!("{}", completion::script(shell, tool, &options)),
+            None => {
+                eprintln!("Invalid --completions shell: {name}");
+                process::exit(2);
+            }
+        }
+    } else {
+        let summary = tools::find(tool).map(|t| t.summary);
+        println!("{}", completion::help_json(tool, summary, &options));
+    }
+
+    process::exit(0);
+}
+
+/// Binary name, e.g. parallel-ingest.
+fn tool_name(arg0: &str) -> String {
+    Path::new(arg0)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Re-parse with values from the --config or EGUTIL_CONFIG file
+/// placed ahead of the command line.
+///
+/// The tool's section wins over [database], and the command line
+/// wins over both.
+fn apply_config(opts: &Options, args: &[String], params: Matches) -> Result<Matches, String> {
+    let path = match params
+        .opt_str("config")
+        .or_else(|| env::var(conf::CONFIG_ENV).ok())
+    {
+        Some(p) => p,
+        None => return Ok(params),
+    };
+
+    let config = Config::load(&path)?;
+
+    let tool = tool_name(&args[0]);
+
+    let mut sections = vec![tool.as_str()];
+    if params.opt_defined("db-host") {
+        sections.push(conf::DATABASE_SECTION);
+    }
+
+    let mut merged = Vec::new();
+    let mut seen = HashSet::new();
+
+    for name in sections {
+        let section = match config.section(name) {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let options: Vec<String> = section.keys().map(|k| k.replace('_', "-")).collect();
+
+        for option in &options {
+            if !params.opt_defined(option) {
+                return Err(format!(
+                    "Unknown option {option} in [{name}] of {}",
+                    config.path()
+                ));
+            }
+        }
+
+        merged.extend(config.section_args(name, |o| params.opt_present(o) || seen.contains(o))?);
+
+        seen.extend(options);
+    }
+
+    if merged.is_empty() {
+        return Ok(params);
+    }
+
+    merged.extend(args[1..].iter().cloned());
+
+    match opts.parse(&merged) {
+        Ok(p) => Ok(p),
+        Err(e) => Err(format!(
+            "Error processing options from {}: {e}",
+            config.path()
+        )),
+    }
+}
+
+/// --log-level wins, then --quiet, which logs errors only, and
+/// each --verbose, which adds a level above warnings.  Without any,
+/// RUST_LOG applies, defaulting to errors only.
+fn init_logging(params: &Matches, tool: &str) -> Result<(), String> {
+    let level = match (params.opt_present("quiet"), params.opt_count("verbose")) {
+        (true, _) => Some(LevelFilter::Error),
+        (false, 0) => None,
+        (false, 1) => Some(LevelFilter::Info),
+        (false, 2) => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    };
+
+    let filter = match (params.opt_str("log-level"), level) {
+        (Some(spec), _) => log::Filter::parse(&spec)?,
+        (None, Some(level)) => log::Filter::new(level),
+        (None, None) => match env::var("RUST_LOG") {
+            Ok(spec) => log::Filter::parse(&spec)?,
+            Err(_) => log::Filter::new(LevelFilter::Error),
+        },
+    };
+
+    let format = match params.opt_str("log-format") {
+        Some(name) => match log::Format::from_name(&name) {
+            Some(f) => f,
+            None => return Err(format!("Invalid --log-format: {name}")),
+        },
+        None => log::Format::Text,
+    };
+
+    let destination = match params.opt_str("log-file") {
+        Some(spec) => log::Destination::from_spec(&spec)?,
+        None => log::Destination::Stderr,
+    };
+
+    log::init(tool, filter, format, &destination)
+}
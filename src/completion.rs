@@ -0,0 +1,455 @@
+//! Machine-readable option descriptions and shell completion scripts.
+//!
+//! getopts keeps its option list private, so options are read back
+//! from its usage text: short_usage() gives each option's argument and
+//! occurrence, and usage() its names and description, both in the
+//! order the options were added.  Hints never contain spaces.
+use getopts::Options;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Argument {
+    None,
+    Required,
+    Optional,
+}
+
+impl Argument {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Argument::None => "none",
+            Argument::Required => "required",
+            Argument::Optional => "optional",
+        }
+    }
+
+    fn from_name(name: &str) -> Argument {
+        match name {
+            "required" => Argument::Required,
+            "optional" => Argument::Optional,
+            _ => Argument::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OptionInfo {
+    pub short: Option<String>,
+    pub long: Option<String>,
+    pub hint: Option<String>,
+    pub argument: Argument,
+    /// May be given more than once.
+    pub multiple: bool,
+    pub description: String,
+}
+
+impl OptionInfo {
+    /// Fixed values from a hint such as text|json.
+    pub fn choices(&self) -> Vec<&str> {
+        match self.hint.as_deref() {
+            Some(h) if h.contains('|') => h.split('|').collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "short": self.short,
+            "long": self.long,
+            "hint": self.hint,
+            "argument": self.argument.as_str(),
+            "multiple": self.multiple,
+            "description": self.description,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> OptionInfo {
+        let text = |key: &str| value[key].as_str().map(|s| s.to_string());
+
+        OptionInfo {
+            short: text("short"),
+            long: text("long"),
+            hint: text("hint"),
+            argument: Argument::from_name(value["argument"].as_str().unwrap_or("")),
+            multiple: value["multiple"].as_bool().unwrap_or(false),
+            description: text("description").unwrap_or_default(),
+        }
+    }
+
+    /// Names as given on the command line, e.g. -v and --verbose.
+    fn flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(s) = &self.short {
+            flags.push(format!("-{s}"));
+        }
+        if let Some(l) = &self.long {
+            flags.push(format!("--{l}"));
+        }
+        flags
+    }
+}
+
+/// The options, in the order they were added.
+pub fn describe(opts: &Options) -> Vec<OptionInfo> {
+    let mut options = Vec::new();
+
+    // "Usage: x [-h] [--config FILE] [-v].. [--flag [HINT]]"
+    for token in opts.short_usage("x").split_whitespace().skip(2) {
+        let name = token.trim_start_matches('[');
+
+        if name.starts_with('-') {
+            options.push(OptionInfo {
+                short: None,
+                long: None,
+                hint: None,
+                argument: Argument::None,
+                multiple: false,
+                description: String::new(),
+            });
+        }
+
+        let option = match options.last_mut() {
+            Some(o) => o,
+            None => continue,
+        };
+
+        if token.ends_with("..") {
+            option.multiple = true;
+        }
+
+        if !name.starts_with('-') {
+            option.argument = match token.starts_with('[') {
+                true => Argument::Optional,
+                false => Argument::Required,
+            };
+            option.hint = Some(
+                token
+                    .trim_matches(|c| c == '[' || c == ']' || c == '.')
+                    .to_string(),
+            );
+        }
+    }
+
+    // One row per option, wrapped descriptions indented 24 spaces.
+    let mut rows: Vec<String> = Vec::new();
+    for line in opts.usage("").lines() {
+        let text = line.trim();
+        if text.is_empty() || text == "Options:" {
+            continue;
+        }
+
+        if line.starts_with(&" ".repeat(24)) {
+            if let Some(row) = rows.last_mut() {
+                row.push(' ');
+                row.push_str(text);
+            }
+        } else {
+            rows.push(text.to_string());
+        }
+    }
+
+    for (option, row) in options.iter_mut().zip(rows.iter()) {
+        let mut tokens = row.split_whitespace().peekable();
+
+        if let Some(t) = tokens.next_if(|t| !t.starts_with("--")) {
+            option.short = Some(t.trim_start_matches('-').trim_end_matches(',').to_string());
+        }
+
+        if let Some(t) = tokens.next_if(|t| t.starts_with("--")) {
+            option.long = Some(t.trim_start_matches('-').to_string());
+        }
+
+        if option.argument != Argument::None {
+            tokens.next();
+        }
+
+        option.description = tokens.collect::<Vec<&str>>().join(" ");
+    }
+
+    options
+}
+
+/// --help-json output.
+pub fn help_json(tool: &str, summary: Option<&str>, options: &[OptionInfo]) -> Value {
+    json!({
+        "tool": tool,
+        "summary": summary,
+        "options": options.iter().map(|o| o.to_json()).collect::<Vec<Value>>(),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn from_name(name: &str) -> Option<Shell> {
+        match name {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// A subcommand of a multi-tool program, with its options.
+pub struct Command {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub summary: String,
+    pub options: Vec<OptionInfo>,
+}
+
+impl Command {
+    fn names(&self) -> Vec<&str> {
+        let mut names = vec![self.name.as_str()];
+        names.extend(self.aliases.iter().map(|a| a.as_str()));
+        names
+    }
+}
+
+/// Completion script for a single tool.
+pub fn script(shell: Shell, tool: &str, options: &[OptionInfo]) -> String {
+    let func = function_name(tool, None);
+
+    match shell {
+        Shell::Bash => format!(
+            "{}\ncomplete -F {func} {tool}\n",
+            bash_function(&func, options)
+        ),
+        Shell::Zsh => format!(
+            "#compdef {tool}\n\n{func}() {{\n{}}}\n\n{func} \"$@\"\n",
+            zsh_arguments(options)
+        ),
+        Shell::Fish => fish_lines(tool, None, options),
+    }
+}
+
+/// Completion script for a program taking a subcommand, e.g. egutil,
+/// completing subcommand names and then each subcommand's options.
+/// extra are further words accepted as the subcommand.
+pub fn subcommand_script(
+    shell: Shell,
+    program: &str,
+    extra: &[(&str, &str)],
+    commands: &[Command],
+) -> String {
+    let mut names: Vec<(&str, &str)> = extra.to_vec();
+    for command in commands {
+        for name in command.names() {
+            names.push((name, command.summary.as_str()));
+        }
+    }
+
+    let words: Vec<&str> = names.iter().map(|(n, _)| *n).collect();
+    let words = words.join(" ");
+
+    let mut script = String::new();
+
+    match shell {
+        Shell::Bash => {
+            for command in commands {
+                let func = function_name(program, Some(&command.name));
+                script += &bash_function(&func, &command.options);
+                script += "\n";
+            }
+
+            script += &format!(
+                "{}() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n\n    \
+                 if [ \"$COMP_CWORD\" -eq 1 ]; then\n        \
+                 COMPREPLY=($(compgen -W \"{words}\" -- \"$cur\"))\n        \
+                 return\n    fi\n\n    case \"${{COMP_WORDS[1]}}\" in\n",
+                function_name(program, None)
+            );
+
+            for command in commands {
+                script += &format!(
+                    "        {}) {} ;;\n",
+                    command.names().join("|"),
+                    function_name(program, Some(&command.name))
+                );
+            }
+
+            script += &format!(
+                "    esac\n}}\ncomplete -F {} {program}\n",
+                function_name(program, None)
+            );
+        }
+        Shell::Zsh => {
+            script += &format!("#compdef {program}\n\n");
+
+            for command in commands {
+                script += &format!(
+                    "{}() {{\n{}}}\n\n",
+                    function_name(program, Some(&command.name)),
+                    zsh_arguments(&command.options)
+                );
+            }
+
+            let func = function_name(program, None);
+            script += &format!("{func}() {{\n    if (( CURRENT == 2 )); then\n        local -a commands\n        commands=(\n");
+            for (name, summary) in &names {
+                script += &format!("            {}\n", quote(&format!("{name}:{summary}")));
+            }
+            script +=
+                "        )\n        _describe command commands\n        return\n    fi\n\n    \
+                       local command=$words[2]\n    shift words\n    (( CURRENT-- ))\n\n    \
+                       case $command in\n";
+
+            for command in commands {
+                script += &format!(
+                    "        {}) {} ;;\n",
+                    command.names().join("|"),
+                    function_name(program, Some(&command.name))
+                );
+            }
+
+            script += &format!("    esac\n}}\n\n{func} \"$@\"\n");
+        }
+        Shell::Fish => {
+            for (name, summary) in &names {
+                script += &format!(
+                    "complete -c {program} -n __fish_use_subcommand -f -a {name} -d {}\n",
+                    quote(summary)
+                );
+            }
+
+            for command in commands {
+                script += &fish_lines(program, Some(&command.names()), &command.options);
+            }
+        }
+    }
+
+    script
+}
+
+/// e.g. _egutil_parallel_ingest
+fn function_name(program: &str, command: Option<&str>) -> String {
+    let name = match command {
+        Some(c) => format!("_{program}_{c}"),
+        None => format!("_{program}"),
+    };
+    name.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+/// Single-quoted for the shell.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Options taking a value complete its choices, or file names.
+fn bash_function(func: &str, options: &[OptionInfo]) -> String {
+    let mut body = format!(
+        "{func}() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    \
+         local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n\n    case \"$prev\" in\n"
+    );
+
+    for option in options.iter().filter(|o| o.argument == Argument::Required) {
+        let reply = match option.choices() {
+            c if c.is_empty() => "$(compgen -f -- \"$cur\")".to_string(),
+            c => format!("$(compgen -W \"{}\" -- \"$cur\")", c.join(" ")),
+        };
+
+        body += &format!(
+            "        {})\n            COMPREPLY=({reply})\n            return\n            ;;\n",
+            option.flags().join("|")
+        );
+    }
+
+    let words: Vec<String> = options.iter().flat_map(|o| o.flags()).collect();
+
+    body += &format!(
+        "    esac\n\n    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n}}\n",
+        words.join(" ")
+    );
+
+    body
+}
+
+fn zsh_arguments(options: &[OptionInfo]) -> String {
+    let mut body = String::from("    _arguments -s \\\n");
+
+    for option in options {
+        let description = option
+            .description
+            .replace('\\', r"\\")
+            .replace('[', r"\[")
+            .replace(']', r"\]")
+            .replace(':', r"\:");
+
+        let action = match option.argument {
+            Argument::None => String::new(),
+            _ => {
+                let hint = option
+                    .hint
+                    .as_deref()
+                    .unwrap_or("VALUE")
+                    .replace(':', r"\:");
+                let optional = match option.argument {
+                    Argument::Optional => ":",
+                    _ => "",
+                };
+                match option.choices() {
+                    c if c.is_empty() => format!(":{optional}{hint}:_files"),
+                    c => format!(":{optional}{hint}:({})", c.join(" ")),
+                }
+            }
+        };
+
+        // Both names as an unquoted brace expansion, each excluding
+        // the other unless repeatable, e.g. '(-q --quiet)'{-q,--quiet}'[...]'
+        let flags = option.flags();
+        let rest = quote(&format!("[{description}]{action}"));
+        let spec = match (flags.len(), option.multiple) {
+            (1, true) => format!("'*'{}{rest}", flags[0]),
+            (1, false) => format!("{}{rest}", flags[0]),
+            (_, true) => format!("'*'{{{}}}{rest}", flags.join(",")),
+            (_, false) => format!("'({})'{{{}}}{rest}", flags.join(" "), flags.join(",")),
+        };
+
+        body += &format!("        {spec} \\\n");
+    }
+
+    body += "        '*:file:_files'\n";
+    body
+}
+
+/// complete lines, limited to a subcommand's names when given.
+fn fish_lines(program: &str, command: Option<&[&str]>, options: &[OptionInfo]) -> String {
+    let condition = match command {
+        Some(names) => format!(
+            " -n {}",
+            quote(&format!("__fish_seen_subcommand_from {}", names.join(" ")))
+        ),
+        None => String::new(),
+    };
+
+    let mut lines = String::new();
+
+    for option in options {
+        let mut line = format!("complete -c {program}{condition}");
+
+        if let Some(s) = &option.short {
+            line += &format!(" -s {s}");
+        }
+        if let Some(l) = &option.long {
+            line += &format!(" -l {l}");
+        }
+
+        if option.argument == Argument::Required {
+            match option.choices() {
+                c if c.is_empty() => line += " -r -F",
+                c => line += &format!(" -x -a {}", quote(&c.join(" "))),
+            }
+        }
+
+        line += &format!(" -d {}\n", quote(&option.description));
+        lines += &line;
+    }
+
+    lines
+}
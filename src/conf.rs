@@ -0,0 +1,105 @@
+//! TOML config files holding command line options.
+//!
+//! A config file has a [database] section shared by all tools and
+//! one section per tool, named for the binary, e.g.
+//!
+//! ```text
+//! [database]
+//! db-host = "db.example.org"
+//!
+//! [parallel-ingest]
+//! max-threads = 8
+//! do-search = true
+//! ```
+//!
+//! Keys are long option names.  Values given on the command line
+//! take precedence.
+use std::fs;
+use toml::{Table, Value};
+
+/// Environment variable naming the config file when --config is unset.
+pub const CONFIG_ENV: &str = "EGUTIL_CONFIG";
+
+/// Section applied to every tool which has the --db-* options.
+pub const DATABASE_SECTION: &str = "database";
+
+pub struct Config {
+    path: String,
+    table: Table,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Cannot read {path}: {e}")),
+        };
+
+        match toml::from_str::<Table>(&text) {
+            Ok(table) => Ok(Config {
+                path: path.to_string(),
+                table,
+            }),
+            Err(e) => Err(format!("Cannot parse {path}: {e}")),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Table> {
+        self.table.get(name).and_then(|v| v.as_table())
+    }
+
+    /// Command line arguments for the values in a section, e.g.
+    /// --max-threads=8.  Underscores in keys are read as dashes.
+    ///
+    /// Options for which skip() returns true are left out, so the
+    /// command line wins.  True booleans become flags and arrays
+    /// repeat the option.
+    pub fn section_args(
+        &self,
+        name: &str,
+        skip: impl Fn(&str) -> bool,
+    ) -> Result<Vec<String>, String> {
+        let mut args = Vec::new();
+
+        let section = match self.section(name) {
+            Some(s) => s,
+            None => return Ok(args),
+        };
+
+        for (key, value) in section {
+            let option = key.replace('_', "-");
+
+            if skip(&option) {
+                continue;
+            }
+
+            let values = match value {
+                Value::Array(list) => list.iter().collect(),
+                _ => vec![value],
+            };
+
+            for value in values {
+                match value {
+                    Value::Boolean(true) => args.push(format!("--{option}")),
+                    Value::Boolean(false) => {}
+                    Value::String(s) => args.push(format!("--{option}={s}")),
+                    Value::Integer(i) => args.push(format!("--{option}={i}")),
+                    Value::Float(f) => args.push(format!("--{option}={f}")),
+                    Value::Datetime(d) => args.push(format!("--{option}={d}")),
+                    Value::Array(_) | Value::Table(_) => {
+                        return Err(format!(
+                            "Unsupported value for {key} in [{name}] of {}",
+                            self.path
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(args)
+    }
+}
@@ -0,0 +1,150 @@
+//! Running binaries as system services.
+//!
+//! With --detach the process forks into the background, starts a new
+//! session and points stdin, stdout and stderr at /dev/null, so logs
+//! belong in --log-file or syslog.  The working directory is kept, so
+//! relative paths in options still resolve.
+//!
+//! --pidfile records the PID, refusing to start while another instance
+//! is running.  Once started, SIGHUP re-executes the binary with its
+//! original arguments, keeping the PID, e.g. to pick up an upgraded
+//! binary or config file.
+//!
+//! SIGINT and SIGTERM are left to the binary, which stops once its
+//! Shutdown shows the request.  The pidfile is removed when the
+//! process exits.
+use crate::lockfile::{self, Lock};
+use crate::signals::{self, Shutdown};
+use getopts::{Matches, Options};
+use log::{error, info};
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{env, io, thread};
+
+/// Set for a re-executed daemon, which is already detached.
+const REEXEC_ENV: &str = "EGUTIL_DAEMON_REEXEC";
+
+/// How often the watcher thread checks for signals.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Held until the process exits, see remove_pidfile().
+static PIDFILE: Mutex<Option<Lock>> = Mutex::new(None);
+
+/// Add --detach and --pidfile.  cli::parse_or_exit() calls start()
+/// for binaries which have them.
+pub fn append_options(opts: &mut Options) {
+    opts.optflag("", "detach", "Run in the Background");
+    opts.optopt("", "pidfile", "PID File", "FILE");
+}
+
+/// Detach if requested, write any pidfile and start the thread which
+/// handles SIGHUP.
+///
+/// Must run before any other threads start, since only the calling
+/// thread survives the fork.
+pub fn start(params: &Matches) -> Result<(), String> {
+    let reexec = env::var_os(REEXEC_ENV).is_some();
+    env::remove_var(REEXEC_ENV);
+
+    if params.opt_present("detach") && !reexec {
+        detach()?;
+    }
+
+    if let Some(path) = params.opt_str("pidfile") {
+        *PIDFILE.lock().unwrap() = Some(lockfile::acquire(&path)?);
+
+        // SAFETY: remove_pidfile() only takes a lock and drops it.
+        if unsafe { libc::atexit(remove_pidfile) } != 0 {
+            return Err("Cannot register pidfile removal".to_string());
+        }
+    }
+
+    let shutdown = signals::install()?;
+
+    let result = thread::Builder::new()
+        .name("daemon".to_string())
+        .spawn(move || watch(shutdown));
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot start signal thread: {e}")),
+    }
+}
+
+fn detach() -> Result<(), String> {
+    let null = match OpenOptions::new().read(true).write(true).open("/dev/null") {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Cannot open /dev/null: {e}")),
+    };
+
+    // SAFETY: no other threads exist yet, see start().
+    match unsafe { libc::fork() } {
+        -1 => return Err(format!("Cannot fork: {}", io::Error::last_os_error())),
+        0 => {}
+        // The parent leaves without running destructors or flushing
+        // anything the child also owns.
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(format!(
+            "Cannot start session: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(format!(
+                "Cannot redirect fd {fd}: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the pidfile however the process exits, short of a crash or
+/// a forced exit on a second signal, which leave a stale one.
+extern "C" fn remove_pidfile() {
+    if let Ok(mut lock) = PIDFILE.lock() {
+        lock.take();
+    }
+}
+
+/// Re-execute on SIGHUP until shutdown is requested.
+fn watch(shutdown: Shutdown) {
+    while !shutdown.requested() {
+        if shutdown.take_hangup() {
+            info!("Received SIGHUP; restarting");
+            reexec();
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Replace the process image, returning only on failure.
+///
+/// argv[0] is used over the current executable so an upgraded binary
+/// at the same path is picked up.
+fn reexec() {
+    let mut args = env::args_os();
+
+    let program = match args.next() {
+        Some(p) => p,
+        None => return,
+    };
+
+    let e = Command::new(&program)
+        .args(args)
+        .env(REEXEC_ENV, "1")
+        .exec();
+
+    error!("Cannot re-execute {}: {e}", program.to_string_lossy());
+}
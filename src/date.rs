@@ -0,0 +1,616 @@
+//! Evergreen timestamps and Postgres intervals.
+//!
+//! Evergreen times are timestamps with time zones, written as ISO 8601
+//! with a numeric offset, e.g. 2024-03-05T14:22:01-0500, or in the
+//! Postgres text form, e.g. 2024-03-05 14:22:01.123-05.  Intervals are
+//! the Postgres strings found in Evergreen settings and rules, e.g.
+//! "2 weeks" or "1 day 02:00:00".
+use regex::Regex;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TIMESTAMP_PATTERN: &str = r"^(\d{4})-(\d{2})-(\d{2})(?:[T ](\d{2}):(\d{2})(?::(\d{2})(?:\.\d+)?)?)?\s*(Z|[+-]\d{2}(?::?\d{2})?)?$";
+
+const MONTH_NAMES: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Starting from Sunday.
+const DAY_NAMES: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// Days since 1970-01-01 for a civil date.
+/// See http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Broken-down UTC time.
+pub struct UtcTime {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: i64,
+    pub minute: i64,
+    pub second: i64,
+}
+
+impl UtcTime {
+    pub fn now() -> Self {
+        UtcTime::from_epoch(epoch_now())
+    }
+
+    pub fn from_epoch(secs: i64) -> Self {
+        let days = secs.div_euclid(86400);
+        let rem = secs.rem_euclid(86400);
+
+        // Civil-from-days; see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        UtcTime {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: (rem % 3600) / 60,
+            second: rem % 60,
+        }
+    }
+
+    pub fn to_epoch(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * 86400
+            + self.hour * 3600
+            + self.minute * 60
+            + self.second
+    }
+
+    /// ISO 8601 format, e.g. 2022-11-01T14:03:55Z
+    pub fn to_iso8601(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn epoch_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// A point in time with the UTC offset it was written in.
+///
+/// Fractional seconds are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    epoch: i64,
+    /// Seconds east of UTC.
+    offset: i64,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp::from_epoch(epoch_now(), 0)
+    }
+
+    pub fn from_epoch(epoch: i64, offset: i64) -> Self {
+        Timestamp { epoch, offset }
+    }
+
+    /// Parse an ISO 8601 or Postgres timestamp.  Times without an
+    /// offset, or dates without a time, are taken as UTC.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let re = Regex::new(TIMESTAMP_PATTERN).unwrap();
+
+        let caps = match re.captures(value.trim()) {
+            Some(c) => c,
+            None => return Err(format!("Invalid timestamp: {value}")),
+        };
+
+        let num = |idx: usize| -> i64 {
+            caps.get(idx)
+                .and_then(|m| m.as_str().parse().ok())
+                .unwrap_or(0)
+        };
+
+        let (year, month, day) = (num(1), num(2), num(3));
+        let (hour, minute, second) = (num(4), num(5), num(6));
+
+        if !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return Err(format!("Invalid timestamp: {value}"));
+        }
+
+        let offset = match caps.get(7).map(|m| m.as_str()) {
+            None | Some("Z") => 0,
+            Some(zone) => {
+                let digits: String = zone[1..].chars().filter(|c| *c != ':').collect();
+                let hours: i64 = digits[..2].parse().unwrap_or(0);
+                let minutes: i64 = digits.get(2..).and_then(|m| m.parse().ok()).unwrap_or(0);
+                let secs = hours * 3600 + minutes * 60;
+                if zone.starts_with('-') {
+                    -secs
+                } else {
+                    secs
+                }
+            }
+        };
+
+        let local = UtcTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        };
+
+        Ok(Timestamp {
+            epoch: local.to_epoch() - offset,
+            offset,
+        })
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn epoch(&self) -> i64 {
+        self.epoch
+    }
+
+    /// Seconds east of UTC.
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    /// The same instant as seen from another offset.
+    pub fn with_offset(&self, offset: i64) -> Self {
+        Timestamp {
+            epoch: self.epoch,
+            offset,
+        }
+    }
+
+    /// Broken-down time at the timestamp's offset.
+    pub fn local(&self) -> UtcTime {
+        UtcTime::from_epoch(self.epoch + self.offset)
+    }
+
+    pub fn to_utc(&self) -> UtcTime {
+        UtcTime::from_epoch(self.epoch)
+    }
+
+    /// Evergreen's format, e.g. 2024-03-05T14:22:01-0500
+    pub fn to_iso8601(&self) -> String {
+        let t = self.local();
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{sign}{:02}{:02}",
+            t.year,
+            t.month,
+            t.day,
+            t.hour,
+            t.minute,
+            t.second,
+            offset / 3600,
+            (offset % 3600) / 60
+        )
+    }
+
+    /// strftime style formatting at the timestamp's offset, e.g.
+    /// "%B %e, %Y" for "March  5, 2024".  Supports %Y %y %m %d %e %H
+    /// %I %M %S %p %b %B %a %A %z and %%.  Other sequences are kept as
+    /// is.
+    pub fn format(&self, pattern: &str) -> String {
+        let t = self.local();
+        let weekday = (self.epoch + self.offset).div_euclid(86400) + 4;
+        let weekday = weekday.rem_euclid(7) as usize;
+        let month = (t.month - 1) as usize;
+        let hour12 = match t.hour % 12 {
+            0 => 12,
+            h => h,
+        };
+
+        let mut out = String::new();
+        let mut chars = pattern.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+
+            match chars.next() {
+                Some('Y') => out += &format!("{:04}", t.year),
+                Some('y') => out += &format!("{:02}", t.year.rem_euclid(100)),
+                Some('m') => out += &format!("{:02}", t.month),
+                Some('d') => out += &format!("{:02}", t.day),
+                Some('e') => out += &format!("{:2}", t.day),
+                Some('H') => out += &format!("{:02}", t.hour),
+                Some('I') => out += &format!("{:02}", hour12),
+                Some('M') => out += &format!("{:02}", t.minute),
+                Some('S') => out += &format!("{:02}", t.second),
+                Some('p') => out += if t.hour < 12 { "AM" } else { "PM" },
+                Some('b') => out += &MONTH_NAMES[month][..3],
+                Some('B') => out += MONTH_NAMES[month],
+                Some('a') => out += &DAY_NAMES[weekday][..3],
+                Some('A') => out += DAY_NAMES[weekday],
+                Some('z') => {
+                    let sign = if self.offset < 0 { '-' } else { '+' };
+                    let offset = self.offset.abs();
+                    out += &format!("{sign}{:02}{:02}", offset / 3600, (offset % 3600) / 60);
+                }
+                Some('%') => out.push('%'),
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
+            }
+        }
+
+        out
+    }
+
+    /// Add an interval as Postgres does: months move the calendar
+    /// date, clamped to the end of shorter months, then days, then
+    /// seconds.  Calendar steps use the timestamp's offset.
+    pub fn add(&self, interval: &Interval) -> Self {
+        let mut t = self.local();
+
+        let months = t.year * 12 + (t.month - 1) + interval.months;
+        t.year = months.div_euclid(12);
+        t.month = months.rem_euclid(12) + 1;
+        t.day = t.day.min(days_in_month(t.year, t.month));
+
+        let local = t.to_epoch() + interval.days * 86400 + interval.seconds;
+
+        Timestamp {
+            epoch: local - self.offset,
+            offset: self.offset,
+        }
+    }
+
+    pub fn sub(&self, interval: &Interval) -> Self {
+        self.add(&interval.negate())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_iso8601())
+    }
+}
+
+/// A Postgres interval, kept as months, days and seconds the way
+/// Postgres does, since neither months nor days have a fixed length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Interval {
+    pub months: i64,
+    pub days: i64,
+    pub seconds: i64,
+}
+
+impl Interval {
+    /// Parse a Postgres interval, e.g. "2 weeks", "1 year 3 mons",
+    /// "1 day 02:30:00", "-1 hour" or "3 days ago".  A bare number
+    /// is seconds.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid interval: {value}");
+
+        let mut tokens: Vec<&str> = value
+            .trim()
+            .trim_start_matches('@')
+            .split_whitespace()
+            .collect();
+
+        let ago = tokens.last() == Some(&"ago");
+        if ago {
+            tokens.pop();
+        }
+
+        if tokens.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut interval = Interval::default();
+        let mut iter = tokens.into_iter().peekable();
+
+        while let Some(token) = iter.next() {
+            if token.contains(':') {
+                interval.seconds += parse_clock(token).ok_or_else(invalid)?;
+                continue;
+            }
+
+            let number: f64 = token.parse().map_err(|_| invalid())?;
+
+            let unit = match iter.peek() {
+                Some(u) if u.parse::<f64>().is_err() && !u.contains(':') => {
+                    iter.next().unwrap().to_lowercase()
+                }
+                _ => "seconds".to_string(),
+            };
+
+            let unit = unit.trim_end_matches(',');
+
+            // Whole months go in months, fractions of months in days,
+            // and fractions of days in seconds, as Postgres does.
+            let (months, days, seconds) = match unit {
+                "millennium" | "millennia" | "millenniums" => (number * 12000.0, 0.0, 0.0),
+                "century" | "centuries" => (number * 1200.0, 0.0, 0.0),
+                "decade" | "decades" => (number * 120.0, 0.0, 0.0),
+                "y" | "yr" | "yrs" | "year" | "years" => (number * 12.0, 0.0, 0.0),
+                "mon" | "mons" | "month" | "months" => (number, 0.0, 0.0),
+                "w" | "week" | "weeks" => (0.0, number * 7.0, 0.0),
+                "d" | "day" | "days" => (0.0, number, 0.0),
+                "h" | "hr" | "hrs" | "hour" | "hours" => (0.0, 0.0, number * 3600.0),
+                "m" | "min" | "mins" | "minute" | "minutes" => (0.0, 0.0, number * 60.0),
+                "s" | "sec" | "secs" | "second" | "seconds" => (0.0, 0.0, number),
+                _ => return Err(invalid()),
+            };
+
+            let whole_months = months.trunc();
+            let days = days + (months - whole_months) * 30.0;
+            let whole_days = days.trunc();
+            let seconds = seconds + (days - whole_days) * 86400.0;
+
+            interval.months += whole_months as i64;
+            interval.days += whole_days as i64;
+            interval.seconds += seconds.round() as i64;
+        }
+
+        if ago {
+            interval = interval.negate();
+        }
+
+        Ok(interval)
+    }
+
+    pub fn negate(&self) -> Self {
+        Interval {
+            months: -self.months,
+            days: -self.days,
+            seconds: -self.seconds,
+        }
+    }
+
+    /// Approximate length, counting months as 30 days.
+    pub fn approx_seconds(&self) -> i64 {
+        (self.months * 30 + self.days) * 86400 + self.seconds
+    }
+}
+
+/// [-]HH:MM[:SS[.frac]] as seconds.
+fn parse_clock(value: &str) -> Option<i64> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, value),
+    };
+
+    let parts: Vec<&str> = value.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return None;
+    }
+
+    let hours: i64 = parts[0].parse().ok()?;
+    let minutes: i64 = parts[1].parse().ok()?;
+    let seconds: f64 = match parts.get(2) {
+        Some(s) => s.parse().ok()?,
+        None => 0.0,
+    };
+
+    let total = hours * 3600 + minutes * 60 + seconds.round() as i64;
+
+    Some(if negative { -total } else { total })
+}
+
+/// Postgres output style, e.g. "1 year 2 mons 3 days 04:05:06"
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+
+        let plural = |n: i64, one: &str, many: &str| match n.abs() {
+            1 => format!("{n} {one}"),
+            _ => format!("{n} {many}"),
+        };
+
+        let (years, months) = (self.months / 12, self.months % 12);
+
+        if years != 0 {
+            parts.push(plural(years, "year", "years"));
+        }
+        if months != 0 {
+            parts.push(plural(months, "mon", "mons"));
+        }
+        if self.days != 0 {
+            parts.push(plural(self.days, "day", "days"));
+        }
+
+        if self.seconds != 0 || parts.is_empty() {
+            let sign = if self.seconds < 0 { "-" } else { "" };
+            let secs = self.seconds.abs();
+            parts.push(format!(
+                "{sign}{:02}:{:02}:{:02}",
+                secs / 3600,
+                (secs % 3600) / 60,
+                secs % 60
+            ));
+        }
+
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Value of a --since style option: a timestamp, or an interval
+/// meaning that long before now, e.g. "2 days".
+pub fn parse_since(value: &str) -> Result<Timestamp, String> {
+    if let Ok(ts) = Timestamp::parse(value) {
+        return Ok(ts);
+    }
+
+    // "2 days" and "2 days ago" both mean the past.
+    match Interval::parse(value) {
+        Ok(i) if i.approx_seconds() < 0 => Ok(Timestamp::now().add(&i)),
+        Ok(i) => Ok(Timestamp::now().sub(&i)),
+        Err(_) => Err(format!(
+            "Invalid time: {value}.  Expected a timestamp or an interval"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        let ts = Timestamp::parse("2024-03-05T14:22:01-0500").unwrap();
+        assert_eq!(ts.epoch(), 1709666521);
+        assert_eq!(ts.offset(), -5 * 3600);
+        assert_eq!(ts.to_iso8601(), "2024-03-05T14:22:01-0500");
+
+        // Postgres text form, with fractional seconds dropped.
+        assert_eq!(Timestamp::parse("2024-03-05 14:22:01.123-05").unwrap(), ts);
+        assert_eq!(
+            Timestamp::parse("2024-03-05T19:22:01Z").unwrap().epoch(),
+            ts.epoch()
+        );
+
+        let ts = Timestamp::parse("2024-03-05 20:00+05:30").unwrap();
+        assert_eq!(ts.offset(), 19800);
+        assert_eq!(ts.to_utc().to_iso8601(), "2024-03-05T14:30:00Z");
+
+        let ts = Timestamp::parse("2024-03-05").unwrap();
+        assert_eq!((ts.epoch(), ts.offset()), (1709596800, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        assert!(Timestamp::parse("2024-02-29").is_ok());
+        assert!(Timestamp::parse("2023-02-29").is_err());
+        assert!(Timestamp::parse("2024-13-01").is_err());
+        assert!(Timestamp::parse("2024-03-05T24:00").is_err());
+        assert!(Timestamp::parse("03/05/2024").is_err());
+        assert!(Timestamp::parse("").is_err());
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        let ts = Timestamp::parse("2024-03-05T14:22:01-0500").unwrap();
+        assert_eq!(ts.format("%B %e, %Y"), "March  5, 2024");
+        assert_eq!(ts.format("%a %I:%M %p %z %%"), "Tue 02:22 PM -0500 %");
+        assert_eq!(ts.with_offset(0).format("%A %H:%M"), "Tuesday 19:22");
+        assert_eq!(ts.format("%q"), "%q");
+    }
+
+    #[test]
+    fn parses_intervals() {
+        let interval = |months, days, seconds| Interval {
+            months,
+            days,
+            seconds,
+        };
+
+        assert_eq!(Interval::parse("2 weeks").unwrap(), interval(0, 14, 0));
+        assert_eq!(
+            Interval::parse("1 year 3 mons").unwrap(),
+            interval(15, 0, 0)
+        );
+        assert_eq!(
+            Interval::parse("1 day 02:30:00").unwrap(),
+            interval(0, 1, 9000)
+        );
+        assert_eq!(Interval::parse("-1 hour").unwrap(), interval(0, 0, -3600));
+        assert_eq!(Interval::parse("3 days ago").unwrap(), interval(0, -3, 0));
+        assert_eq!(Interval::parse("@ 2 days").unwrap(), interval(0, 2, 0));
+        assert_eq!(Interval::parse("90").unwrap(), interval(0, 0, 90));
+        assert_eq!(Interval::parse("-00:15").unwrap(), interval(0, 0, -900));
+
+        // Fractions carry down as Postgres does.
+        assert_eq!(Interval::parse("1.5 months").unwrap(), interval(1, 15, 0));
+        assert_eq!(Interval::parse("1.5 days").unwrap(), interval(0, 1, 43200));
+
+        assert!(Interval::parse("").is_err());
+        assert!(Interval::parse("2 fortnights").is_err());
+        assert!(Interval::parse("soon").is_err());
+    }
+
+    #[test]
+    fn displays_intervals() {
+        let i = Interval::parse("1 year 2 mons 3 days 04:05:06").unwrap();
+        assert_eq!(i.to_string(), "1 year 2 mons 3 days 04:05:06");
+        assert_eq!(Interval::parse("1 day").unwrap().to_string(), "1 day");
+        assert_eq!(
+            Interval::parse("-90 minutes").unwrap().to_string(),
+            "-01:30:00"
+        );
+        assert_eq!(Interval::default().to_string(), "00:00:00");
+    }
+
+    #[test]
+    fn adds_intervals() {
+        let ts = Timestamp::parse("2024-01-31T12:00:00-0500").unwrap();
+
+        let month = Interval::parse("1 mon").unwrap();
+        assert_eq!(ts.add(&month).to_iso8601(), "2024-02-29T12:00:00-0500");
+
+        let day = Interval::parse("1 day 01:00:00").unwrap();
+        assert_eq!(ts.add(&day).to_iso8601(), "2024-02-01T13:00:00-0500");
+        assert_eq!(ts.sub(&day).to_iso8601(), "2024-01-30T11:00:00-0500");
+
+        let year = Interval::parse("1 year").unwrap();
+        let leap = Timestamp::parse("2024-02-29").unwrap();
+        assert_eq!(leap.add(&year).to_iso8601(), "2025-02-28T00:00:00+0000");
+    }
+}
@@ -1,13 +1,19 @@
 use getopts;
 use postgres as pg;
 ///! Create, connect, and manage database connections.
+use std::cell::RefCell;
 use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
 
 const DEFAULT_DB_PORT: u16 = 5432;
 const DEFAULT_DB_HOST: &str = "localhost";
 const DEFAULT_DB_USER: &str = "evergreen";
 const DEFAULT_DB_NAME: &str = "evergreen";
 
+/// How long ensure_connected() waits on a health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// For compiling a set of connection parameters
 ///
 /// Values are applied like so:
@@ -159,7 +165,7 @@ impl DatabaseConnectionBuilder {
         }
 
         if let Some(ref app) = self.application {
-            dsn += &format!(" application={}", app);
+            dsn += &format!(" application_name={}", app);
         }
 
         DatabaseConnection {
@@ -237,6 +243,20 @@ impl DatabaseConnection {
         }
     }
 
+    /// Connect, unless already connected and the server still answers.
+    /// Long-lived workers call this before each unit of work to reuse
+    /// their connection and replace it if it has dropped.
+    pub fn ensure_connected(&mut self) -> Result<(), String> {
+        if let Some(client) = self.client.as_mut() {
+            if !client.is_closed() && client.is_valid(HEALTH_CHECK_TIMEOUT).is_ok() {
+                return Ok(());
+            }
+            log::warn!("Database connection to {} lost; reconnecting", self.host);
+        }
+
+        self.connect()
+    }
+
     pub fn disconnect(&mut self) {
         self.client = None;
     }
@@ -260,4 +280,63 @@ impl DatabaseConnection {
             },
         }
     }
+
+    /// Name of the database we connect to.
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    /// Clones the connection, minus the PG Client, for a different
+    /// database on the same server.
+    pub fn clone_for_database(&self, database: &str) -> DatabaseConnection {
+        let mut builder = DatabaseConnectionBuilder::new();
+
+        builder.set_host(&self.host);
+        builder.set_port(self.port);
+        builder.set_user(&self.user);
+        builder.set_database(database);
+
+        if let Some(ref p) = self.password {
+            builder.set_password(p);
+        }
+
+        if let Some(ref a) = self.application {
+            builder.set_application(a);
+        }
+
+        builder.build()
+    }
+}
+
+thread_local! {
+    // Each worker thread of a server maintains its own connection.
+    static THREAD_CONNECTION: RefCell<Option<DatabaseConnection>> = const { RefCell::new(None) };
+}
+
+/// Run f with this thread's database connection, cloned from template
+/// and connected on first use, so server worker threads keep one
+/// connection across requests.
+///
+/// Returns Err only if the connection cannot be made.
+pub fn with_thread_connection<F, T>(template: &Mutex<DatabaseConnection>, f: F) -> Result<T, String>
+where
+    F: FnOnce(&mut DatabaseConnection) -> T,
+{
+    THREAD_CONNECTION.with(|cell| {
+        let mut slot = cell.borrow_mut();
+
+        if slot.is_none() {
+            let mut con = template.lock().unwrap().partial_clone();
+            con.connect()?;
+            *slot = Some(con);
+        }
+
+        Ok(f(slot.as_mut().unwrap()))
+    })
+}
+
+/// Drop this thread's connection, so the next with_thread_connection()
+/// reconnects, e.g. after an error which may mean the connection is bad.
+pub fn reset_thread_connection() {
+    THREAD_CONNECTION.with(|cell| cell.borrow_mut().take());
 }
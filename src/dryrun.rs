@@ -0,0 +1,115 @@
+//! --dry-run for the tools which change the database.
+//!
+//! A dry run makes the same changes as a real run, inside transactions
+//! which are always rolled back, so its counts and reports are those a
+//! real run would produce.
+//!
+//! Tools with a transaction per record or batch use transaction() and
+//! finish().  Each is rolled back before the next starts, so batches
+//! must not find their work by re-querying what earlier batches
+//! changed.  Tools which change the database in autocommit statements
+//! call begin() and end() around the run instead, so a dry run holds
+//! one transaction, and its locks, throughout.  Those whose autocommit
+//! statements must fail independently, e.g. one per record, wrap each
+//! in isolate() instead.
+//!
+//! Changes outside the database, e.g. moving files or sending email,
+//! are the tool's to skip with enabled().
+//!
+//! Log lines are tagged dry-run, and summaries use past() for "Would
+//! have purged" in place of "Purged".
+use crate::log;
+use getopts::Matches;
+use postgres as pg;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DryRun {
+    enabled: bool,
+}
+
+impl DryRun {
+    /// Also tags this process's log lines for a dry run.
+    pub fn from_params(params: &Matches) -> DryRun {
+        let enabled = params.opt_present("dry-run");
+        log::set_dry_run(enabled);
+        DryRun { enabled }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// On a dry run, open a transaction on the client for the
+    /// statements until end(), which must not use pg::Transaction.
+    pub fn begin(&self, client: &mut pg::Client) -> Result<(), String> {
+        match self.enabled {
+            true => client
+                .batch_execute("BEGIN")
+                .map_err(|e| format!("Cannot start transaction: {e}")),
+            false => Ok(()),
+        }
+    }
+
+    /// Roll back what a dry run did since begin().
+    pub fn end(&self, client: &mut pg::Client) -> Result<(), String> {
+        match self.enabled {
+            true => client
+                .batch_execute("ROLLBACK")
+                .map_err(|e| format!("Error rolling back: {e}")),
+            false => Ok(()),
+        }
+    }
+
+    /// Run f in a transaction which is rolled back after on a dry run,
+    /// so a failure leaves later statements unaffected, as they are
+    /// when each autocommits on a real run.
+    pub fn isolate<T, F>(&self, client: &mut pg::Client, f: F) -> Result<T, pg::Error>
+    where
+        F: FnOnce(&mut pg::Client) -> Result<T, pg::Error>,
+    {
+        if !self.enabled {
+            return f(client);
+        }
+
+        client.batch_execute("BEGIN")?;
+        let result = f(client);
+        client.batch_execute("ROLLBACK")?;
+
+        result
+    }
+
+    pub fn transaction<'a>(
+        &self,
+        client: &'a mut pg::Client,
+    ) -> Result<pg::Transaction<'a>, String> {
+        client
+            .transaction()
+            .map_err(|e| format!("Cannot start transaction: {e}"))
+    }
+
+    /// Commit, or roll back on a dry run.
+    pub fn finish(&self, tx: pg::Transaction) -> Result<(), String> {
+        let result = match self.enabled {
+            true => tx.rollback(),
+            false => tx.commit(),
+        };
+
+        result.map_err(|e| format!("Error ending transaction: {e}"))
+    }
+
+    /// Verb for a summary, e.g. "Purged", or "Would have purged" on a
+    /// dry run.  A lowercase verb gives "would have purged".
+    pub fn past(&self, verb: &str) -> String {
+        if !self.enabled {
+            return verb.to_string();
+        }
+
+        let mut chars = verb.chars();
+        match chars.next() {
+            Some(c) if c.is_uppercase() => {
+                format!("Would have {}{}", c.to_lowercase(), chars.as_str())
+            }
+            _ => format!("would have {verb}"),
+        }
+    }
+}
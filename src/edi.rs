@@ -0,0 +1,430 @@
+//! EDIFACT parsing for inbound acquisitions messages.
+//!
+//! Interchanges are split into UNH/UNT messages, and ORDRSP and
+//! INVOIC messages read into a Document of lineitem-level detail.
+use std::collections::HashMap;
+
+/// One segment, e.g. LIN+1++9780306406157:EN
+pub struct Segment {
+    pub tag: String,
+    /// Data elements, each a list of components.
+    pub elements: Vec<Vec<String>>,
+}
+
+impl Segment {
+    /// Component value or "" when absent.
+    pub fn get(&self, element: usize, component: usize) -> &str {
+        self.elements
+            .get(element)
+            .and_then(|e| e.get(component))
+            .map(|c| c.as_str())
+            .unwrap_or("")
+    }
+}
+
+pub struct Message {
+    /// UNH message type, e.g. ORDRSP
+    pub kind: String,
+    pub segments: Vec<Segment>,
+}
+
+/// Service characters, from the UNA segment when present.
+struct Delimiters {
+    component: char,
+    element: char,
+    release: char,
+    segment: char,
+}
+
+pub fn parse_segments(text: &str) -> Vec<Segment> {
+    let mut text = text.trim_start();
+
+    let mut delims = Delimiters {
+        component: ':',
+        element: '+',
+        release: '?',
+        segment: '\'',
+    };
+
+    if let Some(una) = text.strip_prefix("UNA") {
+        let chars: Vec<char> = una.chars().take(6).collect();
+        if chars.len() == 6 {
+            delims = Delimiters {
+                component: chars[0],
+                element: chars[1],
+                release: chars[3],
+                segment: chars[5],
+            };
+            text = &una[chars.iter().map(|c| c.len_utf8()).sum::<usize>()..];
+        }
+    }
+
+    let mut segments = Vec::new();
+    let mut elements: Vec<Vec<String>> = Vec::new();
+    let mut components: Vec<String> = Vec::new();
+    let mut value = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == delims.release {
+            if let Some(n) = chars.next() {
+                value.push(n);
+            }
+        } else if c == delims.component {
+            components.push(std::mem::take(&mut value));
+        } else if c == delims.element {
+            components.push(std::mem::take(&mut value));
+            elements.push(std::mem::take(&mut components));
+        } else if c == delims.segment {
+            components.push(std::mem::take(&mut value));
+            elements.push(std::mem::take(&mut components));
+
+            let mut elements = std::mem::take(&mut elements);
+            let tag = elements.remove(0).remove(0).trim().to_string();
+            segments.push(Segment { tag, elements });
+        } else if c != '\r' && c != '\n' {
+            value.push(c);
+        }
+    }
+
+    segments
+}
+
+/// Split an interchange into its UNH/UNT messages.
+pub fn parse_messages(text: &str) -> Result<Vec<Message>, String> {
+    let mut messages = Vec::new();
+    let mut current: Option<Message> = None;
+
+    for seg in parse_segments(text) {
+        match seg.tag.as_str() {
+            "UNH" => {
+                current = Some(Message {
+                    kind: seg.get(1, 0).to_string(),
+                    segments: Vec::new(),
+                });
+            }
+            "UNT" => match current.take() {
+                Some(m) => messages.push(m),
+                None => return Err("UNT segment without UNH".to_string()),
+            },
+            _ => {
+                if let Some(ref mut m) = current {
+                    m.segments.push(seg);
+                }
+            }
+        }
+    }
+
+    if current.is_some() {
+        return Err("Message is missing its UNT segment".to_string());
+    }
+
+    if messages.is_empty() {
+        return Err("No messages found".to_string());
+    }
+
+    Ok(messages)
+}
+
+/// Lineitem-level detail shared by ORDRSP and INVOIC messages.
+#[derive(Default)]
+pub struct Line {
+    /// LIN action code
+    pub action: String,
+    pub ident: String,
+    pub purchase_order: Option<i32>,
+    pub lineitem: Option<i32>,
+    /// QTY values by qualifier
+    pub quantities: HashMap<String, i32>,
+    /// MOA values by qualifier
+    pub amounts: HashMap<String, f64>,
+    /// PRI values by qualifier
+    pub prices: HashMap<String, f64>,
+    pub notes: Vec<String>,
+}
+
+impl Line {
+    /// Invoiced quantity (QTY 47), or 1 when absent.
+    pub fn invoiced_count(&self) -> i32 {
+        self.quantities.get("47").copied().unwrap_or(1)
+    }
+
+    /// Line amount (MOA 203), or the net (AAA) or gross (AAB) unit
+    /// price times the invoiced quantity.
+    pub fn invoiced_cost(&self) -> f64 {
+        match self.amounts.get("203") {
+            Some(a) => *a,
+            None => {
+                let price = self
+                    .prices
+                    .get("AAA")
+                    .or_else(|| self.prices.get("AAB"))
+                    .copied()
+                    .unwrap_or(0.0);
+                price * self.invoiced_count() as f64
+            }
+        }
+    }
+}
+
+/// Message header values plus lines and header-level charges.
+#[derive(Default)]
+pub struct Document {
+    /// BGM document number
+    pub number: String,
+    /// BGM message function code
+    pub function: String,
+    pub purchase_order: Option<i32>,
+    pub lines: Vec<Line>,
+    /// Charges as (invoice item type, amount)
+    pub charges: Vec<(&'static str, f64)>,
+}
+
+fn to_f64(s: &str) -> f64 {
+    s.replace(',', ".").parse().unwrap_or(0.0)
+}
+
+/// Evergreen sends lineitem references as "PO_ID/LINEITEM_ID".
+fn parse_li_ref(s: &str) -> (Option<i32>, Option<i32>) {
+    match s.split_once('/') {
+        Some((po, li)) => (po.parse().ok(), li.parse().ok()),
+        None => (None, s.parse().ok()),
+    }
+}
+
+pub fn read_document(msg: &Message) -> Document {
+    let mut doc = Document::default();
+    let mut line: Option<Line> = None;
+    let mut charge: Option<&'static str> = None;
+
+    for seg in &msg.segments {
+        match seg.tag.as_str() {
+            "BGM" => {
+                doc.number = seg.get(1, 0).to_string();
+                doc.function = seg.get(2, 0).to_string();
+            }
+            "LIN" => {
+                if let Some(l) = line.take() {
+                    doc.lines.push(l);
+                }
+                line = Some(Line {
+                    action: seg.get(1, 0).to_string(),
+                    ident: seg.get(2, 0).to_string(),
+                    ..Default::default()
+                });
+            }
+            "RFF" => {
+                let value = seg.get(0, 1);
+                match (seg.get(0, 0), line.as_mut()) {
+                    ("ON", None) => doc.purchase_order = value.parse().ok(),
+                    ("LI", Some(l)) => {
+                        let (po, li) = parse_li_ref(value);
+                        l.purchase_order = po;
+                        l.lineitem = li;
+                    }
+                    _ => {}
+                }
+            }
+            "QTY" => {
+                if let Some(l) = line.as_mut() {
+                    let qty = seg.get(0, 1).parse().unwrap_or(0);
+                    l.quantities.insert(seg.get(0, 0).to_string(), qty);
+                }
+            }
+            "PRI" => {
+                if let Some(l) = line.as_mut() {
+                    l.prices
+                        .insert(seg.get(0, 0).to_string(), to_f64(seg.get(0, 1)));
+                }
+            }
+            "FTX" => {
+                let text: Vec<&str> = match seg.elements.get(3) {
+                    Some(e) => e.iter().map(|c| c.as_str()).collect(),
+                    None => Vec::new(),
+                };
+                if let Some(l) = line.as_mut() {
+                    l.notes.push(text.join(" "));
+                }
+            }
+            // Charges only appear after the lines in the summary section.
+            "ALC" if seg.get(0, 0) == "C" => charge = Some("SHP"),
+            "TAX" => charge = Some("TAX"),
+            "MOA" => {
+                let qualifier = seg.get(0, 0);
+                let amount = to_f64(seg.get(0, 1));
+                match charge.take() {
+                    Some(kind) if qualifier == "8" || qualifier == "124" => {
+                        doc.charges.push((kind, amount))
+                    }
+                    _ => {
+                        if let Some(l) = line.as_mut() {
+                            l.amounts.insert(qualifier.to_string(), amount);
+                        }
+                    }
+                }
+            }
+            "UNS" => {
+                if let Some(l) = line.take() {
+                    doc.lines.push(l);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(l) = line.take() {
+        doc.lines.push(l);
+    }
+
+    doc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVOICE: &str = "UNA:+.? '\
+        UNB+UNOC:3+VENDOR:31B+LIBRARY:31B+260101:1200+42'\
+        UNH+1+INVOIC:D:96A:UN'\
+        BGM+380+INV-100+9'\
+        RFF+ON:12'\
+        LIN+1++9780306406157:EN'\
+        QTY+47:2'\
+        PRI+AAA:12,50'\
+        RFF+LI:12/34'\
+        FTX+LIN+++Back:ordered'\
+        LIN+2++0306406152:IB'\
+        MOA+203:30.00'\
+        PRI+AAB:15.00'\
+        RFF+LI:35'\
+        UNS+S'\
+        ALC+C++++DL'\
+        MOA+8:4.95'\
+        TAX+7+VAT'\
+        MOA+124:1.20'\
+        UNT+19+1'\
+        UNZ+1+42'";
+
+    fn tags(segments: &[Segment]) -> Vec<&str> {
+        segments.iter().map(|s| s.tag.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_segments() {
+        let segments = parse_segments("UNH+1+ORDRSP:D:96A'\r\nLIN+1++978:EN'");
+
+        assert_eq!(tags(&segments), vec!["UNH", "LIN"]);
+        assert_eq!(segments[0].get(1, 0), "ORDRSP");
+        assert_eq!(segments[0].get(1, 2), "96A");
+        assert_eq!(segments[1].get(1, 0), "");
+        assert_eq!(segments[1].get(2, 1), "EN");
+
+        // Absent elements and components are empty.
+        assert_eq!(segments[1].get(2, 5), "");
+        assert_eq!(segments[1].get(9, 0), "");
+    }
+
+    #[test]
+    fn honors_service_characters() {
+        let segments = parse_segments("UNA|*.# ~FTX*AAI***Smith#*Jones|A#~B~");
+        assert_eq!(tags(&segments), vec!["FTX"]);
+        assert_eq!(segments[0].get(3, 0), "Smith*Jones");
+        assert_eq!(segments[0].get(3, 1), "A~B");
+
+        let segments = parse_segments("FTX+AAI+++50?% off?: see?'note?''");
+        assert_eq!(segments[0].get(3, 0), "50% off: see'note'");
+    }
+
+    #[test]
+    fn drops_truncated_segments() {
+        let segments = parse_segments("UNH+1+ORDRSP'BGM+231+PO-1");
+        assert_eq!(tags(&segments), vec!["UNH"]);
+
+        // A trailing release character has nothing to escape.
+        let segments = parse_segments("UNH+1+ORDRSP'FTX+AAI+++Note?");
+        assert_eq!(tags(&segments), vec!["UNH"]);
+
+        assert!(parse_segments("").is_empty());
+        assert!(parse_segments("UNA:+").is_empty());
+    }
+
+    #[test]
+    fn splits_messages() {
+        let text = "UNB+UNOC:3'UNH+1+ORDRSP'BGM+231'UNT+3+1'\
+                    UNH+2+INVOIC'BGM+380'LIN+1'UNT+4+2'UNZ+2'";
+
+        let messages = parse_messages(text).unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].kind, "ORDRSP");
+        assert_eq!(tags(&messages[0].segments), vec!["BGM"]);
+        assert_eq!(messages[1].kind, "INVOIC");
+        assert_eq!(tags(&messages[1].segments), vec!["BGM", "LIN"]);
+    }
+
+    #[test]
+    fn rejects_garbled_interchanges() {
+        let error = |text: &str| parse_messages(text).err().unwrap();
+
+        assert_eq!(
+            error("UNB+UNOC:3'BGM+231'UNT+2+1'"),
+            "UNT segment without UNH"
+        );
+        assert_eq!(
+            error("UNH+1+ORDRSP'BGM+231'UNT+3+1'UNH+2+ORDRSP'BGM+231'"),
+            "Message is missing its UNT segment"
+        );
+        // The UNT is lost with its terminator.
+        assert_eq!(
+            error("UNH+1+ORDRSP'BGM+231'UNT+3+1"),
+            "Message is missing its UNT segment"
+        );
+        assert_eq!(error("UNB+UNOC:3'UNZ+0'"), "No messages found");
+        assert_eq!(error("not an interchange"), "No messages found");
+    }
+
+    #[test]
+    fn reads_invoices() {
+        let messages = parse_messages(INVOICE).unwrap();
+        let doc = read_document(&messages[0]);
+
+        assert_eq!(messages[0].kind, "INVOIC");
+        assert_eq!(doc.number, "INV-100");
+        assert_eq!(doc.function, "9");
+        assert_eq!(doc.purchase_order, Some(12));
+        assert_eq!(doc.lines.len(), 2);
+
+        let line = &doc.lines[0];
+        assert_eq!(line.action, "");
+        assert_eq!(line.ident, "9780306406157");
+        assert_eq!((line.purchase_order, line.lineitem), (Some(12), Some(34)));
+        assert_eq!(line.invoiced_count(), 2);
+        assert_eq!(line.invoiced_cost(), 25.0);
+        assert_eq!(line.notes, vec!["Back ordered"]);
+
+        let line = &doc.lines[1];
+        assert_eq!((line.purchase_order, line.lineitem), (None, Some(35)));
+        assert_eq!(line.invoiced_count(), 1);
+        assert_eq!(line.invoiced_cost(), 30.0);
+
+        assert_eq!(doc.charges, vec![("SHP", 4.95), ("TAX", 1.2)]);
+    }
+
+    #[test]
+    fn reads_incomplete_lines() {
+        let msg = Message {
+            kind: "ORDRSP".to_string(),
+            segments: parse_segments("BGM'LIN'QTY+21:x'PRI+AAA'RFF+LI:PO/LI'MOA+203'"),
+        };
+
+        let doc = read_document(&msg);
+
+        assert_eq!(doc.number, "");
+        assert_eq!(doc.lines.len(), 1);
+
+        let line = &doc.lines[0];
+        assert_eq!(line.quantities.get("21"), Some(&0));
+        assert_eq!((line.purchase_order, line.lineitem), (None, None));
+        assert_eq!(line.invoiced_cost(), 0.0);
+    }
+}
@@ -0,0 +1,293 @@
+//! Sending email through an SMTP relay.
+//!
+//! Binaries which send mail add the --smtp-* options with
+//! append_options() and build a Mailer from them.  Transient failures,
+//! e.g. a 4xx reply or a dropped connection, are retried with a
+//! doubling delay.  Permanent failures, e.g. an unknown recipient, are
+//! not.
+use getopts::{Matches, Options};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{Message, SmtpTransport, Transport};
+use log::{debug, warn};
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+/// Used when --smtp-password is not set, to keep it out of ps.
+const PASSWORD_ENV: &str = "EGUTIL_SMTP_PASSWORD";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Security {
+    /// Plain SMTP, e.g. a relay on localhost.
+    None,
+    /// STARTTLS, usually on port 587.
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+}
+
+impl Security {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(Security::None),
+            "starttls" => Ok(Security::StartTls),
+            "tls" => Ok(Security::Tls),
+            _ => Err(format!("Invalid SMTP security: {value}")),
+        }
+    }
+
+    fn default_port(&self) -> u16 {
+        match self {
+            Security::None => 25,
+            Security::StartTls => 587,
+            Security::Tls => 465,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to the usual port for the security mode.
+    pub port: Option<u16>,
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub timeout: Duration,
+    /// Attempts after the first for transient failures.
+    pub retries: u32,
+    /// Delay before the first retry.
+    pub retry_delay: Duration,
+    /// Pause after this many messages in send_all(), or 0 to never
+    /// pause, for relays which limit sending rates.
+    pub batch_size: usize,
+    pub batch_pause: Duration,
+}
+
+impl SmtpConfig {
+    pub fn new(host: &str) -> Self {
+        SmtpConfig {
+            host: host.to_string(),
+            port: None,
+            security: Security::StartTls,
+            username: None,
+            password: None,
+            timeout: Duration::from_secs(30),
+            retries: 3,
+            retry_delay: Duration::from_secs(5),
+            batch_size: 0,
+            batch_pause: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Add --smtp-host and friends.
+pub fn append_options(opts: &mut Options) {
+    opts.optopt("", "smtp-host", "SMTP Relay Host", "HOST");
+    opts.optopt("", "smtp-port", "SMTP Relay Port", "PORT");
+    opts.optopt("", "smtp-security", "none, starttls or tls", "MODE");
+    opts.optopt("", "smtp-user", "SMTP Username", "USER");
+    opts.optopt("", "smtp-password", "SMTP Password", "PASSWORD");
+    opts.optopt(
+        "",
+        "smtp-retries",
+        "Retries for Transient Failures",
+        "COUNT",
+    );
+    opts.optopt("", "smtp-batch-size", "Messages Between Pauses", "COUNT");
+    opts.optopt("", "smtp-batch-pause", "Seconds to Pause", "SECONDS");
+}
+
+/// SMTP settings from the command line, or None without --smtp-host.
+pub fn config_from_options(params: &Matches) -> Result<Option<SmtpConfig>, String> {
+    let host = match params.opt_str("smtp-host") {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+
+    let mut config = SmtpConfig::new(&host);
+
+    let number = |name: &str| -> Result<Option<u64>, String> {
+        match params.opt_str(name) {
+            Some(v) => match v.parse() {
+                Ok(n) => Ok(Some(n)),
+                Err(_) => Err(format!("Invalid --{name} value: {v}")),
+            },
+            None => Ok(None),
+        }
+    };
+
+    if let Some(port) = number("smtp-port")? {
+        match u16::try_from(port) {
+            Ok(p) => config.port = Some(p),
+            Err(_) => return Err(format!("Invalid --smtp-port value: {port}")),
+        }
+    }
+
+    if let Some(mode) = params.opt_str("smtp-security") {
+        config.security = Security::parse(&mode)?;
+    }
+
+    config.username = params.opt_str("smtp-user");
+    config.password = params
+        .opt_str("smtp-password")
+        .or_else(|| env::var(PASSWORD_ENV).ok());
+
+    if let Some(n) = number("smtp-retries")? {
+        config.retries = n as u32;
+    }
+
+    if let Some(n) = number("smtp-batch-size")? {
+        config.batch_size = n as usize;
+    }
+
+    if let Some(n) = number("smtp-batch-pause")? {
+        config.batch_pause = Duration::from_secs(n);
+    }
+
+    Ok(Some(config))
+}
+
+/// A plain text message.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub from: String,
+    pub to: Vec<String>,
+    pub reply_to: Option<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn new(from: &str, to: &str, subject: &str, body: &str) -> Self {
+        Email {
+            from: from.to_string(),
+            to: vec![to.to_string()],
+            reply_to: None,
+            subject: subject.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    fn to_message(&self) -> Result<Message, String> {
+        let mailbox = |addr: &str| -> Result<Mailbox, String> {
+            match addr.trim().parse() {
+                Ok(m) => Ok(m),
+                Err(e) => Err(format!("Invalid address {addr}: {e}")),
+            }
+        };
+
+        let mut builder = Message::builder()
+            .from(mailbox(&self.from)?)
+            .subject(self.subject.as_str())
+            .header(ContentType::TEXT_PLAIN);
+
+        for to in &self.to {
+            builder = builder.to(mailbox(to)?);
+        }
+
+        if let Some(reply_to) = &self.reply_to {
+            builder = builder.reply_to(mailbox(reply_to)?);
+        }
+
+        match builder.body(self.body.clone()) {
+            Ok(m) => Ok(m),
+            Err(e) => Err(format!("Cannot build message: {e}")),
+        }
+    }
+}
+
+/// Sends messages over a pooled SMTP connection.  Mailers are Sync,
+/// so one can be shared by worker threads.
+pub struct Mailer {
+    config: SmtpConfig,
+    transport: SmtpTransport,
+}
+
+impl Mailer {
+    pub fn new(config: SmtpConfig) -> Result<Self, String> {
+        let tls = match config.security {
+            Security::None => Tls::None,
+            Security::StartTls => Tls::Required(tls_parameters(&config.host)?),
+            Security::Tls => Tls::Wrapper(tls_parameters(&config.host)?),
+        };
+
+        let mut builder = SmtpTransport::builder_dangerous(config.host.as_str())
+            .port(config.port.unwrap_or(config.security.default_port()))
+            .tls(tls)
+            .timeout(Some(config.timeout));
+
+        if let Some(user) = &config.username {
+            let password = config.password.clone().unwrap_or_default();
+            builder = builder.credentials(Credentials::new(user.to_string(), password));
+        }
+
+        Ok(Mailer {
+            transport: builder.build(),
+            config,
+        })
+    }
+
+    /// Send one message, retrying transient failures.
+    pub fn send(&self, email: &Email) -> Result<(), String> {
+        let message = email.to_message()?;
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 0;
+
+        loop {
+            let e = match self.transport.send(&message) {
+                Ok(_) => {
+                    debug!("Sent '{}' to {}", email.subject, email.to.join(", "));
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            if e.is_permanent() || attempt >= self.config.retries {
+                return Err(format!(
+                    "Cannot send '{}' to {}: {e}",
+                    email.subject,
+                    email.to.join(", ")
+                ));
+            }
+
+            attempt += 1;
+            warn!(
+                "Sending to {} failed, retry {attempt} in {}s: {e}",
+                email.to.join(", "),
+                delay.as_secs()
+            );
+
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+
+    /// Send each message, pausing between batches when configured.
+    /// Returns the result for each message, in order, so one bad
+    /// address does not stop the rest.
+    pub fn send_all(&self, emails: &[Email]) -> Vec<Result<(), String>> {
+        let mut results = Vec::new();
+
+        for (idx, email) in emails.iter().enumerate() {
+            if idx > 0 && self.config.batch_size > 0 && idx % self.config.batch_size == 0 {
+                debug!("Sent {idx} messages; pausing");
+                thread::sleep(self.config.batch_pause);
+            }
+
+            results.push(self.send(email));
+        }
+
+        results
+    }
+}
+
+fn tls_parameters(host: &str) -> Result<TlsParameters, String> {
+    match TlsParameters::new(host.to_string()) {
+        Ok(p) => Ok(p),
+        Err(e) => Err(format!("Cannot set up TLS for {host}: {e}")),
+    }
+}
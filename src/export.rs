@@ -0,0 +1,897 @@
+//! Bib and authority record export.
+//!
+//! An Exporter selects records and passes each one's MARC XML to a
+//! RecordSink, e.g. a MarcWriter producing a binary or XML file.
+//!
+//! Records are streamed with a binary COPY rather than fetched a row
+//! at a time, and converted to binary MARC without building a Record,
+//! reusing the same buffers for every record.  Both matter on
+//! multi-million record exports.
+use crate::db::DatabaseConnection;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Read, Write};
+
+/// Signature which starts binary COPY output.
+const COPY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+const FIELD_TERMINATOR: u8 = 0x1E;
+const SUBFIELD_DELIMITER: u8 = 0x1F;
+const RECORD_TERMINATOR: u8 = 0x1D;
+const LEADER_LENGTH: usize = 24;
+
+/// Output buffer size.  Large writes suit NFS and other network
+/// filesystems, where each small write is a round trip.
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+
+const XML_COLLECTION_HEADER: &str = r#"<collection xmlns="http://www.loc.gov/MARC21/slim">"#;
+const XML_COLLECTION_FOOTER: &str = "</collection>";
+
+/// Receives exported records.
+pub trait RecordSink {
+    /// Called once before the first record.
+    fn begin(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// One record's MARC XML, as stored in record_entry.marc.
+    fn write_record(&mut self, marc_xml: &str) -> Result<(), String>;
+
+    /// Called once after the last record.
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Binary,
+    Xml,
+}
+
+/// Writes records as binary MARC or as a MARC XML collection.
+///
+/// Output is buffered.  Optionally, the buffer is flushed every N
+/// records, and file output synced to disk at each flush, so an
+/// interrupted export leaves whole records behind.
+pub struct MarcWriter {
+    writer: BufWriter<Box<dyn Write>>,
+    format: Format,
+    encoder: BinaryEncoder,
+    /// Handle for syncing when writing to a file.
+    file: Option<fs::File>,
+    flush_every: u64,
+    fsync: bool,
+    unflushed: u64,
+}
+
+impl MarcWriter {
+    pub fn new(writer: Box<dyn Write>, format: Format) -> Self {
+        MarcWriter {
+            writer: BufWriter::with_capacity(WRITE_BUFFER_SIZE, writer),
+            format,
+            encoder: BinaryEncoder::default(),
+            file: None,
+            flush_every: 0,
+            fsync: false,
+            unflushed: 0,
+        }
+    }
+
+    pub fn stdout(format: Format) -> Self {
+        MarcWriter::new(Box::new(io::stdout()), format)
+    }
+
+    pub fn create(path: &str, format: Format) -> Result<Self, String> {
+        let file = match fs::File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Cannot create {path}: {e}")),
+        };
+
+        let sync_handle = match file.try_clone() {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Cannot open {path}: {e}")),
+        };
+
+        let mut writer = MarcWriter::new(Box::new(file), format);
+        writer.file = Some(sync_handle);
+
+        Ok(writer)
+    }
+
+    /// Flush output every this many records, or only at the end
+    /// with 0, the default.
+    pub fn set_flush_every(&mut self, records: u64) {
+        self.flush_every = records;
+    }
+
+    /// Sync file output to disk at each flush and at the end.
+    pub fn set_fsync(&mut self, fsync: bool) {
+        self.fsync = fsync;
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        match self.writer.write_all(bytes) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error writing bytes: {e}")),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.unflushed = 0;
+
+        if let Err(e) = self.writer.flush() {
+            return Err(format!("Error writing bytes: {e}"));
+        }
+
+        if let (true, Some(file)) = (self.fsync, &self.file) {
+            if let Err(e) = file.sync_data() {
+                return Err(format!("Error syncing output: {e}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl RecordSink for MarcWriter {
+    fn begin(&mut self) -> Result<(), String> {
+        match self.format {
+            Format::Xml => self.write(XML_COLLECTION_HEADER.as_bytes()),
+            Format::Binary => Ok(()),
+        }
+    }
+
+    fn write_record(&mut self, marc_xml: &str) -> Result<(), String> {
+        match self.format {
+            // No need to parse the record if we going XML to XML.
+            Format::Xml => self.write(marc_xml.as_bytes())?,
+            Format::Binary => {
+                let binary = self.encoder.encode(marc_xml)?;
+                if let Err(e) = self.writer.write_all(binary) {
+                    return Err(format!("Error writing bytes: {e}"));
+                }
+            }
+        }
+
+        self.unflushed += 1;
+
+        if self.flush_every > 0 && self.unflushed >= self.flush_every {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        if self.format == Format::Xml {
+            self.write(XML_COLLECTION_FOOTER.as_bytes())?;
+        }
+
+        self.flush()
+    }
+}
+
+/// Where text content goes while scanning MARC XML.
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    Nowhere,
+    Leader,
+    Data,
+}
+
+/// Converts MARC XML to binary MARC in one pass.  Only the first
+/// record in the XML is encoded.
+#[derive(Default)]
+struct BinaryEncoder {
+    leader: Vec<u8>,
+    directory: Vec<u8>,
+    data: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl BinaryEncoder {
+    /// Returns the binary record, or nothing if the XML holds no
+    /// record.  The slice is valid until the next call.
+    fn encode(&mut self, xml: &str) -> Result<&[u8], String> {
+        self.leader.clear();
+        self.directory.clear();
+        self.data.clear();
+        self.output.clear();
+
+        let mut target = Target::Nowhere;
+        let mut in_record = false;
+        let mut tag = [0u8; 3];
+        let mut field_start = 0;
+        let mut pos = 0;
+
+        while pos < xml.len() {
+            let rest = &xml[pos..];
+
+            let lt = match rest.find('<') {
+                Some(i) => i,
+                None => break,
+            };
+
+            if lt > 0 {
+                self.add_text(target, &rest[..lt])?;
+            }
+            pos += lt;
+            let rest = &xml[pos..];
+
+            if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+                let end = match cdata.find("]]>") {
+                    Some(e) => e,
+                    None => return Err("Unterminated CDATA in MARC XML".to_string()),
+                };
+                match target {
+                    Target::Leader => self.leader.extend(&cdata.as_bytes()[..end]),
+                    Target::Data => self.data.extend(&cdata.as_bytes()[..end]),
+                    Target::Nowhere => {}
+                }
+                pos += "<![CDATA[".len() + end + 3;
+                continue;
+            }
+
+            if rest.starts_with("<!--") {
+                match rest.find("-->") {
+                    Some(e) => pos += e + 3,
+                    None => return Err("Unterminated comment in MARC XML".to_string()),
+                }
+                continue;
+            }
+
+            let end = match tag_end(rest) {
+                Some(e) => e,
+                None => return Err("Unterminated element in MARC XML".to_string()),
+            };
+
+            let body = &rest[1..end];
+            pos += end + 1;
+
+            if body.starts_with('?') || body.starts_with('!') {
+                continue;
+            }
+
+            let closing = body.starts_with('/');
+            let body = body.trim_start_matches('/');
+            let empty = body.ends_with('/');
+            let body = body.trim_end_matches('/');
+
+            let name_end = body.find(|c: char| c.is_whitespace()).unwrap_or(body.len());
+            let (name, attrs) = body.split_at(name_end);
+
+            // Drop any namespace prefix, e.g. marc:record.
+            let name = name.rsplit(':').next().unwrap_or(name);
+
+            if closing {
+                match name {
+                    "record" if in_record => break,
+                    "controlfield" | "datafield" => {
+                        self.data.push(FIELD_TERMINATOR);
+                        self.add_directory_entry(&tag, field_start)?;
+                        target = Target::Nowhere;
+                    }
+                    _ => target = Target::Nowhere,
+                }
+                continue;
+            }
+
+            match name {
+                "record" => in_record = true,
+                "leader" if in_record => target = Target::Leader,
+                "controlfield" | "datafield" if in_record => {
+                    tag = field_tag(attrs)?;
+                    field_start = self.data.len();
+
+                    if name == "datafield" {
+                        for ind in ["ind1", "ind2"] {
+                            match attr(attrs, ind).and_then(|v| v.bytes().next()) {
+                                Some(c) => self.data.push(c),
+                                None => self.data.push(b' '),
+                            }
+                        }
+                        target = Target::Nowhere;
+                    } else {
+                        target = Target::Data;
+                    }
+
+                    if empty {
+                        if name == "controlfield" {
+                            target = Target::Nowhere;
+                        }
+                        self.data.push(FIELD_TERMINATOR);
+                        self.add_directory_entry(&tag, field_start)?;
+                    }
+                }
+                "subfield" if in_record => {
+                    self.data.push(SUBFIELD_DELIMITER);
+                    decode_into(&mut self.data, attr(attrs, "code").unwrap_or(" "))?;
+                    target = match empty {
+                        true => Target::Nowhere,
+                        false => Target::Data,
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        if !in_record {
+            return Ok(&self.output);
+        }
+
+        self.build()?;
+
+        Ok(&self.output)
+    }
+
+    fn add_text(&mut self, target: Target, text: &str) -> Result<(), String> {
+        match target {
+            Target::Leader => decode_into(&mut self.leader, text),
+            Target::Data => decode_into(&mut self.data, text),
+            Target::Nowhere => Ok(()),
+        }
+    }
+
+    fn add_directory_entry(&mut self, tag: &[u8; 3], start: usize) -> Result<(), String> {
+        let len = self.data.len() - start;
+
+        if len > 9999 || start > 99999 {
+            return Err(format!(
+                "Field {} is too long for binary MARC",
+                String::from_utf8_lossy(tag)
+            ));
+        }
+
+        self.directory.extend(tag);
+        write_number(&mut self.directory, len, 4);
+        write_number(&mut self.directory, start, 5);
+
+        Ok(())
+    }
+
+    fn build(&mut self) -> Result<(), String> {
+        let base = LEADER_LENGTH + self.directory.len() + 1;
+        let len = base + self.data.len() + 1;
+
+        if len > 99999 {
+            return Err(format!("Record of {len} bytes is too long for binary MARC"));
+        }
+
+        self.leader.resize(LEADER_LENGTH, b' ');
+
+        write_number(&mut self.output, len, 5);
+        self.output.extend(&self.leader[5..10]);
+        self.output.extend(b"22");
+        write_number(&mut self.output, base, 5);
+        self.output.extend(&self.leader[17..20]);
+        self.output.extend(b"4500");
+
+        self.output.extend(&self.directory);
+        self.output.push(FIELD_TERMINATOR);
+        self.output.extend(&self.data);
+        self.output.push(RECORD_TERMINATOR);
+
+        Ok(())
+    }
+}
+
+/// Zero-padded decimal digits, without a temporary String.
+fn write_number(buf: &mut Vec<u8>, mut n: usize, width: usize) {
+    let start = buf.len();
+    buf.resize(start + width, b'0');
+    for idx in (start..start + width).rev() {
+        buf[idx] = b'0' + (n % 10) as u8;
+        n /= 10;
+    }
+}
+
+/// Offset of the > closing an element, skipping quoted values.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (idx, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => return Some(idx),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn attr<'a>(mut attrs: &'a str, want: &str) -> Option<&'a str> {
+    loop {
+        attrs = attrs.trim_start();
+        let eq = attrs.find('=')?;
+        let name = attrs[..eq].trim();
+        let rest = attrs[eq + 1..].trim_start();
+
+        let quote = rest.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            return None;
+        }
+
+        let end = rest[1..].find(quote)? + 1;
+        if name == want {
+            return Some(&rest[1..end]);
+        }
+
+        attrs = &rest[end + 1..];
+    }
+}
+
+fn field_tag(attrs: &str) -> Result<[u8; 3], String> {
+    match attr(attrs, "tag").map(|t| t.as_bytes()) {
+        Some(&[a, b, c]) => Ok([a, b, c]),
+        _ => Err(format!("Invalid MARC XML field tag: {attrs}")),
+    }
+}
+
+/// Append text with XML entities decoded.
+fn decode_into(buf: &mut Vec<u8>, text: &str) -> Result<(), String> {
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        buf.extend(&rest.as_bytes()[..amp]);
+        rest = &rest[amp + 1..];
+
+        let semi = match rest.find(';') {
+            Some(s) => s,
+            None => return Err(format!("Invalid entity in MARC XML: &{rest}")),
+        };
+
+        let c = match &rest[..semi] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            e => match e.strip_prefix("#x").or_else(|| e.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => e
+                    .strip_prefix('#')
+                    .and_then(|d| d.parse().ok())
+                    .and_then(char::from_u32),
+            },
+        };
+
+        match c {
+            Some(c) => {
+                let mut utf8 = [0; 4];
+                buf.extend(c.encode_utf8(&mut utf8).as_bytes());
+            }
+            None => return Err(format!("Invalid entity in MARC XML: &{}", &rest[..=semi])),
+        }
+
+        rest = &rest[semi + 1..];
+    }
+
+    buf.extend(rest.as_bytes());
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RecordType {
+    #[default]
+    Bib,
+    Authority,
+}
+
+/// Authority 008/11 thesaurus code for a name like "lcsh", or the
+/// code itself, e.g. "a".  "local" is an alias for "other" (z).
+pub fn thesaurus_code(name: &str) -> Option<char> {
+    let code = match name.to_lowercase().as_str() {
+        "lcsh" => 'a',
+        "lcac" => 'b',
+        "mesh" => 'c',
+        "nal" => 'd',
+        "cash" => 'k',
+        "none" => 'n',
+        "aat" => 'r',
+        "sears" => 's',
+        "rvm" => 'v',
+        "other" | "local" => 'z',
+        c if c.len() == 1 && c.chars().all(|c| c.is_ascii_lowercase()) => c.chars().next().unwrap(),
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+/// Selects non-deleted bib or authority records, oldest first unless
+/// configured otherwise, or runs a caller-provided query.
+#[derive(Debug, Clone, Default)]
+pub struct Exporter {
+    min_id: Option<i64>,
+    max_id: Option<i64>,
+    newest_first: bool,
+    query: Option<String>,
+    record_type: RecordType,
+    thesauri: Vec<char>,
+    /// Inclusive (first, last) main heading tag ranges.
+    heading_tags: Vec<(String, String)>,
+}
+
+impl Exporter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Only export records whose ID is >= min_id.
+    pub fn set_min_id(&mut self, min_id: i64) {
+        self.min_id = Some(min_id);
+    }
+
+    /// Only export records whose ID is < max_id.
+    pub fn set_max_id(&mut self, max_id: i64) {
+        self.max_id = Some(max_id);
+    }
+
+    /// Order by create date, newest first.
+    pub fn set_newest_first(&mut self, newest_first: bool) {
+        self.newest_first = newest_first;
+    }
+
+    /// Replace the record selection with this SQL.  Each row must
+    /// have a column named "marc".
+    pub fn set_query(&mut self, sql: &str) {
+        self.query = Some(sql.to_string());
+    }
+
+    /// Export bib records, the default, or authority records.
+    pub fn set_record_type(&mut self, record_type: RecordType) {
+        self.record_type = record_type;
+    }
+
+    /// Only export authority records using this thesaurus, per the
+    /// rec_descriptor.  Repeatable.  Applies to authority exports.
+    pub fn add_thesaurus(&mut self, code: char) {
+        self.thesauri.push(code);
+    }
+
+    /// Only export authority records whose 1XX main heading tag is
+    /// in this range, e.g. "100-151", "150" or "1XX".  Repeatable.
+    /// Applies to authority exports.
+    pub fn add_heading_tags(&mut self, range: &str) -> Result<(), String> {
+        let (first, last) = match range.split_once('-') {
+            Some((f, l)) => (f.trim().to_string(), l.trim().to_string()),
+            None if range.ends_with("XX") || range.ends_with("xx") => {
+                (format!("{}00", &range[..1]), format!("{}99", &range[..1]))
+            }
+            None => (range.trim().to_string(), range.trim().to_string()),
+        };
+
+        for tag in [&first, &last] {
+            if tag.len() != 3 || !tag.starts_with('1') || !tag.chars().all(|c| c.is_ascii_digit()) {
+                return Err(format!("Invalid heading tag range: {range}"));
+            }
+        }
+
+        self.heading_tags.push((first, last));
+
+        Ok(())
+    }
+
+    pub fn sql(&self) -> String {
+        if let Some(sql) = &self.query {
+            return sql.to_string();
+        }
+
+        let (select, from) = match self.record_type {
+            RecordType::Bib => ("SELECT bre.marc", "FROM biblio.record_entry bre"),
+            RecordType::Authority => ("SELECT are.marc", "FROM authority.record_entry are"),
+        };
+
+        let mut filter = match self.record_type {
+            RecordType::Bib => String::from("WHERE NOT bre.deleted"),
+            RecordType::Authority => String::from("WHERE NOT are.deleted"),
+        };
+
+        if let Some(min_id) = self.min_id {
+            filter = format!("{} AND id >= {}", filter, min_id);
+        }
+
+        if let Some(max_id) = self.max_id {
+            filter = format!("{} AND id < {}", filter, max_id);
+        }
+
+        if self.record_type == RecordType::Authority {
+            filter += &self.authority_filter();
+        }
+
+        let order_by = match self.newest_first {
+            true => "ORDER BY create_date DESC",
+            false => "ORDER BY create_date ASC",
+        };
+
+        format!("{select} {from} {filter} {order_by}")
+    }
+
+    fn authority_filter(&self) -> String {
+        let mut filter = String::new();
+
+        if !self.thesauri.is_empty() {
+            let codes: Vec<String> = self.thesauri.iter().map(|c| format!("'{c}'")).collect();
+            filter += &format!(
+                " AND EXISTS (SELECT 1 FROM authority.rec_descriptor ard \
+                WHERE ard.record = are.id AND ard.thesaurus IN ({}))",
+                codes.join(",")
+            );
+        }
+
+        if !self.heading_tags.is_empty() {
+            let ranges: Vec<String> = self
+                .heading_tags
+                .iter()
+                .map(|(first, last)| format!("afr.tag BETWEEN '{first}' AND '{last}'"))
+                .collect();
+            filter += &format!(
+                " AND EXISTS (SELECT 1 FROM authority.full_rec afr \
+                WHERE afr.record = are.id AND ({}))",
+                ranges.join(" OR ")
+            );
+        }
+
+        filter
+    }
+
+    /// Pass every selected record to the sink.  Returns the number of
+    /// records exported.
+    pub fn run(
+        &self,
+        con: &mut DatabaseConnection,
+        sink: &mut dyn RecordSink,
+    ) -> Result<u64, String> {
+        let sql = self.sql();
+
+        // Binary COPY has no column names, so select the marc column
+        // from the query.  Subquery order is kept in the output.
+        let sql = format!(
+            "COPY (SELECT q.marc FROM ({}) q) TO STDOUT (FORMAT binary)",
+            sql.trim().trim_end_matches(';')
+        );
+
+        let mut reader = match con.client().copy_out(&sql) {
+            Ok(r) => CopyReader::new(r)?,
+            Err(e) => return Err(format!("Error selecting records: {e}")),
+        };
+
+        sink.begin()?;
+
+        let mut count = 0;
+        while let Some(value) = reader.next_value()? {
+            let marc_xml = match value {
+                Some(v) => v,
+                None => continue,
+            };
+
+            match std::str::from_utf8(marc_xml) {
+                Ok(xml) => sink.write_record(xml)?,
+                Err(e) => return Err(format!("Record is not UTF-8: {e}")),
+            }
+
+            count += 1;
+        }
+
+        sink.finish()?;
+
+        Ok(count)
+    }
+}
+
+/// Reads single-column rows from binary COPY output.
+struct CopyReader<R: BufRead> {
+    reader: R,
+    /// Reused for every value, so reading allocates only as values
+    /// outgrow it.
+    buf: Vec<u8>,
+}
+
+impl<R: BufRead> CopyReader<R> {
+    fn new(mut reader: R) -> Result<Self, String> {
+        let mut signature = [0; 11];
+        read_exact(&mut reader, &mut signature)?;
+
+        if signature != COPY_SIGNATURE {
+            return Err("Unexpected COPY output signature".to_string());
+        }
+
+        let mut copy = CopyReader {
+            reader,
+            buf: Vec::new(),
+        };
+
+        // Flags, then a header extension to skip.
+        read_i32(&mut copy.reader)?;
+        let ext_len = read_i32(&mut copy.reader)?;
+        copy.fill(ext_len.max(0) as usize)?;
+
+        Ok(copy)
+    }
+
+    /// Read the next len bytes into buf.
+    fn fill(&mut self, len: usize) -> Result<&[u8], String> {
+        self.buf.resize(len, 0);
+        read_exact(&mut self.reader, &mut self.buf)?;
+        Ok(&self.buf)
+    }
+
+    /// The next row's value, Some(None) for NULL, or None after the
+    /// last row.  The value is only valid until the next call.
+    fn next_value(&mut self) -> Result<Option<Option<&[u8]>>, String> {
+        let mut count = [0; 2];
+        read_exact(&mut self.reader, &mut count)?;
+
+        match i16::from_be_bytes(count) {
+            -1 => return Ok(None),
+            1 => {}
+            n => return Err(format!("Unexpected COPY field count: {n}")),
+        }
+
+        let len = read_i32(&mut self.reader)?;
+        if len < 0 {
+            return Ok(Some(None));
+        }
+
+        Ok(Some(Some(self.fill(len as usize)?)))
+    }
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), String> {
+    match reader.read_exact(buf) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error reading records: {e}")),
+    }
+}
+
+fn read_i32(reader: &mut impl Read) -> Result<i32, String> {
+    let mut buf = [0; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(i32::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use marcutil::Record;
+
+    const LEADER: &str = "00000nam a2200000 a 4500";
+
+    fn record_xml(prefix: &str, fields: &str) -> String {
+        format!(
+            r#"<{prefix}record xmlns{ns}="http://www.loc.gov/MARC21/slim">
+  <{prefix}leader>{LEADER}</{prefix}leader>
+  <{prefix}controlfield tag="001">ocm12345</{prefix}controlfield>
+  {fields}
+</{prefix}record>"#,
+            ns = prefix
+                .strip_suffix(':')
+                .map(|p| format!(":{p}"))
+                .unwrap_or_default()
+        )
+    }
+
+    /// The encoder must produce the bytes marcutil does.
+    fn assert_encodes_like_marcutil(xml: &str) {
+        let record = Record::from_xml(xml).next().expect("record");
+        let expected = record.to_binary().unwrap();
+
+        let mut encoder = BinaryEncoder::default();
+        assert_eq!(encoder.encode(xml).unwrap(), &expected[..]);
+    }
+
+    #[test]
+    fn encodes_fields_and_subfields() {
+        assert_encodes_like_marcutil(&record_xml(
+            "",
+            r#"<datafield tag="245" ind1="1" ind2="0">
+    <subfield code="a">Bleak house /</subfield>
+    <subfield code="c">Charles Dickens.</subfield>
+  </datafield>
+  <datafield tag="650" ind1=" " ind2="0"><subfield code="a">London (England)</subfield></datafield>"#,
+        ));
+    }
+
+    #[test]
+    fn encodes_entities() {
+        assert_encodes_like_marcutil(&record_xml(
+            "",
+            r#"<datafield tag="245" ind1="0" ind2="0">
+    <subfield code="a">Pride &amp; prejudice &lt;large print&gt; &quot;&apos;</subfield>
+    <subfield code="b">Caf&#233; &#x65E5;&#X672C;</subfield>
+  </datafield>"#,
+        ));
+    }
+
+    #[test]
+    fn encodes_cdata() {
+        assert_encodes_like_marcutil(&record_xml(
+            "",
+            r#"<datafield tag="500" ind1=" " ind2=" ">
+    <subfield code="a"><![CDATA[x < y & <b>bold</b>]]> after</subfield>
+  </datafield>"#,
+        ));
+    }
+
+    #[test]
+    fn encodes_prefixed_elements() {
+        assert_encodes_like_marcutil(&record_xml(
+            "marc:",
+            r#"<marc:datafield tag="100" ind1="1" ind2=" ">
+    <marc:subfield code="a">Austen, Jane.</marc:subfield>
+  </marc:datafield>"#,
+        ));
+    }
+
+    #[test]
+    fn encodes_empty_elements() {
+        assert_encodes_like_marcutil(&record_xml(
+            "",
+            r#"<datafield tag="500" ind1=" " ind2=" "/>
+  <datafield tag="590" ind1=" " ind2=" "><subfield code="a"/><subfield code="b">kept</subfield></datafield>"#,
+        ));
+    }
+
+    #[test]
+    fn encodes_multibyte_utf8() {
+        // Lengths and offsets count bytes, not characters.
+        assert_encodes_like_marcutil(&record_xml(
+            "",
+            r#"<datafield tag="245" ind1="0" ind2="0">
+    <subfield code="a">Straße ĉu 日本語の本 🎉</subfield>
+  </datafield>
+  <datafield tag="246" ind1="3" ind2=" "><subfield code="a">Ελληνικά</subfield></datafield>"#,
+        ));
+    }
+
+    #[test]
+    fn rejects_records_over_99999_bytes() {
+        let field = format!(
+            r#"<datafield tag="500" ind1=" " ind2=" "><subfield code="a">{}</subfield></datafield>"#,
+            "x".repeat(9000)
+        );
+        let xml = record_xml("", &field.repeat(12));
+
+        let record = Record::from_xml(&xml).next().expect("record");
+        assert!(record.to_binary().is_err());
+
+        let mut encoder = BinaryEncoder::default();
+        assert!(encoder.encode(&xml).is_err());
+
+        // The encoder is reusable after an error.
+        let xml = record_xml("", &field);
+        assert_encodes_like_marcutil(&xml);
+    }
+
+    #[test]
+    fn reads_binary_copy_rows() {
+        let mut copy = COPY_SIGNATURE.to_vec();
+        copy.extend(0i32.to_be_bytes());
+        copy.extend(3i32.to_be_bytes());
+        copy.extend(b"ext");
+
+        for value in [Some(&b"<record/>"[..]), None, Some(b"ab")] {
+            copy.extend(1i16.to_be_bytes());
+            match value {
+                Some(v) => {
+                    copy.extend((v.len() as i32).to_be_bytes());
+                    copy.extend(v);
+                }
+                None => copy.extend((-1i32).to_be_bytes()),
+            }
+        }
+        copy.extend((-1i16).to_be_bytes());
+
+        let mut reader = CopyReader::new(&copy[..]).unwrap();
+
+        assert_eq!(reader.next_value().unwrap(), Some(Some(&b"<record/>"[..])));
+        assert_eq!(reader.next_value().unwrap(), Some(None));
+        // A shorter value after a longer one reuses the buffer.
+        assert_eq!(reader.next_value().unwrap(), Some(Some(&b"ab"[..])));
+        assert_eq!(reader.next_value().unwrap(), None);
+
+        assert!(CopyReader::new(&b"PGCOPY\n\xff"[..]).is_err());
+        assert!(CopyReader::new(&b"NOTCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0"[..]).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! Minimal HTTP/1.1 server for the egutil network services.
+//!
+//! Only what our services need is supported: GET and form-encoded
+//! POST requests, one request per connection, and fully buffered
+//! responses.
+use crate::signals::{self, Shutdown};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use threadpool::ThreadPool;
+
+/// Refuse request bodies larger than this.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// How often an idle listener checks for shutdown.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A parsed HTTP request.
+pub struct Request {
+    pub method: String,
+    /// Request path minus the query string.
+    pub path: String,
+    /// Header names are lowercased.
+    pub headers: HashMap<String, String>,
+    /// Query string and form body parameters, in the order received.
+    pub params: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Value of the first parameter with the provided name.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Number of times a parameter appears in the request.
+    pub fn param_count(&self, name: &str) -> usize {
+        self.params.iter().filter(|(k, _)| k.eq(name)).count()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|v| v.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            content_type: content_type.to_string(),
+            body,
+        }
+    }
+
+    pub fn xml(body: String) -> Self {
+        Response::new(200, "text/xml; charset=utf-8", body.into_bytes())
+    }
+
+    pub fn text(status: u16, body: &str) -> Self {
+        Response::new(
+            status,
+            "text/plain; charset=utf-8",
+            body.as_bytes().to_vec(),
+        )
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decode a percent-encoded URL component, treating '+' as a space.
+pub fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]);
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Percent-encode a value for use in a URL.
+pub fn url_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out += &format!("%{:02X}", b),
+        }
+    }
+    out
+}
+
+/// Parse a query string or form body into key/value pairs.
+pub fn parse_params(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+
+    for part in s.split('&') {
+        if part.is_empty() {
+            continue;
+        }
+
+        let (k, v) = match part.split_once('=') {
+            Some((k, v)) => (k, v),
+            None => (part, ""),
+        };
+
+        params.push((url_decode(k), url_decode(v)));
+    }
+
+    params
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, Response> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    if reader.read_line(&mut line).is_err() {
+        return Err(Response::text(400, "Cannot read request"));
+    }
+
+    let mut parts = line.split_whitespace();
+
+    let method = match parts.next() {
+        Some(m) => m.to_uppercase(),
+        None => return Err(Response::text(400, "Invalid request line")),
+    };
+
+    let target = match parts.next() {
+        Some(t) => t.to_string(),
+        None => return Err(Response::text(400, "Invalid request line")),
+    };
+
+    let mut headers = HashMap::new();
+
+    loop {
+        let mut hline = String::new();
+        match reader.read_line(&mut hline) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(_) => return Err(Response::text(400, "Cannot read headers")),
+        }
+
+        let hline = hline.trim_end();
+        if hline.is_empty() {
+            break;
+        }
+
+        if let Some((k, v)) = hline.split_once(':') {
+            headers.insert(k.trim().to_lowercase(), v.trim().to_string());
+        }
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p.to_string(), q.to_string()),
+        None => (target, String::new()),
+    };
+
+    let mut params = parse_params(&query);
+
+    let length: usize = match headers.get("content-length") {
+        Some(l) => l.parse().unwrap_or(0),
+        None => 0,
+    };
+
+    if length > MAX_BODY_SIZE {
+        return Err(Response::text(413, "Request body too large"));
+    }
+
+    if length > 0 {
+        let mut body = vec![0; length];
+        if reader.read_exact(&mut body).is_err() {
+            return Err(Response::text(400, "Cannot read request body"));
+        }
+
+        let is_form = match headers.get("content-type") {
+            Some(ct) => ct.starts_with("application/x-www-form-urlencoded"),
+            None => false,
+        };
+
+        if is_form {
+            params.append(&mut parse_params(&String::from_utf8_lossy(&body)));
+        }
+    }
+
+    Ok(Request {
+        method,
+        path: url_decode(&path),
+        headers,
+        params,
+    })
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> Result<(), String> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        status_text(response.status),
+        response.content_type,
+        response.body.len()
+    );
+
+    if let Err(e) = stream.write_all(head.as_bytes()) {
+        return Err(format!("Error writing response: {e}"));
+    }
+
+    if let Err(e) = stream.write_all(&response.body) {
+        return Err(format!("Error writing response: {e}"));
+    }
+
+    Ok(())
+}
+
+fn handle_connection<F>(mut stream: TcpStream, handler: &F)
+where
+    F: Fn(&Request) -> Response,
+{
+    let response = match read_request(&stream) {
+        Ok(request) => {
+            debug!("{} {}", request.method, request.path);
+            match request.method.as_str() {
+                "GET" | "POST" => handler(&request),
+                _ => Response::text(405, "Method not allowed"),
+            }
+        }
+        Err(r) => r,
+    };
+
+    if let Err(e) = write_response(&mut stream, &response) {
+        error!("{e}");
+    }
+}
+
+/// Listen on the provided address and pass each request to the handler,
+/// which runs within a pool of max_threads worker threads.
+///
+/// Runs until SIGINT or SIGTERM, then returns once the requests in
+/// progress are answered.
+pub fn serve<F>(address: &str, max_threads: usize, handler: F) -> Result<(), String>
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let shutdown = signals::install()?;
+
+    let listener = match TcpListener::bind(address) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("Cannot listen on {address}: {e}")),
+    };
+
+    serve_listener(listener, max_threads, Some(&shutdown), handler)
+}
+
+/// As serve(), with a listener the caller has already bound.  Without
+/// a Shutdown, runs until the process exits.
+pub fn serve_listener<F>(
+    listener: TcpListener,
+    max_threads: usize,
+    shutdown: Option<&Shutdown>,
+    handler: F,
+) -> Result<(), String>
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let pool = ThreadPool::new(max_threads);
+    let handler = Arc::new(handler);
+
+    // Accept without blocking, so shutdown is seen while idle.
+    if shutdown.is_some() {
+        if let Err(e) = listener.set_nonblocking(true) {
+            return Err(format!("Cannot configure listener: {e}"));
+        }
+    }
+
+    while !shutdown.is_some_and(|s| s.requested()) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = stream.set_nonblocking(false) {
+                    error!("Cannot configure connection: {e}");
+                    continue;
+                }
+                let h = handler.clone();
+                pool.execute(move || handle_connection(stream, h.as_ref()));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(s) = shutdown {
+                    s.sleep(ACCEPT_POLL_INTERVAL);
+                }
+            }
+            Err(e) => error!("Error accepting connection: {e}"),
+        }
+    }
+
+    info!("Shutting down; answering {} requests", pool.active_count());
+    pool.join();
+
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Evergreen IDL (fm_IDL.xml) class definitions.
+//!
+//! Only the persistence details needed to build queries are loaded:
+//! tables, fields, primary keys and links.
+use std::collections::HashMap;
+use std::fs;
+
+const PERSIST_NS: &str = "http://open-ils.org/spec/opensrf/IDL/persistence/v1";
+const REPORTER_NS: &str = "http://open-ils.org/spec/opensrf/IDL/reporter/v1";
+
+pub const DEFAULT_IDL_FILE: &str = "/openils/conf/fm_IDL.xml";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelType {
+    HasA,
+    HasMany,
+    MightHave,
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    /// Reporter datatype, e.g. id, int, text, bool, timestamp, link.
+    pub datatype: String,
+    /// Virtual fields have no database column.
+    pub is_virtual: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub field: String,
+    pub reltype: RelType,
+    /// Field on the linked class.
+    pub key: String,
+    pub class: String,
+    pub map: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub classname: String,
+    pub fieldmapper: Option<String>,
+    pub tablename: Option<String>,
+    /// SQL for classes backed by a query instead of a table.
+    pub source_definition: Option<String>,
+    pub pkey: Option<String>,
+    pub fields: Vec<Field>,
+    pub links: Vec<Link>,
+    /// Classes with no table or source definition.
+    pub is_virtual: bool,
+}
+
+impl Class {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name.eq(name))
+    }
+
+    pub fn link(&self, field: &str) -> Option<&Link> {
+        self.links.iter().find(|l| l.field.eq(field))
+    }
+
+    /// Fields with database columns, in IDL order.
+    pub fn real_fields(&self) -> impl Iterator<Item = &Field> {
+        self.fields.iter().filter(|f| !f.is_virtual)
+    }
+
+    /// SQL usable in a FROM clause.
+    pub fn source(&self) -> Option<String> {
+        if let Some(ref t) = self.tablename {
+            return Some(t.to_string());
+        }
+        self.source_definition
+            .as_ref()
+            .map(|s| format!("({})", s.trim()))
+    }
+}
+
+pub struct Idl {
+    classes: HashMap<String, Class>,
+}
+
+impl Idl {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(xml) => Idl::from_xml(&xml),
+            Err(e) => Err(format!("Cannot read IDL file {path}: {e}")),
+        }
+    }
+
+    pub fn from_xml(xml: &str) -> Result<Self, String> {
+        let doc = match roxmltree::Document::parse(xml) {
+            Ok(d) => d,
+            Err(e) => return Err(format!("Cannot parse IDL: {e}")),
+        };
+
+        let mut classes = HashMap::new();
+
+        for node in doc.root_element().children() {
+            if !node.is_element() || node.tag_name().name() != "class" {
+                continue;
+            }
+
+            let classname = match node.attribute("id") {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+
+            let mut class = Class {
+                classname: classname.to_string(),
+                fieldmapper: node
+                    .attribute((
+                        "http://open-ils.org/spec/opensrf/IDL/objects/v1",
+                        "fieldmapper",
+                    ))
+                    .map(|s| s.to_string()),
+                tablename: node
+                    .attribute((PERSIST_NS, "tablename"))
+                    .map(|s| s.to_string()),
+                source_definition: None,
+                pkey: None,
+                fields: Vec::new(),
+                links: Vec::new(),
+                is_virtual: node.attribute((PERSIST_NS, "virtual")) == Some("true"),
+            };
+
+            for child in node.children().filter(|n| n.is_element()) {
+                match child.tag_name().name() {
+                    "fields" => {
+                        class.pkey = child
+                            .attribute((PERSIST_NS, "primary"))
+                            .map(|s| s.to_string());
+
+                        for f in child.children().filter(|n| n.is_element()) {
+                            let name = match f.attribute("name") {
+                                Some(n) => n.to_string(),
+                                None => continue,
+                            };
+
+                            class.fields.push(Field {
+                                name,
+                                datatype: f
+                                    .attribute((REPORTER_NS, "datatype"))
+                                    .unwrap_or("text")
+                                    .to_string(),
+                                is_virtual: f.attribute((PERSIST_NS, "virtual")) == Some("true"),
+                            });
+                        }
+                    }
+                    "links" => {
+                        for l in child.children().filter(|n| n.is_element()) {
+                            let reltype = match l.attribute("reltype") {
+                                Some("has_a") => RelType::HasA,
+                                Some("has_many") => RelType::HasMany,
+                                Some("might_have") => RelType::MightHave,
+                                _ => continue,
+                            };
+
+                            let (field, key, class_ref) = match (
+                                l.attribute("field"),
+                                l.attribute("key"),
+                                l.attribute("class"),
+                            ) {
+                                (Some(f), Some(k), Some(c)) => (f, k, c),
+                                _ => continue,
+                            };
+
+                            class.links.push(Link {
+                                field: field.to_string(),
+                                reltype,
+                                key: key.to_string(),
+                                class: class_ref.to_string(),
+                                map: l
+                                    .attribute("map")
+                                    .filter(|m| !m.is_empty())
+                                    .map(|m| m.to_string()),
+                            });
+                        }
+                    }
+                    "source_definition" => {
+                        class.source_definition = child.text().map(|s| s.to_string());
+                    }
+                    _ => {}
+                }
+            }
+
+            if class.tablename.is_none() && class.source_definition.is_none() {
+                class.is_virtual = true;
+            }
+
+            classes.insert(classname, class);
+        }
+
+        Ok(Idl { classes })
+    }
+
+    pub fn class(&self, classname: &str) -> Option<&Class> {
+        self.classes.get(classname)
+    }
+
+    pub fn classes(&self) -> impl Iterator<Item = &Class> {
+        self.classes.values()
+    }
+}
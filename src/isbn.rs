@@ -0,0 +1,226 @@
+//! ISBN and UPC normalization and check digits.
+//!
+//! Values are taken as catalogers enter them, e.g.
+//! "0-306-40615-2 (pbk.)": hyphens within the number are dropped and
+//! anything after it is a qualifier.
+
+/// The number and qualifier of a value like "0-306-40615-2 (pbk.)".
+/// The number keeps only digits and X, upper cased.
+pub fn split(value: &str) -> (String, Option<&str>) {
+    let value = value.trim();
+
+    let (number, qualifier) = match value.split_once(char::is_whitespace) {
+        Some((n, q)) => (n, Some(q.trim())),
+        None => (value, None),
+    };
+
+    let number = number
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    (number, qualifier.filter(|q| !q.is_empty()))
+}
+
+/// The ISBN in a value, without hyphens or qualifier, when it is 10
+/// or 13 characters long.  Check digits are not verified.
+pub fn normalize(value: &str) -> Option<String> {
+    let (number, _) = split(value);
+
+    match number.len() {
+        10 | 13 => Some(number),
+        _ => None,
+    }
+}
+
+fn isbn10_check(digits: &[u32]) -> char {
+    let sum: u32 = digits
+        .iter()
+        .take(9)
+        .enumerate()
+        .map(|(idx, d)| d * (10 - idx as u32))
+        .sum();
+
+    match (11 - sum % 11) % 11 {
+        10 => 'X',
+        d => char::from_digit(d, 10).unwrap_or('0'),
+    }
+}
+
+/// EAN-13 and UPC-A check digit for the digits before it.
+fn ean_check(digits: &[u32]) -> char {
+    // Weights alternate 3 and 1 from the digit nearest the check digit.
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, d)| match idx % 2 {
+            0 => d * 3,
+            _ => *d,
+        })
+        .sum();
+
+    char::from_digit((10 - sum % 10) % 10, 10).unwrap_or('0')
+}
+
+fn digits(number: &str, len: usize) -> Option<Vec<u32>> {
+    number.chars().take(len).map(|c| c.to_digit(10)).collect()
+}
+
+/// True if the value is an ISBN-10 or ISBN-13 with a correct check
+/// digit.  Hyphens and trailing qualifiers are ignored.
+pub fn is_valid(value: &str) -> bool {
+    let isbn = match normalize(value) {
+        Some(i) => i,
+        None => return false,
+    };
+
+    let last = isbn.chars().last().unwrap_or(' ');
+
+    match isbn.len() {
+        10 => match digits(&isbn, 9) {
+            Some(d) => isbn10_check(&d) == last,
+            None => false,
+        },
+        _ => match digits(&isbn, 12) {
+            Some(d) => last.is_ascii_digit() && ean_check(&d) == last,
+            None => false,
+        },
+    }
+}
+
+/// The ISBN-13 form of a valid ISBN-10 or ISBN-13.
+pub fn to_isbn13(value: &str) -> Option<String> {
+    if !is_valid(value) {
+        return None;
+    }
+
+    let isbn = normalize(value)?;
+    if isbn.len() == 13 {
+        return Some(isbn);
+    }
+
+    let base = format!("978{}", &isbn[..9]);
+    let check = ean_check(&digits(&base, 12)?);
+
+    Some(format!("{base}{check}"))
+}
+
+/// The ISBN-10 form of a valid ISBN-10, or ISBN-13 starting with 978.
+/// 979 ISBNs have no ISBN-10 form.
+pub fn to_isbn10(value: &str) -> Option<String> {
+    if !is_valid(value) {
+        return None;
+    }
+
+    let isbn = normalize(value)?;
+    if isbn.len() == 10 {
+        return Some(isbn);
+    }
+
+    let base = isbn.strip_prefix("978")?[..9].to_string();
+    let check = isbn10_check(&digits(&base, 9)?);
+
+    Some(format!("{base}{check}"))
+}
+
+/// The other form of a valid ISBN: ISBN-13 for an ISBN-10 and
+/// ISBN-10 for a 978 ISBN-13.
+pub fn counterpart(value: &str) -> Option<String> {
+    match normalize(value)?.len() {
+        10 => to_isbn13(value),
+        _ => to_isbn10(value),
+    }
+}
+
+/// The UPC in a value, digits only, when it is 12 digits long.
+pub fn normalize_upc(value: &str) -> Option<String> {
+    let (number, _) = split(value);
+
+    match number.len() == 12 && number.chars().all(|c| c.is_ascii_digit()) {
+        true => Some(number),
+        false => None,
+    }
+}
+
+/// True if the value is a UPC-A with a correct check digit.
+pub fn upc_valid(value: &str) -> bool {
+    let upc = match normalize_upc(value) {
+        Some(u) => u,
+        None => return false,
+    };
+
+    match digits(&upc, 11) {
+        Some(d) => upc.ends_with(ean_check(&d)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_qualifiers() {
+        assert_eq!(
+            split(" 0-306-40615-2 (pbk.) "),
+            ("0306406152".to_string(), Some("(pbk.)"))
+        );
+        assert_eq!(split("080442957x"), ("080442957X".to_string(), None));
+        assert_eq!(
+            normalize("978-0-306-40615-7 hardcover"),
+            Some("9780306406157".to_string())
+        );
+        assert_eq!(normalize("0-306-4061"), None);
+    }
+
+    #[test]
+    fn validates_check_digits() {
+        assert!(is_valid("0-306-40615-2"));
+        assert!(is_valid("0-8044-2957-X"));
+        assert!(is_valid("0-8044-2957-x (v. 1)"));
+        assert!(is_valid("978-0-306-40615-7"));
+        assert!(is_valid("979-10-90636-07-1"));
+
+        assert!(!is_valid("0-306-40615-3"));
+        assert!(!is_valid("978-0-306-40615-2"));
+        // X is only an ISBN-10 check digit.
+        assert!(!is_valid("978030640615X"));
+        assert!(!is_valid("X306406152"));
+        assert!(!is_valid(""));
+    }
+
+    #[test]
+    fn converts_between_forms() {
+        assert_eq!(
+            to_isbn13("0-306-40615-2"),
+            Some("9780306406157".to_string())
+        );
+        assert_eq!(to_isbn10("9780306406157"), Some("0306406152".to_string()));
+        assert_eq!(
+            to_isbn13("0-8044-2957-X"),
+            Some("9780804429573".to_string())
+        );
+        assert_eq!(to_isbn10("9780804429573"), Some("080442957X".to_string()));
+
+        assert_eq!(counterpart("0306406152"), Some("9780306406157".to_string()));
+        assert_eq!(counterpart("9780306406157"), Some("0306406152".to_string()));
+
+        // 979 ISBNs have no ISBN-10, and invalid ISBNs no other form.
+        assert_eq!(to_isbn10("9791090636071"), None);
+        assert_eq!(to_isbn13("0306406153"), None);
+    }
+
+    #[test]
+    fn validates_upcs() {
+        assert_eq!(
+            normalize_upc("036000291452 (disc)"),
+            Some("036000291452".to_string())
+        );
+        assert_eq!(normalize_upc("03600029145X"), None);
+        assert!(upc_valid("036000291452"));
+        assert!(!upc_valid("036000291453"));
+        assert!(!upc_valid("36000291452"));
+    }
+}
@@ -0,0 +1,389 @@
+//! Postgres-backed job queues.
+//!
+//! Jobs are rows in egutil.job holding a JSON payload.  Any number of
+//! workers on any number of hosts may claim from the same queue; each
+//! claim skips rows another worker has locked, so a job runs once.
+//!
+//! A failed job is retried after a delay which doubles per attempt.
+//! After its last attempt it is marked dead, and stays put until
+//! requeued, e.g. with job-queue --retry-dead.  A worker which dies
+//! mid-job loses its claim once the lease runs out, and the job is
+//! claimed again.
+use crate::db::DatabaseConnection;
+use crate::signals::Shutdown;
+use log::{error, info, warn};
+use serde_json::Value;
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Pending,
+    Running,
+    Complete,
+    Dead,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Pending => "pending",
+            State::Running => "running",
+            State::Complete => "complete",
+            State::Dead => "dead",
+        }
+    }
+}
+
+/// A claimed job.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub queue: String,
+    pub payload: Value,
+    /// Including this one.
+    pub attempts: i32,
+    pub max_attempts: i32,
+}
+
+/// Create egutil.job if needed.
+pub fn create_table(con: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.job (
+            id              BIGSERIAL PRIMARY KEY,
+            queue           TEXT NOT NULL,
+            payload         JSONB NOT NULL,
+            state           TEXT NOT NULL DEFAULT 'pending'
+                CHECK (state IN ('pending', 'running', 'complete', 'dead')),
+            attempts        INT NOT NULL DEFAULT 0,
+            max_attempts    INT NOT NULL DEFAULT 5,
+            run_after       TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            claimed_by      TEXT,
+            lease_expires   TIMESTAMPTZ,
+            last_error      TEXT,
+            create_time     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            complete_time   TIMESTAMPTZ
+        );
+        CREATE INDEX IF NOT EXISTS job_queue_state_idx
+            ON egutil.job (queue, state, run_after);
+    "#;
+
+    match con.client().batch_execute(sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot create egutil.job: {e}")),
+    }
+}
+
+/// A named queue and its retry policy.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    name: String,
+    max_attempts: i32,
+    retry_delay: Duration,
+    lease: Duration,
+}
+
+impl Queue {
+    pub fn new(name: &str) -> Self {
+        Queue {
+            name: name.to_string(),
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(60),
+            lease: Duration::from_secs(600),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Attempts before a job is marked dead, for jobs enqueued from
+    /// here on.
+    pub fn set_max_attempts(&mut self, max_attempts: i32) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Delay before the first retry.  Doubles with each attempt.
+    pub fn set_retry_delay(&mut self, retry_delay: Duration) {
+        self.retry_delay = retry_delay;
+    }
+
+    /// How long a claim lasts before another worker may take the job.
+    /// Should comfortably exceed the longest job.
+    pub fn set_lease(&mut self, lease: Duration) {
+        self.lease = lease;
+    }
+
+    /// Returns the new job's ID.
+    pub fn enqueue(&self, con: &mut DatabaseConnection, payload: &Value) -> Result<i64, String> {
+        self.enqueue_after(con, payload, Duration::from_secs(0))
+    }
+
+    /// Enqueue a job which may not run until delay has passed.
+    pub fn enqueue_after(
+        &self,
+        con: &mut DatabaseConnection,
+        payload: &Value,
+        delay: Duration,
+    ) -> Result<i64, String> {
+        let sql = r#"
+            INSERT INTO egutil.job (queue, payload, max_attempts, run_after)
+            VALUES ($1, $2::TEXT::JSONB, $3, NOW() + ($4::BIGINT * '1 second'::INTERVAL))
+            RETURNING id
+        "#;
+
+        let delay = delay.as_secs() as i64;
+
+        match con.client().query_one(
+            sql,
+            &[&self.name, &payload.to_string(), &self.max_attempts, &delay],
+        ) {
+            Ok(row) => Ok(row.get("id")),
+            Err(e) => Err(format!("Error queueing {} job: {e}", self.name)),
+        }
+    }
+
+    /// Claim the next runnable job, if any: a pending job whose time
+    /// has come, or a running job whose lease has expired.
+    pub fn claim(&self, con: &mut DatabaseConnection, worker: &str) -> Result<Option<Job>, String> {
+        let sql = r#"
+            UPDATE egutil.job SET
+                state = 'running',
+                attempts = attempts + 1,
+                claimed_by = $2,
+                lease_expires = NOW() + ($3::BIGINT * '1 second'::INTERVAL)
+            WHERE id = (
+                SELECT id FROM egutil.job
+                WHERE queue = $1
+                    AND (
+                        (state = 'pending' AND run_after <= NOW())
+                        OR (state = 'running' AND lease_expires < NOW())
+                    )
+                ORDER BY run_after, id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, queue, payload::TEXT AS payload, attempts, max_attempts
+        "#;
+
+        let lease = self.lease.as_secs() as i64;
+
+        let row = match con.client().query_opt(sql, &[&self.name, &worker, &lease]) {
+            Ok(Some(r)) => r,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(format!("Error claiming {} job: {e}", self.name)),
+        };
+
+        let payload: String = row.get("payload");
+
+        Ok(Some(Job {
+            id: row.get("id"),
+            queue: row.get("queue"),
+            // Stored as JSONB, so always valid.
+            payload: serde_json::from_str(&payload).unwrap_or(Value::Null),
+            attempts: row.get("attempts"),
+            max_attempts: row.get("max_attempts"),
+        }))
+    }
+
+    /// Push back the lease on a long-running job.
+    pub fn extend_lease(&self, con: &mut DatabaseConnection, job: &Job) -> Result<(), String> {
+        let sql = r#"
+            UPDATE egutil.job
+            SET lease_expires = NOW() + ($2::BIGINT * '1 second'::INTERVAL)
+            WHERE id = $1 AND state = 'running'
+        "#;
+
+        let lease = self.lease.as_secs() as i64;
+
+        match con.client().execute(sql, &[&job.id, &lease]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error extending lease on job {}: {e}", job.id)),
+        }
+    }
+
+    pub fn complete(&self, con: &mut DatabaseConnection, job: &Job) -> Result<(), String> {
+        let sql = r#"
+            UPDATE egutil.job SET
+                state = 'complete',
+                complete_time = NOW(),
+                lease_expires = NULL,
+                last_error = NULL
+            WHERE id = $1
+        "#;
+
+        match con.client().execute(sql, &[&job.id]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error completing job {}: {e}", job.id)),
+        }
+    }
+
+    /// Record a failure, scheduling a retry or marking the job dead.
+    /// Returns the job's new state.
+    pub fn fail(
+        &self,
+        con: &mut DatabaseConnection,
+        job: &Job,
+        error: &str,
+    ) -> Result<State, String> {
+        let sql = r#"
+            UPDATE egutil.job SET
+                state = CASE WHEN attempts >= max_attempts THEN 'dead' ELSE 'pending' END,
+                run_after = NOW() + ($3::BIGINT * POWER(2, attempts - 1) * '1 second'::INTERVAL),
+                lease_expires = NULL,
+                last_error = $2
+            WHERE id = $1
+            RETURNING state
+        "#;
+
+        let delay = self.retry_delay.as_secs() as i64;
+
+        let state: String = match con.client().query_one(sql, &[&job.id, &error, &delay]) {
+            Ok(row) => row.get("state"),
+            Err(e) => return Err(format!("Error failing job {}: {e}", job.id)),
+        };
+
+        match state.as_str() {
+            "dead" => Ok(State::Dead),
+            _ => Ok(State::Pending),
+        }
+    }
+
+    /// Requeue dead jobs with fresh attempts.  Returns the number
+    /// requeued.
+    pub fn retry_dead(&self, con: &mut DatabaseConnection) -> Result<u64, String> {
+        let sql = r#"
+            UPDATE egutil.job SET state = 'pending', attempts = 0, run_after = NOW()
+            WHERE queue = $1 AND state = 'dead'
+        "#;
+
+        match con.client().execute(sql, &[&self.name]) {
+            Ok(n) => Ok(n),
+            Err(e) => Err(format!("Error requeueing {} jobs: {e}", self.name)),
+        }
+    }
+
+    /// Delete jobs completed more than days ago.  Returns the number
+    /// deleted.
+    pub fn purge_complete(&self, con: &mut DatabaseConnection, days: i32) -> Result<u64, String> {
+        let sql = r#"
+            DELETE FROM egutil.job
+            WHERE queue = $1
+                AND state = 'complete'
+                AND complete_time < NOW() - ($2::INT * '1 day'::INTERVAL)
+        "#;
+
+        match con.client().execute(sql, &[&self.name, &days]) {
+            Ok(n) => Ok(n),
+            Err(e) => Err(format!("Error purging {} jobs: {e}", self.name)),
+        }
+    }
+
+    /// Number of jobs in each state.
+    pub fn counts(&self, con: &mut DatabaseConnection) -> Result<Vec<(String, i64)>, String> {
+        let sql = r#"
+            SELECT state, COUNT(*) AS count
+            FROM egutil.job
+            WHERE queue = $1
+            GROUP BY state
+            ORDER BY state
+        "#;
+
+        match con.client().query(sql, &[&self.name]) {
+            Ok(rows) => Ok(rows
+                .iter()
+                .map(|r| (r.get("state"), r.get("count")))
+                .collect()),
+            Err(e) => Err(format!("Error counting {} jobs: {e}", self.name)),
+        }
+    }
+}
+
+/// Does the work for one job.  Returning Err fails the attempt.
+pub trait Handler {
+    fn handle(&mut self, con: &mut DatabaseConnection, job: &Job) -> Result<(), String>;
+}
+
+/// Claims and runs jobs from one queue until shutdown, or until the
+/// queue is empty when draining.
+pub struct Worker {
+    queue: Queue,
+    id: String,
+    poll_interval: Duration,
+    drain: bool,
+}
+
+impl Worker {
+    pub fn new(queue: Queue) -> Self {
+        Worker {
+            queue,
+            id: worker_id(),
+            poll_interval: Duration::from_secs(5),
+            drain: false,
+        }
+    }
+
+    /// Recorded in egutil.job.claimed_by.  Defaults to HOSTNAME:PID.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = id.to_string();
+    }
+
+    /// How long to wait when the queue is empty.
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+
+    /// Return once no runnable jobs remain, instead of polling.
+    pub fn set_drain(&mut self, drain: bool) {
+        self.drain = drain;
+    }
+
+    /// Returns the number of jobs handled, successfully or not.  Only
+    /// database errors from the queue itself end the loop early.
+    pub fn run(
+        &self,
+        con: &mut DatabaseConnection,
+        handler: &mut dyn Handler,
+        shutdown: &Shutdown,
+    ) -> Result<u64, String> {
+        let mut handled = 0;
+
+        while !shutdown.requested() {
+            let job = match self.queue.claim(con, &self.id)? {
+                Some(j) => j,
+                None if self.drain => break,
+                None => {
+                    shutdown.sleep(self.poll_interval);
+                    continue;
+                }
+            };
+
+            info!(
+                "Running {} job {} (attempt {} of {})",
+                job.queue, job.id, job.attempts, job.max_attempts
+            );
+
+            match handler.handle(con, &job) {
+                Ok(()) => self.queue.complete(con, &job)?,
+                Err(e) => match self.queue.fail(con, &job, &e)? {
+                    State::Dead => error!("{} job {} is dead: {e}", job.queue, job.id),
+                    _ => warn!("{} job {} failed, will retry: {e}", job.queue, job.id),
+                },
+            }
+
+            handled += 1;
+        }
+
+        Ok(handled)
+    }
+}
+
+fn worker_id() -> String {
+    let host = fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|_| "localhost".to_string());
+
+    format!("{host}:{}", process::id())
+}
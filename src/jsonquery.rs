@@ -0,0 +1,929 @@
+//! Compile cstore/pcrud style JSON queries to parameterized SQL.
+//!
+//! Supports the commonly used parts of the json_query syntax:
+//! select (with transforms and aggregates), from (with joins
+//! resolved from IDL links), where/having (including -and, -or,
+//! -not, -exists and +class references), order_by, limit, offset
+//! and distinct.
+//!
+//! ```text
+//! let idl = Idl::from_file(idl::DEFAULT_IDL_FILE)?;
+//! let query = jsonquery::compile(&idl, &json!({
+//!     "select": {"bre": ["id", "tcn_value"]},
+//!     "from": {"bre": "acn"},
+//!     "where": {"+acn": {"owning_lib": 4}, "deleted": "f"},
+//!     "limit": 10
+//! }))?;
+//! let rows = con.client().query(&query.sql, &query.params())?;
+//! ```
+use crate::idl::{Class, Idl, RelType};
+use postgres::types::ToSql;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Compiled SQL plus its parameter values.
+///
+/// Values are sent as text and cast to the column type within the SQL.
+pub struct Query {
+    pub sql: String,
+    pub values: Vec<Option<String>>,
+}
+
+impl Query {
+    /// Parameters suitable for postgres::Client::query() and friends.
+    pub fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.values
+            .iter()
+            .map(|v| v as &(dyn ToSql + Sync))
+            .collect()
+    }
+}
+
+/// Compile a json_query style query.
+pub fn compile(idl: &Idl, query: &Value) -> Result<Query, String> {
+    let mut compiler = Compiler {
+        idl,
+        values: Vec::new(),
+        aliases: HashMap::new(),
+    };
+
+    let sql = compiler.query(query)?;
+
+    Ok(Query {
+        sql,
+        values: compiler.values,
+    })
+}
+
+/// Compile a search in the style of pcrud/cstore search methods: a
+/// where clause relative to one class, plus order_by/limit/offset
+/// options.
+pub fn compile_search(
+    idl: &Idl,
+    classname: &str,
+    filter: &Value,
+    options: &Value,
+) -> Result<Query, String> {
+    let mut query = serde_json::Map::new();
+
+    query.insert("from".to_string(), Value::String(classname.to_string()));
+    query.insert("where".to_string(), filter.clone());
+
+    for key in ["order_by", "limit", "offset"] {
+        if let Some(v) = options.get(key) {
+            query.insert(key.to_string(), v.clone());
+        }
+    }
+
+    compile(idl, &Value::Object(query))
+}
+
+/// Double-quote a query-supplied alias, refusing anything unusual.
+fn ident(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match valid {
+        true => Ok(format!("\"{s}\"")),
+        false => Err(format!("Invalid identifier: {s}")),
+    }
+}
+
+/// Validate a transform function name, e.g. lower or evergreen.lowercase.
+fn function_name(s: &str) -> Result<&str, String> {
+    let valid = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+
+    match valid {
+        true => Ok(s),
+        false => Err(format!("Invalid transform: {s}")),
+    }
+}
+
+fn value_text(v: &Value) -> Option<String> {
+    match v {
+        Value::Null => None,
+        Value::Bool(b) => Some(if *b { "t" } else { "f" }.to_string()),
+        Value::String(s) => Some(s.to_string()),
+        v => Some(v.to_string()),
+    }
+}
+
+/// Postgres type for an IDL reporter datatype.
+fn sql_type(datatype: &str) -> &'static str {
+    match datatype {
+        "id" | "int" | "org_unit" => "BIGINT",
+        "float" | "money" | "number" => "NUMERIC",
+        "bool" => "BOOL",
+        "timestamp" => "TIMESTAMPTZ",
+        "interval" => "INTERVAL",
+        _ => "TEXT",
+    }
+}
+
+/// Result type of well known transforms, when it differs from the column.
+fn transform_type(transform: &str) -> Option<&'static str> {
+    match transform {
+        "lower" | "upper" | "btrim" | "substring" | "evergreen.lowercase" => Some("TEXT"),
+        "date" => Some("DATE"),
+        "age" => Some("INTERVAL"),
+        "count" => Some("BIGINT"),
+        _ => None,
+    }
+}
+
+const AGGREGATES: &[&str] = &[
+    "count",
+    "sum",
+    "avg",
+    "min",
+    "max",
+    "array_agg",
+    "string_agg",
+];
+
+struct Compiler<'a> {
+    idl: &'a Idl,
+    values: Vec<Option<String>>,
+    /// Query alias to class name.
+    aliases: HashMap<String, String>,
+}
+
+impl<'a> Compiler<'a> {
+    fn class(&self, classname: &str) -> Result<&'a Class, String> {
+        match self.idl.class(classname) {
+            Some(c) if !c.is_virtual => Ok(c),
+            Some(_) => Err(format!("Class {classname} is not queryable")),
+            None => Err(format!("No such class: {classname}")),
+        }
+    }
+
+    fn alias_class(&self, alias: &str) -> Result<&'a Class, String> {
+        match self.aliases.get(alias) {
+            Some(c) => self.class(c),
+            None => Err(format!("Unknown class or alias in query: {alias}")),
+        }
+    }
+
+    /// Quoted column reference plus the Postgres type of the column.
+    fn column(&self, alias: &str, field: &str) -> Result<(String, String), String> {
+        let class = self.alias_class(alias)?;
+
+        let f = match class.field(field) {
+            Some(f) if !f.is_virtual => f,
+            _ => {
+                return Err(format!(
+                    "No such field {field} on class {}",
+                    class.classname
+                ))
+            }
+        };
+
+        // Link fields take the type of the linked class primary key.
+        let mut datatype = f.datatype.as_str();
+        if datatype == "link" {
+            datatype = "text";
+            if let Some(link) = class.link(field) {
+                if let Some(target) = self.idl.class(&link.class) {
+                    if let Some(tf) = target.field(&link.key) {
+                        datatype = tf.datatype.as_str();
+                    }
+                }
+            }
+        }
+
+        Ok((
+            format!("{}.\"{}\"", ident(alias)?, f.name),
+            sql_type(datatype).to_string(),
+        ))
+    }
+
+    fn placeholder(&mut self, value: &Value, sql_type: &str) -> String {
+        self.values.push(value_text(value));
+        format!("${}::TEXT::{sql_type}", self.values.len())
+    }
+
+    fn query(&mut self, query: &Value) -> Result<String, String> {
+        let (core, from) = self.build_from(&query["from"])?;
+
+        let mut sql = String::from("SELECT ");
+
+        if query["distinct"].as_bool().unwrap_or(false) {
+            sql += "DISTINCT ";
+        }
+
+        let (columns, group_by) = self.select_clause(&core, &query["select"])?;
+        sql += &columns;
+        sql += &format!("\nFROM {from}");
+
+        if !query["where"].is_null() {
+            let w = self.where_clause(&query["where"], &core, "AND")?;
+            if !w.is_empty() {
+                sql += &format!("\nWHERE {w}");
+            }
+        }
+
+        if !group_by.is_empty() {
+            sql += &format!("\nGROUP BY {}", group_by.join(", "));
+        }
+
+        if !query["having"].is_null() {
+            let h = self.where_clause(&query["having"], &core, "AND")?;
+            if !h.is_empty() {
+                sql += &format!("\nHAVING {h}");
+            }
+        }
+
+        let order = self.order_by(&query["order_by"])?;
+        if !order.is_empty() {
+            sql += &format!("\nORDER BY {}", order.join(", "));
+        }
+
+        if let Some(limit) = query["limit"].as_u64() {
+            sql += &format!("\nLIMIT {limit}");
+        }
+
+        if let Some(offset) = query["offset"].as_u64() {
+            sql += &format!("\nOFFSET {offset}");
+        }
+
+        Ok(sql)
+    }
+
+    /// Returns the core class alias and the FROM SQL.
+    fn build_from(&mut self, from: &Value) -> Result<(String, String), String> {
+        let (core, joins) = match from {
+            Value::String(c) => (c.to_string(), &Value::Null),
+            Value::Object(o) if o.len() == 1 => {
+                let (c, j) = o.iter().next().unwrap();
+                (c.to_string(), j)
+            }
+            _ => return Err("Query 'from' must name one core class".to_string()),
+        };
+
+        let class = self.class(&core)?;
+        self.aliases.insert(core.to_string(), core.to_string());
+
+        let mut sql = format!("{} AS {}", self.source(class)?, ident(&core)?);
+        self.joins(&core, joins, &mut sql)?;
+
+        Ok((core, sql))
+    }
+
+    fn source(&self, class: &Class) -> Result<String, String> {
+        match class.source() {
+            Some(s) => Ok(s),
+            None => Err(format!("Class {} has no table", class.classname)),
+        }
+    }
+
+    fn joins(&mut self, left: &str, spec: &Value, sql: &mut String) -> Result<(), String> {
+        let empty = Value::Object(serde_json::Map::new());
+
+        let entries: Vec<(String, Value)> = match spec {
+            Value::Null => return Ok(()),
+            Value::String(c) => vec![(c.to_string(), empty)],
+            Value::Array(list) => {
+                let mut v = Vec::new();
+                for item in list {
+                    match item.as_str() {
+                        Some(c) => v.push((c.to_string(), empty.clone())),
+                        None => return Err("Join lists must contain class names".to_string()),
+                    }
+                }
+                v
+            }
+            Value::Object(o) => o.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+            _ => return Err(format!("Invalid join for {left}")),
+        };
+
+        for (alias, def) in entries {
+            let classname = def["class"].as_str().unwrap_or(&alias).to_string();
+            let right = self.class(&classname)?;
+            let left_class = self.alias_class(left)?;
+
+            let (field, fkey) = self.join_keys(left_class, right, &def)?;
+
+            let kind = match def["type"].as_str().unwrap_or("inner") {
+                "left" => "LEFT",
+                "right" => "RIGHT",
+                "full" => "FULL",
+                _ => "INNER",
+            };
+
+            self.aliases
+                .insert(alias.to_string(), classname.to_string());
+
+            let mut on = format!(
+                "{}.\"{field}\" = {}.\"{fkey}\"",
+                ident(&alias)?,
+                ident(left)?
+            );
+
+            if !def["filter"].is_null() {
+                let op = match def["filter_op"].as_str() {
+                    Some("or") | Some("OR") => "OR",
+                    _ => "AND",
+                };
+                let filter = self.where_clause(&def["filter"], &alias, "AND")?;
+                on = format!("{on} {op} ({filter})");
+            }
+
+            *sql += &format!(
+                "\n    {kind} JOIN {} AS {} ON ({on})",
+                self.source(right)?,
+                ident(&alias)?
+            );
+
+            self.joins(&alias, &def["join"], sql)?;
+        }
+
+        Ok(())
+    }
+
+    /// Join columns as (right field, left field), from the query or
+    /// from IDL links between the two classes.
+    fn join_keys(
+        &self,
+        left: &Class,
+        right: &Class,
+        def: &Value,
+    ) -> Result<(String, String), String> {
+        let field = def["field"].as_str();
+        let fkey = def["fkey"].as_str();
+
+        let keys = match (field, fkey) {
+            (Some(f), Some(k)) => Some((f.to_string(), k.to_string())),
+            (Some(f), None) => right
+                .link(f)
+                .filter(|l| l.class == left.classname)
+                .map(|l| l.key.to_string())
+                .or_else(|| left.pkey.clone())
+                .map(|k| (f.to_string(), k)),
+            (None, Some(k)) => left
+                .link(k)
+                .filter(|l| l.class == right.classname)
+                .map(|l| (l.key.to_string(), k.to_string())),
+            // A has_a link joins its field to the key on the linked
+            // class.  has_many and might_have fields are virtual, so
+            // those links join the key to the linking class pkey.
+            (None, None) => right
+                .links
+                .iter()
+                .find(|l| l.class == left.classname && l.map.is_none())
+                .and_then(|l| match l.reltype {
+                    RelType::HasA => Some((l.field.to_string(), l.key.to_string())),
+                    _ => right.pkey.clone().map(|pk| (pk, l.key.to_string())),
+                })
+                .or_else(|| {
+                    left.links
+                        .iter()
+                        .find(|l| l.class == right.classname && l.map.is_none())
+                        .and_then(|l| match l.reltype {
+                            RelType::HasA => Some((l.key.to_string(), l.field.to_string())),
+                            _ => left.pkey.clone().map(|pk| (l.key.to_string(), pk)),
+                        })
+                }),
+        };
+
+        let (field, fkey) = match keys {
+            Some(k) => k,
+            None => {
+                return Err(format!(
+                    "No link between {} and {}",
+                    left.classname, right.classname
+                ))
+            }
+        };
+
+        for (class, f) in [(right, &field), (left, &fkey)] {
+            if class.field(f).map(|f| f.is_virtual).unwrap_or(true) {
+                return Err(format!("No such field {f} on class {}", class.classname));
+            }
+        }
+
+        Ok((field, fkey))
+    }
+
+    /// Returns the column list and GROUP BY positions.
+    fn select_clause(
+        &mut self,
+        core: &str,
+        select: &Value,
+    ) -> Result<(String, Vec<String>), String> {
+        let mut columns = Vec::new();
+        let mut plain = Vec::new();
+        let mut has_aggregate = false;
+
+        let specs: Vec<(String, Value)> = match select {
+            Value::Null => vec![(core.to_string(), Value::String("*".to_string()))],
+            Value::Object(o) => o.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+            _ => return Err("Query 'select' must be an object".to_string()),
+        };
+
+        for (alias, fields) in specs {
+            let class = self.alias_class(&alias)?;
+
+            let fields: Vec<Value> = match fields {
+                Value::String(ref s) if s == "*" => class
+                    .real_fields()
+                    .map(|f| Value::String(f.name.to_string()))
+                    .collect(),
+                Value::Array(a) => a,
+                _ => return Err(format!("Invalid select list for {alias}")),
+            };
+
+            for spec in fields {
+                let (expr, label, aggregate) = self.select_column(&alias, &spec)?;
+
+                columns.push(format!("{expr} AS {}", ident(&label)?));
+
+                if aggregate {
+                    has_aggregate = true;
+                } else {
+                    plain.push(columns.len().to_string());
+                }
+            }
+        }
+
+        let group_by = if has_aggregate { plain } else { Vec::new() };
+
+        Ok((columns.join(", "), group_by))
+    }
+
+    /// Returns the column expression, its label and whether it aggregates.
+    fn select_column(
+        &mut self,
+        alias: &str,
+        spec: &Value,
+    ) -> Result<(String, String, bool), String> {
+        let field = match spec {
+            Value::String(f) => return Ok((self.column(alias, f)?.0, f.to_string(), false)),
+            Value::Object(_) => match spec["column"].as_str() {
+                Some(c) => c,
+                None => return Err(format!("Select entry for {alias} has no column")),
+            },
+            _ => return Err(format!("Invalid select entry for {alias}")),
+        };
+
+        let (mut expr, _) = self.column(alias, field)?;
+        let mut aggregate = spec["aggregate"].as_bool().unwrap_or(false);
+
+        if let Some(t) = spec["transform"].as_str() {
+            let func = function_name(t)?;
+            aggregate = aggregate || AGGREGATES.contains(&func.to_lowercase().as_str());
+
+            if spec["distinct"].as_bool().unwrap_or(false) {
+                expr = format!("DISTINCT {expr}");
+            }
+
+            let mut args = vec![expr];
+            if let Some(params) = spec["params"].as_array() {
+                for p in params {
+                    args.push(self.placeholder(p, "TEXT"));
+                }
+            }
+
+            expr = format!("{func}({})", args.join(", "));
+        }
+
+        let label = spec["alias"].as_str().unwrap_or(field).to_string();
+
+        Ok((expr, label, aggregate))
+    }
+
+    fn where_clause(
+        &mut self,
+        clause: &Value,
+        alias: &str,
+        joiner: &str,
+    ) -> Result<String, String> {
+        let mut parts = Vec::new();
+
+        match clause {
+            Value::Object(o) => {
+                for (key, value) in o {
+                    parts.push(self.where_entry(key, value, alias)?);
+                }
+            }
+            Value::Array(list) => {
+                for item in list {
+                    parts.push(self.where_clause(item, alias, "AND")?);
+                }
+            }
+            _ => return Err("Where clauses must be objects or arrays".to_string()),
+        }
+
+        let parts: Vec<String> = parts.into_iter().filter(|p| !p.is_empty()).collect();
+
+        Ok(match parts.len() {
+            0 => String::new(),
+            1 => parts[0].to_string(),
+            _ => format!("({})", parts.join(&format!(" {joiner} "))),
+        })
+    }
+
+    fn where_entry(&mut self, key: &str, value: &Value, alias: &str) -> Result<String, String> {
+        match key {
+            "-or" => self.where_clause(value, alias, "OR"),
+            "-and" => self.where_clause(value, alias, "AND"),
+            "-not" => Ok(format!("NOT {}", self.where_clause(value, alias, "AND")?)),
+            "-exists" | "-not-exists" => {
+                let sub = self.subquery(value)?;
+                let not = if key == "-not-exists" { "NOT " } else { "" };
+                Ok(format!("{not}EXISTS ({sub})"))
+            }
+            k if k.starts_with('+') => self.where_clause(value, &k[1..], "AND"),
+            field => self.predicate(alias, field, value),
+        }
+    }
+
+    /// Compile a nested query which may refer to our aliases.
+    fn subquery(&mut self, query: &Value) -> Result<String, String> {
+        let saved = self.aliases.clone();
+        let sql = self.query(query);
+        self.aliases = saved;
+        sql
+    }
+
+    fn predicate(&mut self, alias: &str, field: &str, value: &Value) -> Result<String, String> {
+        let (col, col_type) = self.column(alias, field)?;
+
+        let ops = match value {
+            Value::Object(o) => o,
+            Value::Null => return Ok(format!("{col} IS NULL")),
+            Value::Array(list) => return self.in_list(&col, "IN", list, &col_type),
+            v => return Ok(format!("{col} = {}", self.placeholder(v, &col_type))),
+        };
+
+        let mut parts = Vec::new();
+
+        for (op, operand) in ops {
+            let op = op.to_lowercase();
+
+            // {"=": {"transform": "lower", "value": "x"}} applies
+            // the transform to the column.
+            let (col, col_type, operand) = match operand.get("transform").and_then(|t| t.as_str()) {
+                Some(t) => {
+                    let func = function_name(t)?;
+                    let ctype = transform_type(func).unwrap_or(&col_type).to_string();
+                    (format!("{func}({col})"), ctype, &operand["value"])
+                }
+                None => (col.to_string(), col_type.to_string(), operand),
+            };
+
+            let part = match op.as_str() {
+                "=" | "!=" | "<>" if operand.is_null() => match op.as_str() {
+                    "=" => format!("{col} IS NULL"),
+                    _ => format!("{col} IS NOT NULL"),
+                },
+                "=" | "!=" | "<>" | ">" | "<" | ">=" | "<=" | "like" | "ilike" | "~" | "~*"
+                | "!~" | "!~*" => format!(
+                    "{col} {} {}",
+                    op.to_uppercase(),
+                    self.placeholder(operand, &col_type)
+                ),
+                "in" | "not in" => match operand {
+                    Value::Array(list) => {
+                        self.in_list(&col, &op.to_uppercase(), list, &col_type)?
+                    }
+                    Value::Object(_) => {
+                        format!("{col} {} ({})", op.to_uppercase(), self.subquery(operand)?)
+                    }
+                    _ => return Err(format!("{op} requires a list or subquery")),
+                },
+                "between" | "not between" => match operand.as_array() {
+                    Some(a) if a.len() == 2 => format!(
+                        "{col} {} {} AND {}",
+                        op.to_uppercase(),
+                        self.placeholder(&a[0], &col_type),
+                        self.placeholder(&a[1], &col_type)
+                    ),
+                    _ => return Err(format!("{op} requires two values")),
+                },
+                _ => return Err(format!("Unsupported operator: {op}")),
+            };
+
+            parts.push(part);
+        }
+
+        Ok(match parts.len() {
+            0 => String::new(),
+            1 => parts.remove(0),
+            _ => format!("({})", parts.join(" AND ")),
+        })
+    }
+
+    fn in_list(
+        &mut self,
+        col: &str,
+        op: &str,
+        list: &[Value],
+        col_type: &str,
+    ) -> Result<String, String> {
+        if list.is_empty() {
+            // Nothing is IN an empty list.
+            return Ok(if op == "IN" { "FALSE" } else { "TRUE" }.to_string());
+        }
+
+        let items: Vec<String> = list.iter().map(|v| self.placeholder(v, col_type)).collect();
+
+        Ok(format!("{col} {op} ({})", items.join(", ")))
+    }
+
+    fn order_item(&self, alias: &str, field: &str, spec: &Value) -> Result<String, String> {
+        let (mut col, _) = self.column(alias, field)?;
+
+        let (direction, transform) = match spec {
+            Value::String(d) => (d.as_str(), None),
+            Value::Object(_) => (
+                spec["direction"].as_str().unwrap_or("asc"),
+                spec["transform"].as_str(),
+            ),
+            _ => ("asc", None),
+        };
+
+        if let Some(t) = transform {
+            col = format!("{}({col})", function_name(t)?);
+        }
+
+        match direction.to_lowercase().as_str() {
+            "desc" => Ok(format!("{col} DESC")),
+            "asc" => Ok(col),
+            d => Err(format!("Invalid sort direction: {d}")),
+        }
+    }
+
+    fn order_by(&self, order: &Value) -> Result<Vec<String>, String> {
+        let mut items = Vec::new();
+
+        match order {
+            Value::Null => {}
+            // [{"class": "bre", "field": "id", "direction": "desc"}]
+            Value::Array(list) => {
+                for o in list {
+                    let alias = o["class"].as_str().unwrap_or("");
+                    let field = o["field"].as_str().unwrap_or("");
+                    items.push(self.order_item(alias, field, o)?);
+                }
+            }
+            // {"bre": {"id": "desc"}} or {"bre": ["id"]} or {"bre": "id"}
+            Value::Object(o) => {
+                for (alias, spec) in o {
+                    match spec {
+                        Value::String(f) => items.push(self.order_item(alias, f, &Value::Null)?),
+                        Value::Array(fields) => {
+                            for f in fields {
+                                let f = f.as_str().unwrap_or("");
+                                items.push(self.order_item(alias, f, &Value::Null)?);
+                            }
+                        }
+                        Value::Object(fields) => {
+                            for (f, dir) in fields {
+                                items.push(self.order_item(alias, f, dir)?);
+                            }
+                        }
+                        _ => return Err(format!("Invalid order_by for {alias}")),
+                    }
+                }
+            }
+            _ => return Err("Invalid order_by".to_string()),
+        }
+
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const IDL: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<IDL xmlns="http://opensrf.org/spec/IDL/base/v1"
+    xmlns:oils_persist="http://open-ils.org/spec/opensrf/IDL/persistence/v1"
+    xmlns:reporter="http://open-ils.org/spec/opensrf/IDL/reporter/v1">
+  <class id="bre" oils_persist:tablename="biblio.record_entry">
+    <fields oils_persist:primary="id">
+      <field name="id" reporter:datatype="id"/>
+      <field name="tcn_value" reporter:datatype="text"/>
+      <field name="deleted" reporter:datatype="bool"/>
+      <field name="call_numbers" oils_persist:virtual="true" reporter:datatype="link"/>
+    </fields>
+    <links>
+      <link field="call_numbers" reltype="has_many" key="record" map="" class="acn"/>
+    </links>
+  </class>
+  <class id="acn" oils_persist:tablename="asset.call_number">
+    <fields oils_persist:primary="id">
+      <field name="id" reporter:datatype="id"/>
+      <field name="record" reporter:datatype="link"/>
+      <field name="owning_lib" reporter:datatype="org_unit"/>
+      <field name="label" reporter:datatype="text"/>
+      <field name="copies" oils_persist:virtual="true" reporter:datatype="link"/>
+    </fields>
+    <links>
+      <link field="record" reltype="has_a" key="id" map="" class="bre"/>
+      <link field="copies" reltype="has_many" key="call_number" map="" class="acp"/>
+    </links>
+  </class>
+  <class id="acp" oils_persist:tablename="asset.copy">
+    <fields oils_persist:primary="id">
+      <field name="id" reporter:datatype="id"/>
+      <field name="call_number" reporter:datatype="link"/>
+      <field name="barcode" reporter:datatype="text"/>
+    </fields>
+    <links>
+      <link field="call_number" reltype="has_a" key="id" map="" class="acn"/>
+    </links>
+  </class>
+</IDL>"#;
+
+    fn to_sql(query: Value) -> String {
+        let idl = Idl::from_xml(IDL).unwrap();
+        compile(&idl, &query).unwrap().sql
+    }
+
+    #[test]
+    fn join_from_has_a_link() {
+        let sql = to_sql(json!({"from": {"acn": {"acp": {}}}}));
+        assert!(
+            sql.contains(r#"ON ("acp"."call_number" = "acn"."id")"#),
+            "{sql}"
+        );
+
+        let sql = to_sql(json!({"from": {"bre": "acn"}}));
+        assert!(sql.contains(r#"ON ("acn"."record" = "bre"."id")"#), "{sql}");
+    }
+
+    #[test]
+    fn join_from_has_many_link() {
+        // Only acn links to acp, by its virtual copies field.
+        let sql = to_sql(json!({"from": {"acp": {"acn": {}}}}));
+        assert!(
+            sql.contains(r#"ON ("acn"."id" = "acp"."call_number")"#),
+            "{sql}"
+        );
+
+        let sql = to_sql(json!({"from": {"acn": "bre"}}));
+        assert!(sql.contains(r#"ON ("bre"."id" = "acn"."record")"#), "{sql}");
+    }
+
+    #[test]
+    fn join_with_field_and_fkey() {
+        let sql = to_sql(json!({
+            "from": {"acp": {"acn": {"field": "id", "fkey": "call_number", "type": "left"}}}
+        }));
+        assert!(
+            sql.contains(
+                r#"LEFT JOIN asset.call_number AS "acn" ON ("acn"."id" = "acp"."call_number")"#
+            ),
+            "{sql}"
+        );
+    }
+
+    fn to_query(query: Value) -> Result<Query, String> {
+        let idl = Idl::from_xml(IDL).unwrap();
+        compile(&idl, &query)
+    }
+
+    #[test]
+    fn compiles_a_full_query() {
+        let query = to_query(json!({
+            "select": {"bre": ["id", "tcn_value"]},
+            "from": "bre",
+            "where": {"deleted": "f"},
+            "order_by": {"bre": {"id": "desc"}},
+            "limit": 10,
+            "offset": 20
+        }))
+        .unwrap();
+
+        assert_eq!(
+            query.sql,
+            "SELECT \"bre\".\"id\" AS \"id\", \"bre\".\"tcn_value\" AS \"tcn_value\"\
+            \nFROM biblio.record_entry AS \"bre\"\
+            \nWHERE \"bre\".\"deleted\" = $1::TEXT::BOOL\
+            \nORDER BY \"bre\".\"id\" DESC\
+            \nLIMIT 10\
+            \nOFFSET 20"
+        );
+        assert_eq!(query.values, vec![Some("f".to_string())]);
+        assert_eq!(query.params().len(), 1);
+    }
+
+    #[test]
+    fn compiles_predicates() {
+        let query = to_query(json!({
+            "from": "acn",
+            "where": {
+                "-or": [{"label": {"ilike": "QA%"}}, {"owning_lib": [4, 5]}],
+                "record": null
+            }
+        }))
+        .unwrap();
+
+        assert!(
+            query.sql.ends_with(
+                "WHERE ((\"acn\".\"label\" ILIKE $1::TEXT::TEXT \
+            OR \"acn\".\"owning_lib\" IN ($2::TEXT::BIGINT, $3::TEXT::BIGINT)) \
+            AND \"acn\".\"record\" IS NULL)"
+            ),
+            "{}",
+            query.sql
+        );
+        assert_eq!(
+            query.values,
+            vec![
+                Some("QA%".to_string()),
+                Some("4".to_string()),
+                Some("5".to_string())
+            ]
+        );
+
+        let query = to_query(json!({"from": "acn", "where": {"id": {"in": []}}})).unwrap();
+        assert!(query.sql.ends_with("WHERE FALSE"), "{}", query.sql);
+
+        let query = to_query(json!({
+            "from": "acp",
+            "where": {"id": {"between": [1, 9], "!=": null}}
+        }))
+        .unwrap();
+        assert!(query.sql.ends_with(
+            "WHERE (\"acp\".\"id\" IS NOT NULL AND \"acp\".\"id\" BETWEEN $1::TEXT::BIGINT AND $2::TEXT::BIGINT)"
+        ), "{}", query.sql);
+    }
+
+    #[test]
+    fn compiles_class_references_and_transforms() {
+        let query = to_query(json!({
+            "from": {"bre": "acn"},
+            "where": {
+                "+acn": {"owning_lib": 4},
+                "tcn_value": {"=": {"transform": "lower", "value": "abc"}}
+            }
+        }))
+        .unwrap();
+
+        assert!(query.sql.ends_with(
+            "WHERE (\"acn\".\"owning_lib\" = $1::TEXT::BIGINT AND lower(\"bre\".\"tcn_value\") = $2::TEXT::TEXT)"
+        ), "{}", query.sql);
+    }
+
+    #[test]
+    fn groups_by_plain_columns_with_aggregates() {
+        let query = to_query(json!({
+            "select": {"acn": ["owning_lib", {"column": "id", "transform": "count", "alias": "total"}]},
+            "from": "acn"
+        }))
+        .unwrap();
+
+        assert_eq!(
+            query.sql,
+            "SELECT \"acn\".\"owning_lib\" AS \"owning_lib\", count(\"acn\".\"id\") AS \"total\"\
+            \nFROM asset.call_number AS \"acn\"\
+            \nGROUP BY 1"
+        );
+    }
+
+    #[test]
+    fn compiles_searches() {
+        let idl = Idl::from_xml(IDL).unwrap();
+        let query = compile_search(
+            &idl,
+            "acp",
+            &json!({"barcode": "123"}),
+            &json!({"limit": 5}),
+        )
+        .unwrap();
+
+        assert_eq!(
+            query.sql,
+            "SELECT \"acp\".\"id\" AS \"id\", \"acp\".\"call_number\" AS \"call_number\", \
+            \"acp\".\"barcode\" AS \"barcode\"\
+            \nFROM asset.copy AS \"acp\"\
+            \nWHERE \"acp\".\"barcode\" = $1::TEXT::TEXT\
+            \nLIMIT 5"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        let invalid = [
+            json!({"from": "nope"}),
+            json!({"from": "bre", "where": {"nope": 1}}),
+            json!({"select": {"bre": ["call_numbers"]}, "from": "bre"}),
+            json!({"from": "bre", "where": {"id": {"regexp": "1"}}}),
+            json!({"from": "bre", "order_by": {"bre": {"id": "desc; DROP TABLE x"}}}),
+            json!({"from": "bre", "where": {"tcn_value": {"=": {"transform": "f(x)", "value": 1}}}}),
+            json!({"from": {"bre": {"x\"y": {"class": "acn"}}}}),
+            json!({"from": {"acp": "bre"}}),
+        ];
+
+        for query in invalid {
+            assert!(to_query(query.clone()).is_err(), "{query}");
+        }
+    }
+}
@@ -1 +1,36 @@
+pub mod audit;
+pub mod barcode;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod callnumber;
+pub mod cli;
+pub mod completion;
+pub mod conf;
+pub mod daemon;
+pub mod date;
 pub mod db;
+pub mod dryrun;
+pub mod edi;
+pub mod email;
+pub mod export;
+pub mod http;
+pub mod idl;
+pub mod isbn;
+pub mod jobs;
+pub mod jsonquery;
+pub mod lockfile;
+pub mod log;
+pub mod marc;
+pub mod metrics;
+pub mod money;
+pub mod penalty;
+pub mod reports;
+pub mod settings;
+pub mod signals;
+pub mod snapshot;
+pub mod tabular;
+pub mod template;
+pub mod testing;
+pub mod tools;
+pub mod xml;
+pub mod z3950;
@@ -0,0 +1,88 @@
+//! Lock files which keep two runs of a job from overlapping, e.g.
+//! when a cron job outlasts its interval.
+//!
+//! The lock file holds the PID of its owner and is removed when the
+//! Lock is dropped.  A lock whose owner is no longer running, e.g.
+//! after a crash, is stale and is replaced.
+use log::warn;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process;
+
+pub struct Lock {
+    path: String,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// PID recorded in an existing lock file.
+fn owner(path: &str) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn is_running(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// Take the lock at path, failing if a running process holds it.
+pub fn acquire(path: &str) -> Result<Lock, String> {
+    let pid = process::id();
+
+    // Write our PID to a private file, then link it into place, so
+    // the lock never exists without its owner.
+    let temp = format!("{path}.{pid}");
+
+    let result = fs::File::create(&temp).and_then(|mut f| writeln!(f, "{pid}"));
+    if let Err(e) = result {
+        return Err(format!("Cannot create lock file {temp}: {e}"));
+    }
+
+    // Once to take a free lock, and again after clearing a stale one.
+    let mut result = Err(format!("Cannot acquire lock {path}"));
+
+    for _ in 0..2 {
+        match fs::hard_link(&temp, path) {
+            Ok(()) => {
+                result = Ok(Lock {
+                    path: path.to_string(),
+                });
+                break;
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                result = Err(format!("Cannot create lock file {path}: {e}"));
+                break;
+            }
+        }
+
+        match owner(path) {
+            // Still ours, e.g. after a daemon re-executes itself.
+            Some(p) if p == pid => {
+                result = Ok(Lock {
+                    path: path.to_string(),
+                });
+                break;
+            }
+            Some(p) if is_running(p) => {
+                result = Err(format!("{path} is held by running process {p}"));
+                break;
+            }
+            Some(p) => warn!("Removing stale lock {path} left by process {p}"),
+            None => warn!("Removing unreadable lock {path}"),
+        }
+
+        if let Err(e) = fs::remove_file(path) {
+            result = Err(format!("Cannot remove stale lock {path}: {e}"));
+            break;
+        }
+    }
+
+    fs::remove_file(&temp).ok();
+
+    result
+}
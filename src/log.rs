@@ -0,0 +1,335 @@
+//! Logger for the egutil binaries.
+//!
+//! Writes text or JSON lines to stderr, a file or syslog.  Each line
+//! carries the thread plus any batch and record set for the thread
+//! with set_batch() and set_record(), and is tagged dry-run after
+//! set_dry_run().
+use crate::date::UtcTime;
+use ::log::{Level, LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::{process, thread};
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Syslog facility names and codes, RFC 5424.
+const FACILITIES: &[(&str, u8)] = &[
+    ("user", 1),
+    ("daemon", 3),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Stderr,
+    File(String),
+    Syslog(u8),
+}
+
+impl Destination {
+    /// "-" for stderr, "syslog" or "syslog:FACILITY", e.g.
+    /// syslog:local0, or a file path to append to.
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        if spec == "-" {
+            return Ok(Destination::Stderr);
+        }
+
+        let facility = match spec.strip_prefix("syslog") {
+            Some("") => "user",
+            Some(f) if f.starts_with(':') => &f[1..],
+            _ => return Ok(Destination::File(spec.to_string())),
+        };
+
+        match FACILITIES.iter().find(|(name, _)| *name == facility) {
+            Some((_, code)) => Ok(Destination::Syslog(*code)),
+            None => Err(format!("Unknown syslog facility: {facility}")),
+        }
+    }
+}
+
+/// Level filter with optional per-target levels, as in RUST_LOG,
+/// e.g. "warn,egutil=debug".  The longest matching target wins.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub fn new(level: LevelFilter) -> Self {
+        Filter {
+            default: level,
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut filter = Filter::new(LevelFilter::Error);
+
+        for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (target, level) = match part.split_once('=') {
+                Some((t, l)) => (Some(t), l),
+                None => (None, part),
+            };
+
+            let level: LevelFilter = match level.parse() {
+                Ok(l) => l,
+                Err(_) => return Err(format!("Invalid log level: {level}")),
+            };
+
+            match target {
+                Some(t) => filter.targets.push((t.to_string(), level)),
+                None => filter.default = level,
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| target.starts_with(t.as_str()))
+            .max_by_key(|(t, _)| t.len())
+            .map(|(_, l)| *l)
+            .unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, l)| *l)
+            .fold(self.default, |a, b| a.max(b))
+    }
+}
+
+#[derive(Default)]
+struct Context {
+    batch: Option<String>,
+    record: Option<i64>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Tag every thread's log lines as from a dry run.
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+/// Tag this thread's log lines with a batch, e.g. an ID range.
+pub fn set_batch(batch: Option<String>) {
+    CONTEXT.with(|c| c.borrow_mut().batch = batch);
+}
+
+/// Tag this thread's log lines with the record being processed.
+pub fn set_record(record: Option<i64>) {
+    CONTEXT.with(|c| c.borrow_mut().record = record);
+}
+
+pub fn clear_context() {
+    CONTEXT.with(|c| *c.borrow_mut() = Context::default());
+}
+
+/// Thread name where set, e.g. "main", otherwise its numeric ID.
+fn thread_label() -> String {
+    let current = thread::current();
+
+    match current.name() {
+        Some(n) => n.to_string(),
+        None => format!("{:?}", current.id())
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect(),
+    }
+}
+
+enum Output {
+    Stderr,
+    File(File),
+    Syslog(UnixDatagram, u8),
+}
+
+struct Logger {
+    filter: Filter,
+    format: Format,
+    ident: String,
+    output: Mutex<Output>,
+}
+
+impl Logger {
+    fn format_line(&self, record: &Record, syslog: bool) -> String {
+        let thread = thread_label();
+        let dry_run = DRY_RUN.load(Ordering::Relaxed);
+
+        CONTEXT.with(|c| {
+            let context = c.borrow();
+
+            if self.format == Format::Json {
+                let line = serde_json::json!({
+                    "time": UtcTime::now().to_iso8601(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "thread": thread,
+                    "batch": context.batch,
+                    "record": context.record,
+                    "dry_run": dry_run,
+                    "message": record.args().to_string(),
+                });
+                return line.to_string();
+            }
+
+            let mut tags = format!("thread={thread}");
+            if let Some(b) = &context.batch {
+                tags += &format!(" batch={b}");
+            }
+            if let Some(r) = context.record {
+                tags += &format!(" record={r}");
+            }
+            if dry_run {
+                tags += " dry-run";
+            }
+
+            // syslog adds its own timestamp.
+            let time = match syslog {
+                true => String::new(),
+                false => format!("{} ", UtcTime::now().to_iso8601()),
+            };
+
+            format!(
+                "{time}{:<5} [{tags}] {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        })
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut output = match self.output.lock() {
+            Ok(o) => o,
+            Err(_) => return,
+        };
+
+        // Logging errors have nowhere to go, so they are ignored.
+        match &mut *output {
+            Output::Stderr => {
+                let line = self.format_line(record, false);
+                writeln!(io::stderr(), "{line}").ok();
+            }
+            Output::File(f) => {
+                let line = self.format_line(record, false);
+                writeln!(f, "{line}").ok();
+            }
+            Output::Syslog(socket, facility) => {
+                let severity = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                let line = format!(
+                    "<{}>{}[{}]: {}",
+                    *facility as u32 * 8 + severity,
+                    self.ident,
+                    process::id(),
+                    self.format_line(record, true)
+                );
+                socket.send(line.as_bytes()).ok();
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut output) = self.output.lock() {
+            match &mut *output {
+                Output::Stderr => io::stderr().flush().ok(),
+                Output::File(f) => f.flush().ok(),
+                Output::Syslog(_, _) => None,
+            };
+        }
+    }
+}
+
+/// Install the logger.  ident names the program in syslog lines.
+pub fn init(
+    ident: &str,
+    filter: Filter,
+    format: Format,
+    destination: &Destination,
+) -> Result<(), String> {
+    let output = match destination {
+        Destination::Stderr => Output::Stderr,
+        Destination::File(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(f) => Output::File(f),
+            Err(e) => return Err(format!("Cannot open log file {path}: {e}")),
+        },
+        Destination::Syslog(facility) => {
+            let socket = match UnixDatagram::unbound() {
+                Ok(s) => s,
+                Err(e) => return Err(format!("Cannot create syslog socket: {e}")),
+            };
+            if let Err(e) = socket.connect(SYSLOG_SOCKET) {
+                return Err(format!("Cannot connect to {SYSLOG_SOCKET}: {e}"));
+            }
+            Output::Syslog(socket, *facility)
+        }
+    };
+
+    let max_level = filter.max_level();
+
+    let logger = Logger {
+        filter,
+        format,
+        ident: ident.to_string(),
+        output: Mutex::new(output),
+    };
+
+    if let Err(e) = ::log::set_boxed_logger(Box::new(logger)) {
+        return Err(format!("Cannot install logger: {e}"));
+    }
+
+    ::log::set_max_level(max_level);
+
+    Ok(())
+}
@@ -0,0 +1,821 @@
+//! MARC record helpers built on marcutil.
+use crate::isbn;
+use marcutil::{Field, Record, Subfield};
+use std::fs;
+
+pub mod rules;
+pub mod validate;
+
+/// Tags of fields kept from the existing record by merge().
+pub const LOCAL_TAGS: &[&str] = &["59X", "9XX"];
+
+/// Content of the first control field with the tag.
+pub fn control_value<'a>(record: &'a Record, tag: &str) -> Option<&'a str> {
+    record
+        .get_control_fields(tag)
+        .first()
+        .map(|cf| cf.content.as_str())
+}
+
+/// Leader character at pos, e.g. 6 for the type of record.
+pub fn leader_char(record: &Record, pos: usize) -> Option<char> {
+    record.leader.chars().nth(pos)
+}
+
+/// Characters start..end of the 008, e.g. 7..11 for Date 1.
+pub fn fixed_field(record: &Record, start: usize, end: usize) -> Option<&str> {
+    control_value(record, "008")?.get(start..end)
+}
+
+/// Leader/06, e.g. 'a' for language material.
+pub fn record_type(record: &Record) -> Option<char> {
+    leader_char(record, 6)
+}
+
+/// Leader/07, e.g. 'm' for a monograph.
+pub fn bib_level(record: &Record) -> Option<char> {
+    leader_char(record, 7)
+}
+
+/// 008/07-10, when set.
+pub fn date1(record: &Record) -> Option<&str> {
+    fixed_field(record, 7, 11).filter(|d| !d.trim().is_empty())
+}
+
+/// 008/35-37 language code, when set.
+pub fn language(record: &Record) -> Option<&str> {
+    fixed_field(record, 35, 38).filter(|l| l.trim().len() == 3)
+}
+
+/// Evergreen's TCN from the 901 $a, falling back to the 001.
+pub fn tcn(record: &Record) -> Option<String> {
+    record
+        .get_values("901", "a")
+        .first()
+        .map(|v| v.to_string())
+        .or_else(|| control_value(record, "001").map(|v| v.to_string()))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// OCLC numbers, digits only, from (OCoLC) prefixed 035 $a values,
+/// and from the 001 when the 003 is OCoLC.
+pub fn oclc_numbers(record: &Record) -> Vec<String> {
+    let mut values: Vec<&str> = record
+        .get_values("035", "a")
+        .into_iter()
+        .filter_map(|v| v.trim().strip_prefix("(OCoLC)"))
+        .collect();
+
+    if control_value(record, "003").map(|v| v.trim()) == Some("OCoLC") {
+        values.extend(control_value(record, "001"));
+    }
+
+    let mut numbers = Vec::new();
+    for value in values {
+        // e.g. ocm00012345, ocn123456789, on1234567890
+        let digits: String = value
+            .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+
+        let number = digits.trim_start_matches('0');
+        if !number.is_empty() && !numbers.iter().any(|n| n == number) {
+            numbers.push(number.to_string());
+        }
+    }
+
+    numbers
+}
+
+/// 020 $a ISBNs without hyphens or qualifiers such as "(pbk.)".
+/// Values which are not 10 or 13 characters long are skipped.
+pub fn isbns(record: &Record) -> Vec<String> {
+    let mut isbns = Vec::new();
+
+    for value in record.get_values("020", "a") {
+        if let Some(isbn) = isbn::normalize(value) {
+            if !isbns.contains(&isbn) {
+                isbns.push(isbn);
+            }
+        }
+    }
+
+    isbns
+}
+
+/// A selection of record content: subfields of a data field, e.g.
+/// "245$a", "050ab" or "6XX$a$x", a control field, e.g. "001", or
+/// character positions of a control field or the leader, e.g.
+/// "008/35-37" or "LDR/06".  A data field with no subfield codes
+/// selects all of its subfields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldSpec {
+    pub tag: String,
+    /// Subfield codes, or empty for all.
+    pub subfields: Vec<String>,
+    /// Inclusive character positions.
+    pub positions: Option<(usize, usize)>,
+}
+
+impl FieldSpec {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        // Ignore stray spaces and invisible characters pasted in with
+        // the spec.
+        let cleaned: String = spec.chars().filter(|c| c.is_ascii_graphic()).collect();
+
+        if cleaned.len() < 3 {
+            return Err(format!("Invalid field spec: {spec}"));
+        }
+
+        let (tag, rest) = cleaned.split_at(3);
+
+        let tag = match tag.eq_ignore_ascii_case("LDR") {
+            true => "LDR".to_string(),
+            false if tag.chars().all(|c| c.is_ascii_digit() || "Xx.".contains(c)) => {
+                tag.to_string()
+            }
+            false => return Err(format!("Invalid tag in field spec: {spec}")),
+        };
+
+        let fixed = tag == "LDR" || rules::is_control_tag(&tag);
+
+        if let Some(pos) = rest.strip_prefix('/') {
+            if !fixed {
+                return Err(format!("Positions require a control field: {spec}"));
+            }
+
+            let parse_pos = |p: &str| match p.parse::<usize>() {
+                Ok(n) => Ok(n),
+                Err(_) => Err(format!("Invalid positions in field spec: {spec}")),
+            };
+
+            let (start, end) = match pos.split_once('-') {
+                Some((s, e)) => (parse_pos(s)?, parse_pos(e)?),
+                None => (parse_pos(pos)?, parse_pos(pos)?),
+            };
+
+            if end < start {
+                return Err(format!("Invalid positions in field spec: {spec}"));
+            }
+
+            return Ok(FieldSpec {
+                tag,
+                subfields: Vec::new(),
+                positions: Some((start, end)),
+            });
+        }
+
+        if fixed && !rest.is_empty() {
+            return Err(format!("{tag} has no subfields: {spec}"));
+        }
+
+        let subfields: Vec<String> = rest
+            .chars()
+            .filter(|c| *c != '$')
+            .map(|c| c.to_string())
+            .collect();
+
+        if !subfields
+            .iter()
+            .all(|c| c.chars().all(|c| c.is_ascii_alphanumeric()))
+        {
+            return Err(format!("Invalid subfield code in field spec: {spec}"));
+        }
+
+        Ok(FieldSpec {
+            tag,
+            subfields,
+            positions: None,
+        })
+    }
+
+    /// Selected content, one value per matching field.  Subfields
+    /// from the same field are joined with spaces.  Empty values are
+    /// skipped.
+    pub fn extract(&self, record: &Record) -> Vec<String> {
+        let mut values = Vec::new();
+
+        if self.tag == "LDR" {
+            values.extend(self.slice(&record.leader));
+        } else if rules::is_control_tag(&self.tag) {
+            for cf in &record.control_fields {
+                if rules::tag_matches(&self.tag, &cf.tag) {
+                    values.extend(self.slice(&cf.content));
+                }
+            }
+        } else {
+            for field in &record.fields {
+                if !rules::tag_matches(&self.tag, &field.tag) {
+                    continue;
+                }
+
+                let parts: Vec<&str> = field
+                    .subfields
+                    .iter()
+                    .filter(|sf| self.subfields.is_empty() || self.subfields.contains(&sf.code))
+                    .map(|sf| sf.content.trim())
+                    .filter(|v| !v.is_empty())
+                    .collect();
+
+                if !parts.is_empty() {
+                    values.push(parts.join(" "));
+                }
+            }
+        }
+
+        values
+    }
+
+    fn slice(&self, content: &str) -> Option<String> {
+        let value = match self.positions {
+            Some((start, end)) => content.chars().skip(start).take(end - start + 1).collect(),
+            None => content.to_string(),
+        };
+
+        match value.is_empty() {
+            true => None,
+            false => Some(value),
+        }
+    }
+}
+
+/// Add a data field after any fields with the same or lower tag.
+pub fn insert_field(record: &mut Record, field: Field) {
+    let pos = record
+        .fields
+        .iter()
+        .position(|f| f.tag > field.tag)
+        .unwrap_or(record.fields.len());
+
+    record.fields.insert(pos, field);
+}
+
+/// The incoming record with the existing record's local fields.
+///
+/// Data fields matching a local_tags pattern, e.g. LOCAL_TAGS, are
+/// taken from existing only.  Everything else comes from incoming.
+pub fn merge(existing: &Record, incoming: &Record, local_tags: &[&str]) -> Record {
+    let is_local = |tag: &str| local_tags.iter().any(|p| rules::tag_matches(p, tag));
+
+    let mut merged = incoming.clone();
+    merged.fields.retain(|f| !is_local(&f.tag));
+
+    for field in existing.fields.iter().filter(|f| is_local(&f.tag)) {
+        insert_field(&mut merged, field.clone());
+    }
+
+    merged
+}
+
+/// One copy, as exported in an 852 holdings field.
+#[derive(Debug, Clone, Default)]
+pub struct Holding {
+    /// Value for $a, e.g. the exporting library's name.
+    pub location: Option<String>,
+    pub owning_lib: String,
+    pub circ_lib: String,
+    pub copy_location: String,
+    pub call_number: String,
+    pub circ_modifier: Option<String>,
+    pub barcode: String,
+    pub price: Option<String>,
+    pub copy_number: Option<String>,
+    pub reference: bool,
+    pub holdable: bool,
+    pub circulate: bool,
+    pub opac_visible: bool,
+}
+
+impl Holding {
+    /// An 852 laid out as Evergreen's marc_export writes them, so
+    /// the files load into Evergreen and other ILSs the same way.
+    pub fn to_field(&self) -> Field {
+        let mut subfields = Vec::new();
+
+        let mut add = |code: &str, content: &str| {
+            subfields.push(Subfield {
+                code: code.to_string(),
+                content: content.to_string(),
+            })
+        };
+
+        if let Some(l) = &self.location {
+            add("a", l);
+        }
+
+        add("b", &self.owning_lib);
+        add("b", &self.circ_lib);
+        add("c", &self.copy_location);
+        add("j", &self.call_number);
+
+        if let Some(m) = &self.circ_modifier {
+            add("g", m);
+        }
+
+        add("p", &self.barcode);
+
+        if let Some(p) = &self.price {
+            add("y", p);
+        }
+
+        if let Some(n) = &self.copy_number {
+            add("t", n);
+        }
+
+        if self.reference {
+            add("x", "reference");
+        }
+        if !self.holdable {
+            add("x", "unholdable");
+        }
+        if !self.circulate {
+            add("x", "noncirculating");
+        }
+        if !self.opac_visible {
+            add("x", "hidden");
+        }
+
+        Field {
+            tag: "852".to_string(),
+            ind1: "4".to_string(),
+            ind2: " ".to_string(),
+            subfields,
+        }
+    }
+}
+
+/// An 856 located URI, which Evergreen ingests as an asset.uri owned
+/// by the org unit named in $9.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocatedUri {
+    pub url: String,
+    pub label: Option<String>,
+    pub note: Option<String>,
+    /// Owning org unit shortname.
+    pub owner: String,
+}
+
+impl LocatedUri {
+    /// The located URI in an 856 with first indicator 4, second
+    /// indicator 0 or 1 and a $9.  Other 856s are plain links.
+    pub fn from_field(field: &Field) -> Option<Self> {
+        if field.tag != "856" || field.ind1 != "4" || !["0", "1"].contains(&field.ind2.as_str()) {
+            return None;
+        }
+
+        let value = |code: &str| {
+            field
+                .subfields
+                .iter()
+                .find(|sf| sf.code == code)
+                .map(|sf| sf.content.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+
+        Some(LocatedUri {
+            url: value("u")?,
+            label: value("y"),
+            note: value("z"),
+            owner: value("9")?,
+        })
+    }
+
+    pub fn to_field(&self) -> Field {
+        let mut subfields = vec![Subfield {
+            code: "u".to_string(),
+            content: self.url.to_string(),
+        }];
+
+        for (code, value) in [("y", &self.label), ("z", &self.note)] {
+            if let Some(v) = value {
+                subfields.push(Subfield {
+                    code: code.to_string(),
+                    content: v.to_string(),
+                });
+            }
+        }
+
+        subfields.push(Subfield {
+            code: "9".to_string(),
+            content: self.owner.to_string(),
+        });
+
+        Field {
+            tag: "856".to_string(),
+            ind1: "4".to_string(),
+            ind2: "0".to_string(),
+            subfields,
+        }
+    }
+}
+
+/// One line per field in MARC breaker format, e.g. =245  10$aTitle
+pub fn breaker_lines(record: &Record) -> Vec<String> {
+    let mut lines = vec![format!("=LDR  {}", record.leader)];
+
+    for cf in &record.control_fields {
+        lines.push(format!("={}  {}", cf.tag, cf.content));
+    }
+
+    for field in &record.fields {
+        let mut line = format!(
+            "={}  {}{}",
+            field.tag,
+            field.ind1.replace(' ', "\\"),
+            field.ind2.replace(' ', "\\")
+        );
+
+        for sf in &field.subfields {
+            line += &format!("${}{}", sf.code, sf.content);
+        }
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Read all records from a MARC XML or binary MARC file.
+pub fn read_file(path: &str, binary: bool) -> Result<Vec<Record>, String> {
+    if !binary {
+        return match fs::read_to_string(path) {
+            Ok(xml) => Ok(Record::from_xml(&xml).collect()),
+            Err(e) => Err(format!("Cannot read {path}: {e}")),
+        };
+    }
+
+    let bytes = match fs::read(path) {
+        Ok(b) => b,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    // Records end with the MARC record terminator.
+    let mut records = Vec::new();
+    for chunk in bytes.split_inclusive(|b| *b == 0x1D) {
+        if chunk.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+        records.push(Record::from_binary(chunk)?);
+    }
+
+    Ok(records)
+}
+
+/// Line diff of two records in breaker format.  Unchanged lines are
+/// prefixed with two spaces, removed lines with "- " and added lines
+/// with "+ ".
+pub fn diff(before: &Record, after: &Record) -> Vec<String> {
+    diff_lines(&breaker_lines(before), &breaker_lines(after))
+}
+
+/// Line diff of two lists of breaker lines.  See diff().
+pub fn diff_lines(old: &[String], new: &[String]) -> Vec<String> {
+    // Longest common subsequence table, built from the end.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(format!("  {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        } else {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEADER: &str = "00000nam a2200000 a 4500";
+
+    fn control(tag: &str, content: &str) -> String {
+        format!(r#"<controlfield tag="{tag}">{content}</controlfield>"#)
+    }
+
+    fn data(tag: &str, ind1: &str, ind2: &str, subfields: &[(&str, &str)]) -> String {
+        let subfields: String = subfields
+            .iter()
+            .map(|(code, value)| format!(r#"<subfield code="{code}">{value}</subfield>"#))
+            .collect();
+        format!(r#"<datafield tag="{tag}" ind1="{ind1}" ind2="{ind2}">{subfields}</datafield>"#)
+    }
+
+    fn record(fields: &[String]) -> Record {
+        let xml = format!(
+            r#"<record xmlns="http://www.loc.gov/MARC21/slim"><leader>{LEADER}</leader>{}</record>"#,
+            fields.concat()
+        );
+        Record::from_xml(&xml).next().expect("record")
+    }
+
+    fn fixed_008(date1: &str, language: &str) -> String {
+        format!("850101s{date1}    nyu{}{language} d", " ".repeat(17))
+    }
+
+    #[test]
+    fn reads_fixed_fields() {
+        let mut rec = record(&[control("008", &fixed_008("1985", "eng"))]);
+
+        assert_eq!(record_type(&rec), Some('a'));
+        assert_eq!(bib_level(&rec), Some('m'));
+        assert_eq!(date1(&rec), Some("1985"));
+        assert_eq!(language(&rec), Some("eng"));
+        assert_eq!(fixed_field(&rec, 15, 18), Some("nyu"));
+
+        // A truncated leader has no type or level.
+        rec.leader = "00000n".to_string();
+        assert_eq!(leader_char(&rec, 5), Some('n'));
+        assert_eq!(record_type(&rec), None);
+        assert_eq!(bib_level(&rec), None);
+    }
+
+    #[test]
+    fn skips_blank_and_short_fixed_fields() {
+        let rec = record(&[control("008", &fixed_008("    ", "   "))]);
+        assert_eq!(date1(&rec), None);
+        assert_eq!(language(&rec), None);
+
+        // Language is past the end of a short 008.
+        let rec = record(&[control("008", "850101s1985    nyu")]);
+        assert_eq!(date1(&rec), Some("1985"));
+        assert_eq!(language(&rec), None);
+        assert_eq!(fixed_field(&rec, 15, 30), None);
+
+        let rec = record(&[]);
+        assert_eq!(date1(&rec), None);
+        assert_eq!(control_value(&rec, "008"), None);
+    }
+
+    #[test]
+    fn finds_tcns() {
+        let rec = record(&[
+            control("001", "ocm12345"),
+            data("901", " ", " ", &[("a", " tcn-1 ")]),
+        ]);
+        assert_eq!(tcn(&rec), Some("tcn-1".to_string()));
+
+        let rec = record(&[control("001", "ocm12345")]);
+        assert_eq!(tcn(&rec), Some("ocm12345".to_string()));
+
+        let rec = record(&[data("901", " ", " ", &[("a", "  ")])]);
+        assert_eq!(tcn(&rec), None);
+    }
+
+    #[test]
+    fn finds_oclc_numbers() {
+        let rec = record(&[
+            control("001", "on1234567890"),
+            control("003", "OCoLC"),
+            data("035", " ", " ", &[("a", "(OCoLC)ocm00012345")]),
+            data("035", " ", " ", &[("a", " (OCoLC)12345 ")]),
+            data("035", " ", " ", &[("a", "(DLC)   85012345")]),
+            data("035", " ", " ", &[("a", "(OCoLC)")]),
+        ]);
+
+        assert_eq!(oclc_numbers(&rec), vec!["12345", "1234567890"]);
+
+        // The 001 is only an OCLC number with an OCoLC 003.
+        let rec = record(&[control("001", "12345"), control("003", "DLC")]);
+        assert!(oclc_numbers(&rec).is_empty());
+    }
+
+    #[test]
+    fn finds_isbns() {
+        let rec = record(&[
+            data("020", " ", " ", &[("a", "0-306-40615-2 (pbk.)")]),
+            data("020", " ", " ", &[("a", "0306406152")]),
+            data("020", " ", " ", &[("a", "978-0-306-40615-7")]),
+            data("020", " ", " ", &[("a", "12345")]),
+            data("020", " ", " ", &[("z", "0804429573")]),
+        ]);
+
+        assert_eq!(isbns(&rec), vec!["0306406152", "9780306406157"]);
+    }
+
+    #[test]
+    fn parses_field_specs() {
+        let spec = |s: &str| FieldSpec::parse(s).unwrap();
+
+        assert_eq!(
+            spec("245$a$b"),
+            FieldSpec {
+                tag: "245".to_string(),
+                subfields: vec!["a".to_string(), "b".to_string()],
+                positions: None,
+            }
+        );
+        assert_eq!(spec(" 6XX ax\u{200b}").subfields, vec!["a", "x"]);
+        assert!(spec("650").subfields.is_empty());
+        assert_eq!(spec("ldr/06").tag, "LDR");
+        assert_eq!(spec("LDR/06").positions, Some((6, 6)));
+        assert_eq!(spec("008/35-37").positions, Some((35, 37)));
+        assert_eq!(spec("001").positions, None);
+    }
+
+    #[test]
+    fn rejects_invalid_field_specs() {
+        let error = |s: &str| FieldSpec::parse(s).err().unwrap();
+
+        assert_eq!(error("24"), "Invalid field spec: 24");
+        assert_eq!(error("2A5$a"), "Invalid tag in field spec: 2A5$a");
+        assert_eq!(
+            error("245/1-2"),
+            "Positions require a control field: 245/1-2"
+        );
+        assert_eq!(
+            error("008/37-35"),
+            "Invalid positions in field spec: 008/37-35"
+        );
+        assert_eq!(error("008/x"), "Invalid positions in field spec: 008/x");
+        assert_eq!(error("008a"), "008 has no subfields: 008a");
+        assert_eq!(
+            error("245$a#"),
+            "Invalid subfield code in field spec: 245$a#"
+        );
+    }
+
+    #[test]
+    fn extracts_field_specs() {
+        let rec = record(&[
+            control("008", &fixed_008("1985", "eng")),
+            data(
+                "245",
+                "1",
+                "0",
+                &[("a", "Bleak house /"), ("c", "Dickens.")],
+            ),
+            data(
+                "650",
+                " ",
+                "0",
+                &[("a", "London"), ("x", " "), ("z", "England")],
+            ),
+            data("651", " ", "0", &[("a", "Thames")]),
+        ]);
+
+        let extract = |s: &str| FieldSpec::parse(s).unwrap().extract(&rec);
+
+        assert_eq!(extract("245a"), vec!["Bleak house /"]);
+        assert_eq!(extract("245"), vec!["Bleak house / Dickens."]);
+        assert_eq!(extract("65Xax"), vec!["London", "Thames"]);
+        assert_eq!(extract("LDR/06-07"), vec!["am"]);
+        assert_eq!(extract("008/35-37"), vec!["eng"]);
+        assert!(extract("008/45-50").is_empty());
+        assert!(extract("500a").is_empty());
+    }
+
+    #[test]
+    fn merges_local_fields() {
+        let existing = record(&[
+            control("001", "old"),
+            data("245", "1", "0", &[("a", "Old title")]),
+            data("590", " ", " ", &[("a", "Local note")]),
+            data("949", " ", " ", &[("a", "Local item")]),
+        ]);
+        let incoming = record(&[
+            control("001", "new"),
+            data("245", "1", "0", &[("a", "New title")]),
+            data("500", " ", " ", &[("a", "Note")]),
+            data("590", " ", " ", &[("a", "Vendor note")]),
+            data("650", " ", "0", &[("a", "Dogs")]),
+        ]);
+
+        let merged = merge(&existing, &incoming, LOCAL_TAGS);
+
+        assert_eq!(
+            breaker_lines(&merged),
+            vec![
+                format!("=LDR  {LEADER}"),
+                "=001  new".to_string(),
+                "=245  10$aNew title".to_string(),
+                "=500  \\\\$aNote".to_string(),
+                "=590  \\\\$aLocal note".to_string(),
+                "=650  \\0$aDogs".to_string(),
+                "=949  \\\\$aLocal item".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_holdings_fields() {
+        let holding = Holding {
+            location: Some("Example Library".to_string()),
+            owning_lib: "BR1".to_string(),
+            circ_lib: "BR2".to_string(),
+            copy_location: "Stacks".to_string(),
+            call_number: "FIC SMITH".to_string(),
+            circ_modifier: Some("book".to_string()),
+            barcode: "30001000".to_string(),
+            price: Some("25.00".to_string()),
+            copy_number: None,
+            reference: true,
+            holdable: false,
+            circulate: true,
+            opac_visible: false,
+        };
+
+        let field = holding.to_field();
+        assert_eq!((field.ind1.as_str(), field.ind2.as_str()), ("4", " "));
+
+        let subfields: Vec<String> = field
+            .subfields
+            .iter()
+            .map(|sf| format!("${}{}", sf.code, sf.content))
+            .collect();
+
+        assert_eq!(
+            subfields.concat(),
+            "$aExample Library$bBR1$bBR2$cStacks$jFIC SMITH$gbook$p30001000\
+             $y25.00$xreference$xunholdable$xhidden"
+        );
+    }
+
+    #[test]
+    fn reads_located_uris() {
+        let rec = record(&[
+            data(
+                "856",
+                "4",
+                "0",
+                &[("u", " http://example.org "), ("y", "Online"), ("9", "BR1")],
+            ),
+            // Malformed indicators make a plain link.
+            data(
+                "856",
+                " ",
+                "0",
+                &[("u", "http://example.org"), ("9", "BR1")],
+            ),
+            data(
+                "856",
+                "4",
+                "2",
+                &[("u", "http://example.org"), ("9", "BR1")],
+            ),
+            // No owner.
+            data("856", "4", "1", &[("u", "http://example.org"), ("9", " ")]),
+        ]);
+
+        let uris: Vec<LocatedUri> = rec
+            .fields
+            .iter()
+            .filter_map(LocatedUri::from_field)
+            .collect();
+
+        let expected = LocatedUri {
+            url: "http://example.org".to_string(),
+            label: Some("Online".to_string()),
+            note: None,
+            owner: "BR1".to_string(),
+        };
+        assert_eq!(uris, vec![expected.clone()]);
+
+        assert_eq!(LocatedUri::from_field(&expected.to_field()), Some(expected));
+    }
+
+    #[test]
+    fn diffs_lines() {
+        let lines = |l: &[&str]| -> Vec<String> { l.iter().map(|s| s.to_string()).collect() };
+
+        assert_eq!(
+            diff_lines(
+                &lines(&["=001  a", "=245  10$aOld", "=650  \\0$aDogs"]),
+                &lines(&[
+                    "=001  a",
+                    "=245  10$aNew",
+                    "=650  \\0$aDogs",
+                    "=690  \\0$aX"
+                ]),
+            ),
+            lines(&[
+                "  =001  a",
+                "+ =245  10$aNew",
+                "- =245  10$aOld",
+                "  =650  \\0$aDogs",
+                "+ =690  \\0$aX",
+            ])
+        );
+    }
+}
@@ -0,0 +1,788 @@
+//! Declarative MARC modification rules.
+//!
+//! Rules are read from a JSON array and applied in order.  Each rule
+//! names an action and may carry an "if" or "unless" condition which
+//! is tested against the record before the action runs.
+//!
+//! ```text
+//! [
+//!   {"action": "add_field", "tag": "590", "subfields": [["a", "Local copy"]]},
+//!   {"action": "delete_field", "tag": "9XX", "unless": {"tag": "901"}},
+//!   {"action": "delete_subfield", "tag": "650", "subfield": "0", "matches": "fast"},
+//!   {"action": "copy_subfield", "tag": "020", "subfield": "a",
+//!    "to_tag": "024", "to_subfield": "a", "if": {"tag": "020", "subfield": "a"}},
+//!   {"action": "move_subfield", "tag": "650", "subfield": "x", "to_subfield": "v"}
+//! ]
+//! ```
+//!
+//! Tags may use X or . as single character wildcards.  "matches"
+//! values are regular expressions tested against field content.
+use super::insert_field;
+use marcutil::{Controlfield, Field, Record, Subfield};
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+
+/// Record-level test: does any field with this tag (and subfield)
+/// have content matching the pattern?
+pub struct Condition {
+    pub tag: String,
+    pub subfield: Option<String>,
+    pub matches: Option<Regex>,
+    /// True for "unless" conditions.
+    pub negate: bool,
+}
+
+impl Condition {
+    pub fn test(&self, record: &Record) -> bool {
+        let found = if is_control_tag(&self.tag) {
+            record
+                .control_fields
+                .iter()
+                .filter(|cf| tag_matches(&self.tag, &cf.tag))
+                .any(|cf| value_matches(&self.matches, &cf.content))
+        } else {
+            record
+                .fields
+                .iter()
+                .filter(|f| tag_matches(&self.tag, &f.tag))
+                .any(|f| match self.subfield {
+                    Some(ref code) => f
+                        .subfields
+                        .iter()
+                        .filter(|sf| sf.code.eq(code))
+                        .any(|sf| value_matches(&self.matches, &sf.content)),
+                    None => {
+                        self.matches.is_none()
+                            || f.subfields
+                                .iter()
+                                .any(|sf| value_matches(&self.matches, &sf.content))
+                    }
+                })
+        };
+
+        found != self.negate
+    }
+}
+
+pub enum Action {
+    AddField {
+        tag: String,
+        ind1: String,
+        ind2: String,
+        subfields: Vec<(String, String)>,
+        /// Content for control fields.
+        content: Option<String>,
+    },
+    /// Delete fields, optionally only those with a subfield whose
+    /// content matches.  Without a subfield code, any subfield may
+    /// match.
+    DeleteField {
+        tag: String,
+        subfield: Option<String>,
+        matches: Option<Regex>,
+    },
+    /// Delete subfields, removing fields the deletion leaves with no
+    /// subfields.
+    DeleteSubfield {
+        tag: String,
+        subfield: String,
+        matches: Option<Regex>,
+    },
+    /// Copy or move subfields.  Without to_tag, the new subfield is
+    /// added to the same field.  Otherwise, each source field produces
+    /// a new to_tag field.
+    CopySubfield {
+        tag: String,
+        subfield: String,
+        matches: Option<Regex>,
+        to_tag: Option<String>,
+        to_subfield: String,
+        ind1: String,
+        ind2: String,
+        remove_source: bool,
+    },
+}
+
+pub struct Rule {
+    pub action: Action,
+    pub condition: Option<Condition>,
+}
+
+impl Rule {
+    /// Apply the rule, returning the number of fields and subfields
+    /// added or removed.
+    pub fn apply(&self, record: &mut Record) -> usize {
+        if let Some(ref cond) = self.condition {
+            if !cond.test(record) {
+                return 0;
+            }
+        }
+
+        match &self.action {
+            Action::AddField {
+                tag,
+                ind1,
+                ind2,
+                subfields,
+                content,
+            } => {
+                if is_control_tag(tag) {
+                    record.control_fields.push(Controlfield {
+                        tag: tag.to_string(),
+                        content: content.clone().unwrap_or_default(),
+                    });
+                    record.control_fields.sort_by(|a, b| a.tag.cmp(&b.tag));
+                } else {
+                    insert_field(
+                        record,
+                        Field {
+                            tag: tag.to_string(),
+                            ind1: ind1.to_string(),
+                            ind2: ind2.to_string(),
+                            subfields: subfields
+                                .iter()
+                                .map(|(code, content)| Subfield {
+                                    code: code.to_string(),
+                                    content: content.to_string(),
+                                })
+                                .collect(),
+                        },
+                    );
+                }
+                1
+            }
+
+            Action::DeleteField {
+                tag,
+                subfield,
+                matches,
+            } => {
+                if is_control_tag(tag) {
+                    let before = record.control_fields.len();
+                    record.control_fields.retain(|cf| {
+                        !(tag_matches(tag, &cf.tag) && value_matches(matches, &cf.content))
+                    });
+                    return before - record.control_fields.len();
+                }
+
+                let before = record.fields.len();
+                record.fields.retain(|f| {
+                    if !tag_matches(tag, &f.tag) {
+                        return true;
+                    }
+                    match subfield {
+                        Some(code) => !f
+                            .subfields
+                            .iter()
+                            .any(|sf| sf.code.eq(code) && value_matches(matches, &sf.content)),
+                        None => {
+                            matches.is_some()
+                                && !f
+                                    .subfields
+                                    .iter()
+                                    .any(|sf| value_matches(matches, &sf.content))
+                        }
+                    }
+                });
+                before - record.fields.len()
+            }
+
+            Action::DeleteSubfield {
+                tag,
+                subfield,
+                matches,
+            } => {
+                let mut changes = 0;
+                let mut emptied = Vec::new();
+                for (idx, field) in record.fields.iter_mut().enumerate() {
+                    if !tag_matches(tag, &field.tag) {
+                        continue;
+                    }
+                    let before = field.subfields.len();
+                    field.subfields.retain(|sf| {
+                        !(sf.code.eq(subfield) && value_matches(matches, &sf.content))
+                    });
+                    changes += before - field.subfields.len();
+                    if before > 0 && field.subfields.is_empty() {
+                        emptied.push(idx);
+                    }
+                }
+                remove_fields(record, &emptied);
+                changes
+            }
+
+            Action::CopySubfield {
+                tag,
+                subfield,
+                matches,
+                to_tag,
+                to_subfield,
+                ind1,
+                ind2,
+                remove_source,
+            } => {
+                let mut changes = 0;
+                let mut new_fields = Vec::new();
+                let mut emptied = Vec::new();
+
+                for (idx, field) in record.fields.iter_mut().enumerate() {
+                    if !tag_matches(tag, &field.tag) {
+                        continue;
+                    }
+
+                    let selected =
+                        |sf: &Subfield| sf.code.eq(subfield) && value_matches(matches, &sf.content);
+
+                    if to_tag.is_none() {
+                        if *remove_source {
+                            // Move within a field keeps the subfield position.
+                            for sf in field.subfields.iter_mut().filter(|sf| selected(sf)) {
+                                sf.code = to_subfield.to_string();
+                                changes += 1;
+                            }
+                        } else {
+                            let copies: Vec<Subfield> = field
+                                .subfields
+                                .iter()
+                                .filter(|sf| selected(sf))
+                                .map(|sf| Subfield {
+                                    code: to_subfield.to_string(),
+                                    content: sf.content.to_string(),
+                                })
+                                .collect();
+                            changes += copies.len();
+                            field.subfields.extend(copies);
+                        }
+                        continue;
+                    }
+
+                    let copies: Vec<Subfield> = field
+                        .subfields
+                        .iter()
+                        .filter(|sf| selected(sf))
+                        .map(|sf| Subfield {
+                            code: to_subfield.to_string(),
+                            content: sf.content.to_string(),
+                        })
+                        .collect();
+
+                    if copies.is_empty() {
+                        continue;
+                    }
+
+                    if *remove_source {
+                        field.subfields.retain(|sf| !selected(sf));
+                        if field.subfields.is_empty() {
+                            emptied.push(idx);
+                        }
+                    }
+
+                    changes += copies.len();
+                    new_fields.push(Field {
+                        tag: to_tag.clone().unwrap_or_default(),
+                        ind1: ind1.to_string(),
+                        ind2: ind2.to_string(),
+                        subfields: copies,
+                    });
+                }
+
+                remove_fields(record, &emptied);
+
+                for field in new_fields {
+                    insert_field(record, field);
+                }
+
+                changes
+            }
+        }
+    }
+}
+
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(json) => RuleSet::from_json(&json),
+            Err(e) => Err(format!("Cannot read rules file {path}: {e}")),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value: Value = match serde_json::from_str(json) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Cannot parse rules: {e}")),
+        };
+
+        let list = match value.as_array() {
+            Some(l) => l,
+            None => return Err("Rules must be a JSON array".to_string()),
+        };
+
+        let mut rules = Vec::new();
+        for (idx, rule) in list.iter().enumerate() {
+            match parse_rule(rule) {
+                Ok(r) => rules.push(r),
+                Err(e) => return Err(format!("Rule {}: {e}", idx + 1)),
+            }
+        }
+
+        Ok(RuleSet { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Apply all rules in order, returning the number of changes.
+    pub fn apply(&self, record: &mut Record) -> usize {
+        self.rules.iter().map(|r| r.apply(record)).sum()
+    }
+}
+
+fn parse_rule(rule: &Value) -> Result<Rule, String> {
+    let action = match rule["action"].as_str() {
+        Some(a) => a,
+        None => return Err("Rule has no action".to_string()),
+    };
+
+    let tag = required_str(rule, "tag")?;
+    let matches = optional_regex(rule, "matches")?;
+
+    let action = match action {
+        "add_field" => {
+            if !is_control_tag(&tag) && rule["subfields"].as_array().is_none() {
+                return Err("add_field requires subfields".to_string());
+            }
+
+            let mut subfields = Vec::new();
+            if let Some(list) = rule["subfields"].as_array() {
+                for pair in list {
+                    match (pair[0].as_str(), pair[1].as_str()) {
+                        (Some(c), Some(v)) => subfields.push((c.to_string(), v.to_string())),
+                        _ => return Err(format!("Invalid subfield: {pair}")),
+                    }
+                }
+            }
+
+            Action::AddField {
+                tag,
+                ind1: indicator(rule, "ind1"),
+                ind2: indicator(rule, "ind2"),
+                subfields,
+                content: rule["content"].as_str().map(|s| s.to_string()),
+            }
+        }
+        "delete_field" => Action::DeleteField {
+            tag,
+            subfield: rule["subfield"].as_str().map(|s| s.to_string()),
+            matches,
+        },
+        "delete_subfield" => Action::DeleteSubfield {
+            tag,
+            subfield: required_str(rule, "subfield")?,
+            matches,
+        },
+        "copy_subfield" | "move_subfield" => Action::CopySubfield {
+            tag,
+            subfield: required_str(rule, "subfield")?,
+            matches,
+            to_tag: rule["to_tag"].as_str().map(|s| s.to_string()),
+            to_subfield: required_str(rule, "to_subfield")?,
+            ind1: indicator(rule, "ind1"),
+            ind2: indicator(rule, "ind2"),
+            remove_source: action == "move_subfield",
+        },
+        _ => return Err(format!("Unknown action: {action}")),
+    };
+
+    if let Action::CopySubfield {
+        to_tag: Some(ref t),
+        ..
+    } = action
+    {
+        if is_control_tag(t) {
+            return Err(format!("Cannot copy subfields into control field {t}"));
+        }
+    }
+
+    let condition = match (rule.get("if"), rule.get("unless")) {
+        (Some(c), None) => Some(parse_condition(c, false)?),
+        (None, Some(c)) => Some(parse_condition(c, true)?),
+        (None, None) => None,
+        _ => return Err("Rule cannot have both if and unless".to_string()),
+    };
+
+    Ok(Rule { action, condition })
+}
+
+fn parse_condition(cond: &Value, negate: bool) -> Result<Condition, String> {
+    Ok(Condition {
+        tag: required_str(cond, "tag")?,
+        subfield: cond["subfield"].as_str().map(|s| s.to_string()),
+        matches: optional_regex(cond, "matches")?,
+        negate,
+    })
+}
+
+fn required_str(obj: &Value, key: &str) -> Result<String, String> {
+    match obj[key].as_str() {
+        Some(s) if !s.is_empty() => Ok(s.to_string()),
+        _ => Err(format!("Missing value for {key}")),
+    }
+}
+
+fn optional_regex(obj: &Value, key: &str) -> Result<Option<Regex>, String> {
+    match obj[key].as_str() {
+        Some(s) => match Regex::new(s) {
+            Ok(r) => Ok(Some(r)),
+            Err(e) => Err(format!("Invalid pattern {s}: {e}")),
+        },
+        None => Ok(None),
+    }
+}
+
+fn indicator(obj: &Value, key: &str) -> String {
+    obj[key].as_str().unwrap_or(" ").to_string()
+}
+
+/// True if the tag, or tag pattern, identifies a control field.
+pub fn is_control_tag(tag: &str) -> bool {
+    tag.starts_with("00")
+}
+
+/// Compare a tag against a pattern where X and . match any character.
+pub fn tag_matches(pattern: &str, tag: &str) -> bool {
+    pattern.len() == tag.len()
+        && pattern
+            .chars()
+            .zip(tag.chars())
+            .all(|(p, t)| p == t || p == 'X' || p == 'x' || p == '.')
+}
+
+/// Remove the fields at these positions, in ascending order.
+fn remove_fields(record: &mut Record, positions: &[usize]) {
+    for idx in positions.iter().rev() {
+        record.fields.remove(*idx);
+    }
+}
+
+fn value_matches(pattern: &Option<Regex>, value: &str) -> bool {
+    match pattern {
+        Some(re) => re.is_match(value),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::breaker_lines;
+    use super::*;
+
+    const RECORD_XML: &str = r#"<record xmlns="http://www.loc.gov/MARC21/slim">
+  <leader>00000nam a2200000 a 4500</leader>
+  <controlfield tag="001">ocm12345</controlfield>
+</record>"#;
+
+    fn field(tag: &str, subfields: &[(&str, &str)]) -> Field {
+        Field {
+            tag: tag.to_string(),
+            ind1: " ".to_string(),
+            ind2: "0".to_string(),
+            subfields: subfields
+                .iter()
+                .map(|(code, content)| Subfield {
+                    code: code.to_string(),
+                    content: content.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn record(fields: Vec<Field>) -> Record {
+        let mut record = Record::from_xml(RECORD_XML).next().expect("record");
+        record.fields = fields;
+        record
+    }
+
+    /// Apply the rules, returning the change count and the record's
+    /// fields in breaker format, less the leader.
+    fn apply(json: &str, record: &mut Record) -> (usize, Vec<String>) {
+        let changes = RuleSet::from_json(json).unwrap().apply(record);
+        (changes, breaker_lines(record)[1..].to_vec())
+    }
+
+    #[test]
+    fn adds_fields() {
+        let mut rec = record(vec![
+            field("245", &[("a", "Title")]),
+            field("650", &[("a", "Dogs")]),
+        ]);
+
+        let (changes, lines) = apply(
+            r#"[
+                {"action": "add_field", "tag": "590", "ind2": "1",
+                 "subfields": [["a", "Local copy"], ["5", "BR1"]]},
+                {"action": "add_field", "tag": "003", "content": "OCoLC"}
+            ]"#,
+            &mut rec,
+        );
+
+        assert_eq!(changes, 2);
+        assert_eq!(
+            lines,
+            vec![
+                "=001  ocm12345",
+                "=003  OCoLC",
+                "=245  \\0$aTitle",
+                "=590  \\1$aLocal copy$5BR1",
+                "=650  \\0$aDogs",
+            ]
+        );
+    }
+
+    #[test]
+    fn deletes_fields() {
+        let mut rec = record(vec![
+            field("650", &[("a", "Dogs"), ("0", "(OCoLC)fst01")]),
+            field("650", &[("a", "Cats"), ("0", "(DLC)sh01")]),
+            field("901", &[("c", "123")]),
+            field("949", &[("a", "Local")]),
+        ]);
+
+        let (changes, lines) = apply(
+            r#"[
+                {"action": "delete_field", "tag": "9XX"},
+                {"action": "delete_field", "tag": "650", "subfield": "0", "matches": "OCoLC"},
+                {"action": "delete_field", "tag": "001", "matches": "^ocm"}
+            ]"#,
+            &mut rec,
+        );
+
+        assert_eq!(changes, 4);
+        assert_eq!(lines, vec!["=650  \\0$aCats$0(DLC)sh01"]);
+    }
+
+    #[test]
+    fn deletes_fields_matching_any_subfield() {
+        // A pattern without a subfield code must only delete the
+        // fields it matches, not every field with the tag.
+        let mut rec = record(vec![
+            field("856", &[("u", "http://example.org/a"), ("y", "Online")]),
+            field("856", &[("u", "http://other.org/b")]),
+            field(
+                "856",
+                &[("3", "Table of contents"), ("u", "http://example.org/c")],
+            ),
+        ]);
+
+        let (changes, lines) = apply(
+            r#"[{"action": "delete_field", "tag": "856", "matches": "example\\.org"}]"#,
+            &mut rec,
+        );
+
+        assert_eq!(changes, 2);
+        assert_eq!(
+            lines,
+            vec!["=001  ocm12345", "=856  \\0$uhttp://other.org/b"]
+        );
+    }
+
+    #[test]
+    fn deletes_subfields() {
+        let mut rec = record(vec![
+            field("650", &[("a", "Dogs"), ("0", "fast01"), ("0", "sh01")]),
+            field("650", &[("0", "fast02")]),
+            // Already empty, so not the rule's to remove.
+            field("690", &[]),
+        ]);
+
+        let (changes, lines) = apply(
+            r#"[{"action": "delete_subfield", "tag": "6XX", "subfield": "0", "matches": "^fast"}]"#,
+            &mut rec,
+        );
+
+        assert_eq!(changes, 2);
+        assert_eq!(
+            lines,
+            vec!["=001  ocm12345", "=650  \\0$aDogs$0sh01", "=690  \\0"]
+        );
+    }
+
+    #[test]
+    fn copies_subfields() {
+        let mut rec = record(vec![
+            field("020", &[("a", "0306406152"), ("q", "pbk.")]),
+            field("650", &[("a", "Dogs"), ("x", "Training")]),
+        ]);
+
+        let (changes, lines) = apply(
+            r#"[
+                {"action": "copy_subfield", "tag": "650", "subfield": "x", "to_subfield": "v"},
+                {"action": "copy_subfield", "tag": "020", "subfield": "a",
+                 "to_tag": "024", "to_subfield": "a", "ind1": "3"}
+            ]"#,
+            &mut rec,
+        );
+
+        assert_eq!(changes, 2);
+        assert_eq!(
+            lines,
+            vec![
+                "=001  ocm12345",
+                "=020  \\0$a0306406152$qpbk.",
+                "=024  3\\$a0306406152",
+                "=650  \\0$aDogs$xTraining$vTraining",
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_subfields() {
+        let mut rec = record(vec![
+            field("500", &[("a", "Note")]),
+            field("650", &[("a", "Dogs"), ("x", "Training"), ("z", "Ohio")]),
+            field("690", &[]),
+        ]);
+
+        let (changes, lines) = apply(
+            r#"[
+                {"action": "move_subfield", "tag": "650", "subfield": "x", "to_subfield": "v"},
+                {"action": "move_subfield", "tag": "500", "subfield": "a",
+                 "to_tag": "590", "to_subfield": "a"}
+            ]"#,
+            &mut rec,
+        );
+
+        // The emptied 500 goes; the 690 was empty to begin with.
+        assert_eq!(changes, 2);
+        assert_eq!(
+            lines,
+            vec![
+                "=001  ocm12345",
+                "=590  \\\\$aNote",
+                "=650  \\0$aDogs$vTraining$zOhio",
+                "=690  \\0",
+            ]
+        );
+    }
+
+    #[test]
+    fn tests_conditions() {
+        let rec = record(vec![
+            field("650", &[("a", "Dogs"), ("0", "(OCoLC)fst01")]),
+            field("901", &[("c", "123")]),
+        ]);
+
+        let condition = |json: &str, negate: bool| {
+            let value: Value = serde_json::from_str(json).unwrap();
+            parse_condition(&value, negate).unwrap().test(&rec)
+        };
+
+        assert!(condition(r#"{"tag": "901"}"#, false));
+        assert!(condition(r#"{"tag": "9XX", "subfield": "c"}"#, false));
+        assert!(condition(r#"{"tag": "650", "matches": "OCoLC"}"#, false));
+        assert!(condition(
+            r#"{"tag": "650", "subfield": "0", "matches": "^\\(OCoLC\\)"}"#,
+            false
+        ));
+        assert!(condition(r#"{"tag": "001", "matches": "^ocm"}"#, false));
+
+        assert!(!condition(r#"{"tag": "902"}"#, false));
+        assert!(!condition(
+            r#"{"tag": "650", "subfield": "a", "matches": "OCoLC"}"#,
+            false
+        ));
+        assert!(!condition(r#"{"tag": "650", "matches": "Cats"}"#, false));
+        assert!(!condition(r#"{"tag": "001", "matches": "^on"}"#, false));
+
+        assert!(condition(r#"{"tag": "902"}"#, true));
+        assert!(!condition(r#"{"tag": "901"}"#, true));
+    }
+
+    #[test]
+    fn applies_rules_conditionally() {
+        let mut rec = record(vec![field("949", &[("a", "Local")])]);
+
+        let (changes, lines) = apply(
+            r#"[
+                {"action": "delete_field", "tag": "9XX", "unless": {"tag": "901"}},
+                {"action": "add_field", "tag": "590", "subfields": [["a", "Kept"]],
+                 "if": {"tag": "949"}}
+            ]"#,
+            &mut rec,
+        );
+
+        // The 949 is gone by the time the second rule tests for it.
+        assert_eq!(changes, 1);
+        assert_eq!(lines, vec!["=001  ocm12345"]);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let error = |json: &str| RuleSet::from_json(json).err().unwrap();
+
+        assert_eq!(
+            error(r#"{"action": "delete_field"}"#),
+            "Rules must be a JSON array"
+        );
+        assert_eq!(error(r#"[{"tag": "650"}]"#), "Rule 1: Rule has no action");
+        assert_eq!(
+            error(
+                r#"[{"action": "delete_field", "tag": "9XX"}, {"action": "rename", "tag": "650"}]"#
+            ),
+            "Rule 2: Unknown action: rename"
+        );
+        assert_eq!(
+            error(r#"[{"action": "delete_field"}]"#),
+            "Rule 1: Missing value for tag"
+        );
+        assert_eq!(
+            error(r#"[{"action": "add_field", "tag": "590"}]"#),
+            "Rule 1: add_field requires subfields"
+        );
+        assert_eq!(
+            error(r#"[{"action": "delete_subfield", "tag": "650"}]"#),
+            "Rule 1: Missing value for subfield"
+        );
+        assert_eq!(
+            error(
+                r#"[{"action": "copy_subfield", "tag": "035", "subfield": "a",
+                     "to_tag": "001", "to_subfield": "a"}]"#
+            ),
+            "Rule 1: Cannot copy subfields into control field 001"
+        );
+        assert_eq!(
+            error(
+                r#"[{"action": "delete_field", "tag": "9XX",
+                     "if": {"tag": "901"}, "unless": {"tag": "902"}}]"#
+            ),
+            "Rule 1: Rule cannot have both if and unless"
+        );
+        assert!(
+            error(r#"[{"action": "delete_field", "tag": "650", "matches": "("}]"#)
+                .starts_with("Rule 1: Invalid pattern (")
+        );
+    }
+
+    #[test]
+    fn matches_tag_patterns() {
+        assert!(tag_matches("9XX", "949"));
+        assert!(tag_matches("6x0", "650"));
+        assert!(tag_matches(".50", "650"));
+        assert!(tag_matches("245", "245"));
+        assert!(!tag_matches("9XX", "850"));
+        assert!(!tag_matches("24", "245"));
+
+        assert!(is_control_tag("001"));
+        assert!(is_control_tag("00X"));
+        assert!(!is_control_tag("010"));
+    }
+}
@@ -0,0 +1,639 @@
+//! MARC record validation profiles.
+//!
+//! A profile is a JSON array of checks, each run against every record.
+//! Failed checks are reported as Issues rather than errors, so one
+//! record can report several problems.
+//!
+//! ```text
+//! [
+//!   {"check": "required", "tag": "245"},
+//!   {"check": "non_repeatable", "tag": "1XX"},
+//!   {"check": "indicators", "tag": "245", "ind1": "01", "ind2": "0123456789"},
+//!   {"check": "required_subfield", "tag": "245", "subfield": "a"},
+//!   {"check": "non_repeatable_subfield", "tag": "245", "subfield": "a"},
+//!   {"check": "isbn", "tag": "020", "subfield": "a"},
+//!   {"check": "issn", "tag": "022", "subfield": "a"},
+//!   {"check": "pattern", "tag": "008", "matches": "^.{40}$"}
+//! ]
+//! ```
+//!
+//! Tags may use X or . as single character wildcards.  In indicator
+//! lists, # or a space stands for blank.
+use super::rules::{is_control_tag, tag_matches};
+use crate::isbn;
+use marcutil::{Field, Record};
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+
+/// Checks used without a profile file.
+const BASIC_PROFILE: &str = r#"[
+    {"check": "required", "tag": "008"},
+    {"check": "required", "tag": "245"},
+    {"check": "non_repeatable", "tag": "1XX"},
+    {"check": "non_repeatable", "tag": "245"},
+    {"check": "indicators", "tag": "245", "ind1": "01", "ind2": "0123456789"},
+    {"check": "required_subfield", "tag": "245", "subfield": "a"},
+    {"check": "non_repeatable_subfield", "tag": "245", "subfield": "a"},
+    {"check": "pattern", "tag": "008", "matches": "^.{40}$"},
+    {"check": "isbn", "tag": "020", "subfield": "a"},
+    {"check": "issn", "tag": "022", "subfield": "a"}
+]"#;
+
+/// One problem found in a record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    /// Tag of the offending field, or the tag pattern checked for.
+    pub tag: String,
+    pub message: String,
+}
+
+impl Issue {
+    fn new(tag: &str, message: String) -> Self {
+        Issue {
+            tag: tag.to_string(),
+            message,
+        }
+    }
+}
+
+pub enum Check {
+    /// At least one field with the tag.
+    Required { tag: String },
+    /// At most one field with the tag.  Patterns count all matching
+    /// fields together, so 1XX allows one main entry.
+    NonRepeatable { tag: String },
+    /// Allowed indicator values.  None allows any value.
+    Indicators {
+        tag: String,
+        ind1: Option<String>,
+        ind2: Option<String>,
+    },
+    /// Each field with the tag has the subfield.
+    RequiredSubfield { tag: String, subfield: String },
+    /// Each field with the tag has the subfield at most once.
+    NonRepeatableSubfield { tag: String, subfield: String },
+    /// Subfield values have a valid ISBN-10 or ISBN-13 check digit.
+    Isbn { tag: String, subfield: String },
+    /// Subfield values have a valid ISSN check digit.
+    Issn { tag: String, subfield: String },
+    /// Control field content, or subfield values, match the pattern.
+    Pattern {
+        tag: String,
+        subfield: Option<String>,
+        matches: Regex,
+    },
+}
+
+impl Check {
+    pub fn run(&self, record: &Record, issues: &mut Vec<Issue>) {
+        match self {
+            Check::Required { tag } => {
+                if count_fields(record, tag) == 0 {
+                    issues.push(Issue::new(tag, format!("Required field {tag} is missing")));
+                }
+            }
+
+            Check::NonRepeatable { tag } => {
+                let count = count_fields(record, tag);
+                if count > 1 {
+                    issues.push(Issue::new(
+                        tag,
+                        format!("Field {tag} is not repeatable; found {count}"),
+                    ));
+                }
+            }
+
+            Check::Indicators { tag, ind1, ind2 } => {
+                for field in fields(record, tag) {
+                    for (pos, allowed, value) in [(1, ind1, &field.ind1), (2, ind2, &field.ind2)] {
+                        if let Some(allowed) = allowed {
+                            if !indicator_allowed(allowed, value) {
+                                issues.push(Issue::new(
+                                    &field.tag,
+                                    format!("Invalid indicator {pos} '{value}'"),
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+
+            Check::RequiredSubfield { tag, subfield } => {
+                for field in fields(record, tag) {
+                    if !field.subfields.iter().any(|sf| sf.code.eq(subfield)) {
+                        issues.push(Issue::new(
+                            &field.tag,
+                            format!("Required subfield ${subfield} is missing"),
+                        ));
+                    }
+                }
+            }
+
+            Check::NonRepeatableSubfield { tag, subfield } => {
+                for field in fields(record, tag) {
+                    let count = field
+                        .subfields
+                        .iter()
+                        .filter(|sf| sf.code.eq(subfield))
+                        .count();
+                    if count > 1 {
+                        issues.push(Issue::new(
+                            &field.tag,
+                            format!("Subfield ${subfield} is not repeatable; found {count}"),
+                        ));
+                    }
+                }
+            }
+
+            Check::Isbn { tag, subfield } => {
+                for (field, value) in values(record, tag, subfield) {
+                    if !isbn_valid(value) {
+                        issues.push(Issue::new(
+                            &field.tag,
+                            format!("Invalid ISBN in ${subfield}: {value}"),
+                        ));
+                    }
+                }
+            }
+
+            Check::Issn { tag, subfield } => {
+                for (field, value) in values(record, tag, subfield) {
+                    if !issn_valid(value) {
+                        issues.push(Issue::new(
+                            &field.tag,
+                            format!("Invalid ISSN in ${subfield}: {value}"),
+                        ));
+                    }
+                }
+            }
+
+            Check::Pattern {
+                tag,
+                subfield,
+                matches,
+            } => {
+                if is_control_tag(tag) {
+                    for cf in record
+                        .control_fields
+                        .iter()
+                        .filter(|cf| tag_matches(tag, &cf.tag))
+                    {
+                        if !matches.is_match(&cf.content) {
+                            issues.push(Issue::new(
+                                &cf.tag,
+                                format!("Content does not match {}", matches.as_str()),
+                            ));
+                        }
+                    }
+                    return;
+                }
+
+                if let Some(code) = subfield {
+                    for (field, value) in values(record, tag, code) {
+                        if !matches.is_match(value) {
+                            issues.push(Issue::new(
+                                &field.tag,
+                                format!("${code} does not match {}: {value}", matches.as_str()),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Profile {
+    checks: Vec<Check>,
+}
+
+impl Profile {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(json) => Profile::from_json(&json),
+            Err(e) => Err(format!("Cannot read profile {path}: {e}")),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value: Value = match serde_json::from_str(json) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Cannot parse profile: {e}")),
+        };
+
+        let list = match value.as_array() {
+            Some(l) => l,
+            None => return Err("Profile must be a JSON array".to_string()),
+        };
+
+        let mut checks = Vec::new();
+        for (idx, check) in list.iter().enumerate() {
+            match parse_check(check) {
+                Ok(c) => checks.push(c),
+                Err(e) => return Err(format!("Check {}: {e}", idx + 1)),
+            }
+        }
+
+        Ok(Profile { checks })
+    }
+
+    /// Required and non-repeatable 008, 1XX and 245 fields, 245
+    /// indicators and $a, and ISBN and ISSN check digits.
+    pub fn basic() -> Self {
+        // The built-in profile is known to parse.
+        Profile::from_json(BASIC_PROFILE).unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.checks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checks.is_empty()
+    }
+
+    /// Run all checks, returning the issues found in check order.
+    pub fn validate(&self, record: &Record) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        for check in &self.checks {
+            check.run(record, &mut issues);
+        }
+        issues
+    }
+}
+
+fn parse_check(check: &Value) -> Result<Check, String> {
+    let name = match check["check"].as_str() {
+        Some(c) => c,
+        None => return Err("Check has no check name".to_string()),
+    };
+
+    let tag = required_str(check, "tag")?;
+
+    let check = match name {
+        "required" => Check::Required { tag },
+        "non_repeatable" => Check::NonRepeatable { tag },
+        "indicators" => Check::Indicators {
+            tag,
+            ind1: check["ind1"].as_str().map(|s| s.to_string()),
+            ind2: check["ind2"].as_str().map(|s| s.to_string()),
+        },
+        "required_subfield" => Check::RequiredSubfield {
+            tag,
+            subfield: required_str(check, "subfield")?,
+        },
+        "non_repeatable_subfield" => Check::NonRepeatableSubfield {
+            tag,
+            subfield: required_str(check, "subfield")?,
+        },
+        "isbn" => Check::Isbn {
+            tag,
+            subfield: check["subfield"].as_str().unwrap_or("a").to_string(),
+        },
+        "issn" => Check::Issn {
+            tag,
+            subfield: check["subfield"].as_str().unwrap_or("a").to_string(),
+        },
+        "pattern" => {
+            let pattern = required_str(check, "matches")?;
+            let matches = match Regex::new(&pattern) {
+                Ok(r) => r,
+                Err(e) => return Err(format!("Invalid pattern {pattern}: {e}")),
+            };
+
+            let subfield = check["subfield"].as_str().map(|s| s.to_string());
+            if subfield.is_none() && !is_control_tag(&tag) {
+                return Err(format!("pattern check on {tag} requires a subfield"));
+            }
+
+            Check::Pattern {
+                tag,
+                subfield,
+                matches,
+            }
+        }
+        _ => return Err(format!("Unknown check: {name}")),
+    };
+
+    Ok(check)
+}
+
+fn required_str(obj: &Value, key: &str) -> Result<String, String> {
+    match obj[key].as_str() {
+        Some(s) if !s.is_empty() => Ok(s.to_string()),
+        _ => Err(format!("Missing value for {key}")),
+    }
+}
+
+/// Number of control or data fields matching the tag pattern.
+fn count_fields(record: &Record, tag: &str) -> usize {
+    match is_control_tag(tag) {
+        true => record
+            .control_fields
+            .iter()
+            .filter(|cf| tag_matches(tag, &cf.tag))
+            .count(),
+        false => fields(record, tag).count(),
+    }
+}
+
+fn fields<'a>(record: &'a Record, tag: &'a str) -> impl Iterator<Item = &'a Field> {
+    record
+        .fields
+        .iter()
+        .filter(move |f| tag_matches(tag, &f.tag))
+}
+
+/// Each subfield value with its field.
+fn values<'a>(
+    record: &'a Record,
+    tag: &'a str,
+    code: &'a str,
+) -> impl Iterator<Item = (&'a Field, &'a str)> {
+    fields(record, tag).flat_map(move |f| {
+        f.subfields
+            .iter()
+            .filter(move |sf| sf.code.eq(code))
+            .map(move |sf| (f, sf.content.as_str()))
+    })
+}
+
+fn indicator_allowed(allowed: &str, value: &str) -> bool {
+    let value = value.chars().next().unwrap_or(' ');
+    allowed
+        .chars()
+        .any(|c| c == value || (c == '#' && value == ' '))
+}
+
+/// Leading digits and X of a value like "0-306-40615-2 (pbk.)".
+fn identifier(value: &str) -> String {
+    value
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// True if the value is an ISBN-10 or ISBN-13 with a correct check
+/// digit.  Hyphens and trailing qualifiers are ignored.
+pub fn isbn_valid(value: &str) -> bool {
+    isbn::is_valid(value)
+}
+
+/// True if the value is an ISSN with a correct check digit, e.g.
+/// 0378-5955.
+pub fn issn_valid(value: &str) -> bool {
+    let issn = identifier(value);
+    let chars: Vec<char> = issn.chars().collect();
+
+    if chars.len() != 8 {
+        return false;
+    }
+
+    let mut sum = 0;
+    for (idx, c) in chars.iter().enumerate() {
+        let digit = match (c.to_digit(10), c, idx) {
+            (Some(d), _, _) => d,
+            (None, 'X', 7) => 10,
+            _ => return false,
+        };
+        sum += digit * (8 - idx as u32);
+    }
+
+    sum % 11 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use marcutil::Subfield;
+
+    fn field(tag: &str, ind1: &str, ind2: &str, subfields: &[(&str, &str)]) -> Field {
+        Field {
+            tag: tag.to_string(),
+            ind1: ind1.to_string(),
+            ind2: ind2.to_string(),
+            subfields: subfields
+                .iter()
+                .map(|(code, content)| Subfield {
+                    code: code.to_string(),
+                    content: content.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// A record with a 001, the 008 and the fields.
+    fn record(fixed: &str, fields: Vec<Field>) -> Record {
+        let xml = format!(
+            r#"<record xmlns="http://www.loc.gov/MARC21/slim">
+  <leader>00000nam a2200000 a 4500</leader>
+  <controlfield tag="001">ocm12345</controlfield>
+  <controlfield tag="008">{fixed}</controlfield>
+</record>"#
+        );
+        let mut record = Record::from_xml(&xml).next().expect("record");
+        record.fields = fields;
+        record
+    }
+
+    fn fixed() -> String {
+        format!("850101s1985    nyu{}eng d", " ".repeat(17))
+    }
+
+    fn issue(tag: &str, message: &str) -> Issue {
+        Issue::new(tag, message.to_string())
+    }
+
+    fn validate(json: &str, record: &Record) -> Vec<Issue> {
+        Profile::from_json(json).unwrap().validate(record)
+    }
+
+    #[test]
+    fn passes_valid_records() {
+        let rec = record(
+            &fixed(),
+            vec![
+                field("020", " ", " ", &[("a", "0-306-40615-2 (pbk.)")]),
+                field("022", " ", " ", &[("a", "0378-5955")]),
+                field("100", "1", " ", &[("a", "Dickens, Charles.")]),
+                field("245", "1", "0", &[("a", "Bleak house /")]),
+            ],
+        );
+
+        assert_eq!(Profile::basic().len(), 10);
+        assert!(Profile::basic().validate(&rec).is_empty());
+    }
+
+    #[test]
+    fn checks_required_and_repeated_fields() {
+        let rec = record(
+            &fixed(),
+            vec![
+                field("100", "1", " ", &[("a", "Dickens, Charles.")]),
+                field("110", "2", " ", &[("a", "Dickens Society.")]),
+            ],
+        );
+
+        assert_eq!(
+            Profile::basic().validate(&rec),
+            vec![
+                issue("245", "Required field 245 is missing"),
+                issue("1XX", "Field 1XX is not repeatable; found 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_indicators() {
+        let rec = record(
+            &fixed(),
+            vec![
+                field("245", "1", "0", &[("a", "Valid")]),
+                field("245", "0", " ", &[("a", "Blank second")]),
+                field("245", "2", "9", &[("a", "Bad first")]),
+                field("245", "", "x", &[("a", "Missing first, bad second")]),
+            ],
+        );
+
+        let json = r##"[{"check": "indicators", "tag": "245", "ind1": "01", "ind2": "#0"}]"##;
+
+        assert_eq!(
+            validate(json, &rec),
+            vec![
+                issue("245", "Invalid indicator 1 '2'"),
+                issue("245", "Invalid indicator 2 '9'"),
+                issue("245", "Invalid indicator 1 ''"),
+                issue("245", "Invalid indicator 2 'x'"),
+            ]
+        );
+
+        // Unlisted indicators allow any value.
+        let json = r#"[{"check": "indicators", "tag": "245", "ind1": "012#"}]"#;
+        assert!(validate(json, &rec).is_empty());
+    }
+
+    #[test]
+    fn checks_subfields() {
+        let rec = record(
+            &fixed(),
+            vec![
+                field("245", "1", "0", &[("b", "No title proper")]),
+                field("245", "1", "0", &[("a", "One"), ("a", "Two")]),
+            ],
+        );
+
+        let json = r#"[
+            {"check": "required_subfield", "tag": "245", "subfield": "a"},
+            {"check": "non_repeatable_subfield", "tag": "245", "subfield": "a"}
+        ]"#;
+
+        assert_eq!(
+            validate(json, &rec),
+            vec![
+                issue("245", "Required subfield $a is missing"),
+                issue("245", "Subfield $a is not repeatable; found 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_identifiers() {
+        let rec = record(
+            &fixed(),
+            vec![
+                field("020", " ", " ", &[("a", "0306406152"), ("z", "0306406153")]),
+                field("020", " ", " ", &[("a", "978-0-306-40615-8")]),
+                field("022", " ", " ", &[("a", "0378-5955"), ("a", "0378-5956")]),
+            ],
+        );
+
+        let json = r#"[
+            {"check": "isbn", "tag": "020"},
+            {"check": "issn", "tag": "022", "subfield": "a"}
+        ]"#;
+
+        assert_eq!(
+            validate(json, &rec),
+            vec![
+                issue("020", "Invalid ISBN in $a: 978-0-306-40615-8"),
+                issue("022", "Invalid ISSN in $a: 0378-5956"),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_patterns() {
+        let rec = record(
+            "850101s1985",
+            vec![
+                field("050", " ", "0", &[("a", "PR4556")]),
+                field("050", " ", "0", &[("a", "4556 .A1")]),
+            ],
+        );
+
+        let json = r#"[
+            {"check": "pattern", "tag": "008", "matches": "^.{40}$"},
+            {"check": "pattern", "tag": "050", "subfield": "a", "matches": "^[A-Z]"}
+        ]"#;
+
+        assert_eq!(
+            validate(json, &rec),
+            vec![
+                issue("008", "Content does not match ^.{40}$"),
+                issue("050", "$a does not match ^[A-Z]: 4556 .A1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_profiles() {
+        let error = |json: &str| Profile::from_json(json).err().unwrap();
+
+        assert_eq!(
+            error(r#"{"check": "required"}"#),
+            "Profile must be a JSON array"
+        );
+        assert_eq!(
+            error(r#"[{"tag": "245"}]"#),
+            "Check 1: Check has no check name"
+        );
+        assert_eq!(
+            error(r#"[{"check": "required", "tag": "245"}, {"check": "required"}]"#),
+            "Check 2: Missing value for tag"
+        );
+        assert_eq!(
+            error(r#"[{"check": "required_subfield", "tag": "245", "subfield": ""}]"#),
+            "Check 1: Missing value for subfield"
+        );
+        assert_eq!(
+            error(r#"[{"check": "pattern", "tag": "245", "matches": "^A"}]"#),
+            "Check 1: pattern check on 245 requires a subfield"
+        );
+        assert_eq!(
+            error(r#"[{"check": "unique", "tag": "245"}]"#),
+            "Check 1: Unknown check: unique"
+        );
+        assert!(
+            error(r#"[{"check": "pattern", "tag": "008", "matches": "("}]"#)
+                .starts_with("Check 1: Invalid pattern (")
+        );
+        assert!(error("[").starts_with("Cannot parse profile"));
+    }
+
+    #[test]
+    fn validates_issns() {
+        assert!(issn_valid("0378-5955"));
+        assert!(issn_valid("2049-3630 (online)"));
+        assert!(issn_valid("0000-006x"));
+
+        assert!(!issn_valid("0378-5956"));
+        assert!(!issn_valid("0378-595"));
+        assert!(!issn_valid("0378-X955"));
+        assert!(!issn_valid(""));
+    }
+}
@@ -0,0 +1,194 @@
+//! Process metrics in the Prometheus text format.
+//!
+//! Counters and histograms are plain atomics, so binaries update them
+//! whether or not anything is scraping.  With the metrics feature,
+//! --metrics-listen serves them over HTTP at /metrics.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Histogram bucket upper bounds in seconds.  Every histogram has this
+/// many buckets plus the implicit +Inf bucket.
+const BUCKETS: usize = 12;
+
+/// For single database queries.
+const QUERY_BUCKETS: [f64; BUCKETS] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// For batches of records, which may take minutes.
+const BATCH_BUCKETS: [f64; BUCKETS] = [
+    0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0,
+];
+
+pub static RECORDS_PROCESSED: Counter = Counter::new(
+    "egutil_records_processed_total",
+    "Records processed, including those which failed.",
+);
+
+pub static ERRORS: Counter = Counter::new("egutil_errors_total", "Record and query errors.");
+
+pub static BATCH_SECONDS: Histogram = Histogram::new(
+    "egutil_batch_seconds",
+    "Time to process one batch of records.",
+    &BATCH_BUCKETS,
+);
+
+pub static DB_QUERY_SECONDS: Histogram = Histogram::new(
+    "egutil_db_query_seconds",
+    "Time spent in a single database query.",
+    &QUERY_BUCKETS,
+);
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str(&format!(
+            "# HELP {0} {1}\n# TYPE {0} counter\n{0} {2}\n",
+            self.name,
+            self.help,
+            self.get()
+        ));
+    }
+}
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64; BUCKETS],
+    /// Per-bucket counts, not cumulative.  The last is +Inf.
+    counts: [AtomicU64; BUCKETS + 1],
+    /// Sum of observations in microseconds.
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        bounds: &'static [f64; BUCKETS],
+    ) -> Self {
+        Histogram {
+            name,
+            help,
+            bounds,
+            counts: [const { AtomicU64::new(0) }; BUCKETS + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(BUCKETS);
+
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Observe the time elapsed since start.
+    pub fn observe_since(&self, start: Instant) {
+        self.observe(start.elapsed());
+    }
+
+    /// Run f and observe how long it took.
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.observe_since(start);
+        result
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str(&format!(
+            "# HELP {0} {1}\n# TYPE {0} histogram\n",
+            self.name, self.help
+        ));
+
+        let mut total = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+
+            let bound = match self.bounds.get(idx) {
+                Some(b) => b.to_string(),
+                None => "+Inf".to_string(),
+            };
+
+            out.push_str(&format!("{}_bucket{{le=\"{bound}\"}} {total}\n", self.name));
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        out.push_str(&format!("{}_sum {sum}\n", self.name));
+        out.push_str(&format!("{}_count {total}\n", self.name));
+    }
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    RECORDS_PROCESSED.render(&mut out);
+    ERRORS.render(&mut out);
+    BATCH_SECONDS.render(&mut out);
+    DB_QUERY_SECONDS.render(&mut out);
+
+    out
+}
+
+/// Serve /metrics on address from a background thread.
+///
+/// Fails if the address cannot be bound.  The thread exits with the
+/// process.
+#[cfg(feature = "metrics")]
+pub fn serve_in_background(address: &str) -> Result<(), String> {
+    use crate::http::{self, Response};
+    use std::net::TcpListener;
+
+    let listener = match TcpListener::bind(address) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("Cannot listen on {address}: {e}")),
+    };
+
+    let handler = |request: &http::Request| match request.path.as_str() {
+        "/metrics" => Response::new(200, "text/plain; version=0.0.4", render().into_bytes()),
+        _ => Response::text(404, "Not found"),
+    };
+
+    std::thread::spawn(move || {
+        if let Err(e) = http::serve_listener(listener, 1, None, handler) {
+            log::error!("Metrics endpoint failed: {e}");
+        }
+    });
+
+    Ok(())
+}
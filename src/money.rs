@@ -0,0 +1,370 @@
+//! Currency amounts and billing helpers.
+//!
+//! Money holds whole cents, so sums are exact.  Amounts move to and
+//! from the database as text, e.g. amount::TEXT and $1::TEXT::NUMERIC,
+//! since Evergreen's money columns are NUMERIC(6,2).
+//!
+//! Balances follow Evergreen's billable transaction summaries: the
+//! total of non-voided billings less the total of non-voided payments,
+//! where payments include account adjustments.
+use postgres as pg;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// config.billing_type ID of "Overdue materials" fines.
+pub const OVERDUE_BTYPE: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_cents(cents: i64) -> Self {
+        Money(cents)
+    }
+
+    pub fn cents(&self) -> i64 {
+        self.0
+    }
+
+    /// Parse an amount like 12, 12.5, -3.25 or $1,234.50.  Extra
+    /// decimal places are rounded half away from zero, as Postgres
+    /// does when storing NUMERIC(6,2).
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid amount: {value}");
+
+        let mut amount = value.trim();
+
+        let negative = amount.starts_with('-');
+        if negative {
+            amount = &amount[1..];
+        }
+
+        let amount = amount.trim_start_matches('$').replace(',', "");
+
+        let (whole, frac) = match amount.split_once('.') {
+            Some((w, f)) => (w, f),
+            None => (amount.as_str(), ""),
+        };
+
+        if (whole.is_empty() && frac.is_empty())
+            || !whole.chars().all(|c| c.is_ascii_digit())
+            || !frac.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        let whole: i64 = match whole {
+            "" => 0,
+            w => w.parse().map_err(|_| invalid())?,
+        };
+
+        let digits: Vec<i64> = frac.chars().map(|c| c as i64 - '0' as i64).collect();
+        let mut cents = digits.first().unwrap_or(&0) * 10 + digits.get(1).unwrap_or(&0);
+
+        if digits.get(2).copied().unwrap_or(0) >= 5 {
+            cents += 1;
+        }
+
+        let total = match whole.checked_mul(100).and_then(|w| w.checked_add(cents)) {
+            Some(t) => t,
+            None => return Err(invalid()),
+        };
+
+        Ok(Money(if negative { -total } else { total }))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn abs(&self) -> Self {
+        Money(self.0.abs())
+    }
+}
+
+/// e.g. 12.50 or -0.25
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let cents = self.0.abs();
+        write!(f, "{sign}{}.{:02}", cents / 100, cents % 100)
+    }
+}
+
+impl FromStr for Money {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Money::parse(s)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+/// e.g. a daily fine times the days overdue.
+impl Mul<i64> for Money {
+    type Output = Money;
+    fn mul(self, n: i64) -> Money {
+        Money(self.0 * n)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        self.0 -= other.0;
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, |a, b| a + b)
+    }
+}
+
+impl<'a> Sum<&'a Money> for Money {
+    fn sum<I: Iterator<Item = &'a Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, |a, b| a + *b)
+    }
+}
+
+/// Owed and paid totals for a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Balance {
+    pub total_owed: Money,
+    pub total_paid: Money,
+}
+
+impl Balance {
+    pub fn balance_owed(&self) -> Money {
+        self.total_owed - self.total_paid
+    }
+
+    /// Count a billing.  Voided billings are not owed.
+    pub fn add_billing(&mut self, amount: Money, voided: bool) {
+        if !voided {
+            self.total_owed += amount;
+        }
+    }
+
+    /// Count a payment or adjustment.  Voided payments are not paid.
+    pub fn add_payment(&mut self, amount: Money, voided: bool) {
+        if !voided {
+            self.total_paid += amount;
+        }
+    }
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn money_column(row: &pg::Row, column: &str) -> Result<Money, String> {
+    Money::parse(row.get(column))
+}
+
+/// Current balance for a transaction from its billings and payments.
+pub fn xact_balance(tx: &mut pg::Transaction, xact: i64) -> Result<Balance, String> {
+    let sql = r#"
+        SELECT
+            (SELECT COALESCE(SUM(amount), 0) FROM money.billing
+                WHERE xact = $1 AND NOT voided)::TEXT AS total_owed,
+            (SELECT COALESCE(SUM(amount), 0) FROM money.payment
+                WHERE xact = $1 AND NOT voided)::TEXT AS total_paid
+    "#;
+
+    let row = tx
+        .query_one(sql, &[&xact])
+        .map_err(|e| db_err(&format!("Error loading balance for {xact}"), e))?;
+
+    Ok(Balance {
+        total_owed: money_column(&row, "total_owed")?,
+        total_paid: money_column(&row, "total_paid")?,
+    })
+}
+
+/// Bill a transaction.  The billing type name is copied from
+/// config.billing_type, as Evergreen does.  Returns the billing ID.
+pub fn add_billing(
+    tx: &mut pg::Transaction,
+    xact: i64,
+    amount: Money,
+    btype: i32,
+    note: &str,
+) -> Result<i64, String> {
+    let sql = r#"
+        INSERT INTO money.billing (xact, amount, billing_type, btype, note)
+        SELECT $1, $2::TEXT::NUMERIC, name, id, $4
+        FROM config.billing_type WHERE id = $3
+        RETURNING id
+    "#;
+
+    match tx.query_opt(sql, &[&xact, &amount.to_string(), &btype, &note]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such billing type: {btype}")),
+        Err(e) => Err(db_err(&format!("Error billing {xact}"), e)),
+    }
+}
+
+/// Void a transaction's billings, or only those of one billing type.
+/// Returns the total voided.
+pub fn void_billings(
+    tx: &mut pg::Transaction,
+    xact: i64,
+    btype: Option<i32>,
+    voider: i32,
+) -> Result<Money, String> {
+    let sql = r#"
+        WITH voided AS (
+            UPDATE money.billing SET voided = TRUE, voider = $3, void_time = NOW()
+            WHERE xact = $1 AND ($2::INT IS NULL OR btype = $2) AND NOT voided
+            RETURNING amount
+        )
+        SELECT COALESCE(SUM(amount), 0)::TEXT AS amount FROM voided
+    "#;
+
+    let row = tx
+        .query_one(sql, &[&xact, &btype, &voider])
+        .map_err(|e| db_err(&format!("Error voiding billings on {xact}"), e))?;
+
+    money_column(&row, "amount")
+}
+
+/// Reduce what is owed on one billing with an account adjustment,
+/// which Evergreen counts as a payment against the transaction.
+pub fn adjust_billing(
+    tx: &mut pg::Transaction,
+    billing: i64,
+    amount: Money,
+    staff: i32,
+    note: &str,
+) -> Result<(), String> {
+    let sql = r#"
+        INSERT INTO money.account_adjustment (xact, billing, amount, note, accepting_usr)
+        SELECT xact, id, $2::TEXT::NUMERIC, $4, $3
+        FROM money.billing WHERE id = $1
+    "#;
+
+    match tx.execute(sql, &[&billing, &amount.to_string(), &staff, &note]) {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(format!("No such billing: {billing}")),
+        Err(e) => Err(db_err(&format!("Error adjusting billing {billing}"), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cents(value: &str) -> i64 {
+        Money::parse(value).unwrap().cents()
+    }
+
+    #[test]
+    fn parses_amounts() {
+        assert_eq!(cents("12"), 1200);
+        assert_eq!(cents("12.5"), 1250);
+        assert_eq!(cents(".05"), 5);
+        assert_eq!(cents("-3.25"), -325);
+        assert_eq!(cents(" $1,234.50 "), 123450);
+        assert_eq!(cents("-$0.10"), -10);
+
+        for bad in [
+            "",
+            ".",
+            "-",
+            "abc",
+            "1.2.3",
+            "1e3",
+            "$-1",
+            "99999999999999999999",
+        ] {
+            assert!(Money::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn rounds_half_away_from_zero() {
+        assert_eq!(cents("0.124"), 12);
+        assert_eq!(cents("0.125"), 13);
+        assert_eq!(cents("0.1249"), 12);
+        assert_eq!(cents("0.995"), 100);
+        assert_eq!(cents("-0.125"), -13);
+        assert_eq!(cents("-0.124"), -12);
+        assert_eq!(cents("2.999"), 300);
+    }
+
+    #[test]
+    fn displays_amounts() {
+        assert_eq!(Money::from_cents(1250).to_string(), "12.50");
+        assert_eq!(Money::from_cents(5).to_string(), "0.05");
+        assert_eq!(Money::from_cents(-25).to_string(), "-0.25");
+        assert_eq!(Money::ZERO.to_string(), "0.00");
+
+        let m: Money = "1,000.01".parse().unwrap();
+        assert_eq!(Money::parse(&m.to_string()).unwrap(), m);
+    }
+
+    #[test]
+    fn sums_exactly() {
+        // 0.10 three times is 0.30, unlike with floats.
+        let dimes = [Money::from_cents(10); 3];
+        assert_eq!(dimes.iter().sum::<Money>(), Money::from_cents(30));
+        assert_eq!(
+            Money::from_cents(25) * 3 - Money::from_cents(75),
+            Money::ZERO
+        );
+        assert_eq!(-Money::from_cents(5), Money::from_cents(-5));
+        assert!(Money::from_cents(-5).is_negative());
+        assert_eq!(Money::from_cents(-5).abs(), Money::from_cents(5));
+    }
+
+    #[test]
+    fn balances_skip_voided() {
+        let mut balance = Balance::default();
+        balance.add_billing(Money::from_cents(500), false);
+        balance.add_billing(Money::from_cents(300), true);
+        balance.add_payment(Money::from_cents(200), false);
+        balance.add_payment(Money::from_cents(100), true);
+
+        assert_eq!(balance.total_owed, Money::from_cents(500));
+        assert_eq!(balance.total_paid, Money::from_cents(200));
+        assert_eq!(balance.balance_owed(), Money::from_cents(300));
+    }
+}
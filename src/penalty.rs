@@ -0,0 +1,363 @@
+//! System standing penalties, as Evergreen calculates them.
+//!
+//! A penalty applies when a patron's fines or circulation counts
+//! reach the threshold in permission.grp_penalty_threshold for their
+//! profile group and home library.  The nearest group wins over the
+//! nearest org unit, as in actor.calculate_system_penalties().
+//!
+//! Only transactions at the threshold's org unit or its descendants
+//! count toward it, and the penalty is applied at that org unit.
+//! Fines count open circulations by circ_lib, grocery bills by
+//! billing_location and reservations by pickup_lib; the counts are of
+//! circulations alone.
+use crate::money::Money;
+use postgres as pg;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Fines,
+    OverdueCount,
+    CheckoutCount,
+    CollectionsWarning,
+    LongOverdueCount,
+    LostCount,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 6] = [
+        Kind::Fines,
+        Kind::OverdueCount,
+        Kind::CheckoutCount,
+        Kind::CollectionsWarning,
+        Kind::LongOverdueCount,
+        Kind::LostCount,
+    ];
+
+    /// config.standing_penalty ID
+    pub fn id(&self) -> i32 {
+        match self {
+            Kind::Fines => 1,
+            Kind::OverdueCount => 2,
+            Kind::CheckoutCount => 3,
+            Kind::CollectionsWarning => 4,
+            Kind::LongOverdueCount => 35,
+            Kind::LostCount => 36,
+        }
+    }
+
+    pub fn from_id(id: i32) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|k| k.id() == id)
+    }
+
+    /// config.standing_penalty name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Fines => "PATRON_EXCEEDS_FINES",
+            Kind::OverdueCount => "PATRON_EXCEEDS_OVERDUE_COUNT",
+            Kind::CheckoutCount => "PATRON_EXCEEDS_CHECKOUT_COUNT",
+            Kind::CollectionsWarning => "PATRON_EXCEEDS_COLLECTIONS_WARNING",
+            Kind::LongOverdueCount => "PATRON_EXCEEDS_LONGOVERDUE_COUNT",
+            Kind::LostCount => "PATRON_EXCEEDS_LOST_COUNT",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    /// Amount or count compared to the threshold.
+    ///
+    /// $1 user, $2 threshold org unit.
+    fn measure_sql(&self) -> String {
+        let scope = r#"
+            circ.usr = $1
+            AND circ.circ_lib IN (SELECT id FROM actor.org_unit_descendants($2::INT))
+        "#;
+
+        let filter = match self {
+            Kind::Fines | Kind::CollectionsWarning => {
+                return r#"
+                    WITH orgs AS (SELECT id FROM actor.org_unit_descendants($2::INT))
+                    SELECT COALESCE(SUM(mbxs.balance_owed), 0)::TEXT AS value
+                    FROM money.materialized_billable_xact_summary mbxs
+                        JOIN (
+                            SELECT circ.id
+                            FROM action.circulation circ
+                            WHERE circ.usr = $1
+                                AND circ.circ_lib IN (SELECT id FROM orgs)
+                                AND circ.xact_finish IS NULL
+                            UNION ALL
+                            SELECT mg.id
+                            FROM money.grocery mg
+                            WHERE mg.usr = $1
+                                AND mg.billing_location IN (SELECT id FROM orgs)
+                                AND mg.xact_finish IS NULL
+                            UNION ALL
+                            SELECT bresv.id
+                            FROM booking.reservation bresv
+                            WHERE bresv.usr = $1
+                                AND bresv.pickup_lib IN (SELECT id FROM orgs)
+                                AND bresv.xact_finish IS NULL
+                        ) xact USING (id)
+                "#
+                .to_string();
+            }
+            Kind::OverdueCount => {
+                "circ.due_date < NOW() AND (circ.stop_fines IS NULL OR circ.stop_fines = 'MAXFINES')"
+            }
+            Kind::CheckoutCount => {
+                "(circ.stop_fines IS NULL OR circ.stop_fines IN ('MAXFINES', 'LONGOVERDUE'))"
+            }
+            Kind::LongOverdueCount => "circ.stop_fines = 'LONGOVERDUE'",
+            Kind::LostCount => "circ.stop_fines = 'LOST'",
+        };
+
+        format!(
+            r#"
+            SELECT COUNT(*)::TEXT AS value
+            FROM action.circulation circ
+            WHERE {scope} AND circ.checkin_time IS NULL AND {filter}
+            "#
+        )
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A penalty a patron currently qualifies for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Penalty {
+    pub kind: Kind,
+    pub org_unit: i32,
+    /// Thresholds are NUMERIC, so counts are whole amounts too.
+    pub threshold: Money,
+    pub value: Money,
+}
+
+/// A penalty added to or removed from a patron.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub usr: i32,
+    pub kind: Kind,
+    pub org_unit: i32,
+    pub applied: bool,
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Thresholds which apply to a patron, one per penalty.
+const THRESHOLD_SQL: &str = r#"
+    WITH RECURSIVE grps AS (
+        SELECT pgt.id, pgt.parent, 0 AS distance
+        FROM permission.grp_tree pgt
+            JOIN actor.usr au ON au.profile = pgt.id
+        WHERE au.id = $1
+        UNION ALL
+        SELECT pgt.id, pgt.parent, grps.distance + 1
+        FROM permission.grp_tree pgt
+            JOIN grps ON pgt.id = grps.parent
+    ), orgs AS (
+        SELECT aou.id, aou.parent_ou, 0 AS distance
+        FROM actor.org_unit aou
+            JOIN actor.usr au ON au.home_ou = aou.id
+        WHERE au.id = $1
+        UNION ALL
+        SELECT aou.id, aou.parent_ou, orgs.distance + 1
+        FROM actor.org_unit aou
+            JOIN orgs ON aou.id = orgs.parent_ou
+    )
+    SELECT DISTINCT ON (pgpt.penalty)
+        pgpt.penalty, pgpt.org_unit, pgpt.threshold::TEXT AS threshold
+    FROM permission.grp_penalty_threshold pgpt
+        JOIN grps ON grps.id = pgpt.grp
+        JOIN orgs ON orgs.id = pgpt.org_unit
+    WHERE pgpt.penalty = ANY($2)
+    ORDER BY pgpt.penalty, grps.distance, orgs.distance
+"#;
+
+fn kind_ids() -> Vec<i32> {
+    Kind::ALL.iter().map(|k| k.id()).collect()
+}
+
+/// System penalties the patron qualifies for now.
+pub fn calculate(tx: &mut pg::Transaction, usr: i32) -> Result<Vec<Penalty>, String> {
+    let rows = tx
+        .query(THRESHOLD_SQL, &[&usr, &kind_ids()])
+        .map_err(|e| db_err(&format!("Error loading thresholds for {usr}"), e))?;
+
+    let mut penalties = Vec::new();
+
+    for row in rows {
+        // Guaranteed by the penalty filter.
+        let kind = Kind::from_id(row.get("penalty")).unwrap();
+        let org_unit: i32 = row.get("org_unit");
+        let threshold = Money::parse(row.get("threshold"))?;
+
+        let value = tx
+            .query_one(&kind.measure_sql(), &[&usr, &org_unit])
+            .map_err(|e| db_err(&format!("Error calculating {kind} for {usr}"), e))?;
+
+        let value = Money::parse(value.get("value"))?;
+
+        if value >= threshold {
+            penalties.push(Penalty {
+                kind,
+                org_unit,
+                threshold,
+                value,
+            });
+        }
+    }
+
+    Ok(penalties)
+}
+
+/// Bring a patron's system penalties up to date, adding those they
+/// qualify for and deleting the rest, as Evergreen does.  Other
+/// penalties, e.g. staff notes and blocks, are left alone.
+pub fn recalculate(tx: &mut pg::Transaction, usr: i32) -> Result<Vec<Change>, String> {
+    let wanted: HashSet<(Kind, i32)> = calculate(tx, usr)?
+        .into_iter()
+        .map(|p| (p.kind, p.org_unit))
+        .collect();
+
+    let sql = r#"
+        SELECT id, standing_penalty, org_unit
+        FROM actor.usr_standing_penalty
+        WHERE usr = $1
+            AND standing_penalty = ANY($2)
+            AND (stop_date IS NULL OR stop_date > NOW())
+    "#;
+
+    let rows = tx
+        .query(sql, &[&usr, &kind_ids()])
+        .map_err(|e| db_err(&format!("Error loading penalties for {usr}"), e))?;
+
+    let mut changes = Vec::new();
+    let mut existing = HashSet::new();
+
+    for row in rows {
+        let id: i32 = row.get("id");
+        let kind = Kind::from_id(row.get("standing_penalty")).unwrap();
+        let org_unit: i32 = row.get("org_unit");
+
+        if wanted.contains(&(kind, org_unit)) && existing.insert((kind, org_unit)) {
+            continue;
+        }
+
+        // No longer applies, or a duplicate.
+        tx.execute(
+            "DELETE FROM actor.usr_standing_penalty WHERE id = $1",
+            &[&id],
+        )
+        .map_err(|e| db_err(&format!("Error removing penalty {id}"), e))?;
+
+        if !wanted.contains(&(kind, org_unit)) {
+            changes.push(Change {
+                usr,
+                kind,
+                org_unit,
+                applied: false,
+            });
+        }
+    }
+
+    let sql = r#"
+        INSERT INTO actor.usr_standing_penalty (usr, standing_penalty, org_unit)
+        VALUES ($1, $2, $3)
+    "#;
+
+    let mut missing: Vec<&(Kind, i32)> = wanted.difference(&existing).collect();
+    missing.sort_by_key(|(kind, org_unit)| (kind.id(), *org_unit));
+
+    for (kind, org_unit) in missing {
+        tx.execute(sql, &[&usr, &kind.id(), org_unit])
+            .map_err(|e| db_err(&format!("Error applying {kind} to {usr}"), e))?;
+
+        changes.push(Change {
+            usr,
+            kind: *kind,
+            org_unit: *org_unit,
+            applied: true,
+        });
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn fines_include_grocery_and_reservation_bills() {
+        let mut db = match testing::test_database("penalty_fines") {
+            Some(db) => db,
+            None => return,
+        };
+
+        let mut tx = db.connection().client().transaction().unwrap();
+
+        // Patron 2 owes 0.50 on a circulation, under the 10.00 threshold
+        // at CONS, and already has a fines block there.
+        tx.batch_execute(
+            r#"
+            INSERT INTO money.grocery (id, usr, billing_location) VALUES (101, 2, 4);
+            INSERT INTO booking.reservation (id, usr, target_resource_type, request_lib, pickup_lib)
+                VALUES (102, 2, 1, 5, 5);
+            INSERT INTO money.billing (xact, amount, billing_type, btype) VALUES
+                (101, 6.00, 'Misc', 101),
+                (102, 4.00, 'Misc', 101);
+            INSERT INTO actor.usr_standing_penalty (usr, standing_penalty, org_unit)
+                VALUES (2, 1, 1);
+            "#,
+        )
+        .unwrap();
+
+        let fines = calculate(&mut tx, 2)
+            .unwrap()
+            .into_iter()
+            .find(|p| p.kind == Kind::Fines)
+            .expect("fines penalty");
+
+        assert_eq!(fines.org_unit, 1);
+        assert_eq!(fines.value, Money::from_cents(1050));
+
+        let changes = recalculate(&mut tx, 2).unwrap();
+        assert!(changes.iter().all(|c| c.kind != Kind::Fines));
+    }
+
+    #[test]
+    fn fines_exclude_closed_and_other_patrons_bills() {
+        let mut db = match testing::test_database("penalty_closed") {
+            Some(db) => db,
+            None => return,
+        };
+
+        let mut tx = db.connection().client().transaction().unwrap();
+
+        tx.batch_execute(
+            r#"
+            INSERT INTO money.grocery (id, usr, billing_location, xact_finish)
+                VALUES (101, 2, 4, NOW());
+            INSERT INTO money.grocery (id, usr, billing_location) VALUES (102, 5, 6);
+            INSERT INTO money.billing (xact, amount, billing_type, btype) VALUES
+                (101, 20.00, 'Misc', 101),
+                (102, 20.00, 'Misc', 101);
+            "#,
+        )
+        .unwrap();
+
+        let penalties = calculate(&mut tx, 2).unwrap();
+        assert!(penalties.iter().all(|p| p.kind != Kind::Fines));
+    }
+}
@@ -0,0 +1,279 @@
+//! Canned operational reports.
+//!
+//! Each report is one SQL query with named parameters.  Parameter
+//! values are passed as text, or NULL when not given and without a
+//! default, and cast by the query, so every report runs the same way
+//! from the command line.
+//!
+//! check() plans each query against a database without reading any
+//! rows, e.g. a scratch database from load-fixtures, to catch schema
+//! drift before a report is needed.
+use crate::tabular::Cell;
+use postgres as pg;
+use std::collections::HashMap;
+
+pub struct Param {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub default: Option<&'static str>,
+}
+
+pub struct Report {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Bound as $1, $2, ... in order.
+    pub params: &'static [Param],
+    pub sql: &'static str,
+}
+
+/// Title from a bib's MARC 245a, for reports on bibs and items.
+macro_rules! title_sql {
+    ($marc:literal) => {
+        concat!(
+            "(XPATH('//*[local-name()=\"datafield\"][@tag=\"245\"]",
+            "/*[local-name()=\"subfield\"][@code=\"a\"]/text()', ",
+            $marc,
+            "::XML))[1]::TEXT"
+        )
+    };
+}
+
+pub const REPORTS: &[Report] = &[
+    Report {
+        name: "items-no-circs",
+        description: "Items not circulated since a date, or ever",
+        params: &[
+            Param {
+                name: "org",
+                description: "Circulating library shortname, with descendants",
+                default: None,
+            },
+            Param {
+                name: "since",
+                description: "No checkouts on or after this date, YYYY-MM-DD.  Defaults to ever",
+                default: None,
+            },
+            Param {
+                name: "created_before",
+                description: "Only items created before this date, YYYY-MM-DD",
+                default: None,
+            },
+        ],
+        sql: concat!(
+            r#"
+            SELECT
+                acp.barcode,
+                acn.label AS call_number,
+                "#,
+            title_sql!("bre.marc"),
+            r#" AS title,
+                acl.name AS location,
+                circ_lib.shortname AS circ_lib,
+                ccs.name AS status,
+                acp.create_date::DATE::TEXT AS create_date,
+                (SELECT MAX(circ.xact_start)
+                    FROM action.all_circulation circ
+                    WHERE circ.target_copy = acp.id)::DATE::TEXT AS last_checkout
+            FROM asset.copy acp
+                JOIN asset.call_number acn ON acn.id = acp.call_number
+                JOIN biblio.record_entry bre ON bre.id = acn.record
+                JOIN asset.copy_location acl ON acl.id = acp.location
+                JOIN actor.org_unit circ_lib ON circ_lib.id = acp.circ_lib
+                JOIN config.copy_status ccs ON ccs.id = acp.status
+            WHERE NOT acp.deleted
+                AND ($1::TEXT IS NULL OR acp.circ_lib IN (
+                    SELECT id FROM actor.org_unit_descendants(
+                        (SELECT id FROM actor.org_unit WHERE shortname = $1))))
+                AND ($3::TEXT IS NULL OR acp.create_date < $3::TEXT::DATE)
+                AND NOT EXISTS (
+                    SELECT 1 FROM action.all_circulation circ
+                    WHERE circ.target_copy = acp.id
+                        AND ($2::TEXT IS NULL OR circ.xact_start >= $2::TEXT::DATE)
+                )
+            ORDER BY circ_lib.shortname, acl.name, acn.label, acp.barcode
+            "#
+        ),
+    },
+    Report {
+        name: "bibs-no-holdings",
+        description: "Bib records without any undeleted items",
+        params: &[
+            Param {
+                name: "org",
+                description: "Only count items owned by this shortname, with descendants",
+                default: None,
+            },
+            Param {
+                name: "created_before",
+                description: "Only bibs created before this date, YYYY-MM-DD",
+                default: None,
+            },
+        ],
+        sql: concat!(
+            r#"
+            SELECT
+                bre.id AS record,
+                bre.tcn_value AS tcn,
+                "#,
+            title_sql!("bre.marc"),
+            r#" AS title,
+                bre.source,
+                bre.create_date::DATE::TEXT AS create_date
+            FROM biblio.record_entry bre
+            WHERE NOT bre.deleted
+                AND bre.id > 0
+                AND ($2::TEXT IS NULL OR bre.create_date < $2::TEXT::DATE)
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM asset.call_number acn
+                        JOIN asset.copy acp ON acp.call_number = acn.id
+                    WHERE acn.record = bre.id
+                        AND NOT acn.deleted
+                        AND NOT acp.deleted
+                        AND ($1::TEXT IS NULL OR acn.owning_lib IN (
+                            SELECT id FROM actor.org_unit_descendants(
+                                (SELECT id FROM actor.org_unit WHERE shortname = $1))))
+                )
+            ORDER BY bre.id
+            "#
+        ),
+    },
+    Report {
+        name: "patrons-by-profile",
+        description: "Patron counts by home library and profile group",
+        params: &[
+            Param {
+                name: "org",
+                description: "Home library shortname, with descendants",
+                default: None,
+            },
+            Param {
+                name: "include_inactive",
+                description: "true to count inactive patrons",
+                default: Some("false"),
+            },
+        ],
+        sql: r#"
+            SELECT
+                home.shortname AS home_ou,
+                pgt.name AS profile,
+                COUNT(*) AS patrons,
+                COUNT(*) FILTER (WHERE au.expire_date < NOW()) AS expired,
+                COUNT(*) FILTER (WHERE au.barred) AS barred,
+                COUNT(*) FILTER (WHERE au.juvenile) AS juvenile
+            FROM actor.usr au
+                JOIN actor.org_unit home ON home.id = au.home_ou
+                JOIN permission.grp_tree pgt ON pgt.id = au.profile
+            WHERE NOT au.deleted
+                AND ($2::TEXT::BOOL OR au.active)
+                AND ($1::TEXT IS NULL OR au.home_ou IN (
+                    SELECT id FROM actor.org_unit_descendants(
+                        (SELECT id FROM actor.org_unit WHERE shortname = $1))))
+            GROUP BY home.shortname, pgt.name
+            ORDER BY home.shortname, pgt.name
+        "#,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Report> {
+    REPORTS.iter().find(|r| r.name == name)
+}
+
+impl Report {
+    /// Parameter values in order, from name => value, with defaults.
+    pub fn bind(&self, values: &HashMap<String, String>) -> Result<Vec<Option<String>>, String> {
+        if let Some(name) = values
+            .keys()
+            .find(|k| !self.params.iter().any(|p| p.name == k.as_str()))
+        {
+            return Err(format!("Report {} has no parameter {name}", self.name));
+        }
+
+        Ok(self
+            .params
+            .iter()
+            .map(|p| {
+                values
+                    .get(p.name)
+                    .cloned()
+                    .or(p.default.map(|d| d.to_string()))
+            })
+            .collect())
+    }
+
+    /// Run the report, returning its column names and rows.
+    pub fn run<C: pg::GenericClient>(
+        &self,
+        client: &mut C,
+        values: &HashMap<String, String>,
+    ) -> Result<(Vec<String>, Vec<Vec<Cell>>), String> {
+        let bound = self.bind(values)?;
+        let params: Vec<&(dyn pg::types::ToSql + Sync)> = bound
+            .iter()
+            .map(|v| v as &(dyn pg::types::ToSql + Sync))
+            .collect();
+
+        let stmt = client
+            .prepare(self.sql)
+            .map_err(|e| format!("Error preparing report {}: {e}", self.name))?;
+
+        let rows = client
+            .query(&stmt, &params)
+            .map_err(|e| format!("Error running report {}: {e}", self.name))?;
+
+        let columns = stmt
+            .columns()
+            .iter()
+            .map(|c| c.name().to_string())
+            .collect();
+
+        let mut cells = Vec::new();
+        for row in &rows {
+            cells.push(row_cells(row)?);
+        }
+
+        Ok((columns, cells))
+    }
+
+    /// Plan the report with default parameters without reading rows.
+    pub fn check<C: pg::GenericClient>(&self, client: &mut C) -> Result<(), String> {
+        let bound = self.bind(&HashMap::new())?;
+        let params: Vec<&(dyn pg::types::ToSql + Sync)> = bound
+            .iter()
+            .map(|v| v as &(dyn pg::types::ToSql + Sync))
+            .collect();
+
+        let sql = format!("SELECT * FROM ({}) r LIMIT 0", self.sql);
+
+        match client.query(sql.as_str(), &params) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Report {} failed: {e}", self.name)),
+        }
+    }
+}
+
+/// A result row as Cells.  Reports cast other types to TEXT.
+fn row_cells(row: &pg::Row) -> Result<Vec<Cell>, String> {
+    let mut cells = Vec::new();
+
+    for (idx, col) in row.columns().iter().enumerate() {
+        let cell = match col.type_().name() {
+            "int2" => Cell::from(row.get::<_, Option<i16>>(idx).map(|v| v as i64)),
+            "int4" => Cell::from(row.get::<_, Option<i32>>(idx)),
+            "int8" => Cell::from(row.get::<_, Option<i64>>(idx)),
+            "float8" => Cell::from(row.get::<_, Option<f64>>(idx)),
+            "bool" => Cell::from(row.get::<_, Option<bool>>(idx)),
+            "text" | "varchar" | "name" => Cell::from(row.get::<_, Option<String>>(idx)),
+            other => {
+                return Err(format!(
+                    "Column {} has unsupported type {other}",
+                    col.name()
+                ))
+            }
+        };
+
+        cells.push(cell);
+    }
+
+    Ok(cells)
+}
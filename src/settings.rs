@@ -0,0 +1,185 @@
+//! Org unit settings, as found in actor.org_unit_setting.
+//!
+//! A setting applies at the org unit where it is set and every org
+//! unit below it, unless set again lower down.  Values are stored as
+//! JSON, e.g. "\"25.00\"", "true" or "14".
+use crate::date::Interval;
+use crate::db::DatabaseConnection;
+use log::warn;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Value of one setting for an org unit, from the org unit or its
+/// nearest ancestor, using actor.org_unit_ancestor_setting().
+pub fn lookup(con: &mut DatabaseConnection, name: &str, org: i32) -> Result<Option<Value>, String> {
+    let sql = "SELECT value FROM actor.org_unit_ancestor_setting($1, $2)";
+
+    let rows = match con.client().query(sql, &[&name, &org]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error looking up setting {name}: {e}")),
+    };
+
+    Ok(rows.first().map(|r| parse_value(name, r.get("value"))))
+}
+
+/// Values which are not valid JSON are taken as plain strings.
+fn parse_value(name: &str, value: &str) -> Value {
+    match serde_json::from_str(value) {
+        Ok(v) => v,
+        Err(_) => {
+            warn!("Setting {name} has a non-JSON value: {value}");
+            Value::String(value.to_string())
+        }
+    }
+}
+
+/// Settings loaded in bulk, for tools which consult the same settings
+/// across many org units.
+pub struct OrgSettings {
+    parents: HashMap<i32, Option<i32>>,
+    values: HashMap<(i32, String), Value>,
+}
+
+impl OrgSettings {
+    /// Load the org tree and every value of the named settings.
+    pub fn load(con: &mut DatabaseConnection, names: &[&str]) -> Result<Self, String> {
+        let mut parents = HashMap::new();
+
+        let sql = "SELECT id, parent_ou FROM actor.org_unit";
+
+        match con.client().query(sql, &[]) {
+            Ok(rows) => {
+                for row in rows {
+                    parents.insert(row.get("id"), row.get("parent_ou"));
+                }
+            }
+            Err(e) => return Err(format!("Error loading org units: {e}")),
+        }
+
+        let sql = r#"
+            SELECT org_unit, name, value
+            FROM actor.org_unit_setting
+            WHERE name = ANY($1)
+        "#;
+
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let mut values = HashMap::new();
+
+        match con.client().query(sql, &[&names]) {
+            Ok(rows) => {
+                for row in rows {
+                    let name: String = row.get("name");
+                    let value = parse_value(&name, row.get("value"));
+                    values.insert((row.get("org_unit"), name), value);
+                }
+            }
+            Err(e) => return Err(format!("Error loading org unit settings: {e}")),
+        }
+
+        Ok(OrgSettings { parents, values })
+    }
+
+    /// The org unit, org at or above org, whose value applies.
+    pub fn owner(&self, org: i32, name: &str) -> Option<i32> {
+        let mut current = Some(org);
+
+        // Bounded by the tree size, in case of a parent_ou loop.
+        for _ in 0..=self.parents.len() {
+            let id = current?;
+
+            if self.values.contains_key(&(id, name.to_string())) {
+                return Some(id);
+            }
+
+            current = self.parents.get(&id).copied().flatten();
+        }
+
+        None
+    }
+
+    /// Raw JSON value for org from org or its nearest ancestor.
+    pub fn value(&self, org: i32, name: &str) -> Option<&Value> {
+        let owner = self.owner(org, name)?;
+        self.values.get(&(owner, name.to_string()))
+    }
+
+    /// Strings as is, and numbers and booleans as text.
+    pub fn string(&self, org: i32, name: &str) -> Result<Option<String>, String> {
+        match self.value(org, name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.to_string())),
+            Some(Value::Number(n)) => Ok(Some(n.to_string())),
+            Some(Value::Bool(b)) => Ok(Some(b.to_string())),
+            Some(v) => Err(type_err(name, org, "a string", v)),
+        }
+    }
+
+    /// JSON booleans, plus Postgres style strings such as "t" and
+    /// the numbers 0 and 1.
+    pub fn bool(&self, org: i32, name: &str) -> Result<Option<bool>, String> {
+        match self.value(org, name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Bool(b)) => Ok(Some(*b)),
+            Some(Value::Number(n)) => Ok(Some(n.as_f64() != Some(0.0))),
+            Some(Value::String(s)) => match s.to_lowercase().as_str() {
+                "t" | "true" | "1" | "yes" => Ok(Some(true)),
+                "f" | "false" | "0" | "no" | "" => Ok(Some(false)),
+                _ => Err(type_err(name, org, "a boolean", &Value::String(s.clone()))),
+            },
+            Some(v) => Err(type_err(name, org, "a boolean", v)),
+        }
+    }
+
+    /// Whole numbers, or strings holding them.
+    pub fn int(&self, org: i32, name: &str) -> Result<Option<i64>, String> {
+        let value = match self.value(org, name) {
+            None | Some(Value::Null) => return Ok(None),
+            Some(v) => v,
+        };
+
+        let int = match value {
+            Value::Number(n) => n.as_i64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+
+        match int {
+            Some(i) => Ok(Some(i)),
+            None => Err(type_err(name, org, "an integer", value)),
+        }
+    }
+
+    /// Numbers, or strings holding them, e.g. "25.00".
+    pub fn number(&self, org: i32, name: &str) -> Result<Option<f64>, String> {
+        let value = match self.value(org, name) {
+            None | Some(Value::Null) => return Ok(None),
+            Some(v) => v,
+        };
+
+        let number = match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+
+        match number {
+            Some(n) => Ok(Some(n)),
+            None => Err(type_err(name, org, "a number", value)),
+        }
+    }
+
+    /// Postgres interval strings, e.g. "18 years".
+    pub fn interval(&self, org: i32, name: &str) -> Result<Option<Interval>, String> {
+        match self.string(org, name)? {
+            Some(s) => match Interval::parse(&s) {
+                Ok(i) => Ok(Some(i)),
+                Err(_) => Err(type_err(name, org, "an interval", &Value::String(s))),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+fn type_err(name: &str, org: i32, expected: &str, value: &Value) -> String {
+    format!("Setting {name} for org unit {org} is not {expected}: {value}")
+}
@@ -0,0 +1,99 @@
+//! Graceful shutdown on SIGINT, SIGTERM and SIGHUP.
+//!
+//! Binaries call install() once and poll the returned Shutdown between
+//! units of work, e.g. batches, finishing or rolling back the current
+//! one before returning.  A second SIGINT or SIGTERM exits at once.
+//!
+//! SIGHUP does not stop the process.  It is recorded for tools which
+//! reload their configuration, and ignored otherwise, so batch jobs
+//! survive a dropped terminal session.
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit status used when a second signal forces an exit, as a shell
+/// would report for SIGINT.
+const FORCED_EXIT_STATUS: i32 = 130;
+
+/// Longest Shutdown::sleep() waits before checking the flag again.
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+static SHUTDOWN: OnceLock<Shutdown> = OnceLock::new();
+
+/// Cancellation token shared by every thread in the process.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    cancel: Arc<AtomicBool>,
+    hangup: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// True once SIGINT or SIGTERM arrives or request() is called.
+    pub fn requested(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    /// Ask the process to stop as though a signal arrived.
+    pub fn request(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// True if SIGHUP arrived since the last call.
+    pub fn take_hangup(&self) -> bool {
+        self.hangup.swap(false, Ordering::Relaxed)
+    }
+
+    /// Sleep for duration, waking early on shutdown.
+    ///
+    /// Returns false if shutdown was requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let end = Instant::now() + duration;
+
+        while !self.requested() {
+            let now = Instant::now();
+            if now >= end {
+                return true;
+            }
+            thread::sleep(SLEEP_SLICE.min(end - now));
+        }
+
+        false
+    }
+}
+
+/// Install the signal handlers, if not already installed, and return
+/// the process Shutdown token.
+pub fn install() -> Result<Shutdown, String> {
+    if let Some(s) = SHUTDOWN.get() {
+        return Ok(s.clone());
+    }
+
+    let shutdown = Shutdown {
+        cancel: Arc::new(AtomicBool::new(false)),
+        hangup: Arc::new(AtomicBool::new(false)),
+    };
+
+    for signal in [SIGINT, SIGTERM] {
+        // Registered ahead of the flag, so it only fires when the flag
+        // was already set by an earlier signal.
+        let result = flag::register_conditional_shutdown(
+            signal,
+            FORCED_EXIT_STATUS,
+            shutdown.cancel.clone(),
+        )
+        .and_then(|_| flag::register(signal, shutdown.cancel.clone()));
+
+        if let Err(e) = result {
+            return Err(format!("Cannot install handler for signal {signal}: {e}"));
+        }
+    }
+
+    if let Err(e) = flag::register(SIGHUP, shutdown.hangup.clone()) {
+        return Err(format!("Cannot install handler for signal {SIGHUP}: {e}"));
+    }
+
+    Ok(SHUTDOWN.get_or_init(|| shutdown).clone())
+}
@@ -0,0 +1,377 @@
+//! Before-images of rows changed by bulk tools, for the rollback tool.
+//!
+//! A tool run with --snapshot saves each row it is about to change to
+//! egutil.snapshot_row under a new egutil.snapshot_run, and with
+//! --snapshot-file FILE writes them to FILE as JSON lines instead.
+//! The two cannot be combined.
+//! Either way a row is saved whole, as JSON, along with the columns
+//! the tool changes, and rollback restores just those columns.
+//!
+//! Images are saved on the tool's own client ahead of each change, so
+//! they commit, or roll back on a dry run, with the change.
+//!
+//! ```text
+//! let mut run = ops.snapshot.begin(con.client(), "marc-modify")?;
+//! run.save(con.client(), "biblio.record_entry", &["marc"], &[id])?;
+//! run.finish();
+//! ```
+use getopts::Matches;
+use log::info;
+use postgres as pg;
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::process;
+
+/// Create the snapshot tables if needed.
+pub fn create_tables<C: pg::GenericClient>(client: &mut C) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.snapshot_run (
+            id              BIGSERIAL PRIMARY KEY,
+            tool            TEXT NOT NULL,
+            create_time     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            rollback_time   TIMESTAMPTZ
+        );
+        CREATE TABLE IF NOT EXISTS egutil.snapshot_row (
+            id          BIGSERIAL PRIMARY KEY,
+            run         BIGINT NOT NULL
+                REFERENCES egutil.snapshot_run (id) ON DELETE CASCADE,
+            table_name  TEXT NOT NULL,
+            columns     TEXT[] NOT NULL,
+            data        JSONB NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS snapshot_row_run_idx
+            ON egutil.snapshot_row (run);
+    "#;
+
+    match client.batch_execute(sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot create snapshot tables: {e}")),
+    }
+}
+
+/// Where a tool saves its before-images, if anywhere.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    database: bool,
+    file: Option<String>,
+}
+
+impl Snapshot {
+    /// Exits with a usage error if both --snapshot and --snapshot-file
+    /// are given.
+    pub fn from_params(params: &Matches) -> Snapshot {
+        if params.opt_present("snapshot") && params.opt_present("snapshot-file") {
+            eprintln!("--snapshot and --snapshot-file cannot be combined");
+            process::exit(2);
+        }
+
+        Snapshot {
+            database: params.opt_present("snapshot"),
+            file: params.opt_str("snapshot-file"),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.database || self.file.is_some()
+    }
+
+    /// Start saving images for a run of the tool.
+    pub fn begin<C: pg::GenericClient>(
+        &self,
+        client: &mut C,
+        tool: &str,
+    ) -> Result<SnapshotRun, String> {
+        let mut run = SnapshotRun {
+            id: None,
+            file: None,
+            path: self.file.clone(),
+            rows: 0,
+        };
+
+        if let Some(path) = &self.file {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Cannot open snapshot file {path}: {e}"))?;
+
+            run.file = Some(file);
+        } else if self.database {
+            create_tables(client)?;
+
+            let sql = "INSERT INTO egutil.snapshot_run (tool) VALUES ($1) RETURNING id";
+
+            let row = client
+                .query_one(sql, &[&tool])
+                .map_err(|e| format!("Cannot start snapshot: {e}"))?;
+
+            run.id = Some(row.get("id"));
+        }
+
+        Ok(run)
+    }
+}
+
+/// Images saved by one run of a tool.
+pub struct SnapshotRun {
+    /// egutil.snapshot_run.id, when saving to the database.
+    id: Option<i64>,
+    file: Option<File>,
+    path: Option<String>,
+    rows: u64,
+}
+
+impl SnapshotRun {
+    pub fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    /// Save the rows of table with these IDs ahead of changing
+    /// columns of them.  Does nothing without --snapshot options.
+    pub fn save<C: pg::GenericClient>(
+        &mut self,
+        client: &mut C,
+        table: &str,
+        columns: &[&str],
+        ids: &[i64],
+    ) -> Result<(), String> {
+        check_name(table)?;
+
+        if let Some(run) = self.id {
+            let sql = format!(
+                r#"
+                INSERT INTO egutil.snapshot_row (run, table_name, columns, data)
+                SELECT $1, $2, $3, TO_JSONB(t)
+                FROM {table} t
+                WHERE t.id = ANY($4::BIGINT[])
+                "#
+            );
+
+            let count = client
+                .execute(sql.as_str(), &[&run, &table, &columns, &ids])
+                .map_err(|e| format!("Cannot save {table} rows to snapshot: {e}"))?;
+
+            self.rows += count;
+        } else if let Some(file) = self.file.as_mut() {
+            let sql = format!(
+                "SELECT TO_JSONB(t)::TEXT AS data FROM {table} t WHERE t.id = ANY($1::BIGINT[])"
+            );
+
+            let rows = client
+                .query(sql.as_str(), &[&ids])
+                .map_err(|e| format!("Cannot read {table} rows for snapshot: {e}"))?;
+
+            let path = self.path.as_deref().unwrap_or_default();
+
+            for row in &rows {
+                let data: Value = serde_json::from_str(row.get("data"))
+                    .map_err(|e| format!("Invalid {table} row JSON: {e}"))?;
+
+                let line = json!({"table": table, "columns": columns, "data": data});
+
+                writeln!(file, "{line}")
+                    .map_err(|e| format!("Cannot write snapshot file {path}: {e}"))?;
+            }
+
+            self.rows += rows.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Log how to roll the run back.
+    pub fn finish(self) {
+        if let Some(id) = self.id {
+            info!(
+                "Saved {} rows to snapshot run {id}; undo with rollback --run-id {id}",
+                self.rows
+            );
+        } else if let Some(path) = self.path {
+            info!(
+                "Saved {} rows to {path}; undo with rollback --snapshot-file {path}",
+                self.rows
+            );
+        }
+    }
+}
+
+/// A saved run, for rollback --list.
+pub struct RunInfo {
+    pub id: i64,
+    pub tool: String,
+    pub create_time: String,
+    pub rollback_time: Option<String>,
+    pub rows: i64,
+}
+
+/// One saved row.
+pub struct Image {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub data: Value,
+}
+
+pub fn runs<C: pg::GenericClient>(client: &mut C) -> Result<Vec<RunInfo>, String> {
+    let sql = r#"
+        SELECT run.id, run.tool,
+            run.create_time::TEXT AS create_time,
+            run.rollback_time::TEXT AS rollback_time,
+            (SELECT COUNT(*) FROM egutil.snapshot_row r WHERE r.run = run.id) AS rows
+        FROM egutil.snapshot_run run
+        ORDER BY run.id
+    "#;
+
+    let rows = client
+        .query(sql, &[])
+        .map_err(|e| format!("Cannot list snapshot runs: {e}"))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| RunInfo {
+            id: row.get("id"),
+            tool: row.get("tool"),
+            create_time: row.get("create_time"),
+            rollback_time: row.get("rollback_time"),
+            rows: row.get("rows"),
+        })
+        .collect())
+}
+
+/// The run's images, last saved first, so a row saved more than once
+/// ends up with its earliest image.
+pub fn read_run<C: pg::GenericClient>(client: &mut C, run: i64) -> Result<Vec<Image>, String> {
+    let sql = "SELECT rollback_time::TEXT AS rollback_time FROM egutil.snapshot_run WHERE id = $1";
+
+    let row = client
+        .query_opt(sql, &[&run])
+        .map_err(|e| format!("Cannot load snapshot run {run}: {e}"))?
+        .ok_or_else(|| format!("No such snapshot run: {run}"))?;
+
+    if let Some(time) = row.get::<_, Option<String>>("rollback_time") {
+        return Err(format!("Snapshot run {run} was rolled back at {time}"));
+    }
+
+    let sql = r#"
+        SELECT table_name, columns, data::TEXT AS data
+        FROM egutil.snapshot_row
+        WHERE run = $1
+        ORDER BY id DESC
+    "#;
+
+    let rows = client
+        .query(sql, &[&run])
+        .map_err(|e| format!("Cannot load snapshot run {run}: {e}"))?;
+
+    let mut images = Vec::new();
+
+    for row in &rows {
+        images.push(Image {
+            table: row.get("table_name"),
+            columns: row.get("columns"),
+            data: serde_json::from_str(row.get("data"))
+                .map_err(|e| format!("Invalid snapshot row in run {run}: {e}"))?,
+        });
+    }
+
+    Ok(images)
+}
+
+/// A --snapshot-file's images, last saved first.
+pub fn read_file(path: &str) -> Result<Vec<Image>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {path}: {e}"))?;
+
+    let mut images = Vec::new();
+
+    for (idx, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let value: Value =
+            serde_json::from_str(line).map_err(|e| format!("{path} line {}: {e}", idx + 1))?;
+
+        let table = value["table"].as_str();
+        let columns = value["columns"].as_array();
+
+        let (table, columns) = match (table, columns) {
+            (Some(t), Some(c)) => (t, c),
+            _ => return Err(format!("{path} line {}: not a snapshot row", idx + 1)),
+        };
+
+        images.push(Image {
+            table: table.to_string(),
+            columns: columns
+                .iter()
+                .filter_map(|c| c.as_str().map(|s| s.to_string()))
+                .collect(),
+            data: value["data"].clone(),
+        });
+    }
+
+    images.reverse();
+
+    Ok(images)
+}
+
+/// Put the image's columns back.  Returns false if the row no longer
+/// exists.
+pub fn restore<C: pg::GenericClient>(client: &mut C, image: &Image) -> Result<bool, String> {
+    check_name(&image.table)?;
+    for column in &image.columns {
+        check_name(column)?;
+    }
+
+    if image.columns.is_empty() {
+        return Ok(true);
+    }
+
+    let table = &image.table;
+    let columns = image.columns.join(", ");
+    let values: Vec<String> = image.columns.iter().map(|c| format!("r.{c}")).collect();
+
+    let sql = format!(
+        r#"
+        UPDATE {table} t SET ({columns}) = (
+            SELECT {} FROM JSONB_POPULATE_RECORD(NULL::{table}, $1::TEXT::JSONB) r
+        )
+        WHERE t.id = ($1::TEXT::JSONB ->> 'id')::BIGINT
+        "#,
+        values.join(", ")
+    );
+
+    let count = client
+        .execute(sql.as_str(), &[&image.data.to_string()])
+        .map_err(|e| format!("Cannot restore {table} row {}: {e}", image.data["id"]))?;
+
+    Ok(count > 0)
+}
+
+/// Mark a run as rolled back, so it is not rolled back twice.
+pub fn mark_rolled_back<C: pg::GenericClient>(client: &mut C, run: i64) -> Result<(), String> {
+    let sql = "UPDATE egutil.snapshot_run SET rollback_time = NOW() WHERE id = $1";
+
+    match client.execute(sql, &[&run]) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot mark snapshot run {run} rolled back: {e}")),
+    }
+}
+
+/// Table and column names are placed in SQL, so only plain
+/// identifiers, optionally schema qualified, are accepted.
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.split('.').count() <= 2
+        && name.split('.').all(|part| {
+            part.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        });
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!("Invalid table or column name: {name}")),
+    }
+}
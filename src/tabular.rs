@@ -0,0 +1,306 @@
+//! Tabular output as CSV, TSV or Excel.
+//!
+//! Rows are written as typed Cells, so CSV gets RFC 4180 quoting,
+//! TSV gets tab-free text, and spreadsheets get real numbers,
+//! amounts and booleans.
+use crate::date::Timestamp;
+use crate::money::Money;
+use rust_xlsxwriter::{Format as XlsxFormat, Workbook, Worksheet, XlsxError};
+use std::fs;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Tsv,
+    Xlsx,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            "xlsx" | "excel" => Ok(Format::Xlsx),
+            _ => Err(format!("Unknown output format: {value}")),
+        }
+    }
+
+    /// Format implied by a file name, e.g. stats.xlsx.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let (_, ext) = path.rsplit_once('.')?;
+        Format::parse(ext).ok()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Tsv => "tsv",
+            Format::Xlsx => "xlsx",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Empty,
+    Text(String),
+    Integer(i64),
+    Number(f64),
+    Money(Money),
+    Bool(bool),
+    Timestamp(Timestamp),
+}
+
+impl Cell {
+    /// A number where the text is one, for query results which arrive
+    /// as text.  Numbers with leading zeros, e.g. barcodes, stay text.
+    pub fn infer(text: &str) -> Self {
+        let digits = text.trim_start_matches('-');
+        if digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.") {
+            return Cell::Text(text.to_string());
+        }
+
+        if let Ok(n) = text.parse::<i64>() {
+            return Cell::Integer(n);
+        }
+
+        match text.parse::<f64>() {
+            Ok(n) if n.is_finite() => Cell::Number(n),
+            _ => Cell::Text(text.to_string()),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Cell::Empty => String::new(),
+            Cell::Text(s) => s.to_string(),
+            Cell::Integer(n) => n.to_string(),
+            Cell::Number(n) => n.to_string(),
+            Cell::Money(m) => m.to_string(),
+            Cell::Bool(b) => b.to_string(),
+            Cell::Timestamp(t) => t.to_iso8601(),
+        }
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(s: &str) -> Self {
+        Cell::Text(s.to_string())
+    }
+}
+
+impl From<String> for Cell {
+    fn from(s: String) -> Self {
+        Cell::Text(s)
+    }
+}
+
+impl From<&String> for Cell {
+    fn from(s: &String) -> Self {
+        Cell::Text(s.to_string())
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(v: Option<T>) -> Self {
+        match v {
+            Some(v) => v.into(),
+            None => Cell::Empty,
+        }
+    }
+}
+
+impl From<i64> for Cell {
+    fn from(n: i64) -> Self {
+        Cell::Integer(n)
+    }
+}
+
+impl From<i32> for Cell {
+    fn from(n: i32) -> Self {
+        Cell::Integer(n as i64)
+    }
+}
+
+impl From<usize> for Cell {
+    fn from(n: usize) -> Self {
+        Cell::Integer(n as i64)
+    }
+}
+
+impl From<f64> for Cell {
+    fn from(n: f64) -> Self {
+        Cell::Number(n)
+    }
+}
+
+impl From<Money> for Cell {
+    fn from(m: Money) -> Self {
+        Cell::Money(m)
+    }
+}
+
+impl From<bool> for Cell {
+    fn from(b: bool) -> Self {
+        Cell::Bool(b)
+    }
+}
+
+impl From<Timestamp> for Cell {
+    fn from(t: Timestamp) -> Self {
+        Cell::Timestamp(t)
+    }
+}
+
+/// Quote a CSV field if needed.
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// TSV has no quoting, so tabs and line breaks become spaces.
+pub fn tsv_field(s: &str) -> String {
+    s.replace(['\t', '\n', '\r'], " ")
+}
+
+fn xlsx_err(e: XlsxError) -> String {
+    format!("Error writing spreadsheet: {e}")
+}
+
+/// Writes a header row and data rows in one format.  Spreadsheets
+/// are built in memory and written by finish().
+pub struct TableWriter {
+    format: Format,
+    writer: Box<dyn Write>,
+    sheet: Option<Worksheet>,
+    row: u32,
+}
+
+impl TableWriter {
+    pub fn new(writer: Box<dyn Write>, format: Format) -> Self {
+        let sheet = match format {
+            Format::Xlsx => Some(Worksheet::new()),
+            _ => None,
+        };
+
+        TableWriter {
+            format,
+            writer,
+            sheet,
+            row: 0,
+        }
+    }
+
+    pub fn stdout(format: Format) -> Self {
+        TableWriter::new(Box::new(io::stdout()), format)
+    }
+
+    pub fn create(path: &str, format: Format) -> Result<Self, String> {
+        match fs::File::create(path) {
+            Ok(f) => Ok(TableWriter::new(Box::new(f), format)),
+            Err(e) => Err(format!("Cannot create {path}: {e}")),
+        }
+    }
+
+    /// A file, or STDOUT without a path, in the format implied by the
+    /// path's extension, if any, or the default format.
+    pub fn for_path(path: Option<&str>, default: Format) -> Result<Self, String> {
+        match path {
+            Some(p) => TableWriter::create(p, Format::from_path(p).unwrap_or(default)),
+            None => Ok(TableWriter::stdout(default)),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Column labels, bold in spreadsheets.
+    pub fn write_header(&mut self, labels: &[&str]) -> Result<(), String> {
+        if let Some(sheet) = self.sheet.as_mut() {
+            let bold = XlsxFormat::new().set_bold();
+            for (col, label) in labels.iter().enumerate() {
+                sheet
+                    .write_string_with_format(self.row, col as u16, label, &bold)
+                    .map_err(xlsx_err)?;
+            }
+            self.row += 1;
+            return Ok(());
+        }
+
+        let cells: Vec<Cell> = labels.iter().map(|l| Cell::from(*l)).collect();
+        self.write_row(&cells)
+    }
+
+    pub fn write_row(&mut self, cells: &[Cell]) -> Result<(), String> {
+        let delimiter = match self.format {
+            Format::Csv => ",",
+            Format::Tsv => "\t",
+            Format::Xlsx => return self.write_xlsx_row(cells),
+        };
+
+        let fields: Vec<String> = cells
+            .iter()
+            .map(|c| match self.format {
+                Format::Tsv => tsv_field(&c.to_text()),
+                _ => csv_field(&c.to_text()),
+            })
+            .collect();
+
+        let line = fields.join(delimiter) + "\n";
+
+        match self.writer.write_all(line.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error writing row: {e}")),
+        }
+    }
+
+    fn write_xlsx_row(&mut self, cells: &[Cell]) -> Result<(), String> {
+        // Only built for Format::Xlsx.
+        let sheet = self.sheet.as_mut().unwrap();
+        let row = self.row;
+        self.row += 1;
+
+        for (idx, cell) in cells.iter().enumerate() {
+            let col = idx as u16;
+
+            match cell {
+                Cell::Empty => continue,
+                Cell::Integer(n) => sheet.write_number(row, col, *n as f64),
+                Cell::Number(n) => sheet.write_number(row, col, *n),
+                Cell::Money(m) => {
+                    let format = XlsxFormat::new().set_num_format("0.00");
+                    sheet.write_number_with_format(row, col, m.cents() as f64 / 100.0, &format)
+                }
+                Cell::Bool(b) => sheet.write_boolean(row, col, *b),
+                Cell::Text(_) | Cell::Timestamp(_) => sheet.write_string(row, col, &cell.to_text()),
+            }
+            .map_err(xlsx_err)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush delimited output, or build and write the spreadsheet.
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(sheet) = self.sheet.take() {
+            let mut workbook = Workbook::new();
+            workbook.push_worksheet(sheet);
+
+            let bytes = workbook.save_to_buffer().map_err(xlsx_err)?;
+
+            if let Err(e) = self.writer.write_all(&bytes) {
+                return Err(format!("Error writing spreadsheet: {e}"));
+            }
+        }
+
+        match self.writer.flush() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error writing output: {e}")),
+        }
+    }
+}
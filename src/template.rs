@@ -0,0 +1,177 @@
+//! Text templates for notices, notifications and export manifests.
+//!
+//! Templates use Tera syntax, e.g. "Dear {{ patron.first_given_name }}",
+//! with these filters alongside Tera's own:
+//!
+//! * date: format an Evergreen timestamp, e.g.
+//!   {{ circ.due_date | date(format="%B %e, %Y") }}.  Defaults to
+//!   %Y-%m-%d.  See date::Timestamp::format() for the supported codes.
+//! * money: format an amount as 12.50, or with a currency symbol, e.g.
+//!   {{ balance | money(symbol="$") }} for $12.50.
+//! * org_name, org_shortname: an org unit ID as its name, after
+//!   Renderer::load_org_units().
+//!
+//! Templates whose names end in .html or .xml escape values; others
+//! do not.
+//!
+//! Renderers are Sync, so one can be shared by worker threads.
+use crate::date::Timestamp;
+use crate::db::DatabaseConnection;
+use crate::money::Money;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::Arc;
+use tera::{Context, Tera};
+
+type Args = HashMap<String, Value>;
+
+/// Org unit ID => (shortname, name)
+type OrgNames = HashMap<i64, (String, String)>;
+
+pub struct Renderer {
+    tera: Tera,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        let mut tera = Tera::default();
+        tera.autoescape_on(vec![".html", ".xml"]);
+        tera.register_filter("date", date_filter);
+        tera.register_filter("money", money_filter);
+
+        Renderer { tera }
+    }
+
+    /// Load org unit names for the org_name and org_shortname filters.
+    pub fn load_org_units(&mut self, con: &mut DatabaseConnection) -> Result<(), String> {
+        let sql = "SELECT id, shortname, name FROM actor.org_unit";
+
+        let rows = match con.client().query(sql, &[]) {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Error loading org units: {e}")),
+        };
+
+        let mut orgs = OrgNames::new();
+        for row in rows {
+            let id: i32 = row.get("id");
+            orgs.insert(id as i64, (row.get("shortname"), row.get("name")));
+        }
+
+        let orgs = Arc::new(orgs);
+
+        let names = orgs.clone();
+        self.tera
+            .register_filter("org_name", move |value: &Value, _: &Args| {
+                org_filter(&names, value).map(|(_, name)| Value::String(name.to_string()))
+            });
+
+        self.tera
+            .register_filter("org_shortname", move |value: &Value, _: &Args| {
+                org_filter(&orgs, value).map(|(sn, _)| Value::String(sn.to_string()))
+            });
+
+        Ok(())
+    }
+
+    pub fn add_template(&mut self, name: &str, source: &str) -> Result<(), String> {
+        self.tera
+            .add_raw_template(name, source)
+            .map_err(|e| tera_err(&format!("Invalid template {name}"), &e))
+    }
+
+    pub fn add_template_file(&mut self, name: &str, path: &str) -> Result<(), String> {
+        let source = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot read {path}: {e}")),
+        };
+
+        self.add_template(name, &source)
+    }
+
+    /// Render a template with a JSON object of values.
+    pub fn render(&self, name: &str, data: &Value) -> Result<String, String> {
+        let context = match Context::from_value(data.clone()) {
+            Ok(c) => c,
+            Err(e) => return Err(tera_err("Template data must be an object", &e)),
+        };
+
+        self.tera
+            .render(name, &context)
+            .map_err(|e| tera_err(&format!("Error rendering {name}"), &e))
+    }
+}
+
+/// Tera reports the useful detail, e.g. the line of a syntax error,
+/// in the error's sources.
+fn tera_err(context: &str, e: &tera::Error) -> String {
+    let mut msg = format!("{context}: {e}");
+
+    let mut source = e.source();
+    while let Some(s) = source {
+        msg += &format!(": {s}");
+        source = s.source();
+    }
+
+    msg
+}
+
+fn date_filter(value: &Value, args: &Args) -> tera::Result<Value> {
+    let format = match args.get("format") {
+        Some(Value::String(f)) => f.as_str(),
+        Some(_) => return Err(tera::Error::msg("date format must be a string")),
+        None => "%Y-%m-%d",
+    };
+
+    match value {
+        // e.g. an unset due date
+        Value::Null => Ok(Value::String(String::new())),
+        Value::String(s) => match Timestamp::parse(s) {
+            Ok(t) => Ok(Value::String(t.format(format))),
+            Err(e) => Err(tera::Error::msg(e)),
+        },
+        _ => Err(tera::Error::msg(format!("Not a timestamp: {value}"))),
+    }
+}
+
+fn money_filter(value: &Value, args: &Args) -> tera::Result<Value> {
+    let amount = match value {
+        Value::Null => Money::ZERO,
+        Value::String(s) => Money::parse(s).map_err(tera::Error::msg)?,
+        Value::Number(n) => Money::parse(&n.to_string()).map_err(tera::Error::msg)?,
+        _ => return Err(tera::Error::msg(format!("Not an amount: {value}"))),
+    };
+
+    let symbol = match args.get("symbol") {
+        Some(Value::String(s)) => s.as_str(),
+        Some(_) => return Err(tera::Error::msg("money symbol must be a string")),
+        None => "",
+    };
+
+    let text = match amount.is_negative() {
+        true => format!("-{symbol}{}", amount.abs()),
+        false => format!("{symbol}{amount}"),
+    };
+
+    Ok(Value::String(text))
+}
+
+fn org_filter<'a>(orgs: &'a OrgNames, value: &Value) -> tera::Result<&'a (String, String)> {
+    let id = match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+
+    match id.and_then(|id| orgs.get(&id)) {
+        Some(names) => Ok(names),
+        None => Err(tera::Error::msg(format!("No such org unit: {value}"))),
+    }
+}
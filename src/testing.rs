@@ -0,0 +1,227 @@
+//! Scratch databases and sample data for running the egutil tools
+//! against a real, minimal Evergreen schema.
+use crate::db::DatabaseConnection;
+use log::{error, info, warn};
+use postgres as pg;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Subset of the Evergreen schema used by the egutil tools.
+pub const SCHEMA_SQL: &str = include_str!("testing/schema.sql");
+
+/// Small consortium with patrons, bibs, items, a circulation and a
+/// hold, for use with SCHEMA_SQL.
+pub const SAMPLE_DATA_SQL: &str = include_str!("testing/sample-data.sql");
+
+/// Maintenance database used to create and drop scratch databases.
+const ADMIN_DATABASE: &str = "postgres";
+
+/// Prefix of the scratch databases made by test_database(), e.g.
+/// EGUTIL_TEST_DB=egutil_test.  Tests which need a database are
+/// skipped when it is not set.
+pub const TEST_DB_VAR: &str = "EGUTIL_TEST_DB";
+
+/// Scratch database with the schema and sample data for one test,
+/// named for it, on the server given by the PG environment variables.
+/// None when EGUTIL_TEST_DB is not set.
+pub fn test_database(test: &str) -> Option<ScratchDatabase> {
+    let prefix = env::var(TEST_DB_VAR).ok().filter(|p| !p.is_empty())?;
+
+    let template = DatabaseConnection::builder().build();
+
+    match ScratchDatabase::with_sample_data(&template, &format!("{prefix}_{test}")) {
+        Ok(db) => Some(db),
+        Err(e) => panic!("Cannot create test database: {e}"),
+    }
+}
+
+/// True if the database has the Evergreen upgrade log, i.e. it was
+/// created by Evergreen itself and may be a production database.
+pub fn is_evergreen_database<C: pg::GenericClient>(client: &mut C) -> Result<bool, String> {
+    table_exists(client, "config.upgrade_log")
+}
+
+/// True if the database already has an actor.org_unit table.
+pub fn has_schema<C: pg::GenericClient>(client: &mut C) -> Result<bool, String> {
+    table_exists(client, "actor.org_unit")
+}
+
+fn table_exists<C: pg::GenericClient>(client: &mut C, table: &str) -> Result<bool, String> {
+    match client.query("SELECT to_regclass($1)::TEXT AS t", &[&table]) {
+        Ok(rows) => Ok(rows
+            .first()
+            .and_then(|r| r.get::<_, Option<String>>("t"))
+            .is_some()),
+        Err(e) => Err(format!("Error checking for {table}: {e}")),
+    }
+}
+
+pub fn create_schema<C: pg::GenericClient>(client: &mut C) -> Result<(), String> {
+    match client.batch_execute(SCHEMA_SQL) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error creating schema: {e}")),
+    }
+}
+
+pub fn load_sample_data<C: pg::GenericClient>(client: &mut C) -> Result<(), String> {
+    match client.batch_execute(SAMPLE_DATA_SQL) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error loading sample data: {e}")),
+    }
+}
+
+/// Run an SQL file, e.g. from Evergreen's Concerto dataset.
+///
+/// psql \i and \ir includes are followed relative to the including
+/// file and \echo is logged.  Other psql meta-commands are skipped.
+/// SQL between meta-commands is sent as one batch, so statements may
+/// not span an include.
+pub fn load_sql_file<C: pg::GenericClient>(client: &mut C, path: &str) -> Result<(), String> {
+    let sql = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let mut batch = String::new();
+
+    for line in sql.lines() {
+        let trimmed = line.trim();
+
+        if !trimmed.starts_with('\\') {
+            batch += line;
+            batch += "\n";
+            continue;
+        }
+
+        run_batch(client, path, &mut batch)?;
+
+        let (command, arg) = trimmed
+            .split_once(char::is_whitespace)
+            .unwrap_or((trimmed, ""));
+        let arg = arg.trim();
+
+        match command {
+            "\\i" | "\\ir" | "\\include" => {
+                let include = dir.join(arg.trim_matches('\''));
+                load_sql_file(client, &include.to_string_lossy())?;
+            }
+            "\\echo" => info!("{arg}"),
+            _ => warn!("Skipping psql command in {path}: {trimmed}"),
+        }
+    }
+
+    run_batch(client, path, &mut batch)
+}
+
+fn run_batch<C: pg::GenericClient>(
+    client: &mut C,
+    path: &str,
+    batch: &mut String,
+) -> Result<(), String> {
+    if batch.trim().is_empty() {
+        batch.clear();
+        return Ok(());
+    }
+
+    let result = client.batch_execute(batch);
+    batch.clear();
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error running SQL from {path}: {e}")),
+    }
+}
+
+/// A database created for the life of a test run and dropped when
+/// it goes out of scope, unless kept.
+pub struct ScratchDatabase {
+    name: String,
+    admin: DatabaseConnection,
+    connection: DatabaseConnection,
+    keep: bool,
+}
+
+impl ScratchDatabase {
+    /// Create (or re-create) the named database on the server used by
+    /// the template connection.
+    pub fn create(template: &DatabaseConnection, name: &str) -> Result<Self, String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Invalid scratch database name: {name}"));
+        }
+
+        let mut admin = template.clone_for_database(ADMIN_DATABASE);
+        admin.connect()?;
+
+        // These cannot share a query string, which runs as one
+        // implicit transaction.
+        for sql in [
+            format!("DROP DATABASE IF EXISTS {name}"),
+            format!("CREATE DATABASE {name}"),
+        ] {
+            if let Err(e) = admin.client().batch_execute(&sql) {
+                return Err(format!("Cannot create database {name}: {e}"));
+            }
+        }
+
+        admin.disconnect();
+
+        let mut connection = template.clone_for_database(name);
+        connection.connect()?;
+
+        Ok(ScratchDatabase {
+            name: name.to_string(),
+            admin,
+            connection,
+            keep: false,
+        })
+    }
+
+    /// Create a scratch database with the schema and sample data.
+    pub fn with_sample_data(template: &DatabaseConnection, name: &str) -> Result<Self, String> {
+        let mut db = ScratchDatabase::create(template, name)?;
+
+        create_schema(db.connection.client())?;
+        load_sample_data(db.connection.client())?;
+
+        Ok(db)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Connected connection to the scratch database.
+    pub fn connection(&mut self) -> &mut DatabaseConnection {
+        &mut self.connection
+    }
+
+    /// Leave the database in place when dropped, e.g. for debugging.
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for ScratchDatabase {
+    fn drop(&mut self) {
+        self.connection.disconnect();
+
+        if self.keep {
+            info!("Keeping scratch database {}", self.name);
+            return;
+        }
+
+        if let Err(e) = self.admin.connect() {
+            error!("Cannot drop scratch database {}: {e}", self.name);
+            return;
+        }
+
+        let sql = format!("DROP DATABASE IF EXISTS {}", self.name);
+        if let Err(e) = self.admin.client().batch_execute(&sql) {
+            error!("Cannot drop scratch database {}: {e}", self.name);
+        }
+
+        self.admin.disconnect();
+    }
+}
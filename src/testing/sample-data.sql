@@ -0,0 +1,132 @@
+-- Small consortium for exercising the egutil tools against the
+-- schema subset in schema.sql.
+
+INSERT INTO config.copy_status (id, name, holdable, opac_visible) VALUES
+    (0, 'Available', TRUE, TRUE),
+    (1, 'Checked out', TRUE, TRUE),
+    (3, 'Lost', FALSE, TRUE),
+    (6, 'In transit', TRUE, TRUE),
+    (7, 'Reshelving', TRUE, TRUE),
+    (8, 'On holds shelf', TRUE, TRUE),
+    (16, 'Long Overdue', FALSE, TRUE);
+
+INSERT INTO config.billing_type (id, name, owner, default_price) VALUES
+    (1, 'Overdue Materials', 1, NULL),
+    (3, 'Lost Materials', 1, NULL),
+    (4, 'Lost Materials Processing Fee', 1, NULL),
+    (10, 'Long-Overdue Materials', 1, NULL),
+    (11, 'Long-Overdue Materials Processing Fee', 1, NULL),
+    (101, 'Misc', 1, NULL);
+
+INSERT INTO config.standing_penalty (id, name, label, block_list) VALUES
+    (1, 'PATRON_EXCEEDS_FINES', 'Patron exceeds fine threshold', 'CIRC|HOLD|RENEW'),
+    (2, 'PATRON_EXCEEDS_OVERDUE_COUNT', 'Patron exceeds max overdue item threshold', 'CIRC|HOLD|RENEW'),
+    (3, 'PATRON_EXCEEDS_CHECKOUT_COUNT', 'Patron exceeds max checked out item threshold', 'CIRC'),
+    (4, 'PATRON_EXCEEDS_COLLECTIONS_WARNING', 'Patron exceeds pre-collections warning threshold', NULL),
+    (30, 'PATRON_IN_COLLECTIONS', 'Patron has been referred to a collections agency', NULL),
+    (35, 'PATRON_EXCEEDS_LONGOVERDUE_COUNT', 'Patron exceeds max long-overdue item threshold', 'CIRC|HOLD|RENEW'),
+    (36, 'PATRON_EXCEEDS_LOST_COUNT', 'Patron exceeds max lost item threshold', 'CIRC|HOLD|RENEW');
+
+INSERT INTO permission.grp_tree (id, name, parent, usergroup) VALUES
+    (1, 'Users', NULL, FALSE),
+    (2, 'Patrons', 1, TRUE),
+    (3, 'Staff', 1, FALSE),
+    (10, 'Local System Administrator', 3, TRUE),
+    (14, 'Juvenile', 2, TRUE);
+
+INSERT INTO actor.org_unit_type (id, name, opac_label, depth, parent) VALUES
+    (1, 'Consortium', 'Everywhere', 0, NULL),
+    (2, 'System', 'Local Library System', 1, 1),
+    (3, 'Branch', 'This Branch', 2, 2);
+
+INSERT INTO actor.org_unit (id, parent_ou, ou_type, shortname, name) VALUES
+    (1, NULL, 1, 'CONS', 'Example Consortium'),
+    (2, 1, 2, 'SYS1', 'Example System 1'),
+    (3, 1, 2, 'SYS2', 'Example System 2'),
+    (4, 2, 3, 'BR1', 'Example Branch 1'),
+    (5, 2, 3, 'BR2', 'Example Branch 2'),
+    (6, 3, 3, 'BR3', 'Example Branch 3');
+
+INSERT INTO actor.org_unit_setting (org_unit, name, value) VALUES
+    (1, 'global.juvenile_age_threshold', '"18 years"'),
+    (1, 'cat.default_item_price', '"25.00"'),
+    (1, 'circ.lost_materials_processing_fee', '"10.00"'),
+    (1, 'cat.marc_control_number_identifier', '"CONS"');
+
+INSERT INTO asset.copy_location (id, name, owning_lib) VALUES
+    (1, 'Stacks', 1),
+    (2, 'Juvenile', 4),
+    (3, 'Reference', 5);
+
+INSERT INTO asset.call_number_class (id, name, normalizer) VALUES
+    (1, 'Generic', 'asset.label_normalizer_generic'),
+    (2, 'Dewey (DDC)', 'asset.label_normalizer_dewey'),
+    (3, 'Library of Congress (LC)', 'asset.label_normalizer_lc');
+
+INSERT INTO actor.usr (id, profile, usrname, first_given_name, family_name, home_ou, dob, juvenile) VALUES
+    (1, 10, 'admin', 'Administrator', 'System Account', 1, NULL, FALSE),
+    (2, 2, 'patron1', 'Jane', 'Adult', 4, '1980-05-01', FALSE),
+    (3, 14, 'patron2', 'Jimmy', 'Junior', 4, '2015-09-12', TRUE),
+    (4, 14, 'patron3', 'Jessica', 'Grownup', 5, '2000-01-15', TRUE),
+    (5, 2, 'patron4', 'John', 'Branchthree', 6, '1975-11-30', FALSE);
+
+INSERT INTO actor.card (id, usr, barcode) VALUES
+    (1, 1, 'admin'),
+    (2, 2, '21234000000018'),
+    (3, 3, '21234000000026'),
+    (4, 4, '21234000000034'),
+    (5, 5, '21234000000042');
+
+UPDATE actor.usr SET card = id;
+
+INSERT INTO permission.grp_penalty_threshold (grp, org_unit, penalty, threshold) VALUES
+    (1, 1, 1, 10.00),
+    (1, 1, 2, 10),
+    (1, 1, 3, 50),
+    (14, 1, 1, 5.00),
+    (14, 1, 2, 5);
+
+INSERT INTO biblio.record_entry (id, tcn_value, marc) VALUES
+    (-1, 'pre-cataloged', '<record xmlns="http://www.loc.gov/MARC21/slim"><leader>00000nam a2200000 a 4500</leader><datafield tag="245" ind1="0" ind2="0"><subfield code="a">Pre-cataloged</subfield></datafield></record>'),
+    (1, '1', '<record xmlns="http://www.loc.gov/MARC21/slim"><leader>00000nam a2200000 a 4500</leader><controlfield tag="001">1</controlfield><controlfield tag="008">050701s2005    caua          001 0 eng d</controlfield><datafield tag="020" ind1=" " ind2=" "><subfield code="a">9780596008086</subfield></datafield><datafield tag="050" ind1="0" ind2="0"><subfield code="a">QA76.73.R87</subfield><subfield code="b">B34 2005</subfield></datafield><datafield tag="100" ind1="1" ind2=" "><subfield code="a">Blandy, Jim.</subfield></datafield><datafield tag="245" ind1="1" ind2="0"><subfield code="a">Programming in Rust /</subfield><subfield code="c">Jim Blandy.</subfield></datafield><datafield tag="650" ind1=" " ind2="0"><subfield code="a">Computer programming.</subfield></datafield></record>'),
+    (2, '2', '<record xmlns="http://www.loc.gov/MARC21/slim"><leader>00000nam a2200000 a 4500</leader><controlfield tag="001">2</controlfield><controlfield tag="008">990101s1999    nyu           000 1 eng d</controlfield><datafield tag="082" ind1="0" ind2="4"><subfield code="a">813.54</subfield></datafield><datafield tag="100" ind1="1" ind2=" "><subfield code="a">Smith, Anne.</subfield></datafield><datafield tag="245" ind1="1" ind2="4"><subfield code="a">The long winter :</subfield><subfield code="b">a novel /</subfield><subfield code="c">Anne Smith.</subfield></datafield></record>'),
+    (3, '3', '<record xmlns="http://www.loc.gov/MARC21/slim"><leader>00000nam a2200000 a 4500</leader><controlfield tag="001">3</controlfield><controlfield tag="008">100301s2010    dcu           f000 0 eng d</controlfield><datafield tag="086" ind1="0" ind2=" "><subfield code="a">Y 4.F 76/1:AF 8/2</subfield></datafield><datafield tag="245" ind1="0" ind2="0"><subfield code="a">Foreign affairs hearing.</subfield></datafield></record>');
+
+INSERT INTO asset.call_number (id, record, owning_lib, label, label_class) VALUES
+    (-1, -1, 1, '##URI##', 1),
+    (1, 1, 4, 'QA76.73.R87 B34 2005', 3),
+    (2, 2, 4, '813.54 SMI', 2),
+    (3, 2, 5, 'FIC SMITH', 1),
+    (4, 3, 6, 'Y 4.F 76/1:AF 8/2', 1);
+
+INSERT INTO asset.copy (id, circ_lib, call_number, barcode, status, location, price) VALUES
+    (1, 4, 1, '31234000000016', 0, 1, 49.99),
+    (2, 4, 2, '31234000000024', 1, 1, 24.95),
+    (3, 5, 3, '31234000000032', 0, 3, NULL),
+    (4, 6, 4, '31234000000040', 0, 1, 10.00);
+
+INSERT INTO action.circulation (id, usr, xact_start, target_copy, circ_lib, circ_staff,
+    due_date, duration, recurring_fine, max_fine) VALUES
+    (1, 2, NOW() - '30 days'::INTERVAL, 2, 4, 1,
+        NOW() - '16 days'::INTERVAL, '14 days', 0.25, 5.00);
+
+INSERT INTO money.billing (xact, amount, billing_type, btype, period_start, period_end) VALUES
+    (1, 0.25, 'Overdue materials', 1, NOW() - '16 days'::INTERVAL, NOW() - '15 days'::INTERVAL),
+    (1, 0.25, 'Overdue materials', 1, NOW() - '15 days'::INTERVAL, NOW() - '14 days'::INTERVAL);
+
+INSERT INTO action.hold_request (usr, requestor, request_lib, selection_ou, pickup_lib,
+    hold_type, target) VALUES
+    (5, 5, 6, 1, 6, 'T', 2);
+
+SELECT SETVAL('permission.grp_tree_id_seq', 100);
+SELECT SETVAL('actor.org_unit_type_id_seq', 100);
+SELECT SETVAL('actor.org_unit_id_seq', 100);
+SELECT SETVAL('actor.usr_id_seq', 100);
+SELECT SETVAL('actor.card_id_seq', 100);
+SELECT SETVAL('permission.grp_penalty_threshold_id_seq', 100);
+SELECT SETVAL('biblio.record_entry_id_seq', 100);
+SELECT SETVAL('asset.call_number_id_seq', 100);
+SELECT SETVAL('asset.copy_id_seq', 100);
+SELECT SETVAL('asset.copy_location_id_seq', 100);
+SELECT SETVAL('asset.call_number_class_id_seq', 100);
+SELECT SETVAL('money.billable_xact_id_seq', 100);
@@ -0,0 +1,444 @@
+-- Minimal subset of the Evergreen schema used by the egutil tools.
+--
+-- Table and column names match Evergreen, but only commonly used
+-- columns, constraints and functions are included.  Not for use with
+-- the Evergreen application itself.
+
+CREATE SCHEMA config;
+CREATE SCHEMA permission;
+CREATE SCHEMA actor;
+CREATE SCHEMA biblio;
+CREATE SCHEMA asset;
+CREATE SCHEMA action;
+CREATE SCHEMA money;
+CREATE SCHEMA metabib;
+CREATE SCHEMA authority;
+CREATE SCHEMA booking;
+
+CREATE TABLE config.copy_status (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL UNIQUE,
+    holdable    BOOL NOT NULL DEFAULT FALSE,
+    opac_visible BOOL NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE config.billing_type (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL,
+    owner       INT NOT NULL,
+    default_price NUMERIC(6,2)
+);
+
+CREATE TABLE config.standing_penalty (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL UNIQUE,
+    label       TEXT NOT NULL,
+    block_list  TEXT
+);
+
+CREATE TABLE config.internal_flag (
+    name        TEXT PRIMARY KEY,
+    value       TEXT,
+    enabled     BOOL NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE permission.grp_tree (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL UNIQUE,
+    parent      INT REFERENCES permission.grp_tree (id),
+    usergroup   BOOL NOT NULL DEFAULT TRUE,
+    description TEXT
+);
+
+CREATE TABLE actor.org_unit_type (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL,
+    opac_label  TEXT NOT NULL,
+    depth       INT NOT NULL,
+    parent      INT REFERENCES actor.org_unit_type (id),
+    can_have_vols BOOL NOT NULL DEFAULT TRUE,
+    can_have_users BOOL NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE actor.org_unit (
+    id          SERIAL PRIMARY KEY,
+    parent_ou   INT REFERENCES actor.org_unit (id),
+    ou_type     INT NOT NULL REFERENCES actor.org_unit_type (id),
+    shortname   TEXT NOT NULL UNIQUE,
+    name        TEXT NOT NULL,
+    email       TEXT,
+    phone       TEXT,
+    opac_visible BOOL NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE actor.org_unit_setting (
+    id          BIGSERIAL PRIMARY KEY,
+    org_unit    INT NOT NULL REFERENCES actor.org_unit (id),
+    name        TEXT NOT NULL,
+    value       TEXT NOT NULL,
+    UNIQUE (org_unit, name)
+);
+
+CREATE TABLE actor.org_unit_closed (
+    id          SERIAL PRIMARY KEY,
+    org_unit    INT NOT NULL REFERENCES actor.org_unit (id),
+    close_start TIMESTAMPTZ NOT NULL,
+    close_end   TIMESTAMPTZ NOT NULL,
+    full_day    BOOL NOT NULL DEFAULT FALSE,
+    multi_day   BOOL NOT NULL DEFAULT FALSE,
+    reason      TEXT
+);
+
+CREATE TABLE actor.usr (
+    id              SERIAL PRIMARY KEY,
+    card            INT,
+    profile         INT NOT NULL REFERENCES permission.grp_tree (id),
+    usrname         TEXT NOT NULL UNIQUE,
+    email           TEXT,
+    passwd          TEXT NOT NULL DEFAULT '',
+    first_given_name TEXT NOT NULL,
+    second_given_name TEXT,
+    family_name     TEXT NOT NULL,
+    day_phone       TEXT,
+    home_ou         INT NOT NULL REFERENCES actor.org_unit (id),
+    dob             DATE,
+    active          BOOL NOT NULL DEFAULT TRUE,
+    barred          BOOL NOT NULL DEFAULT FALSE,
+    deleted         BOOL NOT NULL DEFAULT FALSE,
+    juvenile        BOOL NOT NULL DEFAULT FALSE,
+    usrgroup        SERIAL NOT NULL,
+    claims_returned_count INT NOT NULL DEFAULT 0,
+    create_date     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expire_date     TIMESTAMPTZ NOT NULL DEFAULT NOW() + '3 years'::INTERVAL,
+    last_update_time TIMESTAMPTZ
+);
+
+CREATE TABLE actor.card (
+    id          SERIAL PRIMARY KEY,
+    usr         INT NOT NULL REFERENCES actor.usr (id),
+    barcode     TEXT NOT NULL UNIQUE,
+    active      BOOL NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE actor.usr_standing_penalty (
+    id              SERIAL PRIMARY KEY,
+    org_unit        INT NOT NULL REFERENCES actor.org_unit (id),
+    usr             INT NOT NULL REFERENCES actor.usr (id),
+    standing_penalty INT NOT NULL REFERENCES config.standing_penalty (id),
+    staff           INT REFERENCES actor.usr (id),
+    set_date        TIMESTAMPTZ DEFAULT NOW(),
+    stop_date       TIMESTAMPTZ,
+    note            TEXT
+);
+
+CREATE TABLE permission.grp_penalty_threshold (
+    id          SERIAL PRIMARY KEY,
+    grp         INT NOT NULL REFERENCES permission.grp_tree (id),
+    org_unit    INT NOT NULL REFERENCES actor.org_unit (id),
+    penalty     INT NOT NULL REFERENCES config.standing_penalty (id),
+    threshold   NUMERIC(8,2) NOT NULL,
+    UNIQUE (grp, org_unit, penalty)
+);
+
+CREATE TABLE biblio.record_entry (
+    id          BIGSERIAL PRIMARY KEY,
+    creator     INT NOT NULL DEFAULT 1,
+    editor      INT NOT NULL DEFAULT 1,
+    source      INT,
+    quality     INT,
+    create_date TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    edit_date   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    active      BOOL NOT NULL DEFAULT TRUE,
+    deleted     BOOL NOT NULL DEFAULT FALSE,
+    fingerprint TEXT,
+    tcn_source  TEXT NOT NULL DEFAULT 'AUTOGEN',
+    tcn_value   TEXT NOT NULL,
+    marc        TEXT NOT NULL,
+    last_xact_id TEXT NOT NULL DEFAULT 'none'
+);
+
+CREATE TABLE metabib.metarecord (
+    id          BIGSERIAL PRIMARY KEY,
+    fingerprint TEXT NOT NULL,
+    master_record BIGINT REFERENCES biblio.record_entry (id),
+    mods        TEXT
+);
+
+CREATE TABLE metabib.metarecord_source_map (
+    id          BIGSERIAL PRIMARY KEY,
+    metarecord  BIGINT NOT NULL REFERENCES metabib.metarecord (id) ON DELETE CASCADE,
+    source      BIGINT NOT NULL REFERENCES biblio.record_entry (id)
+);
+
+CREATE TABLE authority.record_entry (
+    id          BIGSERIAL PRIMARY KEY,
+    create_date TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    edit_date   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    deleted     BOOL NOT NULL DEFAULT FALSE,
+    marc        TEXT NOT NULL
+);
+
+CREATE TABLE authority.bib_linking (
+    id          BIGSERIAL PRIMARY KEY,
+    bib         BIGINT NOT NULL REFERENCES biblio.record_entry (id),
+    authority   BIGINT NOT NULL REFERENCES authority.record_entry (id)
+);
+
+CREATE TABLE asset.copy_location (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL,
+    owning_lib  INT NOT NULL REFERENCES actor.org_unit (id),
+    holdable    BOOL NOT NULL DEFAULT TRUE,
+    circulate   BOOL NOT NULL DEFAULT TRUE,
+    opac_visible BOOL NOT NULL DEFAULT TRUE,
+    deleted     BOOL NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE asset.call_number_class (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL,
+    normalizer  TEXT NOT NULL DEFAULT 'asset.normalize_generic',
+    field       TEXT NOT NULL DEFAULT '050ab,055ab,060ab,070ab,080ab,082ab,086ab,088ab,090,092,096,098,099'
+);
+
+CREATE TABLE asset.call_number_prefix (
+    id          SERIAL PRIMARY KEY,
+    owning_lib  INT NOT NULL REFERENCES actor.org_unit (id),
+    label       TEXT NOT NULL,
+    label_sortkey TEXT
+);
+
+CREATE TABLE asset.call_number_suffix (
+    id          SERIAL PRIMARY KEY,
+    owning_lib  INT NOT NULL REFERENCES actor.org_unit (id),
+    label       TEXT NOT NULL,
+    label_sortkey TEXT
+);
+
+CREATE TABLE asset.call_number (
+    id          BIGSERIAL PRIMARY KEY,
+    creator     BIGINT NOT NULL DEFAULT 1,
+    create_date TIMESTAMPTZ DEFAULT NOW(),
+    editor      BIGINT NOT NULL DEFAULT 1,
+    edit_date   TIMESTAMPTZ DEFAULT NOW(),
+    record      BIGINT NOT NULL REFERENCES biblio.record_entry (id),
+    owning_lib  INT NOT NULL REFERENCES actor.org_unit (id),
+    label       TEXT NOT NULL,
+    deleted     BOOL NOT NULL DEFAULT FALSE,
+    prefix      INT NOT NULL DEFAULT -1,
+    suffix      INT NOT NULL DEFAULT -1,
+    label_class BIGINT REFERENCES asset.call_number_class (id),
+    label_sortkey TEXT
+);
+
+CREATE TABLE asset.copy (
+    id          BIGSERIAL PRIMARY KEY,
+    circ_lib    INT NOT NULL REFERENCES actor.org_unit (id),
+    creator     BIGINT NOT NULL DEFAULT 1,
+    call_number BIGINT NOT NULL REFERENCES asset.call_number (id),
+    editor      BIGINT NOT NULL DEFAULT 1,
+    create_date TIMESTAMPTZ DEFAULT NOW(),
+    edit_date   TIMESTAMPTZ DEFAULT NOW(),
+    copy_number INT,
+    status      INT NOT NULL DEFAULT 0 REFERENCES config.copy_status (id),
+    location    INT NOT NULL DEFAULT 1 REFERENCES asset.copy_location (id),
+    loan_duration INT NOT NULL DEFAULT 2,
+    fine_level  INT NOT NULL DEFAULT 2,
+    age_protect INT,
+    circulate   BOOL NOT NULL DEFAULT TRUE,
+    deposit     BOOL NOT NULL DEFAULT FALSE,
+    ref         BOOL NOT NULL DEFAULT FALSE,
+    holdable    BOOL NOT NULL DEFAULT TRUE,
+    deposit_amount NUMERIC(6,2) NOT NULL DEFAULT 0.00,
+    price       NUMERIC(8,2),
+    barcode     TEXT NOT NULL,
+    circ_modifier TEXT,
+    circ_as_type TEXT,
+    dummy_title TEXT,
+    dummy_author TEXT,
+    alert_message TEXT,
+    opac_visible BOOL NOT NULL DEFAULT TRUE,
+    deleted     BOOL NOT NULL DEFAULT FALSE,
+    status_changed_time TIMESTAMPTZ,
+    active_date TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX copy_barcode_key ON asset.copy (barcode) WHERE deleted = FALSE;
+
+CREATE TABLE money.billable_xact (
+    id          BIGSERIAL PRIMARY KEY,
+    usr         INT NOT NULL REFERENCES actor.usr (id),
+    xact_start  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    xact_finish TIMESTAMPTZ,
+    unrecovered BOOL
+);
+
+CREATE TABLE action.circulation (
+    target_copy     BIGINT NOT NULL REFERENCES asset.copy (id),
+    circ_lib        INT NOT NULL REFERENCES actor.org_unit (id),
+    circ_staff      INT NOT NULL,
+    checkin_staff   INT,
+    checkin_lib     INT,
+    renewal_remaining INT NOT NULL DEFAULT 0,
+    due_date        TIMESTAMPTZ,
+    stop_fines_time TIMESTAMPTZ,
+    checkin_time    TIMESTAMPTZ,
+    create_time     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    duration        INTERVAL,
+    fine_interval   INTERVAL NOT NULL DEFAULT '1 day'::INTERVAL,
+    recurring_fine  NUMERIC(6,2),
+    max_fine        NUMERIC(6,2),
+    phone_renewal   BOOL NOT NULL DEFAULT FALSE,
+    desk_renewal    BOOL NOT NULL DEFAULT FALSE,
+    opac_renewal    BOOL NOT NULL DEFAULT FALSE,
+    duration_rule   TEXT NOT NULL DEFAULT 'default',
+    recurring_fine_rule TEXT NOT NULL DEFAULT 'default',
+    max_fine_rule   TEXT NOT NULL DEFAULT 'default',
+    stop_fines      TEXT,
+    parent_circ     BIGINT,
+    copy_location   INT NOT NULL DEFAULT 1,
+    PRIMARY KEY (id)
+) INHERITS (money.billable_xact);
+
+CREATE TABLE action.hold_request (
+    id              SERIAL PRIMARY KEY,
+    request_time    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    capture_time    TIMESTAMPTZ,
+    fulfillment_time TIMESTAMPTZ,
+    checkin_time    TIMESTAMPTZ,
+    return_time     TIMESTAMPTZ,
+    prev_check_time TIMESTAMPTZ,
+    expire_time     TIMESTAMPTZ,
+    cancel_time     TIMESTAMPTZ,
+    cancel_cause    INT,
+    cancel_note     TEXT,
+    target          BIGINT NOT NULL,
+    current_copy    BIGINT,
+    fulfillment_staff INT,
+    fulfillment_lib INT,
+    request_lib     INT NOT NULL,
+    requestor       INT NOT NULL,
+    usr             INT NOT NULL REFERENCES actor.usr (id),
+    selection_ou    INT NOT NULL,
+    selection_depth INT NOT NULL DEFAULT 0,
+    pickup_lib      INT NOT NULL REFERENCES actor.org_unit (id),
+    hold_type       TEXT NOT NULL,
+    holdable_formats TEXT,
+    frozen          BOOL NOT NULL DEFAULT FALSE,
+    thaw_date       TIMESTAMPTZ,
+    shelf_time      TIMESTAMPTZ,
+    shelf_expire_time TIMESTAMPTZ,
+    current_shelf_lib INT
+);
+
+CREATE TABLE money.billing (
+    id          BIGSERIAL PRIMARY KEY,
+    xact        BIGINT NOT NULL,
+    billing_ts  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    voided      BOOL NOT NULL DEFAULT FALSE,
+    voider      INT,
+    void_time   TIMESTAMPTZ,
+    amount      NUMERIC(6,2) NOT NULL,
+    billing_type TEXT NOT NULL,
+    btype       INT NOT NULL REFERENCES config.billing_type (id),
+    note        TEXT,
+    period_start TIMESTAMPTZ,
+    period_end  TIMESTAMPTZ
+);
+
+CREATE TABLE money.payment (
+    id          BIGSERIAL PRIMARY KEY,
+    xact        BIGINT NOT NULL,
+    payment_ts  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    voided      BOOL NOT NULL DEFAULT FALSE,
+    amount      NUMERIC(6,2) NOT NULL,
+    note        TEXT
+);
+
+CREATE TABLE money.account_adjustment (
+    accepting_usr INT NOT NULL,
+    billing     BIGINT REFERENCES money.billing (id),
+    PRIMARY KEY (id)
+) INHERITS (money.payment);
+
+CREATE TABLE money.grocery (
+    billing_location INT NOT NULL REFERENCES actor.org_unit (id),
+    note        TEXT,
+    PRIMARY KEY (id)
+) INHERITS (money.billable_xact);
+
+CREATE TABLE booking.reservation (
+    request_time    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    start_time      TIMESTAMPTZ,
+    end_time        TIMESTAMPTZ,
+    capture_time    TIMESTAMPTZ,
+    cancel_time     TIMESTAMPTZ,
+    pickup_time     TIMESTAMPTZ,
+    return_time     TIMESTAMPTZ,
+    target_resource_type INT NOT NULL,
+    target_resource INT,
+    current_resource INT,
+    request_lib     INT NOT NULL REFERENCES actor.org_unit (id),
+    pickup_lib      INT REFERENCES actor.org_unit (id),
+    PRIMARY KEY (id)
+) INHERITS (money.billable_xact);
+
+-- A table kept up to date by triggers in Evergreen.
+CREATE VIEW money.materialized_billable_xact_summary AS
+    SELECT xact.id, xact.usr, xact.xact_start, xact.xact_finish, xact.unrecovered,
+        COALESCE(paid.total, 0.0) AS total_paid,
+        COALESCE(owed.total, 0.0) AS total_owed,
+        COALESCE(owed.total, 0.0) - COALESCE(paid.total, 0.0) AS balance_owed,
+        SPLIT_PART(xact.tableoid::REGCLASS::TEXT, '.', 2) AS xact_type
+    FROM money.billable_xact xact
+        LEFT JOIN (
+            SELECT xact, SUM(amount) AS total FROM money.billing
+            WHERE NOT voided GROUP BY xact
+        ) owed ON owed.xact = xact.id
+        LEFT JOIN (
+            SELECT xact, SUM(amount) AS total FROM money.payment
+            WHERE NOT voided GROUP BY xact
+        ) paid ON paid.xact = xact.id;
+
+CREATE VIEW action.all_circulation AS SELECT * FROM action.circulation;
+CREATE VIEW action.all_hold_request AS SELECT * FROM action.hold_request;
+
+CREATE FUNCTION actor.org_unit_descendants (INT) RETURNS SETOF actor.org_unit AS $$
+    WITH RECURSIVE descendants AS (
+        SELECT * FROM actor.org_unit WHERE id = $1
+        UNION ALL
+        SELECT aou.* FROM actor.org_unit aou
+            JOIN descendants d ON aou.parent_ou = d.id
+    ) SELECT * FROM descendants;
+$$ LANGUAGE SQL STABLE ROWS 1;
+
+CREATE FUNCTION actor.org_unit_ancestors (INT) RETURNS SETOF actor.org_unit AS $$
+    WITH RECURSIVE ancestors AS (
+        SELECT 1 AS level, * FROM actor.org_unit WHERE id = $1
+        UNION ALL
+        SELECT a.level + 1, aou.* FROM actor.org_unit aou
+            JOIN ancestors a ON aou.id = a.parent_ou
+    ) SELECT id, parent_ou, ou_type, shortname, name, email, phone, opac_visible
+    FROM ancestors ORDER BY level DESC;
+$$ LANGUAGE SQL STABLE ROWS 1;
+
+CREATE FUNCTION actor.org_unit_ancestor_setting (setting_name TEXT, org_id INT)
+RETURNS SETOF actor.org_unit_setting AS $$
+DECLARE
+    setting RECORD;
+    cur_org INT;
+BEGIN
+    cur_org := org_id;
+    LOOP
+        SELECT INTO setting * FROM actor.org_unit_setting
+            WHERE org_unit = cur_org AND name = setting_name;
+        IF FOUND THEN
+            RETURN NEXT setting;
+            EXIT;
+        END IF;
+        SELECT INTO cur_org parent_ou FROM actor.org_unit WHERE id = cur_org;
+        EXIT WHEN cur_org IS NULL;
+    END LOOP;
+    RETURN;
+END;
+$$ LANGUAGE plpgsql STABLE ROWS 1;
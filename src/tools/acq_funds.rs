@@ -0,0 +1,308 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use log::info;
+use postgres as pg;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+const COLUMNS: &[&str] = &[
+    "fund",
+    "org",
+    "code",
+    "name",
+    "year",
+    "currency",
+    "active",
+    "rollover",
+    "propagate",
+    "allocated",
+    "encumbered",
+    "spent",
+    "unspent",
+    "balance",
+];
+
+struct FundOptions {
+    year: Option<i32>,
+    org_unit: Option<i32>,
+    json: bool,
+    out_file: Option<String>,
+    rollover_preview: bool,
+    propagate_only: bool,
+    encumb_only: bool,
+    staff: Option<i32>,
+}
+
+fn read_options() -> (FundOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "year", "Fiscal Year", "YEAR");
+    opts.optopt(
+        "",
+        "org-unit",
+        "Limit to Org Unit and Descendants",
+        "ORG_ID",
+    );
+    opts.optopt("", "out-file", "Output File", "FILE");
+    opts.optopt("", "staff", "Staff User ID for Rollover Preview", "USER_ID");
+    opts.optflag("", "json", "Output JSON Instead of CSV");
+    opts.optflag("", "rollover-preview", "Show Year-End Rollover Results");
+    opts.optflag("", "propagate-only", "Preview Propagation Without Rollover");
+    opts.optflag("", "encumb-only", "Preview Rollover of Encumbrances Only");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        FundOptions {
+            year: params.opt_get("year").unwrap(),
+            org_unit: params.opt_get("org-unit").unwrap(),
+            json: params.opt_present("json"),
+            out_file: params.opt_str("out-file"),
+            rollover_preview: params.opt_present("rollover-preview"),
+            propagate_only: params.opt_present("propagate-only"),
+            encumb_only: params.opt_present("encumb-only"),
+            staff: params.opt_get("staff").unwrap(),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin acq-funds -- --year 2024 --org-unit 1
+    cargo run --bin acq-funds -- --year 2024 --org-unit 1 --rollover-preview --staff 1
+
+Summarizes acquisitions funds: allocations, encumbrances, expenditures
+and balances per fund and fiscal year.
+
+    unspent = allocated - spent
+    balance = allocated - spent - encumbered
+
+Rollover Preview
+
+With --rollover-preview, the year-end fiscal rollover for --year and
+--org-unit (including descendants) is run inside a transaction using
+acq.rollover_funds_by_org_tree(), or acq.propagate_funds_by_org_tree()
+with --propagate-only.  The resulting funds for the old and new years
+are reported, then the transaction is rolled back.  Nothing is saved.
+
+Options
+
+    --year
+        Limit to funds for this fiscal year.  Required for
+        --rollover-preview.
+
+    --org-unit
+        Limit to funds owned by this org unit and its descendants.
+        Required for --rollover-preview.
+
+    --json
+        Write a JSON array of objects instead of CSV.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+
+    --rollover-preview
+        Report fund values as they would be after rollover.
+
+    --propagate-only
+        Preview propagation (creating next year's funds) without
+        moving money.
+
+    --encumb-only
+        Preview a rollover which only moves encumbrances.
+
+    --staff
+        Staff user the rollover runs as.  Required for
+        --rollover-preview.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Fund summary rows for a year (or all years) as text values,
+/// indexed like COLUMNS.
+fn summarize<C: pg::GenericClient>(
+    client: &mut C,
+    years: &[i32],
+    org_unit: Option<i32>,
+) -> Result<Vec<Vec<String>>, String> {
+    let sql = r#"
+        SELECT
+            f.id::TEXT AS fund,
+            aou.shortname AS org,
+            f.code,
+            f.name,
+            f.year::TEXT AS year,
+            f.currency_type AS currency,
+            f.active::TEXT AS active,
+            f.rollover::TEXT AS rollover,
+            f.propagate::TEXT AS propagate,
+            COALESCE(alloc.amount, 0)::TEXT AS allocated,
+            COALESCE(enc.amount, 0)::TEXT AS encumbered,
+            COALESCE(spent.amount, 0)::TEXT AS spent,
+            (COALESCE(alloc.amount, 0) - COALESCE(spent.amount, 0))::TEXT AS unspent,
+            (COALESCE(alloc.amount, 0) - COALESCE(spent.amount, 0)
+                - COALESCE(enc.amount, 0))::TEXT AS balance
+        FROM acq.fund f
+            JOIN actor.org_unit aou ON aou.id = f.org
+            LEFT JOIN acq.fund_allocation_total alloc ON alloc.fund = f.id
+            LEFT JOIN acq.fund_encumbrance_total enc ON enc.fund = f.id
+            LEFT JOIN acq.fund_spent_total spent ON spent.fund = f.id
+        WHERE (CARDINALITY($1::INT[]) = 0 OR f.year = ANY($1::INT[]))
+            AND ($2::INT IS NULL OR f.org IN (
+                SELECT id FROM actor.org_unit_descendants($2::INT)
+            ))
+        ORDER BY f.year, aou.shortname, f.code
+    "#;
+
+    let years = years.to_vec();
+    let rows = client
+        .query(sql, &[&years, &org_unit])
+        .map_err(|e| db_err("Error summarizing funds", e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| COLUMNS.iter().map(|c| row.get::<_, String>(*c)).collect())
+        .collect())
+}
+
+fn rollover_preview(
+    con: &mut DatabaseConnection,
+    ops: &FundOptions,
+) -> Result<Vec<Vec<String>>, String> {
+    let (year, org_unit, staff) = match (ops.year, ops.org_unit, ops.staff) {
+        (Some(y), Some(o), Some(s)) => (y, o, s),
+        _ => return Err("--rollover-preview requires --year, --org-unit and --staff".to_string()),
+    };
+
+    let mut tx = con
+        .client()
+        .transaction()
+        .map_err(|e| db_err("Cannot start transaction", e))?;
+
+    let before = summarize(&mut tx, &[year + 1], Some(org_unit))?.len();
+
+    let result = if ops.propagate_only {
+        tx.execute(
+            "SELECT acq.propagate_funds_by_org_tree($1, $2, $3, TRUE)",
+            &[&year, &staff, &org_unit],
+        )
+    } else {
+        tx.execute(
+            "SELECT acq.rollover_funds_by_org_tree($1, $2, $3, $4, TRUE)",
+            &[&year, &staff, &org_unit, &ops.encumb_only],
+        )
+    };
+
+    result.map_err(|e| db_err("Error running rollover", e))?;
+
+    let rows = summarize(&mut tx, &[year, year + 1], Some(org_unit))?;
+
+    let after = rows
+        .iter()
+        .filter(|r| r[4] == (year + 1).to_string())
+        .count();
+    info!(
+        "Rollover would create {} fund(s) for {}",
+        after - before,
+        year + 1
+    );
+
+    // Dropping the transaction rolls it back.
+    drop(tx);
+
+    Ok(rows)
+}
+
+fn format_output(ops: &FundOptions, rows: &[Vec<String>]) -> String {
+    if ops.json {
+        let list: Vec<Value> = rows
+            .iter()
+            .map(|row| {
+                let mut obj = json!({});
+                for (idx, col) in COLUMNS.iter().enumerate() {
+                    obj[*col] = json!(row[idx]);
+                }
+                obj
+            })
+            .collect();
+
+        return serde_json::to_string_pretty(&Value::Array(list)).unwrap_or_default() + "\n";
+    }
+
+    let mut out = COLUMNS.join(",") + "\n";
+
+    for row in rows {
+        let fields: Vec<String> = row
+            .iter()
+            .map(|v| {
+                if v.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", v.replace('"', "\"\""))
+                } else {
+                    v.to_string()
+                }
+            })
+            .collect();
+
+        out += &fields.join(",");
+        out += "\n";
+    }
+
+    out
+}
+
+fn run(con: &mut DatabaseConnection, ops: &FundOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let rows = match ops.rollover_preview {
+        true => rollover_preview(con, ops)?,
+        false => {
+            let years: Vec<i32> = ops.year.into_iter().collect();
+            summarize(con.client(), &years, ops.org_unit)?
+        }
+    };
+
+    con.disconnect();
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    if let Err(e) = writer.write_all(format_output(ops, &rows).as_bytes()) {
+        return Err(format!("Error writing output: {e}"));
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    run(&mut connection, &options)
+}
@@ -0,0 +1,703 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::export::{Format, MarcWriter, RecordSink};
+use crate::marc;
+use log::{debug, info, warn};
+use marcutil::{Field, Record};
+use postgres as pg;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Copy values a template may map to order fields.
+const COPY_VALUES: &[&str] = &[
+    "quantity",
+    "fund",
+    "owning_lib",
+    "location",
+    "circ_modifier",
+    "collection_code",
+    "call_number",
+    "barcode",
+    "note",
+    "price",
+];
+
+struct LoadOptions {
+    in_file: String,
+    binary: bool,
+    template_file: String,
+    staff: i32,
+    provider: Option<String>,
+    ordering_agency: Option<String>,
+    po_name: Option<String>,
+    fund_year: Option<i32>,
+    reject_file: Option<String>,
+    dry_run: DryRun,
+}
+
+struct Template {
+    copy_tag: String,
+    provider: Option<String>,
+    ordering_agency: Option<String>,
+    /// Copy value name => subfield code.
+    subfields: HashMap<String, String>,
+    /// Copy value name => value used when the subfield is missing.
+    defaults: HashMap<String, String>,
+    fund_year: Option<i32>,
+}
+
+/// Copies ordered by one copy field.
+struct Distribution {
+    quantity: i32,
+    fund: String,
+    owning_lib: String,
+    location: Option<String>,
+    circ_modifier: Option<String>,
+    collection_code: Option<String>,
+    call_number: Option<String>,
+    barcode: Option<String>,
+    note: Option<String>,
+    price: Option<String>,
+}
+
+#[derive(Default)]
+struct Summary {
+    records: usize,
+    rejected: usize,
+    lineitems: usize,
+    copies: usize,
+}
+
+/// Lookups repeated for most records.
+#[derive(Default)]
+struct Cache {
+    orgs: HashMap<String, i32>,
+    funds: HashMap<(String, i32), i32>,
+    locations: HashMap<(i32, String), i32>,
+}
+
+fn read_options() -> (LoadOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "in-file", "Brief Order MARC File", "INPUT_FILE");
+    opts.optflag("", "binary", "Files are Binary MARC");
+    opts.optopt("", "template", "Order Template File", "TEMPLATE_FILE");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "provider", "Provider Code", "CODE");
+    opts.optopt("", "ordering-agency", "Ordering Org Unit", "SHORTNAME");
+    opts.optopt("", "po-name", "Purchase Order Name", "NAME");
+    opts.optopt("", "fund-year", "Fund Year", "YEAR");
+    opts.optopt("", "reject-file", "Rejected Record File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        LoadOptions {
+            in_file: cli::required(&params, "in-file"),
+            binary: params.opt_present("binary"),
+            template_file: cli::required(&params, "template"),
+            staff: cli::required(&params, "staff"),
+            provider: params.opt_str("provider"),
+            ordering_agency: params.opt_str("ordering-agency"),
+            po_name: params.opt_str("po-name"),
+            fund_year: params.opt_get("fund-year").unwrap(),
+            reject_file: params.opt_str("reject-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin acq-load -- --in-file firm-order.mrc --binary \
+        --template order.json --staff 1 --po-name "BT 2025-03"
+
+Loads a brief order MARC file as one purchase order, with a lineitem
+per record and lineitem details (copies) distributed by the record's
+970 copy fields, in place of the staff client upload for large firm
+orders.
+
+Each copy field orders its quantity of copies for an owning library,
+charged to a fund, by code, for the fund year.  Funds are found among
+the owning library and its ancestors.  The purchase order is left
+pending, for review and activation in the staff client.
+
+Records with no copy fields, or whose fund, library or copy location
+is unknown, are left out of the order and written to --reject-file.
+
+The template maps copy values to copy field subfields, with defaults
+for values missing from a field:
+
+    {{
+        "copy_tag": "970",
+        "provider": "BT",
+        "ordering_agency": "SYS1",
+        "subfields": {{
+            "quantity": "q",
+            "fund": "f",
+            "owning_lib": "b",
+            "location": "c",
+            "circ_modifier": "m",
+            "collection_code": "d",
+            "price": "p",
+            "note": "n"
+        }},
+        "defaults": {{"quantity": 1, "fund": "ADULT"}},
+        "fund_year": 2025
+    }}
+
+Copy values are quantity, fund (code), owning_lib (shortname),
+location (copy location name), circ_modifier, collection_code,
+call_number, barcode, note and price, the lineitem's estimated unit
+price.  fund and owning_lib are required.
+
+Options
+
+    --in-file
+        Brief order MARC file.  Required.
+
+    --binary
+        Files are binary MARC.  Otherwise, MARC XML.
+
+    --template
+        Order template.  Required.
+
+    --staff
+        Staff user recorded as purchase order owner and lineitem
+        selector.  Required.
+
+    --provider
+        acq.provider code.  Overrides the template.
+
+    --ordering-agency
+        Ordering org unit shortname.  Overrides the template.
+
+    --po-name
+        Purchase order name.  Defaults to the input file name.
+
+    --fund-year
+        Fund year.  Overrides the template, which defaults to the
+        current year.
+
+    --reject-file
+        Write rejected records to this file, in the input format.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Template values may be JSON strings or numbers.
+fn value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn read_template(path: &str) -> Result<Template, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let template: Value = match serde_json::from_str(&text) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let mut maps: [HashMap<String, String>; 2] = Default::default();
+
+    for (idx, key) in ["subfields", "defaults"].iter().enumerate() {
+        if let Some(map) = template[*key].as_object() {
+            for (name, value) in map {
+                if !COPY_VALUES.contains(&name.as_str()) {
+                    return Err(format!("Unknown copy value in {path}: {name}"));
+                }
+                if let Some(value) = value_string(value) {
+                    maps[idx].insert(name.to_string(), value);
+                }
+            }
+        }
+    }
+
+    let [subfields, defaults] = maps;
+
+    Ok(Template {
+        copy_tag: template["copy_tag"].as_str().unwrap_or("970").to_string(),
+        provider: template["provider"].as_str().map(|s| s.to_string()),
+        ordering_agency: template["ordering_agency"].as_str().map(|s| s.to_string()),
+        subfields,
+        defaults,
+        fund_year: template["fund_year"].as_i64().map(|y| y as i32),
+    })
+}
+
+/// A copy value from its subfield, or the template default.
+fn copy_value(template: &Template, field: &Field, name: &str) -> Option<String> {
+    let from_field = template.subfields.get(name).and_then(|code| {
+        field
+            .subfields
+            .iter()
+            .find(|sf| sf.code.eq(code))
+            .map(|sf| sf.content.trim().to_string())
+            .filter(|v| !v.is_empty())
+    });
+
+    from_field.or_else(|| template.defaults.get(name).cloned())
+}
+
+fn read_distribution(template: &Template, field: &Field) -> Result<Distribution, String> {
+    let value = |name: &str| copy_value(template, field, name);
+
+    let quantity = match value("quantity") {
+        Some(q) => match q.parse::<i32>() {
+            Ok(q) if q > 0 => q,
+            _ => return Err(format!("Invalid quantity {q}")),
+        },
+        None => 1,
+    };
+
+    let fund = match value("fund") {
+        Some(f) => f,
+        None => return Err("No fund".to_string()),
+    };
+
+    let owning_lib = match value("owning_lib") {
+        Some(o) => o,
+        None => return Err("No owning library".to_string()),
+    };
+
+    // Vendors send prices like $24.95.
+    let price = value("price")
+        .map(|p| {
+            p.chars()
+                .filter(|c| c.is_ascii_digit() || *c == '.')
+                .collect()
+        })
+        .filter(|p: &String| p.parse::<f64>().is_ok());
+
+    Ok(Distribution {
+        quantity,
+        fund,
+        owning_lib,
+        location: value("location"),
+        circ_modifier: value("circ_modifier"),
+        collection_code: value("collection_code"),
+        call_number: value("call_number"),
+        barcode: value("barcode"),
+        note: value("note"),
+        price,
+    })
+}
+
+fn org_id(tx: &mut pg::Transaction, cache: &mut Cache, shortname: &str) -> Result<i32, String> {
+    if let Some(id) = cache.orgs.get(shortname) {
+        return Ok(*id);
+    }
+
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+    let id: i32 = match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No such org unit: {shortname}")),
+        Err(e) => return Err(db_err("Error loading org unit", e)),
+    };
+
+    cache.orgs.insert(shortname.to_string(), id);
+
+    Ok(id)
+}
+
+/// Active fund with the code and year owned by the library or its
+/// nearest ancestor.
+fn fund_id(
+    tx: &mut pg::Transaction,
+    cache: &mut Cache,
+    code: &str,
+    year: i32,
+    org: i32,
+) -> Result<i32, String> {
+    let key = (format!("{code}@{org}"), year);
+    if let Some(id) = cache.funds.get(&key) {
+        return Ok(*id);
+    }
+
+    let sql = r#"
+        SELECT fund.id
+        FROM acq.fund fund
+            JOIN actor.org_unit_ancestors($3) anc ON anc.id = fund.org
+            JOIN actor.org_unit_type aout ON aout.id = anc.ou_type
+        WHERE fund.code = $1 AND fund.year = $2 AND fund.active
+        ORDER BY aout.depth DESC
+        LIMIT 1
+    "#;
+
+    let id: i32 = match tx.query_opt(sql, &[&code, &year, &org]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No active {year} fund {code} for org unit {org}")),
+        Err(e) => return Err(db_err("Error loading fund", e)),
+    };
+
+    cache.funds.insert(key, id);
+
+    Ok(id)
+}
+
+/// The named location owned by the library or its nearest ancestor.
+fn location_id(
+    tx: &mut pg::Transaction,
+    cache: &mut Cache,
+    org: i32,
+    name: &str,
+) -> Result<i32, String> {
+    let key = (org, name.to_string());
+    if let Some(id) = cache.locations.get(&key) {
+        return Ok(*id);
+    }
+
+    let sql = r#"
+        SELECT acpl.id
+        FROM asset.copy_location acpl
+            JOIN actor.org_unit_ancestors($1) anc ON anc.id = acpl.owning_lib
+            JOIN actor.org_unit_type aout ON aout.id = anc.ou_type
+        WHERE acpl.name = $2 AND NOT acpl.deleted
+        ORDER BY aout.depth DESC
+        LIMIT 1
+    "#;
+
+    let id: i32 = match tx.query_opt(sql, &[&org, &name]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No copy location {name} for org unit {org}")),
+        Err(e) => return Err(db_err("Error loading copy location", e)),
+    };
+
+    cache.locations.insert(key, id);
+
+    Ok(id)
+}
+
+fn provider_id(tx: &mut pg::Transaction, code: &str, agency: i32) -> Result<i32, String> {
+    // Provider codes are unique per owner, so prefer the closest.
+    let sql = r#"
+        SELECT acqpro.id
+        FROM acq.provider acqpro
+            JOIN actor.org_unit_ancestors($2) anc ON anc.id = acqpro.owner
+            JOIN actor.org_unit_type aout ON aout.id = anc.ou_type
+        WHERE acqpro.code = $1 AND acqpro.active
+        ORDER BY aout.depth DESC
+        LIMIT 1
+    "#;
+
+    match tx.query_opt(sql, &[&code, &agency]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No active provider {code} for org unit {agency}")),
+        Err(e) => Err(db_err("Error loading provider", e)),
+    }
+}
+
+fn create_po(
+    tx: &mut pg::Transaction,
+    ops: &LoadOptions,
+    name: &str,
+    agency: i32,
+    provider: i32,
+) -> Result<i32, String> {
+    let sql = "SELECT id FROM acq.purchase_order WHERE ordering_agency = $1 AND name = $2";
+    let existing = tx
+        .query_opt(sql, &[&agency, &name])
+        .map_err(|e| db_err("Error checking purchase order name", e))?;
+
+    if let Some(row) = existing {
+        let id: i32 = row.get("id");
+        return Err(format!("Purchase order {id} is already named {name}"));
+    }
+
+    let sql = r#"
+        INSERT INTO acq.purchase_order
+            (owner, creator, editor, ordering_agency, provider, name, state)
+        VALUES ($1, $1, $1, $2, $3, $4, 'pending')
+        RETURNING id
+    "#;
+
+    let row = tx
+        .query_one(sql, &[&ops.staff, &agency, &provider, &name])
+        .map_err(|e| db_err("Error creating purchase order", e))?;
+
+    Ok(row.get("id"))
+}
+
+/// Fund, org and location IDs for one distribution.
+fn resolve(
+    tx: &mut pg::Transaction,
+    cache: &mut Cache,
+    year: i32,
+    dist: &Distribution,
+) -> Result<(i32, i32, Option<i32>), String> {
+    let owning_lib = org_id(tx, cache, &dist.owning_lib)?;
+    let fund = fund_id(tx, cache, &dist.fund, year, owning_lib)?;
+    let location = match &dist.location {
+        Some(name) => Some(location_id(tx, cache, owning_lib, name)?),
+        None => None,
+    };
+
+    Ok((fund, owning_lib, location))
+}
+
+/// Add a lineitem and its copies for one record.  Returns the number
+/// of copies, or the reason the record is rejected.
+#[allow(clippy::too_many_arguments)]
+fn add_lineitem(
+    tx: &mut pg::Transaction,
+    ops: &LoadOptions,
+    template: &Template,
+    cache: &mut Cache,
+    po: i32,
+    provider: i32,
+    year: i32,
+    record: &Record,
+) -> Result<Result<i32, String>, String> {
+    let fields: Vec<&Field> = record
+        .fields
+        .iter()
+        .filter(|f| f.tag == template.copy_tag)
+        .collect();
+
+    if fields.is_empty() {
+        return Ok(Err(format!("No {} copy fields", template.copy_tag)));
+    }
+
+    // Check every copy field before adding anything.
+    let mut copies = Vec::new();
+
+    for field in fields {
+        let dist = match read_distribution(template, field) {
+            Ok(d) => d,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        match resolve(tx, cache, year, &dist) {
+            Ok(ids) => copies.push((dist, ids)),
+            Err(e) => return Ok(Err(e)),
+        }
+    }
+
+    let price = copies.iter().find_map(|(d, _)| d.price.as_ref());
+
+    // The copy fields are order data, not part of the bib.
+    let mut bib = record.clone();
+    bib.fields.retain(|f| f.tag != template.copy_tag);
+
+    let sql = r#"
+        INSERT INTO acq.lineitem (
+            creator, editor, selector, provider, purchase_order,
+            marc, state, estimated_unit_price, source_label
+        ) VALUES (
+            $1, $1, $1, $2, $3, $4, 'pending-order', $5::TEXT::NUMERIC, $6
+        )
+        RETURNING id
+    "#;
+
+    let source = Path::new(&ops.in_file)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string());
+
+    let row = tx
+        .query_one(
+            sql,
+            &[&ops.staff, &provider, &po, &bib.to_xml()?, &price, &source],
+        )
+        .map_err(|e| db_err("Error creating lineitem", e))?;
+
+    let lineitem: i32 = row.get("id");
+
+    let sql = r#"
+        INSERT INTO acq.lineitem_detail (
+            lineitem, fund, owning_lib, location, circ_modifier,
+            collection_code, cn_label, barcode, note
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    "#;
+
+    let mut count = 0;
+
+    for (dist, (fund, owning_lib, location)) in &copies {
+        for _ in 0..dist.quantity {
+            tx.execute(
+                sql,
+                &[
+                    &lineitem,
+                    fund,
+                    owning_lib,
+                    location,
+                    &dist.circ_modifier,
+                    &dist.collection_code,
+                    &dist.call_number,
+                    &dist.barcode,
+                    &dist.note,
+                ],
+            )
+            .map_err(|e| db_err("Error creating lineitem detail", e))?;
+
+            count += 1;
+        }
+    }
+
+    debug!("Lineitem {lineitem} has {count} copies");
+
+    Ok(Ok(count))
+}
+
+fn load(con: &mut DatabaseConnection, ops: &LoadOptions) -> Result<(), String> {
+    let template = read_template(&ops.template_file)?;
+    let records = marc::read_file(&ops.in_file, ops.binary)?;
+
+    let provider_code = match ops.provider.as_ref().or(template.provider.as_ref()) {
+        Some(p) => p.to_string(),
+        None => return Err("No provider in --provider or the template".to_string()),
+    };
+
+    let agency_name = match ops
+        .ordering_agency
+        .as_ref()
+        .or(template.ordering_agency.as_ref())
+    {
+        Some(a) => a.to_string(),
+        None => return Err("No ordering agency in --ordering-agency or the template".to_string()),
+    };
+
+    let po_name = match &ops.po_name {
+        Some(n) => n.to_string(),
+        None => Path::new(&ops.in_file)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| ops.in_file.to_string()),
+    };
+
+    con.connect()?;
+
+    let year = match ops.fund_year.or(template.fund_year) {
+        Some(y) => y,
+        None => {
+            let row = con
+                .client()
+                .query_one("SELECT EXTRACT(YEAR FROM NOW())::INT AS year", &[])
+                .map_err(|e| db_err("Error loading year", e))?;
+            row.get("year")
+        }
+    };
+
+    let mut rejects = match &ops.reject_file {
+        Some(path) => {
+            let format = match ops.binary {
+                true => Format::Binary,
+                false => Format::Xml,
+            };
+            let mut writer = MarcWriter::create(path, format)?;
+            writer.begin()?;
+            Some(writer)
+        }
+        None => None,
+    };
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut cache = Cache::default();
+    let agency = org_id(&mut tx, &mut cache, &agency_name)?;
+    let provider = provider_id(&mut tx, &provider_code, agency)?;
+    let po = create_po(&mut tx, ops, &po_name, agency, provider)?;
+
+    let mut summary = Summary::default();
+
+    for (idx, record) in records.iter().enumerate() {
+        summary.records += 1;
+
+        let result = add_lineitem(
+            &mut tx, ops, &template, &mut cache, po, provider, year, record,
+        )?;
+
+        match result {
+            Ok(copies) => {
+                summary.lineitems += 1;
+                summary.copies += copies as usize;
+            }
+            Err(reason) => {
+                let label = marc::tcn(record).unwrap_or_else(|| format!("#{}", idx + 1));
+                warn!("Rejected record {label}: {reason}");
+
+                summary.rejected += 1;
+
+                if let Some(ref mut writer) = rejects {
+                    writer.write_record(&record.to_xml()?)?;
+                }
+            }
+        }
+    }
+
+    if let Some(ref mut writer) = rejects {
+        writer.finish()?;
+    }
+
+    if summary.lineitems == 0 {
+        tx.rollback()
+            .map_err(|e| db_err("Error rolling back changes", e))?;
+        return Err(format!("No records in {} could be ordered", ops.in_file));
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("lineitems", summary.lineitems as u64);
+    audit::count("copies", summary.copies as u64);
+
+    info!(
+        "{} purchase order {po} ({po_name}) with {} lineitems and {} copies",
+        ops.dry_run.past("Created"),
+        summary.lineitems,
+        summary.copies
+    );
+
+    if summary.rejected > 0 {
+        warn!(
+            "{} of {} records rejected",
+            summary.rejected, summary.records
+        );
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(load(&mut connection, &options))
+}
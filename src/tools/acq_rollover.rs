@@ -0,0 +1,566 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::money::Money;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use std::collections::HashMap;
+use std::process;
+
+const LEDGER_COLUMNS: &[&str] = &[
+    "org_unit",
+    "action",
+    "from_fund",
+    "from_code",
+    "to_fund",
+    "to_code",
+    "amount",
+    "currency",
+    "note",
+];
+
+struct RolloverOptions {
+    year: i32,
+    org_units: Vec<String>,
+    staff: i32,
+    propagate_only: bool,
+    encumb_only: bool,
+    no_descendants: bool,
+    ledger_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// One money movement, or fund change, made by the rollover.
+struct Entry {
+    action: &'static str,
+    from_fund: Option<i32>,
+    to_fund: Option<i32>,
+    amount: Option<Money>,
+    currency: Option<String>,
+    note: Option<String>,
+}
+
+/// Row IDs and fund state before the rollover, for finding what it
+/// changed.
+struct Snapshot {
+    max_transfer: i32,
+    max_allocation: i32,
+    max_debit: i32,
+    /// Encumbrance debit ID => fund.
+    encumbrances: HashMap<i32, i32>,
+    /// Active fund IDs for the closing year.
+    active: Vec<i32>,
+    /// Fund IDs for the new year.
+    next_year: Vec<i32>,
+}
+
+#[derive(Default)]
+struct Summary {
+    propagated: usize,
+    transfers: usize,
+    transferred: Money,
+    encumbrances: usize,
+    encumbered: Money,
+    closed: usize,
+}
+
+fn read_options() -> (RolloverOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "year", "Fiscal Year to Close", "YEAR");
+    opts.optmulti("", "org-unit", "Org Unit to Roll Over", "SHORTNAME");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optflag("", "propagate-only", "Propagate Funds Without Rollover");
+    opts.optflag("", "encumb-only", "Roll Over Encumbrances Only");
+    opts.optflag("", "no-descendants", "Skip Descendant Org Units");
+    opts.optopt("", "ledger-file", "Ledger Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let org_units: Vec<String> = params
+        .opt_strs("org-unit")
+        .iter()
+        .flat_map(|o| o.split(','))
+        .map(|o| o.trim().to_string())
+        .filter(|o| !o.is_empty())
+        .collect();
+
+    if org_units.is_empty() {
+        eprintln!("--org-unit is required");
+        process::exit(2);
+    }
+
+    let year = cli::required(&params, "year");
+    let staff = cli::required(&params, "staff");
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        RolloverOptions {
+            year,
+            org_units,
+            staff,
+            propagate_only: params.opt_present("propagate-only"),
+            encumb_only: params.opt_present("encumb-only"),
+            no_descendants: params.opt_present("no-descendants"),
+            ledger_file: params.opt_str("ledger-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin acq-rollover -- --year 2024 --org-unit SYS1 --staff 1 --dry-run
+    cargo run --bin acq-rollover -- --year 2024 --org-unit SYS1,SYS2 --staff 1 \
+        --ledger-file rollover-2024.csv
+
+Runs the fiscal year-end fund rollover for each org unit, and its
+descendants, using acq.rollover_funds_by_org_tree(), and writes a
+ledger of every money movement it makes:
+
+    propagate    Next year's fund created from a propagating fund.
+    transfer     Unspent balance moved to next year's fund.
+    allocation   Allocation added to or removed from a fund.
+    encumbrance  Open encumbrance moved to next year's fund.
+    debit        Debit added by the rollover.
+    close        Closing year fund deactivated.
+
+With --dry-run, each org unit's rollover runs inside a transaction
+which is rolled back after the ledger is written, so the ledger shows
+exactly what a real run would do.  Otherwise, all org units are rolled
+over together and committed only if every one succeeds.
+
+Options
+
+    --year
+        Fiscal year to close.  Funds roll over to the next year.
+        Required.
+
+    --org-unit
+        Org unit shortname to roll over.  Repeatable, or comma
+        separated.  Required.
+
+    --staff
+        Staff user the rollover runs as.  Required.
+
+    --propagate-only
+        Create next year's funds without moving money or closing
+        this year's funds.
+
+    --encumb-only
+        Move encumbrances only, leaving unspent balances in place.
+
+    --no-descendants
+        Roll over funds owned by the org units only, not their
+        descendants.
+
+    --ledger-file
+        Write the ledger to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn org_id(tx: &mut pg::Transaction, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error loading org unit", e)),
+    }
+}
+
+/// SQL matching funds owned by the org unit ($1), and its descendants
+/// unless --no-descendants.
+fn org_filter(ops: &RolloverOptions, column: &str) -> String {
+    match ops.no_descendants {
+        true => format!("{column} = $1"),
+        false => format!("{column} IN (SELECT id FROM actor.org_unit_descendants($1))"),
+    }
+}
+
+fn fund_ids(
+    tx: &mut pg::Transaction,
+    ops: &RolloverOptions,
+    org: i32,
+    year: i32,
+    active_only: bool,
+) -> Result<Vec<i32>, String> {
+    let mut sql = format!(
+        "SELECT id FROM acq.fund WHERE {} AND year = $2",
+        org_filter(ops, "org")
+    );
+
+    if active_only {
+        sql += " AND active";
+    }
+
+    let rows = tx
+        .query(sql.as_str(), &[&org, &year])
+        .map_err(|e| db_err("Error loading funds", e))?;
+
+    Ok(rows.iter().map(|r| r.get("id")).collect())
+}
+
+fn max_id(tx: &mut pg::Transaction, table: &str) -> Result<i32, String> {
+    let sql = format!("SELECT COALESCE(MAX(id), 0)::INT AS id FROM {table}");
+
+    let row = tx
+        .query_one(sql.as_str(), &[])
+        .map_err(|e| db_err(&format!("Error reading {table}"), e))?;
+
+    Ok(row.get("id"))
+}
+
+fn snapshot(tx: &mut pg::Transaction, ops: &RolloverOptions, org: i32) -> Result<Snapshot, String> {
+    let sql = format!(
+        r#"
+        SELECT debit.id, debit.fund
+        FROM acq.fund_debit debit
+            JOIN acq.fund fund ON fund.id = debit.fund
+        WHERE debit.encumbrance AND fund.year = $2 AND {}
+        "#,
+        org_filter(ops, "fund.org")
+    );
+
+    let rows = tx
+        .query(sql.as_str(), &[&org, &ops.year])
+        .map_err(|e| db_err("Error loading encumbrances", e))?;
+
+    let encumbrances = rows
+        .iter()
+        .map(|r| (r.get::<_, i32>("id"), r.get::<_, i32>("fund")))
+        .collect();
+
+    Ok(Snapshot {
+        max_transfer: max_id(tx, "acq.fund_transfer")?,
+        max_allocation: max_id(tx, "acq.fund_allocation")?,
+        max_debit: max_id(tx, "acq.fund_debit")?,
+        encumbrances,
+        active: fund_ids(tx, ops, org, ops.year, true)?,
+        next_year: fund_ids(tx, ops, org, ops.year + 1, false)?,
+    })
+}
+
+fn run_rollover(tx: &mut pg::Transaction, ops: &RolloverOptions, org: i32) -> Result<(), String> {
+    let include_desc = !ops.no_descendants;
+
+    let result = if ops.propagate_only {
+        tx.execute(
+            "SELECT acq.propagate_funds_by_org_tree($1, $2, $3, $4)",
+            &[&ops.year, &ops.staff, &org, &include_desc],
+        )
+    } else {
+        tx.execute(
+            "SELECT acq.rollover_funds_by_org_tree($1, $2, $3, $4, $5)",
+            &[&ops.year, &ops.staff, &org, &ops.encumb_only, &include_desc],
+        )
+    };
+
+    result.map_err(|e| db_err("Error running rollover", e))?;
+
+    Ok(())
+}
+
+/// Everything the rollover changed since the snapshot.
+fn ledger(
+    tx: &mut pg::Transaction,
+    ops: &RolloverOptions,
+    org: i32,
+    before: &Snapshot,
+) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+
+    // Propagated funds, matched to the closing year fund they copy.
+    let sql = format!(
+        r#"
+        SELECT new.id AS to_fund, old.id AS from_fund, new.currency_type
+        FROM acq.fund new
+            LEFT JOIN acq.fund old ON (
+                old.org = new.org
+                AND old.code = new.code
+                AND old.year = new.year - 1
+            )
+        WHERE new.year = $2 AND {}
+        ORDER BY new.id
+        "#,
+        org_filter(ops, "new.org")
+    );
+
+    let rows = tx
+        .query(sql.as_str(), &[&org, &(ops.year + 1)])
+        .map_err(|e| db_err("Error loading propagated funds", e))?;
+
+    for row in rows {
+        let to_fund: i32 = row.get("to_fund");
+        if before.next_year.contains(&to_fund) {
+            continue;
+        }
+
+        entries.push(Entry {
+            action: "propagate",
+            from_fund: row.get("from_fund"),
+            to_fund: Some(to_fund),
+            amount: None,
+            currency: row.get("currency_type"),
+            note: None,
+        });
+    }
+
+    let sql = r#"
+        SELECT xfer.src_fund, xfer.dest_fund, xfer.src_amount::TEXT AS amount,
+            fund.currency_type, xfer.note
+        FROM acq.fund_transfer xfer
+            JOIN acq.fund fund ON fund.id = xfer.src_fund
+        WHERE xfer.id > $1
+        ORDER BY xfer.id
+    "#;
+
+    let rows = tx
+        .query(sql, &[&before.max_transfer])
+        .map_err(|e| db_err("Error loading fund transfers", e))?;
+
+    for row in rows {
+        entries.push(Entry {
+            action: "transfer",
+            from_fund: row.get("src_fund"),
+            to_fund: row.get("dest_fund"),
+            amount: Some(Money::parse(row.get("amount"))?),
+            currency: row.get("currency_type"),
+            note: row.get("note"),
+        });
+    }
+
+    let sql = r#"
+        SELECT alloc.fund, alloc.amount::TEXT AS amount, fund.currency_type, alloc.note
+        FROM acq.fund_allocation alloc
+            JOIN acq.fund fund ON fund.id = alloc.fund
+        WHERE alloc.id > $1
+        ORDER BY alloc.id
+    "#;
+
+    let rows = tx
+        .query(sql, &[&before.max_allocation])
+        .map_err(|e| db_err("Error loading fund allocations", e))?;
+
+    for row in rows {
+        entries.push(Entry {
+            action: "allocation",
+            from_fund: None,
+            to_fund: row.get("fund"),
+            amount: Some(Money::parse(row.get("amount"))?),
+            currency: row.get("currency_type"),
+            note: row.get("note"),
+        });
+    }
+
+    let ids: Vec<i32> = before.encumbrances.keys().copied().collect();
+
+    let sql = r#"
+        SELECT debit.id, debit.fund, debit.amount::TEXT AS amount,
+            debit.origin_currency_type, debit.encumbrance, debit.debit_type
+        FROM acq.fund_debit debit
+        WHERE debit.id = ANY($1) OR debit.id > $2
+        ORDER BY debit.id
+    "#;
+
+    let rows = tx
+        .query(sql, &[&ids, &before.max_debit])
+        .map_err(|e| db_err("Error loading fund debits", e))?;
+
+    for row in rows {
+        let id: i32 = row.get("id");
+        let fund: i32 = row.get("fund");
+
+        let (action, from_fund) = match before.encumbrances.get(&id) {
+            Some(old) if *old == fund => continue,
+            Some(old) => ("encumbrance", Some(*old)),
+            None => ("debit", None),
+        };
+
+        entries.push(Entry {
+            action,
+            from_fund,
+            to_fund: Some(fund),
+            amount: Some(Money::parse(row.get("amount"))?),
+            currency: row.get("origin_currency_type"),
+            note: row.get("debit_type"),
+        });
+    }
+
+    let still_active = fund_ids(tx, ops, org, ops.year, true)?;
+
+    for fund in &before.active {
+        if !still_active.contains(fund) {
+            entries.push(Entry {
+                action: "close",
+                from_fund: Some(*fund),
+                to_fund: None,
+                amount: None,
+                currency: None,
+                note: None,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Fund ID => code, for labeling ledger rows.
+fn fund_codes(tx: &mut pg::Transaction, entries: &[Entry]) -> Result<HashMap<i32, String>, String> {
+    let ids: Vec<i32> = entries
+        .iter()
+        .flat_map(|e| [e.from_fund, e.to_fund])
+        .flatten()
+        .collect();
+
+    let rows = tx
+        .query(
+            "SELECT id, code || ' (' || year || ')' AS label FROM acq.fund WHERE id = ANY($1)",
+            &[&ids],
+        )
+        .map_err(|e| db_err("Error loading fund codes", e))?;
+
+    Ok(rows.iter().map(|r| (r.get("id"), r.get("label"))).collect())
+}
+
+fn write_entries(
+    writer: &mut TableWriter,
+    shortname: &str,
+    codes: &HashMap<i32, String>,
+    entries: &[Entry],
+    summary: &mut Summary,
+) -> Result<(), String> {
+    for entry in entries {
+        match entry.action {
+            "propagate" => summary.propagated += 1,
+            "transfer" => {
+                summary.transfers += 1;
+                summary.transferred += entry.amount.unwrap_or_default();
+            }
+            "encumbrance" => {
+                summary.encumbrances += 1;
+                summary.encumbered += entry.amount.unwrap_or_default();
+            }
+            "close" => summary.closed += 1,
+            _ => {}
+        }
+
+        let code = |fund: Option<i32>| fund.and_then(|f| codes.get(&f).cloned());
+
+        writer.write_row(&[
+            Cell::from(shortname),
+            Cell::from(entry.action),
+            Cell::from(entry.from_fund),
+            Cell::from(code(entry.from_fund)),
+            Cell::from(entry.to_fund),
+            Cell::from(code(entry.to_fund)),
+            Cell::from(entry.amount),
+            Cell::from(entry.currency.as_ref()),
+            Cell::from(entry.note.as_ref()),
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn rollover(con: &mut DatabaseConnection, ops: &RolloverOptions) -> Result<(), String> {
+    let mut writer = TableWriter::for_path(ops.ledger_file.as_deref(), Format::Csv)?;
+    writer.write_header(LEDGER_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut summary = Summary::default();
+
+    for shortname in &ops.org_units {
+        let org = org_id(&mut tx, shortname)?;
+
+        // On a dry run, each org unit sees the database as it is
+        // now, not as changed by the org units before it.
+        let mut sp = tx
+            .savepoint("rollover")
+            .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+        let before = snapshot(&mut sp, ops, org)?;
+
+        run_rollover(&mut sp, ops, org)?;
+
+        let entries = ledger(&mut sp, ops, org, &before)?;
+        let codes = fund_codes(&mut sp, &entries)?;
+
+        if entries.is_empty() {
+            warn!("Rollover of {shortname} for {} changed nothing", ops.year);
+        }
+
+        write_entries(&mut writer, shortname, &codes, &entries, &mut summary)?;
+
+        ops.dry_run.finish(sp)?;
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    let verb = match ops.dry_run.enabled() {
+        true => "Would have",
+        false => "Rollover",
+    };
+
+    audit::count("propagated", summary.propagated as u64);
+    audit::count("transfers", summary.transfers as u64);
+    audit::count("encumbrances", summary.encumbrances as u64);
+    audit::count("closed", summary.closed as u64);
+
+    info!(
+        "{verb} propagated {} funds, transferred {} in {} transfers, \
+        moved {} in {} encumbrances and closed {} funds",
+        summary.propagated,
+        summary.transferred,
+        summary.transfers,
+        summary.encumbered,
+        summary.encumbrances,
+        summary.closed
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(rollover(&mut connection, &options))
+}
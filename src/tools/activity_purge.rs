@@ -0,0 +1,297 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::signals;
+use log::info;
+use std::time::{Duration, Instant};
+
+/// A table pruned by age.
+struct Target {
+    name: &'static str,
+    description: &'static str,
+    /// Rows older than $1, keeping each user's latest unless $2.
+    /// Selects eligible row IDs when wrapped by count or delete SQL.
+    eligible_sql: &'static str,
+    table: &'static str,
+}
+
+const TARGETS: &[Target] = &[
+    Target {
+        name: "activity",
+        description: "actor.usr_activity logins and other patron activity",
+        eligible_sql: r#"
+            SELECT aua.id
+            FROM actor.usr_activity aua
+            WHERE aua.event_time < NOW() - $1::TEXT::INTERVAL
+                AND ($2 OR EXISTS (
+                    SELECT 1
+                    FROM actor.usr_activity newer
+                    WHERE newer.usr = aua.usr
+                        AND newer.etype = aua.etype
+                        AND (newer.event_time, newer.id) > (aua.event_time, aua.id)
+                ))
+        "#,
+        table: "actor.usr_activity",
+    },
+    Target {
+        name: "password_reset",
+        description: "actor.usr_password_reset requests, used or expired",
+        eligible_sql: r#"
+            SELECT aupr.id
+            FROM actor.usr_password_reset aupr
+            WHERE aupr.request_time < NOW() - $1::TEXT::INTERVAL
+                AND ($2 OR EXISTS (
+                    SELECT 1
+                    FROM actor.usr_password_reset newer
+                    WHERE newer.usr = aupr.usr
+                        AND (newer.request_time, newer.id) > (aupr.request_time, aupr.id)
+                ))
+        "#,
+        table: "actor.usr_password_reset",
+    },
+];
+
+struct PurgeOptions {
+    retention: String,
+    targets: Vec<String>,
+    prune_latest: bool,
+    batch_size: i64,
+    sleep: u64,
+    max_rows: Option<i64>,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (PurgeOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "retention", "Retention Interval", "INTERVAL");
+    opts.optmulti("", "target", "Tables to Prune", "TARGETS");
+    opts.optflag("", "prune-latest", "Also Prune Each User's Latest Row");
+    opts.optopt("", "batch-size", "Rows per Transaction", "BATCH_SIZE");
+    opts.optopt("", "sleep", "Milliseconds to Pause Between Batches", "MS");
+    opts.optopt(
+        "",
+        "max-rows",
+        "Stop After Pruning This Many per Table",
+        "COUNT",
+    );
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let mut targets: Vec<String> = params
+        .opt_strs("target")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    if targets.is_empty() {
+        targets = TARGETS.iter().map(|t| t.name.to_string()).collect();
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        PurgeOptions {
+            retention: cli::required(&params, "retention"),
+            targets,
+            prune_latest: params.opt_present("prune-latest"),
+            batch_size: params.opt_get_default("batch-size", 1000).unwrap(),
+            sleep: params.opt_get_default("sleep", 0).unwrap(),
+            max_rows: params.opt_get("max-rows").unwrap(),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin activity-purge -- --retention '1 year' --dry-run
+    cargo run --bin activity-purge -- --retention '6 months' --target activity --sleep 250
+
+Prunes patron activity and stale authentication artifacts older than
+a retention interval.  These tables grow without bound and bloat
+backups.
+
+Each user's most recent row is kept, so last login and last activity
+times survive, unless --prune-latest is given.
+
+Targets
+
+    activity
+        actor.usr_activity logins and other patron activity, keeping
+        the latest of each activity type per user.
+
+    password_reset
+        actor.usr_password_reset requests, which are used or expired
+        long before the retention interval passes.
+
+Rows are deleted in batches, one transaction each, oldest IDs first.
+Committed batches stay pruned when the run is interrupted.
+
+Options
+
+    --retention
+        Prune rows older than this Postgres interval.  Required.
+
+    --target
+        Tables to prune.  Repeatable or comma separated.  Defaults
+        to all of: activity,password_reset
+
+    --prune-latest
+        Also prune each user's latest row.
+
+    --batch-size
+        Rows deleted per transaction.  Defaults to 1000.
+
+    --sleep
+        Milliseconds to pause between batches to reduce load.
+
+    --max-rows
+        Stop pruning a table after this many rows.
+
+{}
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+/// Prune one table in batches.  Returns the number of rows deleted.
+fn prune(
+    con: &mut DatabaseConnection,
+    ops: &PurgeOptions,
+    target: &Target,
+    shutdown: &signals::Shutdown,
+) -> Result<i64, String> {
+    let sql = format!(
+        "DELETE FROM {} WHERE id IN (SELECT e.id FROM ({}) e ORDER BY e.id LIMIT $3)",
+        target.table, target.eligible_sql
+    );
+
+    let stmt = match con.client().prepare(&sql) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error preparing {} delete: {e}", target.table)),
+    };
+
+    let mut pruned: i64 = 0;
+
+    loop {
+        // Committed batches stay pruned.
+        if shutdown.requested() {
+            return Err(format!(
+                "Shutdown requested after pruning {pruned} {} row(s)",
+                target.table
+            ));
+        }
+
+        let mut limit = ops.batch_size;
+        if let Some(max) = ops.max_rows {
+            limit = limit.min(max - pruned);
+            if limit <= 0 {
+                break;
+            }
+        }
+
+        // Each statement runs in its own implicit transaction.
+        let count = match con
+            .client()
+            .execute(&stmt, &[&ops.retention, &ops.prune_latest, &limit])
+        {
+            Ok(c) => c as i64,
+            Err(e) => return Err(format!("Error pruning {}: {e}", target.table)),
+        };
+
+        if count == 0 {
+            break;
+        }
+
+        pruned += count;
+        info!("Pruned {pruned} {} rows so far", target.table);
+
+        if ops.sleep > 0 {
+            shutdown.sleep(Duration::from_millis(ops.sleep));
+        }
+    }
+
+    Ok(pruned)
+}
+
+fn purge(con: &mut DatabaseConnection, ops: &PurgeOptions) -> Result<(), String> {
+    let mut targets = Vec::new();
+
+    for name in &ops.targets {
+        match TARGETS.iter().find(|t| t.name == name) {
+            Some(t) => targets.push(t),
+            None => return Err(format!("Unknown target: {name}")),
+        }
+    }
+
+    con.connect()?;
+
+    let shutdown = signals::install()?;
+
+    ops.dry_run.begin(con.client())?;
+
+    for target in targets {
+        info!(
+            "Pruning {} older than {}",
+            target.description, ops.retention
+        );
+
+        let start = Instant::now();
+        let pruned = prune(con, ops, target, &shutdown)?;
+
+        audit::count(target.table, pruned as u64);
+
+        println!(
+            "{} {pruned} {} row(s) in {:.1}s",
+            ops.dry_run.past("Pruned"),
+            target.table,
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    ops.dry_run.end(con.client())?;
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(purge(&mut connection, &options))
+}
@@ -0,0 +1,367 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::snapshot::{Snapshot, SnapshotRun};
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{debug, info};
+use postgres as pg;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+
+struct AgeProtectOptions {
+    policy_file: String,
+    staff: i32,
+    out_file: Option<String>,
+    dry_run: DryRun,
+    snapshot: Snapshot,
+}
+
+/// One policy file rule.  Copies get the age protection rule of the
+/// first policy rule they match.
+struct PolicyRule {
+    org: String,
+    circ_modifier: Option<String>,
+    location: Option<String>,
+    /// config.rule_age_hold_protect name, or None to clear.
+    age_protect: Option<String>,
+}
+
+/// One copy whose age protection changes.
+struct Change {
+    copy: i64,
+    barcode: String,
+    circ_lib: String,
+    circ_modifier: String,
+    location: String,
+    old_rule: Option<String>,
+    new_rule: Option<String>,
+}
+
+fn read_options() -> (AgeProtectOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "policy-file", "Age Protection Policy File", "FILE");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "out-file", "Change Report File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+    cli::append_snapshot(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        AgeProtectOptions {
+            policy_file: cli::required(&params, "policy-file"),
+            staff: cli::required(&params, "staff"),
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+            snapshot: Snapshot::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin age-protect -- --policy-file age-protect.json --staff 1 --dry-run
+
+Applies an age hold protection policy to existing copies, e.g. after
+the policy for new materials changes, and reports each copy whose
+asset.copy.age_protect rule changes.
+
+The policy file lists rules matched by circulating library, including
+descendants, and optionally by circulation modifier and copy location
+name.  Each copy gets the config.rule_age_hold_protect rule, by name,
+of the first rule it matches, so list specific rules before general
+ones.  An age_protect of null clears the copy's protection.  Copies
+matching no rule are left alone.
+
+    {{
+        "rules": [
+            {{"org": "BR1", "circ_modifier": "new-book", "age_protect": "3month"}},
+            {{"org": "BR1", "location": "New DVDs", "age_protect": "6month"}},
+            {{"org": "CONS", "circ_modifier": "new-book", "age_protect": null}}
+        ]
+    }}
+
+Every change is reported as CSV: copy, barcode, circ_lib,
+circ_modifier, location, old_rule, new_rule.
+
+Options
+
+    --policy-file
+        Age protection policy file.  Required.
+
+    --staff
+        Staff user recorded as copy editor.  Required.
+
+    --out-file
+        Write the change report to this file.  Otherwise, writes to
+        STDOUT.  Files ending in .tsv or .xlsx are written as TSV or
+        a spreadsheet.
+
+{}
+{}
+    --snapshot
+        Save each copy ahead of changing it, for the rollback tool.
+        The run ID to roll back is logged.
+
+    --snapshot-file
+        Save each copy ahead of changing it to this file, for
+        rollback --snapshot-file.  Cannot be combined with --snapshot.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn read_policy(path: &str) -> Result<Vec<PolicyRule>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let policy: Value = match serde_json::from_str(&text) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let rules = match policy["rules"].as_array() {
+        Some(r) => r,
+        None => return Err(format!("{path} has no rules list")),
+    };
+
+    let mut list = Vec::new();
+
+    for rule in rules {
+        let org = match rule["org"].as_str() {
+            Some(o) => o,
+            None => return Err(format!("Policy rule requires org: {rule}")),
+        };
+
+        // A missing age_protect is more likely a typo than a request
+        // to clear protection, which takes an explicit null.
+        let age_protect = match rule.get("age_protect") {
+            Some(Value::Null) => None,
+            Some(Value::String(s)) => Some(s.to_string()),
+            _ => return Err(format!("Policy rule requires age_protect: {rule}")),
+        };
+
+        list.push(PolicyRule {
+            org: org.to_string(),
+            circ_modifier: rule["circ_modifier"].as_str().map(|s| s.to_string()),
+            location: rule["location"].as_str().map(|s| s.to_string()),
+            age_protect,
+        });
+    }
+
+    Ok(list)
+}
+
+/// Copies matching a policy rule, with their current protection.
+const CANDIDATE_SQL: &str = r#"
+    SELECT
+        acp.id,
+        acp.barcode,
+        aou.shortname AS circ_lib,
+        COALESCE(acp.circ_modifier, '') AS circ_modifier,
+        acpl.name AS location,
+        acp.age_protect,
+        crahp.name AS old_rule
+    FROM asset.copy acp
+        JOIN actor.org_unit aou ON aou.id = acp.circ_lib
+        JOIN asset.copy_location acpl ON acpl.id = acp.location
+        LEFT JOIN config.rule_age_hold_protect crahp ON crahp.id = acp.age_protect
+    WHERE NOT acp.deleted
+        AND acp.circ_lib IN (SELECT id FROM actor.org_unit_descendants($1::INT))
+        AND ($2::TEXT IS NULL OR acp.circ_modifier = $2)
+        AND ($3::TEXT IS NULL OR acpl.name = $3)
+    ORDER BY acp.id
+"#;
+
+fn org_id(tx: &mut pg::Transaction, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+    match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error loading org unit", e)),
+    }
+}
+
+fn rule_id(tx: &mut pg::Transaction, name: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM config.rule_age_hold_protect WHERE name = $1";
+    match tx.query_opt(sql, &[&name]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such age protection rule: {name}")),
+        Err(e) => Err(db_err("Error loading age protection rule", e)),
+    }
+}
+
+/// Apply each rule to the copies not matched by an earlier rule.
+fn apply_policy(
+    tx: &mut pg::Transaction,
+    ops: &AgeProtectOptions,
+    rules: &[PolicyRule],
+    saved: &mut SnapshotRun,
+) -> Result<Vec<Change>, String> {
+    let mut matched: HashSet<i64> = HashSet::new();
+    let mut changes = Vec::new();
+
+    for rule in rules {
+        let org = org_id(tx, &rule.org)?;
+
+        let new_id = match &rule.age_protect {
+            Some(name) => Some(rule_id(tx, name)?),
+            None => None,
+        };
+
+        let rows = tx
+            .query(CANDIDATE_SQL, &[&org, &rule.circ_modifier, &rule.location])
+            .map_err(|e| db_err("Error finding copies", e))?;
+
+        let mut ids = Vec::new();
+
+        for row in &rows {
+            let id: i64 = row.get("id");
+
+            if !matched.insert(id) {
+                continue;
+            }
+
+            let old_id: Option<i32> = row.get("age_protect");
+            if old_id == new_id {
+                continue;
+            }
+
+            debug!("Copy {id} age protection {old_id:?} => {new_id:?}");
+
+            ids.push(id);
+            changes.push(Change {
+                copy: id,
+                barcode: row.get("barcode"),
+                circ_lib: row.get("circ_lib"),
+                circ_modifier: row.get("circ_modifier"),
+                location: row.get("location"),
+                old_rule: row.get("old_rule"),
+                new_rule: rule.age_protect.clone(),
+            });
+        }
+
+        info!(
+            "{}: {} matching copies, {} to change",
+            rule.org,
+            rows.len(),
+            ids.len()
+        );
+
+        if ids.is_empty() {
+            continue;
+        }
+
+        saved.save(
+            tx,
+            "asset.copy",
+            &["age_protect", "editor", "edit_date"],
+            &ids,
+        )?;
+
+        let sql = r#"
+            UPDATE asset.copy SET age_protect = $2, editor = $3, edit_date = NOW()
+            WHERE id = ANY($1)
+        "#;
+
+        tx.execute(sql, &[&ids, &new_id, &ops.staff])
+            .map_err(|e| db_err("Error updating copies", e))?;
+    }
+
+    Ok(changes)
+}
+
+fn write_report(ops: &AgeProtectOptions, changes: &[Change]) -> Result<(), String> {
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+
+    writer.write_header(&[
+        "copy",
+        "barcode",
+        "circ_lib",
+        "circ_modifier",
+        "location",
+        "old_rule",
+        "new_rule",
+    ])?;
+
+    for change in changes {
+        writer.write_row(&[
+            Cell::from(change.copy),
+            Cell::from(&change.barcode),
+            Cell::from(&change.circ_lib),
+            Cell::from(&change.circ_modifier),
+            Cell::from(&change.location),
+            Cell::from(change.old_rule.as_ref()),
+            Cell::from(change.new_rule.as_ref()),
+        ])?;
+    }
+
+    writer.finish()
+}
+
+fn update(con: &mut DatabaseConnection, ops: &AgeProtectOptions) -> Result<(), String> {
+    let rules = read_policy(&ops.policy_file)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut saved = ops.snapshot.begin(&mut tx, "age-protect")?;
+
+    let changes = apply_policy(&mut tx, ops, &rules, &mut saved)?;
+
+    write_report(ops, &changes)?;
+
+    ops.dry_run.finish(tx)?;
+
+    // A dry run's snapshot rolled back with its changes.
+    if !ops.dry_run.enabled() {
+        saved.finish();
+    }
+
+    audit::count("copies", changes.len() as u64);
+
+    info!(
+        "{} age protection for {} copies",
+        ops.dry_run.past("Changed"),
+        changes.len()
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(update(&mut connection, &options))
+}
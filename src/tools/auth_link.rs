@@ -0,0 +1,533 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::marc;
+use log::{error, info, warn};
+use marcutil::{Record, Subfield};
+use postgres as pg;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
+use threadpool::ThreadPool;
+
+#[derive(Clone)]
+struct LinkOptions {
+    min_id: i64,
+    max_id: i64,
+    max_threads: usize,
+    batch_size: usize,
+    control_set: i32,
+    cni: Option<String>,
+    staff: Option<i32>,
+    report_file: Option<String>,
+    remove_stale: bool,
+    dry_run: DryRun,
+}
+
+/// Authority main entry field controlling a bib tag.
+struct ControlledField {
+    authority_field: i32,
+    sf_list: String,
+}
+
+/// A bib heading with no matching authority record.
+struct Unmatched {
+    bib: i64,
+    tag: String,
+    heading: String,
+}
+
+#[derive(Default)]
+struct BatchResult {
+    records: usize,
+    modified: usize,
+    linked: usize,
+    unmatched: Vec<Unmatched>,
+}
+
+fn read_options() -> (LinkOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "max-threads", "Max Worker Threads", "MAX_THREADS");
+    opts.optopt("", "batch-size", "Records per Batch", "BATCH_SIZE");
+    opts.optopt("", "control-set", "Authority Control Set ID", "CONTROL_SET");
+    opts.optopt("", "cni", "Control Number Identifier for $0", "CNI");
+    opts.optopt("", "staff", "Staff User ID for Record Editor", "USER_ID");
+    opts.optopt("", "report-file", "Unmatched Headings Report", "FILE");
+    opts.optflag("", "remove-stale", "Remove $0 from Unmatched Headings");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        LinkOptions {
+            min_id: params.opt_get_default("min-id", 0).unwrap(),
+            max_id: params.opt_get_default("max-id", 0).unwrap(),
+            max_threads: params.opt_get_default("max-threads", 5).unwrap(),
+            batch_size: params.opt_get_default("batch-size", 100).unwrap(),
+            control_set: params.opt_get_default("control-set", 1).unwrap(),
+            cni: params.opt_str("cni"),
+            staff: params.opt_get("staff").unwrap(),
+            report_file: params.opt_str("report-file"),
+            remove_stale: params.opt_present("remove-stale"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin auth-link -- --max-id 10000 --report-file unmatched.csv
+
+Links bib record headings to authority records.
+
+For each bib field controlled by the authority control set, the
+subfields listed for its authority main entry field are combined into
+a heading, normalized, and matched against authority.simple_heading.
+
+For matched headings:
+
+    * Subfield $0 is set to (CNI)AUTHORITY_ID, replacing any $0
+      with the same control number identifier.
+    * authority.bib_linking is rebuilt for the record.
+
+Unmatched headings are reported as CSV: bib, tag, heading.
+
+Options
+
+    --min-id
+    --max-id
+        Limit to records with IDs in this range.
+
+    --max-threads
+        Number of parallel database workers.  Defaults to 5.
+
+    --batch-size
+        Number of records processed per transaction.  Defaults to 100.
+
+    --control-set
+        Authority control set.  Defaults to 1 (LoC).
+
+    --cni
+        Control number identifier used in $0 values.  Defaults to the
+        cat.marc_control_number_identifier setting for the root org
+        unit, or CONS.
+
+    --staff
+        Set biblio.record_entry.editor to this user for modified
+        records.
+
+    --report-file
+        Write the unmatched headings report to this file.  Otherwise,
+        writes to STDOUT.
+
+    --remove-stale
+        Remove $0 values with our control number identifier from
+        headings that no longer match an authority record.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Bib tag => controlling authority field for the control set.
+fn load_controlled_fields(
+    con: &mut DatabaseConnection,
+    control_set: i32,
+) -> Result<HashMap<String, ControlledField>, String> {
+    let sql = r#"
+        SELECT acsbf.tag, acsaf.id, acsaf.sf_list
+        FROM authority.control_set_bib_field acsbf
+            JOIN authority.control_set_authority_field acsaf
+                ON acsaf.id = acsbf.authority_field
+        WHERE acsaf.control_set = $1 AND acsaf.main_entry IS NULL
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&control_set])
+        .map_err(|e| db_err("Error loading control set fields", e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            (
+                row.get("tag"),
+                ControlledField {
+                    authority_field: row.get("id"),
+                    sf_list: row.get("sf_list"),
+                },
+            )
+        })
+        .collect())
+}
+
+fn get_cni(con: &mut DatabaseConnection, ops: &LinkOptions) -> Result<String, String> {
+    if let Some(ref cni) = ops.cni {
+        return Ok(cni.to_string());
+    }
+
+    let sql = r#"
+        SELECT (
+            SELECT value::JSON #>> '{}'
+            FROM actor.org_unit_ancestor_setting('cat.marc_control_number_identifier', aou.id)
+        ) AS cni
+        FROM actor.org_unit aou WHERE parent_ou IS NULL
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[])
+        .map_err(|e| db_err("Error loading control number identifier", e))?;
+
+    Ok(rows
+        .first()
+        .and_then(|r| r.get::<_, Option<String>>("cni"))
+        .unwrap_or("CONS".to_string()))
+}
+
+fn get_record_ids(con: &mut DatabaseConnection, ops: &LinkOptions) -> Result<Vec<i64>, String> {
+    let sql = r#"
+        SELECT id FROM biblio.record_entry
+        WHERE NOT deleted AND id > 0 AND id >= $1 AND ($2 = 0 OR id <= $2)
+        ORDER BY id
+    "#;
+
+    match con.client().query(sql, &[&ops.min_id, &ops.max_id]) {
+        Ok(rows) => Ok(rows.iter().map(|r| r.get("id")).collect()),
+        Err(e) => Err(format!("Error finding records: {e}")),
+    }
+}
+
+/// (field index, authority field, heading) for each controlled field.
+fn extract_headings(
+    record: &Record,
+    fields: &HashMap<String, ControlledField>,
+) -> Vec<(usize, i32, String)> {
+    let mut headings = Vec::new();
+
+    for (idx, field) in record.fields.iter().enumerate() {
+        let cf = match fields.get(&field.tag) {
+            Some(c) => c,
+            None => continue,
+        };
+
+        let heading = field
+            .subfields
+            .iter()
+            .filter(|sf| cf.sf_list.contains(sf.code.as_str()))
+            .map(|sf| sf.content.trim())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        if !heading.is_empty() {
+            headings.push((idx, cf.authority_field, heading));
+        }
+    }
+
+    headings
+}
+
+/// Authority record matching each heading, in order.
+fn match_headings(
+    tx: &mut pg::Transaction,
+    headings: &[(usize, i32, String)],
+) -> Result<Vec<Option<i64>>, String> {
+    let sql = r#"
+        SELECT h.idx, (
+            SELECT ash.record
+            FROM authority.simple_heading ash
+                JOIN authority.record_entry are ON are.id = ash.record
+            WHERE ash.atag = h.atag
+                AND ash.sort_value = public.naco_normalize(h.heading)
+                AND NOT are.deleted
+            ORDER BY ash.record
+            LIMIT 1
+        ) AS authority
+        FROM UNNEST($1::INT[], $2::TEXT[]) WITH ORDINALITY AS h(atag, heading, idx)
+        ORDER BY h.idx
+    "#;
+
+    let atags: Vec<i32> = headings.iter().map(|(_, a, _)| *a).collect();
+    let values: Vec<String> = headings.iter().map(|(_, _, h)| h.to_string()).collect();
+
+    let rows = tx
+        .query(sql, &[&atags, &values])
+        .map_err(|e| db_err("Error matching headings", e))?;
+
+    Ok(rows.iter().map(|r| r.get("authority")).collect())
+}
+
+fn link_batch(
+    mut con: DatabaseConnection,
+    ops: &LinkOptions,
+    fields: &HashMap<String, ControlledField>,
+    cni: &str,
+    ids: &[i64],
+) -> Result<BatchResult, String> {
+    con.connect()?;
+
+    let mut result = BatchResult::default();
+    let prefix = format!("({cni})");
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let ids = ids.to_vec();
+    let rows = tx
+        .query(
+            "SELECT id, marc FROM biblio.record_entry WHERE id = ANY($1) ORDER BY id",
+            &[&ids],
+        )
+        .map_err(|e| db_err("Error loading records", e))?;
+
+    for row in rows {
+        let id: i64 = row.get("id");
+        let xml: &str = row.get("marc");
+
+        let mut record = match Record::from_xml(xml).next() {
+            Some(r) => r,
+            None => {
+                warn!("Record {id} cannot be parsed; skipping");
+                continue;
+            }
+        };
+
+        result.records += 1;
+
+        let headings = extract_headings(&record, fields);
+        if headings.is_empty() {
+            continue;
+        }
+
+        let matches = match_headings(&mut tx, &headings)?;
+        let before = marc::breaker_lines(&record);
+        let mut authorities: Vec<i64> = Vec::new();
+
+        for ((idx, _, heading), authority) in headings.into_iter().zip(matches) {
+            let field = &mut record.fields[idx];
+
+            match authority {
+                Some(auth_id) => {
+                    field
+                        .subfields
+                        .retain(|sf| !(sf.code == "0" && sf.content.starts_with(&prefix)));
+                    field.subfields.push(Subfield {
+                        code: "0".to_string(),
+                        content: format!("{prefix}{auth_id}"),
+                    });
+                    if !authorities.contains(&auth_id) {
+                        authorities.push(auth_id);
+                    }
+                    result.linked += 1;
+                }
+                None => {
+                    if ops.remove_stale {
+                        field
+                            .subfields
+                            .retain(|sf| !(sf.code == "0" && sf.content.starts_with(&prefix)));
+                    }
+                    result.unmatched.push(Unmatched {
+                        bib: id,
+                        tag: field.tag.to_string(),
+                        heading,
+                    });
+                }
+            }
+        }
+
+        if marc::breaker_lines(&record) != before {
+            result.modified += 1;
+
+            let sql = r#"
+                UPDATE biblio.record_entry
+                SET marc = $1, editor = COALESCE($2, editor), edit_date = NOW()
+                WHERE id = $3
+            "#;
+
+            let xml = record.to_xml()?;
+            tx.execute(sql, &[&xml, &ops.staff, &id])
+                .map_err(|e| db_err(&format!("Error updating record {id}"), e))?;
+        }
+
+        // Ingest may also map linking from $0, but records whose MARC
+        // did not change are not reingested.
+        tx.execute("DELETE FROM authority.bib_linking WHERE bib = $1", &[&id])
+            .map_err(|e| db_err(&format!("Error clearing links for {id}"), e))?;
+
+        tx.execute(
+            r#"
+            INSERT INTO authority.bib_linking (bib, authority)
+            SELECT $1, UNNEST($2::BIGINT[])
+            "#,
+            &[&id, &authorities],
+        )
+        .map_err(|e| db_err(&format!("Error linking record {id}"), e))?;
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    con.disconnect();
+
+    Ok(result)
+}
+
+fn write_report(ops: &LinkOptions, unmatched: &[Unmatched]) -> Result<(), String> {
+    let mut writer: Box<dyn Write> = match &ops.report_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let mut out = String::from("bib,tag,heading\n");
+    for u in unmatched {
+        let heading = if u.heading.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", u.heading.replace('"', "\"\""))
+        } else {
+            u.heading.to_string()
+        };
+        out += &format!("{},{},{heading}\n", u.bib, u.tag);
+    }
+
+    match writer.write_all(out.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing report: {e}")),
+    }
+}
+
+fn link(con: &mut DatabaseConnection, ops: &LinkOptions) -> Result<(), String> {
+    let start = Instant::now();
+
+    con.connect()?;
+
+    let fields = load_controlled_fields(con, ops.control_set)?;
+    if fields.is_empty() {
+        return Err(format!(
+            "No controlled bib fields for control set {}",
+            ops.control_set
+        ));
+    }
+
+    let cni = get_cni(con, ops)?;
+    info!("Linking {} bib tags using $0 prefix ({cni})", fields.len());
+
+    let ids = get_record_ids(con, ops)?;
+    info!("Found {} records to process", ids.len());
+
+    let fields = Arc::new(fields);
+    let pool = ThreadPool::new(ops.max_threads);
+    let (sender, receiver) = mpsc::channel();
+    let mut batches = 0;
+
+    for chunk in ids.chunks(ops.batch_size.max(1)) {
+        let batch = chunk.to_vec();
+        let worker_con = con.partial_clone();
+        let worker_ops = ops.clone();
+        let worker_fields = fields.clone();
+        let worker_cni = cni.to_string();
+        let tx = sender.clone();
+
+        pool.execute(move || {
+            tx.send(link_batch(
+                worker_con,
+                &worker_ops,
+                &worker_fields,
+                &worker_cni,
+                &batch,
+            ))
+            .ok();
+        });
+
+        batches += 1;
+    }
+
+    drop(sender);
+
+    let mut done = 0;
+    let mut totals = BatchResult::default();
+    let mut failed = false;
+
+    for result in receiver {
+        done += 1;
+
+        match result {
+            Ok(r) => {
+                totals.records += r.records;
+                totals.modified += r.modified;
+                totals.linked += r.linked;
+                totals.unmatched.extend(r.unmatched);
+            }
+            Err(e) => {
+                error!("{e}");
+                failed = true;
+            }
+        }
+
+        if done % 10 == 0 || done == batches {
+            info!("Completed {done}/{batches} batches");
+        }
+    }
+
+    pool.join();
+    con.disconnect();
+
+    totals.unmatched.sort_by_key(|u| u.bib);
+    write_report(ops, &totals.unmatched)?;
+
+    audit::count("records", totals.records as u64);
+    audit::count("modified", totals.modified as u64);
+    audit::count("linked", totals.linked as u64);
+
+    info!(
+        "{} records, {} {}, {} headings linked, {} unmatched, in {:.1}s",
+        totals.records,
+        totals.modified,
+        ops.dry_run.past("modified"),
+        totals.linked,
+        totals.unmatched.len(),
+        start.elapsed().as_secs_f64()
+    );
+
+    if failed {
+        return Err("One or more batches failed".to_string());
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(link(&mut connection, &options))
+}
@@ -0,0 +1,232 @@
+use crate::barcode::{self, CheckDigit, Generator, Target};
+use crate::cli;
+use crate::db::DatabaseConnection;
+use log::info;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+use std::{fs, io};
+
+struct BarcodeOptions {
+    validate: Option<String>,
+    generate: usize,
+    prefix: String,
+    width: usize,
+    check: Option<CheckDigit>,
+    start: u64,
+    resume: bool,
+    target: Target,
+    out_file: Option<String>,
+}
+
+fn read_options() -> (BarcodeOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "validate", "Validate Barcodes in File", "FILE");
+    opts.optopt("", "generate", "Number of Barcodes to Generate", "COUNT");
+    opts.optopt("", "prefix", "Generated Barcode Prefix", "PREFIX");
+    opts.optopt("", "width", "Sequence Width", "WIDTH");
+    opts.optopt(
+        "",
+        "check",
+        "Check Digit: mod10, mod43, codabar",
+        "ALGORITHM",
+    );
+    opts.optopt("", "start", "First Sequence Number", "NUMBER");
+    opts.optopt("", "target", "Barcode Use: patron, item", "TARGET");
+    opts.optopt("", "out-file", "Output File", "FILE");
+    opts.optflag("", "resume", "Continue After Last Used Sequence");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let check = params
+        .opt_str("check")
+        .map(|s| CheckDigit::from_name(&s).unwrap_or_else(|| panic!("Invalid --check value: {s}")));
+
+    let target = match params.opt_str("target").as_deref() {
+        None | Some("item") => Target::Item,
+        Some("patron") => Target::Patron,
+        Some(t) => panic!("Invalid --target value: {t}"),
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        BarcodeOptions {
+            validate: params.opt_str("validate"),
+            generate: params.opt_get_default("generate", 0).unwrap(),
+            prefix: params.opt_get_default("prefix", String::new()).unwrap(),
+            width: params.opt_get_default("width", 8).unwrap(),
+            check,
+            start: params.opt_get_default("start", 1).unwrap(),
+            resume: params.opt_present("resume"),
+            target,
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin barcode-tool -- --validate barcodes.txt --check codabar
+    cargo run --bin barcode-tool -- --generate 500 --prefix 31234 --check codabar --resume
+
+Validates or generates patron and item barcodes.
+
+Validation reads one barcode per line and reports problems as CSV:
+barcode, problem.  Problems are an invalid check digit, a duplicate
+within the file, or a barcode already used by a card or item.
+
+Generation creates barcodes as PREFIX + zero-padded sequence number
++ optional check digit, skipping barcodes already used by a card or
+item.
+
+Options
+
+    --validate
+        Validate barcodes in this file.
+
+    --generate
+        Number of barcodes to generate.
+
+    --prefix
+        Generated barcode prefix.  For codabar, the type digit (2 for
+        patrons, 3 for items) plus the 4-digit institution code.
+
+    --width
+        Digits in the generated sequence number.  Defaults to 8.
+
+    --check
+        Check digit algorithm: mod10, mod43 or codabar.  Without it,
+        barcodes have no check digit.
+
+    --start
+        First sequence number to generate.  Defaults to 1.
+
+    --resume
+        Start after the highest sequence number already in use for
+        the prefix, if higher than --start.
+
+    --target
+        "patron" or "item".  Codabar prefixes must start with the
+        matching type digit.  Defaults to item.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn validate(
+    con: &mut DatabaseConnection,
+    ops: &BarcodeOptions,
+    path: &str,
+) -> Result<Vec<String>, String> {
+    let barcodes: Vec<String> = match fs::read_to_string(path) {
+        Ok(s) => s
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    info!("Validating {} barcodes", barcodes.len());
+
+    let mut existing = HashSet::new();
+    for chunk in barcodes.chunks(1000) {
+        existing.extend(barcode::existing_barcodes(con.client(), chunk)?);
+    }
+
+    let mut seen = HashSet::new();
+    let mut lines = vec!["barcode,problem".to_string()];
+
+    for bc in &barcodes {
+        if let Some(check) = ops.check {
+            if !check.validate(bc) {
+                lines.push(format!("{bc},invalid check digit"));
+            }
+        }
+
+        if !seen.insert(bc) {
+            lines.push(format!("{bc},duplicate in file"));
+        }
+
+        if existing.contains(bc) {
+            lines.push(format!("{bc},in use"));
+        }
+    }
+
+    info!("{} problems found", lines.len() - 1);
+
+    Ok(lines)
+}
+
+fn generate(con: &mut DatabaseConnection, ops: &BarcodeOptions) -> Result<Vec<String>, String> {
+    if ops.check == Some(CheckDigit::Codabar) && !ops.prefix.starts_with(ops.target.codabar_type())
+    {
+        return Err(format!(
+            "Codabar prefix for {:?} barcodes must start with {}",
+            ops.target,
+            ops.target.codabar_type()
+        ));
+    }
+
+    let mut generator = Generator::new(&ops.prefix, ops.width, ops.check, ops.start)?;
+
+    if ops.resume {
+        let next = generator.resume(con.client())?;
+        info!("Resuming at sequence number {next}");
+    }
+
+    generator.generate(con.client(), ops.generate)
+}
+
+fn run(con: &mut DatabaseConnection, ops: &BarcodeOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let lines = match (&ops.validate, ops.generate) {
+        (Some(path), _) => validate(con, ops, path)?,
+        (None, n) if n > 0 => generate(con, ops)?,
+        _ => return Err("--validate or --generate required".to_string()),
+    };
+
+    con.disconnect();
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    for line in lines {
+        if let Err(e) = writeln!(writer, "{line}") {
+            return Err(format!("Error writing output: {e}"));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    run(&mut connection, &options)
+}
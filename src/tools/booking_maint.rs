@@ -0,0 +1,367 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::signals;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{debug, info};
+use postgres as pg;
+
+struct BookingOptions {
+    cancel_unclaimed: bool,
+    grace: String,
+    retention: Option<String>,
+    batch_size: i64,
+    report: bool,
+    report_days: i32,
+    out_file: Option<String>,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (BookingOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optflag(
+        "",
+        "cancel-unclaimed",
+        "Cancel Expired Unclaimed Reservations",
+    );
+    opts.optopt("", "grace", "Time After End Before Canceling", "INTERVAL");
+    opts.optopt("", "retention", "Purge Reservations Older Than", "INTERVAL");
+    opts.optopt(
+        "",
+        "batch-size",
+        "Reservations Purged per Transaction",
+        "BATCH_SIZE",
+    );
+    opts.optflag("", "report", "Print Utilization per Resource Type");
+    opts.optopt("", "report-days", "Days Covered by the Report", "DAYS");
+    opts.optopt("", "out-file", "Report Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let options = BookingOptions {
+        cancel_unclaimed: params.opt_present("cancel-unclaimed"),
+        grace: params
+            .opt_get_default("grace", "1 day".to_string())
+            .unwrap(),
+        retention: params.opt_str("retention"),
+        batch_size: params.opt_get_default("batch-size", 500).unwrap(),
+        report: params.opt_present("report"),
+        report_days: params.opt_get_default("report-days", 30).unwrap(),
+        out_file: params.opt_str("out-file"),
+        dry_run: DryRun::from_params(&params),
+        lockfile: params.opt_str("lockfile"),
+    };
+
+    if !options.cancel_unclaimed && options.retention.is_none() && !options.report {
+        eprintln!("One of --cancel-unclaimed, --retention or --report is required");
+        std::process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (options, connection)
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin booking-maint -- --cancel-unclaimed --retention '2 years' --report
+
+Booking reservation maintenance.  Each requested task runs in the
+order listed below.
+
+Canceling sets the cancel time of reservations which were never
+picked up and whose end time passed more than --grace ago.
+
+Purging deletes reservations which were returned or canceled longer
+ago than --retention, along with their attribute values.
+Reservations with billings or payments are kept for the financial
+record.
+
+The utilization report has one CSV row per resource type and owning
+library, over the last --report-days days: resources, reservations,
+picked_up, canceled, hours_booked and utilization, the percent of
+available resource hours booked by reservations not canceled.
+
+Options
+
+    --cancel-unclaimed
+        Cancel expired reservations which were never picked up.
+
+    --grace
+        Time after a reservation's end time before it is canceled,
+        as a Postgres interval.  Defaults to "1 day".
+
+    --retention
+        Purge reservations returned or canceled longer ago than this
+        Postgres interval.
+
+    --batch-size
+        Number of reservations purged per transaction.  Defaults to
+        500.
+
+    --report
+        Write the utilization report.
+
+    --report-days
+        Days covered by the report, ending now.  Defaults to 30.
+
+    --out-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+{}
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn cancel_unclaimed(con: &mut DatabaseConnection, ops: &BookingOptions) -> Result<u64, String> {
+    let filter = r#"
+        WHERE cancel_time IS NULL
+            AND pickup_time IS NULL
+            AND end_time < NOW() - $1::TEXT::INTERVAL
+    "#;
+
+    let sql = format!("UPDATE booking.reservation SET cancel_time = NOW() {filter}");
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let count = tx
+        .execute(sql.as_str(), &[&ops.grace])
+        .map_err(|e| db_err("Error canceling reservations", e))?;
+
+    ops.dry_run.finish(tx)?;
+
+    Ok(count)
+}
+
+/// Purgeable reservations, oldest ID first.
+///
+/// $1 retention interval, $2 last ID seen, $3 limit.
+const PURGE_SQL: &str = r#"
+    SELECT bresv.id
+    FROM booking.reservation bresv
+    WHERE bresv.id > $2
+        AND COALESCE(bresv.return_time, bresv.cancel_time) < NOW() - $1::TEXT::INTERVAL
+        AND NOT EXISTS (SELECT 1 FROM money.billing mb WHERE mb.xact = bresv.id)
+        AND NOT EXISTS (SELECT 1 FROM money.payment mp WHERE mp.xact = bresv.id)
+    ORDER BY bresv.id
+    LIMIT $3
+"#;
+
+fn purge(
+    con: &mut DatabaseConnection,
+    ops: &BookingOptions,
+    retention: &str,
+) -> Result<i64, String> {
+    let stmt = con
+        .client()
+        .prepare(PURGE_SQL)
+        .map_err(|e| db_err("Error preparing purge query", e))?;
+
+    let shutdown = signals::install()?;
+
+    let mut last_id: i64 = 0;
+    let mut purged: i64 = 0;
+
+    loop {
+        // Committed batches stay purged.
+        if shutdown.requested() {
+            return Err(format!(
+                "Shutdown requested after purging {purged} reservation(s)"
+            ));
+        }
+
+        let rows = con
+            .client()
+            .query(&stmt, &[&retention, &last_id, &ops.batch_size])
+            .map_err(|e| db_err("Error finding reservations", e))?;
+
+        if rows.is_empty() {
+            break;
+        }
+
+        let ids: Vec<i64> = rows.iter().map(|r| r.get("id")).collect();
+        last_id = *ids.last().unwrap();
+
+        let mut tx = ops.dry_run.transaction(con.client())?;
+
+        let sql = "DELETE FROM booking.reservation_attr_value_map WHERE reservation = ANY($1)";
+        tx.execute(sql, &[&ids])
+            .map_err(|e| db_err("Error purging reservation attributes", e))?;
+
+        let sql = "DELETE FROM booking.reservation WHERE id = ANY($1)";
+        let count = tx
+            .execute(sql, &[&ids])
+            .map_err(|e| db_err("Error purging reservations", e))?;
+
+        ops.dry_run.finish(tx)?;
+
+        purged += count as i64;
+        debug!("Purged {purged} reservations so far");
+    }
+
+    Ok(purged)
+}
+
+/// Reservation counts and booked hours per resource type and owner
+/// over the last $1 days.  Booked hours are clipped to the window.
+const REPORT_SQL: &str = r#"
+    WITH period AS (
+        SELECT NOW() - ($1::INT * '1 day'::INTERVAL) AS start_time, NOW() AS end_time
+    ), resources AS (
+        SELECT brsrc.type, brsrc.owner, COUNT(*) AS count
+        FROM booking.resource brsrc
+        GROUP BY 1, 2
+    ), reservations AS (
+        SELECT
+            brt.id AS type,
+            brsrc.owner,
+            COUNT(*) AS reservations,
+            COUNT(bresv.pickup_time) AS picked_up,
+            COUNT(bresv.cancel_time) AS canceled,
+            COALESCE(SUM(
+                EXTRACT(EPOCH FROM
+                    LEAST(bresv.end_time, w.end_time) - GREATEST(bresv.start_time, w.start_time)
+                ) / 3600
+            ) FILTER (WHERE bresv.cancel_time IS NULL), 0) AS hours_booked
+        FROM booking.reservation bresv
+            JOIN booking.resource_type brt ON brt.id = bresv.target_resource_type
+            LEFT JOIN booking.resource brsrc
+                ON brsrc.id = COALESCE(bresv.current_resource, bresv.target_resource)
+            CROSS JOIN period w
+        WHERE bresv.start_time < w.end_time AND bresv.end_time > w.start_time
+        GROUP BY 1, 2
+    )
+    SELECT
+        brt.name AS resource_type,
+        COALESCE(aou.shortname, '') AS owner,
+        COALESCE(r.count, 0) AS resources,
+        COALESCE(resv.reservations, 0) AS reservations,
+        COALESCE(resv.picked_up, 0) AS picked_up,
+        COALESCE(resv.canceled, 0) AS canceled,
+        COALESCE(resv.hours_booked, 0)::FLOAT8 AS hours_booked,
+        CASE WHEN COALESCE(r.count, 0) = 0 THEN 0
+            ELSE COALESCE(resv.hours_booked, 0) * 100 / (r.count * $1::INT * 24)
+        END::FLOAT8 AS utilization
+    FROM (
+        SELECT type, owner FROM resources
+        UNION SELECT type, owner FROM reservations
+    ) k
+        JOIN booking.resource_type brt ON brt.id = k.type
+        LEFT JOIN resources r ON r.type = k.type AND r.owner = k.owner
+        LEFT JOIN reservations resv
+            ON resv.type = k.type AND resv.owner IS NOT DISTINCT FROM k.owner
+        LEFT JOIN actor.org_unit aou ON aou.id = k.owner
+    ORDER BY 1, 2
+"#;
+
+fn report(con: &mut DatabaseConnection, ops: &BookingOptions) -> Result<(), String> {
+    let rows = con
+        .client()
+        .query(REPORT_SQL, &[&ops.report_days])
+        .map_err(|e| db_err("Error building utilization report", e))?;
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+
+    writer.write_header(&[
+        "resource_type",
+        "owner",
+        "resources",
+        "reservations",
+        "picked_up",
+        "canceled",
+        "hours_booked",
+        "utilization",
+    ])?;
+
+    let round = |n: f64| (n * 10.0).round() / 10.0;
+
+    for row in &rows {
+        writer.write_row(&[
+            Cell::from(row.get::<_, String>("resource_type")),
+            Cell::from(row.get::<_, String>("owner")),
+            Cell::from(row.get::<_, i64>("resources")),
+            Cell::from(row.get::<_, i64>("reservations")),
+            Cell::from(row.get::<_, i64>("picked_up")),
+            Cell::from(row.get::<_, i64>("canceled")),
+            Cell::from(round(row.get("hours_booked"))),
+            Cell::from(round(row.get("utilization"))),
+        ])?;
+    }
+
+    writer.finish()
+}
+
+fn maintain(con: &mut DatabaseConnection, ops: &BookingOptions) -> Result<(), String> {
+    con.connect()?;
+
+    if ops.cancel_unclaimed {
+        let count = cancel_unclaimed(con, ops)?;
+        audit::count("canceled", count);
+
+        info!(
+            "{} {count} unclaimed reservation(s)",
+            ops.dry_run.past("Canceled")
+        );
+    }
+
+    if let Some(retention) = &ops.retention {
+        let count = purge(con, ops, retention)?;
+        audit::count("purged", count as u64);
+
+        info!(
+            "{} {count} reservation(s) older than {retention}",
+            ops.dry_run.past("Purged")
+        );
+    }
+
+    if ops.report {
+        report(con, ops)?;
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(maintain(&mut connection, &options))
+}
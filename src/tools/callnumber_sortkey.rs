@@ -0,0 +1,320 @@
+use crate::audit;
+use crate::callnumber::Scheme;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::signals;
+use log::info;
+use postgres as pg;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::time::Instant;
+
+struct SortkeyOptions {
+    min_id: i64,
+    max_id: i64,
+    batch_size: i64,
+    scheme: Option<Scheme>,
+    out_file: Option<String>,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (SortkeyOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "batch-size", "Call Numbers per Batch", "BATCH_SIZE");
+    opts.optopt(
+        "",
+        "scheme",
+        "Force Scheme: lc, dewey, sudoc, generic",
+        "SCHEME",
+    );
+    opts.optopt("", "out-file", "Write CSV Instead of Storing", "FILE");
+
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let scheme = params
+        .opt_str("scheme")
+        .map(|s| Scheme::from_name(&s).unwrap_or_else(|| panic!("Invalid --scheme value: {s}")));
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        SortkeyOptions {
+            min_id: params.opt_get_default("min-id", 1).unwrap(),
+            max_id: params.opt_get_default("max-id", 0).unwrap(),
+            batch_size: params.opt_get_default("batch-size", 1000).unwrap(),
+            scheme,
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin callnumber-sortkey -- --min-id 1 --max-id 100000
+
+Computes shelf-order sort keys for call number labels using the
+egutil::callnumber LC, Dewey and SuDoc parsers, and stores them in
+egutil.call_number_sortkey:
+
+    call_number BIGINT PRIMARY KEY
+    scheme      TEXT
+    sortkey     TEXT (C collation)
+
+The scheme comes from the call number's classification
+(asset.call_number_class normalizer) unless --scheme is used.
+Sort keys for deleted call numbers in the ID range are removed.
+
+Options
+
+    --min-id
+    --max-id
+        Limit to call numbers with IDs in this range.
+
+    --batch-size
+        Number of call numbers stored per transaction.  Defaults
+        to 1000.
+
+    --scheme
+        Parse all call numbers as lc, dewey, sudoc or generic.
+
+    --out-file
+        Write CSV (id, scheme, label, sortkey) to this file ("-" for
+        STDOUT) instead of storing sort keys.
+
+    --dry-run
+        Make the changes, then roll them back, reporting what a real
+        run would do.  The run holds one transaction throughout.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn init_table(con: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.call_number_sortkey (
+            call_number BIGINT PRIMARY KEY,
+            scheme      TEXT NOT NULL,
+            sortkey     TEXT COLLATE "C" NOT NULL,
+            edit_time   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        CREATE INDEX IF NOT EXISTS call_number_sortkey_sortkey_idx
+            ON egutil.call_number_sortkey (sortkey);
+    "#;
+
+    con.client()
+        .batch_execute(sql)
+        .map_err(|e| db_err("Cannot create egutil.call_number_sortkey", e))
+}
+
+fn store_batch(
+    con: &mut DatabaseConnection,
+    ids: &[i64],
+    schemes: &[String],
+    sortkeys: &[String],
+) -> Result<u64, String> {
+    let sql = r#"
+        INSERT INTO egutil.call_number_sortkey (call_number, scheme, sortkey)
+        SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[], $3::TEXT[])
+        ON CONFLICT (call_number) DO UPDATE
+            SET scheme = EXCLUDED.scheme, sortkey = EXCLUDED.sortkey, edit_time = NOW()
+            WHERE call_number_sortkey.scheme <> EXCLUDED.scheme
+                OR call_number_sortkey.sortkey <> EXCLUDED.sortkey
+    "#;
+
+    con.client()
+        .execute(sql, &[&ids, &schemes, &sortkeys])
+        .map_err(|e| db_err(&format!("Error storing batch at {}", ids[0]), e))
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn run(con: &mut DatabaseConnection, ops: &SortkeyOptions) -> Result<(), String> {
+    let start = Instant::now();
+
+    con.connect()?;
+
+    let mut writer: Option<Box<dyn Write>> = match ops.out_file.as_deref() {
+        Some("-") => Some(Box::new(io::stdout())),
+        Some(f) => match File::create(f) {
+            Ok(f) => Some(Box::new(f)),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => None,
+    };
+
+    match writer.as_mut() {
+        Some(w) => {
+            if let Err(e) = w.write_all(b"id,scheme,label,sortkey\n") {
+                return Err(format!("Error writing output: {e}"));
+            }
+        }
+        None => {
+            ops.dry_run.begin(con.client())?;
+            init_table(con)?;
+        }
+    }
+
+    let sql = r#"
+        SELECT acn.id, acn.label, acnc.normalizer
+        FROM asset.call_number acn
+            LEFT JOIN asset.call_number_class acnc ON acnc.id = acn.label_class
+        WHERE NOT acn.deleted AND acn.id > $1 AND ($2 = 0 OR acn.id <= $2)
+        ORDER BY acn.id
+        LIMIT $3
+    "#;
+
+    let shutdown = signals::install()?;
+
+    let mut last_id = ops.min_id - 1;
+    let mut processed = 0;
+    let mut stored = 0;
+
+    loop {
+        // Stored batches are kept.  Stale keys are removed on the next
+        // complete run.
+        if shutdown.requested() {
+            return Err(format!(
+                "Shutdown requested after {processed} call numbers; last ID {last_id}"
+            ));
+        }
+
+        let rows = con
+            .client()
+            .query(sql, &[&last_id, &ops.max_id, &ops.batch_size])
+            .map_err(|e| db_err("Error loading call numbers", e))?;
+
+        if rows.is_empty() {
+            break;
+        }
+
+        let mut ids = Vec::new();
+        let mut schemes = Vec::new();
+        let mut sortkeys = Vec::new();
+
+        for row in &rows {
+            let id: i64 = row.get("id");
+            let label: &str = row.get("label");
+            let normalizer: Option<&str> = row.get("normalizer");
+
+            let scheme = ops
+                .scheme
+                .unwrap_or_else(|| Scheme::from_normalizer(normalizer));
+            let sortkey = scheme.sortkey(label);
+
+            if let Some(w) = writer.as_mut() {
+                let line = format!(
+                    "{id},{},{},{}\n",
+                    scheme.name(),
+                    csv_field(label),
+                    csv_field(&sortkey)
+                );
+                if let Err(e) = w.write_all(line.as_bytes()) {
+                    return Err(format!("Error writing output: {e}"));
+                }
+            }
+
+            ids.push(id);
+            schemes.push(scheme.name().to_string());
+            sortkeys.push(sortkey);
+            last_id = id;
+        }
+
+        processed += ids.len();
+
+        if writer.is_none() {
+            stored += store_batch(con, &ids, &schemes, &sortkeys)?;
+        }
+
+        info!("Processed {processed} call numbers; last ID {last_id}");
+    }
+
+    if writer.is_none() {
+        let sql = r#"
+            DELETE FROM egutil.call_number_sortkey cns
+            WHERE cns.call_number >= $1 AND ($2 = 0 OR cns.call_number <= $2)
+                AND NOT EXISTS (
+                    SELECT 1 FROM asset.call_number acn
+                    WHERE acn.id = cns.call_number AND NOT acn.deleted
+                )
+        "#;
+
+        let removed = con
+            .client()
+            .execute(sql, &[&ops.min_id, &ops.max_id])
+            .map_err(|e| db_err("Error removing stale sort keys", e))?;
+
+        audit::count("removed", removed);
+
+        info!(
+            "{} {removed} sort keys for deleted call numbers",
+            ops.dry_run.past("Removed")
+        );
+
+        ops.dry_run.end(con.client())?;
+    }
+
+    con.disconnect();
+
+    audit::count("call_numbers", processed as u64);
+    audit::count("stored", stored);
+
+    info!(
+        "{processed} call numbers processed, {stored} sort keys {}, in {:.1}s",
+        ops.dry_run.past("stored"),
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(run(&mut connection, &options))
+}
@@ -0,0 +1,296 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::signals;
+use log::{error, info};
+use std::time::{Duration, Instant};
+
+struct PurgeOptions {
+    retention: Option<String>,
+    keep_per_copy: Option<i64>,
+    batch_size: i64,
+    sleep: u64,
+    max_chains: Option<i64>,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (PurgeOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "retention", "Retention Interval", "INTERVAL");
+    opts.optopt("", "keep-per-copy", "Circ Chains to Keep per Copy", "COUNT");
+    opts.optopt(
+        "",
+        "batch-size",
+        "Circ Chains per Transaction",
+        "BATCH_SIZE",
+    );
+    opts.optopt("", "sleep", "Milliseconds to Pause Between Batches", "MS");
+    opts.optopt("", "max-chains", "Stop After Purging This Many", "COUNT");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        PurgeOptions {
+            retention: params.opt_str("retention"),
+            keep_per_copy: params.opt_get("keep-per-copy").unwrap(),
+            batch_size: params.opt_get_default("batch-size", 500).unwrap(),
+            sleep: params.opt_get_default("sleep", 0).unwrap(),
+            max_chains: params.opt_get("max-chains").unwrap(),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin circ-purge -- --retention '3 years' --sleep 500
+
+Ages completed circulation chains, which moves them to
+action.aged_circulation and removes the patron link.
+
+A chain is eligible when its final circulation is finished
+(checked in, no balance) and older than the retention interval.
+
+Patron preferences are honored:
+
+    history.circ.retention_age
+        Patrons who request a longer retention keep their
+        circulations longer.
+
+    history.circ.retention_start
+        Circulations started after a patron opted in to
+        circulation history are retained.
+
+Options
+
+    --retention
+        Age circulations which finished longer ago than this
+        Postgres interval.  Defaults to the enabled value of the
+        history.circ.retention_age global flag.
+
+    --keep-per-copy
+        Always retain this many of the most recent chains per copy.
+        Defaults to the enabled value of the
+        history.circ.retention_count global flag.
+
+    --batch-size
+        Number of chains purged per transaction.  Defaults to 500.
+
+    --sleep
+        Milliseconds to pause between batches to reduce load.
+
+    --max-chains
+        Stop after purging this many chains.
+
+{}
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn global_flag(con: &mut DatabaseConnection, name: &str) -> Result<Option<String>, String> {
+    let sql = "SELECT value FROM config.global_flag WHERE name = $1 AND enabled";
+    match con.client().query_opt(sql, &[&name]) {
+        Ok(Some(row)) => Ok(row.get("value")),
+        Ok(None) => Ok(None),
+        Err(e) => Err(format!("Error reading global flag {name}: {e}")),
+    }
+}
+
+/// Final circulations of eligible chains, oldest first.
+///
+/// $1 retention interval, $2 chains to keep per copy, $3 last ID seen,
+/// $4 limit.
+const CANDIDATE_SQL: &str = r#"
+    SELECT circ.id
+    FROM action.circulation circ
+    LEFT JOIN actor.usr_setting keep_age
+        ON keep_age.usr = circ.usr
+        AND keep_age.name = 'history.circ.retention_age'
+    LEFT JOIN actor.usr_setting keep_start
+        ON keep_start.usr = circ.usr
+        AND keep_start.name = 'history.circ.retention_start'
+    WHERE circ.id > $3
+        AND circ.xact_finish IS NOT NULL
+        AND circ.checkin_time IS NOT NULL
+        AND NOT EXISTS (
+            SELECT 1 FROM action.circulation next WHERE next.parent_circ = circ.id
+        )
+        AND circ.xact_finish < NOW() - GREATEST(
+            $1::TEXT::INTERVAL,
+            COALESCE(BTRIM(keep_age.value, '"')::INTERVAL, $1::TEXT::INTERVAL)
+        )
+        AND (
+            keep_start.id IS NULL
+            OR circ.xact_start < BTRIM(keep_start.value, '"')::TIMESTAMPTZ
+        )
+        AND (
+            $2::BIGINT = 0 OR (
+                SELECT COUNT(*)
+                FROM action.circulation newer
+                WHERE newer.target_copy = circ.target_copy
+                    AND newer.xact_start > circ.xact_start
+                    AND NOT EXISTS (
+                        SELECT 1 FROM action.circulation n2 WHERE n2.parent_circ = newer.id
+                    )
+            ) >= $2::BIGINT
+        )
+    ORDER BY circ.id
+    LIMIT $4
+"#;
+
+fn purge(con: &mut DatabaseConnection, ops: &PurgeOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let retention = match ops.retention {
+        Some(ref r) => r.to_string(),
+        None => match global_flag(con, "history.circ.retention_age")? {
+            Some(r) => r,
+            None => {
+                return Err(
+                    "No --retention provided and history.circ.retention_age is not enabled"
+                        .to_string(),
+                )
+            }
+        },
+    };
+
+    let keep_per_copy: i64 = match ops.keep_per_copy {
+        Some(k) => k,
+        None => match global_flag(con, "history.circ.retention_count")? {
+            Some(v) => v.parse().unwrap_or(0),
+            None => 0,
+        },
+    };
+
+    info!(
+        "Purging circ chains finished more than {retention} ago, keeping {keep_per_copy} per copy"
+    );
+
+    let stmt = match con.client().prepare(CANDIDATE_SQL) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error preparing candidate query: {e}")),
+    };
+
+    let shutdown = signals::install()?;
+
+    let start = Instant::now();
+    let mut last_id: i64 = 0;
+    let mut purged: i64 = 0;
+    let mut aged_circs: u64 = 0;
+
+    loop {
+        // Committed batches stay purged.
+        if shutdown.requested() {
+            return Err(format!(
+                "Shutdown requested after purging {purged} chain(s)"
+            ));
+        }
+
+        let mut limit = ops.batch_size;
+        if let Some(max) = ops.max_chains {
+            limit = limit.min(max - purged);
+            if limit <= 0 {
+                break;
+            }
+        }
+
+        let rows = match con
+            .client()
+            .query(&stmt, &[&retention, &keep_per_copy, &last_id, &limit])
+        {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Error finding circulations: {e}")),
+        };
+
+        if rows.is_empty() {
+            break;
+        }
+
+        let ids: Vec<i64> = rows.iter().map(|r| r.get("id")).collect();
+        last_id = *ids.last().unwrap();
+
+        let mut tx = ops.dry_run.transaction(con.client())?;
+
+        // Deleting a circulation ages it via the action.age_circ_on_delete trigger.
+        let sql = r#"
+            DELETE FROM action.circulation
+            WHERE id IN (SELECT id FROM action.circ_chain($1))
+        "#;
+
+        let mut batch_circs = 0;
+        for id in &ids {
+            match tx.execute(sql, &[id]) {
+                Ok(count) => batch_circs += count,
+                Err(e) => {
+                    error!("Error purging circ chain ending with {id}: {e}");
+                    return Err(format!("Batch rolled back after error on circ {id}"));
+                }
+            }
+        }
+
+        ops.dry_run.finish(tx)?;
+
+        purged += ids.len() as i64;
+        aged_circs += batch_circs;
+
+        info!("Purged {purged} chains ({aged_circs} circulations) so far");
+
+        if ops.sleep > 0 {
+            shutdown.sleep(Duration::from_millis(ops.sleep));
+        }
+    }
+
+    con.disconnect();
+
+    audit::count("chains", purged as u64);
+    audit::count("circulations", aged_circs);
+
+    println!(
+        "{} {purged} circulation chain(s) ({aged_circs} circulations) in {:.1}s",
+        ops.dry_run.past("Purged"),
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(purge(&mut connection, &options))
+}
@@ -0,0 +1,325 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::info;
+use postgres::fallible_iterator::FallibleIterator;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+const DIMENSIONS: &[&str] = &["org", "location", "modifier", "month"];
+
+struct StatsOptions {
+    start_date: String,
+    end_date: String,
+    org_unit: Option<i32>,
+    group_by: Vec<String>,
+    json: bool,
+    format: Format,
+    out_file: Option<String>,
+}
+
+#[derive(Default)]
+struct Counts {
+    checkouts: i64,
+    renewals: i64,
+    holds: i64,
+}
+
+/// Group key values indexed like DIMENSIONS.  Values for dimensions
+/// not grouped on are left empty.
+type GroupKey = [String; 4];
+
+fn read_options() -> (StatsOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "start-date", "First Day, YYYY-MM-DD", "DATE");
+    opts.optopt("", "end-date", "Last Day, YYYY-MM-DD", "DATE");
+    opts.optopt(
+        "",
+        "org-unit",
+        "Limit to Org Unit and Descendants",
+        "ORG_ID",
+    );
+    opts.optopt("", "group-by", "Comma-Separated Group Dimensions", "DIMS");
+    opts.optopt("", "out-file", "Output File", "FILE");
+    opts.optflag("", "json", "Output JSON Instead of CSV");
+    opts.optopt("", "format", "csv, tsv or xlsx", "FORMAT");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let out_file = params.opt_str("out-file");
+
+    let format = match params.opt_str("format") {
+        Some(f) => match Format::parse(&f) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(2);
+            }
+        },
+        None => out_file
+            .as_deref()
+            .and_then(Format::from_path)
+            .unwrap_or(Format::Csv),
+    };
+
+    let group_by = match params.opt_str("group-by") {
+        Some(s) => s.split(',').map(|d| d.trim().to_string()).collect(),
+        None => DIMENSIONS.iter().map(|d| d.to_string()).collect(),
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        StatsOptions {
+            start_date: cli::required(&params, "start-date"),
+            end_date: cli::required(&params, "end-date"),
+            org_unit: params.opt_get("org-unit").unwrap(),
+            group_by,
+            json: params.opt_present("json"),
+            format,
+            out_file,
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin circ-stats -- --start-date 2023-07-01 --end-date 2024-06-30
+
+Summarizes checkouts, renewals and holds placed within a date range,
+grouped by circulating org unit, copy location, circulation modifier
+and month.
+
+Circulations are read from action.all_circulation, so aged
+circulations are included.  Holds are read from action.all_hold_request
+and grouped by pickup library and request month; they have no
+location or modifier.
+
+Rows are aggregated as they are streamed from the database.
+
+Options
+
+    --start-date
+    --end-date
+        Inclusive date range, YYYY-MM-DD.  Required.
+
+    --org-unit
+        Limit to this org unit and its descendants.
+
+    --group-by
+        Comma-separated list of dimensions to group on.  Defaults to
+        all of: org,location,modifier,month
+
+    --json
+        Write a JSON array of objects instead of CSV.
+
+    --format
+        csv, tsv or xlsx.  Defaults to the --out-file extension, or
+        csv.  Counts are written as numbers in spreadsheets.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+const CIRC_SQL: &str = r#"
+    SELECT
+        aou.shortname AS org,
+        COALESCE(acpl.name, '') AS location,
+        COALESCE(acp.circ_modifier, '') AS modifier,
+        TO_CHAR(circ.xact_start, 'YYYY-MM') AS month,
+        circ.parent_circ IS NOT NULL AS renewal
+    FROM action.all_circulation circ
+        JOIN actor.org_unit aou ON aou.id = circ.circ_lib
+        JOIN asset.copy acp ON acp.id = circ.target_copy
+        LEFT JOIN asset.copy_location acpl ON acpl.id = circ.copy_location
+    WHERE circ.xact_start >= $1::TEXT::DATE
+        AND circ.xact_start < $2::TEXT::DATE + 1
+        AND ($3::INT IS NULL OR circ.circ_lib IN (
+            SELECT id FROM actor.org_unit_descendants($3::INT)
+        ))
+"#;
+
+const HOLD_SQL: &str = r#"
+    SELECT
+        aou.shortname AS org,
+        '' AS location,
+        '' AS modifier,
+        TO_CHAR(ahr.request_time, 'YYYY-MM') AS month,
+        FALSE AS renewal
+    FROM action.all_hold_request ahr
+        JOIN actor.org_unit aou ON aou.id = ahr.pickup_lib
+    WHERE ahr.request_time >= $1::TEXT::DATE
+        AND ahr.request_time < $2::TEXT::DATE + 1
+        AND ($3::INT IS NULL OR ahr.pickup_lib IN (
+            SELECT id FROM actor.org_unit_descendants($3::INT)
+        ))
+"#;
+
+/// Stream rows from the query, adding each to its group.
+fn aggregate(
+    con: &mut DatabaseConnection,
+    ops: &StatsOptions,
+    sql: &str,
+    is_hold: bool,
+    groups: &mut BTreeMap<GroupKey, Counts>,
+) -> Result<usize, String> {
+    let params: [&(dyn postgres::types::ToSql + Sync); 3] =
+        [&ops.start_date, &ops.end_date, &ops.org_unit];
+
+    let mut rows = match con.client().query_raw(sql, params) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error running stats query: {e}")),
+    };
+
+    let mut count = 0;
+
+    loop {
+        let row = match rows.next() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => return Err(format!("Error reading stats rows: {e}")),
+        };
+
+        let mut key = GroupKey::default();
+        for (idx, dim) in DIMENSIONS.iter().enumerate() {
+            if ops.group_by.iter().any(|d| d == dim) {
+                key[idx] = row.get(*dim);
+            }
+        }
+
+        let counts = groups.entry(key).or_default();
+
+        if is_hold {
+            counts.holds += 1;
+        } else if row.get::<_, bool>("renewal") {
+            counts.renewals += 1;
+        } else {
+            counts.checkouts += 1;
+        }
+
+        count += 1;
+        if count % 100000 == 0 {
+            info!("Processed {count} rows; {} groups", groups.len());
+        }
+    }
+
+    Ok(count)
+}
+
+fn dimensions(ops: &StatsOptions) -> Vec<(usize, &'static str)> {
+    DIMENSIONS
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| ops.group_by.iter().any(|g| g == *d))
+        .map(|(i, d)| (i, *d))
+        .collect()
+}
+
+fn format_json(ops: &StatsOptions, groups: &BTreeMap<GroupKey, Counts>) -> String {
+    let dims = dimensions(ops);
+
+    let list: Vec<serde_json::Value> = groups
+        .iter()
+        .map(|(key, counts)| {
+            let mut obj = json!({
+                "checkouts": counts.checkouts,
+                "renewals": counts.renewals,
+                "holds": counts.holds,
+            });
+            for (idx, dim) in &dims {
+                obj[*dim] = json!(key[*idx]);
+            }
+            obj
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&serde_json::Value::Array(list)).unwrap_or_default() + "\n"
+}
+
+fn write_table(ops: &StatsOptions, groups: &BTreeMap<GroupKey, Counts>) -> Result<(), String> {
+    let dims = dimensions(ops);
+
+    let mut writer = match &ops.out_file {
+        Some(f) => TableWriter::create(f, ops.format)?,
+        None => TableWriter::stdout(ops.format),
+    };
+
+    let mut labels: Vec<&str> = dims.iter().map(|(_, d)| *d).collect();
+    labels.extend(["checkouts", "renewals", "holds"]);
+    writer.write_header(&labels)?;
+
+    for (key, counts) in groups {
+        let mut row: Vec<Cell> = dims.iter().map(|(idx, _)| Cell::from(&key[*idx])).collect();
+        row.push(counts.checkouts.into());
+        row.push(counts.renewals.into());
+        row.push(counts.holds.into());
+        writer.write_row(&row)?;
+    }
+
+    writer.finish()
+}
+
+fn summarize(con: &mut DatabaseConnection, ops: &StatsOptions) -> Result<(), String> {
+    for dim in &ops.group_by {
+        if !DIMENSIONS.contains(&dim.as_str()) {
+            return Err(format!("Unknown group dimension: {dim}"));
+        }
+    }
+
+    con.connect()?;
+
+    let mut groups = BTreeMap::new();
+
+    let circs = aggregate(con, ops, CIRC_SQL, false, &mut groups)?;
+    info!("Counted {circs} circulations");
+
+    let holds = aggregate(con, ops, HOLD_SQL, true, &mut groups)?;
+    info!("Counted {holds} holds");
+
+    con.disconnect();
+
+    if !ops.json {
+        return write_table(ops, &groups);
+    }
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    if let Err(e) = writer.write_all(format_json(ops, &groups).as_bytes()) {
+        return Err(format!("Error writing output: {e}"));
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    summarize(&mut connection, &options)
+}
@@ -0,0 +1,635 @@
+use crate::audit;
+use crate::cli;
+use crate::date::{Interval, Timestamp};
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+
+const REPORT_COLUMNS: &[&str] = &[
+    "source",
+    "org_unit",
+    "close_start",
+    "close_end",
+    "full_day",
+    "reason",
+    "closed",
+    "result",
+];
+
+struct ClosedOptions {
+    import_files: Vec<String>,
+    org_units: Vec<String>,
+    descendants: bool,
+    reason: String,
+    allow_overlap: bool,
+    report_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// One closing read from a CSV line or calendar event.
+struct Closing {
+    /// file:line of the CSV line or event.
+    source: String,
+    /// Org unit shortnames, before --descendants.
+    org_units: Vec<String>,
+    /// Wall clock times at the library, unless utc.
+    start: Timestamp,
+    end: Timestamp,
+    utc: bool,
+    full_day: bool,
+    reason: String,
+}
+
+impl Closing {
+    /// close_start and close_end as Postgres will read them.
+    fn range(&self) -> (String, String) {
+        let fmt = |t: &Timestamp| match self.utc {
+            true => t.to_iso8601(),
+            false => t.format("%Y-%m-%d %H:%M:%S"),
+        };
+
+        (fmt(&self.start), fmt(&self.end))
+    }
+}
+
+fn read_options() -> (ClosedOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "import-file", "Closed Dates CSV or ICS File", "FILE");
+    opts.optmulti("", "org-unit", "Default Org Units", "SHORTNAMES");
+    opts.optflag("", "descendants", "Also Close Descendant Org Units");
+    opts.optopt("", "reason", "Default Reason", "REASON");
+    opts.optflag("", "allow-overlap", "Add Overlapping Closings");
+    opts.optopt("", "report-file", "Report Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let import_files = params.opt_strs("import-file");
+    if import_files.is_empty() {
+        eprintln!("--import-file required");
+        process::exit(2);
+    }
+
+    let org_units = params
+        .opt_strs("org-unit")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ClosedOptions {
+            import_files,
+            org_units,
+            descendants: params.opt_present("descendants"),
+            reason: params
+                .opt_get_default("reason", "Closed".to_string())
+                .unwrap(),
+            allow_overlap: params.opt_present("allow-overlap"),
+            report_file: params.opt_str("report-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin closed-dates -- --import-file holidays-2027.ics --org-unit SYS1,SYS2 \
+        --descendants --dry-run
+    cargo run --bin closed-dates -- --import-file closings.csv --report-file closings-report.csv
+
+Loads org unit closed dates from CSV files or ICS calendars across
+many org units at once.
+
+CSV files have a header row and these columns:
+
+    org_unit
+        Org unit shortname.  Defaults to --org-unit.
+
+    start
+        First closed day, YYYY-MM-DD, or the time the closing
+        starts, YYYY-MM-DD HH:MM.  Required.
+
+    end
+        Last closed day, or the time the closing ends.  Defaults
+        to start for full days.
+
+    reason
+        Defaults to --reason.
+
+ICS files are read for VEVENTs.  SUMMARY is the reason.  Events are
+closed for each --org-unit.  All-day events close full days, with
+DTEND the day after the last closed day.  Times with a TZID are taken
+as the library's local time.  Recurring events are not expanded;
+only the first occurrence is loaded.
+
+Full days close from 00:00:00 through 23:59:59 of the last day.
+
+Closings that overlap an existing closing at the same org unit,
+including ones added earlier in the same run, are reported and
+skipped unless --allow-overlap is given.  Closings identical to an
+existing one are always skipped.
+
+Each closing is added in its own savepoint, and every closing is
+written to the report with its result.
+
+Options
+
+    --import-file
+        Path to a CSV or ICS file.  Repeatable.  Files ending in .ics
+        are read as calendars.
+
+    --org-unit
+        Org unit shortnames for events, and CSV lines without an
+        org_unit.  Repeatable or comma separated.
+
+    --descendants
+        Also close the descendants of each org unit.
+
+    --reason
+        Reason for closings without one.  Defaults to "Closed".
+
+    --allow-overlap
+        Add closings that overlap existing closings.
+
+    --report-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// 23:59:59 on the day of a timestamp.
+fn end_of_day(t: &Timestamp) -> Result<Timestamp, String> {
+    let day = Timestamp::parse(&t.format("%Y-%m-%d"))?;
+    Ok(day
+        .add(&Interval::parse("1 day")?)
+        .sub(&Interval::parse("1 second")?))
+}
+
+/// A closing from CSV start and end values.
+fn csv_range(start: &str, end: Option<&str>) -> Result<(Timestamp, Timestamp, bool), String> {
+    let full_day = !start.contains([' ', 'T']);
+    let start_ts = Timestamp::parse(start)?;
+
+    let end_ts = match end {
+        Some(e) if full_day => end_of_day(&Timestamp::parse(e)?)?,
+        Some(e) => Timestamp::parse(e)?,
+        None if full_day => end_of_day(&start_ts)?,
+        None => return Err("end required for partial days".to_string()),
+    };
+
+    if end_ts.epoch() < start_ts.epoch() {
+        return Err(format!("Closing ends before it starts: {start}"));
+    }
+
+    Ok((start_ts, end_ts, full_day))
+}
+
+fn read_csv(path: &str, ops: &ClosedOptions) -> Result<Vec<Closing>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let start_col = match col("start") {
+        Some(c) => c,
+        None => return Err(format!("{path} requires a start column")),
+    };
+    let end_col = col("end");
+    let org_col = col("org_unit");
+    let reason_col = col("reason");
+
+    let mut closings = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let field = |col: Option<usize>| {
+            col.and_then(|c| fields.get(c))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+
+        let start = match field(Some(start_col)) {
+            Some(s) => s,
+            None => {
+                warn!("{path} line {}: start required: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        let (start, end, full_day) = match csv_range(&start, field(end_col).as_deref()) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{path} line {}: {e}", idx + 1);
+                continue;
+            }
+        };
+
+        let org_units = match field(org_col) {
+            Some(o) => vec![o],
+            None => ops.org_units.clone(),
+        };
+
+        closings.push(Closing {
+            source: format!("{path}:{}", idx + 1),
+            org_units,
+            start,
+            end,
+            utc: false,
+            full_day,
+            reason: field(reason_col).unwrap_or(ops.reason.clone()),
+        });
+    }
+
+    Ok(closings)
+}
+
+/// An ICS DATE or DATE-TIME value as (timestamp, is_date, is_utc).
+fn ics_time(value: &str) -> Result<(Timestamp, bool, bool), String> {
+    let v = value.trim();
+    let digits = |r: std::ops::Range<usize>| v.get(r).unwrap_or("");
+
+    let date = format!("{}-{}-{}", digits(0..4), digits(4..6), digits(6..8));
+
+    if v.len() == 8 {
+        return Ok((Timestamp::parse(&date)?, true, false));
+    }
+
+    let time = format!("{}:{}:{}", digits(9..11), digits(11..13), digits(13..15));
+    let utc = v.ends_with('Z');
+
+    Ok((Timestamp::parse(&format!("{date}T{time}"))?, false, utc))
+}
+
+fn ics_unescape(value: &str) -> String {
+    value
+        .replace("\\n", " ")
+        .replace("\\N", " ")
+        .replace("\\,", ",")
+        .replace("\\;", ";")
+        .replace("\\\\", "\\")
+}
+
+fn read_ics(path: &str, ops: &ClosedOptions) -> Result<Vec<Closing>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    // Unfold continuation lines, keeping the line number each
+    // property starts on.
+    let mut props: Vec<(usize, String)> = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        match (line.strip_prefix([' ', '\t']), props.last_mut()) {
+            (Some(rest), Some((_, prop))) => prop.push_str(rest),
+            _ => props.push((idx + 1, line.to_string())),
+        }
+    }
+
+    let mut closings = Vec::new();
+    let mut event: Option<(usize, HashMap<String, String>)> = None;
+
+    for (lineno, prop) in props {
+        let (name, value) = match prop.split_once(':') {
+            Some((n, v)) => (n, v),
+            None => continue,
+        };
+
+        // DTSTART;VALUE=DATE => DTSTART
+        let name = name.split(';').next().unwrap_or("").to_uppercase();
+
+        match (name.as_str(), value.trim()) {
+            ("BEGIN", "VEVENT") => event = Some((lineno, HashMap::new())),
+            ("END", "VEVENT") => {
+                let (start_line, fields) = match event.take() {
+                    Some(e) => e,
+                    None => continue,
+                };
+
+                let source = format!("{path}:{start_line}");
+
+                match ics_closing(ops, &source, &fields) {
+                    Ok(c) => closings.push(c),
+                    Err(e) => warn!("{source}: {e}"),
+                }
+            }
+            _ => {
+                if let Some((_, ref mut fields)) = event {
+                    fields.insert(name, value.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(closings)
+}
+
+fn ics_closing(
+    ops: &ClosedOptions,
+    source: &str,
+    fields: &HashMap<String, String>,
+) -> Result<Closing, String> {
+    let (start, full_day, utc) = match fields.get("DTSTART") {
+        Some(v) => ics_time(v)?,
+        None => return Err("Event has no DTSTART".to_string()),
+    };
+
+    let end = match fields.get("DTEND").map(|v| ics_time(v)).transpose()? {
+        // All-day DTEND is the day after the last closed day.
+        Some((end, true, _)) => end.sub(&Interval::parse("1 second")?),
+        Some((end, false, _)) => end,
+        None if full_day => end_of_day(&start)?,
+        None => start,
+    };
+
+    if fields.contains_key("RRULE") {
+        warn!("{source}: recurring event; only the first occurrence is loaded");
+    }
+
+    if ops.org_units.is_empty() {
+        return Err("--org-unit required for calendar events".to_string());
+    }
+
+    Ok(Closing {
+        source: source.to_string(),
+        org_units: ops.org_units.clone(),
+        start,
+        end,
+        utc,
+        full_day,
+        reason: match fields.get("SUMMARY") {
+            Some(s) if !s.trim().is_empty() => ics_unescape(s.trim()),
+            _ => ops.reason.clone(),
+        },
+    })
+}
+
+/// The org units, with descendants when requested, as (id, shortname).
+fn expand_orgs(
+    tx: &mut pg::Transaction,
+    ops: &ClosedOptions,
+    cache: &mut HashMap<String, Vec<(i32, String)>>,
+    shortname: &str,
+) -> Result<Vec<(i32, String)>, String> {
+    if let Some(orgs) = cache.get(shortname) {
+        return Ok(orgs.clone());
+    }
+
+    let sql = r#"
+        SELECT d.id, d.shortname
+        FROM actor.org_unit aou
+            JOIN actor.org_unit_descendants(aou.id) d
+                ON $2 OR d.id = aou.id
+        WHERE aou.shortname = $1
+        ORDER BY d.id
+    "#;
+
+    let rows = tx
+        .query(sql, &[&shortname, &ops.descendants])
+        .map_err(|e| db_err("Error looking up org unit", e))?;
+
+    if rows.is_empty() {
+        return Err(format!("No such org unit: {shortname}"));
+    }
+
+    let orgs: Vec<(i32, String)> = rows
+        .iter()
+        .map(|r| (r.get("id"), r.get("shortname")))
+        .collect();
+
+    cache.insert(shortname.to_string(), orgs.clone());
+
+    Ok(orgs)
+}
+
+/// Add one closing at one org unit.  Returns the new or identical
+/// closing's ID and the result.
+fn add_closing(
+    tx: &mut pg::Transaction,
+    ops: &ClosedOptions,
+    closing: &Closing,
+    org: i32,
+) -> Result<(Option<i32>, String), String> {
+    let (start, end) = closing.range();
+
+    let sql = r#"
+        SELECT id,
+            close_start = $2::TEXT::TIMESTAMPTZ
+                AND close_end = $3::TEXT::TIMESTAMPTZ AS same
+        FROM actor.org_unit_closed
+        WHERE org_unit = $1
+            AND close_start <= $3::TEXT::TIMESTAMPTZ
+            AND close_end >= $2::TEXT::TIMESTAMPTZ
+        ORDER BY close_start
+    "#;
+
+    let rows = tx
+        .query(sql, &[&org, &start, &end])
+        .map_err(|e| db_err("Error checking for overlaps", e))?;
+
+    if let Some(row) = rows.iter().find(|r| r.get::<_, bool>("same")) {
+        return Ok((Some(row.get("id")), "Exists".to_string()));
+    }
+
+    if !rows.is_empty() && !ops.allow_overlap {
+        let ids: Vec<String> = rows
+            .iter()
+            .map(|r| r.get::<_, i32>("id").to_string())
+            .collect();
+        return Err(format!("Overlaps closing {}", ids.join(" ")));
+    }
+
+    let sql = r#"
+        INSERT INTO actor.org_unit_closed
+            (org_unit, close_start, close_end, full_day, multi_day, reason)
+        VALUES (
+            $1,
+            $2::TEXT::TIMESTAMPTZ,
+            $3::TEXT::TIMESTAMPTZ,
+            $4,
+            $2::TEXT::TIMESTAMPTZ::DATE <> $3::TEXT::TIMESTAMPTZ::DATE,
+            $5
+        )
+        RETURNING id
+    "#;
+
+    let row = tx
+        .query_one(
+            sql,
+            &[&org, &start, &end, &closing.full_day, &closing.reason],
+        )
+        .map_err(|e| db_err("Error adding closing", e))?;
+
+    let result = match rows.is_empty() {
+        true => "Added".to_string(),
+        false => format!("Added; overlaps {} closing(s)", rows.len()),
+    };
+
+    Ok((Some(row.get("id")), result))
+}
+
+fn load(con: &mut DatabaseConnection, ops: &ClosedOptions) -> Result<(), String> {
+    let mut closings = Vec::new();
+
+    for path in &ops.import_files {
+        let mut list = match path.to_lowercase().ends_with(".ics") {
+            true => read_ics(path, ops)?,
+            false => read_csv(path, ops)?,
+        };
+
+        info!("Read {} closings from {path}", list.len());
+        closings.append(&mut list);
+    }
+
+    let mut writer = TableWriter::for_path(ops.report_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut cache = HashMap::new();
+    let mut added = 0;
+    let mut total = 0;
+
+    for closing in &closings {
+        if closing.org_units.is_empty() {
+            warn!("{}: no org unit", closing.source);
+        }
+
+        for shortname in &closing.org_units {
+            let orgs = match expand_orgs(&mut tx, ops, &mut cache, shortname) {
+                Ok(o) => o,
+                Err(e) => {
+                    total += 1;
+                    warn!("{}: {e}", closing.source);
+                    write_row(&mut writer, closing, shortname, None, &e)?;
+                    continue;
+                }
+            };
+
+            for (org, org_name) in orgs {
+                total += 1;
+
+                let mut sp = tx
+                    .savepoint("closed_date")
+                    .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+                let result = add_closing(&mut sp, ops, closing, org);
+
+                match result {
+                    Ok(_) => sp
+                        .commit()
+                        .map_err(|e| db_err("Cannot release savepoint", e))?,
+                    Err(_) => sp
+                        .rollback()
+                        .map_err(|e| db_err("Cannot roll back savepoint", e))?,
+                }
+
+                let (id, result) = match result {
+                    Ok((id, r)) => {
+                        if r != "Exists" {
+                            added += 1;
+                        }
+                        (id, r)
+                    }
+                    Err(e) => (None, e),
+                };
+
+                write_row(&mut writer, closing, &org_name, id, &result)?;
+            }
+        }
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("closed_dates", added as u64);
+
+    info!(
+        "{} {added} of {total} closed dates",
+        ops.dry_run.past("Added")
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+fn write_row(
+    writer: &mut TableWriter,
+    closing: &Closing,
+    org_unit: &str,
+    id: Option<i32>,
+    result: &str,
+) -> Result<(), String> {
+    let (start, end) = closing.range();
+
+    writer.write_row(&[
+        Cell::from(&closing.source),
+        Cell::from(org_unit),
+        Cell::from(start),
+        Cell::from(end),
+        Cell::from(closing.full_day),
+        Cell::from(&closing.reason),
+        Cell::from(id),
+        Cell::from(result),
+    ])
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(load(&mut connection, &options))
+}
@@ -0,0 +1,356 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use log::info;
+use postgres as pg;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+struct ClosingOptions {
+    org_unit: i32,
+    descendants: bool,
+    start_date: String,
+    end_date: String,
+    staff: i32,
+    add_closing: bool,
+    reason: String,
+    out_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// One changed value, reported as a CSV line.
+struct Change {
+    kind: &'static str,
+    id: i64,
+    usr: i32,
+    field: &'static str,
+    old: String,
+    new: String,
+}
+
+fn read_options() -> (ClosingOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "org-unit", "Closed Org Unit ID", "ORG_ID");
+    opts.optflag("", "no-descendants", "Exclude Descendant Org Units");
+    opts.optopt("", "start-date", "First Closed Day, YYYY-MM-DD", "DATE");
+    opts.optopt("", "end-date", "Last Closed Day, YYYY-MM-DD", "DATE");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optflag("", "add-closing", "Also Add Closed Dates Entries");
+    opts.optopt("", "reason", "Closed Dates Reason", "REASON");
+    opts.optopt("", "out-file", "Change Report File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ClosingOptions {
+            org_unit: cli::required(&params, "org-unit"),
+            descendants: !params.opt_present("no-descendants"),
+            start_date: cli::required(&params, "start-date"),
+            end_date: cli::required(&params, "end-date"),
+            staff: cli::required(&params, "staff"),
+            add_closing: params.opt_present("add-closing"),
+            reason: params
+                .opt_get_default("reason", "Emergency Closing".to_string())
+                .unwrap(),
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin closing-adjust -- --org-unit 4 --start-date 2024-01-15 \
+        --end-date 2024-01-17 --staff 1 --dry-run
+
+Adjusts transactions for an unplanned closure of an org unit and,
+by default, its descendants:
+
+    * Open circulations due during the closure are moved to the same
+      time on the day after the closure.
+    * Hold shelf expire times falling within the closure are moved
+      to the same time on the day after the closure.
+    * Overdue fines for periods starting during the closure are
+      voided on open circulations.
+
+Every change is reported as CSV: type, id, usr, field, old, new.
+
+Options
+
+    --org-unit
+        Closed org unit.  Required.
+
+    --no-descendants
+        Only adjust the org unit itself.
+
+    --start-date
+    --end-date
+        First and last closed days, YYYY-MM-DD.  Required.
+
+    --staff
+        Staff user recorded as billing voider.  Required.
+
+    --add-closing
+        Add actor.org_unit_closed entries for the closure, so future
+        due dates avoid it.
+
+    --reason
+        Reason for --add-closing entries.  Defaults to
+        "Emergency Closing".
+
+    --out-file
+        Write the change report to this file.  Otherwise, writes to
+        STDOUT.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn extend_due_dates(
+    tx: &mut pg::Transaction,
+    ops: &ClosingOptions,
+    orgs: &[i32],
+) -> Result<Vec<Change>, String> {
+    let sql = r#"
+        WITH affected AS (
+            SELECT id, usr, due_date AS old_due,
+                due_date + (($3::TEXT::DATE + 1 - due_date::DATE) * '1 day'::INTERVAL) AS new_due
+            FROM action.circulation
+            WHERE circ_lib = ANY($1)
+                AND checkin_time IS NULL
+                AND xact_finish IS NULL
+                AND (stop_fines IS NULL OR stop_fines = 'MAXFINES')
+                AND due_date >= $2::TEXT::DATE
+                AND due_date < $3::TEXT::DATE + 1
+            FOR UPDATE
+        )
+        UPDATE action.circulation circ SET due_date = affected.new_due
+        FROM affected
+        WHERE circ.id = affected.id
+        RETURNING circ.id, affected.usr, affected.old_due::TEXT AS old, affected.new_due::TEXT AS new
+    "#;
+
+    let rows = tx
+        .query(sql, &[&orgs, &ops.start_date, &ops.end_date])
+        .map_err(|e| db_err("Error extending due dates", e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| Change {
+            kind: "circ",
+            id: row.get("id"),
+            usr: row.get("usr"),
+            field: "due_date",
+            old: row.get("old"),
+            new: row.get("new"),
+        })
+        .collect())
+}
+
+fn extend_shelf_expirations(
+    tx: &mut pg::Transaction,
+    ops: &ClosingOptions,
+    orgs: &[i32],
+) -> Result<Vec<Change>, String> {
+    let sql = r#"
+        WITH affected AS (
+            SELECT id, usr, shelf_expire_time AS old_expire,
+                shelf_expire_time + (($3::TEXT::DATE + 1 - shelf_expire_time::DATE)
+                    * '1 day'::INTERVAL) AS new_expire
+            FROM action.hold_request
+            WHERE current_shelf_lib = ANY($1)
+                AND shelf_time IS NOT NULL
+                AND fulfillment_time IS NULL
+                AND cancel_time IS NULL
+                AND shelf_expire_time >= $2::TEXT::DATE
+                AND shelf_expire_time < $3::TEXT::DATE + 1
+            FOR UPDATE
+        )
+        UPDATE action.hold_request ahr SET shelf_expire_time = affected.new_expire
+        FROM affected
+        WHERE ahr.id = affected.id
+        RETURNING ahr.id, affected.usr,
+            affected.old_expire::TEXT AS old, affected.new_expire::TEXT AS new
+    "#;
+
+    let rows = tx
+        .query(sql, &[&orgs, &ops.start_date, &ops.end_date])
+        .map_err(|e| db_err("Error extending hold shelf expirations", e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| Change {
+            kind: "hold",
+            id: row.get::<_, i32>("id") as i64,
+            usr: row.get("usr"),
+            field: "shelf_expire_time",
+            old: row.get("old"),
+            new: row.get("new"),
+        })
+        .collect())
+}
+
+fn void_fines(
+    tx: &mut pg::Transaction,
+    ops: &ClosingOptions,
+    orgs: &[i32],
+) -> Result<Vec<Change>, String> {
+    let sql = r#"
+        WITH affected AS (
+            SELECT mb.id, circ.usr, mb.amount
+            FROM money.billing mb
+                JOIN action.circulation circ ON circ.id = mb.xact
+            WHERE mb.btype = 1
+                AND NOT mb.voided
+                AND circ.circ_lib = ANY($1)
+                AND circ.xact_finish IS NULL
+                AND mb.period_start >= $2::TEXT::DATE
+                AND mb.period_start < $3::TEXT::DATE + 1
+            FOR UPDATE OF mb
+        )
+        UPDATE money.billing mb SET voided = TRUE, voider = $4, void_time = NOW()
+        FROM affected
+        WHERE mb.id = affected.id
+        RETURNING mb.id, affected.usr, affected.amount::TEXT AS amount
+    "#;
+
+    let rows = tx
+        .query(sql, &[&orgs, &ops.start_date, &ops.end_date, &ops.staff])
+        .map_err(|e| db_err("Error voiding overdue fines", e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| Change {
+            kind: "billing",
+            id: row.get("id"),
+            usr: row.get("usr"),
+            field: "voided",
+            old: format!("false ({})", row.get::<_, String>("amount")),
+            new: "true".to_string(),
+        })
+        .collect())
+}
+
+fn add_closings(
+    tx: &mut pg::Transaction,
+    ops: &ClosingOptions,
+    orgs: &[i32],
+) -> Result<u64, String> {
+    let sql = r#"
+        INSERT INTO actor.org_unit_closed (org_unit, close_start, close_end, reason)
+        SELECT org, $2::TEXT::DATE, $3::TEXT::DATE + '1 day'::INTERVAL - '1 second'::INTERVAL, $4
+        FROM UNNEST($1::INT[]) AS org
+    "#;
+
+    tx.execute(sql, &[&orgs, &ops.start_date, &ops.end_date, &ops.reason])
+        .map_err(|e| db_err("Error adding closed dates", e))
+}
+
+fn write_report(ops: &ClosingOptions, changes: &[Change]) -> Result<(), String> {
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let mut out = String::from("type,id,usr,field,old,new\n");
+    for c in changes {
+        out += &format!(
+            "{},{},{},{},{},{}\n",
+            c.kind, c.id, c.usr, c.field, c.old, c.new
+        );
+    }
+
+    match writer.write_all(out.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing report: {e}")),
+    }
+}
+
+fn adjust(con: &mut DatabaseConnection, ops: &ClosingOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let sql = match ops.descendants {
+        true => "SELECT id FROM actor.org_unit_descendants($1)",
+        false => "SELECT id FROM actor.org_unit WHERE id = $1",
+    };
+
+    let orgs: Vec<i32> = con
+        .client()
+        .query(sql, &[&ops.org_unit])
+        .map_err(|e| db_err("Error loading org units", e))?
+        .iter()
+        .map(|r| r.get("id"))
+        .collect();
+
+    if orgs.is_empty() {
+        return Err(format!("No such org unit: {}", ops.org_unit));
+    }
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut changes = extend_due_dates(&mut tx, ops, &orgs)?;
+    audit::count("due_dates", changes.len() as u64);
+    info!("{} due dates extended", changes.len());
+
+    let holds = extend_shelf_expirations(&mut tx, ops, &orgs)?;
+    audit::count("shelf_expirations", holds.len() as u64);
+    info!("{} hold shelf expirations extended", holds.len());
+    changes.extend(holds);
+
+    let fines = void_fines(&mut tx, ops, &orgs)?;
+    audit::count("fines_voided", fines.len() as u64);
+    info!("{} overdue fines voided", fines.len());
+    changes.extend(fines);
+
+    if ops.add_closing {
+        let count = add_closings(&mut tx, ops, &orgs)?;
+        audit::count("closed_dates", count);
+        info!("Added {count} closed date entries");
+    }
+
+    write_report(ops, &changes)?;
+
+    ops.dry_run.finish(tx)?;
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(adjust(&mut connection, &options))
+}
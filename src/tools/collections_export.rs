@@ -0,0 +1,599 @@
+use crate::audit;
+use crate::cli;
+use crate::date::UtcTime;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::money::Money;
+use log::{info, warn};
+use postgres as pg;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+const PENALTY_NAME: &str = "PATRON_IN_COLLECTIONS";
+
+const EXPORT_COLUMNS: &[&str] = &[
+    "barcode",
+    "family_name",
+    "first_given_name",
+    "dob",
+    "street1",
+    "street2",
+    "city",
+    "state",
+    "post_code",
+    "phone",
+    "email",
+    "home_lib",
+    "balance",
+    "oldest_xact",
+];
+
+struct CollectionsOptions {
+    config_file: Option<String>,
+    out_dir: String,
+    payments_file: Option<String>,
+    staff: i32,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+/// Per-org export settings from the config file.
+struct OrgConfig {
+    org: String,
+    threshold: String,
+    min_age_days: i64,
+    format: String,
+}
+
+struct Payment {
+    line: usize,
+    barcode: String,
+    amount: Money,
+    reference: String,
+}
+
+fn read_options() -> (CollectionsOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "org-file", "Org Threshold Config File", "FILE");
+    opts.optopt("", "out-dir", "Export File Directory", "DIR");
+    opts.optopt("", "payments", "Process Agency Payment File", "FILE");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        CollectionsOptions {
+            config_file: params.opt_str("org-file"),
+            out_dir: params.opt_get_default("out-dir", ".".to_string()).unwrap(),
+            payments_file: params.opt_str("payments"),
+            staff: cli::required(&params, "staff"),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin collections-export -- --org-file collections.json --out-dir /tmp --staff 1
+    cargo run --bin collections-export -- --payments returned.csv --staff 1
+
+Exports patrons with overdue balances for a collections agency, and
+applies payments reported back by the agency.
+
+Export
+
+For each org unit in the config file, patrons whose home library is
+the org unit or a descendant are exported when their unpaid balance
+meets the threshold and their oldest unpaid transaction is older than
+min_age_days.  Patrons already in collections are skipped.
+
+Exported patrons receive the PATRON_IN_COLLECTIONS standing penalty
+and a money.collections_tracker entry.  Each org unit gets a file
+named collections-ORG-YYYYMMDD.csv (or .tsv) in --out-dir.
+
+    {{
+        "orgs": [
+            {{"org": "BR1", "threshold": "25.00", "min_age_days": 60, "format": "csv"}},
+            {{"org": "SYS2", "threshold": "50.00", "min_age_days": 90, "format": "tsv"}}
+        ]
+    }}
+
+Payments
+
+The payment file is CSV with a header row and columns barcode, amount
+and optionally reference.  Each payment is applied as a cash payment
+to the patron's open transactions, oldest first.  Patrons whose
+balance is paid in full are removed from collections.
+
+Options
+
+    --org-file
+        Export config file.  Required unless --payments is used.
+
+    --out-dir
+        Directory for export files.  Defaults to the current directory.
+
+    --payments
+        Process this agency payment file instead of exporting.
+
+    --staff
+        Staff user recorded as collector, penalty creator and payment
+        acceptor.  Required.
+
+{}
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn read_config(path: &str) -> Result<Vec<OrgConfig>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let config: Value = match serde_json::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let orgs = match config["orgs"].as_array() {
+        Some(o) => o,
+        None => return Err(format!("{path} has no orgs list")),
+    };
+
+    let mut list = Vec::new();
+
+    for org in orgs {
+        let (shortname, threshold) = match (org["org"].as_str(), org["threshold"].as_str()) {
+            (Some(o), Some(t)) => (o, t),
+            _ => return Err(format!("Org config requires org and threshold: {org}")),
+        };
+
+        let format = org["format"].as_str().unwrap_or("csv");
+        if format != "csv" && format != "tsv" {
+            return Err(format!("Unsupported format for {shortname}: {format}"));
+        }
+
+        list.push(OrgConfig {
+            org: shortname.to_string(),
+            threshold: threshold.to_string(),
+            min_age_days: org["min_age_days"].as_i64().unwrap_or(60),
+            format: format.to_string(),
+        });
+    }
+
+    Ok(list)
+}
+
+fn format_line(values: &[String], format: &str) -> String {
+    let fields: Vec<String> = match format {
+        "tsv" => values
+            .iter()
+            .map(|v| v.replace(['\t', '\n', '\r'], " "))
+            .collect(),
+        _ => values
+            .iter()
+            .map(|v| {
+                if v.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", v.replace('"', "\"\""))
+                } else {
+                    v.to_string()
+                }
+            })
+            .collect(),
+    };
+
+    let sep = if format == "tsv" { "\t" } else { "," };
+    fields.join(sep) + "\n"
+}
+
+const ELIGIBLE_SQL: &str = r#"
+    SELECT
+        au.id,
+        card.barcode,
+        au.family_name,
+        au.first_given_name,
+        COALESCE(au.dob::TEXT, '') AS dob,
+        COALESCE(addr.street1, '') AS street1,
+        COALESCE(addr.street2, '') AS street2,
+        COALESCE(addr.city, '') AS city,
+        COALESCE(addr.state, '') AS state,
+        COALESCE(addr.post_code, '') AS post_code,
+        COALESCE(au.day_phone, au.evening_phone, au.other_phone, '') AS phone,
+        COALESCE(au.email, '') AS email,
+        hl.shortname AS home_lib,
+        SUM(mbxs.balance_owed)::TEXT AS balance,
+        MIN(mbxs.xact_start)::DATE::TEXT AS oldest_xact
+    FROM actor.usr au
+        JOIN actor.card card ON card.id = au.card
+        JOIN actor.org_unit hl ON hl.id = au.home_ou
+        LEFT JOIN actor.usr_address addr
+            ON addr.id = COALESCE(au.mailing_address, au.billing_address)
+        JOIN money.materialized_billable_xact_summary mbxs
+            ON mbxs.usr = au.id AND mbxs.xact_finish IS NULL AND mbxs.balance_owed > 0
+    WHERE NOT au.deleted
+        AND au.home_ou IN (SELECT id FROM actor.org_unit_descendants($1::INT))
+        AND NOT EXISTS (
+            SELECT 1 FROM actor.usr_standing_penalty ausp
+                JOIN config.standing_penalty csp ON csp.id = ausp.standing_penalty
+            WHERE ausp.usr = au.id AND csp.name = $4
+                AND (ausp.stop_date IS NULL OR ausp.stop_date > NOW())
+        )
+    GROUP BY 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13
+    HAVING SUM(mbxs.balance_owed) >= $2::TEXT::NUMERIC
+        AND MIN(mbxs.xact_start) < NOW() - ($3::BIGINT * '1 day'::INTERVAL)
+    ORDER BY au.family_name, au.first_given_name
+"#;
+
+fn export_org(
+    con: &mut DatabaseConnection,
+    ops: &CollectionsOptions,
+    config: &OrgConfig,
+    date: &str,
+) -> Result<usize, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+    let org_id: i32 = match con.client().query_opt(sql, &[&config.org]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No such org unit: {}", config.org)),
+        Err(e) => return Err(db_err("Error loading org unit", e)),
+    };
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let params: &[&(dyn pg::types::ToSql + Sync)] = &[
+        &org_id,
+        &config.threshold,
+        &config.min_age_days,
+        &PENALTY_NAME,
+    ];
+
+    let rows = tx
+        .query(ELIGIBLE_SQL, params)
+        .map_err(|e| db_err("Error finding patrons", e))?;
+
+    let mut out = format_line(
+        &EXPORT_COLUMNS
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>(),
+        &config.format,
+    );
+
+    let penalty = r#"
+        INSERT INTO actor.usr_standing_penalty (usr, standing_penalty, org_unit, staff)
+        SELECT $1, id, $2, $3 FROM config.standing_penalty WHERE name = $4
+    "#;
+
+    let tracker = r#"
+        INSERT INTO money.collections_tracker (usr, collector, location)
+        VALUES ($1, $2, $3)
+    "#;
+
+    for row in &rows {
+        let usr_id: i32 = row.get("id");
+
+        let values: Vec<String> = EXPORT_COLUMNS
+            .iter()
+            .map(|c| row.get::<_, String>(*c))
+            .collect();
+        out += &format_line(&values, &config.format);
+
+        tx.execute(penalty, &[&usr_id, &org_id, &ops.staff, &PENALTY_NAME])
+            .map_err(|e| db_err("Error adding penalty", e))?;
+
+        tx.execute(tracker, &[&usr_id, &ops.staff, &org_id])
+            .map_err(|e| db_err("Error adding collections tracker", e))?;
+    }
+
+    let path = Path::new(&ops.out_dir).join(format!(
+        "collections-{}-{date}.{}",
+        config.org, config.format
+    ));
+
+    // Write the file before committing so a failed write leaves the
+    // patrons eligible for the next run.
+    if let Err(e) = fs::write(&path, out) {
+        return Err(format!("Cannot write {}: {e}", path.display()));
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    info!(
+        "{}: exported {} patrons to {}",
+        config.org,
+        rows.len(),
+        path.display()
+    );
+
+    Ok(rows.len())
+}
+
+fn export(con: &mut DatabaseConnection, ops: &CollectionsOptions) -> Result<(), String> {
+    let config_file = match ops.config_file {
+        Some(ref f) => f,
+        None => return Err("--org-file required".to_string()),
+    };
+
+    let orgs = read_config(config_file)?;
+
+    let now = UtcTime::now();
+    let date = format!("{:04}{:02}{:02}", now.year, now.month, now.day);
+
+    let mut total = 0;
+    for config in &orgs {
+        total += export_org(con, ops, config, &date)?;
+    }
+
+    audit::count("patrons", total as u64);
+
+    println!("{} {total} patrons", ops.dry_run.past("Exported"));
+
+    Ok(())
+}
+
+fn read_payments(path: &str) -> Result<Vec<Payment>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let (barcode_col, amount_col) = match (col("barcode"), col("amount")) {
+        (Some(b), Some(a)) => (b, a),
+        _ => return Err(format!("{path} requires barcode and amount columns")),
+    };
+    let ref_col = col("reference");
+
+    let mut payments = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let barcode = fields.get(barcode_col).copied().unwrap_or("");
+        let amount = fields.get(amount_col).copied().unwrap_or("");
+
+        let amount = match Money::parse(amount) {
+            Ok(a) if a.is_positive() && !barcode.is_empty() => a,
+            _ => {
+                warn!("Line {}: invalid payment: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        payments.push(Payment {
+            line: idx + 1,
+            barcode: barcode.to_string(),
+            amount,
+            reference: ref_col
+                .and_then(|c| fields.get(c))
+                .map(|s| s.to_string())
+                .unwrap_or_default(),
+        });
+    }
+
+    Ok(payments)
+}
+
+/// Apply one payment, returning the unapplied remainder.
+fn apply_payment(
+    tx: &mut pg::Transaction,
+    ops: &CollectionsOptions,
+    payment: &Payment,
+) -> Result<Money, String> {
+    let sql = r#"
+        SELECT au.id FROM actor.card ac JOIN actor.usr au ON au.id = ac.usr
+        WHERE ac.barcode = $1 AND NOT au.deleted
+    "#;
+
+    let usr_id: i32 = match tx.query_opt(sql, &[&payment.barcode]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No patron found for {}", payment.barcode)),
+        Err(e) => return Err(db_err("Error loading patron", e)),
+    };
+
+    let sql = r#"
+        SELECT id, balance_owed::TEXT AS owed
+        FROM money.materialized_billable_xact_summary
+        WHERE usr = $1 AND xact_finish IS NULL AND balance_owed > 0
+        ORDER BY xact_start, id
+    "#;
+
+    let xacts = tx
+        .query(sql, &[&usr_id])
+        .map_err(|e| db_err("Error loading transactions", e))?;
+
+    let note = match payment.reference.is_empty() {
+        true => "Collections agency payment".to_string(),
+        false => format!("Collections agency payment {}", payment.reference),
+    };
+
+    let pay_sql = r#"
+        INSERT INTO money.cash_payment (xact, amount, note, accepting_usr)
+        VALUES ($1, $2::TEXT::NUMERIC, $3, $4)
+    "#;
+
+    // Close paid transactions unless they are still-open circulations.
+    let finish_sql = r#"
+        UPDATE money.billable_xact SET xact_finish = NOW()
+        WHERE id = $1 AND xact_finish IS NULL
+            AND NOT EXISTS (
+                SELECT 1 FROM action.circulation
+                WHERE id = $1 AND checkin_time IS NULL
+            )
+    "#;
+
+    let mut remaining = payment.amount;
+
+    for xact in xacts {
+        if remaining.is_zero() {
+            break;
+        }
+
+        let xact_id: i64 = xact.get("id");
+        let owed: String = xact.get("owed");
+        let owed = Money::parse(&owed)?;
+
+        let amount = remaining.min(owed);
+        if !amount.is_positive() {
+            continue;
+        }
+
+        tx.execute(pay_sql, &[&xact_id, &amount.to_string(), &note, &ops.staff])
+            .map_err(|e| db_err("Error adding payment", e))?;
+
+        if amount == owed {
+            tx.execute(finish_sql, &[&xact_id])
+                .map_err(|e| db_err("Error closing transaction", e))?;
+        }
+
+        remaining -= amount;
+    }
+
+    let sql = r#"
+        SELECT COALESCE(SUM(balance_owed), 0) <= 0 AS paid
+        FROM money.materialized_billable_xact_summary
+        WHERE usr = $1 AND xact_finish IS NULL
+    "#;
+
+    let paid: bool = tx
+        .query_one(sql, &[&usr_id])
+        .map_err(|e| db_err("Error checking balance", e))?
+        .get("paid");
+
+    if paid {
+        let sql = r#"
+            UPDATE actor.usr_standing_penalty SET stop_date = NOW()
+            WHERE usr = $1
+                AND (stop_date IS NULL OR stop_date > NOW())
+                AND standing_penalty IN (
+                    SELECT id FROM config.standing_penalty WHERE name = $2
+                )
+        "#;
+
+        tx.execute(sql, &[&usr_id, &PENALTY_NAME])
+            .map_err(|e| db_err("Error removing collections penalty", e))?;
+
+        info!(
+            "Patron {} paid in full; removed from collections",
+            payment.barcode
+        );
+    }
+
+    Ok(remaining)
+}
+
+fn process_payments(
+    con: &mut DatabaseConnection,
+    ops: &CollectionsOptions,
+    path: &str,
+) -> Result<(), String> {
+    let payments = read_payments(path)?;
+
+    let mut applied = 0;
+    let mut failed = 0;
+
+    for payment in &payments {
+        let mut tx = ops.dry_run.transaction(con.client())?;
+
+        match apply_payment(&mut tx, ops, payment) {
+            Ok(remaining) => {
+                if remaining.is_positive() {
+                    warn!(
+                        "Line {}: {} unapplied for {}",
+                        payment.line, remaining, payment.barcode
+                    );
+                }
+
+                ops.dry_run.finish(tx)?;
+
+                applied += 1;
+            }
+            Err(e) => {
+                warn!("Line {}: {e}", payment.line);
+                failed += 1;
+            }
+        }
+    }
+
+    audit::count("payments", applied as u64);
+    audit::count("failed", failed as u64);
+
+    println!(
+        "{} {applied} payments, {failed} failed",
+        ops.dry_run.past("Applied")
+    );
+
+    Ok(())
+}
+
+fn run(con: &mut DatabaseConnection, ops: &CollectionsOptions) -> Result<(), String> {
+    con.connect()?;
+
+    match ops.payments_file {
+        Some(ref path) => process_payments(con, ops, path)?,
+        None => export(con, ops)?,
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(run(&mut connection, &options))
+}
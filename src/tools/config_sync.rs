@@ -0,0 +1,476 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::info;
+use postgres as pg;
+use std::collections::HashMap;
+
+/// A table of org unit owned config, matched across org units by name.
+struct Target {
+    name: &'static str,
+    table: &'static str,
+    /// Column matched between org units.
+    key: &'static str,
+    /// Columns not copied, besides id and owning_lib.
+    exclude: &'static [&'static str],
+    /// Limits the source rows.
+    filter: &'static str,
+}
+
+/// Copy locations come first so templates can use the new ones.
+const TARGETS: &[Target] = &[
+    Target {
+        name: "locations",
+        table: "asset.copy_location",
+        key: "name",
+        exclude: &[],
+        filter: "NOT deleted",
+    },
+    Target {
+        name: "prefixes",
+        table: "asset.call_number_prefix",
+        key: "label",
+        exclude: &["label_sortkey"],
+        filter: "TRUE",
+    },
+    Target {
+        name: "suffixes",
+        table: "asset.call_number_suffix",
+        key: "label",
+        exclude: &["label_sortkey"],
+        filter: "TRUE",
+    },
+    Target {
+        name: "templates",
+        table: "asset.copy_template",
+        key: "name",
+        exclude: &["creator", "editor", "create_date", "edit_date"],
+        filter: "TRUE",
+    },
+];
+
+const REPORT_COLUMNS: &[&str] = &["target", "to_org", "name", "source", "dest", "result"];
+
+struct SyncOptions {
+    from: String,
+    to: Vec<String>,
+    targets: Vec<String>,
+    update: bool,
+    staff: i32,
+    report_file: Option<String>,
+    dry_run: DryRun,
+}
+
+fn read_options() -> (SyncOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "from", "Source Org Unit", "SHORTNAME");
+    opts.optmulti("", "to", "Destination Org Units", "SHORTNAMES");
+    opts.optmulti("", "target", "Config to Copy", "TARGETS");
+    opts.optflag("", "update", "Update Existing Entries");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "report-file", "Report Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let list = |name: &str| -> Vec<String> {
+        params
+            .opt_strs(name)
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect()
+    };
+
+    let to = list("to");
+    if to.is_empty() {
+        eprintln!("--to required");
+        std::process::exit(2);
+    }
+
+    let mut targets = list("target");
+    if targets.is_empty() {
+        targets = TARGETS.iter().map(|t| t.name.to_string()).collect();
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        SyncOptions {
+            from: cli::required(&params, "from"),
+            to,
+            targets,
+            update: params.opt_present("update"),
+            staff: cli::required(&params, "staff"),
+            report_file: params.opt_str("report-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin config-sync -- --from BR1 --to BR4,BR5 --staff 1 --dry-run
+    cargo run --bin config-sync -- --from BR1 --to BR4 --target templates --update --staff 1
+
+Copies org unit owned cataloging config from one org unit to others,
+e.g. when onboarding a new member library.
+
+Targets
+
+    locations
+        Copy locations, except deleted ones.
+
+    prefixes
+        Call number prefixes.
+
+    suffixes
+        Call number suffixes.
+
+    templates
+        Copy templates.  The copies use the destination's copy
+        location of the same name, when it has one, and circulate at
+        the destination when the source template circulates at the
+        source org unit.
+
+Entries are matched by name, or label for prefixes and suffixes.
+Entries the destination already has are reported and left alone
+unless --update is given.  Entries the destination has that the
+source lacks are never removed.
+
+Each entry is copied in its own savepoint, and every entry is
+written to the report with its result.
+
+Options
+
+    --from
+        Source org unit shortname.  Required.
+
+    --to
+        Destination org unit shortnames.  Repeatable or comma
+        separated.  Required.
+
+    --target
+        Config to copy.  Repeatable or comma separated.  Defaults to
+        all of: locations,prefixes,suffixes,templates
+
+    --update
+        Update entries the destination already has to match the
+        source.
+
+    --staff
+        Staff user recorded as template creator and editor.
+        Required.
+
+    --report-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn org_id(tx: &mut pg::Transaction, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error looking up org unit", e)),
+    }
+}
+
+/// The target's copied columns, as this database has them.
+fn copy_columns(tx: &mut pg::Transaction, target: &Target) -> Result<Vec<String>, String> {
+    let (schema, table) = target.table.split_once('.').unwrap_or(("", target.table));
+
+    let mut exclude: Vec<&str> = vec!["id", "owning_lib"];
+    exclude.extend(target.exclude);
+
+    let sql = r#"
+        SELECT column_name::TEXT
+        FROM information_schema.columns
+        WHERE table_schema = $1
+            AND table_name = $2
+            AND column_name <> ALL($3::TEXT[])
+        ORDER BY ordinal_position
+    "#;
+
+    let rows = tx
+        .query(sql, &[&schema, &table, &exclude])
+        .map_err(|e| db_err("Error reading table columns", e))?;
+
+    if rows.is_empty() {
+        return Err(format!("No such table: {}", target.table));
+    }
+
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
+
+/// Point a copied template at the destination's locations and circ lib.
+fn localize_template(
+    tx: &mut pg::Transaction,
+    template: i32,
+    from: i32,
+    to: i32,
+) -> Result<(), String> {
+    let sql = r#"
+        UPDATE asset.copy_template act
+        SET circ_lib = CASE WHEN act.circ_lib = $2 THEN $3 ELSE act.circ_lib END,
+            location = COALESCE((
+                SELECT dest.id
+                FROM asset.copy_location src
+                    JOIN asset.copy_location dest
+                        ON dest.name = src.name
+                        AND dest.owning_lib = $3
+                        AND NOT dest.deleted
+                WHERE src.id = act.location
+                LIMIT 1
+            ), act.location)
+        WHERE act.id = $1
+    "#;
+
+    tx.execute(sql, &[&template, &from, &to])
+        .map(|_| ())
+        .map_err(|e| db_err("Error updating template", e))
+}
+
+/// Copy or update one entry.  Returns the destination ID and result.
+#[allow(clippy::too_many_arguments)]
+fn sync_entry(
+    tx: &mut pg::Transaction,
+    ops: &SyncOptions,
+    target: &Target,
+    columns: &[String],
+    source: i32,
+    existing: Option<i32>,
+    from: i32,
+    to: i32,
+) -> Result<(i32, &'static str), String> {
+    let cols = columns.join(", ");
+    let is_template = target.name == "templates";
+
+    let dest = match existing {
+        Some(dest) if !ops.update => return Ok((dest, "Exists")),
+        Some(dest) => {
+            let audit = match is_template {
+                true => ", editor = $3::INT, edit_date = NOW()",
+                false => "",
+            };
+
+            let sql = format!(
+                r#"
+                UPDATE {table} dest
+                SET ({cols}) = (SELECT {cols} FROM {table} WHERE id = $1) {audit}
+                WHERE dest.id = $2
+                "#,
+                table = target.table
+            );
+
+            let result = match is_template {
+                true => tx.execute(sql.as_str(), &[&source, &dest, &ops.staff]),
+                false => tx.execute(sql.as_str(), &[&source, &dest]),
+            };
+
+            result.map_err(|e| db_err("Error updating entry", e))?;
+            dest
+        }
+        None => {
+            let (audit_cols, audit_vals) = match is_template {
+                true => (", creator, editor", ", $3::INT, $3::INT"),
+                false => ("", ""),
+            };
+
+            let sql = format!(
+                r#"
+                INSERT INTO {table} (owning_lib, {cols}{audit_cols})
+                SELECT $2, {cols}{audit_vals}
+                FROM {table}
+                WHERE id = $1
+                RETURNING id
+                "#,
+                table = target.table
+            );
+
+            let result = match is_template {
+                true => tx.query_one(sql.as_str(), &[&source, &to, &ops.staff]),
+                false => tx.query_one(sql.as_str(), &[&source, &to]),
+            };
+
+            result
+                .map_err(|e| db_err("Error copying entry", e))?
+                .get("id")
+        }
+    };
+
+    if is_template {
+        localize_template(tx, dest, from, to)?;
+    }
+
+    Ok((
+        dest,
+        match existing {
+            Some(_) => "Updated",
+            None => "Added",
+        },
+    ))
+}
+
+/// Entries owned by an org unit as key => id.
+fn entries(
+    tx: &mut pg::Transaction,
+    target: &Target,
+    org: i32,
+) -> Result<Vec<(String, i32)>, String> {
+    let sql = format!(
+        "SELECT {key}::TEXT AS key, id FROM {table} WHERE owning_lib = $1 AND {filter} ORDER BY {key}",
+        key = target.key,
+        table = target.table,
+        filter = target.filter
+    );
+
+    tx.query(sql.as_str(), &[&org])
+        .map(|rows| rows.iter().map(|r| (r.get("key"), r.get("id"))).collect())
+        .map_err(|e| db_err(&format!("Error loading {}", target.table), e))
+}
+
+fn sync(con: &mut DatabaseConnection, ops: &SyncOptions) -> Result<(), String> {
+    let mut targets = Vec::new();
+
+    for name in &ops.targets {
+        if !TARGETS.iter().any(|t| t.name == name) {
+            return Err(format!("Unknown target: {name}"));
+        }
+    }
+
+    // Keep TARGETS order regardless of the option order.
+    for target in TARGETS {
+        if ops.targets.iter().any(|n| n == target.name) {
+            targets.push(target);
+        }
+    }
+
+    let mut writer = TableWriter::for_path(ops.report_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let from = org_id(&mut tx, &ops.from)?;
+
+    let mut dests = Vec::new();
+    for shortname in &ops.to {
+        dests.push((shortname, org_id(&mut tx, shortname)?));
+    }
+
+    let mut changed = 0;
+
+    for target in targets {
+        let columns = copy_columns(&mut tx, target)?;
+        let sources = entries(&mut tx, target, from)?;
+
+        info!(
+            "Copying {} {} from {}",
+            sources.len(),
+            target.name,
+            ops.from
+        );
+
+        for (shortname, to) in &dests {
+            let existing: HashMap<String, i32> = entries(&mut tx, target, *to)?
+                .into_iter()
+                .map(|(k, id)| (k.to_lowercase(), id))
+                .collect();
+
+            for (key, source) in &sources {
+                let mut sp = tx
+                    .savepoint("config_sync")
+                    .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+                let result = sync_entry(
+                    &mut sp,
+                    ops,
+                    target,
+                    &columns,
+                    *source,
+                    existing.get(&key.to_lowercase()).copied(),
+                    from,
+                    *to,
+                );
+
+                let (dest, result) = match result {
+                    Ok((dest, r)) => {
+                        sp.commit()
+                            .map_err(|e| db_err("Cannot release savepoint", e))?;
+                        if r != "Exists" {
+                            changed += 1;
+                        }
+                        (Some(dest), r.to_string())
+                    }
+                    Err(e) => {
+                        sp.rollback()
+                            .map_err(|e| db_err("Cannot roll back savepoint", e))?;
+                        (None, e)
+                    }
+                };
+
+                writer.write_row(&[
+                    Cell::from(target.name),
+                    Cell::from(*shortname),
+                    Cell::from(key),
+                    Cell::from(*source),
+                    Cell::from(dest),
+                    Cell::from(result),
+                ])?;
+            }
+        }
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("entries", changed as u64);
+
+    info!(
+        "{} {changed} entries",
+        ops.dry_run.past("Copied or updated")
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(sync(&mut connection, &options))
+}
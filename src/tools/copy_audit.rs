@@ -0,0 +1,396 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use std::process;
+
+const REPORT_COLUMNS: &[&str] = &[
+    "anomaly",
+    "copy",
+    "barcode",
+    "circ_lib",
+    "status",
+    "repair_status",
+    "detail",
+];
+
+/// config.copy_status IDs
+const CHECKED_OUT: i32 = 1;
+const LOST: i32 = 3;
+const IN_TRANSIT: i32 = 6;
+const RESHELVING: i32 = 7;
+const ON_HOLDS_SHELF: i32 = 8;
+const LONG_OVERDUE: i32 = 16;
+
+struct AuditOptions {
+    anomalies: Vec<String>,
+    org_unit: Option<String>,
+    repair: bool,
+    staff: Option<i32>,
+    out_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// A kind of inconsistent copy state.
+///
+/// Each query produces rows with copy "id", "repair_status" (NULL
+/// when the anomaly needs a person) and "detail" columns.
+struct Anomaly {
+    name: &'static str,
+    label: &'static str,
+    sql: String,
+}
+
+fn anomalies() -> Vec<Anomaly> {
+    let open_circ = r#"
+        SELECT 1 FROM action.circulation circ
+        WHERE circ.target_copy = acp.id AND circ.checkin_time IS NULL
+    "#;
+
+    vec![
+        Anomaly {
+            name: "checked_out_no_circ",
+            label: "Checked out with no open circulation",
+            sql: format!(
+                r#"
+                SELECT acp.id, {RESHELVING} AS repair_status,
+                    'Last circulation ' || COALESCE((
+                        SELECT MAX(circ.id)::TEXT FROM action.circulation circ
+                        WHERE circ.target_copy = acp.id
+                    ), 'none') AS detail
+                FROM asset.copy acp
+                WHERE acp.status = {CHECKED_OUT} AND NOT EXISTS ({open_circ})
+                "#
+            ),
+        },
+        Anomaly {
+            name: "open_circ_wrong_status",
+            label: "Open circulation but not checked out, lost or long overdue",
+            sql: format!(
+                r#"
+                SELECT acp.id,
+                    CASE circ.stop_fines
+                        WHEN 'LOST' THEN {LOST}
+                        WHEN 'LONGOVERDUE' THEN {LONG_OVERDUE}
+                        ELSE {CHECKED_OUT}
+                    END AS repair_status,
+                    'Circulation ' || circ.id
+                        || COALESCE(' (' || circ.stop_fines || ')', '') AS detail
+                FROM asset.copy acp
+                    JOIN action.circulation circ
+                        ON circ.target_copy = acp.id AND circ.checkin_time IS NULL
+                WHERE acp.status <> CASE circ.stop_fines
+                    WHEN 'LOST' THEN {LOST}
+                    WHEN 'LONGOVERDUE' THEN {LONG_OVERDUE}
+                    ELSE {CHECKED_OUT}
+                END
+                "#
+            ),
+        },
+        Anomaly {
+            name: "multiple_open_circs",
+            label: "More than one open circulation",
+            sql: r#"
+                SELECT acp.id, NULL::INT AS repair_status,
+                    'Circulations ' || STRING_AGG(circ.id::TEXT, ' ' ORDER BY circ.id) AS detail
+                FROM asset.copy acp
+                    JOIN action.circulation circ
+                        ON circ.target_copy = acp.id AND circ.checkin_time IS NULL
+                GROUP BY acp.id
+                HAVING COUNT(*) > 1
+            "#
+            .to_string(),
+        },
+        Anomaly {
+            name: "holds_shelf_no_hold",
+            label: "On holds shelf with no captured hold",
+            sql: format!(
+                r#"
+                SELECT acp.id, {RESHELVING} AS repair_status,
+                    NULL::TEXT AS detail
+                FROM asset.copy acp
+                WHERE acp.status = {ON_HOLDS_SHELF}
+                    AND NOT EXISTS (
+                        SELECT 1 FROM action.hold_request ahr
+                        WHERE ahr.current_copy = acp.id
+                            AND ahr.capture_time IS NOT NULL
+                            AND ahr.fulfillment_time IS NULL
+                            AND ahr.cancel_time IS NULL
+                    )
+                "#
+            ),
+        },
+        Anomaly {
+            name: "captured_hold_not_on_shelf",
+            label: "Captured for a hold but not on holds shelf or in transit",
+            sql: format!(
+                r#"
+                SELECT acp.id, {ON_HOLDS_SHELF} AS repair_status,
+                    'Hold ' || ahr.id AS detail
+                FROM asset.copy acp
+                    JOIN action.hold_request ahr ON ahr.current_copy = acp.id
+                WHERE ahr.capture_time IS NOT NULL
+                    AND ahr.fulfillment_time IS NULL
+                    AND ahr.cancel_time IS NULL
+                    AND acp.status NOT IN ({ON_HOLDS_SHELF}, {IN_TRANSIT})
+                    AND NOT EXISTS ({open_circ})
+                "#
+            ),
+        },
+    ]
+}
+
+fn read_options() -> (AuditOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "anomaly", "Anomaly to Check, Repeatable", "NAME");
+    opts.optopt(
+        "",
+        "org-unit",
+        "Limit to Org Unit and Descendants",
+        "SHORTNAME",
+    );
+    opts.optflag("", "repair", "Repair Anomalies");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "out-file", "Report Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let repair = params.opt_present("repair");
+    let staff: Option<i32> = params.opt_get("staff").unwrap();
+
+    if repair && staff.is_none() {
+        eprintln!("--repair requires --staff");
+        process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        AuditOptions {
+            anomalies: params
+                .opt_strs("anomaly")
+                .iter()
+                .flat_map(|a| a.split(','))
+                .map(|a| a.trim().to_string())
+                .filter(|a| !a.is_empty())
+                .collect(),
+            org_unit: params.opt_str("org-unit"),
+            repair,
+            staff,
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin copy-audit -- --org-unit SYS1 --out-file anomalies.csv
+    cargo run --bin copy-audit -- --repair --staff 1 --dry-run
+
+Finds copies whose status disagrees with their circulations and holds,
+and reports them grouped by anomaly:
+
+    checked_out_no_circ
+        Checked out with no open circulation.  Repaired to
+        Reshelving.
+
+    open_circ_wrong_status
+        Open circulation, but not Checked out, or Lost or Long
+        Overdue to match the circulation.  Repaired to the status
+        matching the circulation.
+
+    multiple_open_circs
+        More than one open circulation.  Needs review.
+
+    holds_shelf_no_hold
+        On holds shelf with no captured, unfilled hold.  Repaired
+        to Reshelving.
+
+    captured_hold_not_on_shelf
+        Captured for an unfilled hold, but neither On holds shelf
+        nor In transit.  Repaired to On holds shelf.
+
+With --repair, anomalies with a repair status have their copies
+updated, all in one transaction.  With --dry-run, the report shows
+the repairs, which are rolled back.
+
+Options
+
+    --anomaly
+        Only check this anomaly.  Repeatable, or comma separated.
+        Defaults to all.
+
+    --org-unit
+        Only check copies whose circulating library is this org unit
+        or one of its descendants.
+
+    --repair
+        Repair anomalies which have a repair status.
+
+    --staff
+        Staff user recorded as copy editor.  Required for --repair.
+
+    --out-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Anomaly rows with copy details, limited to $1 org unit.
+fn report_sql(anomaly: &Anomaly) -> String {
+    format!(
+        r#"
+        SELECT
+            acp.id,
+            acp.barcode,
+            aou.shortname AS circ_lib,
+            ccs.name AS status,
+            found.repair_status,
+            repair.name AS repair_status_name,
+            found.detail
+        FROM ({}) found
+            JOIN asset.copy acp ON acp.id = found.id
+            JOIN actor.org_unit aou ON aou.id = acp.circ_lib
+            JOIN config.copy_status ccs ON ccs.id = acp.status
+            LEFT JOIN config.copy_status repair ON repair.id = found.repair_status
+        WHERE NOT acp.deleted
+            AND ($1::INT IS NULL OR acp.circ_lib IN (
+                SELECT id FROM actor.org_unit_descendants($1::INT)
+            ))
+        ORDER BY acp.id
+        "#,
+        anomaly.sql
+    )
+}
+
+fn audit(con: &mut DatabaseConnection, ops: &AuditOptions) -> Result<(), String> {
+    let all = anomalies();
+
+    for name in &ops.anomalies {
+        if !all.iter().any(|a| a.name == name) {
+            return Err(format!("No such anomaly: {name}"));
+        }
+    }
+
+    let checks: Vec<&Anomaly> = all
+        .iter()
+        .filter(|a| ops.anomalies.is_empty() || ops.anomalies.iter().any(|n| n == a.name))
+        .collect();
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let org_unit: Option<i32> = match &ops.org_unit {
+        Some(shortname) => {
+            let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+            match tx.query_opt(sql, &[&shortname]) {
+                Ok(Some(row)) => Some(row.get("id")),
+                Ok(None) => return Err(format!("No such org unit: {shortname}")),
+                Err(e) => return Err(db_err("Error loading org unit", e)),
+            }
+        }
+        None => None,
+    };
+
+    let update = r#"
+        UPDATE asset.copy
+        SET status = $2, editor = $3::INT, edit_date = NOW(), status_changed_time = NOW()
+        WHERE id = $1
+    "#;
+
+    let mut found = 0;
+    let mut repaired = 0;
+
+    // Each anomaly is checked after the repairs before it, so a copy
+    // fixed once is not reported again.
+    for anomaly in checks {
+        let rows = tx
+            .query(report_sql(anomaly).as_str(), &[&org_unit])
+            .map_err(|e| db_err(&format!("Error checking {}", anomaly.name), e))?;
+
+        if !rows.is_empty() {
+            warn!("{}: {} copies", anomaly.label, rows.len());
+        }
+
+        for row in &rows {
+            let copy: i64 = row.get("id");
+            let repair_status: Option<i32> = row.get("repair_status");
+
+            writer.write_row(&[
+                Cell::from(anomaly.name),
+                Cell::from(copy),
+                Cell::from(row.get::<_, String>("barcode")),
+                Cell::from(row.get::<_, String>("circ_lib")),
+                Cell::from(row.get::<_, String>("status")),
+                Cell::from(row.get::<_, Option<String>>("repair_status_name")),
+                Cell::from(row.get::<_, Option<String>>("detail")),
+            ])?;
+
+            found += 1;
+
+            if let (true, Some(status)) = (ops.repair, repair_status) {
+                tx.execute(update, &[&copy, &status, &ops.staff])
+                    .map_err(|e| db_err(&format!("Error repairing copy {copy}"), e))?;
+                repaired += 1;
+            }
+        }
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("anomalies", found as u64);
+    audit::count("repaired", repaired as u64);
+
+    info!(
+        "Found {found} anomalies; {} {repaired} copies",
+        ops.dry_run.past("repaired")
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(audit(&mut connection, &options))
+}
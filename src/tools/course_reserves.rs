@@ -0,0 +1,405 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use std::fs;
+
+struct CourseOptions {
+    export: bool,
+    term: Option<String>,
+    include_archived: bool,
+    out_file: Option<String>,
+    rollovers: Vec<Rollover>,
+    import_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// Courses in one term to add to another, optionally one course.
+#[derive(Debug, Clone)]
+struct Rollover {
+    from_term: String,
+    to_term: String,
+    course_number: Option<String>,
+    section_number: Option<String>,
+}
+
+fn read_options() -> (CourseOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optflag("", "export", "Export Course Reserve Lists");
+    opts.optopt("", "term", "Export Courses in This Term", "TERM");
+    opts.optflag("", "include-archived", "Export Archived Courses");
+    opts.optopt("", "out-file", "Export Output File", "FILE");
+    opts.optmulti("", "rollover", "Add a Term's Courses to Another", "FROM:TO");
+    opts.optopt("", "import", "Rollover CSV File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let rollovers = params
+        .opt_strs("rollover")
+        .iter()
+        .map(|r| match r.split_once(':') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Rollover {
+                from_term: from.trim().to_string(),
+                to_term: to.trim().to_string(),
+                course_number: None,
+                section_number: None,
+            },
+            _ => {
+                eprintln!("Invalid --rollover value: {r}");
+                std::process::exit(2);
+            }
+        })
+        .collect();
+
+    let options = CourseOptions {
+        export: params.opt_present("export"),
+        term: params.opt_str("term"),
+        include_archived: params.opt_present("include-archived"),
+        out_file: params.opt_str("out-file"),
+        rollovers,
+        import_file: params.opt_str("import"),
+        dry_run: DryRun::from_params(&params),
+    };
+
+    if !options.export && options.rollovers.is_empty() && options.import_file.is_none() {
+        eprintln!("One of --export, --rollover or --import is required");
+        std::process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (options, connection)
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin course-reserves -- --export --term "Fall 2024" --out-file reserves.csv
+    cargo run --bin course-reserves -- --rollover "Fall 2024:Spring 2025" --dry-run
+    cargo run --bin course-reserves -- --import rollover.csv
+
+Exports course reserve lists and rolls courses over from term to
+term in bulk.
+
+Exports have one CSV row per course material, or one row for a
+course without materials: terms, course_number, section_number,
+course_name, owning_lib, instructors, record, title, barcode and
+relationship.  Instructors are listed as "Name (Role)", separated by
+semicolons, as are terms.
+
+A rollover adds each unarchived course in the first term to the
+second, keeping its materials and instructors.  Courses already in
+the second term are skipped.  Rollovers run in one transaction, so
+an error saves none of them.
+
+Import files are CSV with a header row.  from_term and to_term
+columns are required.  Rows with a course_number, and optionally a
+section_number, roll over only that course; others roll over the
+whole term.
+
+    from_term,to_term,course_number,section_number
+    Fall 2024,Spring 2025,HIST101,01
+    Fall 2024,Spring 2025,BIO200,
+
+Options
+
+    --export
+        Export course reserve lists.
+
+    --term
+        Only export courses in the term with this name.
+
+    --include-archived
+        Include archived courses in the export.
+
+    --out-file
+        Write the export to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --rollover
+        FROM:TO term names.  Add the courses in term FROM to term TO.
+        Repeatable.
+
+    --import
+        CSV file of rollovers.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// $1 term name or NULL, $2 include archived courses.
+const EXPORT_SQL: &str = r#"
+    SELECT
+        COALESCE(terms.names, '') AS terms,
+        acmc.course_number,
+        COALESCE(acmc.section_number, '') AS section_number,
+        acmc.name AS course_name,
+        aou.shortname AS owning_lib,
+        COALESCE((
+            SELECT STRING_AGG(
+                au.first_given_name || ' ' || au.family_name
+                    || COALESCE(' (' || acmr.name || ')', ''),
+                '; ' ORDER BY au.family_name, au.first_given_name
+            )
+            FROM asset.course_module_course_users acmcu
+                JOIN actor.usr au ON au.id = acmcu.usr
+                LEFT JOIN asset.course_module_role acmr ON acmr.id = acmcu.usr_role
+            WHERE acmcu.course = acmc.id
+        ), '') AS instructors,
+        acmcm.record,
+        COALESCE(rmsr.title, '') AS title,
+        COALESCE(acp.barcode, '') AS barcode,
+        COALESCE(acmcm.relationship, '') AS relationship
+    FROM asset.course_module_course acmc
+        JOIN actor.org_unit aou ON aou.id = acmc.owning_lib
+        LEFT JOIN LATERAL (
+            SELECT STRING_AGG(acmt.name, '; ' ORDER BY acmt.start_date) AS names
+            FROM asset.course_module_term_course_map acmtcm
+                JOIN asset.course_module_term acmt ON acmt.id = acmtcm.term
+            WHERE acmtcm.course = acmc.id
+        ) terms ON TRUE
+        LEFT JOIN asset.course_module_course_materials acmcm ON acmcm.course = acmc.id
+        LEFT JOIN asset.copy acp ON acp.id = acmcm.item
+        LEFT JOIN reporter.materialized_simple_record rmsr ON rmsr.id = acmcm.record
+    WHERE ($2 OR NOT acmc.is_archived)
+        AND ($1::TEXT IS NULL OR EXISTS (
+            SELECT 1
+            FROM asset.course_module_term_course_map acmtcm
+                JOIN asset.course_module_term acmt ON acmt.id = acmtcm.term
+            WHERE acmtcm.course = acmc.id AND acmt.name = $1
+        ))
+    ORDER BY acmc.course_number, acmc.section_number, acmc.id, 8, 9
+"#;
+
+fn export(con: &mut DatabaseConnection, ops: &CourseOptions) -> Result<(), String> {
+    let rows = con
+        .client()
+        .query(EXPORT_SQL, &[&ops.term, &ops.include_archived])
+        .map_err(|e| db_err("Error loading courses", e))?;
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+
+    writer.write_header(&[
+        "terms",
+        "course_number",
+        "section_number",
+        "course_name",
+        "owning_lib",
+        "instructors",
+        "record",
+        "title",
+        "barcode",
+        "relationship",
+    ])?;
+
+    for row in &rows {
+        writer.write_row(&[
+            Cell::from(row.get::<_, String>("terms")),
+            Cell::from(row.get::<_, String>("course_number")),
+            Cell::from(row.get::<_, String>("section_number")),
+            Cell::from(row.get::<_, String>("course_name")),
+            Cell::from(row.get::<_, String>("owning_lib")),
+            Cell::from(row.get::<_, String>("instructors")),
+            Cell::from(row.get::<_, Option<i64>>("record")),
+            Cell::from(row.get::<_, String>("title")),
+            Cell::from(row.get::<_, String>("barcode")),
+            Cell::from(row.get::<_, String>("relationship")),
+        ])?;
+    }
+
+    writer.finish()?;
+
+    info!("Exported {} course reserve rows", rows.len());
+
+    Ok(())
+}
+
+fn read_import(path: &str) -> Result<Vec<Rollover>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let (from_col, to_col) = match (col("from_term"), col("to_term")) {
+        (Some(f), Some(t)) => (f, t),
+        _ => return Err(format!("{path} requires from_term and to_term columns")),
+    };
+    let course_col = col("course_number");
+    let section_col = col("section_number");
+
+    let mut rollovers = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let field = |col: Option<usize>| {
+            col.and_then(|c| fields.get(c))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+
+        let (from_term, to_term) = match (field(Some(from_col)), field(Some(to_col))) {
+            (Some(f), Some(t)) => (f, t),
+            _ => {
+                warn!("Line {}: from_term and to_term required: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        rollovers.push(Rollover {
+            from_term,
+            to_term,
+            course_number: field(course_col),
+            section_number: field(section_col),
+        });
+    }
+
+    Ok(rollovers)
+}
+
+fn term_id(tx: &mut pg::Transaction, name: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM asset.course_module_term WHERE name = $1";
+    let rows = tx
+        .query(sql, &[&name])
+        .map_err(|e| db_err("Error loading term", e))?;
+
+    match rows.len() {
+        1 => Ok(rows[0].get("id")),
+        0 => Err(format!("No such term: {name}")),
+        _ => Err(format!("Term name {name} is used by more than one library")),
+    }
+}
+
+/// Returns the number of courses added to the new term.
+fn rollover(tx: &mut pg::Transaction, rollover: &Rollover) -> Result<u64, String> {
+    let from = term_id(tx, &rollover.from_term)?;
+    let to = term_id(tx, &rollover.to_term)?;
+
+    let sql = r#"
+        INSERT INTO asset.course_module_term_course_map (term, course)
+        SELECT $2, acmtcm.course
+        FROM asset.course_module_term_course_map acmtcm
+            JOIN asset.course_module_course acmc ON acmc.id = acmtcm.course
+        WHERE acmtcm.term = $1
+            AND NOT acmc.is_archived
+            AND ($3::TEXT IS NULL OR acmc.course_number = $3)
+            AND ($4::TEXT IS NULL OR acmc.section_number = $4)
+            AND NOT EXISTS (
+                SELECT 1 FROM asset.course_module_term_course_map existing
+                WHERE existing.term = $2 AND existing.course = acmtcm.course
+            )
+    "#;
+
+    tx.execute(
+        sql,
+        &[
+            &from,
+            &to,
+            &rollover.course_number,
+            &rollover.section_number,
+        ],
+    )
+    .map_err(|e| db_err("Error rolling over courses", e))
+}
+
+fn rollover_all(con: &mut DatabaseConnection, ops: &CourseOptions) -> Result<(), String> {
+    let mut rollovers = ops.rollovers.clone();
+
+    if let Some(path) = &ops.import_file {
+        rollovers.extend(read_import(path)?);
+    }
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut total = 0;
+
+    for r in &rollovers {
+        let count = rollover(&mut tx, r)?;
+
+        let course = match (&r.course_number, &r.section_number) {
+            (Some(c), Some(s)) => format!("{c} section {s}"),
+            (Some(c), None) => c.to_string(),
+            _ => "all courses".to_string(),
+        };
+
+        info!(
+            "{} => {}, {course}: {count} course(s) added",
+            r.from_term, r.to_term
+        );
+
+        total += count;
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("courses", total);
+
+    info!("{} {total} course(s)", ops.dry_run.past("Rolled over"));
+
+    Ok(())
+}
+
+fn run(con: &mut DatabaseConnection, ops: &CourseOptions) -> Result<(), String> {
+    con.connect()?;
+
+    if !ops.rollovers.is_empty() || ops.import_file.is_some() {
+        rollover_all(con, ops)?;
+    }
+
+    if ops.export {
+        export(con, ops)?;
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(run(&mut connection, &options))
+}
@@ -0,0 +1,555 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use serde_json::json;
+use std::process;
+
+/// First columns of indexes Evergreen relies upon for ingest and search.
+const EXPECTED_INDEXES: &[(&str, &str, &str)] = &[
+    ("biblio", "record_entry", "edit_date"),
+    ("metabib", "keyword_field_entry", "source"),
+    ("metabib", "title_field_entry", "source"),
+    ("metabib", "author_field_entry", "source"),
+    ("metabib", "subject_field_entry", "source"),
+    ("metabib", "series_field_entry", "source"),
+    ("metabib", "identifier_field_entry", "source"),
+    ("metabib", "keyword_field_entry", "index_vector"),
+    ("metabib", "title_field_entry", "index_vector"),
+    ("metabib", "author_field_entry", "index_vector"),
+    ("metabib", "subject_field_entry", "index_vector"),
+    ("metabib", "series_field_entry", "index_vector"),
+    ("metabib", "identifier_field_entry", "index_vector"),
+    ("metabib", "facet_entry", "source"),
+    ("metabib", "display_entry", "source"),
+    ("metabib", "browse_entry_def_map", "entry"),
+    ("metabib", "browse_entry_def_map", "source"),
+    ("metabib", "record_attr_vector_list", "vlist"),
+    ("metabib", "metarecord_source_map", "source"),
+    ("asset", "call_number", "record"),
+    ("asset", "copy", "call_number"),
+    ("action", "circulation", "target_copy"),
+    ("action", "hold_request", "target"),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+        }
+    }
+}
+
+struct Finding {
+    check: &'static str,
+    status: Status,
+    subject: String,
+    detail: String,
+}
+
+struct HealthOptions {
+    json: bool,
+    bloat_threshold: f64,
+    min_dead_tuples: i64,
+    seq_scan_rows: i64,
+    max_xact_minutes: i64,
+    max_lag_seconds: f64,
+    show_ok: bool,
+}
+
+fn read_options() -> (HealthOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt(
+        "",
+        "bloat-threshold",
+        "Dead Tuple Ratio Warning Level",
+        "RATIO",
+    );
+    opts.optopt(
+        "",
+        "min-dead-tuples",
+        "Ignore Tables with Fewer Dead Tuples",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "seq-scan-rows",
+        "Seq Scan Rows Read Warning Level",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "max-xact-minutes",
+        "Long Transaction Warning Level",
+        "MINUTES",
+    );
+    opts.optopt(
+        "",
+        "max-lag-seconds",
+        "Replication Lag Warning Level",
+        "SECONDS",
+    );
+    opts.optflag("", "json", "JSON Output");
+    opts.optflag("", "show-ok", "Include Passing Checks in Text Output");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let mut builder = DatabaseConnection::builder();
+    builder.set_opts(&params);
+    builder.set_application("egutil-db-health");
+
+    (
+        HealthOptions {
+            json: params.opt_present("json"),
+            show_ok: params.opt_present("show-ok"),
+            bloat_threshold: params.opt_get_default("bloat-threshold", 0.2).unwrap(),
+            min_dead_tuples: params.opt_get_default("min-dead-tuples", 10000).unwrap(),
+            seq_scan_rows: params
+                .opt_get_default("seq-scan-rows", 1_000_000_000)
+                .unwrap(),
+            max_xact_minutes: params.opt_get_default("max-xact-minutes", 60).unwrap(),
+            max_lag_seconds: params.opt_get_default("max-lag-seconds", 300.0).unwrap(),
+        },
+        builder.build(),
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin db-health -- --json
+
+Reports Evergreen-specific database health signals.  Exits with
+status 1 when any check produces a warning.
+
+Options
+
+    --bloat-threshold
+        Warn when dead tuples make up more than this fraction of a
+        metabib table.  Defaults to 0.2.
+
+    --min-dead-tuples
+        Ignore tables with fewer dead tuples than this.
+        Defaults to 10000.
+
+    --seq-scan-rows
+        Warn when sequential scans on a table have read more rows
+        than this since statistics were last reset.
+        Defaults to 1000000000.
+
+    --max-xact-minutes
+        Warn about transactions open longer than this.
+        Defaults to 60.
+
+    --max-lag-seconds
+        Warn when replication lag exceeds this.  Defaults to 300.
+
+    --json
+        Produce JSON output.
+
+    --show-ok
+        Include passing checks in text output.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn query_err(check: &str, e: postgres::Error) -> String {
+    format!("Error running {check} check: {e}")
+}
+
+fn check_bloat(
+    con: &mut DatabaseConnection,
+    ops: &HealthOptions,
+    findings: &mut Vec<Finding>,
+) -> Result<(), String> {
+    let sql = r#"
+        SELECT
+            relname,
+            n_live_tup,
+            n_dead_tup,
+            PG_SIZE_PRETTY(PG_TABLE_SIZE(relid)) AS table_size,
+            PG_SIZE_PRETTY(PG_INDEXES_SIZE(relid)) AS index_size,
+            COALESCE(last_autovacuum, last_vacuum)::TEXT AS last_vacuum
+        FROM pg_stat_user_tables
+        WHERE schemaname = 'metabib'
+        ORDER BY n_dead_tup DESC
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[])
+        .map_err(|e| query_err("bloat", e))?;
+
+    for row in rows {
+        let name: &str = row.get("relname");
+        let live: i64 = row.get("n_live_tup");
+        let dead: i64 = row.get("n_dead_tup");
+        let table_size: &str = row.get("table_size");
+        let index_size: &str = row.get("index_size");
+        let last_vacuum: Option<String> = row.get("last_vacuum");
+
+        let ratio = match live + dead {
+            0 => 0.0,
+            total => dead as f64 / total as f64,
+        };
+
+        let status = match dead >= ops.min_dead_tuples && ratio > ops.bloat_threshold {
+            true => Status::Warn,
+            false => Status::Ok,
+        };
+
+        findings.push(Finding {
+            check: "bloat",
+            status,
+            subject: format!("metabib.{name}"),
+            detail: format!(
+                "{:.1}% dead tuples ({dead} dead / {live} live); table {table_size}, indexes {index_size}; last vacuum {}",
+                ratio * 100.0,
+                last_vacuum.as_deref().unwrap_or("never")
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+fn check_seq_scans(
+    con: &mut DatabaseConnection,
+    ops: &HealthOptions,
+    findings: &mut Vec<Finding>,
+) -> Result<(), String> {
+    let sql = r#"
+        SELECT
+            schemaname || '.' || relname AS name,
+            seq_scan,
+            seq_tup_read,
+            COALESCE(idx_scan, 0) AS idx_scan
+        FROM pg_stat_user_tables
+        WHERE schemaname IN ('metabib', 'biblio', 'asset', 'action', 'actor', 'authority')
+            AND seq_tup_read > $1
+            AND seq_scan > COALESCE(idx_scan, 0)
+        ORDER BY seq_tup_read DESC
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&ops.seq_scan_rows])
+        .map_err(|e| query_err("seq scan", e))?;
+
+    if rows.is_empty() {
+        findings.push(Finding {
+            check: "seq_scan",
+            status: Status::Ok,
+            subject: "tables".to_string(),
+            detail: "No seq-scan-heavy tables".to_string(),
+        });
+    }
+
+    for row in rows {
+        let seq_scan: i64 = row.get("seq_scan");
+        let seq_tup_read: i64 = row.get("seq_tup_read");
+        let idx_scan: i64 = row.get("idx_scan");
+
+        findings.push(Finding {
+            check: "seq_scan",
+            status: Status::Warn,
+            subject: row.get("name"),
+            detail: format!(
+                "{seq_scan} seq scans reading {seq_tup_read} rows vs {idx_scan} index scans"
+            ),
+        });
+    }
+
+    // Per-query detail is only available with pg_stat_statements.
+    let sql = "SELECT 1 FROM pg_extension WHERE extname = 'pg_stat_statements'";
+    let has_pss = con
+        .client()
+        .query_opt(sql, &[])
+        .map_err(|e| query_err("seq scan", e))?
+        .is_some();
+
+    if !has_pss {
+        return Ok(());
+    }
+
+    let sql = r#"
+        SELECT
+            LEFT(REGEXP_REPLACE(query, '\s+', ' ', 'g'), 200) AS query,
+            calls,
+            ROUND((mean_exec_time)::NUMERIC, 1)::TEXT AS mean_ms,
+            shared_blks_read
+        FROM pg_stat_statements
+        WHERE query ~* '(metabib|biblio)\.'
+        ORDER BY shared_blks_read DESC
+        LIMIT 10
+    "#;
+
+    let rows = match con.client().query(sql, &[]) {
+        Ok(r) => r,
+        // Older PG versions name the column mean_time; not worth failing over.
+        Err(_) => return Ok(()),
+    };
+
+    for row in rows {
+        let calls: i64 = row.get("calls");
+        let mean_ms: &str = row.get("mean_ms");
+        let blks: i64 = row.get("shared_blks_read");
+
+        findings.push(Finding {
+            check: "heavy_query",
+            status: Status::Ok,
+            subject: row.get("query"),
+            detail: format!("{calls} calls, mean {mean_ms} ms, {blks} blocks read"),
+        });
+    }
+
+    Ok(())
+}
+
+fn check_indexes(con: &mut DatabaseConnection, findings: &mut Vec<Finding>) -> Result<(), String> {
+    let sql = r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM pg_index i
+            JOIN pg_class c ON c.oid = i.indrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum = i.indkey[0]
+            WHERE n.nspname = $1 AND c.relname = $2 AND a.attname = $3
+        ) AS found, EXISTS (
+            SELECT 1
+            FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE n.nspname = $1 AND c.relname = $2
+        ) AS table_exists
+    "#;
+
+    let stmt = con
+        .client()
+        .prepare(sql)
+        .map_err(|e| query_err("index", e))?;
+
+    for (schema, table, column) in EXPECTED_INDEXES {
+        let row = con
+            .client()
+            .query_one(&stmt, &[schema, table, column])
+            .map_err(|e| query_err("index", e))?;
+
+        let table_exists: bool = row.get("table_exists");
+        if !table_exists {
+            continue;
+        }
+
+        let found: bool = row.get("found");
+
+        findings.push(Finding {
+            check: "index",
+            status: if found { Status::Ok } else { Status::Warn },
+            subject: format!("{schema}.{table}({column})"),
+            detail: match found {
+                true => "Index present".to_string(),
+                false => "No index leads with this column".to_string(),
+            },
+        });
+    }
+
+    Ok(())
+}
+
+fn check_transactions(
+    con: &mut DatabaseConnection,
+    ops: &HealthOptions,
+    findings: &mut Vec<Finding>,
+) -> Result<(), String> {
+    let sql = r#"
+        SELECT
+            pid,
+            COALESCE(usename, '') AS usename,
+            COALESCE(application_name, '') AS application_name,
+            COALESCE(state, '') AS state,
+            (EXTRACT(EPOCH FROM NOW() - xact_start) / 60)::BIGINT AS minutes,
+            LEFT(REGEXP_REPLACE(COALESCE(query, ''), '\s+', ' ', 'g'), 200) AS query
+        FROM pg_stat_activity
+        WHERE xact_start IS NOT NULL
+            AND pid <> PG_BACKEND_PID()
+            AND xact_start < NOW() - ($1::TEXT || ' minutes')::INTERVAL
+        ORDER BY xact_start
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&ops.max_xact_minutes.to_string()])
+        .map_err(|e| query_err("transaction", e))?;
+
+    if rows.is_empty() {
+        findings.push(Finding {
+            check: "long_transaction",
+            status: Status::Ok,
+            subject: "transactions".to_string(),
+            detail: format!(
+                "No transactions open longer than {} minutes",
+                ops.max_xact_minutes
+            ),
+        });
+    }
+
+    for row in rows {
+        let pid: i32 = row.get("pid");
+        let user: &str = row.get("usename");
+        let app: &str = row.get("application_name");
+        let state: &str = row.get("state");
+        let minutes: i64 = row.get("minutes");
+        let query: &str = row.get("query");
+
+        findings.push(Finding {
+            check: "long_transaction",
+            status: Status::Warn,
+            subject: format!("pid {pid}"),
+            detail: format!("{minutes} minutes; user={user} app={app} state={state}; {query}"),
+        });
+    }
+
+    Ok(())
+}
+
+fn check_replication(
+    con: &mut DatabaseConnection,
+    ops: &HealthOptions,
+    findings: &mut Vec<Finding>,
+) -> Result<(), String> {
+    let sql = "SELECT PG_IS_IN_RECOVERY() AS replica";
+    let replica: bool = con
+        .client()
+        .query_one(sql, &[])
+        .map_err(|e| query_err("replication", e))?
+        .get("replica");
+
+    let push_lag = |findings: &mut Vec<Finding>, subject: String, lag: Option<f64>| {
+        let (status, detail) = match lag {
+            Some(l) if l > ops.max_lag_seconds => (Status::Warn, format!("{l:.1} seconds behind")),
+            Some(l) => (Status::Ok, format!("{l:.1} seconds behind")),
+            None => (Status::Ok, "No replay activity reported".to_string()),
+        };
+
+        findings.push(Finding {
+            check: "replication_lag",
+            status,
+            subject,
+            detail,
+        });
+    };
+
+    if replica {
+        let sql = r#"
+            SELECT EXTRACT(EPOCH FROM NOW() - PG_LAST_XACT_REPLAY_TIMESTAMP())::FLOAT8 AS lag
+        "#;
+        let lag: Option<f64> = con
+            .client()
+            .query_one(sql, &[])
+            .map_err(|e| query_err("replication", e))?
+            .get("lag");
+
+        push_lag(findings, "this replica".to_string(), lag);
+        return Ok(());
+    }
+
+    let sql = r#"
+        SELECT
+            COALESCE(application_name, '') || '@' || COALESCE(client_addr::TEXT, 'local') AS name,
+            EXTRACT(EPOCH FROM replay_lag)::FLOAT8 AS lag
+        FROM pg_stat_replication
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[])
+        .map_err(|e| query_err("replication", e))?;
+
+    for row in rows {
+        push_lag(findings, row.get("name"), row.get("lag"));
+    }
+
+    Ok(())
+}
+
+fn report(ops: &HealthOptions, findings: &[Finding]) -> bool {
+    let warnings = findings.iter().filter(|f| f.status == Status::Warn).count();
+
+    if ops.json {
+        let list: Vec<serde_json::Value> = findings
+            .iter()
+            .map(|f| {
+                json!({
+                    "check": f.check,
+                    "status": f.status.as_str(),
+                    "subject": f.subject,
+                    "detail": f.detail,
+                })
+            })
+            .collect();
+
+        let doc = json!({
+            "status": if warnings > 0 { "warn" } else { "ok" },
+            "warnings": warnings,
+            "findings": list,
+        });
+
+        println!("{}", serde_json::to_string_pretty(&doc).unwrap());
+    } else {
+        for f in findings {
+            if f.status == Status::Ok && !ops.show_ok {
+                continue;
+            }
+            println!(
+                "[{}] {:<16} {}: {}",
+                f.status.as_str().to_uppercase(),
+                f.check,
+                f.subject,
+                f.detail
+            );
+        }
+        println!("{} check(s), {} warning(s)", findings.len(), warnings);
+    }
+
+    warnings > 0
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut con) = read_options();
+
+    con.connect()?;
+
+    let mut findings = Vec::new();
+
+    check_bloat(&mut con, &options, &mut findings)?;
+    check_seq_scans(&mut con, &options, &mut findings)?;
+    check_indexes(&mut con, &mut findings)?;
+    check_transactions(&mut con, &options, &mut findings)?;
+    check_replication(&mut con, &options, &mut findings)?;
+
+    con.disconnect();
+
+    if report(&options, &findings) {
+        process::exit(1);
+    }
+
+    Ok(())
+}
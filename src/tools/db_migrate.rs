@@ -0,0 +1,354 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use log::{info, warn};
+use postgres as pg;
+use std::collections::HashMap;
+use std::fs;
+
+/// Migrations starting with this line run outside a transaction, e.g.
+/// for CREATE INDEX CONCURRENTLY.
+const NO_TRANSACTION_MARKER: &str = "-- egutil:no-transaction";
+
+struct MigrateOptions {
+    dir: String,
+    target: Option<u64>,
+    status: bool,
+    allow_modified: bool,
+    dry_run: DryRun,
+}
+
+struct Migration {
+    version: u64,
+    name: String,
+    sql: String,
+    checksum: String,
+    transactional: bool,
+}
+
+struct Applied {
+    name: String,
+    checksum: String,
+}
+
+fn read_options() -> (MigrateOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "dir", "Migration Files Directory", "DIR");
+    opts.optopt("", "target", "Apply Migrations Up To Version", "VERSION");
+    opts.optflag("", "status", "Show Migration Status");
+    opts.optflag("", "allow-modified", "Warn on Changed Applied Migrations");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        MigrateOptions {
+            dir: cli::required(&params, "dir"),
+            target: params.opt_get("target").unwrap(),
+            status: params.opt_present("status"),
+            allow_modified: params.opt_present("allow-modified"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin db-migrate -- --dir local-migrations --status
+    cargo run --bin db-migrate -- --dir local-migrations --dry-run
+
+Applies local schema customizations from a directory of SQL files.
+
+Files are named VERSION-description.sql, e.g. 0001-circ-index.sql,
+and are applied in version order.  Applied migrations are tracked in
+egutil.schema_migration with an MD5 checksum of their contents.
+Applying stops if an applied migration file has changed since it was
+applied, unless --allow-modified is used.
+
+Each migration runs in its own transaction along with its tracking
+row.  Files starting with the line
+
+    -- egutil:no-transaction
+
+run outside of a transaction, e.g. for CREATE INDEX CONCURRENTLY,
+and end a --dry-run.
+
+Options
+
+    --dir
+        Directory of migration files.  Required.
+
+    --target
+        Only apply migrations up to and including this version.
+
+    --status
+        List migrations as applied, pending or modified and exit.
+
+    --allow-modified
+        Warn instead of stopping when applied migrations have changed.
+
+    --dry-run
+        Run pending migrations in one transaction, then roll back.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn init_table(con: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.schema_migration (
+            version         BIGINT PRIMARY KEY,
+            name            TEXT NOT NULL,
+            checksum        TEXT NOT NULL,
+            applied_time    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            applied_by      TEXT NOT NULL DEFAULT CURRENT_USER
+        );
+    "#;
+
+    con.client()
+        .batch_execute(sql)
+        .map_err(|e| db_err("Cannot create egutil.schema_migration", e))
+}
+
+fn read_migrations(con: &mut DatabaseConnection, dir: &str) -> Result<Vec<Migration>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Cannot read {dir}: {e}")),
+    };
+
+    let mut migrations: Vec<Migration> = Vec::new();
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if !name.ends_with(".sql") {
+            continue;
+        }
+
+        let digits: String = name.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let version: u64 = match digits.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Skipping {name}: no leading version number");
+                continue;
+            }
+        };
+
+        if let Some(other) = migrations.iter().find(|m| m.version == version) {
+            return Err(format!("{name} and {} share version {version}", other.name));
+        }
+
+        let path = entry.path();
+        let sql = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot read {}: {e}", path.display())),
+        };
+
+        let checksum: String = con
+            .client()
+            .query_one("SELECT MD5($1) AS sum", &[&sql])
+            .map_err(|e| db_err("Error computing checksum", e))?
+            .get("sum");
+
+        migrations.push(Migration {
+            version,
+            name,
+            transactional: !sql.trim_start().starts_with(NO_TRANSACTION_MARKER),
+            sql,
+            checksum,
+        });
+    }
+
+    migrations.sort_by_key(|m| m.version);
+
+    Ok(migrations)
+}
+
+fn applied_migrations(con: &mut DatabaseConnection) -> Result<HashMap<u64, Applied>, String> {
+    let rows = con
+        .client()
+        .query(
+            "SELECT version, name, checksum FROM egutil.schema_migration",
+            &[],
+        )
+        .map_err(|e| db_err("Error loading applied migrations", e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            (
+                row.get::<_, i64>("version") as u64,
+                Applied {
+                    name: row.get("name"),
+                    checksum: row.get("checksum"),
+                },
+            )
+        })
+        .collect())
+}
+
+fn print_status(migrations: &[Migration], applied: &HashMap<u64, Applied>) {
+    for m in migrations {
+        let status = match applied.get(&m.version) {
+            Some(a) if a.checksum != m.checksum => "modified",
+            Some(_) => "applied",
+            None => "pending",
+        };
+        println!("{:<10} {:>6} {}", status, m.version, m.name);
+    }
+
+    for (version, a) in applied {
+        if !migrations.iter().any(|m| m.version == *version) {
+            println!("{:<10} {:>6} {}", "missing", version, a.name);
+        }
+    }
+}
+
+fn apply<C: pg::GenericClient>(client: &mut C, m: &Migration) -> Result<(), String> {
+    let insert = r#"
+        INSERT INTO egutil.schema_migration (version, name, checksum)
+        VALUES ($1, $2, $3)
+    "#;
+
+    let version = m.version as i64;
+
+    client
+        .batch_execute(&m.sql)
+        .map_err(|e| db_err(&format!("Error applying {}", m.name), e))?;
+
+    client
+        .execute(insert, &[&version, &m.name, &m.checksum])
+        .map_err(|e| db_err(&format!("Error recording {}", m.name), e))?;
+
+    info!("Applied {}", m.name);
+
+    Ok(())
+}
+
+/// Run pending migrations in a single transaction, so later
+/// migrations see earlier ones, then roll back.
+fn dry_run(con: &mut DatabaseConnection, pending: &[&Migration]) -> Result<usize, String> {
+    let mut tx = con
+        .client()
+        .transaction()
+        .map_err(|e| db_err("Cannot start transaction", e))?;
+
+    let mut count = 0;
+
+    for m in pending {
+        if !m.transactional {
+            warn!("{} cannot run in a transaction; stopping here", m.name);
+            break;
+        }
+        apply(&mut tx, m)?;
+        count += 1;
+    }
+
+    // Dropping the transaction rolls it back.
+    drop(tx);
+
+    Ok(count)
+}
+
+fn migrate(con: &mut DatabaseConnection, ops: &MigrateOptions) -> Result<(), String> {
+    con.connect()?;
+
+    init_table(con)?;
+
+    let migrations = read_migrations(con, &ops.dir)?;
+    let applied = applied_migrations(con)?;
+
+    if ops.status {
+        print_status(&migrations, &applied);
+        con.disconnect();
+        return Ok(());
+    }
+
+    for m in &migrations {
+        if let Some(a) = applied.get(&m.version) {
+            if a.checksum != m.checksum {
+                if ops.allow_modified {
+                    warn!("{} was modified after it was applied", m.name);
+                } else {
+                    return Err(format!(
+                        "{} was modified after it was applied; see --allow-modified",
+                        m.name
+                    ));
+                }
+            }
+        }
+    }
+
+    let pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|m| !applied.contains_key(&m.version))
+        .filter(|m| ops.target.is_none_or(|t| m.version <= t))
+        .collect();
+
+    let count = match ops.dry_run.enabled() {
+        true => dry_run(con, &pending)?,
+        false => {
+            for m in &pending {
+                if m.transactional {
+                    let mut tx = con
+                        .client()
+                        .transaction()
+                        .map_err(|e| db_err("Cannot start transaction", e))?;
+                    apply(&mut tx, m)?;
+                    tx.commit()
+                        .map_err(|e| db_err(&format!("Error committing {}", m.name), e))?;
+                } else {
+                    apply(con.client(), m)?;
+                }
+            }
+            pending.len()
+        }
+    };
+
+    con.disconnect();
+
+    audit::count("migrations", count as u64);
+
+    println!(
+        "{count} migrations {}",
+        if ops.dry_run.enabled() {
+            "ran and rolled back"
+        } else {
+            "applied"
+        }
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(migrate(&mut connection, &options))
+}
@@ -0,0 +1,780 @@
+use crate::cli;
+use crate::date::{self, Timestamp};
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::marc::{self, FieldSpec, LocatedUri};
+use log::{info, warn};
+use marcutil::Record;
+use postgres as pg;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+/// Document fields and the record content they hold, used unless
+/// --fields-file says otherwise.  Lists hold every match; the rest
+/// hold the first.
+const DEFAULT_FIELDS: &[(&str, &str, bool)] = &[
+    ("title", "245abnp", false),
+    ("author", "100abcdq", false),
+    ("added_authors", "700abcdq", true),
+    ("edition", "250a", false),
+    ("publisher", "264b", false),
+    ("pubdate", "008/07-10", false),
+    ("language", "008/35-37", false),
+    ("series", "490a", true),
+    ("subjects", "6XXabvxyz", true),
+    ("genres", "655a", true),
+    ("summary", "520a", false),
+    ("format", "LDR/06", false),
+];
+
+/// Copy statuses counted as available.  Available and Reshelving.
+const DEFAULT_AVAILABLE: &[i32] = &[0, 7];
+
+/// Records per holdings query and document batch.
+const BATCH_SIZE: usize = 500;
+
+struct FeedOptions {
+    min_id: i64,
+    max_id: i64,
+    query_file: Option<String>,
+    since: Option<String>,
+    org_unit: Option<String>,
+    fields_file: Option<String>,
+    available_status: Vec<i32>,
+    out_file: Option<String>,
+    /// Document hashes are stored under this name in egutil.feed_hash.
+    feed_name: Option<String>,
+    /// Skip documents whose hash matches the feed's stored one.
+    skip_unchanged: bool,
+    dry_run: DryRun,
+}
+
+/// A document field and its source.
+struct DocField {
+    name: String,
+    specs: Vec<FieldSpec>,
+    list: bool,
+}
+
+/// Visible copies of one call number at one library and location.
+struct Holding {
+    library: String,
+    location: String,
+    call_number: String,
+    copies: i64,
+    available: i64,
+}
+
+#[derive(Default)]
+struct Summary {
+    documents: usize,
+    deleted: usize,
+    unparsed: usize,
+    unchanged: usize,
+}
+
+fn read_options() -> (FeedOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+    opts.optopt("", "since", "Changed Since Time or Interval", "SINCE");
+    opts.optopt("", "org-unit", "Limit Holdings to Org Unit", "SHORTNAME");
+    opts.optopt("", "fields-file", "Document Fields JSON File", "FILE");
+    opts.optmulti("", "available-status", "Available Copy Status IDs", "IDS");
+    opts.optopt("", "out-file", "Output File", "FILE");
+    opts.optopt("", "feed-name", "Store Document Hashes for Feed", "NAME");
+    opts.optflag("", "skip-unchanged", "Skip Documents Unchanged in Feed");
+    cli::append_dry_run(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let mut available_status: Vec<i32> = params
+        .opt_strs("available-status")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().expect("Invalid --available-status"))
+        .collect();
+
+    if available_status.is_empty() {
+        available_status = DEFAULT_AVAILABLE.to_vec();
+    }
+
+    let feed_name = params.opt_str("feed-name");
+    let skip_unchanged = params.opt_present("skip-unchanged");
+
+    if skip_unchanged && feed_name.is_none() {
+        eprintln!("--skip-unchanged requires --feed-name");
+        process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        FeedOptions {
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            query_file: params.opt_get("query-file").unwrap(),
+            since: params.opt_str("since"),
+            org_unit: params.opt_str("org-unit"),
+            fields_file: params.opt_str("fields-file"),
+            available_status,
+            out_file: params.opt_str("out-file"),
+            feed_name,
+            skip_unchanged,
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin discovery-feed -- --out-file full.jsonl
+    cargo run --bin discovery-feed -- --since "1 day" --org-unit BR1 --out-file delta.jsonl
+    cargo run --bin discovery-feed -- --feed-name vufind --skip-unchanged --out-file delta.jsonl
+
+Writes one JSON document per bib record, one per line, for loading
+into Solr, Elasticsearch and other discovery layer indexes.
+
+Each document holds the record ID, title and other metadata fields,
+ISBNs, OCLC numbers, located URIs, and a holdings summary of visible
+copies per library, location and call number, with counts of copies
+available now:
+
+    {{"id": 123, "deleted": false, "title": "...", "isbn": ["..."],
+     "holdings": [{{"library": "BR1", "location": "Stacks",
+       "call_number": "FIC SMITH", "copies": 2, "available": 1}}],
+     "libraries": ["BR1"], "copies": 2, "available": 1, ...}}
+
+With --since, only records whose bib, call numbers or copies changed
+since then are written, including copies changing status, so
+availability stays current.  Records deleted since then are written
+as {{"id": 123, "deleted": true}} so they can be dropped from the
+index.  The run's start time is logged for use as the next --since.
+
+Content hashes
+
+    edit_date changes with edits which leave a document as it was,
+    e.g. saving a record unchanged, so --since deltas run large.
+
+    With --feed-name, an MD5 hash of each document written, less its
+    edit_date, is stored in egutil.feed_hash under the feed's name,
+    once the document's batch is written.  --skip-unchanged then
+    leaves out documents whose hash matches, so only real changes
+    are written, with or without --since.  Deleted records with a
+    stored hash are written as deletions once, and their hashes are
+    then removed.
+
+    Give each index its own feed name.  A run with --feed-name and
+    without --skip-unchanged writes every document and stores fresh
+    hashes, e.g. after an index is rebuilt.
+
+Document fields
+
+    --fields-file replaces the default metadata fields with a JSON
+    object of field names and field specs, e.g.
+
+        {{"title": "245ab", "subjects": ["650a", "651a"]}}
+
+    A spec string holds the first matching value and a list of specs
+    holds every match.  Specs are as in marc-report, e.g. "245$a",
+    "6XX$a$x" or "008/35-37".
+
+Options
+
+    --min-id
+    --max-id
+        Only export records with IDs in this range.
+
+    --query-file
+        Path to a file containing an SQL query.  The query must
+        produce rows with an "id" column of bib record IDs.
+        Overrides --min-id, --max-id and --since.
+
+    --since
+        Only export records changed since this time, e.g.
+        "2026-01-01T00:00:00-05:00", or this long ago, e.g. "1 day".
+
+    --org-unit
+        Only include holdings at this org unit and its descendants.
+        Records are exported with or without holdings.
+
+    --fields-file
+        JSON file of document fields.  See above.
+
+    --available-status
+        Copy status IDs counted as available.  Repeatable or comma
+        separated.  Defaults to 0 and 7, Available and Reshelving.
+
+    --out-file
+        Write documents to this file.  Otherwise, writes to STDOUT.
+
+    --feed-name
+        Store document hashes in egutil.feed_hash under this name.
+
+    --skip-unchanged
+        Leave out documents unchanged since they were last written
+        for --feed-name.
+
+    --dry-run
+        Write the documents, then roll back the stored hashes, so the
+        next run writes them again.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn load_fields(ops: &FeedOptions) -> Result<Vec<DocField>, String> {
+    let path = match ops.fields_file {
+        Some(ref p) => p,
+        None => {
+            return DEFAULT_FIELDS
+                .iter()
+                .map(|(name, spec, list)| {
+                    Ok(DocField {
+                        name: name.to_string(),
+                        specs: vec![FieldSpec::parse(spec)?],
+                        list: *list,
+                    })
+                })
+                .collect();
+        }
+    };
+
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let value: serde_json::Value = match serde_json::from_str(&text) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let object = match value.as_object() {
+        Some(o) => o,
+        None => return Err(format!("{path} must hold a JSON object")),
+    };
+
+    let mut fields = Vec::new();
+
+    for (name, spec) in object {
+        let (specs, list) = match spec {
+            serde_json::Value::String(s) => (vec![s.as_str()], false),
+            serde_json::Value::Array(a) => (a.iter().filter_map(|s| s.as_str()).collect(), true),
+            _ => return Err(format!("Invalid spec for field {name} in {path}")),
+        };
+
+        fields.push(DocField {
+            name: name.to_string(),
+            specs: specs
+                .into_iter()
+                .map(FieldSpec::parse)
+                .collect::<Result<_, _>>()?,
+            list,
+        });
+    }
+
+    Ok(fields)
+}
+
+fn org_id(con: &mut DatabaseConnection, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    match con.client().query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error looking up org unit", e)),
+    }
+}
+
+/// IDs of the records to export, in ID order.
+fn record_ids(
+    con: &mut DatabaseConnection,
+    ops: &FeedOptions,
+    since: Option<&str>,
+) -> Result<Vec<i64>, String> {
+    if let Some(ref fname) = ops.query_file {
+        let query = match fs::read_to_string(fname) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot read {fname}: {e}")),
+        };
+
+        let sql = format!("SELECT DISTINCT q.id FROM ({query}) q ORDER BY q.id");
+
+        return match con.client().query(sql.as_str(), &[]) {
+            Ok(rows) => Ok(rows.iter().map(|r| r.get("id")).collect()),
+            Err(e) => Err(db_err("Error selecting records", e)),
+        };
+    }
+
+    // Deleted records are only of interest to an index which may
+    // still hold them: those deleted since --since, or those with a
+    // hash stored for the feed.
+    let hashed = match ops.feed_name {
+        Some(_) => "OR bre.id IN (SELECT record FROM egutil.feed_hash WHERE feed = $2)",
+        None => "",
+    };
+
+    let mut sql = format!(
+        r#"
+        SELECT bre.id
+        FROM biblio.record_entry bre
+        WHERE bre.id > 0
+            AND ($1::TEXT IS NOT NULL OR NOT bre.deleted {hashed})
+            AND (
+                $1::TEXT IS NULL
+                OR bre.edit_date >= $1::TEXT::TIMESTAMPTZ
+                OR bre.id IN (
+                    SELECT acn.record
+                    FROM asset.call_number acn
+                    WHERE acn.edit_date >= $1::TEXT::TIMESTAMPTZ
+                )
+                OR bre.id IN (
+                    SELECT acn.record
+                    FROM asset.copy acp
+                    JOIN asset.call_number acn ON acn.id = acp.call_number
+                    WHERE acp.edit_date >= $1::TEXT::TIMESTAMPTZ
+                        OR acp.status_changed_time >= $1::TEXT::TIMESTAMPTZ
+                )
+            )
+    "#,
+    );
+
+    if ops.min_id > -1 {
+        sql += &format!(" AND bre.id >= {}", ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        sql += &format!(" AND bre.id <= {}", ops.max_id);
+    }
+
+    sql += " ORDER BY bre.id";
+
+    let mut params: Vec<&(dyn pg::types::ToSql + Sync)> = vec![&since];
+    if let Some(ref feed) = ops.feed_name {
+        params.push(feed);
+    }
+
+    match con.client().query(sql.as_str(), &params) {
+        Ok(rows) => Ok(rows.iter().map(|r| r.get("id")).collect()),
+        Err(e) => Err(db_err("Error selecting records", e)),
+    }
+}
+
+/// Visible holdings of each record in the batch.
+fn load_holdings(
+    con: &mut DatabaseConnection,
+    ops: &FeedOptions,
+    org_id: Option<i32>,
+    ids: &[i64],
+) -> Result<HashMap<i64, Vec<Holding>>, String> {
+    let sql = r#"
+        SELECT
+            acn.record,
+            aou.shortname AS library,
+            acpl.name AS location,
+            TRIM(CONCAT_WS(' ', acnp.label, acn.label, acns.label)) AS call_number,
+            COUNT(*) AS copies,
+            COUNT(*) FILTER (WHERE acp.status = ANY($3)) AS available
+        FROM asset.copy acp
+        JOIN asset.call_number acn ON acn.id = acp.call_number
+        JOIN asset.call_number_prefix acnp ON acnp.id = acn.prefix
+        JOIN asset.call_number_suffix acns ON acns.id = acn.suffix
+        JOIN actor.org_unit aou ON aou.id = acp.circ_lib
+        JOIN asset.copy_location acpl ON acpl.id = acp.location
+        JOIN config.copy_status ccs ON ccs.id = acp.status
+        WHERE acn.record = ANY($1)
+            AND NOT acp.deleted
+            AND NOT acn.deleted
+            AND NOT acpl.deleted
+            AND acp.opac_visible
+            AND acpl.opac_visible
+            AND ccs.opac_visible
+            AND ($2::INT IS NULL
+                OR acp.circ_lib IN (SELECT id FROM actor.org_unit_descendants($2::INT)))
+        GROUP BY 1, 2, 3, 4
+        ORDER BY 1, 2, 3, 4
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&ids, &org_id, &ops.available_status])
+        .map_err(|e| db_err("Error selecting holdings", e))?;
+
+    let mut holdings: HashMap<i64, Vec<Holding>> = HashMap::new();
+
+    for row in rows {
+        holdings
+            .entry(row.get("record"))
+            .or_default()
+            .push(Holding {
+                library: row.get("library"),
+                location: row.get("location"),
+                call_number: row.get("call_number"),
+                copies: row.get("copies"),
+                available: row.get("available"),
+            });
+    }
+
+    Ok(holdings)
+}
+
+fn document(
+    fields: &[DocField],
+    id: i64,
+    edit_date: &str,
+    record: &Record,
+    holdings: &[Holding],
+) -> serde_json::Value {
+    let mut doc = json!({
+        "id": id,
+        "deleted": false,
+        "edit_date": edit_date,
+    });
+
+    for field in fields {
+        let values: Vec<String> = field.specs.iter().flat_map(|s| s.extract(record)).collect();
+
+        doc[field.name.as_str()] = match field.list {
+            true => json!(values),
+            false => json!(values.first()),
+        };
+    }
+
+    doc["isbn"] = json!(marc::isbns(record));
+    doc["oclc"] = json!(marc::oclc_numbers(record));
+
+    let uris: Vec<serde_json::Value> = record
+        .get_fields("856")
+        .into_iter()
+        .filter_map(LocatedUri::from_field)
+        .map(|u| {
+            json!({
+                "url": u.url,
+                "label": u.label,
+                "owner": u.owner,
+            })
+        })
+        .collect();
+
+    doc["urls"] = json!(uris);
+
+    let mut libraries: Vec<&str> = Vec::new();
+    for holding in holdings {
+        if !libraries.contains(&holding.library.as_str()) {
+            libraries.push(&holding.library);
+        }
+    }
+
+    doc["holdings"] = holdings
+        .iter()
+        .map(|h| {
+            json!({
+                "library": h.library,
+                "location": h.location,
+                "call_number": h.call_number,
+                "copies": h.copies,
+                "available": h.available,
+            })
+        })
+        .collect();
+
+    doc["libraries"] = json!(libraries);
+    doc["copies"] = json!(holdings.iter().map(|h| h.copies).sum::<i64>());
+    doc["available"] = json!(holdings.iter().map(|h| h.available).sum::<i64>());
+
+    doc
+}
+
+/// Create egutil.feed_hash if needed.
+fn create_hash_table(con: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.feed_hash (
+            feed        TEXT NOT NULL,
+            record      BIGINT NOT NULL,
+            doc_hash    TEXT NOT NULL,
+            export_time TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            PRIMARY KEY (feed, record)
+        );
+    "#;
+
+    match con.client().batch_execute(sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(db_err("Cannot create egutil.feed_hash", e)),
+    }
+}
+
+/// The documents to write, with their hashes: those whose hash
+/// differs from the feed's stored one, or all with no --skip-unchanged.
+///
+/// edit_date is left out of the hash, as it changes with edits which
+/// leave the document as it was.
+fn changed_documents(
+    con: &mut DatabaseConnection,
+    ops: &FeedOptions,
+    feed: &str,
+    docs: Vec<(i64, serde_json::Value)>,
+) -> Result<Vec<(i64, serde_json::Value, String)>, String> {
+    let ids: Vec<i64> = docs.iter().map(|(id, _)| *id).collect();
+    let contents: Vec<String> = docs
+        .iter()
+        .map(|(_, doc)| {
+            let mut doc = doc.clone();
+            if let Some(map) = doc.as_object_mut() {
+                map.remove("edit_date");
+            }
+            doc.to_string()
+        })
+        .collect();
+
+    let sql = r#"
+        SELECT doc.record, MD5(doc.content) AS doc_hash
+        FROM UNNEST($2::BIGINT[], $3::TEXT[]) AS doc (record, content)
+        LEFT JOIN egutil.feed_hash hash
+            ON hash.feed = $1 AND hash.record = doc.record
+        WHERE NOT $4 OR hash.doc_hash IS DISTINCT FROM MD5(doc.content)
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&feed, &ids, &contents, &ops.skip_unchanged])
+        .map_err(|e| db_err("Error comparing document hashes", e))?;
+
+    let mut hashes: HashMap<i64, String> = rows
+        .iter()
+        .map(|row| (row.get("record"), row.get("doc_hash")))
+        .collect();
+
+    Ok(docs
+        .into_iter()
+        .filter_map(|(id, doc)| hashes.remove(&id).map(|hash| (id, doc, hash)))
+        .collect())
+}
+
+/// Store the hashes of documents written for the feed.
+fn store_hashes(
+    con: &mut DatabaseConnection,
+    feed: &str,
+    ids: &[i64],
+    hashes: &[String],
+) -> Result<(), String> {
+    let sql = r#"
+        INSERT INTO egutil.feed_hash (feed, record, doc_hash)
+        SELECT $1, doc.record, doc.doc_hash
+        FROM UNNEST($2::BIGINT[], $3::TEXT[]) AS doc (record, doc_hash)
+        ON CONFLICT (feed, record) DO UPDATE
+            SET doc_hash = EXCLUDED.doc_hash, export_time = NOW()
+    "#;
+
+    match con.client().execute(sql, &[&feed, &ids, &hashes]) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(db_err("Error storing document hashes", e)),
+    }
+}
+
+/// Remove the hashes of records written as deletions, so they are not
+/// selected again for the feed.
+fn remove_hashes(con: &mut DatabaseConnection, feed: &str, ids: &[i64]) -> Result<(), String> {
+    let sql = "DELETE FROM egutil.feed_hash WHERE feed = $1 AND record = ANY($2)";
+
+    match con.client().execute(sql, &[&feed, &ids]) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(db_err("Error removing document hashes", e)),
+    }
+}
+
+fn write_line(writer: &mut dyn Write, doc: &serde_json::Value) -> Result<(), String> {
+    match writeln!(writer, "{doc}") {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing output: {e}")),
+    }
+}
+
+fn export(con: &mut DatabaseConnection, ops: &FeedOptions) -> Result<(), String> {
+    let fields = load_fields(ops)?;
+
+    // Taken before selecting, so changes made during the export are
+    // picked up by the next one.
+    let started = Timestamp::now();
+
+    let since = match ops.since {
+        Some(ref s) => Some(date::parse_since(s)?.to_iso8601()),
+        None => None,
+    };
+
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match &ops.out_file {
+        Some(f) => match fs::File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    });
+
+    con.connect()?;
+
+    if ops.feed_name.is_some() {
+        create_hash_table(con)?;
+    }
+
+    ops.dry_run.begin(con.client())?;
+
+    let org_id = match ops.org_unit {
+        Some(ref s) => Some(org_id(con, s)?),
+        None => None,
+    };
+
+    let ids = record_ids(con, ops, since.as_deref())?;
+    info!("Exporting {} records", ids.len());
+
+    let sql = r#"
+        SELECT
+            id,
+            deleted,
+            marc,
+            TO_CHAR(edit_date AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') AS edit_date
+        FROM biblio.record_entry
+        WHERE id = ANY($1)
+        ORDER BY id
+    "#;
+
+    let mut summary = Summary::default();
+
+    for batch in ids.chunks(BATCH_SIZE) {
+        let rows = con
+            .client()
+            .query(sql, &[&batch])
+            .map_err(|e| db_err("Error selecting records", e))?;
+
+        let holdings = load_holdings(con, ops, org_id, batch)?;
+
+        let mut docs = Vec::new();
+
+        for row in rows {
+            let id: i64 = row.get("id");
+
+            if row.get::<_, bool>("deleted") {
+                docs.push((id, json!({"id": id, "deleted": true})));
+                continue;
+            }
+
+            let record = match Record::from_xml(row.get("marc")).next() {
+                Some(r) => r,
+                None => {
+                    warn!("Record {id} cannot be parsed; skipping");
+                    summary.unparsed += 1;
+                    continue;
+                }
+            };
+
+            let doc = document(
+                &fields,
+                id,
+                row.get("edit_date"),
+                &record,
+                holdings.get(&id).map(|h| h.as_slice()).unwrap_or(&[]),
+            );
+
+            docs.push((id, doc));
+        }
+
+        let count = docs.len();
+
+        let docs = match ops.feed_name {
+            Some(ref feed) => changed_documents(con, ops, feed, docs)?,
+            None => docs
+                .into_iter()
+                .map(|(id, doc)| (id, doc, String::new()))
+                .collect(),
+        };
+
+        summary.unchanged += count - docs.len();
+
+        for (_, doc, _) in &docs {
+            match doc["deleted"].as_bool() {
+                Some(true) => summary.deleted += 1,
+                _ => summary.documents += 1,
+            }
+            write_line(&mut writer, doc)?;
+        }
+
+        // Hashes are stored only once their documents are written
+        // out, so a failed run does not mark unwritten ones as sent.
+        if let Some(ref feed) = ops.feed_name {
+            if let Err(e) = writer.flush() {
+                return Err(format!("Error writing output: {e}"));
+            }
+
+            let mut ids = Vec::new();
+            let mut hashes = Vec::new();
+            let mut deleted = Vec::new();
+
+            for (id, doc, hash) in docs {
+                match doc["deleted"].as_bool() {
+                    Some(true) => deleted.push(id),
+                    _ => {
+                        ids.push(id);
+                        hashes.push(hash);
+                    }
+                }
+            }
+
+            store_hashes(con, feed, &ids, &hashes)?;
+            remove_hashes(con, feed, &deleted)?;
+        }
+    }
+
+    if let Err(e) = writer.flush() {
+        return Err(format!("Error writing output: {e}"));
+    }
+
+    ops.dry_run.end(con.client())?;
+
+    con.disconnect();
+
+    info!(
+        "Wrote {} documents and {} deletions; {} records could not be parsed",
+        summary.documents, summary.deleted, summary.unparsed
+    );
+
+    if ops.skip_unchanged {
+        info!("Skipped {} unchanged documents", summary.unchanged);
+    }
+
+    info!(
+        "Export started at {}; use it as the next --since",
+        started.to_iso8601()
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    export(&mut connection, &options)
+}
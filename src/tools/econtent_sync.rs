@@ -0,0 +1,447 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::marc;
+use log::{debug, info, warn};
+use marcutil::Record;
+use postgres as pg;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::{fs, io};
+
+struct SyncOptions {
+    vendor: String,
+    id_tag: String,
+    id_subfield: Option<String>,
+    bib_source: i32,
+    adds: Vec<String>,
+    deletes: Vec<String>,
+    delete_ids: Vec<String>,
+    binary: bool,
+    staff: i32,
+    no_overlay: bool,
+    out_file: Option<String>,
+    dry_run: DryRun,
+}
+
+#[derive(Default)]
+struct FeedSummary {
+    added: usize,
+    overlaid: usize,
+    deleted: usize,
+    not_found: usize,
+    skipped: usize,
+}
+
+fn read_options() -> (SyncOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "vendor", "E-Content Vendor", "overdrive|cloudlibrary");
+    opts.optopt("", "id-field", "Vendor ID Field", "TAG[SUBFIELD]");
+    opts.optopt("", "bib-source", "Bib Source ID", "SOURCE_ID");
+    opts.optmulti("", "adds", "MARC File of Added Records", "FILE");
+    opts.optmulti("", "deletes", "MARC File of Deleted Records", "FILE");
+    opts.optmulti("", "delete-ids", "File of Deleted Vendor IDs", "FILE");
+    opts.optopt("", "staff", "Staff User ID for Record Editor", "USER_ID");
+    opts.optopt("", "out-file", "Summary Report File", "FILE");
+    opts.optflag("", "binary", "MARC Files are Binary");
+    opts.optflag("", "no-overlay", "Skip Added Records Already Loaded");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let vendor = params
+        .opt_get_default("vendor", "overdrive".to_string())
+        .unwrap();
+
+    let id_field = match params.opt_str("id-field") {
+        Some(f) => f,
+        None => match vendor.as_str() {
+            "overdrive" => "037a".to_string(),
+            "cloudlibrary" => "001".to_string(),
+            _ => panic!("--id-field required for vendor {vendor}"),
+        },
+    };
+
+    if id_field.len() < 3 {
+        panic!("Invalid --id-field: {id_field}");
+    }
+
+    let (id_tag, id_subfield) = id_field.split_at(3);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        SyncOptions {
+            vendor,
+            id_tag: id_tag.to_string(),
+            id_subfield: id_subfield.get(..1).map(|s| s.to_string()),
+            bib_source: cli::required(&params, "bib-source"),
+            adds: params.opt_strs("adds"),
+            deletes: params.opt_strs("deletes"),
+            delete_ids: params.opt_strs("delete-ids"),
+            binary: params.opt_present("binary"),
+            staff: params.opt_get_default("staff", 1).unwrap(),
+            no_overlay: params.opt_present("no-overlay"),
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin econtent-sync -- --vendor overdrive --bib-source 3 \
+        --adds od-adds.mrc --deletes od-deletes.mrc --binary --dry-run
+
+Applies OverDrive or cloudLibrary MARC delta feeds to the catalog.
+
+Records are matched to existing bibs with the same bib source by
+vendor ID.  Added records which match are overlaid, others are
+loaded as new bibs.  Deleted records which match are deleted, along
+with their located URI call numbers.  Bibs with items attached are
+never deleted.
+
+Prints a summary per feed as CSV:
+feed,added,overlaid,deleted,not_found,skipped
+
+Options
+
+    --vendor
+        overdrive or cloudlibrary.  Sets the default --id-field and
+        labels the report.  Defaults to overdrive.
+
+    --id-field
+        Field holding the vendor ID, as a tag with an optional
+        subfield.  Defaults to 037a for OverDrive and 001 for
+        cloudLibrary.
+
+    --bib-source
+        config.bib_source ID for loaded records.  Only bibs with this
+        source are matched.  Required.
+
+    --adds
+        MARC file of added or updated records.  Repeatable.
+
+    --deletes
+        MARC file of withdrawn records.  Repeatable.
+
+    --delete-ids
+        File of withdrawn vendor IDs, one per line.  Repeatable.
+
+    --binary
+        MARC files are binary MARC instead of MARC XML.
+
+    --staff
+        Creator and editor for changed records.  Defaults to 1.
+
+    --no-overlay
+        Skip added records which are already loaded.
+
+    --out-file
+        Write the summary here instead of stdout.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Vendor IDs are compared case-insensitively, since OverDrive IDs
+/// appear in upper and lower case across feeds.
+fn normalize_id(id: &str) -> String {
+    id.trim().to_lowercase()
+}
+
+fn vendor_id(ops: &SyncOptions, record: &Record) -> Option<String> {
+    let value = match &ops.id_subfield {
+        Some(sf) => record
+            .get_values(&ops.id_tag, sf)
+            .first()
+            .map(|v| v.to_string()),
+        None => marc::control_value(record, &ops.id_tag).map(|v| v.to_string()),
+    };
+
+    value.map(|v| normalize_id(&v)).filter(|v| !v.is_empty())
+}
+
+/// Map of vendor ID to bib ID for existing records from the source.
+fn load_existing(
+    tx: &mut pg::Transaction,
+    ops: &SyncOptions,
+) -> Result<HashMap<String, i64>, String> {
+    let sql = r#"
+        SELECT id, marc
+        FROM biblio.record_entry
+        WHERE source = $1 AND NOT deleted
+    "#;
+
+    let rows = tx
+        .query(sql, &[&ops.bib_source])
+        .map_err(|e| db_err("Error loading existing records", e))?;
+
+    let mut existing = HashMap::new();
+
+    for row in &rows {
+        let id: i64 = row.get("id");
+        let xml: &str = row.get("marc");
+
+        let record = match Record::from_xml(xml).next() {
+            Some(r) => r,
+            None => {
+                warn!("Record {id} cannot be parsed; skipping");
+                continue;
+            }
+        };
+
+        match vendor_id(ops, &record) {
+            Some(vid) => {
+                if let Some(other) = existing.insert(vid.clone(), id) {
+                    warn!("Records {other} and {id} share vendor ID {vid}");
+                }
+            }
+            None => debug!("Record {id} has no vendor ID"),
+        }
+    }
+
+    info!("Loaded {} existing {} records", existing.len(), ops.vendor);
+
+    Ok(existing)
+}
+
+fn apply_adds(
+    tx: &mut pg::Transaction,
+    ops: &SyncOptions,
+    existing: &mut HashMap<String, i64>,
+    path: &str,
+) -> Result<FeedSummary, String> {
+    let insert = r#"
+        INSERT INTO biblio.record_entry (marc, last_xact_id, source, creator, editor)
+        VALUES ($1, 'econtent-sync', $2, $3, $3)
+        RETURNING id
+    "#;
+
+    let update = r#"
+        UPDATE biblio.record_entry
+        SET marc = $1, last_xact_id = 'econtent-sync', editor = $2, edit_date = NOW()
+        WHERE id = $3
+    "#;
+
+    let mut summary = FeedSummary::default();
+
+    for record in marc::read_file(path, ops.binary)? {
+        let vid = match vendor_id(ops, &record) {
+            Some(v) => v,
+            None => {
+                warn!("Skipping record with no vendor ID in {path}");
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        let xml = record.to_xml()?;
+
+        match existing.get(&vid) {
+            Some(_) if ops.no_overlay => {
+                debug!("{vid} is already loaded");
+                summary.skipped += 1;
+            }
+            Some(id) => {
+                tx.execute(update, &[&xml, &ops.staff, id])
+                    .map_err(|e| db_err(&format!("Error overlaying record {id}"), e))?;
+                debug!("Overlaid record {id} with {vid}");
+                summary.overlaid += 1;
+            }
+            None => {
+                let id: i64 = tx
+                    .query_one(insert, &[&xml, &ops.bib_source, &ops.staff])
+                    .map_err(|e| db_err(&format!("Error loading {vid}"), e))?
+                    .get("id");
+                debug!("Loaded {vid} as record {id}");
+                existing.insert(vid, id);
+                summary.added += 1;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+fn apply_deletes(
+    tx: &mut pg::Transaction,
+    ops: &SyncOptions,
+    existing: &mut HashMap<String, i64>,
+    ids: Vec<String>,
+) -> Result<FeedSummary, String> {
+    let items = r#"
+        SELECT COUNT(*) AS count
+        FROM asset.copy acp
+            JOIN asset.call_number acn ON acn.id = acp.call_number
+        WHERE acn.record = $1 AND NOT acp.deleted AND NOT acn.deleted
+    "#;
+
+    let uris = r#"
+        UPDATE asset.call_number SET deleted = TRUE
+        WHERE record = $1 AND label = '##URI##' AND NOT deleted
+    "#;
+
+    let delete = r#"
+        UPDATE biblio.record_entry
+        SET deleted = TRUE, editor = $2, edit_date = NOW()
+        WHERE id = $1
+    "#;
+
+    let mut summary = FeedSummary::default();
+
+    for vid in ids {
+        let id = match existing.get(&vid) {
+            Some(id) => *id,
+            None => {
+                debug!("{vid} is not loaded");
+                summary.not_found += 1;
+                continue;
+            }
+        };
+
+        let count: i64 = tx
+            .query_one(items, &[&id])
+            .map_err(|e| db_err(&format!("Error checking items on {id}"), e))?
+            .get("count");
+
+        if count > 0 {
+            warn!("Record {id} ({vid}) has {count} items; not deleting");
+            summary.skipped += 1;
+            continue;
+        }
+
+        tx.execute(uris, &[&id])
+            .map_err(|e| db_err(&format!("Error deleting URIs on {id}"), e))?;
+
+        tx.execute(delete, &[&id, &ops.staff])
+            .map_err(|e| db_err(&format!("Error deleting record {id}"), e))?;
+
+        debug!("Deleted record {id} ({vid})");
+        existing.remove(&vid);
+        summary.deleted += 1;
+    }
+
+    Ok(summary)
+}
+
+fn read_id_file(path: &str) -> Result<Vec<String>, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text
+            .lines()
+            .map(normalize_id)
+            .filter(|l| !l.is_empty())
+            .collect()),
+        Err(e) => Err(format!("Cannot read {path}: {e}")),
+    }
+}
+
+fn sync(con: &mut DatabaseConnection, ops: &SyncOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut existing = load_existing(&mut tx, ops)?;
+    let mut feeds = Vec::new();
+
+    for path in &ops.adds {
+        feeds.push((path, apply_adds(&mut tx, ops, &mut existing, path)?));
+    }
+
+    for path in &ops.deletes {
+        let ids = marc::read_file(path, ops.binary)?
+            .iter()
+            .filter_map(|r| vendor_id(ops, r))
+            .collect();
+        feeds.push((path, apply_deletes(&mut tx, ops, &mut existing, ids)?));
+    }
+
+    for path in &ops.delete_ids {
+        let ids = read_id_file(path)?;
+        feeds.push((path, apply_deletes(&mut tx, ops, &mut existing, ids)?));
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    con.disconnect();
+
+    let mut out = String::from("feed,added,overlaid,deleted,not_found,skipped\n");
+
+    for (path, s) in &feeds {
+        audit::count("added", s.added as u64);
+        audit::count("overlaid", s.overlaid as u64);
+        audit::count("deleted", s.deleted as u64);
+
+        info!(
+            "{} {path}: added {}, overlaid {}, deleted {}, not found {}, skipped {}",
+            ops.vendor, s.added, s.overlaid, s.deleted, s.not_found, s.skipped
+        );
+        out += &format!(
+            "{},{},{},{},{},{}\n",
+            csv_field(path),
+            s.added,
+            s.overlaid,
+            s.deleted,
+            s.not_found,
+            s.skipped
+        );
+    }
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    if let Err(e) = writer.write_all(out.as_bytes()) {
+        return Err(format!("Error writing report: {e}"));
+    }
+
+    if ops.dry_run.enabled() {
+        info!("Dry run; no changes saved");
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(sync(&mut connection, &options))
+}
@@ -0,0 +1,853 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::edi::{self, Document};
+use log::{debug, error, info, warn};
+use postgres as pg;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+struct EdiOptions {
+    account: Option<i32>,
+    local_dir: Option<String>,
+    staff: i32,
+    cancel_reason: Option<i32>,
+    timeout: u64,
+    dry_run: DryRun,
+}
+
+/// Inbound EDI account from acq.edi_account.
+struct EdiAccount {
+    id: i32,
+    label: String,
+    host: String,
+    username: Option<String>,
+    password: Option<String>,
+    in_dir: String,
+    provider: i32,
+}
+
+fn read_options() -> (EdiOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "account", "EDI Account ID", "ACCOUNT_ID");
+    opts.optopt("", "local-dir", "Process Files From Directory", "DIR");
+    opts.optopt("", "staff", "Staff User ID for Notes", "USER_ID");
+    opts.optopt(
+        "",
+        "cancel-reason",
+        "Cancel Reason for Rejected Lines",
+        "REASON_ID",
+    );
+    opts.optopt("", "timeout", "Network Timeout Seconds", "SECONDS");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        EdiOptions {
+            account: params.opt_get("account").unwrap(),
+            local_dir: params.opt_str("local-dir"),
+            staff: cli::required(&params, "staff"),
+            cancel_reason: params.opt_get("cancel-reason").unwrap(),
+            timeout: params.opt_get_default("timeout", 30).unwrap(),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin edi-process -- --staff 1 --cancel-reason 1283
+
+Fetches EDIFACT messages from the incoming directory of each EDI
+account (acq.edi_account.in_dir) over FTP or SFTP and applies them.
+
+    ORDRSP
+        Adds a note to the purchase order and to each responding
+        lineitem.  Lineitems the vendor cannot supply are cancelled
+        when --cancel-reason is set.
+
+    INVOIC
+        Creates an acq.invoice with entries for each invoiced
+        lineitem, plus tax and shipping charges.  Invoices already
+        on file for the provider are skipped.
+
+Each file is recorded in acq.edi_message and files already recorded
+for an account are not fetched again.  Accounts whose host starts
+with sftp:// use SFTP.  All others use FTP.
+
+Options
+
+    --account
+        Only process this EDI account.
+
+    --local-dir
+        Process files from this directory instead of fetching them.
+        Requires --account.
+
+    --staff
+        ID of the user credited with PO and lineitem notes.
+
+    --cancel-reason
+        acq.cancel_reason ID applied to lineitems the vendor
+        reports as not available.
+
+    --timeout
+        Network timeout in seconds.  Defaults to 30.
+
+    --dry-run
+        Make the changes, then roll them back, reporting what a real
+        run would do.  Messages are not recorded as processed.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+// --- Message processing ---
+
+fn order_response_text(function: &str) -> &str {
+    match function {
+        "4" => "changed",
+        "27" => "not accepted",
+        "29" => "accepted without amendment",
+        _ => "received",
+    }
+}
+
+/// Returns the PO's ordering agency when it belongs to the provider.
+fn find_po(tx: &mut pg::Transaction, po_id: i32, provider: i32) -> Result<i32, String> {
+    let sql = "SELECT ordering_agency FROM acq.purchase_order WHERE id = $1 AND provider = $2";
+    match tx.query_opt(sql, &[&po_id, &provider]) {
+        Ok(Some(row)) => Ok(row.get("ordering_agency")),
+        Ok(None) => Err(format!("No purchase order {po_id} for provider {provider}")),
+        Err(e) => Err(db_err("Error finding purchase order", e)),
+    }
+}
+
+fn cancel_lineitem(tx: &mut pg::Transaction, li_id: i32, reason: i32) -> Result<(), String> {
+    let sqls = [
+        r#"
+        UPDATE acq.lineitem SET state = 'cancelled', cancel_reason = $2, edit_time = NOW()
+        WHERE id = $1 AND state NOT IN ('received', 'cancelled')
+        "#,
+        // Release the encumbrances held by copies not yet received.
+        r#"
+        WITH debits AS (
+            UPDATE acq.lineitem_detail lid SET cancel_reason = $2, fund_debit = NULL
+            FROM acq.lineitem_detail old
+            WHERE lid.id = old.id
+                AND lid.lineitem = $1
+                AND lid.recv_time IS NULL
+            RETURNING old.fund_debit
+        )
+        DELETE FROM acq.fund_debit
+        WHERE encumbrance AND id IN (SELECT fund_debit FROM debits)
+        "#,
+    ];
+
+    for sql in sqls {
+        if let Err(e) = tx.execute(sql, &[&li_id, &reason]) {
+            return Err(db_err("Error cancelling lineitem", e));
+        }
+    }
+
+    Ok(())
+}
+
+fn process_ordrsp(
+    tx: &mut pg::Transaction,
+    ops: &EdiOptions,
+    account: &EdiAccount,
+    doc: &Document,
+) -> Result<Option<i32>, String> {
+    // The PO ID is our order number, echoed back to us.
+    let po_id = match doc.purchase_order.or_else(|| doc.number.parse().ok()) {
+        Some(id) => id,
+        None => return Err("Order response has no purchase order reference".to_string()),
+    };
+
+    find_po(tx, po_id, account.provider)?;
+
+    let sql = r#"
+        INSERT INTO acq.po_note (purchase_order, creator, editor, value)
+        VALUES ($1, $2, $2, $3)
+    "#;
+
+    let note = format!(
+        "Order response {}: {} line(s)",
+        order_response_text(&doc.function),
+        doc.lines.len()
+    );
+
+    if let Err(e) = tx.execute(sql, &[&po_id, &ops.staff, &note]) {
+        return Err(db_err("Error adding PO note", e));
+    }
+
+    for line in &doc.lines {
+        let li_id = match line.lineitem {
+            Some(id) => id,
+            None => {
+                warn!("PO {po_id}: response line {} has no lineitem", line.ident);
+                continue;
+            }
+        };
+
+        let qty = |q: &str| line.quantities.get(q).copied().unwrap_or(0);
+
+        // 21 = ordered, 12 = dispatched, 83 = backordered
+        let mut note = format!(
+            "Vendor response: ordered {}, dispatched {}, backordered {}",
+            qty("21"),
+            qty("12"),
+            qty("83")
+        );
+
+        for n in &line.notes {
+            note += &format!("; {n}");
+        }
+
+        let sql = r#"
+            INSERT INTO acq.lineitem_note (lineitem, creator, editor, value)
+            SELECT id, $2, $2, $3 FROM acq.lineitem WHERE id = $1 AND purchase_order = $4
+        "#;
+
+        match tx.execute(sql, &[&li_id, &ops.staff, &note, &po_id]) {
+            Ok(0) => return Err(format!("Lineitem {li_id} is not on PO {po_id}")),
+            Ok(_) => {}
+            Err(e) => return Err(db_err("Error adding lineitem note", e)),
+        }
+
+        // The vendor cannot supply the line at all.
+        let rejected = line.action == "2"
+            || (line.quantities.contains_key("21") && qty("21") == 0 && qty("83") == 0);
+
+        if rejected {
+            match ops.cancel_reason {
+                Some(reason) => cancel_lineitem(tx, li_id, reason)?,
+                None => info!("PO {po_id}: lineitem {li_id} not available"),
+            }
+        }
+    }
+
+    Ok(Some(po_id))
+}
+
+fn process_invoic(
+    tx: &mut pg::Transaction,
+    account: &EdiAccount,
+    doc: &Document,
+) -> Result<Option<i32>, String> {
+    if doc.number.is_empty() {
+        return Err("Invoice has no invoice number".to_string());
+    }
+
+    let sql = "SELECT id FROM acq.invoice WHERE provider = $1 AND inv_ident = $2";
+    match tx.query_opt(sql, &[&account.provider, &doc.number]) {
+        Ok(Some(_)) => {
+            info!("Invoice {} already on file; skipping", doc.number);
+            return Ok(None);
+        }
+        Ok(None) => {}
+        Err(e) => return Err(db_err("Error checking invoices", e)),
+    }
+
+    let header_po = doc
+        .purchase_order
+        .or_else(|| doc.lines.iter().find_map(|l| l.purchase_order));
+
+    let po_id = match header_po {
+        Some(id) => id,
+        None => return Err("Invoice references no purchase order".to_string()),
+    };
+
+    let receiver = find_po(tx, po_id, account.provider)?;
+
+    let sql = r#"
+        INSERT INTO acq.invoice (receiver, provider, shipper, recv_method, inv_ident, recv_date)
+        VALUES ($1, $2, $2, 'EDI', $3, NOW())
+        RETURNING id
+    "#;
+
+    let invoice_id: i32 = match tx.query_one(sql, &[&receiver, &account.provider, &doc.number]) {
+        Ok(row) => row.get("id"),
+        Err(e) => return Err(db_err("Error creating invoice", e)),
+    };
+
+    let entry_sql = r#"
+        INSERT INTO acq.invoice_entry (
+            invoice, purchase_order, lineitem, inv_item_count,
+            phys_item_count, cost_billed, amount_paid
+        ) VALUES ($1, $2, $3, $4, $4, $5::TEXT::NUMERIC, $5::TEXT::NUMERIC)
+    "#;
+
+    for line in &doc.lines {
+        let li_id = match line.lineitem {
+            Some(id) => id,
+            None => {
+                warn!(
+                    "Invoice {}: line {} has no lineitem",
+                    doc.number, line.ident
+                );
+                continue;
+            }
+        };
+
+        let line_po = line.purchase_order.unwrap_or(po_id);
+
+        let count = line.invoiced_count();
+        let cost = format!("{:.2}", line.invoiced_cost());
+
+        if let Err(e) = tx.execute(entry_sql, &[&invoice_id, &line_po, &li_id, &count, &cost]) {
+            return Err(db_err("Error creating invoice entry", e));
+        }
+    }
+
+    let item_sql = r#"
+        INSERT INTO acq.invoice_item (
+            invoice, purchase_order, inv_item_type, cost_billed, amount_paid
+        ) VALUES ($1, $2, $3, $4::TEXT::NUMERIC, $4::TEXT::NUMERIC)
+    "#;
+
+    for (kind, amount) in &doc.charges {
+        let amount = format!("{amount:.2}");
+        if let Err(e) = tx.execute(item_sql, &[&invoice_id, &po_id, kind, &amount]) {
+            return Err(db_err("Error creating invoice item", e));
+        }
+    }
+
+    info!(
+        "Created invoice {invoice_id} ({}) with {} entries",
+        doc.number,
+        doc.lines.len()
+    );
+
+    Ok(Some(po_id))
+}
+
+/// Apply every message in a file.
+///
+/// Returns the message type and purchase order for the edi_message entry.
+fn process_file(
+    con: &mut DatabaseConnection,
+    ops: &EdiOptions,
+    account: &EdiAccount,
+    edi: &str,
+) -> Result<(String, Option<i32>), String> {
+    let messages = edi::parse_messages(edi)?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut purchase_order = None;
+
+    for msg in &messages {
+        let doc = edi::read_document(msg);
+
+        let po = match msg.kind.as_str() {
+            "ORDRSP" => process_ordrsp(&mut tx, ops, account, &doc)?,
+            "INVOIC" => process_invoic(&mut tx, account, &doc)?,
+            k => return Err(format!("Unsupported message type: {k}")),
+        };
+
+        purchase_order = purchase_order.or(po);
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    Ok((messages[0].kind.to_string(), purchase_order))
+}
+
+// --- Remote file access ---
+
+trait RemoteDir {
+    fn list(&mut self) -> Result<Vec<String>, String>;
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, String>;
+}
+
+/// Minimal passive-mode FTP client.
+struct FtpDir {
+    reader: BufReader<TcpStream>,
+    timeout: Duration,
+}
+
+impl FtpDir {
+    fn connect(addr: &str, account: &EdiAccount, timeout: Duration) -> Result<FtpDir, String> {
+        let stream = match TcpStream::connect(addr) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot connect to {addr}: {e}")),
+        };
+
+        stream.set_read_timeout(Some(timeout)).ok();
+        stream.set_write_timeout(Some(timeout)).ok();
+
+        let mut ftp = FtpDir {
+            reader: BufReader::new(stream),
+            timeout,
+        };
+
+        ftp.expect(None, &[220])?;
+
+        let user = account.username.as_deref().unwrap_or("anonymous");
+        let (code, _) = ftp.command(&format!("USER {user}"))?;
+        if code == 331 {
+            let pass = account.password.as_deref().unwrap_or("");
+            ftp.expect(Some(&format!("PASS {pass}")), &[230])?;
+        } else if code != 230 {
+            return Err(format!("FTP login failed with code {code}"));
+        }
+
+        ftp.expect(Some("TYPE I"), &[200])?;
+
+        if !account.in_dir.is_empty() {
+            ftp.expect(Some(&format!("CWD {}", account.in_dir)), &[250])?;
+        }
+
+        Ok(ftp)
+    }
+
+    fn read_reply(&mut self) -> Result<(u32, String), String> {
+        let mut text = String::new();
+
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Err("FTP connection closed".to_string()),
+                Ok(_) => {}
+                Err(e) => return Err(format!("Error reading FTP reply: {e}")),
+            }
+
+            text += &line;
+
+            // Multi-line replies end with "NNN " matching the first code.
+            if line.len() >= 4 && line.as_bytes()[3] == b' ' {
+                if let Ok(code) = line[..3].parse() {
+                    return Ok((code, text));
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, cmd: &str) -> Result<(u32, String), String> {
+        debug!("FTP: {}", cmd.split(' ').next().unwrap_or(""));
+
+        let stream = self.reader.get_mut();
+        if let Err(e) = stream.write_all(format!("{cmd}\r\n").as_bytes()) {
+            return Err(format!("Error writing FTP command: {e}"));
+        }
+
+        self.read_reply()
+    }
+
+    fn expect(&mut self, cmd: Option<&str>, codes: &[u32]) -> Result<String, String> {
+        let (code, text) = match cmd {
+            Some(c) => self.command(c)?,
+            None => self.read_reply()?,
+        };
+
+        if codes.contains(&code) {
+            Ok(text)
+        } else {
+            Err(format!("Unexpected FTP reply: {}", text.trim()))
+        }
+    }
+
+    /// Run a command which sends its response over a passive data connection.
+    fn transfer(&mut self, cmd: &str) -> Result<Vec<u8>, String> {
+        let reply = self.expect(Some("PASV"), &[227])?;
+
+        // 227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)
+        let nums: Vec<u16> = match (reply.find('('), reply.find(')')) {
+            (Some(s), Some(e)) if s < e => reply[s + 1..e]
+                .split(',')
+                .filter_map(|n| n.trim().parse().ok())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        if nums.len() != 6 {
+            return Err(format!("Invalid PASV reply: {}", reply.trim()));
+        }
+
+        let addr = format!(
+            "{}.{}.{}.{}:{}",
+            nums[0],
+            nums[1],
+            nums[2],
+            nums[3],
+            nums[4] * 256 + nums[5]
+        );
+
+        let mut data = match TcpStream::connect(&addr) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot open FTP data connection: {e}")),
+        };
+
+        data.set_read_timeout(Some(self.timeout)).ok();
+
+        self.expect(Some(cmd), &[125, 150])?;
+
+        let mut bytes = Vec::new();
+        if let Err(e) = data.read_to_end(&mut bytes) {
+            return Err(format!("Error reading FTP data: {e}"));
+        }
+
+        self.expect(None, &[226, 250])?;
+
+        Ok(bytes)
+    }
+}
+
+impl RemoteDir for FtpDir {
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        let bytes = self.transfer("NLST")?;
+        Ok(String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect())
+    }
+
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        self.transfer(&format!("RETR {name}"))
+    }
+}
+
+impl Drop for FtpDir {
+    fn drop(&mut self) {
+        self.command("QUIT").ok();
+    }
+}
+
+struct SftpDir {
+    sftp: ssh2::Sftp,
+    dir: String,
+    // Keep the session alive as long as the SFTP channel.
+    _session: ssh2::Session,
+}
+
+impl SftpDir {
+    fn connect(addr: &str, account: &EdiAccount, timeout: Duration) -> Result<SftpDir, String> {
+        let stream = match TcpStream::connect(addr) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot connect to {addr}: {e}")),
+        };
+
+        let ssh_err = |e: ssh2::Error| format!("SFTP error for {addr}: {e}");
+
+        let mut session = ssh2::Session::new().map_err(ssh_err)?;
+        session.set_tcp_stream(stream);
+        session.set_timeout(timeout.as_millis() as u32);
+        session.handshake().map_err(ssh_err)?;
+        session
+            .userauth_password(
+                account.username.as_deref().unwrap_or(""),
+                account.password.as_deref().unwrap_or(""),
+            )
+            .map_err(ssh_err)?;
+
+        let sftp = session.sftp().map_err(ssh_err)?;
+
+        let dir = match account.in_dir.as_str() {
+            "" => ".".to_string(),
+            d => d.to_string(),
+        };
+
+        Ok(SftpDir {
+            sftp,
+            dir,
+            _session: session,
+        })
+    }
+}
+
+impl RemoteDir for SftpDir {
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        let entries = match self.sftp.readdir(Path::new(&self.dir)) {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Cannot list {}: {e}", self.dir)),
+        };
+
+        Ok(entries
+            .iter()
+            .filter(|(_, stat)| stat.is_file())
+            .filter_map(|(path, _)| path.file_name())
+            .map(|n| n.to_string_lossy().to_string())
+            .collect())
+    }
+
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        let path = Path::new(&self.dir).join(name);
+
+        let mut file = match self.sftp.open(&path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Cannot open {name}: {e}")),
+        };
+
+        let mut bytes = Vec::new();
+        match file.read_to_end(&mut bytes) {
+            Ok(_) => Ok(bytes),
+            Err(e) => Err(format!("Error reading {name}: {e}")),
+        }
+    }
+}
+
+/// Local directory, for reprocessing or testing.
+struct LocalDir {
+    dir: String,
+}
+
+impl RemoteDir for LocalDir {
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Cannot list {}: {e}", self.dir)),
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_file())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        match fs::read(Path::new(&self.dir).join(name)) {
+            Ok(b) => Ok(b),
+            Err(e) => Err(format!("Error reading {name}: {e}")),
+        }
+    }
+}
+
+fn open_dir(ops: &EdiOptions, account: &EdiAccount) -> Result<Box<dyn RemoteDir>, String> {
+    if let Some(ref dir) = ops.local_dir {
+        return Ok(Box::new(LocalDir {
+            dir: dir.to_string(),
+        }));
+    }
+
+    let timeout = Duration::from_secs(ops.timeout);
+
+    let (sftp, host) = match account.host.split_once("://") {
+        Some((scheme, host)) => (scheme.eq_ignore_ascii_case("sftp"), host),
+        None => (false, account.host.as_str()),
+    };
+
+    let host = host.trim_end_matches('/');
+
+    let addr = match (host.contains(':'), sftp) {
+        (true, _) => host.to_string(),
+        (false, true) => format!("{host}:22"),
+        (false, false) => format!("{host}:21"),
+    };
+
+    if sftp {
+        Ok(Box::new(SftpDir::connect(&addr, account, timeout)?))
+    } else {
+        Ok(Box::new(FtpDir::connect(&addr, account, timeout)?))
+    }
+}
+
+// --- Driver ---
+
+fn load_accounts(
+    con: &mut DatabaseConnection,
+    ops: &EdiOptions,
+) -> Result<Vec<EdiAccount>, String> {
+    let sql = r#"
+        SELECT id, label, host, username, password, in_dir, provider
+        FROM acq.edi_account
+        WHERE ($1::INT IS NULL AND in_dir IS NOT NULL) OR id = $1::INT
+        ORDER BY id
+    "#;
+
+    let rows = match con.client().query(sql, &[&ops.account]) {
+        Ok(r) => r,
+        Err(e) => return Err(db_err("Error loading EDI accounts", e)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| EdiAccount {
+            id: row.get("id"),
+            label: row.get("label"),
+            host: row.get("host"),
+            username: row.get("username"),
+            password: row.get("password"),
+            in_dir: row
+                .get::<&str, Option<String>>("in_dir")
+                .unwrap_or_default(),
+            provider: row.get("provider"),
+        })
+        .collect())
+}
+
+fn already_processed(
+    con: &mut DatabaseConnection,
+    account: &EdiAccount,
+    remote_file: &str,
+) -> Result<bool, String> {
+    let sql = "SELECT 1 FROM acq.edi_message WHERE account = $1 AND remote_file = $2";
+    match con.client().query_opt(sql, &[&account.id, &remote_file]) {
+        Ok(r) => Ok(r.is_some()),
+        Err(e) => Err(db_err("Error checking EDI messages", e)),
+    }
+}
+
+fn record_message(
+    con: &mut DatabaseConnection,
+    account: &EdiAccount,
+    remote_file: &str,
+    edi: &str,
+    result: &Result<(String, Option<i32>), String>,
+) -> Result<(), String> {
+    let sql = r#"
+        INSERT INTO acq.edi_message (
+            account, remote_file, edi, message_type, status,
+            error, error_time, purchase_order, process_time
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6,
+            CASE WHEN $6::TEXT IS NULL THEN NULL ELSE NOW() END,
+            $7, CASE WHEN $6::TEXT IS NULL THEN NOW() ELSE NULL END
+        )
+    "#;
+
+    let (kind, status, error, po) = match result {
+        Ok((kind, po)) => (kind.as_str(), "processed", None, *po),
+        Err(e) => ("", "proc_error", Some(e.as_str()), None),
+    };
+
+    // message_type may only hold known message types.
+    let kind = match kind {
+        "ORDRSP" | "INVOIC" => Some(kind),
+        _ => None,
+    };
+
+    match con.client().execute(
+        sql,
+        &[&account.id, &remote_file, &edi, &kind, &status, &error, &po],
+    ) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(db_err("Error recording EDI message", e)),
+    }
+}
+
+fn process_account(
+    con: &mut DatabaseConnection,
+    ops: &EdiOptions,
+    account: &EdiAccount,
+) -> Result<(usize, usize), String> {
+    let mut dir = open_dir(ops, account)?;
+    let mut processed = 0;
+    let mut failed = 0;
+
+    for name in dir.list()? {
+        let remote_file = match ops.local_dir {
+            Some(ref d) => format!("{d}/{name}"),
+            None => format!("{}/{}/{}", account.host, account.in_dir, name),
+        };
+
+        if already_processed(con, account, &remote_file)? {
+            debug!("Skipping {remote_file}");
+            continue;
+        }
+
+        let bytes = dir.fetch(&name)?;
+        let edi = String::from_utf8_lossy(&bytes).to_string();
+
+        let result = process_file(con, ops, account, &edi);
+
+        match result {
+            Ok((ref kind, _)) => {
+                info!("Processed {kind} file {remote_file}");
+                processed += 1;
+            }
+            Err(ref e) => {
+                error!("Error processing {remote_file}: {e}");
+                failed += 1;
+            }
+        }
+
+        if !ops.dry_run.enabled() {
+            record_message(con, account, &remote_file, &edi, &result)?;
+        }
+    }
+
+    Ok((processed, failed))
+}
+
+fn process(con: &mut DatabaseConnection, ops: &EdiOptions) -> Result<(), String> {
+    if ops.local_dir.is_some() && ops.account.is_none() {
+        return Err("--local-dir requires --account".to_string());
+    }
+
+    con.connect()?;
+
+    let mut processed = 0;
+    let mut failed = 0;
+
+    for account in load_accounts(con, ops)? {
+        info!("Checking EDI account {} ({})", account.id, account.label);
+
+        // One unreachable vendor should not block the others.
+        match process_account(con, ops, &account) {
+            Ok((p, f)) => {
+                processed += p;
+                failed += f;
+            }
+            Err(e) => {
+                error!("Account {}: {e}", account.id);
+                failed += 1;
+            }
+        }
+    }
+
+    con.disconnect();
+
+    audit::count("files", processed as u64);
+    audit::count("errors", failed as u64);
+
+    println!(
+        "{} {processed} file(s), {failed} error(s)",
+        ops.dry_run.past("Processed")
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(process(&mut connection, &options))
+}
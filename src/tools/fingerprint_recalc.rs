@@ -0,0 +1,351 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use log::{error, info};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc;
+use std::time::Instant;
+use threadpool::ThreadPool;
+
+#[derive(Clone)]
+struct RecalcOptions {
+    min_id: i64,
+    max_id: i64,
+    max_threads: usize,
+    batch_size: usize,
+    lang: String,
+    item_type: String,
+    regroup: bool,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (RecalcOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "max-threads", "Max Worker Threads", "MAX_THREADS");
+    opts.optopt("", "batch-size", "Records per Batch", "BATCH_SIZE");
+    opts.optopt("", "lang", "Preferred Language for Quality", "LANG");
+    opts.optopt("", "item-type", "Preferred Record Type for Quality", "TYPE");
+    opts.optflag("", "regroup", "Regroup Metarecords for Changed Records");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        RecalcOptions {
+            min_id: params.opt_get_default("min-id", 0).unwrap(),
+            max_id: params.opt_get_default("max-id", 0).unwrap(),
+            max_threads: params.opt_get_default("max-threads", 5).unwrap(),
+            batch_size: params.opt_get_default("batch-size", 1000).unwrap(),
+            lang: params.opt_get_default("lang", "eng".to_string()).unwrap(),
+            item_type: params
+                .opt_get_default("item-type", "BKS".to_string())
+                .unwrap(),
+            regroup: params.opt_present("regroup"),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin fingerprint-recalc -- --min-id 1 --max-id 500000 --regroup
+
+Recomputes biblio.record_entry fingerprint and quality values in
+parallel batches using biblio.extract_fingerprint() and
+biblio.extract_quality().  Only records whose values change are
+updated.
+
+With --regroup, changed records are then remapped to metarecords
+with metabib.remap_metarecord_for_bib().  Records sharing a
+fingerprint are always remapped by the same worker.
+
+Options
+
+    --min-id
+    --max-id
+        Limit to records with IDs in this range.
+
+    --max-threads
+        Number of parallel database workers.  Defaults to 5.
+
+    --batch-size
+        Number of records per batch.  Defaults to 1000.
+
+    --lang
+    --item-type
+        Preferred language and record type passed to
+        biblio.extract_quality().  These should match the arguments
+        of the fingerprint trigger.  Defaults to eng and BKS.
+
+    --regroup
+        Regroup metarecords for records whose fingerprint changed.
+
+    --dry-run
+        Make the changes, then roll them back, reporting what a real
+        run would do.  --regroup is skipped.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn get_record_ids(con: &mut DatabaseConnection, ops: &RecalcOptions) -> Result<Vec<i64>, String> {
+    let sql = r#"
+        SELECT id FROM biblio.record_entry
+        WHERE NOT deleted AND id > 0 AND id >= $1 AND ($2 = 0 OR id <= $2)
+        ORDER BY id
+    "#;
+
+    match con.client().query(sql, &[&ops.min_id, &ops.max_id]) {
+        Ok(rows) => Ok(rows.iter().map(|r| r.get("id")).collect()),
+        Err(e) => Err(format!("Error finding records: {e}")),
+    }
+}
+
+/// Returns (record ID, changed fingerprint?) for each changed record.
+fn recalc_batch(
+    mut con: DatabaseConnection,
+    ops: &RecalcOptions,
+    ids: &[i64],
+) -> Result<Vec<(i64, bool)>, String> {
+    con.connect()?;
+
+    let changes = r#"
+        SELECT bre.id, x.fingerprint, x.quality,
+            x.fingerprint IS DISTINCT FROM bre.fingerprint AS fp_changed
+        FROM biblio.record_entry bre,
+            LATERAL (
+                SELECT
+                    biblio.extract_fingerprint(bre.marc) AS fingerprint,
+                    biblio.extract_quality(bre.marc, $2, $3) AS quality
+            ) x
+        WHERE bre.id = ANY($1)
+            AND (
+                x.fingerprint IS DISTINCT FROM bre.fingerprint
+                OR x.quality IS DISTINCT FROM bre.quality
+            )
+    "#;
+
+    // The fingerprint trigger recalculates both values again during
+    // the update, hence --lang/--item-type must match its arguments.
+    // The MARC is unchanged, so no reingest occurs.
+    let sql = format!(
+        r#"
+        WITH changes AS ({changes})
+        UPDATE biblio.record_entry bre
+        SET fingerprint = changes.fingerprint, quality = changes.quality
+        FROM changes
+        WHERE bre.id = changes.id
+        RETURNING bre.id, changes.fp_changed
+        "#
+    );
+
+    let ids = ids.to_vec();
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let rows = match tx.query(sql.as_str(), &[&ids, &ops.lang, &ops.item_type]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error recalculating batch at {}: {e}", ids[0])),
+    };
+
+    ops.dry_run.finish(tx)?;
+
+    con.disconnect();
+
+    Ok(rows
+        .iter()
+        .map(|r| (r.get("id"), r.get("fp_changed")))
+        .collect())
+}
+
+fn regroup_worker(mut con: DatabaseConnection, ids: Vec<i64>) -> Result<usize, String> {
+    con.connect()?;
+
+    let sql = r#"
+        SELECT metabib.remap_metarecord_for_bib(id, fingerprint)
+        FROM biblio.record_entry WHERE id = $1
+    "#;
+
+    for id in &ids {
+        if let Err(e) = con.client().query(sql, &[id]) {
+            return Err(format!("Error regrouping record {id}: {e}"));
+        }
+    }
+
+    con.disconnect();
+
+    Ok(ids.len())
+}
+
+fn regroup(con: &mut DatabaseConnection, ops: &RecalcOptions, ids: Vec<i64>) -> Result<(), String> {
+    let sql = "SELECT id, fingerprint FROM biblio.record_entry WHERE id = ANY($1)";
+
+    let rows = match con.client().query(sql, &[&ids]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error loading fingerprints: {e}")),
+    };
+
+    // Partition by fingerprint so no two workers touch the same
+    // new metarecord at once.
+    let workers = ops.max_threads.max(1);
+    let mut partitions: Vec<Vec<i64>> = vec![Vec::new(); workers];
+
+    for row in rows {
+        let fp: Option<String> = row.get("fingerprint");
+        let mut hasher = DefaultHasher::new();
+        fp.hash(&mut hasher);
+        partitions[(hasher.finish() % workers as u64) as usize].push(row.get("id"));
+    }
+
+    let pool = ThreadPool::new(workers);
+    let (sender, receiver) = mpsc::channel();
+
+    for part in partitions.into_iter().filter(|p| !p.is_empty()) {
+        let worker_con = con.partial_clone();
+        let tx = sender.clone();
+        pool.execute(move || {
+            tx.send(regroup_worker(worker_con, part)).ok();
+        });
+    }
+
+    drop(sender);
+
+    let mut regrouped = 0;
+    let mut failed = false;
+
+    for result in receiver {
+        match result {
+            Ok(n) => regrouped += n,
+            Err(e) => {
+                error!("{e}");
+                failed = true;
+            }
+        }
+    }
+
+    pool.join();
+
+    info!("Regrouped {regrouped} records");
+
+    match failed {
+        true => Err("Metarecord regrouping failed for some records".to_string()),
+        false => Ok(()),
+    }
+}
+
+fn recalc(con: &mut DatabaseConnection, ops: &RecalcOptions) -> Result<(), String> {
+    let start = Instant::now();
+
+    con.connect()?;
+
+    let ids = get_record_ids(con, ops)?;
+    info!("Found {} records to process", ids.len());
+
+    let pool = ThreadPool::new(ops.max_threads);
+    let (sender, receiver) = mpsc::channel();
+    let mut batches = 0;
+
+    for chunk in ids.chunks(ops.batch_size.max(1)) {
+        let batch = chunk.to_vec();
+        let worker_con = con.partial_clone();
+        let worker_ops = ops.clone();
+        let tx = sender.clone();
+
+        pool.execute(move || {
+            tx.send(recalc_batch(worker_con, &worker_ops, &batch)).ok();
+        });
+
+        batches += 1;
+    }
+
+    drop(sender);
+
+    let mut done = 0;
+    let mut changed = 0;
+    let mut fp_changed = Vec::new();
+    let mut failed = false;
+
+    for result in receiver {
+        done += 1;
+
+        match result {
+            Ok(rows) => {
+                changed += rows.len();
+                fp_changed.extend(rows.iter().filter(|(_, fp)| *fp).map(|(id, _)| *id));
+            }
+            Err(e) => {
+                error!("{e}");
+                failed = true;
+            }
+        }
+
+        if done % 10 == 0 || done == batches {
+            info!("Completed {done}/{batches} batches; {changed} records changed so far");
+        }
+    }
+
+    pool.join();
+
+    audit::count("records", changed as u64);
+    audit::count("fingerprints", fp_changed.len() as u64);
+
+    println!(
+        "{changed} record(s) {} new values, {} with a new fingerprint, in {:.1}s",
+        ops.dry_run.past("got"),
+        fp_changed.len(),
+        start.elapsed().as_secs_f64()
+    );
+
+    if failed {
+        return Err("One or more batches failed".to_string());
+    }
+
+    if ops.regroup && !ops.dry_run.enabled() && !fp_changed.is_empty() {
+        regroup(con, ops, fp_changed)?;
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(recalc(&mut connection, &options))
+}
@@ -0,0 +1,493 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::info;
+use postgres as pg;
+use postgres::fallible_iterator::FallibleIterator;
+use std::collections::BTreeMap;
+
+const REPORTS: &[&str] = &["fill", "cancel", "ratio"];
+const DIMENSIONS: &[&str] = &["org", "title", "month"];
+
+struct StatsOptions {
+    start_date: String,
+    end_date: String,
+    org_unit: Option<String>,
+    report: String,
+    group_by: Vec<String>,
+    out_file: Option<String>,
+}
+
+/// Pickup library, bib record and title, and request month.  Values
+/// for dimensions not grouped on are left empty.
+type GroupKey = (String, i64, String, String);
+
+#[derive(Default)]
+struct Counts {
+    placed: i64,
+    filled: i64,
+    cancelled: i64,
+    /// Days from request to fulfillment of each filled hold.
+    fill_days: Vec<f64>,
+    /// Cancelled holds by cancel cause.
+    causes: BTreeMap<String, i64>,
+    copies: i64,
+}
+
+fn read_options() -> (StatsOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "start-date", "First Day, YYYY-MM-DD", "DATE");
+    opts.optopt("", "end-date", "Last Day, YYYY-MM-DD", "DATE");
+    opts.optopt("", "org-unit", "Limit to Org Unit", "SHORTNAME");
+    opts.optopt("", "report", "fill, cancel or ratio", "REPORT");
+    opts.optopt("", "group-by", "Comma-Separated Group Dimensions", "DIMS");
+    opts.optopt("", "out-file", "Output File", "FILE");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let group_by = match params.opt_str("group-by") {
+        Some(s) => s.split(',').map(|d| d.trim().to_string()).collect(),
+        None => vec!["org".to_string()],
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        StatsOptions {
+            start_date: cli::required(&params, "start-date"),
+            end_date: cli::required(&params, "end-date"),
+            org_unit: params.opt_str("org-unit"),
+            report: params
+                .opt_get_default("report", "fill".to_string())
+                .unwrap(),
+            group_by,
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin hold-stats -- --start-date 2025-07-01 --end-date 2026-06-30
+    cargo run --bin hold-stats -- --start-date 2026-01-01 --end-date 2026-03-31 --report ratio --group-by title
+
+Reports on holds placed within a date range, for collection
+development decisions.  Holds are read from action.all_hold_request,
+so aged holds are included, and grouped by pickup library, title and
+request month.  Rows are aggregated as they are streamed from the
+database.
+
+Reports
+
+    fill
+        Holds placed, filled, cancelled and still open, the fill
+        rate, and the average and median days from request to
+        fulfillment.  This is the default.
+
+    cancel
+        Cancelled holds by cancel cause, with each cause's share of
+        the group's cancellations.
+
+    ratio
+        Holds placed per visible, holdable copy.  Copies are counted
+        at the pickup library when grouping by org, and across
+        --org-unit otherwise.  Cannot be grouped by month.
+
+Options
+
+    --start-date
+    --end-date
+        Inclusive request date range, YYYY-MM-DD.  Required.
+
+    --org-unit
+        Limit to holds picked up at this org unit and its
+        descendants.
+
+    --report
+        fill, cancel or ratio.  Defaults to fill.
+
+    --group-by
+        Comma-separated list of dimensions to group on, from:
+        org,title,month.  Defaults to org.  Titles are grouped by bib
+        record.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+const HOLD_SQL: &str = r#"
+    SELECT
+        aou.shortname AS org,
+        COALESCE(rhrr.bib_record, 0) AS record,
+        COALESCE(rmsr.title, '') AS title,
+        TO_CHAR(ahr.request_time, 'YYYY-MM') AS month,
+        EXTRACT(EPOCH FROM ahr.fulfillment_time - ahr.request_time)::FLOAT8 / 86400
+            AS fill_days,
+        ahr.cancel_time IS NOT NULL AS cancelled,
+        COALESCE(ahrcc.label, 'Unknown') AS cause
+    FROM action.all_hold_request ahr
+        JOIN actor.org_unit aou ON aou.id = ahr.pickup_lib
+        LEFT JOIN reporter.hold_request_record rhrr ON rhrr.id = ahr.id
+        LEFT JOIN reporter.materialized_simple_record rmsr ON rmsr.id = rhrr.bib_record
+        LEFT JOIN action.hold_request_cancel_cause ahrcc ON ahrcc.id = ahr.cancel_cause
+    WHERE ahr.request_time >= $1::TEXT::DATE
+        AND ahr.request_time < $2::TEXT::DATE + 1
+        AND ($3::INT IS NULL OR ahr.pickup_lib IN (
+            SELECT id FROM actor.org_unit_descendants($3::INT)
+        ))
+"#;
+
+/// Visible, holdable copies of records with holds in the range.
+const COPY_SQL: &str = r#"
+    SELECT
+        aou.shortname AS org,
+        acn.record,
+        COUNT(*) AS copies
+    FROM asset.copy acp
+        JOIN asset.call_number acn ON acn.id = acp.call_number
+        JOIN actor.org_unit aou ON aou.id = acp.circ_lib
+        JOIN asset.copy_location acpl ON acpl.id = acp.location
+        JOIN config.copy_status ccs ON ccs.id = acp.status
+    WHERE NOT acp.deleted
+        AND NOT acn.deleted
+        AND acp.holdable
+        AND acpl.holdable
+        AND ccs.holdable
+        AND acp.opac_visible
+        AND ($3::INT IS NULL OR acp.circ_lib IN (
+            SELECT id FROM actor.org_unit_descendants($3::INT)
+        ))
+        AND acn.record IN (
+            SELECT rhrr.bib_record
+            FROM action.all_hold_request ahr
+                JOIN reporter.hold_request_record rhrr ON rhrr.id = ahr.id
+            WHERE ahr.request_time >= $1::TEXT::DATE
+                AND ahr.request_time < $2::TEXT::DATE + 1
+        )
+    GROUP BY 1, 2
+"#;
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn grouped(ops: &StatsOptions, dim: &str) -> bool {
+    ops.group_by.iter().any(|d| d == dim)
+}
+
+fn org_id(con: &mut DatabaseConnection, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    match con.client().query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error looking up org unit", e)),
+    }
+}
+
+/// Stream holds from the database, adding each to its group.
+fn aggregate_holds(
+    con: &mut DatabaseConnection,
+    ops: &StatsOptions,
+    org_id: Option<i32>,
+    groups: &mut BTreeMap<GroupKey, Counts>,
+) -> Result<usize, String> {
+    let params: [&(dyn postgres::types::ToSql + Sync); 3] =
+        [&ops.start_date, &ops.end_date, &org_id];
+
+    let mut rows = con
+        .client()
+        .query_raw(HOLD_SQL, params)
+        .map_err(|e| db_err("Error running hold query", e))?;
+
+    let by_org = grouped(ops, "org");
+    let by_title = grouped(ops, "title");
+    let by_month = grouped(ops, "month");
+
+    let mut count = 0;
+
+    loop {
+        let row = match rows.next() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => return Err(db_err("Error reading holds", e)),
+        };
+
+        let key: GroupKey = (
+            if by_org {
+                row.get("org")
+            } else {
+                String::new()
+            },
+            if by_title { row.get("record") } else { 0 },
+            if by_title {
+                row.get("title")
+            } else {
+                String::new()
+            },
+            if by_month {
+                row.get("month")
+            } else {
+                String::new()
+            },
+        );
+
+        let counts = groups.entry(key).or_default();
+        counts.placed += 1;
+
+        if let Some(days) = row.get::<_, Option<f64>>("fill_days") {
+            counts.filled += 1;
+            counts.fill_days.push(days);
+        } else if row.get::<_, bool>("cancelled") {
+            counts.cancelled += 1;
+            *counts.causes.entry(row.get("cause")).or_default() += 1;
+        }
+
+        count += 1;
+        if count % 100000 == 0 {
+            info!("Processed {count} holds; {} groups", groups.len());
+        }
+    }
+
+    Ok(count)
+}
+
+/// Add copy counts to the groups with holds.
+fn add_copies(
+    con: &mut DatabaseConnection,
+    ops: &StatsOptions,
+    org_id: Option<i32>,
+    groups: &mut BTreeMap<GroupKey, Counts>,
+) -> Result<(), String> {
+    let params: [&(dyn postgres::types::ToSql + Sync); 3] =
+        [&ops.start_date, &ops.end_date, &org_id];
+
+    let mut rows = con
+        .client()
+        .query_raw(COPY_SQL, params)
+        .map_err(|e| db_err("Error running copy query", e))?;
+
+    let by_org = grouped(ops, "org");
+    let by_title = grouped(ops, "title");
+
+    // Copies have no title of their own; use the one found with the
+    // record's holds.
+    let titles: BTreeMap<i64, String> = groups
+        .keys()
+        .map(|(_, record, title, _)| (*record, title.to_string()))
+        .collect();
+
+    loop {
+        let row = match rows.next() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => return Err(db_err("Error reading copies", e)),
+        };
+
+        let record: i64 = row.get("record");
+
+        let key: GroupKey = (
+            if by_org {
+                row.get("org")
+            } else {
+                String::new()
+            },
+            if by_title { record } else { 0 },
+            match by_title {
+                true => titles.get(&record).cloned().unwrap_or_default(),
+                false => String::new(),
+            },
+            String::new(),
+        );
+
+        if let Some(counts) = groups.get_mut(&key) {
+            counts.copies += row.get::<_, i64>("copies");
+        }
+    }
+
+    Ok(())
+}
+
+fn round(n: f64) -> f64 {
+    (n * 100.0).round() / 100.0
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(|a, b| a.total_cmp(b));
+
+    let mid = values.len() / 2;
+
+    match values.len() % 2 {
+        0 => Some((values[mid - 1] + values[mid]) / 2.0),
+        _ => Some(values[mid]),
+    }
+}
+
+fn write_report(ops: &StatsOptions, groups: &mut BTreeMap<GroupKey, Counts>) -> Result<(), String> {
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+
+    let by_org = grouped(ops, "org");
+    let by_title = grouped(ops, "title");
+    let by_month = grouped(ops, "month");
+
+    let mut labels: Vec<&str> = Vec::new();
+    if by_org {
+        labels.push("org");
+    }
+    if by_title {
+        labels.extend(["record", "title"]);
+    }
+    if by_month {
+        labels.push("month");
+    }
+
+    labels.extend(match ops.report.as_str() {
+        "fill" => &[
+            "placed",
+            "filled",
+            "cancelled",
+            "open",
+            "fill_rate",
+            "avg_fill_days",
+            "median_fill_days",
+        ][..],
+        "cancel" => &["cause", "cancelled", "share"][..],
+        _ => &["holds", "copies", "holds_per_copy"][..],
+    });
+
+    writer.write_header(&labels)?;
+
+    for ((org, record, title, month), counts) in groups.iter_mut() {
+        let mut key: Vec<Cell> = Vec::new();
+        if by_org {
+            key.push(Cell::from(org));
+        }
+        if by_title {
+            key.push(Cell::from(match *record {
+                0 => None,
+                r => Some(r),
+            }));
+            key.push(Cell::from(title));
+        }
+        if by_month {
+            key.push(Cell::from(month));
+        }
+
+        match ops.report.as_str() {
+            "fill" => {
+                let avg = match counts.fill_days.len() {
+                    0 => None,
+                    n => Some(round(counts.fill_days.iter().sum::<f64>() / n as f64)),
+                };
+
+                let mut row = key;
+                row.extend([
+                    Cell::from(counts.placed),
+                    Cell::from(counts.filled),
+                    Cell::from(counts.cancelled),
+                    Cell::from(counts.placed - counts.filled - counts.cancelled),
+                    Cell::from(round(counts.filled as f64 / counts.placed as f64)),
+                    Cell::from(avg),
+                    Cell::from(median(&mut counts.fill_days).map(round)),
+                ]);
+
+                writer.write_row(&row)?;
+            }
+            "cancel" => {
+                for (cause, count) in &counts.causes {
+                    let mut row = key.clone();
+                    row.extend([
+                        Cell::from(cause),
+                        Cell::from(*count),
+                        Cell::from(round(*count as f64 / counts.cancelled as f64)),
+                    ]);
+
+                    writer.write_row(&row)?;
+                }
+            }
+            _ => {
+                let ratio = match counts.copies {
+                    0 => None,
+                    c => Some(round(counts.placed as f64 / c as f64)),
+                };
+
+                let mut row = key;
+                row.extend([
+                    Cell::from(counts.placed),
+                    Cell::from(counts.copies),
+                    Cell::from(ratio),
+                ]);
+
+                writer.write_row(&row)?;
+            }
+        }
+    }
+
+    writer.finish()
+}
+
+fn summarize(con: &mut DatabaseConnection, ops: &StatsOptions) -> Result<(), String> {
+    if !REPORTS.contains(&ops.report.as_str()) {
+        return Err(format!("Unknown report: {}", ops.report));
+    }
+
+    for dim in &ops.group_by {
+        if !DIMENSIONS.contains(&dim.as_str()) {
+            return Err(format!("Unknown group dimension: {dim}"));
+        }
+    }
+
+    if ops.report == "ratio" && grouped(ops, "month") {
+        return Err("The ratio report cannot be grouped by month".to_string());
+    }
+
+    con.connect()?;
+
+    let org_id = match ops.org_unit {
+        Some(ref s) => Some(org_id(con, s)?),
+        None => None,
+    };
+
+    let mut groups = BTreeMap::new();
+
+    let holds = aggregate_holds(con, ops, org_id, &mut groups)?;
+    info!("Counted {holds} holds in {} groups", groups.len());
+
+    if ops.report == "ratio" {
+        add_copies(con, ops, org_id, &mut groups)?;
+    }
+
+    con.disconnect();
+
+    write_report(ops, &mut groups)
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    summarize(&mut connection, &options)
+}
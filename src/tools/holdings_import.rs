@@ -0,0 +1,739 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::export::{Format, MarcWriter, RecordSink};
+use crate::marc;
+use log::{debug, info, warn};
+use marcutil::{Field, Record};
+use postgres as pg;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+
+/// Copy values a profile may map to holdings subfields.
+const ITEM_VALUES: &[&str] = &[
+    "barcode",
+    "call_number",
+    "owning_lib",
+    "circ_lib",
+    "location",
+    "circ_modifier",
+    "price",
+    "copy_number",
+    "status",
+];
+
+struct ImportOptions {
+    in_file: String,
+    binary: bool,
+    profile_file: String,
+    staff: i32,
+    reject_file: Option<String>,
+    dry_run: DryRun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MatchPoint {
+    /// Evergreen record ID in 901 $c, e.g. from a catalog export.
+    Id,
+    Tcn,
+    Oclc,
+    Isbn,
+}
+
+impl MatchPoint {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "id" => Ok(MatchPoint::Id),
+            "tcn" => Ok(MatchPoint::Tcn),
+            "oclc" => Ok(MatchPoint::Oclc),
+            "isbn" => Ok(MatchPoint::Isbn),
+            _ => Err(format!("Unknown match point: {name}")),
+        }
+    }
+}
+
+struct Profile {
+    holdings_tag: String,
+    match_points: Vec<MatchPoint>,
+    /// Copy value name => subfield code.
+    subfields: HashMap<String, String>,
+    /// Copy value name => value used when the subfield is missing.
+    defaults: HashMap<String, String>,
+    label_class: i64,
+    loan_duration: i32,
+    fine_level: i32,
+}
+
+/// One holdings field's copy.
+struct Holding {
+    barcode: String,
+    call_number: String,
+    owning_lib: String,
+    circ_lib: String,
+    location: String,
+    circ_modifier: Option<String>,
+    price: Option<String>,
+    copy_number: Option<i32>,
+    status: i32,
+}
+
+enum BibMatch {
+    One(i64),
+    None,
+    Many(Vec<i64>),
+}
+
+#[derive(Default)]
+struct Summary {
+    records: usize,
+    rejected: usize,
+    call_numbers: usize,
+    copies: usize,
+    skipped_copies: usize,
+}
+
+/// Lookups repeated for most records.
+#[derive(Default)]
+struct Cache {
+    orgs: HashMap<String, i32>,
+    locations: HashMap<(i32, String), i32>,
+}
+
+fn read_options() -> (ImportOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "in-file", "Shelf-Ready MARC File", "INPUT_FILE");
+    opts.optflag("", "binary", "Files are Binary MARC");
+    opts.optopt("", "profile", "Holdings Mapping Profile", "PROFILE_FILE");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "reject-file", "Unmatched Record File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ImportOptions {
+            in_file: cli::required(&params, "in-file"),
+            binary: params.opt_present("binary"),
+            profile_file: cli::required(&params, "profile"),
+            staff: cli::required(&params, "staff"),
+            reject_file: params.opt_str("reject-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin holdings-import -- --in-file shelf-ready.mrc --binary \
+        --profile vendor.json --staff 1 --reject-file unmatched.mrc
+
+Creates call numbers and copies from the holdings fields, e.g. 949,
+embedded in vendor shelf-ready MARC records, attached to the matching
+bib records already in the catalog.
+
+Each record is matched by the profile's match points in order, and
+the first which finds bibs decides: exactly one bib is a match, and
+more than one is rejected as ambiguous.  Records without a match, or
+whose holdings all fail, go to --reject-file for review.
+
+Holdings fields whose barcode is in use, or whose org unit or copy
+location is unknown, are skipped and logged.  Call numbers are reused
+when the bib already has one with the same label and owning library.
+
+The profile maps copy values to holdings subfields, with defaults
+for values missing from a field:
+
+    {{
+        "holdings_tag": "949",
+        "match": ["id", "oclc", "isbn", "tcn"],
+        "subfields": {{
+            "barcode": "i",
+            "call_number": "a",
+            "owning_lib": "b",
+            "circ_lib": "l",
+            "location": "c",
+            "circ_modifier": "m",
+            "price": "p"
+        }},
+        "defaults": {{"location": "Stacks", "status": 0}},
+        "label_class": 1,
+        "loan_duration": 2,
+        "fine_level": 2
+    }}
+
+Copy values are barcode, call_number, owning_lib, circ_lib (org unit
+shortnames), location (copy location name, owned by circ_lib or an
+ancestor), circ_modifier, price, copy_number and status (ID).
+barcode, call_number and owning_lib or circ_lib are required; each
+library defaults to the other.
+
+Match points are id (901 $c record ID), oclc (035 $a and 001), isbn
+(020 $a) and tcn (901 $a or 001).
+
+Options
+
+    --in-file
+        Vendor MARC file.  Required.
+
+    --binary
+        Files are binary MARC.  Otherwise, MARC XML.
+
+    --profile
+        Holdings mapping profile.  Required.
+
+    --staff
+        Staff user recorded as call number and copy creator.
+        Required.
+
+    --reject-file
+        Write rejected records to this file, in the input format.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Profile values may be JSON strings or numbers.
+fn value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn read_profile(path: &str) -> Result<Profile, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let profile: Value = match serde_json::from_str(&text) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let holdings_tag = profile["holdings_tag"].as_str().unwrap_or("949");
+
+    let match_points = match profile["match"].as_array() {
+        Some(list) => list
+            .iter()
+            .map(|m| MatchPoint::parse(m.as_str().unwrap_or("")))
+            .collect::<Result<Vec<MatchPoint>, String>>()?,
+        None => vec![MatchPoint::Id, MatchPoint::Oclc, MatchPoint::Isbn],
+    };
+
+    let mut subfields = HashMap::new();
+    if let Some(map) = profile["subfields"].as_object() {
+        for (name, code) in map {
+            if !ITEM_VALUES.contains(&name.as_str()) {
+                return Err(format!("Unknown copy value in {path}: {name}"));
+            }
+            if let Some(code) = code.as_str() {
+                subfields.insert(name.to_string(), code.to_string());
+            }
+        }
+    }
+
+    let mut defaults = HashMap::new();
+    if let Some(map) = profile["defaults"].as_object() {
+        for (name, value) in map {
+            if !ITEM_VALUES.contains(&name.as_str()) {
+                return Err(format!("Unknown copy value in {path}: {name}"));
+            }
+            if let Some(value) = value_string(value) {
+                defaults.insert(name.to_string(), value);
+            }
+        }
+    }
+
+    if !subfields.contains_key("barcode") {
+        return Err(format!("{path} does not map a barcode subfield"));
+    }
+
+    Ok(Profile {
+        holdings_tag: holdings_tag.to_string(),
+        match_points,
+        subfields,
+        defaults,
+        label_class: profile["label_class"].as_i64().unwrap_or(1),
+        loan_duration: profile["loan_duration"].as_i64().unwrap_or(2) as i32,
+        fine_level: profile["fine_level"].as_i64().unwrap_or(2) as i32,
+    })
+}
+
+/// A copy value from its subfield, or the profile default.
+fn item_value(profile: &Profile, field: &Field, name: &str) -> Option<String> {
+    let from_field = profile.subfields.get(name).and_then(|code| {
+        field
+            .subfields
+            .iter()
+            .find(|sf| sf.code.eq(code))
+            .map(|sf| sf.content.trim().to_string())
+            .filter(|v| !v.is_empty())
+    });
+
+    from_field.or_else(|| profile.defaults.get(name).cloned())
+}
+
+fn read_holding(profile: &Profile, field: &Field) -> Result<Holding, String> {
+    let value = |name: &str| item_value(profile, field, name);
+
+    let barcode = match value("barcode") {
+        Some(b) => b,
+        None => return Err("No barcode".to_string()),
+    };
+
+    let call_number = match value("call_number") {
+        Some(c) => c,
+        None => return Err(format!("Copy {barcode} has no call number")),
+    };
+
+    let (owning_lib, circ_lib) = match (value("owning_lib"), value("circ_lib")) {
+        (Some(o), Some(c)) => (o, c),
+        (Some(o), None) => (o.to_string(), o),
+        (None, Some(c)) => (c.to_string(), c),
+        (None, None) => return Err(format!("Copy {barcode} has no library")),
+    };
+
+    let location = match value("location") {
+        Some(l) => l,
+        None => return Err(format!("Copy {barcode} has no copy location")),
+    };
+
+    // Vendors send prices like $24.95.
+    let price = value("price")
+        .map(|p| {
+            p.chars()
+                .filter(|c| c.is_ascii_digit() || *c == '.')
+                .collect()
+        })
+        .filter(|p: &String| p.parse::<f64>().is_ok());
+
+    let copy_number = match value("copy_number") {
+        Some(n) => match n.parse::<i32>() {
+            Ok(n) => Some(n),
+            Err(_) => return Err(format!("Copy {barcode} has invalid copy number {n}")),
+        },
+        None => None,
+    };
+
+    let status = match value("status") {
+        Some(s) => match s.parse::<i32>() {
+            Ok(s) => s,
+            Err(_) => return Err(format!("Copy {barcode} has invalid status {s}")),
+        },
+        None => 0,
+    };
+
+    Ok(Holding {
+        barcode,
+        call_number,
+        owning_lib,
+        circ_lib,
+        location,
+        circ_modifier: value("circ_modifier"),
+        price,
+        copy_number,
+        status,
+    })
+}
+
+/// Non-deleted bibs found for one match point.
+fn find_bibs(
+    tx: &mut pg::Transaction,
+    point: MatchPoint,
+    record: &Record,
+) -> Result<Vec<i64>, String> {
+    let (sql, values): (&str, Vec<String>) = match point {
+        MatchPoint::Id => (
+            "SELECT id FROM biblio.record_entry WHERE id::TEXT = ANY($1) AND NOT deleted",
+            record
+                .get_values("901", "c")
+                .iter()
+                .map(|v| v.trim().to_string())
+                .filter(|v| v.parse::<i64>().is_ok())
+                .collect(),
+        ),
+        MatchPoint::Tcn => (
+            "SELECT id FROM biblio.record_entry WHERE tcn_value = ANY($1) AND NOT deleted",
+            marc::tcn(record).into_iter().collect(),
+        ),
+        // 035 values are NACO normalized, e.g. "ocolc ocm00012345".
+        MatchPoint::Oclc => (
+            r#"
+                SELECT DISTINCT mfr.record AS id
+                FROM metabib.real_full_rec mfr
+                    JOIN biblio.record_entry bre ON bre.id = mfr.record
+                WHERE mfr.tag = '035' AND mfr.subfield = 'a'
+                    AND mfr.value ~ ANY($1) AND NOT bre.deleted
+            "#,
+            marc::oclc_numbers(record)
+                .iter()
+                .map(|n| format!(r"^ocolc\s*[a-z]*0*{n}$"))
+                .collect(),
+        ),
+        MatchPoint::Isbn => (
+            r#"
+                SELECT DISTINCT mfr.record AS id
+                FROM metabib.real_full_rec mfr
+                    JOIN biblio.record_entry bre ON bre.id = mfr.record
+                WHERE mfr.tag = '020' AND mfr.subfield = 'a'
+                    AND mfr.value LIKE ANY($1) AND NOT bre.deleted
+            "#,
+            marc::isbns(record)
+                .iter()
+                .map(|i| format!("{}%", i.to_lowercase()))
+                .collect(),
+        ),
+    };
+
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let rows = tx
+        .query(sql, &[&values])
+        .map_err(|e| db_err("Error matching records", e))?;
+
+    Ok(rows.iter().map(|r| r.get("id")).collect())
+}
+
+fn match_bib(
+    tx: &mut pg::Transaction,
+    profile: &Profile,
+    record: &Record,
+) -> Result<BibMatch, String> {
+    for point in &profile.match_points {
+        let mut ids = find_bibs(tx, *point, record)?;
+        ids.sort();
+        ids.dedup();
+
+        debug!("Match point {point:?} found {ids:?}");
+
+        match ids.len() {
+            0 => continue,
+            1 => return Ok(BibMatch::One(ids[0])),
+            _ => return Ok(BibMatch::Many(ids)),
+        }
+    }
+
+    Ok(BibMatch::None)
+}
+
+fn org_id(tx: &mut pg::Transaction, cache: &mut Cache, shortname: &str) -> Result<i32, String> {
+    if let Some(id) = cache.orgs.get(shortname) {
+        return Ok(*id);
+    }
+
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+    let id: i32 = match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No such org unit: {shortname}")),
+        Err(e) => return Err(db_err("Error loading org unit", e)),
+    };
+
+    cache.orgs.insert(shortname.to_string(), id);
+
+    Ok(id)
+}
+
+/// The named location owned by the library or its nearest ancestor.
+fn location_id(
+    tx: &mut pg::Transaction,
+    cache: &mut Cache,
+    circ_lib: i32,
+    name: &str,
+) -> Result<i32, String> {
+    let key = (circ_lib, name.to_string());
+    if let Some(id) = cache.locations.get(&key) {
+        return Ok(*id);
+    }
+
+    let sql = r#"
+        SELECT acpl.id
+        FROM asset.copy_location acpl
+            JOIN actor.org_unit_ancestors($1) anc ON anc.id = acpl.owning_lib
+            JOIN actor.org_unit_type aout ON aout.id = anc.ou_type
+        WHERE acpl.name = $2 AND NOT acpl.deleted
+        ORDER BY aout.depth DESC
+        LIMIT 1
+    "#;
+
+    let id: i32 = match tx.query_opt(sql, &[&circ_lib, &name]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No copy location {name} for org unit {circ_lib}")),
+        Err(e) => return Err(db_err("Error loading copy location", e)),
+    };
+
+    cache.locations.insert(key, id);
+
+    Ok(id)
+}
+
+/// Create one holdings field's copy, and its call number if needed.
+/// Returns true if a call number was created.
+fn create_copy(
+    tx: &mut pg::Transaction,
+    ops: &ImportOptions,
+    profile: &Profile,
+    cache: &mut Cache,
+    bib: i64,
+    holding: &Holding,
+) -> Result<bool, String> {
+    let sql = "SELECT id FROM asset.copy WHERE barcode = $1 AND NOT deleted";
+    let existing = tx
+        .query_opt(sql, &[&holding.barcode])
+        .map_err(|e| db_err("Error checking barcode", e))?;
+
+    if existing.is_some() {
+        return Err(format!("Barcode {} is already in use", holding.barcode));
+    }
+
+    let owning_lib = org_id(tx, cache, &holding.owning_lib)?;
+    let circ_lib = org_id(tx, cache, &holding.circ_lib)?;
+    let location = location_id(tx, cache, circ_lib, &holding.location)?;
+
+    let sql = r#"
+        SELECT id FROM asset.call_number
+        WHERE record = $1 AND owning_lib = $2 AND label = $3
+            AND prefix = -1 AND suffix = -1 AND NOT deleted
+    "#;
+
+    let existing = tx
+        .query_opt(sql, &[&bib, &owning_lib, &holding.call_number])
+        .map_err(|e| db_err("Error loading call number", e))?;
+
+    let (call_number, created) = match existing {
+        Some(row) => (row.get::<_, i64>("id"), false),
+        None => {
+            let sql = r#"
+                INSERT INTO asset.call_number
+                    (creator, editor, record, owning_lib, label, label_class)
+                VALUES ($1::INT, $1::INT, $2, $3, $4, $5)
+                RETURNING id
+            "#;
+
+            let row = tx
+                .query_one(
+                    sql,
+                    &[
+                        &ops.staff,
+                        &bib,
+                        &owning_lib,
+                        &holding.call_number,
+                        &profile.label_class,
+                    ],
+                )
+                .map_err(|e| db_err("Error creating call number", e))?;
+
+            (row.get::<_, i64>("id"), true)
+        }
+    };
+
+    let sql = r#"
+        INSERT INTO asset.copy (
+            creator, editor, call_number, barcode, circ_lib, location,
+            status, loan_duration, fine_level, circ_modifier, price,
+            copy_number
+        ) VALUES (
+            $1::INT, $1::INT, $2, $3, $4, $5, $6, $7, $8, $9,
+            $10::TEXT::NUMERIC, $11
+        )
+    "#;
+
+    tx.execute(
+        sql,
+        &[
+            &ops.staff,
+            &call_number,
+            &holding.barcode,
+            &circ_lib,
+            &location,
+            &holding.status,
+            &profile.loan_duration,
+            &profile.fine_level,
+            &holding.circ_modifier,
+            &holding.price,
+            &holding.copy_number,
+        ],
+    )
+    .map_err(|e| db_err("Error creating copy", e))?;
+
+    debug!(
+        "Created copy {} on call number {call_number} for bib {bib}",
+        holding.barcode
+    );
+
+    Ok(created)
+}
+
+/// Import one record's holdings in its own transaction.  Returns the
+/// reason the record is rejected, if it is.
+fn import_record(
+    con: &mut DatabaseConnection,
+    ops: &ImportOptions,
+    profile: &Profile,
+    cache: &mut Cache,
+    summary: &mut Summary,
+    record: &Record,
+) -> Result<Option<String>, String> {
+    let fields: Vec<&Field> = record
+        .fields
+        .iter()
+        .filter(|f| f.tag == profile.holdings_tag)
+        .collect();
+
+    if fields.is_empty() {
+        return Ok(Some(format!("No {} holdings fields", profile.holdings_tag)));
+    }
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let bib = match match_bib(&mut tx, profile, record)? {
+        BibMatch::One(id) => id,
+        BibMatch::None => return Ok(Some("No matching bib record".to_string())),
+        BibMatch::Many(ids) => return Ok(Some(format!("Matches several bib records: {ids:?}"))),
+    };
+
+    let mut copies = 0;
+
+    for field in fields {
+        let holding = match read_holding(profile, field) {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("Bib {bib}: {e}; skipping");
+                summary.skipped_copies += 1;
+                continue;
+            }
+        };
+
+        // A failed statement aborts the transaction, so each copy gets
+        // a savepoint.
+        let mut sp = tx
+            .savepoint("holding")
+            .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+        match create_copy(&mut sp, ops, profile, cache, bib, &holding) {
+            Ok(created) => {
+                sp.commit()
+                    .map_err(|e| db_err("Error releasing savepoint", e))?;
+                copies += 1;
+                summary.copies += 1;
+                if created {
+                    summary.call_numbers += 1;
+                }
+            }
+            Err(e) => {
+                sp.rollback()
+                    .map_err(|e| db_err("Error rolling back savepoint", e))?;
+                warn!("Bib {bib}: {e}; skipping");
+                summary.skipped_copies += 1;
+            }
+        }
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    match copies {
+        0 => Ok(Some(format!("No holdings imported for bib {bib}"))),
+        _ => Ok(None),
+    }
+}
+
+fn import(con: &mut DatabaseConnection, ops: &ImportOptions) -> Result<(), String> {
+    let profile = read_profile(&ops.profile_file)?;
+    let records = marc::read_file(&ops.in_file, ops.binary)?;
+
+    let mut rejects = match &ops.reject_file {
+        Some(path) => {
+            let format = match ops.binary {
+                true => Format::Binary,
+                false => Format::Xml,
+            };
+            let mut writer = MarcWriter::create(path, format)?;
+            writer.begin()?;
+            Some(writer)
+        }
+        None => None,
+    };
+
+    con.connect()?;
+
+    let mut cache = Cache::default();
+    let mut summary = Summary::default();
+
+    for (idx, record) in records.iter().enumerate() {
+        summary.records += 1;
+
+        let reason = import_record(con, ops, &profile, &mut cache, &mut summary, record)?;
+
+        if let Some(reason) = reason {
+            let label = marc::tcn(record).unwrap_or_else(|| format!("#{}", idx + 1));
+            warn!("Rejected record {label}: {reason}");
+
+            summary.rejected += 1;
+
+            if let Some(ref mut writer) = rejects {
+                writer.write_record(&record.to_xml()?)?;
+            }
+        }
+    }
+
+    if let Some(ref mut writer) = rejects {
+        writer.finish()?;
+    }
+
+    con.disconnect();
+
+    audit::count("records", summary.records as u64);
+    audit::count("rejected", summary.rejected as u64);
+    audit::count("call_numbers", summary.call_numbers as u64);
+    audit::count("copies", summary.copies as u64);
+
+    let verb = ops.dry_run.past("Created");
+
+    info!(
+        "{} records, {} rejected; {verb} {} call numbers and {} copies; skipped {} holdings",
+        summary.records,
+        summary.rejected,
+        summary.call_numbers,
+        summary.copies,
+        summary.skipped_copies
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(import(&mut connection, &options))
+}
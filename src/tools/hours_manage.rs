@@ -0,0 +1,953 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::process;
+
+/// Evergreen numbers days from Monday: dow_0 is Monday.
+const DAYS: &[&str] = &[
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+const REPORT_COLUMNS: &[&str] = &[
+    "org_unit",
+    "day",
+    "old_open",
+    "old_close",
+    "new_open",
+    "new_close",
+    "result",
+];
+
+struct HoursOptions {
+    import_file: Option<String>,
+    export_file: Option<String>,
+    exceptions_file: Option<String>,
+    org_units: Vec<String>,
+    descendants: bool,
+    since: Option<String>,
+    report_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// One day's hours.  Open and close are equal on closed days.
+#[derive(Clone, PartialEq)]
+struct Day {
+    open: String,
+    close: String,
+    note: Option<String>,
+}
+
+/// A closing kept with the schedule.
+struct Exception {
+    org_unit: Option<String>,
+    start: String,
+    end: String,
+    full_day: bool,
+    reason: Option<String>,
+}
+
+/// Hours for an org unit, or for each --org-unit when None.
+struct Schedule {
+    org_unit: Option<String>,
+    days: [Option<Day>; 7],
+}
+
+fn read_options() -> (HoursOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "import-file", "Hours CSV or JSON File", "FILE");
+    opts.optopt("", "export-file", "Hours CSV or JSON File", "FILE");
+    opts.optopt("", "exceptions-file", "Closings CSV File", "FILE");
+    opts.optmulti("", "org-unit", "Org Units", "SHORTNAMES");
+    opts.optflag("", "descendants", "Include Descendant Org Units");
+    opts.optopt("", "since", "Export Exceptions Ending After", "DATE");
+    opts.optopt("", "report-file", "Report Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let import_file = params.opt_str("import-file");
+    let export_file = params.opt_str("export-file");
+
+    if import_file.is_some() == export_file.is_some() {
+        eprintln!("One of --import-file or --export-file is required");
+        process::exit(2);
+    }
+
+    let org_units = params
+        .opt_strs("org-unit")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        HoursOptions {
+            import_file,
+            export_file,
+            exceptions_file: params.opt_str("exceptions-file"),
+            org_units,
+            descendants: params.opt_present("descendants"),
+            since: params.opt_str("since"),
+            report_file: params.opt_str("report-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin hours-manage -- --export-file hours.json --org-unit SYS1 --descendants
+    cargo run --bin hours-manage -- --import-file winter-hours.csv --org-unit SYS1 \
+        --descendants --dry-run
+
+Exports and imports actor.hours_of_operation, with closed date
+exceptions, across org units for consortium-wide schedule changes.
+
+Files ending in .json are JSON.  Others are CSV.
+
+CSV hours files have a header row and these columns:
+
+    org_unit
+        Org unit shortname.  Lines without one apply to each
+        --org-unit.
+
+    day
+        monday through sunday.  Required.
+
+    open
+    close
+        Opening and closing times, e.g. 09:00 or 9:00 PM.  Use
+        closed, or equal times, for days the library is closed.
+
+    note
+        Optional note shown with the day's hours.
+
+JSON files hold an array of objects like:
+
+    {{
+        "org_unit": "BR1",
+        "hours": [
+            {{"day": "monday", "open": "09:00:00", "close": "17:00:00"}}
+        ],
+        "exceptions": [
+            {{"start": "2026-12-25 00:00:00-05", "end": "2026-12-25 23:59:59-05",
+             "full_day": true, "reason": "Holiday"}}
+        ]
+    }}
+
+Exceptions are closed dates.  With CSV, they are read from and
+written to --exceptions-file with org_unit, start, end, full_day and
+reason columns.
+
+Only days in the import are changed.  Org units without hours get
+Evergreen's defaults for other days.  Exceptions identical to an
+existing closing are skipped, as are overlapping ones, which are
+reported.
+
+Every imported day and exception is written to the report.  The
+import runs in one transaction.
+
+Options
+
+    --import-file
+        Path to the CSV or JSON file to import.
+
+    --export-file
+        Path to the CSV or JSON file to export.
+
+    --exceptions-file
+        CSV file of exceptions to import or export with CSV hours.
+
+    --org-unit
+        Org units to export, and to apply lines without an org_unit
+        to.  Repeatable or comma separated.  Exports all org units
+        with hours when not set.
+
+    --descendants
+        Include the descendants of each --org-unit.
+
+    --since
+        Export exceptions ending after this date, YYYY-MM-DD.
+        Defaults to today.
+
+    --report-file
+        Write the import report to this file.  Otherwise, writes to
+        STDOUT.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn is_json(path: &str) -> bool {
+    path.to_lowercase().ends_with(".json")
+}
+
+/// The day's index, from monday = 0.
+fn day_index(day: &str) -> Option<usize> {
+    let day = day.trim().to_lowercase();
+    DAYS.iter()
+        .position(|d| *d == day || (day.len() >= 3 && d.starts_with(&day)))
+}
+
+fn new_day(open: &str, close: Option<&str>, note: Option<String>) -> Day {
+    let closed = open.eq_ignore_ascii_case("closed");
+
+    Day {
+        open: if closed { "00:00" } else { open }.to_string(),
+        close: match close {
+            Some(c) if !closed => c.to_string(),
+            _ => "00:00".to_string(),
+        },
+        note,
+    }
+}
+
+/// Read CSV lines into a schedule per org unit.
+fn read_csv_hours(path: &str) -> Result<Vec<Schedule>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let (day_col, open_col) = match (col("day"), col("open")) {
+        (Some(d), Some(o)) => (d, o),
+        _ => return Err(format!("{path} requires day and open columns")),
+    };
+    let close_col = col("close");
+    let org_col = col("org_unit");
+    let note_col = col("note");
+
+    let mut schedules: Vec<Schedule> = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let field = |col: Option<usize>| {
+            col.and_then(|c| fields.get(c))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+
+        let day = match field(Some(day_col)).as_deref().and_then(day_index) {
+            Some(d) => d,
+            None => {
+                warn!("{path} line {}: invalid day: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        let open = match field(Some(open_col)) {
+            Some(o) => o,
+            None => {
+                warn!("{path} line {}: open required: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        let org_unit = field(org_col);
+
+        let pos = match schedules.iter().position(|s| s.org_unit == org_unit) {
+            Some(p) => p,
+            None => {
+                schedules.push(Schedule {
+                    org_unit,
+                    days: Default::default(),
+                });
+                schedules.len() - 1
+            }
+        };
+
+        schedules[pos].days[day] =
+            Some(new_day(&open, field(close_col).as_deref(), field(note_col)));
+    }
+
+    Ok(schedules)
+}
+
+fn read_csv_exceptions(path: &str) -> Result<Vec<Exception>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let (start_col, end_col) = match (col("start"), col("end")) {
+        (Some(s), Some(e)) => (s, e),
+        _ => return Err(format!("{path} requires start and end columns")),
+    };
+    let org_col = col("org_unit");
+    let full_day_col = col("full_day");
+    let reason_col = col("reason");
+
+    let mut exceptions = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let field = |col: Option<usize>| {
+            col.and_then(|c| fields.get(c))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+
+        let (start, end) = match (field(Some(start_col)), field(Some(end_col))) {
+            (Some(s), Some(e)) => (s, e),
+            _ => {
+                warn!("{path} line {}: start and end required: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        exceptions.push(Exception {
+            org_unit: field(org_col),
+            start,
+            end,
+            full_day: matches!(
+                field(full_day_col).as_deref(),
+                Some("t" | "true" | "TRUE" | "1")
+            ),
+            reason: field(reason_col),
+        });
+    }
+
+    Ok(exceptions)
+}
+
+fn read_json(path: &str) -> Result<(Vec<Schedule>, Vec<Exception>), String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let list: Value = match serde_json::from_str(&text) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let list = match list.as_array() {
+        Some(l) => l,
+        None => return Err(format!("{path} must contain an array")),
+    };
+
+    let mut schedules = Vec::new();
+    let mut exceptions = Vec::new();
+
+    for obj in list {
+        let org_unit = obj["org_unit"].as_str().map(|s| s.to_string());
+        let mut days: [Option<Day>; 7] = Default::default();
+
+        for hours in obj["hours"].as_array().unwrap_or(&Vec::new()) {
+            let day = match hours["day"].as_str().and_then(day_index) {
+                Some(d) => d,
+                None => {
+                    warn!("{path}: invalid day: {hours}");
+                    continue;
+                }
+            };
+
+            let open = match hours["open"].as_str() {
+                Some(o) => o,
+                None => {
+                    warn!("{path}: open required: {hours}");
+                    continue;
+                }
+            };
+
+            days[day] = Some(new_day(
+                open,
+                hours["close"].as_str(),
+                hours["note"].as_str().map(|s| s.to_string()),
+            ));
+        }
+
+        for exc in obj["exceptions"].as_array().unwrap_or(&Vec::new()) {
+            match (exc["start"].as_str(), exc["end"].as_str()) {
+                (Some(start), Some(end)) => exceptions.push(Exception {
+                    org_unit: org_unit.clone(),
+                    start: start.to_string(),
+                    end: end.to_string(),
+                    full_day: exc["full_day"].as_bool().unwrap_or(false),
+                    reason: exc["reason"].as_str().map(|s| s.to_string()),
+                }),
+                _ => warn!("{path}: exception start and end required: {exc}"),
+            }
+        }
+
+        schedules.push(Schedule { org_unit, days });
+    }
+
+    Ok((schedules, exceptions))
+}
+
+/// True if this database has the dow_N_note columns.
+fn has_notes(client: &mut pg::Client) -> Result<bool, String> {
+    let sql = r#"
+        SELECT 1 FROM information_schema.columns
+        WHERE table_schema = 'actor'
+            AND table_name = 'hours_of_operation'
+            AND column_name = 'dow_0_note'
+    "#;
+
+    match client.query_opt(sql, &[]) {
+        Ok(row) => Ok(row.is_some()),
+        Err(e) => Err(db_err("Error checking hours columns", e)),
+    }
+}
+
+/// The org units with descendants when requested, as shortname => id.
+fn expand_orgs(
+    client: &mut pg::Client,
+    ops: &HoursOptions,
+    shortnames: &[String],
+) -> Result<BTreeMap<String, i32>, String> {
+    let sql = r#"
+        SELECT d.id, d.shortname
+        FROM actor.org_unit aou
+            JOIN actor.org_unit_descendants(aou.id) d
+                ON $2 OR d.id = aou.id
+        WHERE aou.shortname = $1
+    "#;
+
+    let mut orgs = BTreeMap::new();
+
+    for shortname in shortnames {
+        let rows = client
+            .query(sql, &[shortname, &ops.descendants])
+            .map_err(|e| db_err("Error looking up org unit", e))?;
+
+        if rows.is_empty() {
+            return Err(format!("No such org unit: {shortname}"));
+        }
+
+        for row in rows {
+            orgs.insert(row.get("shortname"), row.get("id"));
+        }
+    }
+
+    Ok(orgs)
+}
+
+/// Current hours per org unit ID.
+fn load_hours(
+    client: &mut pg::Client,
+    notes: bool,
+    orgs: Option<Vec<i32>>,
+) -> Result<BTreeMap<i32, [Day; 7]>, String> {
+    let columns: Vec<String> = (0..7)
+        .map(|d| {
+            let note = match notes {
+                true => format!("dow_{d}_note"),
+                false => "NULL::TEXT".to_string(),
+            };
+            format!("dow_{d}_open::TEXT, dow_{d}_close::TEXT, {note}")
+        })
+        .collect();
+
+    let sql = format!(
+        "SELECT id, {} FROM actor.hours_of_operation WHERE $1::INT[] IS NULL OR id = ANY($1)",
+        columns.join(", ")
+    );
+
+    let rows = client
+        .query(sql.as_str(), &[&orgs])
+        .map_err(|e| db_err("Error loading hours", e))?;
+
+    let mut hours = BTreeMap::new();
+
+    for row in rows {
+        let days: [Day; 7] = std::array::from_fn(|d| Day {
+            open: row.get(1 + d * 3),
+            close: row.get(2 + d * 3),
+            note: row.get(3 + d * 3),
+        });
+
+        hours.insert(row.get::<_, i32>("id"), days);
+    }
+
+    Ok(hours)
+}
+
+fn export(con: &mut DatabaseConnection, ops: &HoursOptions, path: &str) -> Result<(), String> {
+    con.connect()?;
+
+    let notes = has_notes(con.client())?;
+
+    let orgs = match ops.org_units.is_empty() {
+        true => None,
+        false => Some(expand_orgs(con.client(), ops, &ops.org_units)?),
+    };
+
+    let hours = load_hours(
+        con.client(),
+        notes,
+        orgs.as_ref().map(|o| o.values().copied().collect()),
+    )?;
+
+    let ids: Vec<i32> = hours.keys().copied().collect();
+
+    let sql = r#"
+        SELECT aou.id, aou.shortname,
+            aouc.close_start::TEXT, aouc.close_end::TEXT,
+            aouc.full_day, aouc.reason
+        FROM actor.org_unit aou
+            LEFT JOIN actor.org_unit_closed aouc
+                ON aouc.org_unit = aou.id
+                AND aouc.close_end >= COALESCE($2::TEXT::DATE, CURRENT_DATE)
+        WHERE aou.id = ANY($1)
+        ORDER BY aou.shortname, aouc.close_start
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&ids, &ops.since])
+        .map_err(|e| db_err("Error loading exceptions", e))?;
+
+    // shortname => (id, exceptions), in shortname order.
+    let mut orgs: BTreeMap<String, (i32, Vec<Exception>)> = BTreeMap::new();
+
+    for row in rows {
+        let shortname: String = row.get("shortname");
+        let entry = orgs
+            .entry(shortname.clone())
+            .or_insert((row.get("id"), Vec::new()));
+
+        if let Some(start) = row.get::<_, Option<String>>("close_start") {
+            entry.1.push(Exception {
+                org_unit: Some(shortname),
+                start,
+                end: row.get("close_end"),
+                full_day: row.get("full_day"),
+                reason: row.get("reason"),
+            });
+        }
+    }
+
+    if is_json(path) {
+        let list: Vec<Value> = orgs
+            .iter()
+            .map(|(shortname, (id, exceptions))| {
+                let days: Vec<Value> = hours[id]
+                    .iter()
+                    .enumerate()
+                    .map(|(d, day)| {
+                        let mut obj = json!({
+                            "day": DAYS[d],
+                            "open": day.open,
+                            "close": day.close,
+                        });
+                        if let Some(ref note) = day.note {
+                            obj["note"] = json!(note);
+                        }
+                        obj
+                    })
+                    .collect();
+
+                let exceptions: Vec<Value> = exceptions
+                    .iter()
+                    .map(|e| {
+                        json!({
+                            "start": e.start,
+                            "end": e.end,
+                            "full_day": e.full_day,
+                            "reason": e.reason,
+                        })
+                    })
+                    .collect();
+
+                json!({
+                    "org_unit": shortname,
+                    "hours": days,
+                    "exceptions": exceptions,
+                })
+            })
+            .collect();
+
+        let text = serde_json::to_string_pretty(&Value::Array(list)).unwrap_or_default() + "\n";
+
+        if let Err(e) = fs::write(path, text) {
+            return Err(format!("Cannot write {path}: {e}"));
+        }
+    } else {
+        let mut writer = TableWriter::for_path(Some(path), Format::Csv)?;
+        writer.write_header(&["org_unit", "day", "open", "close", "note"])?;
+
+        for (shortname, (id, _)) in &orgs {
+            for (d, day) in hours[id].iter().enumerate() {
+                writer.write_row(&[
+                    Cell::from(shortname),
+                    Cell::from(DAYS[d]),
+                    Cell::from(&day.open),
+                    Cell::from(&day.close),
+                    Cell::from(day.note.as_deref()),
+                ])?;
+            }
+        }
+
+        writer.finish()?;
+
+        if let Some(ref exc_path) = ops.exceptions_file {
+            let mut writer = TableWriter::for_path(Some(exc_path), Format::Csv)?;
+            writer.write_header(&["org_unit", "start", "end", "full_day", "reason"])?;
+
+            for (shortname, (_, exceptions)) in &orgs {
+                for e in exceptions {
+                    writer.write_row(&[
+                        Cell::from(shortname),
+                        Cell::from(&e.start),
+                        Cell::from(&e.end),
+                        Cell::from(e.full_day),
+                        Cell::from(e.reason.as_deref()),
+                    ])?;
+                }
+            }
+
+            writer.finish()?;
+        }
+    }
+
+    info!("Exported hours for {} org units", orgs.len());
+
+    con.disconnect();
+
+    Ok(())
+}
+
+/// The day with times as Postgres writes them, so 9:00 matches
+/// 09:00:00.
+fn normalize_day(tx: &mut pg::Transaction, day: &Day) -> Result<Day, String> {
+    let sql = "SELECT $1::TEXT::TIME::TEXT AS open, $2::TEXT::TIME::TEXT AS close";
+
+    match tx.query_one(sql, &[&day.open, &day.close]) {
+        Ok(row) => Ok(Day {
+            open: row.get("open"),
+            close: row.get("close"),
+            note: day.note.clone(),
+        }),
+        Err(e) => Err(db_err(
+            &format!("Invalid hours {} - {}", day.open, day.close),
+            e,
+        )),
+    }
+}
+
+/// Write a day's hours, creating the org unit's row if needed.
+fn save_day(
+    tx: &mut pg::Transaction,
+    notes: bool,
+    org: i32,
+    day: usize,
+    hours: &Day,
+) -> Result<(), String> {
+    let (note_col, note_val) = match notes {
+        true => (format!(", dow_{day}_note"), ", $4".to_string()),
+        false => (String::new(), String::new()),
+    };
+
+    let sql = format!(
+        r#"
+        INSERT INTO actor.hours_of_operation (id, dow_{day}_open, dow_{day}_close{note_col})
+        VALUES ($1, $2::TEXT::TIME, $3::TEXT::TIME{note_val})
+        ON CONFLICT (id) DO UPDATE SET
+            dow_{day}_open = EXCLUDED.dow_{day}_open,
+            dow_{day}_close = EXCLUDED.dow_{day}_close
+            {}
+        "#,
+        match notes {
+            true => format!(", dow_{day}_note = EXCLUDED.dow_{day}_note"),
+            false => String::new(),
+        }
+    );
+
+    let result = match notes {
+        true => tx.execute(
+            sql.as_str(),
+            &[&org, &hours.open, &hours.close, &hours.note],
+        ),
+        false => tx.execute(sql.as_str(), &[&org, &hours.open, &hours.close]),
+    };
+
+    result
+        .map(|_| ())
+        .map_err(|e| db_err("Error saving hours", e))
+}
+
+/// Add an exception unless it matches or overlaps an existing closing.
+fn save_exception(tx: &mut pg::Transaction, org: i32, exc: &Exception) -> Result<String, String> {
+    let sql = r#"
+        SELECT id,
+            close_start = $2::TEXT::TIMESTAMPTZ
+                AND close_end = $3::TEXT::TIMESTAMPTZ AS same
+        FROM actor.org_unit_closed
+        WHERE org_unit = $1
+            AND close_start <= $3::TEXT::TIMESTAMPTZ
+            AND close_end >= $2::TEXT::TIMESTAMPTZ
+    "#;
+
+    let rows = tx
+        .query(sql, &[&org, &exc.start, &exc.end])
+        .map_err(|e| db_err("Error checking for overlaps", e))?;
+
+    if rows.iter().any(|r| r.get::<_, bool>("same")) {
+        return Ok("Exists".to_string());
+    }
+
+    if !rows.is_empty() {
+        let ids: Vec<String> = rows
+            .iter()
+            .map(|r| r.get::<_, i32>("id").to_string())
+            .collect();
+        return Err(format!("Overlaps closing {}", ids.join(" ")));
+    }
+
+    let sql = r#"
+        INSERT INTO actor.org_unit_closed
+            (org_unit, close_start, close_end, full_day, multi_day, reason)
+        VALUES (
+            $1,
+            $2::TEXT::TIMESTAMPTZ,
+            $3::TEXT::TIMESTAMPTZ,
+            $4,
+            $2::TEXT::TIMESTAMPTZ::DATE <> $3::TEXT::TIMESTAMPTZ::DATE,
+            $5
+        )
+    "#;
+
+    tx.execute(
+        sql,
+        &[&org, &exc.start, &exc.end, &exc.full_day, &exc.reason],
+    )
+    .map_err(|e| db_err("Error adding exception", e))?;
+
+    Ok("Added".to_string())
+}
+
+/// The org units a schedule or exception applies to.
+fn targets(
+    defaults: &BTreeMap<String, i32>,
+    cache: &mut HashMap<String, i32>,
+    tx: &mut pg::Transaction,
+    org_unit: Option<&str>,
+) -> Result<Vec<(String, i32)>, String> {
+    let shortname = match org_unit {
+        Some(s) => s,
+        None => return Ok(defaults.iter().map(|(s, i)| (s.clone(), *i)).collect()),
+    };
+
+    if let Some(id) = cache.get(shortname) {
+        return Ok(vec![(shortname.to_string(), *id)]);
+    }
+
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    let id: i32 = match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No such org unit: {shortname}")),
+        Err(e) => return Err(db_err("Error looking up org unit", e)),
+    };
+
+    cache.insert(shortname.to_string(), id);
+
+    Ok(vec![(shortname.to_string(), id)])
+}
+
+fn import(con: &mut DatabaseConnection, ops: &HoursOptions, path: &str) -> Result<(), String> {
+    let (schedules, mut exceptions) = match is_json(path) {
+        true => read_json(path)?,
+        false => (read_csv_hours(path)?, Vec::new()),
+    };
+
+    if let Some(ref exc_path) = ops.exceptions_file {
+        exceptions.extend(read_csv_exceptions(exc_path)?);
+    }
+
+    info!(
+        "Read {} schedules and {} exceptions",
+        schedules.len(),
+        exceptions.len()
+    );
+
+    con.connect()?;
+
+    let notes = has_notes(con.client())?;
+    let defaults = expand_orgs(con.client(), ops, &ops.org_units)?;
+    let current = load_hours(con.client(), notes, None)?;
+
+    let mut writer = TableWriter::for_path(ops.report_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let mut cache = HashMap::new();
+    let mut changed = 0;
+
+    for schedule in &schedules {
+        let orgs = targets(&defaults, &mut cache, &mut tx, schedule.org_unit.as_deref())?;
+
+        if orgs.is_empty() {
+            warn!("Schedule without org_unit and no --org-unit");
+        }
+
+        for (shortname, org) in orgs {
+            for (d, day) in schedule.days.iter().enumerate() {
+                let day = match day {
+                    Some(d) => d,
+                    None => continue,
+                };
+
+                let old = current.get(&org).map(|h| &h[d]);
+                let day = normalize_day(&mut tx, day)?;
+
+                let result = match old {
+                    Some(o) if *o == day => "Unchanged",
+                    Some(_) => "Updated",
+                    None => "Added",
+                };
+
+                if result != "Unchanged" {
+                    save_day(&mut tx, notes, org, d, &day)?;
+                    changed += 1;
+                }
+
+                writer.write_row(&[
+                    Cell::from(&shortname),
+                    Cell::from(DAYS[d]),
+                    Cell::from(old.map(|o| o.open.as_str())),
+                    Cell::from(old.map(|o| o.close.as_str())),
+                    Cell::from(&day.open),
+                    Cell::from(&day.close),
+                    Cell::from(result),
+                ])?;
+            }
+        }
+    }
+
+    for exc in &exceptions {
+        let orgs = targets(&defaults, &mut cache, &mut tx, exc.org_unit.as_deref())?;
+
+        for (shortname, org) in orgs {
+            let mut sp = tx
+                .savepoint("hours_exception")
+                .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+            let result = save_exception(&mut sp, org, exc);
+
+            let result = match result {
+                Ok(r) => {
+                    sp.commit()
+                        .map_err(|e| db_err("Cannot release savepoint", e))?;
+                    r
+                }
+                Err(e) => {
+                    sp.rollback()
+                        .map_err(|e| db_err("Cannot roll back savepoint", e))?;
+                    e
+                }
+            };
+
+            writer.write_row(&[
+                Cell::from(&shortname),
+                Cell::from("exception"),
+                Cell::Empty,
+                Cell::Empty,
+                Cell::from(&exc.start),
+                Cell::from(&exc.end),
+                Cell::from(result),
+            ])?;
+        }
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("days", changed as u64);
+
+    info!("{} hours for {changed} days", ops.dry_run.past("Saved"));
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let result = match (&options.export_file, &options.import_file) {
+        (Some(path), _) => export(&mut connection, &options, path),
+        (None, Some(path)) => import(&mut connection, &options, path),
+        (None, None) => Ok(()),
+    };
+
+    audit::finish(result)
+}
@@ -0,0 +1,387 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use log::info;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+
+const SEARCH_CLASSES: &[&str] = &[
+    "keyword",
+    "title",
+    "author",
+    "subject",
+    "series",
+    "identifier",
+];
+
+struct CheckOptions {
+    min_id: i64,
+    max_id: i64,
+    batch_size: i64,
+    classes: Vec<String>,
+    out_file: Option<String>,
+    queue: bool,
+    sql_file: Option<String>,
+    dry_run: DryRun,
+}
+
+fn read_options() -> (CheckOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "batch-size", "Records per Query", "BATCH_SIZE");
+    opts.optmulti("", "class", "Search Class to Check, Repeatable", "CLASS");
+    opts.optopt("", "out-file", "Report File", "FILE");
+    opts.optflag("", "queue", "Queue Problem Records for Reingest");
+    opts.optopt("", "sql-file", "Write parallel-ingest SQL File", "FILE");
+
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let mut classes = params.opt_strs("class");
+    if classes.is_empty() {
+        classes = vec!["keyword".to_string(), "title".to_string()];
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        CheckOptions {
+            min_id: params.opt_get_default("min-id", 0).unwrap(),
+            max_id: params.opt_get_default("max-id", 0).unwrap(),
+            batch_size: params.opt_get_default("batch-size", 50000).unwrap(),
+            classes,
+            out_file: params.opt_str("out-file"),
+            queue: params.opt_present("queue"),
+            sql_file: params.opt_str("sql-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin index-check -- --queue --sql-file /tmp/reingest.sql
+    cargo run --bin parallel-ingest -- --sql-file /tmp/reingest.sql --do-search --do-attrs
+
+Compares bib records against their search index rows and reports
+problem records as CSV (record ID, problems).
+
+Problems checked:
+
+    missing_CLASS
+        Non-deleted record has no metabib.CLASS_field_entry rows.
+
+    missing_attrs
+        Non-deleted record has no record attribute vector.
+
+    missing_metarecord
+        Non-deleted record is not mapped to a metarecord.
+
+    deleted_with_entries
+        Deleted record still has keyword index rows.
+
+    orphan_field_CLASS
+        Record has CLASS index rows for a metabib field which is
+        no longer defined.
+
+Options
+
+    --min-id
+    --max-id
+        Limit to records with IDs in this range.
+
+    --batch-size
+        Number of record IDs covered by each query.  Defaults to 50000.
+
+    --class
+        Search class to check for missing index rows.  Repeatable.
+        Defaults to keyword and title.  One of:
+        keyword, title, author, subject, series, identifier.
+
+    --out-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+
+    --queue
+        Add non-deleted problem records to egutil.reingest_queue,
+        creating the table if needed.
+
+    --sql-file
+        Write a query selecting the non-deleted problem records for
+        use with parallel-ingest --sql-file.  With --queue, the query
+        selects from the queue table, except on a dry run.
+
+    --dry-run
+        Queue the problem records, then roll back, reporting what a
+        real run would do.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+/// Each check returns IDs within a record ID window, $1 < id <= $2.
+fn checks(ops: &CheckOptions) -> Vec<(String, String)> {
+    let mut checks = Vec::new();
+
+    for class in &ops.classes {
+        checks.push((
+            format!("missing_{class}"),
+            format!(
+                r#"
+                SELECT bre.id FROM biblio.record_entry bre
+                WHERE bre.id > $1 AND bre.id <= $2 AND NOT bre.deleted
+                    AND NOT EXISTS (
+                        SELECT 1 FROM metabib.{class}_field_entry e WHERE e.source = bre.id
+                    )
+                "#
+            ),
+        ));
+
+        checks.push((
+            format!("orphan_field_{class}"),
+            format!(
+                r#"
+                SELECT DISTINCT e.source AS id FROM metabib.{class}_field_entry e
+                WHERE e.source > $1 AND e.source <= $2
+                    AND NOT EXISTS (SELECT 1 FROM config.metabib_field f WHERE f.id = e.field)
+                "#
+            ),
+        ));
+    }
+
+    checks.push((
+        "missing_attrs".to_string(),
+        r#"
+        SELECT bre.id FROM biblio.record_entry bre
+        WHERE bre.id > $1 AND bre.id <= $2 AND NOT bre.deleted
+            AND NOT EXISTS (
+                SELECT 1 FROM metabib.record_attr_vector_list v WHERE v.source = bre.id
+            )
+        "#
+        .to_string(),
+    ));
+
+    checks.push((
+        "missing_metarecord".to_string(),
+        r#"
+        SELECT bre.id FROM biblio.record_entry bre
+        WHERE bre.id > $1 AND bre.id <= $2 AND NOT bre.deleted
+            AND NOT EXISTS (
+                SELECT 1 FROM metabib.metarecord_source_map m WHERE m.source = bre.id
+            )
+        "#
+        .to_string(),
+    ));
+
+    checks.push((
+        "deleted_with_entries".to_string(),
+        r#"
+        SELECT bre.id FROM biblio.record_entry bre
+        WHERE bre.id > $1 AND bre.id <= $2 AND bre.deleted
+            AND EXISTS (
+                SELECT 1 FROM metabib.keyword_field_entry e WHERE e.source = bre.id
+            )
+        "#
+        .to_string(),
+    ));
+
+    checks
+}
+
+fn record_range(con: &mut DatabaseConnection, ops: &CheckOptions) -> Result<(i64, i64), String> {
+    let sql = "SELECT COALESCE(MAX(id), 0) AS max FROM biblio.record_entry";
+
+    let max: i64 = match con.client().query_one(sql, &[]) {
+        Ok(row) => row.get("max"),
+        Err(e) => return Err(format!("Error finding max record ID: {e}")),
+    };
+
+    // Start just below min-id so the window includes it.
+    let start = (ops.min_id - 1).max(0);
+
+    let end = match ops.max_id {
+        0 => max,
+        m => m.min(max),
+    };
+
+    Ok((start, end))
+}
+
+fn queue_records(
+    con: &mut DatabaseConnection,
+    ops: &CheckOptions,
+    problems: &BTreeMap<i64, Vec<String>>,
+) -> Result<usize, String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.reingest_queue (
+            record      BIGINT PRIMARY KEY,
+            reason      TEXT NOT NULL,
+            queued_time TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+    "#;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    if let Err(e) = tx.batch_execute(sql) {
+        return Err(format!("Cannot create egutil.reingest_queue: {e}"));
+    }
+
+    let sql = r#"
+        INSERT INTO egutil.reingest_queue (record, reason) VALUES ($1, $2)
+        ON CONFLICT (record) DO UPDATE
+            SET reason = EXCLUDED.reason, queued_time = NOW()
+    "#;
+
+    let mut count = 0;
+    for (id, reasons) in reingest_records(problems) {
+        let reason = reasons.join(" ");
+        if let Err(e) = tx.execute(sql, &[&id, &reason]) {
+            return Err(format!("Error queueing record {id}: {e}"));
+        }
+        count += 1;
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    Ok(count)
+}
+
+/// Records which need a reingest, i.e. not deleted.
+fn reingest_records(
+    problems: &BTreeMap<i64, Vec<String>>,
+) -> impl Iterator<Item = (&i64, &Vec<String>)> {
+    problems
+        .iter()
+        .filter(|(_, reasons)| !reasons.iter().any(|r| r == "deleted_with_entries"))
+}
+
+fn write_sql_file(
+    path: &str,
+    ops: &CheckOptions,
+    problems: &BTreeMap<i64, Vec<String>>,
+) -> Result<(), String> {
+    // A dry run leaves the queue as it was.
+    let sql = if ops.queue && !ops.dry_run.enabled() {
+        "SELECT record AS id FROM egutil.reingest_queue ORDER BY record\n".to_string()
+    } else {
+        let ids: Vec<String> = reingest_records(problems)
+            .map(|(id, _)| id.to_string())
+            .collect();
+        format!("SELECT UNNEST('{{{}}}'::BIGINT[]) AS id\n", ids.join(","))
+    };
+
+    match fs::write(path, sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot write {path}: {e}")),
+    }
+}
+
+fn check(con: &mut DatabaseConnection, ops: &CheckOptions) -> Result<(), String> {
+    for class in &ops.classes {
+        if !SEARCH_CLASSES.contains(&class.as_str()) {
+            return Err(format!("Unknown search class: {class}"));
+        }
+    }
+
+    con.connect()?;
+
+    let (start, end) = record_range(con, ops)?;
+    let checks = checks(ops);
+
+    let mut problems: BTreeMap<i64, Vec<String>> = BTreeMap::new();
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+
+    let mut low = start;
+    while low < end {
+        let high = (low + ops.batch_size).min(end);
+
+        for (name, sql) in &checks {
+            let rows = match con.client().query(sql.as_str(), &[&low, &high]) {
+                Ok(r) => r,
+                Err(e) => return Err(format!("Error running check {name}: {e}")),
+            };
+
+            for row in rows {
+                let id: i64 = row.get("id");
+                problems.entry(id).or_default().push(name.to_string());
+                *counts.entry(name.as_str()).or_default() += 1;
+            }
+        }
+
+        info!(
+            "Checked records through {high} of {end}; {} with problems",
+            problems.len()
+        );
+
+        low = high;
+    }
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let mut out = String::from("record,problems\n");
+    for (id, reasons) in &problems {
+        out += &format!("{id},{}\n", reasons.join(" "));
+    }
+
+    if let Err(e) = writer.write_all(out.as_bytes()) {
+        return Err(format!("Error writing report: {e}"));
+    }
+
+    if ops.queue {
+        let queued = queue_records(con, ops, &problems)?;
+        audit::count("queued", queued as u64);
+        info!(
+            "{} {queued} records in egutil.reingest_queue",
+            ops.dry_run.past("Queued")
+        );
+    }
+
+    con.disconnect();
+
+    if let Some(ref path) = ops.sql_file {
+        write_sql_file(path, ops, &problems)?;
+        info!("Wrote parallel-ingest query to {path}");
+    }
+
+    audit::count("problems", problems.len() as u64);
+
+    for (name, count) in counts {
+        info!("{name}: {count}");
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(check(&mut connection, &options))
+}
@@ -0,0 +1,646 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::edi;
+use crate::isbn;
+use crate::money::Money;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+const REPORT_COLUMNS: &[&str] = &[
+    "purchase_order",
+    "lineitem",
+    "invoice",
+    "problem",
+    "invoiced",
+    "received",
+    "invoiced_price",
+    "expected_price",
+    "detail",
+];
+
+struct ReconcileOptions {
+    invoice_files: Vec<String>,
+    tolerance: Money,
+    all: bool,
+    out_file: Option<String>,
+}
+
+/// Invoiced copies of one lineitem, summed over the invoice's lines.
+struct InvoiceLine {
+    invoice: String,
+    purchase_order: Option<i32>,
+    lineitem: Option<i32>,
+    isbn: Option<String>,
+    count: i64,
+    cost: Money,
+}
+
+/// What Evergreen has for one lineitem.
+#[derive(Default)]
+struct LineitemState {
+    ordered: i64,
+    received: i64,
+    estimated_price: Option<Money>,
+    isbns: Vec<String>,
+    /// Copies invoiced by invoice number.
+    invoiced: HashMap<String, i64>,
+}
+
+/// A report row.
+struct Finding {
+    purchase_order: Option<i32>,
+    lineitem: Option<i32>,
+    invoice: Option<String>,
+    problem: &'static str,
+    invoiced: Option<i64>,
+    received: Option<i64>,
+    invoiced_price: Option<Money>,
+    expected_price: Option<Money>,
+    detail: String,
+}
+
+fn read_options() -> (ReconcileOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "invoice-file", "Vendor Invoice File", "FILE");
+    opts.optopt("", "tolerance", "Allowed Price Difference", "AMOUNT");
+    opts.optflag("", "all", "Report Lines Without Problems");
+    opts.optopt("", "out-file", "Report Output File", "FILE");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let invoice_files = params.opt_strs("invoice-file");
+    if invoice_files.is_empty() {
+        eprintln!("--invoice-file is required");
+        std::process::exit(2);
+    }
+
+    let tolerance = match params.opt_str("tolerance") {
+        Some(t) => Money::parse(&t).expect("Invalid --tolerance"),
+        None => Money::default(),
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ReconcileOptions {
+            invoice_files,
+            tolerance,
+            all: params.opt_present("all"),
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin invoice-reconcile -- --invoice-file inv-20261001.edi --out-file discrepancies.csv
+    cargo run --bin invoice-reconcile -- --invoice-file shelf-ready.csv --tolerance 0.05 --all
+
+Compares vendor invoices for shelf-ready orders against the received
+lineitems of their purchase orders, and reports discrepancies keyed
+by purchase order and lineitem.  Nothing is changed.
+
+Invoice files are EDI INVOIC messages, or CSV with a header row and
+these columns:
+
+    invoice         Invoice number.  Defaults to the file name.
+    purchase_order  PO ID.  Required.
+    lineitem        Lineitem ID.  Matched by ISBN when absent.
+    isbn            ISBN, for matching lines without a lineitem.
+    quantity        Invoiced copies.  Defaults to 1.
+    price           Unit price.
+    amount          Line total, instead of price.
+
+Lines for the same lineitem on one invoice are combined.
+
+Problems
+
+    no_po               The line references no purchase order.
+    unknown_po          No such purchase order.
+    unmatched           No lineitem on the PO matches the line.
+    not_received        More copies invoiced than received.
+    over_invoiced       More copies invoiced than ordered.
+    price_mismatch      Unit price differs from the lineitem's
+                        estimated price by more than --tolerance.
+    duplicate_invoice   The lineitem is already on another invoice.
+    not_invoiced        Received copies of a lineitem on an
+                        invoiced PO appear on no invoice.
+    ok                  No problems, reported with --all.
+
+Options
+
+    --invoice-file
+        Vendor invoice file.  Repeatable.  Required.  Files are EDI
+        when they start with a UNA or UNB segment.
+
+    --tolerance
+        Allowed unit price difference, e.g. 0.05.  Defaults to 0.
+
+    --all
+        Also report lines without problems.
+
+    --out-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn money(amount: f64) -> Money {
+    Money::parse(&format!("{amount:.2}")).unwrap_or_default()
+}
+
+/// Lines of EDI INVOIC messages.
+fn read_edi(path: &str, text: &str) -> Result<Vec<InvoiceLine>, String> {
+    let mut lines = Vec::new();
+
+    for msg in edi::parse_messages(text).map_err(|e| format!("{path}: {e}"))? {
+        if msg.kind != "INVOIC" {
+            warn!("{path}: skipping {} message", msg.kind);
+            continue;
+        }
+
+        let doc = edi::read_document(&msg);
+
+        for line in &doc.lines {
+            lines.push(InvoiceLine {
+                invoice: doc.number.to_string(),
+                purchase_order: line.purchase_order.or(doc.purchase_order),
+                lineitem: line.lineitem,
+                isbn: isbn::normalize(&line.ident),
+                count: line.invoiced_count() as i64,
+                cost: money(line.invoiced_cost()),
+            });
+        }
+    }
+
+    Ok(lines)
+}
+
+fn read_csv(path: &str, text: &str) -> Result<Vec<InvoiceLine>, String> {
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let po_col = match col("purchase_order") {
+        Some(c) => c,
+        None => return Err(format!("{path} requires a purchase_order column")),
+    };
+
+    let invoice_col = col("invoice");
+    let lineitem_col = col("lineitem");
+    let isbn_col = col("isbn");
+    let quantity_col = col("quantity");
+    let price_col = col("price");
+    let amount_col = col("amount");
+
+    let file_name = Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+
+    let mut invoice_lines = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let field = |col: Option<usize>| {
+            col.and_then(|c| fields.get(c))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+
+        let amount = |col: Option<usize>| match field(col) {
+            Some(v) => match Money::parse(&v) {
+                Ok(m) => Ok(Some(m)),
+                Err(e) => Err(format!("{path} line {}: {e}", idx + 1)),
+            },
+            None => Ok(None),
+        };
+
+        let count: i64 = match field(quantity_col) {
+            Some(q) => match q.parse() {
+                Ok(n) => n,
+                Err(_) => return Err(format!("{path} line {}: invalid quantity {q}", idx + 1)),
+            },
+            None => 1,
+        };
+
+        let cost = match (amount(amount_col)?, amount(price_col)?) {
+            (Some(a), _) => a,
+            (None, Some(p)) => p * count,
+            (None, None) => Money::default(),
+        };
+
+        invoice_lines.push(InvoiceLine {
+            invoice: field(invoice_col).unwrap_or_else(|| file_name.to_string()),
+            purchase_order: field(Some(po_col)).and_then(|v| v.parse().ok()),
+            lineitem: field(lineitem_col).and_then(|v| v.parse().ok()),
+            isbn: field(isbn_col).and_then(|v| isbn::normalize(&v)),
+            count,
+            cost,
+        });
+    }
+
+    Ok(invoice_lines)
+}
+
+fn read_invoice(path: &str) -> Result<Vec<InvoiceLine>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let start = text.trim_start();
+
+    match start.starts_with("UNA") || start.starts_with("UNB") {
+        true => read_edi(path, &text),
+        false => read_csv(path, &text),
+    }
+}
+
+/// Lineitems of the PO, or None if there is no such PO.
+fn load_po(
+    con: &mut DatabaseConnection,
+    po_id: i32,
+) -> Result<Option<BTreeMap<i32, LineitemState>>, String> {
+    let sql = "SELECT id FROM acq.purchase_order WHERE id = $1";
+    let exists = con
+        .client()
+        .query_opt(sql, &[&po_id])
+        .map_err(|e| db_err("Error loading purchase order", e))?;
+
+    if exists.is_none() {
+        return Ok(None);
+    }
+
+    let sql = r#"
+        SELECT
+            li.id,
+            li.estimated_price::TEXT AS estimated_price,
+            COUNT(lid.id) FILTER (WHERE lid.cancel_reason IS NULL) AS ordered,
+            COUNT(lid.id) FILTER (WHERE lid.recv_time IS NOT NULL) AS received
+        FROM acq.lineitem li
+            LEFT JOIN acq.lineitem_detail lid ON lid.lineitem = li.id
+        WHERE li.purchase_order = $1
+        GROUP BY li.id
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&po_id])
+        .map_err(|e| db_err("Error loading lineitems", e))?;
+
+    let mut lineitems = BTreeMap::new();
+
+    for row in rows {
+        let price: Option<String> = row.get("estimated_price");
+
+        lineitems.insert(
+            row.get::<_, i32>("id"),
+            LineitemState {
+                ordered: row.get("ordered"),
+                received: row.get("received"),
+                estimated_price: price.and_then(|p| Money::parse(&p).ok()),
+                ..Default::default()
+            },
+        );
+    }
+
+    let sql = r#"
+        SELECT lia.lineitem, lia.attr_value
+        FROM acq.lineitem_attr lia
+            JOIN acq.lineitem li ON li.id = lia.lineitem
+        WHERE li.purchase_order = $1 AND lia.attr_name = 'isbn'
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&po_id])
+        .map_err(|e| db_err("Error loading lineitem ISBNs", e))?;
+
+    for row in rows {
+        let value: String = row.get("attr_value");
+
+        if let (Some(li), Some(number)) = (
+            lineitems.get_mut(&row.get::<_, i32>("lineitem")),
+            isbn::normalize(&value),
+        ) {
+            // Match either form of the ISBN.
+            li.isbns.extend(isbn::counterpart(&number));
+            li.isbns.push(number);
+        }
+    }
+
+    let sql = r#"
+        SELECT aie.lineitem, ai.inv_ident, SUM(aie.inv_item_count)::BIGINT AS count
+        FROM acq.invoice_entry aie
+            JOIN acq.invoice ai ON ai.id = aie.invoice
+        WHERE aie.purchase_order = $1 AND aie.lineitem IS NOT NULL
+        GROUP BY 1, 2
+    "#;
+
+    let rows = con
+        .client()
+        .query(sql, &[&po_id])
+        .map_err(|e| db_err("Error loading invoice entries", e))?;
+
+    for row in rows {
+        if let Some(li) = lineitems.get_mut(&row.get::<_, i32>("lineitem")) {
+            li.invoiced.insert(
+                row.get("inv_ident"),
+                row.get::<_, Option<i64>>("count").unwrap_or(0),
+            );
+        }
+    }
+
+    Ok(Some(lineitems))
+}
+
+/// Unit price, rounded to the cent.
+fn unit_price(cost: Money, count: i64) -> Money {
+    match count {
+        0 => cost,
+        n => Money::from_cents((cost.cents() + n / 2) / n),
+    }
+}
+
+fn check_line(
+    ops: &ReconcileOptions,
+    line: &InvoiceLine,
+    po_id: i32,
+    lineitem: i32,
+    state: &LineitemState,
+    findings: &mut Vec<Finding>,
+) {
+    let price = unit_price(line.cost, line.count);
+
+    let finding = |problem: &'static str, detail: String| Finding {
+        purchase_order: Some(po_id),
+        lineitem: Some(lineitem),
+        invoice: Some(line.invoice.to_string()),
+        problem,
+        invoiced: Some(line.count),
+        received: Some(state.received),
+        invoiced_price: Some(price),
+        expected_price: state.estimated_price,
+        detail,
+    };
+
+    let start = findings.len();
+
+    if line.count > state.received {
+        findings.push(finding(
+            "not_received",
+            format!("{} invoiced, {} received", line.count, state.received),
+        ));
+    }
+
+    if line.count > state.ordered {
+        findings.push(finding(
+            "over_invoiced",
+            format!("{} invoiced, {} ordered", line.count, state.ordered),
+        ));
+    }
+
+    if let Some(expected) = state.estimated_price {
+        if (price - expected).abs().cents() > ops.tolerance.cents() {
+            findings.push(finding(
+                "price_mismatch",
+                format!("Invoiced at {price}, estimated at {expected}"),
+            ));
+        }
+    }
+
+    let others: Vec<&String> = state
+        .invoiced
+        .keys()
+        .filter(|i| **i != line.invoice)
+        .collect();
+
+    if !others.is_empty() {
+        let others: Vec<&str> = others.iter().map(|i| i.as_str()).collect();
+        findings.push(finding(
+            "duplicate_invoice",
+            format!("Also on invoice {}", others.join(", ")),
+        ));
+    }
+
+    if ops.all && findings.len() == start {
+        findings.push(finding("ok", String::new()));
+    }
+}
+
+/// Combine lines for the same lineitem on the same invoice.
+fn combine(lines: Vec<InvoiceLine>) -> Vec<InvoiceLine> {
+    let mut combined: Vec<InvoiceLine> = Vec::new();
+
+    for line in lines {
+        let existing = combined.iter_mut().find(|l| {
+            line.lineitem.is_some()
+                && l.lineitem == line.lineitem
+                && l.purchase_order == line.purchase_order
+                && l.invoice == line.invoice
+        });
+
+        match existing {
+            Some(l) => {
+                l.count += line.count;
+                l.cost += line.cost;
+            }
+            None => combined.push(line),
+        }
+    }
+
+    combined
+}
+
+fn reconcile(con: &mut DatabaseConnection, ops: &ReconcileOptions) -> Result<(), String> {
+    let mut lines = Vec::new();
+    for path in &ops.invoice_files {
+        lines.extend(read_invoice(path)?);
+    }
+
+    info!("Read {} invoice lines", lines.len());
+
+    con.connect()?;
+
+    let mut pos: BTreeMap<i32, Option<BTreeMap<i32, LineitemState>>> = BTreeMap::new();
+    let mut findings = Vec::new();
+
+    // Lines matched by ISBN get their lineitem before combining.
+    for line in lines.iter_mut() {
+        let po_id = match line.purchase_order {
+            Some(p) => p,
+            None => continue,
+        };
+
+        if let Entry::Vacant(e) = pos.entry(po_id) {
+            e.insert(load_po(con, po_id)?);
+        }
+
+        if let (None, Some(number), Some(Some(lineitems))) =
+            (line.lineitem, line.isbn.as_ref(), pos.get(&po_id))
+        {
+            line.lineitem = lineitems
+                .iter()
+                .find(|(_, li)| li.isbns.contains(number))
+                .map(|(id, _)| *id);
+        }
+    }
+
+    let lines = combine(lines);
+
+    // Lineitems found on any invoice, by PO.
+    let mut invoiced: HashMap<i32, Vec<i32>> = HashMap::new();
+
+    for line in &lines {
+        let unmatched = |problem: &'static str, detail: String| Finding {
+            purchase_order: line.purchase_order,
+            lineitem: line.lineitem,
+            invoice: Some(line.invoice.to_string()),
+            problem,
+            invoiced: Some(line.count),
+            received: None,
+            invoiced_price: Some(unit_price(line.cost, line.count)),
+            expected_price: None,
+            detail,
+        };
+
+        let po_id = match line.purchase_order {
+            Some(p) => p,
+            None => {
+                findings.push(unmatched("no_po", String::new()));
+                continue;
+            }
+        };
+
+        let lineitems = match pos.get(&po_id) {
+            Some(Some(l)) => l,
+            _ => {
+                findings.push(unmatched("unknown_po", String::new()));
+                continue;
+            }
+        };
+
+        let (lineitem, state) = match line.lineitem.and_then(|id| lineitems.get_key_value(&id)) {
+            Some((id, state)) => (*id, state),
+            None => {
+                let detail = match (line.lineitem, &line.isbn) {
+                    (Some(id), _) => format!("Lineitem {id} is not on the PO"),
+                    (None, Some(i)) => format!("No lineitem with ISBN {i}"),
+                    (None, None) => "No lineitem or ISBN".to_string(),
+                };
+                findings.push(unmatched("unmatched", detail));
+                continue;
+            }
+        };
+
+        invoiced.entry(po_id).or_default().push(lineitem);
+
+        check_line(ops, line, po_id, lineitem, state, &mut findings);
+    }
+
+    for (po_id, lineitems) in &pos {
+        let lineitems = match lineitems {
+            Some(l) => l,
+            None => continue,
+        };
+
+        let seen = invoiced.get(po_id).cloned().unwrap_or_default();
+
+        for (id, state) in lineitems {
+            if state.received == 0 || seen.contains(id) || !state.invoiced.is_empty() {
+                continue;
+            }
+
+            findings.push(Finding {
+                purchase_order: Some(*po_id),
+                lineitem: Some(*id),
+                invoice: None,
+                problem: "not_invoiced",
+                invoiced: Some(0),
+                received: Some(state.received),
+                invoiced_price: None,
+                expected_price: state.estimated_price,
+                detail: format!("{} received", state.received),
+            });
+        }
+    }
+
+    con.disconnect();
+
+    findings.sort_by_key(|f| (f.purchase_order, f.lineitem));
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    for f in &findings {
+        writer.write_row(&[
+            Cell::from(f.purchase_order),
+            Cell::from(f.lineitem),
+            Cell::from(f.invoice.as_ref()),
+            Cell::from(f.problem),
+            Cell::from(f.invoiced),
+            Cell::from(f.received),
+            Cell::from(f.invoiced_price),
+            Cell::from(f.expected_price),
+            Cell::from(&f.detail),
+        ])?;
+    }
+
+    writer.finish()?;
+
+    let problems = findings.iter().filter(|f| f.problem != "ok").count();
+    info!(
+        "Checked {} invoice lines on {} purchase orders; found {problems} discrepancies",
+        lines.len(),
+        pos.len()
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    reconcile(&mut connection, &options)
+}
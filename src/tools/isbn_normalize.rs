@@ -0,0 +1,396 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::isbn;
+use crate::marc;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use marcutil::{Field, Record, Subfield};
+use std::fs;
+
+const REPORT_COLUMNS: &[&str] = &["record", "tag", "action", "old_value", "new_value"];
+
+/// Records loaded and saved per batch.
+const BATCH_SIZE: i64 = 1000;
+
+struct NormalizeOptions {
+    min_id: i64,
+    max_id: i64,
+    query_file: Option<String>,
+    add_counterparts: bool,
+    move_invalid: bool,
+    skip_upc: bool,
+    staff: i32,
+    out_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// One change to a record, for the report.
+struct Change {
+    tag: &'static str,
+    action: &'static str,
+    old_value: Option<String>,
+    new_value: Option<String>,
+}
+
+#[derive(Default)]
+struct Summary {
+    records: usize,
+    modified: usize,
+    normalized: usize,
+    invalid: usize,
+    added: usize,
+}
+
+fn read_options() -> (NormalizeOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+    opts.optflag("", "add-counterparts", "Add Missing ISBN-10/13 Forms");
+    opts.optflag("", "move-invalid", "Move Invalid Values to $z");
+    opts.optflag("", "skip-upc", "Leave 024 UPCs Alone");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "out-file", "Change Report File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        NormalizeOptions {
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            query_file: params.opt_get("query-file").unwrap(),
+            add_counterparts: params.opt_present("add-counterparts"),
+            move_invalid: params.opt_present("move-invalid"),
+            skip_upc: params.opt_present("skip-upc"),
+            staff: cli::required(&params, "staff"),
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin isbn-normalize -- --staff 1 --dry-run --out-file isbn-changes.csv
+    cargo run --bin isbn-normalize -- --add-counterparts --move-invalid --staff 1
+
+Normalizes 020 $a ISBNs and 024 UPCs in bib records, improving match
+rates for deduplication and added content lookups.
+
+ISBNs lose their hyphens, keeping any qualifier, e.g. "0-306-40615-2
+(pbk.)" becomes "0306406152 (pbk.)".  UPCs (024 first indicator 1)
+keep only their digits.  Values with a wrong length or check digit
+are reported as invalid, and moved to $z with --move-invalid.
+
+With --add-counterparts, an 020 is added with the ISBN-13 form of
+each valid ISBN-10 lacking one, and the ISBN-10 form of each 978
+ISBN-13 lacking one.
+
+Options
+
+    --min-id
+    --max-id
+        Only normalize records with IDs in this range.
+
+    --query-file
+        Path to a file containing an SQL query.  The query must
+        produce rows with "id" and "marc" columns.
+
+    --add-counterparts
+        Add missing ISBN-10 and ISBN-13 forms.
+
+    --move-invalid
+        Move invalid ISBNs and UPCs from $a to $z.
+
+    --skip-upc
+        Leave 024 UPCs alone.
+
+    --staff
+        Staff user recorded as record editor.  Required.
+
+    --out-file
+        Write the change report to this file.  Otherwise, writes to
+        STDOUT.  Files ending in .tsv or .xlsx are written as TSV or
+        a spreadsheet.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn create_sql(ops: &NormalizeOptions) -> Result<String, String> {
+    if let Some(ref fname) = ops.query_file {
+        return match fs::read_to_string(fname) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(format!("Cannot read {fname}: {e}")),
+        };
+    }
+
+    let mut sql =
+        String::from("SELECT id, marc FROM biblio.record_entry WHERE NOT deleted AND id > 0");
+
+    if ops.min_id > -1 {
+        sql += &format!(" AND id >= {}", ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        sql += &format!(" AND id <= {}", ops.max_id);
+    }
+
+    Ok(sql)
+}
+
+/// Normalize or invalidate one $a.  Returns the change, if any.
+fn normalize_subfield(
+    ops: &NormalizeOptions,
+    tag: &'static str,
+    sf: &mut Subfield,
+) -> Option<Change> {
+    let (number, qualifier) = isbn::split(&sf.content);
+
+    let valid = match tag {
+        "020" => isbn::is_valid(&sf.content),
+        _ => isbn::upc_valid(&sf.content),
+    };
+
+    if !valid {
+        let old_value = Some(sf.content.to_string());
+
+        if ops.move_invalid {
+            sf.code = "z".to_string();
+        }
+
+        return Some(Change {
+            tag,
+            action: "invalid",
+            old_value,
+            new_value: None,
+        });
+    }
+
+    let value = match qualifier {
+        Some(q) => format!("{number} {q}"),
+        None => number,
+    };
+
+    if value == sf.content {
+        return None;
+    }
+
+    let old_value = std::mem::replace(&mut sf.content, value.to_string());
+
+    Some(Change {
+        tag,
+        action: "normalize",
+        old_value: Some(old_value),
+        new_value: Some(value),
+    })
+}
+
+fn is_upc_field(field: &Field) -> bool {
+    field.tag == "024" && field.ind1 == "1"
+}
+
+/// Normalize a record's ISBNs and UPCs, returning the changes.
+fn normalize(ops: &NormalizeOptions, record: &mut Record) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    for field in record.fields.iter_mut() {
+        let tag = match field.tag.as_str() {
+            "020" => "020",
+            "024" if !ops.skip_upc && is_upc_field(field) => "024",
+            _ => continue,
+        };
+
+        for sf in field.subfields.iter_mut().filter(|sf| sf.code == "a") {
+            changes.extend(normalize_subfield(ops, tag, sf));
+        }
+    }
+
+    if !ops.add_counterparts {
+        return changes;
+    }
+
+    // Valid ISBNs, with their qualifiers, in record order.
+    let mut present: Vec<(String, Option<String>)> = Vec::new();
+
+    for value in record.get_values("020", "a") {
+        if isbn::is_valid(value) {
+            let (number, qualifier) = isbn::split(value);
+            present.push((number, qualifier.map(|q| q.to_string())));
+        }
+    }
+
+    let mut numbers: Vec<String> = present.iter().map(|(n, _)| n.to_string()).collect();
+
+    for (number, qualifier) in present {
+        let other = match isbn::counterpart(&number) {
+            Some(o) if !numbers.contains(&o) => o,
+            _ => continue,
+        };
+
+        numbers.push(other.to_string());
+
+        let content = match qualifier {
+            Some(q) => format!("{other} {q}"),
+            None => other.to_string(),
+        };
+
+        marc::insert_field(
+            record,
+            Field {
+                tag: "020".to_string(),
+                ind1: " ".to_string(),
+                ind2: " ".to_string(),
+                subfields: vec![Subfield {
+                    code: "a".to_string(),
+                    content: content.to_string(),
+                }],
+            },
+        );
+
+        changes.push(Change {
+            tag: "020",
+            action: "add",
+            old_value: Some(number),
+            new_value: Some(content),
+        });
+    }
+
+    changes
+}
+
+fn run(con: &mut DatabaseConnection, ops: &NormalizeOptions) -> Result<(), String> {
+    let record_set = create_sql(ops)?;
+
+    let select =
+        format!("SELECT q.id, q.marc FROM ({record_set}) q WHERE q.id > $1 ORDER BY q.id LIMIT $2");
+
+    let update = r#"
+        UPDATE biblio.record_entry
+        SET marc = $1, editor = $2, edit_date = NOW()
+        WHERE id = $3
+    "#;
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut summary = Summary::default();
+    let mut last_id: i64 = 0;
+
+    // Each batch is its own transaction, so a long pass neither holds
+    // locks on the whole catalog nor loses finished work to a late
+    // failure.
+    loop {
+        let mut tx = ops.dry_run.transaction(con.client())?;
+
+        let rows = tx
+            .query(select.as_str(), &[&last_id, &BATCH_SIZE])
+            .map_err(|e| format!("Error selecting records: {e}"))?;
+
+        if rows.is_empty() {
+            break;
+        }
+
+        for row in &rows {
+            let id: i64 = row.get("id");
+            last_id = id;
+            summary.records += 1;
+
+            let mut record = match Record::from_xml(row.get("marc")).next() {
+                Some(r) => r,
+                None => {
+                    warn!("Record {id} cannot be parsed; skipping");
+                    continue;
+                }
+            };
+
+            let changes = normalize(ops, &mut record);
+
+            for change in &changes {
+                match change.action {
+                    "normalize" => summary.normalized += 1,
+                    "invalid" => summary.invalid += 1,
+                    _ => summary.added += 1,
+                }
+
+                writer.write_row(&[
+                    Cell::from(id),
+                    Cell::from(change.tag),
+                    Cell::from(change.action),
+                    Cell::from(change.old_value.as_ref()),
+                    Cell::from(change.new_value.as_ref()),
+                ])?;
+            }
+
+            // Invalid values left in place change nothing.
+            if changes.iter().all(|c| c.action == "invalid") && !ops.move_invalid {
+                continue;
+            }
+
+            if !changes.is_empty() {
+                summary.modified += 1;
+
+                tx.execute(update, &[&record.to_xml()?, &ops.staff, &id])
+                    .map_err(|e| format!("Error updating record {id}: {e}"))?;
+            }
+        }
+
+        ops.dry_run.finish(tx)?;
+
+        info!("Processed {} records", summary.records);
+    }
+
+    writer.finish()?;
+
+    audit::count("records", summary.records as u64);
+    audit::count("modified", summary.modified as u64);
+    audit::count("normalized", summary.normalized as u64);
+    audit::count("invalid", summary.invalid as u64);
+    audit::count("added", summary.added as u64);
+
+    info!(
+        "{} of {} records {}: {} normalized, {} invalid, {} counterparts added",
+        summary.modified,
+        summary.records,
+        ops.dry_run.past("modified"),
+        summary.normalized,
+        summary.invalid,
+        summary.added
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(run(&mut connection, &options))
+}
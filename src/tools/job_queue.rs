@@ -0,0 +1,137 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::jobs::{self, Queue};
+use serde_json::Value;
+
+struct QueueOptions {
+    queue: String,
+    enqueue: Vec<String>,
+    max_attempts: Option<i32>,
+    retry_dead: bool,
+    purge_days: Option<i32>,
+}
+
+fn read_options() -> (QueueOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "queue", "Queue Name", "QUEUE");
+    opts.optmulti("", "enqueue", "Add a Job", "JSON");
+    opts.optopt("", "max-attempts", "Attempts per New Job", "COUNT");
+    opts.optflag("", "retry-dead", "Requeue Dead Jobs");
+    opts.optopt("", "purge-days", "Delete Jobs Completed Days Ago", "DAYS");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let queue = match params.opt_str("queue") {
+        Some(q) => q,
+        None => {
+            eprintln!("--queue is required");
+            std::process::exit(2);
+        }
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        QueueOptions {
+            queue,
+            enqueue: params.opt_strs("enqueue"),
+            max_attempts: params.opt_get("max-attempts").unwrap(),
+            retry_dead: params.opt_present("retry-dead"),
+            purge_days: params.opt_get("purge-days").unwrap(),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin job-queue -- --queue link-check --retry-dead
+
+Manages a job queue in egutil.job, creating the table if needed.
+Requested changes are applied in the order listed below, then job
+counts by state are printed.
+
+Workers for each queue are provided by the tools which use them.
+
+Options
+
+    --queue
+        Queue name.  Required.
+
+    --enqueue
+        Add a job with this JSON payload.  Repeatable.
+
+    --max-attempts
+        Attempts before jobs added with --enqueue are marked dead.
+        Defaults to 5.
+
+    --retry-dead
+        Requeue dead jobs with fresh attempts.
+
+    --purge-days
+        Delete jobs completed more than this many days ago.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn manage(con: &mut DatabaseConnection, ops: &QueueOptions) -> Result<(), String> {
+    con.connect()?;
+
+    jobs::create_table(con)?;
+
+    let mut queue = Queue::new(&ops.queue);
+
+    if let Some(n) = ops.max_attempts {
+        queue.set_max_attempts(n);
+    }
+
+    for payload in &ops.enqueue {
+        let payload: Value = match serde_json::from_str(payload) {
+            Ok(p) => p,
+            Err(e) => return Err(format!("Invalid --enqueue JSON {payload}: {e}")),
+        };
+
+        let id = queue.enqueue(con, &payload)?;
+        println!("Queued job {id}");
+    }
+
+    if ops.retry_dead {
+        let count = queue.retry_dead(con)?;
+        println!("Requeued {count} dead job(s)");
+    }
+
+    if let Some(days) = ops.purge_days {
+        let count = queue.purge_complete(con, days)?;
+        println!("Purged {count} complete job(s)");
+    }
+
+    println!("{:<12} {:>10}", "State", "Jobs");
+    for (state, count) in queue.counts(con)? {
+        println!("{:<12} {:>10}", state, count);
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    manage(&mut connection, &options)
+}
@@ -0,0 +1,240 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use log::{debug, info};
+use postgres as pg;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+struct JuvenileOptions {
+    org_unit: Option<i32>,
+    default_age: String,
+    profile_map: Vec<(i32, i32)>,
+    out_file: Option<String>,
+    dry_run: DryRun,
+}
+
+fn read_options() -> (JuvenileOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt(
+        "",
+        "org-unit",
+        "Limit to Patrons Homed at Org Unit and Descendants",
+        "ORG_ID",
+    );
+    opts.optopt("", "default-age", "Age Used Where Unset", "INTERVAL");
+    opts.optmulti("", "profile-map", "Change Profile Group", "OLD:NEW");
+    opts.optopt("", "out-file", "Change Report File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let profile_map = params
+        .opt_strs("profile-map")
+        .iter()
+        .map(|m| {
+            let (old, new) = m
+                .split_once(':')
+                .unwrap_or_else(|| panic!("Invalid --profile-map value: {m}"));
+            (
+                old.parse().expect("Invalid --profile-map group ID"),
+                new.parse().expect("Invalid --profile-map group ID"),
+            )
+        })
+        .collect();
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        JuvenileOptions {
+            org_unit: params.opt_get("org-unit").unwrap(),
+            default_age: params
+                .opt_get_default("default-age", "18 years".to_string())
+                .unwrap(),
+            profile_map,
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin juvenile-update -- --profile-map 3:2 --dry-run
+
+Clears the juvenile flag for patrons who have reached the juvenile
+age threshold, and optionally moves them to a new profile group.
+
+The threshold is the global.juvenile_age_threshold setting for each
+patron's home library, or --default-age where unset.  Patrons without
+a date of birth are skipped.
+
+Every change is reported as CSV: usr, barcode, home_ou, dob,
+old_profile, new_profile.
+
+Options
+
+    --org-unit
+        Limit to patrons whose home library is this org unit or one
+        of its descendants.
+
+    --default-age
+        Age threshold where the setting is unset, as a Postgres
+        interval.  Defaults to "18 years".
+
+    --profile-map
+        OLD:NEW profile group IDs.  Patrons in group OLD are moved to
+        group NEW.  Repeatable.
+
+    --out-file
+        Write the change report to this file.  Otherwise, writes to
+        STDOUT.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn update(con: &mut DatabaseConnection, ops: &JuvenileOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let sql = r#"
+        WITH candidates AS (
+            SELECT au.id, au.profile
+            FROM actor.usr au
+            WHERE au.juvenile
+                AND NOT au.deleted
+                AND au.dob IS NOT NULL
+                AND ($2::INT IS NULL OR au.home_ou IN (
+                    SELECT id FROM actor.org_unit_descendants($2::INT)
+                ))
+                AND au.dob + COALESCE(
+                    (SELECT value::JSON #>> '{}' FROM actor.org_unit_ancestor_setting(
+                        'global.juvenile_age_threshold', au.home_ou)),
+                    $1::TEXT
+                )::INTERVAL <= NOW()::DATE
+            FOR UPDATE
+        ), profile_map AS (
+            SELECT * FROM UNNEST($3::INT[], $4::INT[]) AS m(old_profile, new_profile)
+        )
+        UPDATE actor.usr au
+        SET juvenile = FALSE, profile = COALESCE(pm.new_profile, au.profile)
+        FROM candidates c
+            LEFT JOIN profile_map pm ON pm.old_profile = c.profile
+        WHERE au.id = c.id
+        RETURNING
+            au.id,
+            COALESCE((SELECT barcode FROM actor.card WHERE id = au.card), '') AS barcode,
+            (SELECT shortname FROM actor.org_unit WHERE id = au.home_ou) AS home_ou,
+            au.dob::TEXT AS dob,
+            (SELECT name FROM permission.grp_tree WHERE id = c.profile) AS old_profile,
+            (SELECT name FROM permission.grp_tree WHERE id = au.profile) AS new_profile
+    "#;
+
+    let old: Vec<i32> = ops.profile_map.iter().map(|(o, _)| *o).collect();
+    let new: Vec<i32> = ops.profile_map.iter().map(|(_, n)| *n).collect();
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let rows = tx
+        .query(sql, &[&ops.default_age, &ops.org_unit, &old, &new])
+        .map_err(|e| db_err("Error updating patrons", e))?;
+
+    let mut out = String::from("usr,barcode,home_ou,dob,old_profile,new_profile\n");
+    let mut moved = 0;
+
+    for row in &rows {
+        let id: i32 = row.get("id");
+        let old_profile: String = row.get("old_profile");
+        let new_profile: String = row.get("new_profile");
+
+        debug!("Patron {id} is no longer juvenile");
+
+        if old_profile != new_profile {
+            moved += 1;
+        }
+
+        let fields: Vec<String> = [
+            id.to_string(),
+            row.get("barcode"),
+            row.get("home_ou"),
+            row.get("dob"),
+            old_profile,
+            new_profile,
+        ]
+        .iter()
+        .map(|v| {
+            if v.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", v.replace('"', "\"\""))
+            } else {
+                v.to_string()
+            }
+        })
+        .collect();
+
+        out += &fields.join(",");
+        out += "\n";
+    }
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    if let Err(e) = writer.write_all(out.as_bytes()) {
+        return Err(format!("Error writing report: {e}"));
+    }
+
+    audit::count("patrons", rows.len() as u64);
+    audit::count("moved", moved as u64);
+
+    info!(
+        "{} patrons {} juvenile, {moved} moved to a new profile",
+        rows.len(),
+        if ops.dry_run.enabled() {
+            "would no longer be"
+        } else {
+            "no longer"
+        }
+    );
+
+    ops.dry_run.finish(tx)?;
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(update(&mut connection, &options))
+}
@@ -0,0 +1,469 @@
+use crate::callnumber::Scheme;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+
+// PDF page geometry, in points.
+const PAGE_WIDTH: f64 = 612.0;
+const PAGE_HEIGHT: f64 = 792.0;
+const PAGE_MARGIN: f64 = 36.0;
+const FONT_SIZE: f64 = 9.0;
+const LINE_HEIGHT: f64 = 10.0;
+
+struct LabelOptions {
+    in_file: String,
+    out_file: String,
+    barcodes: bool,
+    pdf: bool,
+    pocket: bool,
+    line_width: usize,
+    max_lines: usize,
+    columns: usize,
+    rows: usize,
+}
+
+struct Label {
+    barcode: String,
+    title: String,
+    location: String,
+    circ_lib: String,
+    call_number: String,
+    lines: Vec<String>,
+}
+
+fn read_options() -> (LabelOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "in-file", "Item ID File, One per Line", "FILE");
+    opts.optopt("", "out-file", "Output File", "FILE");
+    opts.optflag("", "barcodes", "Input File Contains Barcodes");
+    opts.optflag("", "pdf", "Write PDF Instead of CSV");
+    opts.optflag("", "pocket", "Add Title and Barcode to PDF Labels");
+    opts.optopt("", "line-width", "Max Characters per Label Line", "CHARS");
+    opts.optopt("", "max-lines", "Max Call Number Lines", "LINES");
+    opts.optopt("", "columns", "PDF Labels per Row", "COLUMNS");
+    opts.optopt("", "rows", "PDF Label Rows per Page", "ROWS");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        LabelOptions {
+            in_file: cli::required(&params, "in-file"),
+            out_file: cli::required(&params, "out-file"),
+            barcodes: params.opt_present("barcodes"),
+            pdf: params.opt_present("pdf"),
+            pocket: params.opt_present("pocket"),
+            line_width: params.opt_get_default("line-width", 8).unwrap(),
+            max_lines: params.opt_get_default("max-lines", 9).unwrap(),
+            columns: params.opt_get_default("columns", 6).unwrap(),
+            rows: params.opt_get_default("rows", 8).unwrap(),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin label-export -- --in-file items.txt --out-file labels.csv
+    cargo run --bin label-export -- --in-file barcodes.txt --barcodes --pdf --out-file labels.pdf
+
+Exports spine and pocket label data for a list of items.
+
+Call numbers are split into label lines using the scheme of each call
+number's classification (asset.call_number_class normalizer): LC call
+numbers split between class letters and numbers and before cutters,
+Dewey and generic call numbers split on spaces.  Lines longer than
+--line-width are wrapped.  Prefixes and suffixes get their own lines.
+
+CSV output has columns barcode, title, circ_lib, location,
+call_number, then one column per label line.
+
+PDF output prints one label per item on a grid of --columns by --rows
+labels per US Letter page, in Courier.
+
+Options
+
+    --in-file
+        File of item IDs, one per line.  Required.
+
+    --barcodes
+        --in-file contains item barcodes instead of IDs.
+
+    --out-file
+        Output file.  Required.  Without --pdf, files ending in .tsv
+        or .xlsx are written as TSV or a spreadsheet; others as CSV.
+
+    --pdf
+        Write a PDF instead of CSV.
+
+    --pocket
+        Add the title and barcode after the call number on PDF labels.
+
+    --line-width
+        Max characters per call number line.  Defaults to 8.
+
+    --max-lines
+        Max call number lines per label.  Defaults to 9.
+
+    --columns
+    --rows
+        PDF label grid.  Defaults to 6 columns by 8 rows.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+/// Split an LC call number part like QA76.73.R87 into class letters,
+/// class number and cutters: QA, 76.73, .R87
+///
+/// Class letters are only split from the first part, so a later cutter
+/// like K63 stays whole.
+fn split_lc_part(part: &str, is_class: bool) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let chars: Vec<char> = part.chars().collect();
+
+    for (idx, c) in chars.iter().enumerate() {
+        let prev = if idx > 0 { Some(chars[idx - 1]) } else { None };
+        let next = chars.get(idx + 1);
+
+        let starts_number = c.is_ascii_digit() && prev.is_some_and(|p| p.is_ascii_alphabetic());
+        let starts_cutter = *c == '.' && next.is_some_and(|n| n.is_ascii_alphabetic());
+
+        // Only split class letters from the class number at the start.
+        if !current.is_empty() && (starts_cutter || (is_class && starts_number && parts.is_empty()))
+        {
+            parts.push(current);
+            current = String::new();
+        }
+
+        current.push(*c);
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+fn wrap(token: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = token.chars().collect();
+    chars
+        .chunks(width.max(1))
+        .map(|c| c.iter().collect())
+        .collect()
+}
+
+fn split_call_number(scheme: Scheme, label: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for (idx, token) in label.split_whitespace().enumerate() {
+        let parts = match scheme {
+            Scheme::Lc => split_lc_part(token, idx == 0),
+            Scheme::Dewey | Scheme::Sudoc | Scheme::Generic => vec![token.to_string()],
+        };
+
+        for part in parts {
+            lines.extend(wrap(&part, width));
+        }
+    }
+
+    lines
+}
+
+fn read_ids(path: &str) -> Result<Vec<String>, String> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(s
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()),
+        Err(e) => Err(format!("Cannot read {path}: {e}")),
+    }
+}
+
+fn load_labels(con: &mut DatabaseConnection, ops: &LabelOptions) -> Result<Vec<Label>, String> {
+    let idents = read_ids(&ops.in_file)?;
+
+    let filter = match ops.barcodes {
+        true => "acp.barcode = ANY($1)",
+        false => "acp.id = ANY($1::TEXT[]::BIGINT[])",
+    };
+
+    let sql = format!(
+        r#"
+        SELECT
+            acp.id::TEXT AS id,
+            acp.barcode,
+            acn.label,
+            acnc.normalizer,
+            COALESCE(acnp.label, '') AS prefix,
+            COALESCE(acns.label, '') AS suffix,
+            acpl.name AS location,
+            aou.shortname AS circ_lib,
+            COALESCE(rmsr.title, acp.dummy_title, '') AS title
+        FROM asset.copy acp
+            JOIN asset.call_number acn ON acn.id = acp.call_number
+            JOIN asset.copy_location acpl ON acpl.id = acp.location
+            JOIN actor.org_unit aou ON aou.id = acp.circ_lib
+            LEFT JOIN asset.call_number_class acnc ON acnc.id = acn.label_class
+            LEFT JOIN asset.call_number_prefix acnp ON acnp.id = acn.prefix AND acnp.id > -1
+            LEFT JOIN asset.call_number_suffix acns ON acns.id = acn.suffix AND acns.id > -1
+            LEFT JOIN reporter.materialized_simple_record rmsr ON rmsr.id = acn.record
+        WHERE NOT acp.deleted AND {filter}
+        "#
+    );
+
+    let rows = match con.client().query(sql.as_str(), &[&idents]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error loading items: {e}")),
+    };
+
+    let mut found: HashMap<String, Label> = HashMap::new();
+
+    for row in rows {
+        let label: String = row.get("label");
+        let normalizer: Option<String> = row.get("normalizer");
+        let prefix: String = row.get("prefix");
+        let suffix: String = row.get("suffix");
+
+        let scheme = Scheme::from_normalizer(normalizer.as_deref());
+
+        let mut lines = Vec::new();
+        if !prefix.is_empty() {
+            lines.extend(wrap(&prefix, ops.line_width));
+        }
+        lines.extend(split_call_number(scheme, &label, ops.line_width));
+        if !suffix.is_empty() {
+            lines.extend(wrap(&suffix, ops.line_width));
+        }
+
+        if lines.len() > ops.max_lines {
+            warn!("Call number {label} exceeds {} lines", ops.max_lines);
+            lines.truncate(ops.max_lines);
+        }
+
+        let key: String = match ops.barcodes {
+            true => row.get("barcode"),
+            false => row.get("id"),
+        };
+
+        found.insert(
+            key,
+            Label {
+                barcode: row.get("barcode"),
+                title: row.get("title"),
+                location: row.get("location"),
+                circ_lib: row.get("circ_lib"),
+                call_number: [prefix, label, suffix]
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(" "),
+                lines,
+            },
+        );
+    }
+
+    // Keep the input order, which is usually the shelving order.
+    let mut labels = Vec::new();
+    for ident in idents {
+        match found.remove(&ident) {
+            Some(l) => labels.push(l),
+            None => warn!("No item found for {ident}"),
+        }
+    }
+
+    Ok(labels)
+}
+
+/// CSV, or TSV or a spreadsheet by --out-file extension.
+fn write_table(ops: &LabelOptions, labels: &[Label]) -> Result<(), String> {
+    let max = labels.iter().map(|l| l.lines.len()).max().unwrap_or(0);
+
+    let format = Format::from_path(&ops.out_file).unwrap_or(Format::Csv);
+    let mut writer = TableWriter::create(&ops.out_file, format)?;
+
+    let line_labels: Vec<String> = (1..=max).map(|n| format!("line{n}")).collect();
+
+    let mut header = vec!["barcode", "title", "circ_lib", "location", "call_number"];
+    header.extend(line_labels.iter().map(|l| l.as_str()));
+    writer.write_header(&header)?;
+
+    for label in labels {
+        let mut row = vec![
+            Cell::from(&label.barcode),
+            Cell::from(&label.title),
+            Cell::from(&label.circ_lib),
+            Cell::from(&label.location),
+            Cell::from(&label.call_number),
+        ];
+
+        for n in 0..max {
+            row.push(label.lines.get(n).into());
+        }
+
+        writer.write_row(&row)?;
+    }
+
+    writer.finish()
+}
+
+/// PDF string literal in WinAnsi / Latin-1.
+fn pdf_string(s: &str) -> String {
+    let mut out = String::from("(");
+
+    for c in s.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            c if (c as u32) >= 0xA0 && (c as u32) <= 0xFF => out += &format!("\\{:03o}", c as u32),
+            _ => out.push('?'),
+        }
+    }
+
+    out.push(')');
+    out
+}
+
+/// Page content stream drawing each label's lines at its grid cell.
+fn page_content(ops: &LabelOptions, labels: &[Label]) -> String {
+    let cell_width = (PAGE_WIDTH - 2.0 * PAGE_MARGIN) / ops.columns as f64;
+    let cell_height = (PAGE_HEIGHT - 2.0 * PAGE_MARGIN) / ops.rows as f64;
+    let title_width = (cell_width / (FONT_SIZE * 0.6)) as usize;
+
+    let mut content = format!("BT\n/F1 {FONT_SIZE} Tf\n{LINE_HEIGHT} TL\n");
+
+    for (idx, label) in labels.iter().enumerate() {
+        let col = idx % ops.columns;
+        let row = idx / ops.columns;
+
+        let x = PAGE_MARGIN + col as f64 * cell_width + 4.0;
+        let y = PAGE_HEIGHT - PAGE_MARGIN - row as f64 * cell_height - LINE_HEIGHT;
+
+        let mut lines = label.lines.clone();
+        if ops.pocket {
+            lines.push(String::new());
+            lines.extend(wrap(&label.title, title_width).into_iter().take(2));
+            lines.push(label.barcode.to_string());
+        }
+
+        content += &format!("1 0 0 1 {x:.2} {y:.2} Tm\n");
+        for line in lines {
+            content += &format!("{} Tj T*\n", pdf_string(&line));
+        }
+    }
+
+    content += "ET\n";
+    content
+}
+
+fn write_pdf(ops: &LabelOptions, labels: &[Label]) -> Result<(), String> {
+    let per_page = (ops.columns * ops.rows).max(1);
+    let pages: Vec<&[Label]> = labels.chunks(per_page).collect();
+
+    // Objects: 1 catalog, 2 pages, 3 font, then a page and content
+    // stream pair per page.
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        String::new(),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Courier /Encoding /WinAnsiEncoding >>"
+            .to_string(),
+    ];
+
+    let mut kids = Vec::new();
+
+    for page in &pages {
+        let page_id = objects.len() + 1;
+        let content = page_content(ops, page);
+
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {PAGE_WIDTH} {PAGE_HEIGHT}] \
+             /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            page_id + 1
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{content}endstream",
+            content.len()
+        ));
+
+        kids.push(format!("{page_id} 0 R"));
+    }
+
+    objects[1] = format!(
+        "<< /Type /Pages /Kids [{}] /Count {} >>",
+        kids.join(" "),
+        kids.len()
+    );
+
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::new();
+
+    for (idx, obj) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf += &format!("{} 0 obj\n{obj}\nendobj\n", idx + 1);
+    }
+
+    let xref = pdf.len();
+    pdf += &format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+    for offset in offsets {
+        pdf += &format!("{offset:010} 00000 n \n");
+    }
+    pdf += &format!(
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+        objects.len() + 1
+    );
+
+    match fs::write(&ops.out_file, pdf) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot write {}: {e}", ops.out_file)),
+    }
+}
+
+fn export(con: &mut DatabaseConnection, ops: &LabelOptions) -> Result<(), String> {
+    if ops.columns == 0 || ops.rows == 0 {
+        return Err("--columns and --rows must be positive".to_string());
+    }
+
+    con.connect()?;
+    let labels = load_labels(con, ops)?;
+    con.disconnect();
+
+    match ops.pdf {
+        true => write_pdf(ops, &labels)?,
+        false => write_table(ops, &labels)?,
+    }
+
+    info!("Wrote {} labels to {}", labels.len(), ops.out_file);
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    export(&mut connection, &options)
+}
@@ -0,0 +1,780 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use ldap3::{LdapConn, Scope, SearchEntry};
+use log::{debug, info, warn};
+use postgres as pg;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::prelude::*;
+use std::{fs, io};
+
+/// Patron fields which may be mapped from directory attributes.
+/// All but barcode are actor.usr text columns.
+const FIELDS: &[&str] = &[
+    "usrname",
+    "barcode",
+    "first_given_name",
+    "second_given_name",
+    "family_name",
+    "email",
+    "day_phone",
+    "evening_phone",
+    "other_phone",
+    "ident_value",
+];
+
+/// Fields every directory entry needs to create a patron.
+const REQUIRED_FIELDS: &[&str] = &["usrname", "barcode", "first_given_name", "family_name"];
+
+struct SyncOptions {
+    config_file: String,
+    max_expire: usize,
+    out_file: Option<String>,
+    dry_run: DryRun,
+}
+
+struct SyncConfig {
+    source: String,
+    url: String,
+    bind_dn: Option<String>,
+    bind_password: Option<String>,
+    base_dn: String,
+    filter: String,
+    page_size: i32,
+    home_ou: String,
+    ident_type: i32,
+    expire_interval: String,
+    /// Patron field => directory attribute
+    fields: Vec<(String, String)>,
+    profile_attribute: Option<String>,
+    /// Attribute value => permission group
+    profiles: HashMap<String, i32>,
+    default_profile: Option<i32>,
+}
+
+/// A directory entry mapped to patron fields.
+struct DirectoryPatron {
+    dn: String,
+    values: BTreeMap<String, String>,
+    profile: i32,
+}
+
+#[derive(Default)]
+struct Summary {
+    created: usize,
+    updated: usize,
+    unchanged: usize,
+    expired: usize,
+    skipped: usize,
+    errors: usize,
+}
+
+fn read_options() -> (SyncOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt(
+        "",
+        "directory-file",
+        "Directory and Field Map Config File",
+        "FILE",
+    );
+    opts.optopt("", "max-expire", "Maximum Patrons to Expire", "COUNT");
+    opts.optopt("", "out-file", "Reconciliation Report File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        SyncOptions {
+            config_file: cli::required(&params, "directory-file"),
+            max_expire: params.opt_get_default("max-expire", 100).unwrap(),
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin ldap-sync -- --directory-file ldap.json --out-file ldap-sync.csv --dry-run
+
+Synchronizes patrons with an LDAP or Active Directory directory.
+
+Directory entries are matched to patrons by username.  New entries
+create patrons with a card, existing patrons have their mapped fields,
+profile and barcode updated, and their expire date extended by
+expire_interval.  Patrons created or matched by an earlier run of the
+same source which are no longer in the directory are expired.
+Synchronized patrons are tracked in egutil.ldap_sync_patron.
+
+    {{
+        "source": "campus-ad",
+        "url": "ldaps://ad.example.edu",
+        "bind_dn": "CN=evergreen,OU=Service,DC=example,DC=edu",
+        "bind_password": "secret",
+        "base_dn": "OU=People,DC=example,DC=edu",
+        "filter": "(&(objectClass=person)(employeeID=*))",
+        "page_size": 500,
+        "home_ou": "BR1",
+        "ident_type": 3,
+        "expire_interval": "1 year",
+        "fields": {{
+            "usrname": "sAMAccountName",
+            "barcode": "employeeID",
+            "first_given_name": "givenName",
+            "family_name": "sn",
+            "email": "mail"
+        }},
+        "profile_attribute": "eduPersonPrimaryAffiliation",
+        "profiles": {{"student": 2, "faculty": 3}},
+        "default_profile": 2
+    }}
+
+usrname, barcode, first_given_name and family_name must be mapped.
+second_given_name, email, day_phone, evening_phone, other_phone and
+ident_value may be.  Unmapped fields are not modified.  Entries
+with no profile match and no default_profile are skipped.
+
+New patrons get a random password, so logins go through the
+directory, e.g. with Evergreen's LDAP authentication proxy.
+
+The report lists each patron as created, updated, unchanged, expired,
+skipped or error, as CSV: action,usrname,usr,detail
+
+Options
+
+    --directory-file
+        Directory and field map config file.  Required.
+
+    --max-expire
+        Stop without saving if more than this many patrons would be
+        expired, e.g. after a bad filter change.  Defaults to 100.
+
+    --out-file
+        Write the reconciliation report here instead of stdout.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn read_config(path: &str) -> Result<SyncConfig, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let config: Value = match serde_json::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let string = |key: &str| config[key].as_str().map(|s| s.to_string());
+    let required = |key: &str| string(key).ok_or_else(|| format!("{path} requires {key}"));
+
+    let mut fields = Vec::new();
+    if let Some(map) = config["fields"].as_object() {
+        for (field, attr) in map {
+            if !FIELDS.contains(&field.as_str()) {
+                return Err(format!("Unknown patron field in {path}: {field}"));
+            }
+            match attr.as_str() {
+                Some(a) => fields.push((field.to_string(), a.to_string())),
+                None => return Err(format!("Invalid attribute for {field}: {attr}")),
+            }
+        }
+    }
+
+    for field in REQUIRED_FIELDS {
+        if !fields.iter().any(|(f, _)| f == field) {
+            return Err(format!("{path} must map the {field} field"));
+        }
+    }
+
+    let mut profiles = HashMap::new();
+    if let Some(map) = config["profiles"].as_object() {
+        for (value, group) in map {
+            match group.as_i64() {
+                Some(g) => profiles.insert(value.to_string(), g as i32),
+                None => return Err(format!("Invalid profile group for {value}: {group}")),
+            };
+        }
+    }
+
+    Ok(SyncConfig {
+        source: string("source").unwrap_or("ldap".to_string()),
+        url: required("url")?,
+        bind_dn: string("bind_dn"),
+        bind_password: string("bind_password"),
+        base_dn: required("base_dn")?,
+        filter: string("filter").unwrap_or("(objectClass=person)".to_string()),
+        page_size: config["page_size"].as_i64().unwrap_or(500) as i32,
+        home_ou: required("home_ou")?,
+        ident_type: config["ident_type"].as_i64().unwrap_or(3) as i32,
+        expire_interval: string("expire_interval").unwrap_or("1 year".to_string()),
+        fields,
+        profile_attribute: string("profile_attribute"),
+        profiles,
+        default_profile: config["default_profile"].as_i64().map(|p| p as i32),
+    })
+}
+
+/// Map a directory entry to patron fields, or a reason to skip it.
+fn map_entry(config: &SyncConfig, entry: SearchEntry) -> Result<DirectoryPatron, String> {
+    // Servers may return attribute names in a different case.
+    let attrs: HashMap<String, Vec<String>> = entry
+        .attrs
+        .into_iter()
+        .map(|(k, v)| (k.to_lowercase(), v))
+        .collect();
+
+    let first = |attr: &str| {
+        attrs
+            .get(&attr.to_lowercase())
+            .and_then(|v| v.first())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+
+    let mut values = BTreeMap::new();
+
+    for (field, attr) in &config.fields {
+        match first(attr) {
+            Some(v) => {
+                values.insert(field.to_string(), v);
+            }
+            None if REQUIRED_FIELDS.contains(&field.as_str()) => {
+                return Err(format!("no {attr} for {field}"));
+            }
+            None => {}
+        }
+    }
+
+    let profile = config
+        .profile_attribute
+        .as_ref()
+        .and_then(|attr| attrs.get(&attr.to_lowercase()))
+        .and_then(|list| list.iter().find_map(|v| config.profiles.get(v.trim())))
+        .copied()
+        .or(config.default_profile);
+
+    match profile {
+        Some(profile) => Ok(DirectoryPatron {
+            dn: entry.dn,
+            values,
+            profile,
+        }),
+        None => Err("no matching profile".to_string()),
+    }
+}
+
+fn read_directory(
+    config: &SyncConfig,
+    report: &mut String,
+    summary: &mut Summary,
+) -> Result<Vec<DirectoryPatron>, String> {
+    let mut ldap = match LdapConn::new(&config.url) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("Cannot connect to {}: {e}", config.url)),
+    };
+
+    if let Some(dn) = &config.bind_dn {
+        let password = config.bind_password.as_deref().unwrap_or("");
+        if let Err(e) = ldap.simple_bind(dn, password).and_then(|r| r.success()) {
+            return Err(format!("Cannot bind as {dn}: {e}"));
+        }
+    }
+
+    let mut attrs: Vec<String> = config.fields.iter().map(|(_, a)| a.clone()).collect();
+    if let Some(attr) = &config.profile_attribute {
+        attrs.push(attr.clone());
+    }
+
+    // Paging gets past the server's size limit, e.g. 1000 for AD.
+    let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
+        Box::new(EntriesOnly::new()),
+        Box::new(PagedResults::new(config.page_size)),
+    ];
+
+    let mut search = match ldap.streaming_search_with(
+        adapters,
+        &config.base_dn,
+        Scope::Subtree,
+        &config.filter,
+        attrs,
+    ) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Directory search failed: {e}")),
+    };
+
+    let mut patrons = Vec::new();
+
+    loop {
+        let entry = match search.next() {
+            Ok(Some(e)) => SearchEntry::construct(e),
+            Ok(None) => break,
+            Err(e) => return Err(format!("Directory search failed: {e}")),
+        };
+
+        let dn = entry.dn.clone();
+
+        match map_entry(config, entry) {
+            Ok(p) => patrons.push(p),
+            Err(reason) => {
+                debug!("Skipping {dn}: {reason}");
+                summary.skipped += 1;
+                *report += &format!("skipped,,,{}\n", csv_field(&format!("{dn}: {reason}")));
+            }
+        }
+    }
+
+    if let Err(e) = search.result().success() {
+        return Err(format!("Directory search failed: {e}"));
+    }
+
+    ldap.unbind().ok();
+
+    info!(
+        "Read {} directory entries from {}",
+        patrons.len(),
+        config.url
+    );
+
+    Ok(patrons)
+}
+
+fn init_table(tx: &mut pg::Transaction) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.ldap_sync_patron (
+            usr         INT PRIMARY KEY,
+            source      TEXT NOT NULL,
+            dn          TEXT NOT NULL,
+            last_seen   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+    "#;
+
+    tx.batch_execute(sql)
+        .map_err(|e| db_err("Cannot create egutil.ldap_sync_patron", e))
+}
+
+fn add_card(tx: &mut pg::Transaction, usr: i32, barcode: &str) -> Result<(), String> {
+    let card: i32 = tx
+        .query_one(
+            "INSERT INTO actor.card (usr, barcode) VALUES ($1, $2) RETURNING id",
+            &[&usr, &barcode],
+        )
+        .map_err(|e| db_err(&format!("Error adding card {barcode}"), e))?
+        .get("id");
+
+    tx.execute(
+        "UPDATE actor.usr SET card = $2 WHERE id = $1",
+        &[&usr, &card],
+    )
+    .map_err(|e| db_err(&format!("Error adding card {barcode}"), e))?;
+
+    Ok(())
+}
+
+fn create_patron(
+    tx: &mut pg::Transaction,
+    config: &SyncConfig,
+    home_ou: i32,
+    patron: &DirectoryPatron,
+) -> Result<i32, String> {
+    let columns: Vec<&String> = patron.values.keys().filter(|f| *f != "barcode").collect();
+
+    let mut params: Vec<&(dyn pg::types::ToSql + Sync)> = vec![
+        &patron.profile,
+        &home_ou,
+        &config.ident_type,
+        &config.expire_interval,
+    ];
+
+    let mut placeholders = Vec::new();
+    for field in &columns {
+        params.push(&patron.values[*field]);
+        placeholders.push(format!("${}", params.len()));
+    }
+
+    // Fields come from FIELDS, so they are safe to interpolate.
+    let sql = format!(
+        r#"
+        INSERT INTO actor.usr
+            (profile, home_ou, ident_type, expire_date, passwd, {})
+        VALUES
+            ($1, $2, $3, NOW() + $4::TEXT::INTERVAL, MD5(RANDOM()::TEXT), {})
+        RETURNING id
+        "#,
+        columns
+            .iter()
+            .map(|c| c.as_str())
+            .collect::<Vec<&str>>()
+            .join(", "),
+        placeholders.join(", ")
+    );
+
+    let usr: i32 = tx
+        .query_one(sql.as_str(), &params)
+        .map_err(|e| db_err("Error creating patron", e))?
+        .get("id");
+
+    add_card(tx, usr, &patron.values["barcode"])?;
+
+    Ok(usr)
+}
+
+/// Apply directory values to an existing patron, returning the
+/// changed fields.
+fn update_patron(
+    tx: &mut pg::Transaction,
+    config: &SyncConfig,
+    existing: &pg::Row,
+    patron: &DirectoryPatron,
+) -> Result<Vec<String>, String> {
+    let usr: i32 = existing.get("id");
+    let mut changed = Vec::new();
+
+    let mut params: Vec<&(dyn pg::types::ToSql + Sync)> = vec![&usr, &config.expire_interval];
+    let mut sets =
+        vec!["expire_date = GREATEST(expire_date, NOW() + $2::TEXT::INTERVAL)".to_string()];
+
+    let profile: i32 = existing.get("profile");
+    if profile != patron.profile {
+        params.push(&patron.profile);
+        sets.push(format!("profile = ${}", params.len()));
+        changed.push("profile".to_string());
+    }
+
+    for (field, value) in &patron.values {
+        if field == "usrname" || field == "barcode" {
+            continue;
+        }
+        let old: Option<String> = existing.get(field.as_str());
+        if old.as_deref() != Some(value) {
+            params.push(value);
+            sets.push(format!("{field} = ${}", params.len()));
+            changed.push(field.to_string());
+        }
+    }
+
+    // Fields come from FIELDS, so they are safe to interpolate.
+    let sql = format!("UPDATE actor.usr SET {} WHERE id = $1", sets.join(", "));
+
+    tx.execute(sql.as_str(), &params)
+        .map_err(|e| db_err(&format!("Error updating patron {usr}"), e))?;
+
+    let barcode = &patron.values["barcode"];
+    let old_barcode: Option<String> = existing.get("barcode");
+
+    if old_barcode.as_ref() != Some(barcode) {
+        tx.execute(
+            "UPDATE actor.card SET active = FALSE WHERE usr = $1 AND barcode <> $2",
+            &[&usr, barcode],
+        )
+        .map_err(|e| db_err(&format!("Error deactivating cards for {usr}"), e))?;
+
+        let reactivated = tx
+            .execute(
+                "UPDATE actor.card SET active = TRUE WHERE usr = $1 AND barcode = $2",
+                &[&usr, barcode],
+            )
+            .map_err(|e| db_err(&format!("Error updating cards for {usr}"), e))?;
+
+        if reactivated > 0 {
+            tx.execute(
+                r#"
+                UPDATE actor.usr SET card =
+                    (SELECT id FROM actor.card WHERE usr = $1 AND barcode = $2)
+                WHERE id = $1
+                "#,
+                &[&usr, barcode],
+            )
+            .map_err(|e| db_err(&format!("Error updating cards for {usr}"), e))?;
+        } else {
+            add_card(tx, usr, barcode)?;
+        }
+
+        changed.push("barcode".to_string());
+    }
+
+    Ok(changed)
+}
+
+fn track_patron(
+    tx: &mut pg::Transaction,
+    config: &SyncConfig,
+    usr: i32,
+    dn: &str,
+) -> Result<(), String> {
+    let sql = r#"
+        INSERT INTO egutil.ldap_sync_patron (usr, source, dn)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (usr) DO UPDATE
+            SET source = EXCLUDED.source, dn = EXCLUDED.dn, last_seen = NOW()
+    "#;
+
+    tx.execute(sql, &[&usr, &config.source, &dn])
+        .map_err(|e| db_err(&format!("Error tracking patron {usr}"), e))?;
+
+    Ok(())
+}
+
+/// Create or update one patron in its own savepoint, so a bad entry,
+/// e.g. a duplicate barcode, does not stop the run.
+fn sync_patron(
+    tx: &mut pg::Transaction,
+    config: &SyncConfig,
+    home_ou: i32,
+    existing: Option<&pg::Row>,
+    patron: &DirectoryPatron,
+) -> Result<(&'static str, i32, String), String> {
+    let mut sp = tx
+        .transaction()
+        .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+    let (action, usr, detail) = match existing {
+        Some(row) => {
+            let usr: i32 = row.get("id");
+            let changed = update_patron(&mut sp, config, row, patron)?;
+            let renewed = row.get::<_, bool>("expired");
+
+            let mut detail = changed.join(" ");
+            if renewed {
+                detail = format!("renewed {detail}").trim().to_string();
+            }
+
+            match changed.is_empty() && !renewed {
+                true => ("unchanged", usr, detail),
+                false => ("updated", usr, detail),
+            }
+        }
+        None => (
+            "created",
+            create_patron(&mut sp, config, home_ou, patron)?,
+            String::new(),
+        ),
+    };
+
+    track_patron(&mut sp, config, usr, &patron.dn)?;
+
+    sp.commit()
+        .map_err(|e| db_err("Cannot release savepoint", e))?;
+
+    Ok((action, usr, detail))
+}
+
+fn sync(con: &mut DatabaseConnection, ops: &SyncOptions) -> Result<(), String> {
+    let config = read_config(&ops.config_file)?;
+
+    let mut report = String::from("action,usrname,usr,detail\n");
+    let mut summary = Summary::default();
+
+    let patrons = read_directory(&config, &mut report, &mut summary)?;
+
+    if patrons.is_empty() {
+        return Err(format!(
+            "No usable entries in {}; refusing to expire every patron",
+            config.base_dn
+        ));
+    }
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    init_table(&mut tx)?;
+
+    let home_ou: i32 = match tx
+        .query_opt(
+            "SELECT id FROM actor.org_unit WHERE shortname = $1",
+            &[&config.home_ou],
+        )
+        .map_err(|e| db_err("Error loading org unit", e))?
+    {
+        Some(row) => row.get("id"),
+        None => return Err(format!("No such org unit: {}", config.home_ou)),
+    };
+
+    let usrnames: Vec<&String> = patrons.iter().map(|p| &p.values["usrname"]).collect();
+
+    let sql = r#"
+        SELECT
+            au.id,
+            au.usrname,
+            au.profile,
+            au.first_given_name,
+            au.second_given_name,
+            au.family_name,
+            au.email,
+            au.day_phone,
+            au.evening_phone,
+            au.other_phone,
+            au.ident_value,
+            ac.barcode,
+            au.expire_date < NOW() AS expired
+        FROM actor.usr au
+            LEFT JOIN actor.card ac ON ac.id = au.card
+        WHERE au.usrname = ANY($1::TEXT[]) AND NOT au.deleted
+    "#;
+
+    let rows = tx
+        .query(sql, &[&usrnames])
+        .map_err(|e| db_err("Error loading patrons", e))?;
+
+    let existing: HashMap<String, &pg::Row> = rows
+        .iter()
+        .map(|row| (row.get::<_, String>("usrname"), row))
+        .collect();
+
+    for patron in &patrons {
+        let usrname = &patron.values["usrname"];
+
+        match sync_patron(
+            &mut tx,
+            &config,
+            home_ou,
+            existing.get(usrname).copied(),
+            patron,
+        ) {
+            Ok((action, usr, detail)) => {
+                match action {
+                    "created" => summary.created += 1,
+                    "updated" => summary.updated += 1,
+                    _ => summary.unchanged += 1,
+                }
+                report += &format!(
+                    "{action},{},{usr},{}\n",
+                    csv_field(usrname),
+                    csv_field(&detail)
+                );
+            }
+            Err(e) => {
+                warn!("{usrname}: {e}");
+                summary.errors += 1;
+                report += &format!("error,{},,{}\n", csv_field(usrname), csv_field(&e));
+            }
+        }
+    }
+
+    // Seen patrons have last_seen = NOW(), which is fixed for the
+    // life of the transaction.
+    let sql = r#"
+        SELECT au.id, au.usrname
+        FROM egutil.ldap_sync_patron sp
+            JOIN actor.usr au ON au.id = sp.usr
+        WHERE sp.source = $1
+            AND sp.last_seen < NOW()
+            AND au.expire_date > NOW()
+            AND NOT au.deleted
+        ORDER BY au.id
+    "#;
+
+    let gone = tx
+        .query(sql, &[&config.source])
+        .map_err(|e| db_err("Error finding departed patrons", e))?;
+
+    if gone.len() > ops.max_expire {
+        return Err(format!(
+            "{} patrons would be expired, more than --max-expire {}",
+            gone.len(),
+            ops.max_expire
+        ));
+    }
+
+    let ids: Vec<i32> = gone.iter().map(|row| row.get("id")).collect();
+
+    tx.execute(
+        "UPDATE actor.usr SET expire_date = NOW() WHERE id = ANY($1::INT[])",
+        &[&ids],
+    )
+    .map_err(|e| db_err("Error expiring patrons", e))?;
+
+    for row in &gone {
+        let usr: i32 = row.get("id");
+        let usrname: String = row.get("usrname");
+        report += &format!("expired,{},{usr},\n", csv_field(&usrname));
+    }
+
+    summary.expired = gone.len();
+
+    ops.dry_run.finish(tx)?;
+
+    con.disconnect();
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    if let Err(e) = writer.write_all(report.as_bytes()) {
+        return Err(format!("Error writing report: {e}"));
+    }
+
+    audit::count("created", summary.created as u64);
+    audit::count("updated", summary.updated as u64);
+    audit::count("expired", summary.expired as u64);
+    audit::count("errors", summary.errors as u64);
+
+    info!(
+        "{}created {}, updated {}, unchanged {}, expired {}, skipped {}, errors {}",
+        if ops.dry_run.enabled() {
+            "Dry run: "
+        } else {
+            ""
+        },
+        summary.created,
+        summary.updated,
+        summary.unchanged,
+        summary.expired,
+        summary.skipped,
+        summary.errors
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(sync(&mut connection, &options))
+}
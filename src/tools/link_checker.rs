@@ -0,0 +1,494 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use log::{debug, error, info};
+use marcutil::Record;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use threadpool::ThreadPool;
+
+const DEFAULT_RESULT_TABLE: &str = "egutil.link_check";
+
+#[derive(Clone)]
+struct CheckOptions {
+    min_id: i64,
+    max_id: i64,
+    max_threads: usize,
+    host_delay: u64,
+    timeout: u64,
+    all: bool,
+    out_file: Option<String>,
+    result_table: Option<String>,
+    dry_run: DryRun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkStatus {
+    Ok,
+    Redirect,
+    Dead,
+}
+
+impl LinkStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LinkStatus::Ok => "ok",
+            LinkStatus::Redirect => "redirect",
+            LinkStatus::Dead => "dead",
+        }
+    }
+}
+
+struct CheckResult {
+    url: String,
+    status: LinkStatus,
+    code: Option<u16>,
+    location: Option<String>,
+    error: Option<String>,
+}
+
+fn read_options() -> (CheckOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "max-threads", "Max Concurrent Checks", "MAX_THREADS");
+    opts.optopt(
+        "",
+        "host-delay",
+        "Milliseconds Between Requests per Host",
+        "MS",
+    );
+    opts.optopt("", "timeout", "Request Timeout Seconds", "TIMEOUT");
+    opts.optopt("", "out-file", "Report File", "OUTPUT_FILE");
+    opts.optflagopt("", "update-table", "Store Results in Table", "TABLE");
+    opts.optflag("", "all", "Report All Links, Including OK");
+
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let result_table = match params.opt_present("update-table") {
+        true => Some(
+            params
+                .opt_str("update-table")
+                .unwrap_or(DEFAULT_RESULT_TABLE.to_string()),
+        ),
+        false => None,
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        CheckOptions {
+            result_table,
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            max_threads: params.opt_get_default("max-threads", 10).unwrap(),
+            host_delay: params.opt_get_default("host-delay", 1000).unwrap(),
+            timeout: params.opt_get_default("timeout", 15).unwrap(),
+            all: params.opt_present("all"),
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin link-checker -- --out-file /tmp/dead-links.csv
+
+Options
+
+    --min-id
+        Only check records whose ID is >= this value.
+
+    --max-id
+        Only check records whose ID is < this value.
+
+    --max-threads
+        Number of links checked in parallel.  Defaults to 10.
+
+    --host-delay
+        Minimum milliseconds between requests to the same host.
+        Defaults to 1000.
+
+    --timeout
+        Seconds to wait for each response.  Defaults to 15.
+
+    --out-file
+        Write the CSV report to this file.  Otherwise, writes to STDOUT.
+
+    --all
+        Include working links in the report.
+
+    --update-table[=TABLE]
+        Replace the check results for each URL in this table,
+        which is created if necessary.  Defaults to {DEFAULT_RESULT_TABLE}.
+
+    --dry-run
+        With --update-table, store each URL's results, then roll them
+        back, reporting what a real run would do.  The table is not
+        created.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn create_sql(ops: &CheckOptions) -> String {
+    let mut filter = String::from("WHERE NOT bre.deleted AND bre.marc ~ 'tag=\"856\"'");
+
+    if ops.min_id > -1 {
+        filter += &format!(" AND bre.id >= {}", ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        filter += &format!(" AND bre.id < {}", ops.max_id);
+    }
+
+    format!("SELECT bre.id, bre.marc FROM biblio.record_entry bre {filter} ORDER BY bre.id")
+}
+
+/// Map of URL to the records which contain it.
+fn collect_urls(
+    con: &mut DatabaseConnection,
+    ops: &CheckOptions,
+) -> Result<HashMap<String, Vec<i64>>, String> {
+    let mut urls: HashMap<String, Vec<i64>> = HashMap::new();
+
+    let rows = match con.client().query(&create_sql(ops)[..], &[]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error querying records: {e}")),
+    };
+
+    for row in rows {
+        let id: i64 = row.get("id");
+        let marc: &str = row.get("marc");
+
+        let record = match Record::from_xml(marc).next() {
+            Some(r) => r,
+            None => {
+                error!("Cannot parse record {id}");
+                continue;
+            }
+        };
+
+        for field in record.get_fields("856") {
+            for sf in field.get_subfields("u") {
+                let url = sf.content.trim();
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    let ids = urls.entry(url.to_string()).or_default();
+                    if !ids.contains(&id) {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(urls)
+}
+
+fn url_host(url: &str) -> String {
+    let rest = url.split_once("://").map(|(_, r)| r).unwrap_or(url);
+    rest.split(['/', '?', '#'])
+        .next()
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+/// Tracks the next time a request may be sent to each host.
+struct HostThrottle {
+    delay: Duration,
+    next: Mutex<HashMap<String, Instant>>,
+}
+
+impl HostThrottle {
+    /// Sleep until we're allowed to contact the host.
+    fn wait(&self, host: &str) {
+        let now = Instant::now();
+
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let slot = next.entry(host.to_string()).or_insert(now);
+            let start = if *slot > now { *slot } else { now };
+            *slot = start + self.delay;
+            start - now
+        };
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+fn check_url(agent: &ureq::Agent, url: &str) -> CheckResult {
+    let mut result = agent.head(url).call();
+
+    // Some servers refuse HEAD requests.
+    if let Err(ureq::Error::Status(code, _)) = result {
+        if code == 405 || code == 501 {
+            result = agent.get(url).call();
+        }
+    }
+
+    let (status, code, location, error) = match result {
+        Ok(resp) => {
+            let code = resp.status();
+            match code {
+                300..=399 => (
+                    LinkStatus::Redirect,
+                    Some(code),
+                    resp.header("location").map(|l| l.to_string()),
+                    None,
+                ),
+                _ => (LinkStatus::Ok, Some(code), None, None),
+            }
+        }
+        Err(ureq::Error::Status(code, _)) => (LinkStatus::Dead, Some(code), None, None),
+        Err(ureq::Error::Transport(t)) => (LinkStatus::Dead, None, None, Some(t.to_string())),
+    };
+
+    CheckResult {
+        url: url.to_string(),
+        status,
+        code,
+        location,
+        error,
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn write_line(writer: &mut Box<dyn Write>, fields: &[&str]) -> Result<(), String> {
+    let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+    match writeln!(writer, "{}", line.join(",")) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing report: {e}")),
+    }
+}
+
+fn result_table_sql(table: &str) -> String {
+    let mut sql = String::new();
+
+    if let Some((schema, _)) = table.split_once('.') {
+        sql += &format!("CREATE SCHEMA IF NOT EXISTS {schema};");
+    }
+
+    let index = table.rsplit('.').next().unwrap_or(table);
+
+    sql += &format!(
+        r#"
+        CREATE TABLE IF NOT EXISTS {table} (
+            id          SERIAL PRIMARY KEY,
+            record      BIGINT NOT NULL,
+            url         TEXT NOT NULL,
+            status      TEXT NOT NULL,
+            http_code   INTEGER,
+            redirect    TEXT,
+            error       TEXT,
+            check_time  TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        CREATE INDEX IF NOT EXISTS {index}_url_idx ON {table} (url);
+        "#
+    );
+
+    sql
+}
+
+fn init_result_table(con: &mut DatabaseConnection, table: &str) -> Result<(), String> {
+    match con.client().batch_execute(&result_table_sql(table)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot create {table}: {e}")),
+    }
+}
+
+fn store_result(
+    con: &mut DatabaseConnection,
+    ops: &CheckOptions,
+    table: &str,
+    result: &CheckResult,
+    records: &[i64],
+) -> Result<(), String> {
+    let code = result.code.map(|c| c as i32);
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    // A dry run's table is rolled back with each result.
+    if ops.dry_run.enabled() {
+        if let Err(e) = tx.batch_execute(&result_table_sql(table)) {
+            return Err(format!("Cannot create {table}: {e}"));
+        }
+    }
+
+    let delete = format!("DELETE FROM {table} WHERE url = $1");
+    let insert = format!(
+        "INSERT INTO {table} (record, url, status, http_code, redirect, error)
+        VALUES ($1, $2, $3, $4, $5, $6)"
+    );
+
+    let stored = tx.execute(&delete[..], &[&result.url]).and_then(|_| {
+        for id in records {
+            tx.execute(
+                &insert[..],
+                &[
+                    id,
+                    &result.url,
+                    &result.status.as_str(),
+                    &code,
+                    &result.location,
+                    &result.error,
+                ],
+            )?;
+        }
+        Ok(())
+    });
+
+    if let Err(e) = stored {
+        return Err(format!("Error storing result for {}: {e}", result.url));
+    }
+
+    ops.dry_run.finish(tx)
+}
+
+fn check_links(con: &mut DatabaseConnection, ops: &CheckOptions) -> Result<(), String> {
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(fname) => match fs::File::create(fname) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {fname}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    con.connect()?;
+
+    if let Some(ref table) = ops.result_table {
+        if !ops.dry_run.enabled() {
+            init_result_table(con, table)?;
+        }
+    }
+
+    let urls = collect_urls(con, ops)?;
+    info!("Found {} distinct URLs to check", urls.len());
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(ops.timeout))
+        .redirects(0)
+        .user_agent(concat!("egutil-link-checker/", env!("CARGO_PKG_VERSION")))
+        .build();
+
+    let throttle = Arc::new(HostThrottle {
+        delay: Duration::from_millis(ops.host_delay),
+        next: Mutex::new(HashMap::new()),
+    });
+
+    let pool = ThreadPool::new(ops.max_threads);
+    let (tx, rx) = mpsc::channel();
+
+    for url in urls.keys() {
+        let url = url.to_string();
+        let agent = agent.clone();
+        let throttle = throttle.clone();
+        let tx = tx.clone();
+
+        pool.execute(move || {
+            throttle.wait(&url_host(&url));
+            debug!("Checking {url}");
+            tx.send(check_url(&agent, &url)).ok();
+        });
+    }
+
+    // Only the worker copies remain, so the receiver loop ends once
+    // every check has reported.
+    drop(tx);
+
+    write_line(
+        &mut writer,
+        &["status", "http_code", "url", "redirect", "error", "records"],
+    )?;
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+
+    for result in rx {
+        *counts.entry(result.status.as_str()).or_default() += 1;
+
+        let records = &urls[&result.url];
+
+        if let Some(ref table) = ops.result_table {
+            if let Err(e) = store_result(con, ops, table, &result, records) {
+                error!("{e}");
+            }
+        }
+
+        if result.status == LinkStatus::Ok && !ops.all {
+            continue;
+        }
+
+        let ids: Vec<String> = records.iter().map(|i| i.to_string()).collect();
+        let code = result.code.map(|c| c.to_string()).unwrap_or_default();
+
+        write_line(
+            &mut writer,
+            &[
+                result.status.as_str(),
+                &code,
+                &result.url,
+                result.location.as_deref().unwrap_or(""),
+                result.error.as_deref().unwrap_or(""),
+                &ids.join(" "),
+            ],
+        )?;
+    }
+
+    pool.join();
+    con.disconnect();
+
+    audit::count("urls", urls.len() as u64);
+    for status in ["ok", "redirect", "dead"] {
+        audit::count(status, *counts.get(status).unwrap_or(&0) as u64);
+    }
+
+    info!(
+        "Checked {} URLs: {} ok, {} redirected, {} dead",
+        urls.len(),
+        counts.get("ok").unwrap_or(&0),
+        counts.get("redirect").unwrap_or(&0),
+        counts.get("dead").unwrap_or(&0),
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(check_links(&mut connection, &options))
+}
@@ -0,0 +1,159 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::testing::{self, ScratchDatabase};
+use log::info;
+
+struct FixtureOptions {
+    create_db: bool,
+    schema: bool,
+    sample_data: bool,
+    sql_files: Vec<String>,
+    force: bool,
+}
+
+fn read_options() -> (FixtureOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optflag("", "create-db", "Drop and Re-Create the Database");
+    opts.optflag("", "schema", "Create the Schema Subset");
+    opts.optflag("", "sample-data", "Load the Sample Data");
+    opts.optmulti("", "sql-file", "Load SQL File", "SQL_FILE");
+    opts.optflag("", "force", "Allow Changes to Evergreen Databases");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        FixtureOptions {
+            create_db: params.opt_present("create-db"),
+            schema: params.opt_present("schema"),
+            sample_data: params.opt_present("sample-data"),
+            sql_files: params.opt_strs("sql-file"),
+            force: params.opt_present("force"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin load-fixtures -- --db-name egutil_test --create-db --schema --sample-data
+
+Loads test fixtures into a scratch database.
+
+The schema is a small subset of Evergreen's (org units, settings,
+patrons, bibs, call numbers, items, circulations, holds and
+billing) with the same table and column names, plus the org unit
+tree and setting lookup functions.  The sample data is a small
+consortium with a few patrons, bibs and items.
+
+Evergreen's Concerto dataset (Open-ILS/tests/datasets/sql/) may be
+loaded with --sql-file, but requires a full Evergreen schema rather
+than the subset.
+
+Databases containing config.upgrade_log are assumed to be real
+Evergreen databases and are not modified without --force.
+
+Options
+
+    --create-db
+        Drop and re-create --db-name, connecting to the "postgres"
+        database to do so.
+
+    --schema
+        Create the schema subset.
+
+    --sample-data
+        Load the sample data.
+
+    --sql-file
+        Run an SQL file after any schema and sample data.  psql \i
+        includes are followed.  Repeatable.
+
+    --force
+        Modify the database even if it looks like a real Evergreen
+        database.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn load(con: &mut DatabaseConnection, ops: &FixtureOptions) -> Result<(), String> {
+    let mut scratch = None;
+
+    if ops.create_db {
+        // Check before dropping, if the database exists.
+        if !ops.force && con.connect().is_ok() {
+            let is_eg = testing::is_evergreen_database(con.client())?;
+            con.disconnect();
+            if is_eg {
+                return Err(format!(
+                    "{} looks like an Evergreen database; use --force to re-create it",
+                    con.database()
+                ));
+            }
+        }
+
+        let mut db = ScratchDatabase::create(con, con.database())?;
+        db.keep();
+        info!("Created database {}", db.name());
+        scratch = Some(db);
+    }
+
+    let con = match scratch.as_mut() {
+        Some(db) => db.connection(),
+        None => {
+            con.connect()?;
+            con
+        }
+    };
+
+    if !ops.force && testing::is_evergreen_database(con.client())? {
+        return Err(format!(
+            "{} looks like an Evergreen database; use --force to modify it",
+            con.database()
+        ));
+    }
+
+    if ops.schema {
+        if testing::has_schema(con.client())? {
+            return Err(format!("{} already has a schema", con.database()));
+        }
+        testing::create_schema(con.client())?;
+        info!("Created schema");
+    }
+
+    if ops.sample_data {
+        testing::load_sample_data(con.client())?;
+        info!("Loaded sample data");
+    }
+
+    for path in &ops.sql_files {
+        testing::load_sql_file(con.client(), path)?;
+        info!("Loaded {path}");
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    load(&mut connection, &options)
+}
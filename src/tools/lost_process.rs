@@ -0,0 +1,384 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::money::{self, Money, OVERDUE_BTYPE};
+use log::{info, warn};
+use postgres as pg;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+struct LostOptions {
+    config_file: String,
+    staff: i32,
+    dry_run: DryRun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mark {
+    Lost,
+    LongOverdue,
+}
+
+/// Evergreen stop_fines value, copy status and billing types for
+/// each kind of marking.
+struct MarkPolicy {
+    stop_fines: &'static str,
+    copy_status: i32,
+    price_btype: i32,
+    fee_btype: i32,
+    fee_setting: &'static str,
+    void_setting: &'static str,
+}
+
+impl Mark {
+    fn policy(&self) -> MarkPolicy {
+        match self {
+            Mark::Lost => MarkPolicy {
+                stop_fines: "LOST",
+                copy_status: 3,
+                price_btype: 3,
+                fee_btype: 4,
+                fee_setting: "circ.lost_materials_processing_fee",
+                void_setting: "circ.void_overdue_on_lost",
+            },
+            Mark::LongOverdue => MarkPolicy {
+                stop_fines: "LONGOVERDUE",
+                copy_status: 16,
+                price_btype: 10,
+                fee_btype: 11,
+                fee_setting: "circ.longoverdue_materials_processing_fee",
+                void_setting: "circ.void_overdue_on_longoverdue",
+            },
+        }
+    }
+}
+
+/// Per-org thresholds from the config file.
+struct OrgPolicy {
+    org: String,
+    days: i64,
+    mark: Mark,
+    charge_item_price: bool,
+}
+
+struct LibrarySummary {
+    marked: usize,
+    billed: Money,
+    voided: Money,
+}
+
+fn read_options() -> (LostOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "policy-file", "Org Policy Config File", "FILE");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        LostOptions {
+            config_file: cli::required(&params, "policy-file"),
+            staff: cli::required(&params, "staff"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin lost-process -- --policy-file lost.json --staff 1 --dry-run
+
+Marks overdue circulations Lost or Long Overdue once they are a
+configured number of days past due, bills the item price and
+processing fee, optionally voids overdue fines, and prints a
+per-library summary.
+
+The config file lists org unit policies.  Circulations are matched
+by circulating library, including descendants.  The first matching
+policy wins, so list specific org units before their ancestors.
+
+    {{
+        "orgs": [
+            {{"org": "BR1", "days": 60, "mark": "lost", "charge_item_price": true}},
+            {{"org": "CONS", "days": 180, "mark": "long_overdue"}}
+        ]
+    }}
+
+For each circulation:
+
+    * stop_fines is set to LOST or LONGOVERDUE.
+    * The copy status is set to Lost (3) or Long Overdue (16).
+    * With charge_item_price, the copy price is billed, falling back
+      to cat.default_item_price and bounded by circ.min_item_price
+      and circ.max_item_price.
+    * The circ.lost_materials_processing_fee or
+      circ.longoverdue_materials_processing_fee org setting is billed.
+    * Overdue fines are voided when circ.void_overdue_on_lost or
+      circ.void_overdue_on_longoverdue is set.
+
+Options
+
+    --policy-file
+        Org policy config file.  Required.
+
+    --staff
+        Staff user recorded as copy editor and billing voider.
+        Required.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn read_config(path: &str) -> Result<Vec<OrgPolicy>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let config: Value = match serde_json::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let orgs = match config["orgs"].as_array() {
+        Some(o) => o,
+        None => return Err(format!("{path} has no orgs list")),
+    };
+
+    let mut list = Vec::new();
+
+    for org in orgs {
+        let (shortname, days) = match (org["org"].as_str(), org["days"].as_i64()) {
+            (Some(o), Some(d)) => (o, d),
+            _ => return Err(format!("Org policy requires org and days: {org}")),
+        };
+
+        let mark = match org["mark"].as_str().unwrap_or("lost") {
+            "lost" => Mark::Lost,
+            "long_overdue" => Mark::LongOverdue,
+            m => return Err(format!("Unknown mark for {shortname}: {m}")),
+        };
+
+        list.push(OrgPolicy {
+            org: shortname.to_string(),
+            days,
+            mark,
+            charge_item_price: org["charge_item_price"].as_bool().unwrap_or(true),
+        });
+    }
+
+    Ok(list)
+}
+
+/// Overdue circulations for a policy, with the amounts to bill
+/// computed from the circ library's settings.
+const CANDIDATE_SQL: &str = r#"
+    SELECT
+        circ.id,
+        circ.target_copy,
+        aou.shortname AS circ_lib,
+        CASE WHEN s.max_price IS NULL
+            THEN GREATEST(COALESCE(NULLIF(acp.price, 0), s.default_price, 0), COALESCE(s.min_price, 0))
+            ELSE LEAST(
+                GREATEST(COALESCE(NULLIF(acp.price, 0), s.default_price, 0), COALESCE(s.min_price, 0)),
+                s.max_price
+            )
+        END::TEXT AS price,
+        COALESCE(s.fee, 0)::TEXT AS fee,
+        COALESCE(s.void_overdues, FALSE) AS void_overdues
+    FROM action.circulation circ
+        JOIN asset.copy acp ON acp.id = circ.target_copy
+        JOIN actor.org_unit aou ON aou.id = circ.circ_lib
+        JOIN LATERAL (
+            SELECT
+                (SELECT value::JSON #>> '{}' FROM actor.org_unit_ancestor_setting(
+                    'cat.default_item_price', circ.circ_lib))::NUMERIC AS default_price,
+                (SELECT value::JSON #>> '{}' FROM actor.org_unit_ancestor_setting(
+                    'circ.min_item_price', circ.circ_lib))::NUMERIC AS min_price,
+                (SELECT value::JSON #>> '{}' FROM actor.org_unit_ancestor_setting(
+                    'circ.max_item_price', circ.circ_lib))::NUMERIC AS max_price,
+                (SELECT value::JSON #>> '{}' FROM actor.org_unit_ancestor_setting(
+                    $3, circ.circ_lib))::NUMERIC AS fee,
+                (SELECT value::JSON #>> '{}' FROM actor.org_unit_ancestor_setting(
+                    $4, circ.circ_lib))::BOOL AS void_overdues
+        ) s ON TRUE
+    WHERE circ.checkin_time IS NULL
+        AND circ.xact_finish IS NULL
+        AND (circ.stop_fines IS NULL OR circ.stop_fines = 'MAXFINES')
+        AND circ.due_date < NOW() - ($2::BIGINT * '1 day'::INTERVAL)
+        AND acp.status = 1
+        AND circ.circ_lib IN (SELECT id FROM actor.org_unit_descendants($1::INT))
+    ORDER BY circ.id
+"#;
+
+/// Process one circulation, returning the amounts billed and voided.
+fn process_circ(
+    tx: &mut pg::Transaction,
+    ops: &LostOptions,
+    policy: &OrgPolicy,
+    row: &pg::Row,
+) -> Result<(Money, Money), String> {
+    let rules = policy.mark.policy();
+    let circ_id: i64 = row.get("id");
+    let copy_id: i64 = row.get("target_copy");
+    let price = Money::parse(row.get("price"))?;
+    let fee = Money::parse(row.get("fee"))?;
+    let void_overdues: bool = row.get("void_overdues");
+
+    let sql = r#"
+        UPDATE action.circulation SET stop_fines = $2, stop_fines_time = NOW()
+        WHERE id = $1
+    "#;
+    tx.execute(sql, &[&circ_id, &rules.stop_fines])
+        .map_err(|e| db_err("Error updating circulation", e))?;
+
+    let sql = r#"
+        UPDATE asset.copy SET status = $2, editor = $3, edit_date = NOW()
+        WHERE id = $1
+    "#;
+    tx.execute(sql, &[&copy_id, &rules.copy_status, &ops.staff])
+        .map_err(|e| db_err("Error updating copy", e))?;
+
+    let mut bills = Vec::new();
+    if policy.charge_item_price {
+        bills.push((price, rules.price_btype));
+    }
+    bills.push((fee, rules.fee_btype));
+
+    let mut billed = Money::ZERO;
+    for (amount, btype) in bills {
+        if !amount.is_positive() {
+            continue;
+        }
+        money::add_billing(tx, circ_id, amount, btype, "System: lost-process")?;
+        billed += amount;
+    }
+
+    let mut voided = Money::ZERO;
+    if void_overdues {
+        voided = money::void_billings(tx, circ_id, Some(OVERDUE_BTYPE), ops.staff)?;
+    }
+
+    Ok((billed, voided))
+}
+
+fn process(con: &mut DatabaseConnection, ops: &LostOptions) -> Result<(), String> {
+    let policies = read_config(&ops.config_file)?;
+
+    con.connect()?;
+
+    // circ_lib => (marked, billed, voided)
+    let mut totals: BTreeMap<String, (usize, Money, Money)> = BTreeMap::new();
+
+    for policy in &policies {
+        let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+        let org_id: i32 = match con.client().query_opt(sql, &[&policy.org]) {
+            Ok(Some(row)) => row.get("id"),
+            Ok(None) => return Err(format!("No such org unit: {}", policy.org)),
+            Err(e) => return Err(db_err("Error loading org unit", e)),
+        };
+
+        let rules = policy.mark.policy();
+
+        let rows = con
+            .client()
+            .query(
+                CANDIDATE_SQL,
+                &[
+                    &org_id,
+                    &policy.days,
+                    &rules.fee_setting,
+                    &rules.void_setting,
+                ],
+            )
+            .map_err(|e| db_err("Error finding overdue circulations", e))?;
+
+        info!(
+            "{}: {} circulations over {} days overdue",
+            policy.org,
+            rows.len(),
+            policy.days
+        );
+
+        for row in &rows {
+            let circ_id: i64 = row.get("id");
+            let circ_lib: String = row.get("circ_lib");
+
+            let mut tx = ops.dry_run.transaction(con.client())?;
+
+            // Each circulation commits on its own so a failure only
+            // skips that circulation.
+            match process_circ(&mut tx, ops, policy, row) {
+                Ok((billed, voided)) => {
+                    ops.dry_run.finish(tx)?;
+
+                    let entry = totals.entry(circ_lib).or_default();
+                    entry.0 += 1;
+                    entry.1 += billed;
+                    entry.2 += voided;
+                }
+                Err(e) => warn!("Circulation {circ_id}: {e}"),
+            }
+        }
+    }
+
+    let mut summary: BTreeMap<String, LibrarySummary> = BTreeMap::new();
+    for (lib, (marked, billed, voided)) in totals {
+        summary.insert(
+            lib,
+            LibrarySummary {
+                marked,
+                billed,
+                voided,
+            },
+        );
+    }
+
+    con.disconnect();
+
+    println!("circ_lib,marked,billed,voided");
+    for (lib, s) in &summary {
+        audit::count("marked", s.marked as u64);
+        println!("{lib},{},{},{}", s.marked, s.billed, s.voided);
+    }
+
+    if ops.dry_run.enabled() {
+        println!("Dry run; no changes saved");
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(process(&mut connection, &options))
+}
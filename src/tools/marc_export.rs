@@ -0,0 +1,219 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::export::{self, Exporter, Format, MarcWriter, RecordType};
+use crate::lockfile;
+use std::fs;
+
+struct ExportOptions {
+    min_id: i64,
+    max_id: i64,
+    to_xml: bool,
+    newest_first: bool,
+    destination: ExportDestination,
+    query_file: Option<String>,
+    lockfile: Option<String>,
+    flush_every: u64,
+    fsync: bool,
+    authority: bool,
+    thesauri: Vec<String>,
+    heading_tags: Vec<String>,
+}
+
+enum ExportDestination {
+    Stdout,
+    File(String),
+}
+
+fn read_options() -> (ExportOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "out-file", "Output File", "OUTPUT_FILE");
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+
+    opts.optflag("", "to-xml", "Export to XML");
+    opts.optflag("", "newest-first", "Newest First");
+    opts.optopt("", "flush-every", "Flush Output Every N Records", "N");
+    opts.optflag("", "fsync", "Sync Output to Disk at Each Flush");
+    opts.optflag("", "authority", "Export Authority Records");
+    opts.optmulti("", "thesaurus", "Authority Thesaurus", "THESAURUS");
+    opts.optmulti("", "heading-tags", "Authority Heading Tag Range", "TAGS");
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let destination = match params.opt_get::<String>("out-file").unwrap() {
+        Some(filename) => ExportDestination::File(filename),
+        None => ExportDestination::Stdout,
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ExportOptions {
+            destination,
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            newest_first: params.opt_present("newest-first"),
+            to_xml: params.opt_present("to-xml"),
+            query_file: params.opt_get("query-file").unwrap(),
+            lockfile: params.opt_str("lockfile"),
+            flush_every: params.opt_get_default("flush-every", 0).unwrap(),
+            fsync: params.opt_present("fsync"),
+            authority: params.opt_present("authority"),
+            thesauri: split_values(params.opt_strs("thesaurus")),
+            heading_tags: split_values(params.opt_strs("heading-tags")),
+        },
+        connection,
+    )
+}
+
+/// Values from repeated options, also split on commas.
+fn split_values(values: Vec<String>) -> Vec<String> {
+    values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run -- --out-file /tmp/records.mrc
+    cargo run -- --authority --thesaurus lcsh --heading-tags 100-111,130 --out-file /tmp/names.mrc
+
+Exports bib records, or authority records with --authority.
+
+Options
+
+    --min-id
+        Only export records whose ID is >= this value.
+
+    --max-id
+        Only export records whose ID is <= this value.
+
+    --out-file
+        Write data to this file.
+        Otherwise, writes to STDOUT.
+
+    --query-file
+        Path to a file containing an SQL query.  The query must
+        produce rows that have a column named "marc".
+
+    --newest-first
+        Export records newest to oldest by create date.
+        Otherwise, export oldests to newest.
+
+    --authority
+        Export authority records instead of bib records.
+
+    --thesaurus
+        Only export authority records using this subject heading
+        thesaurus: lcsh, lcac, mesh, nal, cash, aat, sears, rvm, or
+        local (other, 008/11 z).  Single letter 008/11 codes are also
+        accepted.  Repeatable, or comma separated.
+
+    --heading-tags
+        Only export authority records whose main heading tag is in
+        this range, e.g. 100-111, 150, or 1XX.  Repeatable, or comma
+        separated.
+
+    --flush-every
+        Flush buffered output every this many records.  Otherwise,
+        output is written in 1MB chunks and flushed at the end.
+
+    --fsync
+        With --out-file, sync the file to disk at each flush and at
+        the end, so records up to the last flush survive a crash.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn export(con: &mut DatabaseConnection, ops: &ExportOptions) -> Result<(), String> {
+    let format = match ops.to_xml {
+        true => Format::Xml,
+        false => Format::Binary,
+    };
+
+    // Where are we spewing bytes?
+    let mut sink = match &ops.destination {
+        ExportDestination::File(fname) => MarcWriter::create(fname, format)?,
+        _ => MarcWriter::stdout(format),
+    };
+
+    sink.set_flush_every(ops.flush_every);
+    sink.set_fsync(ops.fsync);
+
+    let mut exporter = Exporter::new();
+
+    if let Some(fname) = &ops.query_file {
+        match fs::read_to_string(fname) {
+            Ok(sql) => exporter.set_query(&sql),
+            Err(e) => return Err(format!("Cannot read {fname}: {e}")),
+        }
+    }
+
+    if ops.min_id > -1 {
+        exporter.set_min_id(ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        exporter.set_max_id(ops.max_id);
+    }
+
+    exporter.set_newest_first(ops.newest_first);
+
+    if ops.authority {
+        exporter.set_record_type(RecordType::Authority);
+
+        for name in &ops.thesauri {
+            match export::thesaurus_code(name) {
+                Some(code) => exporter.add_thesaurus(code),
+                None => return Err(format!("Unknown thesaurus: {name}")),
+            }
+        }
+
+        for range in &ops.heading_tags {
+            exporter.add_heading_tags(range)?;
+        }
+    } else if !ops.thesauri.is_empty() || !ops.heading_tags.is_empty() {
+        return Err("--thesaurus and --heading-tags require --authority".to_string());
+    }
+
+    con.connect()?;
+
+    exporter.run(con, &mut sink)?;
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    export(&mut connection, &options)
+}
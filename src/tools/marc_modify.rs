@@ -0,0 +1,392 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::marc::{self, rules::RuleSet};
+use crate::snapshot::Snapshot;
+use log::{info, warn};
+use marcutil::Record;
+use std::io::prelude::*;
+use std::{fs, io};
+
+const XML_COLLECTION_HEADER: &str = r#"<collection xmlns="http://www.loc.gov/MARC21/slim">"#;
+const XML_COLLECTION_FOOTER: &str = "</collection>";
+
+/// Records selected at a time, so the catalog is never held in memory.
+const BATCH_SIZE: i64 = 1000;
+
+struct ModifyOptions {
+    rules_file: String,
+    min_id: i64,
+    max_id: i64,
+    query_file: Option<String>,
+    in_file: Option<String>,
+    out_file: Option<String>,
+    binary: bool,
+    staff: Option<i32>,
+    dry_run: DryRun,
+    diff: bool,
+    snapshot: Snapshot,
+}
+
+fn read_options() -> (ModifyOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "rules-file", "JSON Rules File", "RULES_FILE");
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+    opts.optopt("", "in-file", "Modify Records in File", "INPUT_FILE");
+    opts.optopt(
+        "",
+        "out-file",
+        "Write Modified Records to File",
+        "OUTPUT_FILE",
+    );
+    opts.optopt("", "staff", "Staff User ID for Record Editor", "USER_ID");
+    opts.optflag("", "binary", "Files are Binary MARC");
+    opts.optflag("", "diff", "Show Changes While Saving");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+    cli::append_snapshot(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ModifyOptions {
+            rules_file: cli::required(&params, "rules-file"),
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            query_file: params.opt_get("query-file").unwrap(),
+            in_file: params.opt_get("in-file").unwrap(),
+            out_file: params.opt_get("out-file").unwrap(),
+            binary: params.opt_present("binary"),
+            staff: params.opt_get("staff").unwrap(),
+            dry_run: DryRun::from_params(&params),
+            diff: params.opt_present("diff"),
+            snapshot: Snapshot::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin marc-modify -- --rules-file rules.json --max-id 1000 --dry-run
+    cargo run --bin marc-modify -- --rules-file rules.json --in-file in.xml --out-file out.xml
+
+Applies declarative modification rules to bib records in the database
+or in a file.
+
+Rules are a JSON array applied in order to each record.  Actions:
+
+    add_field        tag, ind1, ind2, subfields [[code, value], ...]
+                     or content for control fields
+    delete_field     tag, optional subfield and matches
+    delete_subfield  tag, subfield, optional matches
+    copy_subfield    tag, subfield, to_subfield, optional matches,
+                     to_tag, ind1, ind2
+    move_subfield    like copy_subfield, removing the source
+
+Any rule may have an "if" or "unless" condition with a tag and an
+optional subfield and matches.  Tags may use X as a wildcard, e.g.
+9XX.  "matches" values are regular expressions.
+
+    [
+        {{"action": "delete_field", "tag": "9XX", "unless": {{"tag": "901"}}}},
+        {{"action": "move_subfield", "tag": "650", "subfield": "x", "to_subfield": "v",
+            "if": {{"tag": "650", "subfield": "x", "matches": "^Juvenile"}}}}
+    ]
+
+Options
+
+    --rules-file
+        JSON rules file.  Required.
+
+    --min-id
+    --max-id
+        Only modify database records with IDs in this range.
+
+    --query-file
+        Path to a file containing an SQL query.  The query must
+        produce rows with "id" and "marc" columns, one row per
+        record ID.
+
+    --in-file
+        Modify MARC XML records in this file instead of the database.
+
+    --out-file
+        With --in-file, write all records to this file.
+        Otherwise, writes to STDOUT.
+
+    --binary
+        --in-file and --out-file contain binary MARC.
+
+    --staff
+        Set biblio.record_entry.editor to this user for modified
+        records.
+
+    --diff
+        Print a diff of changed records while saving.
+
+    --dry-run
+        Make the changes, then roll them back, printing a diff of
+        changed records.  With --in-file, no records are written
+        and --snapshot and --snapshot-file are ignored.
+
+{}
+    --snapshot
+        Save each database record ahead of changing it, for the
+        rollback tool.  The run ID to roll back is logged.
+
+    --snapshot-file
+        Save each database record ahead of changing it to this file,
+        for rollback --snapshot-file.  Cannot be combined with
+        --snapshot.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+/// Apply the rules, returning the before and after breaker lines if
+/// the record changed.
+fn apply(rules: &RuleSet, record: &mut Record) -> Option<(Vec<String>, Vec<String>)> {
+    let before = marc::breaker_lines(record);
+
+    if rules.apply(record) == 0 {
+        return None;
+    }
+
+    let after = marc::breaker_lines(record);
+
+    match before == after {
+        true => None,
+        false => Some((before, after)),
+    }
+}
+
+fn print_diff(label: &str, before: &[String], after: &[String]) {
+    // Diff output may conflict with records written to STDOUT.
+    eprintln!("--- {label}");
+    for line in marc::diff_lines(before, after) {
+        eprintln!("{line}");
+    }
+}
+
+fn create_sql(ops: &ModifyOptions) -> Result<String, String> {
+    if let Some(ref fname) = ops.query_file {
+        return match fs::read_to_string(fname) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(format!("Cannot read {fname}: {e}")),
+        };
+    }
+
+    let mut sql =
+        String::from("SELECT id, marc FROM biblio.record_entry WHERE NOT deleted AND id > 0");
+
+    if ops.min_id > -1 {
+        sql += &format!(" AND id >= {}", ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        sql += &format!(" AND id <= {}", ops.max_id);
+    }
+
+    Ok(sql)
+}
+
+fn modify_database(
+    con: &mut DatabaseConnection,
+    ops: &ModifyOptions,
+    rules: &RuleSet,
+) -> Result<(), String> {
+    con.connect()?;
+
+    let record_set = create_sql(ops)?;
+
+    let select =
+        format!("SELECT q.id, q.marc FROM ({record_set}) q WHERE q.id > $1 ORDER BY q.id LIMIT $2");
+
+    let update = r#"
+        UPDATE biblio.record_entry
+        SET marc = $1, editor = COALESCE($2, editor), edit_date = NOW()
+        WHERE id = $3
+    "#;
+
+    // A dry run keeps nothing, so has nothing to save.
+    let snapshot = match ops.dry_run.enabled() {
+        true => Snapshot::default(),
+        false => ops.snapshot.clone(),
+    };
+
+    ops.dry_run.begin(con.client())?;
+
+    let mut saved = snapshot.begin(con.client(), "marc-modify")?;
+
+    let mut records: u64 = 0;
+    let mut modified = 0;
+    let mut last_id: i64 = 0;
+
+    loop {
+        let rows = match con
+            .client()
+            .query(select.as_str(), &[&last_id, &BATCH_SIZE])
+        {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Error selecting records: {e}")),
+        };
+
+        if rows.is_empty() {
+            break;
+        }
+
+        for row in &rows {
+            let id: i64 = row.get("id");
+            let xml: &str = row.get("marc");
+            last_id = id;
+            records += 1;
+
+            let mut record = match Record::from_xml(xml).next() {
+                Some(r) => r,
+                None => {
+                    warn!("Record {id} cannot be parsed; skipping");
+                    continue;
+                }
+            };
+
+            let (before, after) = match apply(rules, &mut record) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            modified += 1;
+
+            if ops.dry_run.enabled() || ops.diff {
+                print_diff(&format!("record {id}"), &before, &after);
+            }
+
+            saved.save(
+                con.client(),
+                "biblio.record_entry",
+                &["marc", "editor", "edit_date"],
+                &[id],
+            )?;
+
+            let xml = record.to_xml()?;
+            if let Err(e) = con.client().execute(update, &[&xml, &ops.staff, &id]) {
+                return Err(format!("Error updating record {id}: {e}"));
+            }
+        }
+
+        info!("Processed {records} records");
+    }
+
+    saved.finish();
+
+    ops.dry_run.end(con.client())?;
+
+    audit::count("records", records);
+    audit::count("modified", modified);
+
+    con.disconnect();
+
+    info!(
+        "{modified} of {records} records {}",
+        ops.dry_run.past("modified")
+    );
+
+    Ok(())
+}
+
+fn modify_file(ops: &ModifyOptions, path: &str, rules: &RuleSet) -> Result<(), String> {
+    let mut records = marc::read_file(path, ops.binary)?;
+
+    let mut writer: Option<Box<dyn Write>> = match (&ops.out_file, ops.dry_run.enabled()) {
+        (_, true) => None,
+        (Some(f), false) => match fs::File::create(f) {
+            Ok(f) => Some(Box::new(f)),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        (None, false) => Some(Box::new(io::stdout())),
+    };
+
+    if let (Some(w), false) = (writer.as_mut(), ops.binary) {
+        write(w, XML_COLLECTION_HEADER.as_bytes())?;
+    }
+
+    let mut modified = 0;
+
+    for (idx, record) in records.iter_mut().enumerate() {
+        if let Some((before, after)) = apply(rules, record) {
+            modified += 1;
+            if ops.dry_run.enabled() || ops.diff {
+                print_diff(&format!("record {}", idx + 1), &before, &after);
+            }
+        }
+
+        if let Some(w) = writer.as_mut() {
+            match ops.binary {
+                true => write(w, &record.to_binary()?)?,
+                false => write(w, record.to_xml()?.as_bytes())?,
+            }
+        }
+    }
+
+    if let (Some(w), false) = (writer.as_mut(), ops.binary) {
+        write(w, XML_COLLECTION_FOOTER.as_bytes())?;
+    }
+
+    audit::count("records", records.len() as u64);
+    audit::count("modified", modified);
+
+    info!(
+        "{modified} of {} records {}",
+        records.len(),
+        ops.dry_run.past("modified")
+    );
+
+    Ok(())
+}
+
+fn write(writer: &mut Box<dyn Write>, bytes: &[u8]) -> Result<(), String> {
+    match writer.write_all(bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing bytes: {e}")),
+    }
+}
+
+fn modify(con: &mut DatabaseConnection, ops: &ModifyOptions) -> Result<(), String> {
+    let rules = RuleSet::from_file(&ops.rules_file)?;
+
+    if rules.is_empty() {
+        return Err(format!("No rules found in {}", ops.rules_file));
+    }
+
+    info!("Loaded {} rules", rules.len());
+
+    match ops.in_file {
+        Some(ref path) => modify_file(ops, path, &rules),
+        None => modify_database(con, ops, &rules),
+    }
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(modify(&mut connection, &options))
+}
@@ -0,0 +1,295 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::marc::FieldSpec;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use marcutil::Record;
+use postgres::fallible_iterator::FallibleIterator;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::{fs, process};
+
+struct ReportOptions {
+    fields: Vec<String>,
+    min_id: i64,
+    max_id: i64,
+    query_file: Option<String>,
+    group: bool,
+    split: bool,
+    skip_empty: bool,
+    separator: String,
+    out_file: Option<String>,
+}
+
+fn read_options() -> (ReportOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "field", "Field Spec to Extract", "SPEC");
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+    opts.optflag("", "group", "Count Records per Distinct Value");
+    opts.optflag("", "split", "One Row per Value");
+    opts.optflag("", "skip-empty", "Skip Records with No Values");
+    opts.optopt("", "separator", "Multiple Value Separator", "SEPARATOR");
+    opts.optopt("", "out-file", "Report Output File", "FILE");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let fields: Vec<String> = params
+        .opt_strs("field")
+        .iter()
+        .flat_map(|f| f.split(','))
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect();
+
+    if fields.is_empty() {
+        eprintln!("--field is required");
+        process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ReportOptions {
+            fields,
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            query_file: params.opt_get("query-file").unwrap(),
+            group: params.opt_present("group"),
+            split: params.opt_present("split"),
+            skip_empty: params.opt_present("skip-empty"),
+            separator: params
+                .opt_get_default("separator", " | ".to_string())
+                .unwrap(),
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin marc-report -- --field 245a --field 050ab --max-id 10000
+    cargo run --bin marc-report -- --field 650a --split --group --out-file subjects.xlsx
+    cargo run --bin marc-report -- --field 008/35-37 --group
+
+Extracts values from stored bib MARC into a CSV report, one row per
+record with a column per field spec, for cataloging analysis without
+a full export.
+
+Field specs name a tag and subfield codes, with or without $, e.g.
+245$a, 050ab or 6XX$a$x.  Subfields from one field are joined with
+spaces.  A tag alone selects all subfields.  Control fields and the
+leader take optional character positions, e.g. 001, 008/35-37 or
+LDR/06.  Tags may use X as a wildcard.
+
+Records with several matching fields have their values joined with
+--separator, unless --split is used.
+
+Options
+
+    --field
+        Field spec to extract.  Repeatable, or comma separated.
+        Required.
+
+    --min-id
+    --max-id
+        Only report on records with IDs in this range.
+
+    --query-file
+        Path to a file containing an SQL query.  The query must
+        produce rows with "id" and "marc" columns.
+
+    --group
+        Report each distinct combination of values with the number
+        of records having it, most common first, in place of the
+        record rows.
+
+    --split
+        Write one row per value instead of joining multiple values.
+        With several field specs, writes a row per combination.
+        With --group, counts each value separately.
+
+    --skip-empty
+        Skip records where no field spec has a value.
+
+    --separator
+        Separates multiple values in one column.  Defaults to " | ".
+
+    --out-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn create_sql(ops: &ReportOptions) -> Result<String, String> {
+    if let Some(ref fname) = ops.query_file {
+        return match fs::read_to_string(fname) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(format!("Cannot read {fname}: {e}")),
+        };
+    }
+
+    let mut sql =
+        String::from("SELECT id, marc FROM biblio.record_entry WHERE NOT deleted AND id > 0");
+
+    if ops.min_id > -1 {
+        sql += &format!(" AND id >= {}", ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        sql += &format!(" AND id <= {}", ops.max_id);
+    }
+
+    Ok(sql + " ORDER BY id")
+}
+
+/// Report rows for one record's values, one list per spec.
+fn value_rows(ops: &ReportOptions, values: &[Vec<String>]) -> Vec<Vec<String>> {
+    if !ops.split {
+        return vec![values.iter().map(|v| v.join(&ops.separator)).collect()];
+    }
+
+    // Every combination, with an empty value standing in for specs
+    // with no values.
+    let mut rows: Vec<Vec<String>> = vec![Vec::new()];
+
+    for list in values {
+        let list: Vec<String> = match list.is_empty() {
+            true => vec![String::new()],
+            false => list.to_vec(),
+        };
+
+        let mut next = Vec::new();
+        for row in &rows {
+            for value in &list {
+                let mut row = row.clone();
+                row.push(value.to_string());
+                next.push(row);
+            }
+        }
+        rows = next;
+    }
+
+    rows
+}
+
+fn report(con: &mut DatabaseConnection, ops: &ReportOptions) -> Result<(), String> {
+    let specs = ops
+        .fields
+        .iter()
+        .map(|f| FieldSpec::parse(f))
+        .collect::<Result<Vec<FieldSpec>, String>>()?;
+
+    let sql = create_sql(ops)?;
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+
+    let mut header: Vec<&str> = Vec::new();
+    if !ops.group {
+        header.push("record");
+    }
+    header.extend(ops.fields.iter().map(|f| f.as_str()));
+    if ops.group {
+        header.push("count");
+    }
+    writer.write_header(&header)?;
+
+    con.connect()?;
+
+    let params: [&(dyn postgres::types::ToSql + Sync); 0] = [];
+
+    let mut rows = match con.client().query_raw(sql.as_str(), params) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error selecting records: {e}")),
+    };
+
+    let mut groups: BTreeMap<Vec<String>, i64> = BTreeMap::new();
+    let mut records = 0;
+
+    loop {
+        let row = match rows.next() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => return Err(format!("Error reading records: {e}")),
+        };
+
+        let id: i64 = row.get("id");
+        let xml: &str = row.get("marc");
+
+        let record = match Record::from_xml(xml).next() {
+            Some(r) => r,
+            None => {
+                warn!("Record {id} cannot be parsed; skipping");
+                continue;
+            }
+        };
+
+        let values: Vec<Vec<String>> = specs.iter().map(|s| s.extract(&record)).collect();
+
+        if ops.skip_empty && values.iter().all(|v| v.is_empty()) {
+            continue;
+        }
+
+        records += 1;
+
+        for values in value_rows(ops, &values) {
+            if ops.group {
+                *groups.entry(values).or_default() += 1;
+                continue;
+            }
+
+            let mut cells = vec![Cell::from(id)];
+            cells.extend(values.into_iter().map(Cell::from));
+            writer.write_row(&cells)?;
+        }
+
+        if records % 100000 == 0 {
+            info!("Processed {records} records");
+        }
+    }
+
+    if ops.group {
+        let mut groups: Vec<(Vec<String>, i64)> = groups.into_iter().collect();
+
+        // Most common first; ties stay in value order.
+        groups.sort_by_key(|(_, count)| Reverse(*count));
+
+        for (values, count) in groups {
+            let mut cells: Vec<Cell> = values.into_iter().map(Cell::from).collect();
+            cells.push(Cell::from(count));
+            writer.write_row(&cells)?;
+        }
+    }
+
+    writer.finish()?;
+
+    con.disconnect();
+
+    info!("Reported on {records} records");
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    report(&mut connection, &options)
+}
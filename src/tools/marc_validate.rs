@@ -0,0 +1,286 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::marc::{self, validate::Profile};
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use marcutil::Record;
+use std::fs;
+
+struct ValidateOptions {
+    profile_file: Option<String>,
+    min_id: i64,
+    max_id: i64,
+    query_file: Option<String>,
+    in_file: Option<String>,
+    binary: bool,
+    out_file: Option<String>,
+}
+
+/// Counts for the closing summary.
+#[derive(Default)]
+struct Summary {
+    records: usize,
+    invalid: usize,
+    issues: usize,
+}
+
+fn read_options() -> (ValidateOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "profile", "JSON Validation Profile", "PROFILE_FILE");
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+    opts.optopt("", "in-file", "Validate Records in File", "INPUT_FILE");
+    opts.optflag("", "binary", "Input File is Binary MARC");
+    opts.optopt("", "out-file", "Report Output File", "FILE");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ValidateOptions {
+            profile_file: params.opt_str("profile"),
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            query_file: params.opt_get("query-file").unwrap(),
+            in_file: params.opt_get("in-file").unwrap(),
+            binary: params.opt_present("binary"),
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin marc-validate -- --max-id 10000 --out-file problems.csv
+    cargo run --bin marc-validate -- --profile serials.json --in-file load.mrc --binary
+
+Checks bib records in the database or in a file against a validation
+profile and reports each problem found, for cataloging cleanup.
+
+A profile is a JSON array of checks run against every record:
+
+    required                 tag
+    non_repeatable           tag
+    indicators               tag, ind1 and/or ind2 allowed values
+    required_subfield        tag, subfield
+    non_repeatable_subfield  tag, subfield
+    isbn                     tag, subfield (default a)
+    issn                     tag, subfield (default a)
+    pattern                  tag, matches, and a subfield for data
+                             fields
+
+Tags may use X as a wildcard, e.g. 1XX.  Non-repeatable patterns
+count all matching fields together.  In indicator values, # stands
+for blank.  "matches" values are regular expressions.
+
+    [
+        {{"check": "required", "tag": "022"}},
+        {{"check": "non_repeatable", "tag": "1XX"}},
+        {{"check": "indicators", "tag": "245", "ind1": "01", "ind2": "0123456789"}},
+        {{"check": "issn", "tag": "022", "subfield": "a"}},
+        {{"check": "pattern", "tag": "008", "matches": "^.{{40}}$"}}
+    ]
+
+Without --profile, records are checked for a 008, 245 and at most one
+1XX, 245 indicators and $a, a 40 character 008, and 020 ISBN and 022
+ISSN check digits.
+
+The report has one CSV row per problem: record, tcn, tag and message.
+record is the database ID, or the position of the record in
+--in-file.
+
+Options
+
+    --profile
+        JSON validation profile.
+
+    --min-id
+    --max-id
+        Only validate database records with IDs in this range.
+
+    --query-file
+        Path to a file containing an SQL query.  The query must
+        produce rows with "id" and "marc" columns.
+
+    --in-file
+        Validate MARC XML records in this file instead of the
+        database.
+
+    --binary
+        --in-file contains binary MARC.
+
+    --out-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn create_sql(ops: &ValidateOptions) -> Result<String, String> {
+    if let Some(ref fname) = ops.query_file {
+        return match fs::read_to_string(fname) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(format!("Cannot read {fname}: {e}")),
+        };
+    }
+
+    let mut sql =
+        String::from("SELECT id, marc FROM biblio.record_entry WHERE NOT deleted AND id > 0");
+
+    if ops.min_id > -1 {
+        sql += &format!(" AND id >= {}", ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        sql += &format!(" AND id <= {}", ops.max_id);
+    }
+
+    Ok(sql + " ORDER BY id")
+}
+
+/// Validate one record, adding a report row per issue.
+fn check(
+    profile: &Profile,
+    writer: &mut TableWriter,
+    summary: &mut Summary,
+    id: i64,
+    record: &Record,
+) -> Result<(), String> {
+    let issues = profile.validate(record);
+
+    summary.records += 1;
+
+    if issues.is_empty() {
+        return Ok(());
+    }
+
+    summary.invalid += 1;
+    summary.issues += issues.len();
+
+    let tcn = marc::tcn(record);
+
+    for issue in issues {
+        writer.write_row(&[
+            Cell::from(id),
+            Cell::from(tcn.as_ref()),
+            Cell::from(issue.tag),
+            Cell::from(issue.message),
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn validate_database(
+    con: &mut DatabaseConnection,
+    ops: &ValidateOptions,
+    profile: &Profile,
+    writer: &mut TableWriter,
+) -> Result<Summary, String> {
+    con.connect()?;
+
+    let sql = create_sql(ops)?;
+
+    let rows = match con.client().query(sql.as_str(), &[]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error selecting records: {e}")),
+    };
+
+    let mut summary = Summary::default();
+
+    for row in &rows {
+        let id: i64 = row.get("id");
+        let xml: &str = row.get("marc");
+
+        match Record::from_xml(xml).next() {
+            Some(record) => check(profile, writer, &mut summary, id, &record)?,
+            None => {
+                warn!("Record {id} cannot be parsed");
+                writer.write_row(&[
+                    Cell::from(id),
+                    Cell::Empty,
+                    Cell::Empty,
+                    Cell::from("Record cannot be parsed"),
+                ])?;
+                summary.records += 1;
+                summary.invalid += 1;
+                summary.issues += 1;
+            }
+        }
+    }
+
+    con.disconnect();
+
+    Ok(summary)
+}
+
+fn validate_file(
+    ops: &ValidateOptions,
+    path: &str,
+    profile: &Profile,
+    writer: &mut TableWriter,
+) -> Result<Summary, String> {
+    let records = marc::read_file(path, ops.binary)?;
+
+    let mut summary = Summary::default();
+
+    for (idx, record) in records.iter().enumerate() {
+        check(profile, writer, &mut summary, idx as i64 + 1, record)?;
+    }
+
+    Ok(summary)
+}
+
+fn validate(con: &mut DatabaseConnection, ops: &ValidateOptions) -> Result<(), String> {
+    let profile = match &ops.profile_file {
+        Some(path) => Profile::from_file(path)?,
+        None => Profile::basic(),
+    };
+
+    if profile.is_empty() {
+        return Err("Validation profile has no checks".to_string());
+    }
+
+    info!("Loaded {} checks", profile.len());
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(&["record", "tcn", "tag", "message"])?;
+
+    let summary = match ops.in_file {
+        Some(ref path) => validate_file(ops, path, &profile, &mut writer)?,
+        None => validate_database(con, ops, &profile, &mut writer)?,
+    };
+
+    writer.finish()?;
+
+    info!(
+        "{} of {} records have problems; {} problems found",
+        summary.invalid, summary.records, summary.issues
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    validate(&mut connection, &options)
+}
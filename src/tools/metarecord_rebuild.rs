@@ -0,0 +1,380 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use log::{error, info, warn};
+use postgres as pg;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc;
+use std::time::Instant;
+use threadpool::ThreadPool;
+
+struct RebuildOptions {
+    min_id: i64,
+    max_id: i64,
+    max_threads: usize,
+    batch_size: usize,
+    lockfile: Option<String>,
+    dry_run: DryRun,
+}
+
+impl RebuildOptions {
+    fn is_full(&self) -> bool {
+        self.min_id == 0 && self.max_id == 0
+    }
+}
+
+fn read_options() -> (RebuildOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "max-threads", "Max Worker Threads", "MAX_THREADS");
+    opts.optopt("", "batch-size", "Records per Batch", "BATCH_SIZE");
+
+    cli::append_lockfile(&mut opts);
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        RebuildOptions {
+            min_id: params.opt_get_default("min-id", 0).unwrap(),
+            max_id: params.opt_get_default("max-id", 0).unwrap(),
+            max_threads: params.opt_get_default("max-threads", 5).unwrap(),
+            batch_size: params.opt_get_default("batch-size", 500).unwrap(),
+            lockfile: params.opt_str("lockfile"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin metarecord-rebuild -- --max-threads 8
+
+Regenerates metarecords and metarecord source maps from bib record
+fingerprints using metabib.remap_metarecord_for_bib().
+
+Without --min-id or --max-id, all metarecords and source maps are
+deleted first and rebuilt for every non-deleted record.  Metarecord
+searches return no results until the rebuild completes.
+
+With an ID range, only records in the range are remapped.  Metarecords
+left with no source records are then deleted.
+
+Records are partitioned across workers by fingerprint, so records
+sharing a metarecord are always mapped by the same worker.  Open
+metarecord (M) holds are retargeted to the new metarecord with the
+same fingerprint.
+
+Hold targets are saved to egutil.metarecord_hold_target first.  If a
+worker fails, holds are retargeted to the metarecords rebuilt so far,
+and the rest are retargeted by the next run.
+
+Options
+
+    --min-id
+    --max-id
+        Limit to records with IDs in this range.
+
+    --max-threads
+        Number of parallel database workers.  Defaults to 5.
+
+    --batch-size
+        Number of records remapped per query.  Defaults to 500.
+
+{}
+    --dry-run
+        Make the changes, then roll them back, reporting what a real
+        run would do.  Runs on one connection, in one transaction,
+        so --max-threads is ignored and a full rebuild holds its
+        locks on the metarecord tables throughout.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::LOCKFILE_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+/// Remember the fingerprint targeted by each open metarecord hold.
+///
+/// Saved to a table, not a temp table, so holds whose metarecords
+/// were deleted by a failed run are retargeted by the next one.
+fn save_hold_targets(con: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.metarecord_hold_target (
+            hold        INT PRIMARY KEY,
+            fingerprint TEXT NOT NULL
+        );
+        INSERT INTO egutil.metarecord_hold_target (hold, fingerprint)
+        SELECT ahr.id, mmr.fingerprint
+        FROM action.hold_request ahr
+            JOIN metabib.metarecord mmr ON mmr.id = ahr.target
+        WHERE ahr.hold_type = 'M'
+            AND ahr.fulfillment_time IS NULL
+            AND ahr.cancel_time IS NULL
+        ON CONFLICT (hold) DO UPDATE SET fingerprint = EXCLUDED.fingerprint
+    "#;
+
+    match con.client().batch_execute(sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error saving metarecord hold targets: {e}")),
+    }
+}
+
+/// Point holds whose metarecord was deleted at the metarecord now
+/// holding their fingerprint.  Saved targets are kept only for open
+/// holds still without a metarecord.  Returns the holds retargeted and
+/// those left waiting.
+fn retarget_holds(con: &mut DatabaseConnection) -> Result<(u64, u64), String> {
+    let sql = r#"
+        UPDATE action.hold_request ahr SET target = mmr.id
+        FROM egutil.metarecord_hold_target t
+            JOIN metabib.metarecord mmr ON mmr.fingerprint = t.fingerprint
+        WHERE ahr.id = t.hold
+            AND ahr.target <> mmr.id
+            AND NOT EXISTS (SELECT 1 FROM metabib.metarecord WHERE id = ahr.target)
+    "#;
+
+    let retargeted = match con.client().execute(sql, &[]) {
+        Ok(n) => n,
+        Err(e) => return Err(format!("Error retargeting metarecord holds: {e}")),
+    };
+
+    let sql = r#"
+        DELETE FROM egutil.metarecord_hold_target t
+        WHERE NOT EXISTS (
+            SELECT 1 FROM action.hold_request ahr
+            WHERE ahr.id = t.hold
+                AND ahr.fulfillment_time IS NULL
+                AND ahr.cancel_time IS NULL
+                AND NOT EXISTS (SELECT 1 FROM metabib.metarecord WHERE id = ahr.target)
+        )
+    "#;
+
+    if let Err(e) = con.client().execute(sql, &[]) {
+        return Err(format!("Error clearing metarecord hold targets: {e}"));
+    }
+
+    let sql = "SELECT COUNT(*) AS waiting FROM egutil.metarecord_hold_target";
+
+    match con.client().query_one(sql, &[]) {
+        Ok(row) => Ok((retargeted, row.get::<_, i64>("waiting") as u64)),
+        Err(e) => Err(format!("Error counting metarecord hold targets: {e}")),
+    }
+}
+
+fn log_retargeted(con: &mut DatabaseConnection, dry_run: DryRun) -> Result<(), String> {
+    let (retargeted, waiting) = retarget_holds(con)?;
+
+    audit::count("retargeted", retargeted);
+
+    info!(
+        "{} {retargeted} metarecord holds",
+        dry_run.past("Retargeted")
+    );
+
+    if waiting > 0 {
+        warn!("{waiting} metarecord holds have no metarecord for their fingerprint");
+    }
+
+    Ok(())
+}
+
+fn remap(con: &mut DatabaseConnection, ids: &[i64], batch_size: usize) -> Result<usize, String> {
+    let sql = r#"
+        SELECT metabib.remap_metarecord_for_bib(id, fingerprint)
+        FROM biblio.record_entry WHERE id = ANY($1) ORDER BY id
+    "#;
+
+    for chunk in ids.chunks(batch_size.max(1)) {
+        let batch = chunk.to_vec();
+        if let Err(e) = con.client().query(sql, &[&batch]) {
+            return Err(format!("Error remapping batch at {}: {e}", batch[0]));
+        }
+    }
+
+    Ok(ids.len())
+}
+
+fn remap_worker(
+    mut con: DatabaseConnection,
+    ids: Vec<i64>,
+    batch_size: usize,
+) -> Result<usize, String> {
+    con.connect()?;
+
+    let count = remap(&mut con, &ids, batch_size)?;
+
+    con.disconnect();
+
+    Ok(count)
+}
+
+fn rebuild(con: &mut DatabaseConnection, ops: &RebuildOptions) -> Result<(), String> {
+    let start = Instant::now();
+
+    con.connect()?;
+
+    ops.dry_run.begin(con.client())?;
+
+    save_hold_targets(con)?;
+
+    if ops.is_full() {
+        info!("Deleting all metarecords");
+        let sql = "TRUNCATE metabib.metarecord_source_map; DELETE FROM metabib.metarecord;";
+        if let Err(e) = con.client().batch_execute(sql) {
+            return Err(format!("Error deleting metarecords: {e}"));
+        }
+    }
+
+    let sql = r#"
+        SELECT id, fingerprint FROM biblio.record_entry
+        WHERE NOT deleted AND id > 0 AND id >= $1 AND ($2 = 0 OR id <= $2)
+    "#;
+
+    let rows = match con.client().query(sql, &[&ops.min_id, &ops.max_id]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error loading records: {e}")),
+    };
+
+    info!("Remapping {} records", rows.len());
+
+    audit::count("records", rows.len() as u64);
+
+    let (remapped, failed) = match ops.dry_run.enabled() {
+        true => {
+            // The workers' own connections would not see this one's
+            // uncommitted deletes.
+            let ids: Vec<i64> = rows.iter().map(|r| r.get("id")).collect();
+            (remap(con, &ids, ops.batch_size)?, false)
+        }
+        false => remap_parallel(con, ops, rows, start),
+    };
+
+    audit::count("remapped", remapped as u64);
+
+    if failed {
+        // Holds on metarecords not yet rebuilt keep their saved
+        // targets for the next run.
+        log_retargeted(con, ops.dry_run)?;
+        return Err("One or more workers failed; metarecords are incomplete".to_string());
+    }
+
+    let sql = r#"
+        DELETE FROM metabib.metarecord mmr
+        WHERE NOT EXISTS (
+            SELECT 1 FROM metabib.metarecord_source_map WHERE metarecord = mmr.id
+        )
+    "#;
+
+    match con.client().execute(sql, &[]) {
+        Ok(n) => {
+            audit::count("deleted", n);
+            info!("{} {n} empty metarecords", ops.dry_run.past("Deleted"));
+        }
+        Err(e) => return Err(format!("Error deleting empty metarecords: {e}")),
+    }
+
+    log_retargeted(con, ops.dry_run)?;
+
+    ops.dry_run.end(con.client())?;
+
+    con.disconnect();
+
+    println!(
+        "{} {remapped} records in {:.1}s",
+        ops.dry_run.past("Remapped"),
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+/// Remap the records on --max-threads workers.  Returns the records
+/// remapped and whether any worker failed.
+fn remap_parallel(
+    con: &mut DatabaseConnection,
+    ops: &RebuildOptions,
+    rows: Vec<pg::Row>,
+    start: Instant,
+) -> (usize, bool) {
+    let workers = ops.max_threads.max(1);
+    let mut partitions: Vec<Vec<i64>> = vec![Vec::new(); workers];
+
+    for row in rows {
+        let fp: Option<String> = row.get("fingerprint");
+        let mut hasher = DefaultHasher::new();
+        fp.hash(&mut hasher);
+        partitions[(hasher.finish() % workers as u64) as usize].push(row.get("id"));
+    }
+
+    let pool = ThreadPool::new(workers);
+    let (sender, receiver) = mpsc::channel();
+
+    for part in partitions.into_iter().filter(|p| !p.is_empty()) {
+        let worker_con = con.partial_clone();
+        let tx = sender.clone();
+        let batch_size = ops.batch_size;
+        pool.execute(move || {
+            tx.send(remap_worker(worker_con, part, batch_size)).ok();
+        });
+    }
+
+    drop(sender);
+
+    let mut remapped = 0;
+    let mut failed = false;
+
+    for result in receiver {
+        match result {
+            Ok(n) => {
+                remapped += n;
+                info!(
+                    "Remapped {remapped} records in {:.1}s",
+                    start.elapsed().as_secs_f64()
+                );
+            }
+            Err(e) => {
+                error!("{e}");
+                failed = true;
+            }
+        }
+    }
+
+    pool.join();
+
+    (remapped, failed)
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(rebuild(&mut connection, &options))
+}
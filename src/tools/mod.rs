@@ -0,0 +1,472 @@
+//! The egutil tools, run by their standalone binaries and as
+//! subcommands of the egutil binary.
+//!
+//! Each tool reads its own command line with cli::parse_or_exit(), so
+//! both ways of running it share the config file, logging and
+//! database options.
+pub mod acq_funds;
+pub mod acq_load;
+pub mod acq_rollover;
+pub mod activity_purge;
+pub mod age_protect;
+pub mod auth_link;
+pub mod barcode_tool;
+pub mod booking_maint;
+pub mod callnumber_sortkey;
+pub mod circ_purge;
+pub mod circ_stats;
+pub mod closed_dates;
+pub mod closing_adjust;
+pub mod collections_export;
+pub mod config_sync;
+pub mod copy_audit;
+pub mod course_reserves;
+pub mod db_health;
+pub mod db_migrate;
+pub mod discovery_feed;
+pub mod econtent_sync;
+pub mod edi_process;
+pub mod fingerprint_recalc;
+pub mod hold_stats;
+pub mod holdings_import;
+pub mod hours_manage;
+pub mod index_check;
+pub mod invoice_reconcile;
+pub mod isbn_normalize;
+pub mod job_queue;
+pub mod juvenile_update;
+pub mod label_export;
+pub mod ldap_sync;
+pub mod link_checker;
+pub mod load_fixtures;
+pub mod lost_process;
+pub mod marc_export;
+pub mod marc_modify;
+pub mod marc_report;
+pub mod marc_validate;
+pub mod metarecord_rebuild;
+pub mod oai_server;
+pub mod offline_circ;
+pub mod org_bootstrap;
+pub mod parallel_ingest;
+pub mod patron_block;
+pub mod patron_purge;
+pub mod penalty_recalc;
+pub mod perm_audit;
+pub mod report;
+pub mod report_runner;
+pub mod rollback;
+pub mod search_stats;
+pub mod serial_items;
+pub mod shelflist;
+pub mod sip2_bench;
+pub mod sitemap_gen;
+pub mod sru_server;
+pub mod symspell_rebuild;
+pub mod tcn_repair;
+pub mod transit_report;
+pub mod uri_manage;
+pub mod workstation_manage;
+pub mod z39_fetch;
+
+pub struct Tool {
+    pub name: &'static str,
+    /// Other subcommand names, e.g. ingest for parallel-ingest.
+    pub aliases: &'static [&'static str],
+    pub summary: &'static str,
+    pub run: fn() -> Result<(), String>,
+}
+
+pub const TOOLS: &[Tool] = &[
+    Tool {
+        name: "acq-funds",
+        aliases: &[],
+        summary: "Summarize acquisitions fund balances",
+        run: acq_funds::main,
+    },
+    Tool {
+        name: "acq-load",
+        aliases: &[],
+        summary: "Load a brief order MARC file as a purchase order",
+        run: acq_load::main,
+    },
+    Tool {
+        name: "acq-rollover",
+        aliases: &[],
+        summary: "Run the fiscal year-end fund rollover",
+        run: acq_rollover::main,
+    },
+    Tool {
+        name: "activity-purge",
+        aliases: &[],
+        summary: "Prune old patron activity and auth artifacts",
+        run: activity_purge::main,
+    },
+    Tool {
+        name: "age-protect",
+        aliases: &[],
+        summary: "Apply an age hold protection policy to copies",
+        run: age_protect::main,
+    },
+    Tool {
+        name: "auth-link",
+        aliases: &[],
+        summary: "Link bib headings to authority records",
+        run: auth_link::main,
+    },
+    Tool {
+        name: "barcode-tool",
+        aliases: &[],
+        summary: "Validate or generate patron and item barcodes",
+        run: barcode_tool::main,
+    },
+    Tool {
+        name: "booking-maint",
+        aliases: &[],
+        summary: "Booking reservation maintenance",
+        run: booking_maint::main,
+    },
+    Tool {
+        name: "callnumber-sortkey",
+        aliases: &[],
+        summary: "Compute call number shelf-order sort keys",
+        run: callnumber_sortkey::main,
+    },
+    Tool {
+        name: "circ-purge",
+        aliases: &[],
+        summary: "Age completed circulation chains",
+        run: circ_purge::main,
+    },
+    Tool {
+        name: "circ-stats",
+        aliases: &[],
+        summary: "Summarize checkouts, renewals and holds",
+        run: circ_stats::main,
+    },
+    Tool {
+        name: "closed-dates",
+        aliases: &[],
+        summary: "Load closed dates from CSV or ICS",
+        run: closed_dates::main,
+    },
+    Tool {
+        name: "closing-adjust",
+        aliases: &[],
+        summary: "Adjust transactions for an unplanned closure",
+        run: closing_adjust::main,
+    },
+    Tool {
+        name: "collections-export",
+        aliases: &[],
+        summary: "Export patrons for a collections agency",
+        run: collections_export::main,
+    },
+    Tool {
+        name: "config-sync",
+        aliases: &[],
+        summary: "Copy cataloging config between org units",
+        run: config_sync::main,
+    },
+    Tool {
+        name: "copy-audit",
+        aliases: &[],
+        summary: "Find copies with inconsistent statuses",
+        run: copy_audit::main,
+    },
+    Tool {
+        name: "course-reserves",
+        aliases: &[],
+        summary: "Export and roll over course reserves",
+        run: course_reserves::main,
+    },
+    Tool {
+        name: "db-health",
+        aliases: &[],
+        summary: "Report Evergreen database health signals",
+        run: db_health::main,
+    },
+    Tool {
+        name: "db-migrate",
+        aliases: &[],
+        summary: "Apply local schema customizations",
+        run: db_migrate::main,
+    },
+    Tool {
+        name: "discovery-feed",
+        aliases: &[],
+        summary: "Write bib records as JSON for discovery layers",
+        run: discovery_feed::main,
+    },
+    Tool {
+        name: "econtent-sync",
+        aliases: &[],
+        summary: "Apply e-content vendor MARC delta feeds",
+        run: econtent_sync::main,
+    },
+    Tool {
+        name: "edi-process",
+        aliases: &[],
+        summary: "Fetch and apply incoming EDI messages",
+        run: edi_process::main,
+    },
+    Tool {
+        name: "fingerprint-recalc",
+        aliases: &[],
+        summary: "Recompute bib fingerprints and quality",
+        run: fingerprint_recalc::main,
+    },
+    Tool {
+        name: "hold-stats",
+        aliases: &[],
+        summary: "Report on holds placed within a date range",
+        run: hold_stats::main,
+    },
+    Tool {
+        name: "holdings-import",
+        aliases: &[],
+        summary: "Create items from embedded MARC holdings",
+        run: holdings_import::main,
+    },
+    Tool {
+        name: "hours-manage",
+        aliases: &[],
+        summary: "Export and import hours of operation",
+        run: hours_manage::main,
+    },
+    Tool {
+        name: "index-check",
+        aliases: &[],
+        summary: "Compare bib records against search index rows",
+        run: index_check::main,
+    },
+    Tool {
+        name: "invoice-reconcile",
+        aliases: &[],
+        summary: "Reconcile vendor invoices against receipts",
+        run: invoice_reconcile::main,
+    },
+    Tool {
+        name: "isbn-normalize",
+        aliases: &[],
+        summary: "Normalize ISBNs and UPCs in bib records",
+        run: isbn_normalize::main,
+    },
+    Tool {
+        name: "job-queue",
+        aliases: &[],
+        summary: "Manage the egutil job queue",
+        run: job_queue::main,
+    },
+    Tool {
+        name: "juvenile-update",
+        aliases: &[],
+        summary: "Clear the juvenile flag for patrons of age",
+        run: juvenile_update::main,
+    },
+    Tool {
+        name: "label-export",
+        aliases: &[],
+        summary: "Export spine and pocket label data",
+        run: label_export::main,
+    },
+    Tool {
+        name: "ldap-sync",
+        aliases: &[],
+        summary: "Synchronize patrons with an LDAP directory",
+        run: ldap_sync::main,
+    },
+    Tool {
+        name: "link-checker",
+        aliases: &[],
+        summary: "Check 856 URLs for dead and redirected links",
+        run: link_checker::main,
+    },
+    Tool {
+        name: "load-fixtures",
+        aliases: &[],
+        summary: "Load test fixtures into a scratch database",
+        run: load_fixtures::main,
+    },
+    Tool {
+        name: "lost-process",
+        aliases: &[],
+        summary: "Mark overdue circulations Lost or Long Overdue",
+        run: lost_process::main,
+    },
+    Tool {
+        name: "marc-export",
+        aliases: &["export"],
+        summary: "Export bib or authority records",
+        run: marc_export::main,
+    },
+    Tool {
+        name: "marc-modify",
+        aliases: &[],
+        summary: "Apply modification rules to bib records",
+        run: marc_modify::main,
+    },
+    Tool {
+        name: "marc-report",
+        aliases: &[],
+        summary: "Report MARC field values as CSV",
+        run: marc_report::main,
+    },
+    Tool {
+        name: "marc-validate",
+        aliases: &[],
+        summary: "Validate bib records against a profile",
+        run: marc_validate::main,
+    },
+    Tool {
+        name: "metarecord-rebuild",
+        aliases: &[],
+        summary: "Regenerate metarecords from fingerprints",
+        run: metarecord_rebuild::main,
+    },
+    Tool {
+        name: "oai-server",
+        aliases: &[],
+        summary: "Serve bib records over OAI-PMH",
+        run: oai_server::main,
+    },
+    Tool {
+        name: "offline-circ",
+        aliases: &[],
+        summary: "Replay offline circulation transactions",
+        run: offline_circ::main,
+    },
+    Tool {
+        name: "org-bootstrap",
+        aliases: &[],
+        summary: "Create an org unit from a JSON spec",
+        run: org_bootstrap::main,
+    },
+    Tool {
+        name: "parallel-ingest",
+        aliases: &["ingest"],
+        summary: "Reingest bib records in parallel",
+        run: parallel_ingest::main,
+    },
+    Tool {
+        name: "patron-block",
+        aliases: &[],
+        summary: "Apply or remove patron standing penalties",
+        run: patron_block::main,
+    },
+    Tool {
+        name: "patron-purge",
+        aliases: &[],
+        summary: "Purge long-expired patrons",
+        run: patron_purge::main,
+    },
+    Tool {
+        name: "penalty-recalc",
+        aliases: &[],
+        summary: "Recalculate system standing penalties",
+        run: penalty_recalc::main,
+    },
+    Tool {
+        name: "perm-audit",
+        aliases: &[],
+        summary: "Audit who holds permissions",
+        run: perm_audit::main,
+    },
+    Tool {
+        name: "report",
+        aliases: &[],
+        summary: "Run canned operational reports",
+        run: report::main,
+    },
+    Tool {
+        name: "report-runner",
+        aliases: &[],
+        summary: "Run pending reporter schedule entries",
+        run: report_runner::main,
+    },
+    Tool {
+        name: "rollback",
+        aliases: &[],
+        summary: "Restore rows saved by a --snapshot run",
+        run: rollback::main,
+    },
+    Tool {
+        name: "search-stats",
+        aliases: &[],
+        summary: "Summarize catalog searches",
+        run: search_stats::main,
+    },
+    Tool {
+        name: "serial-items",
+        aliases: &[],
+        summary: "Create items for arrived serial issues",
+        run: serial_items::main,
+    },
+    Tool {
+        name: "shelflist",
+        aliases: &[],
+        summary: "List a library's items in shelf order",
+        run: shelflist::main,
+    },
+    Tool {
+        name: "sip2-bench",
+        aliases: &[],
+        summary: "Benchmark a SIP2 server",
+        run: sip2_bench::main,
+    },
+    Tool {
+        name: "sitemap-gen",
+        aliases: &[],
+        summary: "Write sitemaps for OPAC-visible records",
+        run: sitemap_gen::main,
+    },
+    Tool {
+        name: "sru-server",
+        aliases: &[],
+        summary: "Serve bib records over SRU",
+        run: sru_server::main,
+    },
+    Tool {
+        name: "symspell-rebuild",
+        aliases: &[],
+        summary: "Regenerate the search suggestion dictionary",
+        run: symspell_rebuild::main,
+    },
+    Tool {
+        name: "tcn-repair",
+        aliases: &[],
+        summary: "Repair duplicate and malformed TCNs",
+        run: tcn_repair::main,
+    },
+    Tool {
+        name: "transit-report",
+        aliases: &[],
+        summary: "Report problem transits",
+        run: transit_report::main,
+    },
+    Tool {
+        name: "uri-manage",
+        aliases: &[],
+        summary: "Manage 856 located URIs from CSV",
+        run: uri_manage::main,
+    },
+    Tool {
+        name: "workstation-manage",
+        aliases: &[],
+        summary: "Register, rename and purge workstations",
+        run: workstation_manage::main,
+    },
+    Tool {
+        name: "z39-fetch",
+        aliases: &[],
+        summary: "Fetch records from a Z39.50 target",
+        run: z39_fetch::main,
+    },
+];
+
+/// Tool by name or alias.
+pub fn find(name: &str) -> Option<&'static Tool> {
+    TOOLS
+        .iter()
+        .find(|t| t.name == name || t.aliases.contains(&name))
+}
@@ -0,0 +1,822 @@
+use crate::cli;
+use crate::daemon;
+use crate::date::UtcTime;
+use crate::db::{self, DatabaseConnection};
+use crate::http::{self, Request, Response};
+use crate::marc;
+use crate::xml::{escape, strip_declaration};
+use log::{error, info};
+use marcutil::Record;
+use postgres as pg;
+use std::sync::{Arc, Mutex};
+
+const OAI_NS: &str = "http://www.openarchives.org/OAI/2.0/";
+const OAI_SCHEMA: &str = "http://www.openarchives.org/OAI/2.0/OAI-PMH.xsd";
+const MARC21_NS: &str = "http://www.loc.gov/MARC21/slim";
+const MARC21_SCHEMA: &str = "http://www.loc.gov/standards/marcxml/schema/MARC21slim.xsd";
+const OAI_DC_NS: &str = "http://www.openarchives.org/OAI/2.0/oai_dc/";
+const OAI_DC_SCHEMA: &str = "http://www.openarchives.org/OAI/2.0/oai_dc.xsd";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+
+const DATESTAMP_FORMAT: &str = r#"'YYYY-MM-DD"T"HH24:MI:SS"Z"'"#;
+
+#[derive(Clone)]
+struct ServerOptions {
+    address: String,
+    max_threads: usize,
+    page_size: i64,
+    repository_id: String,
+    repository_name: String,
+    admin_email: String,
+    base_url: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MetadataFormat {
+    Marc21,
+    OaiDc,
+}
+
+impl MetadataFormat {
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix {
+            "marc21" => Some(MetadataFormat::Marc21),
+            "oai_dc" => Some(MetadataFormat::OaiDc),
+            _ => None,
+        }
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            MetadataFormat::Marc21 => "marc21",
+            MetadataFormat::OaiDc => "oai_dc",
+        }
+    }
+}
+
+/// OAI-PMH protocol errors, returned to the harvester as <error> elements.
+enum OaiError {
+    BadVerb,
+    BadArgument(String),
+    BadResumptionToken,
+    CannotDisseminateFormat,
+    IdDoesNotExist,
+    NoRecordsMatch,
+    Internal(String),
+}
+
+impl OaiError {
+    fn code(&self) -> &'static str {
+        match self {
+            OaiError::BadVerb => "badVerb",
+            OaiError::BadArgument(_) => "badArgument",
+            OaiError::BadResumptionToken => "badResumptionToken",
+            OaiError::CannotDisseminateFormat => "cannotDisseminateFormat",
+            OaiError::IdDoesNotExist => "idDoesNotExist",
+            OaiError::NoRecordsMatch => "noRecordsMatch",
+            // Not an OAI error code, but harvesters will report it.
+            OaiError::Internal(_) => "internalError",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            OaiError::BadVerb => "Illegal or missing OAI verb".to_string(),
+            OaiError::BadArgument(m) => m.to_string(),
+            OaiError::BadResumptionToken => "Invalid resumption token".to_string(),
+            OaiError::CannotDisseminateFormat => "Unsupported metadata format".to_string(),
+            OaiError::IdDoesNotExist => "No such record".to_string(),
+            OaiError::NoRecordsMatch => "No records match the request".to_string(),
+            OaiError::Internal(m) => m.to_string(),
+        }
+    }
+}
+
+impl From<pg::Error> for OaiError {
+    fn from(e: pg::Error) -> Self {
+        OaiError::Internal(format!("Database error: {e}"))
+    }
+}
+
+/// Parameters for a ListRecords / ListIdentifiers request, which are
+/// round-tripped through the resumption token.
+struct ListParams {
+    format: MetadataFormat,
+    set: Option<String>,
+    from: Option<String>,
+    until: Option<String>,
+    last_id: i64,
+}
+
+impl ListParams {
+    /// Tokens are hex encoded so they can be passed back to us without
+    /// the harvester needing to escape anything.
+    fn to_token(&self) -> String {
+        let raw = format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.format.prefix(),
+            self.set.as_deref().unwrap_or(""),
+            self.from.as_deref().unwrap_or(""),
+            self.until.as_deref().unwrap_or(""),
+            self.last_id
+        );
+
+        raw.bytes().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn from_token(token: &str) -> Result<Self, OaiError> {
+        if !token.len().is_multiple_of(2) || !token.is_ascii() {
+            return Err(OaiError::BadResumptionToken);
+        }
+
+        let mut bytes = Vec::new();
+        for i in (0..token.len()).step_by(2) {
+            match u8::from_str_radix(&token[i..i + 2], 16) {
+                Ok(b) => bytes.push(b),
+                Err(_) => return Err(OaiError::BadResumptionToken),
+            }
+        }
+
+        let raw = String::from_utf8(bytes).or(Err(OaiError::BadResumptionToken))?;
+        let parts: Vec<&str> = raw.split('\t').collect();
+
+        if parts.len() != 5 {
+            return Err(OaiError::BadResumptionToken);
+        }
+
+        let opt = |s: &str| match s {
+            "" => None,
+            _ => Some(s.to_string()),
+        };
+
+        Ok(ListParams {
+            format: MetadataFormat::from_prefix(parts[0]).ok_or(OaiError::BadResumptionToken)?,
+            set: opt(parts[1]),
+            from: opt(parts[2]),
+            until: opt(parts[3]),
+            last_id: parts[4].parse().or(Err(OaiError::BadResumptionToken))?,
+        })
+    }
+}
+
+fn read_options() -> (ServerOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "listen", "Listen Address", "ADDRESS:PORT");
+    opts.optopt("", "max-threads", "Max Worker Threads", "MAX_THREADS");
+    opts.optopt("", "page-size", "Records Per Response", "PAGE_SIZE");
+    opts.optopt("", "repository-id", "Repository Identifier", "REPO_ID");
+    opts.optopt("", "repository-name", "Repository Name", "REPO_NAME");
+    opts.optopt("", "admin-email", "Administrator Email", "EMAIL");
+    opts.optopt("", "base-url", "Public Base URL", "BASE_URL");
+
+    daemon::append_options(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ServerOptions {
+            address: params
+                .opt_get_default("listen", "127.0.0.1:8080".to_string())
+                .unwrap(),
+            max_threads: params.opt_get_default("max-threads", 5).unwrap(),
+            page_size: params.opt_get_default("page-size", 200).unwrap(),
+            repository_id: params
+                .opt_get_default("repository-id", "evergreen".to_string())
+                .unwrap(),
+            repository_name: params
+                .opt_get_default("repository-name", "Evergreen".to_string())
+                .unwrap(),
+            admin_email: params
+                .opt_get_default("admin-email", "root@localhost".to_string())
+                .unwrap(),
+            base_url: params.opt_get("base-url").unwrap(),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin oai-server -- --listen 0.0.0.0:8080 --repository-id example.org
+
+Options
+
+    --listen
+        Address and port to listen on.  Defaults to 127.0.0.1:8080.
+
+    --max-threads
+        Maximum number of requests to process in parallel.
+
+    --page-size
+        Number of records/identifiers to return per response before
+        issuing a resumption token.  Defaults to 200.
+
+    --repository-id
+        Namespace used in OAI identifiers, e.g. oai:<repository-id>:123
+
+    --repository-name
+    --admin-email
+        Reported in the Identify response.
+
+    --base-url
+        Base URL reported to harvesters.  Otherwise, built from the
+        request Host header.
+
+    --detach
+        Run in the background.  Log to --log-file or syslog, since
+        stderr is closed.
+
+    --pidfile
+        Write the process ID to this file, and refuse to start
+        while the process it names is running.  SIGHUP restarts
+        in place and SIGTERM stops the server.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+Sets
+
+    Each org unit is a set, using the org unit shortname as the
+    setSpec.  A record is a member of a set when it has holdings
+    owned by the org unit or one of its descendants.
+
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+/// Current UTC time in OAI datestamp format.
+fn now_datestamp() -> String {
+    UtcTime::now().to_iso8601()
+}
+
+/// Verify a from/until value uses one of the supported granularities.
+///
+/// Returns the value as a full timestamp.  'until' dates which lack a
+/// time component refer to the end of the day.
+fn check_datestamp(value: &str, is_until: bool) -> Result<String, OaiError> {
+    let bytes = value.as_bytes();
+    let digits = |r: std::ops::Range<usize>| r.into_iter().all(|i| bytes[i].is_ascii_digit());
+
+    let date_ok = bytes.len() >= 10
+        && digits(0..4)
+        && bytes[4] == b'-'
+        && digits(5..7)
+        && bytes[7] == b'-'
+        && digits(8..10);
+
+    if date_ok && bytes.len() == 10 {
+        return match is_until {
+            true => Ok(format!("{value}T23:59:59Z")),
+            false => Ok(format!("{value}T00:00:00Z")),
+        };
+    }
+
+    if date_ok
+        && bytes.len() == 20
+        && bytes[10] == b'T'
+        && digits(11..13)
+        && bytes[13] == b':'
+        && digits(14..16)
+        && bytes[16] == b':'
+        && digits(17..19)
+        && bytes[19] == b'Z'
+    {
+        return Ok(value.to_string());
+    }
+
+    Err(OaiError::BadArgument(format!("Invalid datestamp: {value}")))
+}
+
+/// Verify the request contains only the allowed arguments, each at most once.
+fn check_arguments(request: &Request, allowed: &[&str], required: &[&str]) -> Result<(), OaiError> {
+    for (name, _) in &request.params {
+        if name == "verb" {
+            continue;
+        }
+
+        if !allowed.contains(&name.as_str()) {
+            return Err(OaiError::BadArgument(format!("Illegal argument: {name}")));
+        }
+
+        if request.param_count(name) > 1 {
+            return Err(OaiError::BadArgument(format!("Repeated argument: {name}")));
+        }
+    }
+
+    if request.param("resumptionToken").is_some() {
+        // resumptionToken is an exclusive argument.
+        if request.params.len() > 2 {
+            return Err(OaiError::BadArgument(
+                "resumptionToken is an exclusive argument".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    for name in required {
+        if request.param(name).is_none() {
+            return Err(OaiError::BadArgument(format!("Missing argument: {name}")));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_identifier(options: &ServerOptions, identifier: &str) -> Option<i64> {
+    let prefix = format!("oai:{}:", options.repository_id);
+    match identifier.strip_prefix(&prefix) {
+        Some(id) => id.parse::<i64>().ok(),
+        None => None,
+    }
+}
+
+fn identifier(options: &ServerOptions, id: i64) -> String {
+    format!("oai:{}:{}", options.repository_id, id)
+}
+
+/// Join the values of the selected subfields from a field.
+fn subfield_text(field: &marcutil::Field, codes: &str, separator: &str) -> String {
+    field
+        .subfields
+        .iter()
+        .filter(|sf| codes.contains(sf.code.as_str()))
+        .map(|sf| sf.content.trim())
+        .collect::<Vec<&str>>()
+        .join(separator)
+}
+
+/// Simple Dublin Core crosswalk, loosely following the LC MARC to DC mapping.
+fn marc_to_dc(record: &Record) -> String {
+    let mut elements: Vec<(&str, String)> = Vec::new();
+
+    let mut add = |name: &'static str, tags: &[&str], codes: &str, sep: &str| {
+        for tag in tags {
+            for field in record.get_fields(tag) {
+                let value = subfield_text(field, codes, sep);
+                if !value.is_empty() {
+                    elements.push((name, value));
+                }
+            }
+        }
+    };
+
+    add("title", &["245"], "abfgknps", " ");
+    add("creator", &["100", "110", "111"], "abcdq", " ");
+    add("contributor", &["700", "710", "711", "720"], "abcdq", " ");
+    add(
+        "subject",
+        &["600", "610", "611", "630", "650", "651", "653"],
+        "abcdqtvxyz",
+        " -- ",
+    );
+    add("description", &["500", "520"], "a", " ");
+    add("publisher", &["260", "264"], "ab", " ");
+    add("date", &["260", "264"], "c", " ");
+    add("identifier", &["020", "022"], "a", " ");
+    add("identifier", &["856"], "u", " ");
+
+    let mut xml = format!(
+        r#"<oai_dc:dc xmlns:oai_dc="{OAI_DC_NS}" xmlns:dc="{DC_NS}" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="{OAI_DC_NS} {OAI_DC_SCHEMA}">"#
+    );
+
+    for (name, value) in elements {
+        xml += &format!("<dc:{name}>{}</dc:{name}>", escape(&value));
+    }
+
+    if let Some(lang) = marc::language(record) {
+        xml += &format!("<dc:language>{}</dc:language>", escape(lang));
+    }
+
+    xml += "</oai_dc:dc>";
+    xml
+}
+
+fn record_header(options: &ServerOptions, row: &pg::Row) -> String {
+    let id: i64 = row.get("id");
+    let deleted: bool = row.get("deleted");
+    let datestamp: &str = row.get("datestamp");
+    let sets: Vec<String> = row.get("sets");
+
+    let mut xml = match deleted {
+        true => r#"<header status="deleted">"#.to_string(),
+        false => "<header>".to_string(),
+    };
+
+    xml += &format!(
+        "<identifier>{}</identifier><datestamp>{}</datestamp>",
+        escape(&identifier(options, id)),
+        datestamp
+    );
+
+    for set in sets {
+        xml += &format!("<setSpec>{}</setSpec>", escape(&set));
+    }
+
+    xml += "</header>";
+    xml
+}
+
+fn record_metadata(row: &pg::Row, format: MetadataFormat) -> Result<String, OaiError> {
+    let marc: &str = row.get("marc");
+
+    let body = match format {
+        MetadataFormat::Marc21 => strip_declaration(marc).to_string(),
+        MetadataFormat::OaiDc => match Record::from_xml(marc).next() {
+            Some(record) => marc_to_dc(&record),
+            None => {
+                let id: i64 = row.get("id");
+                return Err(OaiError::Internal(format!("Cannot parse record {id}")));
+            }
+        },
+    };
+
+    Ok(format!("<metadata>{body}</metadata>"))
+}
+
+/// Shared SELECT for record queries.  Set membership is derived from
+/// the org units (and their ancestors) which own call numbers on the record.
+fn record_select() -> String {
+    format!(
+        r#"
+        SELECT
+            bre.id,
+            bre.marc,
+            bre.deleted,
+            TO_CHAR(DATE_TRUNC('second', bre.edit_date) AT TIME ZONE 'UTC', {DATESTAMP_FORMAT}) AS datestamp,
+            ARRAY(
+                SELECT DISTINCT anc.shortname
+                FROM asset.call_number acn,
+                    actor.org_unit_ancestors(acn.owning_lib) anc
+                WHERE acn.record = bre.id AND NOT acn.deleted
+            )::TEXT[] AS sets
+        FROM biblio.record_entry bre
+    "#
+    )
+}
+
+fn get_record(
+    con: &mut DatabaseConnection,
+    options: &ServerOptions,
+    request: &Request,
+) -> Result<String, OaiError> {
+    check_arguments(
+        request,
+        &["identifier", "metadataPrefix"],
+        &["identifier", "metadataPrefix"],
+    )?;
+
+    let format = MetadataFormat::from_prefix(request.param("metadataPrefix").unwrap())
+        .ok_or(OaiError::CannotDisseminateFormat)?;
+
+    let id = parse_identifier(options, request.param("identifier").unwrap())
+        .ok_or(OaiError::IdDoesNotExist)?;
+
+    let sql = format!("{} WHERE bre.id = $1 AND bre.id > 0", record_select());
+
+    let row = match con.client().query_opt(&sql[..], &[&id])? {
+        Some(r) => r,
+        None => return Err(OaiError::IdDoesNotExist),
+    };
+
+    let mut xml = format!("<GetRecord><record>{}", record_header(options, &row));
+
+    let deleted: bool = row.get("deleted");
+    if !deleted {
+        xml += &record_metadata(&row, format)?;
+    }
+
+    xml += "</record></GetRecord>";
+
+    Ok(xml)
+}
+
+fn list_records(
+    con: &mut DatabaseConnection,
+    options: &ServerOptions,
+    request: &Request,
+    verb: &str,
+) -> Result<String, OaiError> {
+    check_arguments(
+        request,
+        &["metadataPrefix", "from", "until", "set", "resumptionToken"],
+        &["metadataPrefix"],
+    )?;
+
+    let params = match request.param("resumptionToken") {
+        Some(token) => ListParams::from_token(token)?,
+        None => {
+            let format = MetadataFormat::from_prefix(request.param("metadataPrefix").unwrap())
+                .ok_or(OaiError::CannotDisseminateFormat)?;
+
+            let from = match request.param("from") {
+                Some(f) => Some(check_datestamp(f, false)?),
+                None => None,
+            };
+
+            let until = match request.param("until") {
+                Some(u) => Some(check_datestamp(u, true)?),
+                None => None,
+            };
+
+            if let (Some(f), Some(u)) = (request.param("from"), request.param("until")) {
+                if f.len() != u.len() {
+                    return Err(OaiError::BadArgument(
+                        "from and until must have the same granularity".to_string(),
+                    ));
+                }
+            }
+
+            ListParams {
+                format,
+                set: request.param("set").map(|s| s.to_string()),
+                from,
+                until,
+                last_id: 0,
+            }
+        }
+    };
+
+    let mut org_id: Option<i32> = None;
+
+    if let Some(ref set) = params.set {
+        let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+        match con.client().query_opt(sql, &[set])? {
+            Some(row) => org_id = Some(row.get("id")),
+            None => return Err(OaiError::BadArgument(format!("No such set: {set}"))),
+        }
+    }
+
+    let sql = format!(
+        r#"{}
+        WHERE bre.id > $1
+            AND ($2::TEXT IS NULL OR DATE_TRUNC('second', bre.edit_date) >= $2::TEXT::TIMESTAMPTZ)
+            AND ($3::TEXT IS NULL OR DATE_TRUNC('second', bre.edit_date) <= $3::TEXT::TIMESTAMPTZ)
+            AND ($4::INTEGER IS NULL OR EXISTS (
+                SELECT 1 FROM asset.call_number acn
+                WHERE acn.record = bre.id
+                    AND NOT acn.deleted
+                    AND acn.owning_lib IN (
+                        SELECT id FROM actor.org_unit_descendants($4::INTEGER)
+                    )
+            ))
+        ORDER BY bre.id
+        LIMIT $5
+        "#,
+        record_select()
+    );
+
+    // Fetch one extra to see if there are more records to return.
+    let limit = options.page_size + 1;
+
+    let rows = con.client().query(
+        &sql[..],
+        &[
+            &params.last_id,
+            &params.from,
+            &params.until,
+            &org_id,
+            &limit,
+        ],
+    )?;
+
+    if rows.is_empty() {
+        return Err(OaiError::NoRecordsMatch);
+    }
+
+    let mut xml = format!("<{verb}>");
+    let mut last_id = params.last_id;
+
+    for row in rows.iter().take(options.page_size as usize) {
+        last_id = row.get("id");
+
+        if verb == "ListIdentifiers" {
+            xml += &record_header(options, row);
+            continue;
+        }
+
+        let deleted: bool = row.get("deleted");
+
+        xml += "<record>";
+        xml += &record_header(options, row);
+
+        if !deleted {
+            match record_metadata(row, params.format) {
+                Ok(m) => xml += &m,
+                Err(e) => error!("{}", e.message()),
+            }
+        }
+
+        xml += "</record>";
+    }
+
+    if rows.len() as i64 > options.page_size {
+        let next = ListParams { last_id, ..params };
+        xml += &format!("<resumptionToken>{}</resumptionToken>", next.to_token());
+    } else if request.param("resumptionToken").is_some() {
+        // An empty token signals the final page of a multi-page list.
+        xml += "<resumptionToken/>";
+    }
+
+    xml += &format!("</{verb}>");
+
+    Ok(xml)
+}
+
+fn identify(
+    con: &mut DatabaseConnection,
+    options: &ServerOptions,
+    request: &Request,
+    base_url: &str,
+) -> Result<String, OaiError> {
+    check_arguments(request, &[], &[])?;
+
+    let sql = format!(
+        "SELECT TO_CHAR(MIN(edit_date) AT TIME ZONE 'UTC', {DATESTAMP_FORMAT}) AS earliest
+        FROM biblio.record_entry WHERE id > 0"
+    );
+
+    let row = con.client().query_one(&sql[..], &[])?;
+    let earliest: Option<String> = row.get("earliest");
+
+    Ok(format!(
+        r#"<Identify>
+<repositoryName>{}</repositoryName>
+<baseURL>{}</baseURL>
+<protocolVersion>2.0</protocolVersion>
+<adminEmail>{}</adminEmail>
+<earliestDatestamp>{}</earliestDatestamp>
+<deletedRecord>persistent</deletedRecord>
+<granularity>YYYY-MM-DDThh:mm:ssZ</granularity>
+</Identify>"#,
+        escape(&options.repository_name),
+        escape(base_url),
+        escape(&options.admin_email),
+        earliest.unwrap_or_else(now_datestamp),
+    ))
+}
+
+fn list_metadata_formats(
+    con: &mut DatabaseConnection,
+    options: &ServerOptions,
+    request: &Request,
+) -> Result<String, OaiError> {
+    check_arguments(request, &["identifier"], &[])?;
+
+    if let Some(ident) = request.param("identifier") {
+        let id = parse_identifier(options, ident).ok_or(OaiError::IdDoesNotExist)?;
+        let sql = "SELECT id FROM biblio.record_entry WHERE id = $1";
+        if con.client().query_opt(sql, &[&id])?.is_none() {
+            return Err(OaiError::IdDoesNotExist);
+        }
+    }
+
+    Ok(format!(
+        r#"<ListMetadataFormats>
+<metadataFormat><metadataPrefix>marc21</metadataPrefix><schema>{MARC21_SCHEMA}</schema><metadataNamespace>{MARC21_NS}</metadataNamespace></metadataFormat>
+<metadataFormat><metadataPrefix>oai_dc</metadataPrefix><schema>{OAI_DC_SCHEMA}</schema><metadataNamespace>{OAI_DC_NS}</metadataNamespace></metadataFormat>
+</ListMetadataFormats>"#
+    ))
+}
+
+fn list_sets(con: &mut DatabaseConnection, request: &Request) -> Result<String, OaiError> {
+    check_arguments(request, &["resumptionToken"], &[])?;
+
+    if request.param("resumptionToken").is_some() {
+        // We never issue tokens for ListSets.
+        return Err(OaiError::BadResumptionToken);
+    }
+
+    let mut xml = String::from("<ListSets>");
+
+    let sql = "SELECT shortname, name FROM actor.org_unit ORDER BY id";
+    for row in con.client().query(sql, &[])? {
+        let shortname: &str = row.get("shortname");
+        let name: &str = row.get("name");
+        xml += &format!(
+            "<set><setSpec>{}</setSpec><setName>{}</setName></set>",
+            escape(shortname),
+            escape(name)
+        );
+    }
+
+    xml += "</ListSets>";
+
+    Ok(xml)
+}
+
+fn process_verb(
+    con: &mut DatabaseConnection,
+    options: &ServerOptions,
+    request: &Request,
+    base_url: &str,
+) -> Result<String, OaiError> {
+    if request.param_count("verb") != 1 {
+        return Err(OaiError::BadVerb);
+    }
+
+    match request.param("verb").unwrap() {
+        "Identify" => identify(con, options, request, base_url),
+        "ListMetadataFormats" => list_metadata_formats(con, options, request),
+        "ListSets" => list_sets(con, request),
+        "GetRecord" => get_record(con, options, request),
+        "ListRecords" => list_records(con, options, request, "ListRecords"),
+        "ListIdentifiers" => list_records(con, options, request, "ListIdentifiers"),
+        _ => Err(OaiError::BadVerb),
+    }
+}
+
+/// Run the request using this thread's database connection, connecting
+/// first if necessary.
+fn with_connection<F>(template: &Mutex<DatabaseConnection>, f: F) -> Result<String, OaiError>
+where
+    F: FnOnce(&mut DatabaseConnection) -> Result<String, OaiError>,
+{
+    let result =
+        db::with_thread_connection(template, f).unwrap_or_else(|e| Err(OaiError::Internal(e)));
+
+    if let Err(OaiError::Internal(ref msg)) = result {
+        // Reconnect on the next request in case our connection is bad.
+        error!("{msg}");
+        db::reset_thread_connection();
+    }
+
+    result
+}
+
+fn handle_request(
+    template: &Mutex<DatabaseConnection>,
+    options: &ServerOptions,
+    request: &Request,
+) -> Response {
+    let base_url = match options.base_url {
+        Some(ref u) => u.to_string(),
+        None => format!(
+            "http://{}{}",
+            request.header("host").unwrap_or("localhost"),
+            request.path
+        ),
+    };
+
+    let result = with_connection(template, |con| {
+        process_verb(con, options, request, &base_url)
+    });
+
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OAI-PMH xmlns="{OAI_NS}" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="{OAI_NS} {OAI_SCHEMA}">
+<responseDate>{}</responseDate>
+"#,
+        now_datestamp()
+    );
+
+    let mut request_attrs = String::new();
+
+    match result {
+        Ok(body) => {
+            // Only echo request arguments when the request was valid.
+            for (k, v) in &request.params {
+                request_attrs += &format!(r#" {}="{}""#, k, escape(v));
+            }
+            xml += &format!("<request{request_attrs}>{}</request>\n", escape(&base_url));
+            xml += &body;
+        }
+        Err(e) => {
+            xml += &format!("<request>{}</request>\n", escape(&base_url));
+            xml += &format!(
+                r#"<error code="{}">{}</error>"#,
+                e.code(),
+                escape(&e.message())
+            );
+        }
+    }
+
+    xml += "\n</OAI-PMH>\n";
+
+    Response::xml(xml)
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, connection) = read_options();
+
+    info!("OAI-PMH server listening on {}", options.address);
+
+    let template = Arc::new(Mutex::new(connection));
+    let ops = options.clone();
+
+    http::serve(&options.address, options.max_threads, move |request| {
+        handle_request(&template, &ops, request)
+    })
+}
@@ -0,0 +1,696 @@
+use crate::audit;
+use crate::cli;
+use crate::date::UtcTime;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use log::{debug, info};
+use postgres as pg;
+use serde_json::Value;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+
+const COPY_STATUS_CHECKED_OUT: i32 = 1;
+const COPY_STATUS_RESHELVING: i32 = 7;
+
+struct OfflineOptions {
+    in_file: String,
+    exceptions_file: Option<String>,
+    circ_lib: i32,
+    staff: i32,
+    dry_run: DryRun,
+}
+
+/// One transaction from an offline upload file.
+struct OfflineXact {
+    line: usize,
+    kind: String,
+    timestamp: i64,
+    barcode: String,
+    patron_barcode: String,
+    /// When the transaction occurred, as a Postgres timestamp string.
+    when: String,
+    due_date: Option<String>,
+    noncat_type: Option<i32>,
+    count: i32,
+}
+
+/// One line of the exceptions report.
+struct Exception {
+    line: usize,
+    kind: String,
+    severity: &'static str,
+    patron_barcode: String,
+    barcode: String,
+    message: String,
+}
+
+fn read_options() -> (OfflineOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "in-file", "Offline Upload File", "IN_FILE");
+    opts.optopt("", "exceptions-file", "Exceptions Report File", "FILE");
+    opts.optopt("", "circ-lib", "Circulating Org Unit ID", "ORG_ID");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        OfflineOptions {
+            in_file: cli::required(&params, "in-file"),
+            exceptions_file: params.opt_str("exceptions-file"),
+            circ_lib: cli::required(&params, "circ-lib"),
+            staff: cli::required(&params, "staff"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin offline-circ -- --in-file /tmp/offline.jsonl \
+        --circ-lib 4 --staff 1 --exceptions-file /tmp/exceptions.csv
+
+Replays offline circulation transactions, one JSON object per line as
+written by the offline interface, in timestamp order.
+
+Supported transaction types are checkout, renew, checkin and
+in_house_use.  Each transaction is applied in its own database
+transaction.
+
+Transactions which cannot be applied are listed in the exceptions
+report as errors.  Transactions which were applied but need staff
+review (e.g. barred patrons, items needing transit or hold capture)
+are listed as warnings.
+
+Options
+
+    --in-file
+        Offline transaction file.
+
+    --exceptions-file
+        Write the CSV exceptions report to this file.  Otherwise,
+        writes to STDOUT.
+
+    --circ-lib
+        ID of the org unit where the transactions occurred.
+
+    --staff
+        ID of the staff user credited with the transactions.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// String value of a transaction field, which the offline interface
+/// sometimes writes as a number.
+fn str_field(obj: &Value, name: &str) -> Option<String> {
+    match obj.get(name) {
+        Some(Value::String(s)) if !s.is_empty() => Some(s.to_string()),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn int_field(obj: &Value, name: &str) -> Option<i64> {
+    match obj.get(name) {
+        Some(Value::Number(n)) => n.as_i64(),
+        Some(Value::String(s)) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_xact(line: usize, text: &str) -> Result<OfflineXact, String> {
+    let obj: Value = match serde_json::from_str(text) {
+        Ok(o) => o,
+        Err(e) => return Err(format!("Invalid JSON: {e}")),
+    };
+
+    let kind = match str_field(&obj, "type") {
+        Some(k) => k,
+        None => return Err("Transaction has no type".to_string()),
+    };
+
+    let timestamp = int_field(&obj, "timestamp").unwrap_or(0);
+
+    // Checkouts may carry the time of the transaction and checkins
+    // a backdate.  Otherwise, use the time the entry was recorded.
+    let when = str_field(&obj, "checkout_time")
+        .or_else(|| str_field(&obj, "backdate"))
+        .unwrap_or_else(|| UtcTime::from_epoch(timestamp).to_iso8601());
+
+    Ok(OfflineXact {
+        line,
+        kind,
+        timestamp,
+        barcode: str_field(&obj, "barcode").unwrap_or_default(),
+        patron_barcode: str_field(&obj, "patron_barcode").unwrap_or_default(),
+        when,
+        due_date: str_field(&obj, "due_date"),
+        noncat_type: int_field(&obj, "noncat_type").map(|t| t as i32),
+        count: int_field(&obj, "count")
+            .or_else(|| int_field(&obj, "noncat_count"))
+            .unwrap_or(1)
+            .max(1) as i32,
+    })
+}
+
+fn read_xacts(
+    ops: &OfflineOptions,
+    exceptions: &mut Vec<Exception>,
+) -> Result<Vec<OfflineXact>, String> {
+    let file = match File::open(&ops.in_file) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Cannot open {}: {e}", ops.in_file)),
+    };
+
+    let mut xacts = Vec::new();
+
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => return Err(format!("Error reading {}: {e}", ops.in_file)),
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_xact(idx + 1, &line) {
+            Ok(x) => xacts.push(x),
+            Err(message) => exceptions.push(Exception {
+                line: idx + 1,
+                kind: String::new(),
+                severity: "error",
+                patron_barcode: String::new(),
+                barcode: String::new(),
+                message,
+            }),
+        }
+    }
+
+    // Stable sort so entries with matching timestamps keep file order.
+    xacts.sort_by_key(|x| x.timestamp);
+
+    Ok(xacts)
+}
+
+/// Returns the patron ID plus any warnings about the account.
+fn find_patron(
+    tx: &mut pg::Transaction,
+    barcode: &str,
+    warnings: &mut Vec<String>,
+) -> Result<i32, String> {
+    let sql = r#"
+        SELECT au.id, au.deleted, au.barred, au.active, au.expire_date < NOW() AS expired
+        FROM actor.card ac
+        JOIN actor.usr au ON au.id = ac.usr
+        WHERE ac.barcode = $1
+    "#;
+
+    let row = match tx.query_opt(sql, &[&barcode]) {
+        Ok(Some(r)) => r,
+        Ok(None) => return Err(format!("Patron barcode not found: {barcode}")),
+        Err(e) => return Err(db_err("Error finding patron", e)),
+    };
+
+    if row.get::<&str, bool>("deleted") {
+        return Err(format!("Patron {barcode} is deleted"));
+    }
+
+    if row.get::<&str, bool>("barred") {
+        warnings.push("Patron is barred".to_string());
+    }
+
+    if !row.get::<&str, bool>("active") {
+        warnings.push("Patron is inactive".to_string());
+    }
+
+    if row.get::<&str, bool>("expired") {
+        warnings.push("Patron is expired".to_string());
+    }
+
+    Ok(row.get("id"))
+}
+
+fn find_copy(tx: &mut pg::Transaction, barcode: &str) -> Result<(i64, i32), String> {
+    let sql = "SELECT id, circ_lib FROM asset.copy WHERE barcode = $1 AND NOT deleted";
+
+    match tx.query_opt(sql, &[&barcode]) {
+        Ok(Some(row)) => Ok((row.get("id"), row.get("circ_lib"))),
+        Ok(None) => Err(format!("Item barcode not found: {barcode}")),
+        Err(e) => Err(db_err("Error finding item", e)),
+    }
+}
+
+/// Open circulation on a copy, as (circ id, usr, renewal_remaining, overdue).
+fn open_circ(
+    tx: &mut pg::Transaction,
+    copy_id: i64,
+    when: &str,
+) -> Result<Option<(i64, i32, i32, bool)>, String> {
+    let sql = r#"
+        SELECT id, usr, renewal_remaining, due_date < $2::TEXT::TIMESTAMPTZ AS overdue
+        FROM action.circulation
+        WHERE target_copy = $1 AND checkin_time IS NULL
+        ORDER BY xact_start DESC
+        LIMIT 1
+    "#;
+
+    match tx.query_opt(sql, &[&copy_id, &when]) {
+        Ok(Some(row)) => Ok(Some((
+            row.get("id"),
+            row.get("usr"),
+            row.get("renewal_remaining"),
+            row.get("overdue"),
+        ))),
+        Ok(None) => Ok(None),
+        Err(e) => Err(db_err("Error finding circulation", e)),
+    }
+}
+
+/// Close a circulation as of the transaction time.
+fn close_circ(
+    tx: &mut pg::Transaction,
+    ops: &OfflineOptions,
+    circ_id: i64,
+    when: &str,
+    stop_fines: &str,
+) -> Result<(), String> {
+    let sql = r#"
+        UPDATE action.circulation SET
+            checkin_time = $2::TEXT::TIMESTAMPTZ,
+            checkin_scan_time = NOW(),
+            checkin_lib = $3,
+            checkin_staff = $4,
+            stop_fines = $5,
+            stop_fines_time = $2::TEXT::TIMESTAMPTZ
+        WHERE id = $1
+    "#;
+
+    match tx.execute(
+        sql,
+        &[&circ_id, &when, &ops.circ_lib, &ops.staff, &stop_fines],
+    ) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(db_err("Error closing circulation", e)),
+    }
+}
+
+/// Create a circulation using the circ policy matching the copy and patron.
+#[allow(clippy::too_many_arguments)]
+fn create_circ(
+    tx: &mut pg::Transaction,
+    ops: &OfflineOptions,
+    usr: i32,
+    copy_id: i64,
+    when: &str,
+    due_date: &Option<String>,
+    renewal: bool,
+    parent: Option<(i64, i32)>,
+) -> Result<i64, String> {
+    let sql = r#"
+        INSERT INTO action.circulation (
+            usr, target_copy, circ_lib, circ_staff, xact_start, due_date,
+            renewal_remaining, grace_period, duration, recurring_fine,
+            max_fine, fine_interval, duration_rule, recurring_fine_rule,
+            max_fine_rule, desk_renewal, parent_circ
+        )
+        SELECT
+            au.id, acp.id, $3, $4, $5::TEXT::TIMESTAMPTZ,
+            COALESCE(
+                $6::TEXT::TIMESTAMPTZ,
+                $5::TEXT::TIMESTAMPTZ + CASE acp.loan_duration
+                    WHEN 1 THEN crcd.shrt WHEN 3 THEN crcd.extended ELSE crcd.normal END
+            ),
+            COALESCE($8::INT, crcd.max_renewals),
+            crrf.grace_period,
+            CASE acp.loan_duration
+                WHEN 1 THEN crcd.shrt WHEN 3 THEN crcd.extended ELSE crcd.normal END,
+            CASE acp.fine_level
+                WHEN 1 THEN crrf.low WHEN 3 THEN crrf.high ELSE crrf.normal END,
+            crmf.amount,
+            crrf.recurrence_interval,
+            crcd.name, crrf.name, crmf.name,
+            $7::BOOL,
+            $9::BIGINT
+        FROM asset.copy acp
+        JOIN actor.usr au ON au.id = $1
+        CROSS JOIN LATERAL action.find_circ_matrix_matchpoint($3, acp, au, $7::BOOL) mp
+        JOIN config.rule_circ_duration crcd ON crcd.id = (mp.matchpoint).duration_rule
+        JOIN config.rule_recurring_fine crrf ON crrf.id = (mp.matchpoint).recurring_fine_rule
+        JOIN config.rule_max_fine crmf ON crmf.id = (mp.matchpoint).max_fine_rule
+        WHERE acp.id = $2
+        RETURNING id
+    "#;
+
+    let parent_id = parent.map(|p| p.0);
+    let renewal_remaining = parent.map(|p| p.1);
+
+    let row = match tx.query_opt(
+        sql,
+        &[
+            &usr,
+            &copy_id,
+            &ops.circ_lib,
+            &ops.staff,
+            &when,
+            due_date,
+            &renewal,
+            &renewal_remaining,
+            &parent_id,
+        ],
+    ) {
+        Ok(Some(r)) => r,
+        Ok(None) => return Err("No circulation policy matches item and patron".to_string()),
+        Err(e) => return Err(db_err("Error creating circulation", e)),
+    };
+
+    set_copy_status(tx, copy_id, COPY_STATUS_CHECKED_OUT)?;
+
+    Ok(row.get("id"))
+}
+
+fn set_copy_status(tx: &mut pg::Transaction, copy_id: i64, status: i32) -> Result<(), String> {
+    let sql = "UPDATE asset.copy SET status = $2, status_changed_time = NOW() WHERE id = $1";
+    match tx.execute(sql, &[&copy_id, &status]) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(db_err("Error updating item status", e)),
+    }
+}
+
+fn checkout(
+    tx: &mut pg::Transaction,
+    ops: &OfflineOptions,
+    xact: &OfflineXact,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    let usr = find_patron(tx, &xact.patron_barcode, warnings)?;
+
+    if let Some(noncat_type) = xact.noncat_type {
+        let sql = r#"
+            INSERT INTO action.non_cataloged_circulation
+                (patron, staff, circ_lib, item_type, circ_time)
+            VALUES ($1, $2, $3, $4, $5::TEXT::TIMESTAMPTZ)
+        "#;
+
+        for _ in 0..xact.count {
+            if let Err(e) = tx.execute(
+                sql,
+                &[&usr, &ops.staff, &ops.circ_lib, &noncat_type, &xact.when],
+            ) {
+                return Err(db_err("Error creating non-cataloged circulation", e));
+            }
+        }
+
+        return Ok(());
+    }
+
+    let (copy_id, _) = find_copy(tx, &xact.barcode)?;
+
+    if let Some((circ_id, _, _, _)) = open_circ(tx, copy_id, &xact.when)? {
+        close_circ(tx, ops, circ_id, &xact.when, "CHECKIN")?;
+        warnings.push(format!("Checked in previous circulation {circ_id}"));
+    }
+
+    create_circ(
+        tx,
+        ops,
+        usr,
+        copy_id,
+        &xact.when,
+        &xact.due_date,
+        false,
+        None,
+    )?;
+
+    Ok(())
+}
+
+fn renew(
+    tx: &mut pg::Transaction,
+    ops: &OfflineOptions,
+    xact: &OfflineXact,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    let (copy_id, _) = find_copy(tx, &xact.barcode)?;
+
+    let (circ_id, usr, renewal_remaining, _) = match open_circ(tx, copy_id, &xact.when)? {
+        Some(c) => c,
+        None => return Err("Item is not checked out".to_string()),
+    };
+
+    if !xact.patron_barcode.is_empty() {
+        let patron = find_patron(tx, &xact.patron_barcode, warnings)?;
+        if patron != usr {
+            return Err("Item is checked out to a different patron".to_string());
+        }
+    }
+
+    if renewal_remaining < 1 {
+        warnings.push("Renewal limit exceeded".to_string());
+    }
+
+    close_circ(tx, ops, circ_id, &xact.when, "RENEW")?;
+
+    create_circ(
+        tx,
+        ops,
+        usr,
+        copy_id,
+        &xact.when,
+        &xact.due_date,
+        true,
+        Some((circ_id, (renewal_remaining - 1).max(0))),
+    )?;
+
+    Ok(())
+}
+
+fn checkin(
+    tx: &mut pg::Transaction,
+    ops: &OfflineOptions,
+    xact: &OfflineXact,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    let (copy_id, copy_circ_lib) = find_copy(tx, &xact.barcode)?;
+
+    match open_circ(tx, copy_id, &xact.when)? {
+        Some((circ_id, _, _, overdue)) => {
+            close_circ(tx, ops, circ_id, &xact.when, "CHECKIN")?;
+            if overdue {
+                warnings.push("Returned overdue; review fines".to_string());
+            }
+        }
+        None => warnings.push("Item was not checked out".to_string()),
+    }
+
+    set_copy_status(tx, copy_id, COPY_STATUS_RESHELVING)?;
+
+    if copy_circ_lib != ops.circ_lib {
+        warnings.push(format!(
+            "Item belongs to org unit {copy_circ_lib}; needs transit"
+        ));
+    }
+
+    let sql = r#"
+        SELECT id FROM action.hold_request
+        WHERE current_copy = $1
+            AND capture_time IS NULL
+            AND fulfillment_time IS NULL
+            AND cancel_time IS NULL
+        LIMIT 1
+    "#;
+
+    match tx.query_opt(sql, &[&copy_id]) {
+        Ok(Some(row)) => {
+            let hold_id: i32 = row.get("id");
+            warnings.push(format!(
+                "Item is targeted by hold {hold_id}; check in to capture"
+            ));
+        }
+        Ok(None) => {}
+        Err(e) => return Err(db_err("Error checking holds", e)),
+    }
+
+    Ok(())
+}
+
+fn in_house_use(
+    tx: &mut pg::Transaction,
+    ops: &OfflineOptions,
+    xact: &OfflineXact,
+) -> Result<(), String> {
+    let (sql, item): (&str, i64) = match xact.noncat_type {
+        Some(t) => (
+            r#"
+            INSERT INTO action.non_cat_in_house_use (item_type, staff, org_unit, use_time)
+            VALUES ($1, $2, $3, $4::TEXT::TIMESTAMPTZ)
+            "#,
+            t as i64,
+        ),
+        None => (
+            r#"
+            INSERT INTO action.in_house_use (item, staff, org_unit, use_time)
+            VALUES ($1, $2, $3, $4::TEXT::TIMESTAMPTZ)
+            "#,
+            find_copy(tx, &xact.barcode)?.0,
+        ),
+    };
+
+    for _ in 0..xact.count {
+        if let Err(e) = tx.execute(sql, &[&item, &ops.staff, &ops.circ_lib, &xact.when]) {
+            return Err(db_err("Error recording in-house use", e));
+        }
+    }
+
+    Ok(())
+}
+
+fn process_xact(
+    con: &mut DatabaseConnection,
+    ops: &OfflineOptions,
+    xact: &OfflineXact,
+) -> Result<Vec<String>, String> {
+    let mut warnings = Vec::new();
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    match xact.kind.as_str() {
+        "checkout" => checkout(&mut tx, ops, xact, &mut warnings)?,
+        "renew" => renew(&mut tx, ops, xact, &mut warnings)?,
+        "checkin" => checkin(&mut tx, ops, xact, &mut warnings)?,
+        "in_house_use" => in_house_use(&mut tx, ops, xact)?,
+        "register" => return Err("Offline patron registration is not supported".to_string()),
+        k => return Err(format!("Unknown transaction type: {k}")),
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    Ok(warnings)
+}
+
+fn write_exceptions(ops: &OfflineOptions, exceptions: &[Exception]) -> Result<(), String> {
+    let mut writer: Box<dyn Write> = match &ops.exceptions_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let mut out = String::from("line,type,severity,patron_barcode,item_barcode,message\n");
+
+    for ex in exceptions {
+        out += &format!(
+            "{},{},{},{},{},{}\n",
+            ex.line,
+            csv_field(&ex.kind),
+            ex.severity,
+            csv_field(&ex.patron_barcode),
+            csv_field(&ex.barcode),
+            csv_field(&ex.message)
+        );
+    }
+
+    match writer.write_all(out.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing exceptions report: {e}")),
+    }
+}
+
+fn process(con: &mut DatabaseConnection, ops: &OfflineOptions) -> Result<(), String> {
+    let mut exceptions = Vec::new();
+    let xacts = read_xacts(ops, &mut exceptions)?;
+
+    info!("Processing {} offline transactions", xacts.len());
+
+    con.connect()?;
+
+    let mut applied = 0;
+
+    for xact in &xacts {
+        debug!("Line {}: {} {}", xact.line, xact.kind, xact.barcode);
+
+        let mut add = |severity, message| {
+            exceptions.push(Exception {
+                line: xact.line,
+                kind: xact.kind.to_string(),
+                severity,
+                patron_barcode: xact.patron_barcode.to_string(),
+                barcode: xact.barcode.to_string(),
+                message,
+            })
+        };
+
+        match process_xact(con, ops, xact) {
+            Ok(warnings) => {
+                applied += 1;
+                for w in warnings {
+                    add("warning", w);
+                }
+            }
+            Err(e) => add("error", e),
+        }
+    }
+
+    con.disconnect();
+
+    // Report in file order regardless of processing order.
+    exceptions.sort_by_key(|e| e.line);
+    write_exceptions(ops, &exceptions)?;
+
+    let errors = exceptions.iter().filter(|e| e.severity == "error").count();
+
+    audit::count("transactions", applied as u64);
+    audit::count("errors", errors as u64);
+
+    info!(
+        "{} {applied} transactions; {errors} errors, {} warnings",
+        ops.dry_run.past("Applied"),
+        exceptions.len() - errors
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(process(&mut connection, &options))
+}
@@ -0,0 +1,473 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use log::info;
+use postgres as pg;
+use serde_json::Value;
+use std::fs;
+
+/// actor.org_unit address columns, also used as spec keys, with the
+/// address_type of each.
+const ADDRESS_TYPES: &[(&str, &str)] = &[
+    ("mailing_address", "MAILING"),
+    ("billing_address", "BILLING"),
+    ("holds_address", "HOLDS"),
+    ("ill_address", "ILL"),
+];
+
+const ADDRESS_FIELDS: &[&str] = &[
+    "street1",
+    "street2",
+    "city",
+    "county",
+    "state",
+    "country",
+    "post_code",
+    "san",
+];
+
+struct BootstrapOptions {
+    spec_file: String,
+    template: String,
+    exclude_settings: Vec<String>,
+    no_hours: bool,
+    no_settings: bool,
+    dry_run: DryRun,
+}
+
+/// The new org unit, from the spec file.
+struct Spec {
+    shortname: String,
+    name: String,
+    parent: Option<String>,
+    ou_type: Option<String>,
+    email: Option<String>,
+    phone: Option<String>,
+    opac_visible: bool,
+    /// Addresses by org unit column, as keyed in the spec.
+    addresses: Vec<(&'static str, &'static str, Value)>,
+}
+
+fn read_options() -> (BootstrapOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "spec-file", "New Org Unit JSON File", "FILE");
+    opts.optopt("", "template", "Org Unit to Copy From", "SHORTNAME");
+    opts.optmulti(
+        "",
+        "exclude-setting",
+        "Setting Name Prefixes Not Copied",
+        "PREFIX",
+    );
+    opts.optflag("", "no-hours", "Do Not Copy Hours of Operation");
+    opts.optflag("", "no-settings", "Do Not Copy Settings");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let exclude_settings = params
+        .opt_strs("exclude-setting")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        BootstrapOptions {
+            spec_file: cli::required(&params, "spec-file"),
+            template: cli::required(&params, "template"),
+            exclude_settings,
+            no_hours: params.opt_present("no-hours"),
+            no_settings: params.opt_present("no-settings"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin org-bootstrap -- --spec-file br6.json --template BR1 --dry-run
+
+Creates a new org unit with its addresses, and copies hours of
+operation and org unit settings from a template org unit, in one
+transaction.
+
+The spec file is a JSON object:
+
+    {{
+        "shortname": "BR6",
+        "name": "Example Branch 6",
+        "parent": "SYS1",
+        "ou_type": "Branch",
+        "email": "br6@example.org",
+        "phone": "555-0106",
+        "opac_visible": false,
+        "address": {{
+            "street1": "6 Main St",
+            "city": "Anytown",
+            "state": "GA",
+            "country": "USA",
+            "post_code": "30000"
+        }}
+    }}
+
+shortname and name are required.  parent and ou_type, an org unit
+shortname and an org unit type name, default to the template's.
+opac_visible defaults to true.
+
+address is used for the mailing, billing, holds and ILL addresses.
+Any of mailing_address, billing_address, holds_address and
+ill_address may be given instead, with the same fields.  Address
+fields are street1, street2, city, county, state, country, post_code
+and san.
+
+Settings set directly on the template are copied.  Settings the
+template inherits apply to the new org unit when it shares the
+template's ancestors.
+
+Copy locations, call number prefixes and suffixes and copy templates
+can then be copied with config-sync.  Run autogen, and reload
+services as needed, for the new org unit to appear in the catalog
+and staff client.
+
+Options
+
+    --spec-file
+        Path to the JSON spec file.  Required.
+
+    --template
+        Org unit to copy hours and settings from.  Required.
+
+    --exclude-setting
+        Settings whose names start with this are not copied.
+        Repeatable or comma separated.
+
+    --no-hours
+        Leave the new org unit with Evergreen's default hours.
+
+    --no-settings
+        Do not copy settings.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn read_spec(path: &str) -> Result<Spec, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let spec: Value = match serde_json::from_str(&text) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let string = |key: &str| spec[key].as_str().map(|s| s.trim().to_string());
+
+    let (shortname, name) = match (string("shortname"), string("name")) {
+        (Some(s), Some(n)) if !s.is_empty() && !n.is_empty() => (s, n),
+        _ => return Err(format!("{path} requires shortname and name")),
+    };
+
+    let mut addresses = Vec::new();
+
+    for (column, address_type) in ADDRESS_TYPES {
+        let address = match spec[*column].is_object() {
+            true => spec[*column].clone(),
+            false => spec["address"].clone(),
+        };
+
+        if let Some(obj) = address.as_object() {
+            if let Some(key) = obj.keys().find(|k| !ADDRESS_FIELDS.contains(&k.as_str())) {
+                return Err(format!("Unknown address field in {path}: {key}"));
+            }
+            addresses.push((*column, *address_type, address));
+        }
+    }
+
+    Ok(Spec {
+        shortname,
+        name,
+        parent: string("parent"),
+        ou_type: string("ou_type"),
+        email: string("email"),
+        phone: string("phone"),
+        opac_visible: spec["opac_visible"].as_bool().unwrap_or(true),
+        addresses,
+    })
+}
+
+/// ID, parent and type of an org unit.
+fn org_unit(tx: &mut pg::Transaction, shortname: &str) -> Result<(i32, Option<i32>, i32), String> {
+    let sql = "SELECT id, parent_ou, ou_type FROM actor.org_unit WHERE shortname = $1";
+
+    match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok((row.get("id"), row.get("parent_ou"), row.get("ou_type"))),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error looking up org unit", e)),
+    }
+}
+
+/// Insert and attach one address.  Returns the new address ID.
+fn add_address(
+    tx: &mut pg::Transaction,
+    org: i32,
+    column: &str,
+    address_type: &str,
+    address: &Value,
+) -> Result<i32, String> {
+    let field = |name: &str| address[name].as_str().map(|s| s.trim().to_string());
+
+    let sql = r#"
+        INSERT INTO actor.org_address (
+            org_unit, address_type,
+            street1, street2, city, county, state, country, post_code, san
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING id
+    "#;
+
+    let row = tx
+        .query_one(
+            sql,
+            &[
+                &org,
+                &address_type,
+                &field("street1"),
+                &field("street2"),
+                &field("city"),
+                &field("county"),
+                &field("state"),
+                &field("country"),
+                &field("post_code"),
+                &field("san"),
+            ],
+        )
+        .map_err(|e| db_err(&format!("Error adding {column}"), e))?;
+
+    let id: i32 = row.get("id");
+
+    // Column names come from ADDRESS_TYPES.
+    let sql = format!("UPDATE actor.org_unit SET {column} = $2 WHERE id = $1");
+
+    tx.execute(sql.as_str(), &[&org, &id])
+        .map_err(|e| db_err(&format!("Error setting {column}"), e))?;
+
+    Ok(id)
+}
+
+/// Copy the template's hours.  Returns false if it has none.
+fn copy_hours(tx: &mut pg::Transaction, template: i32, org: i32) -> Result<bool, String> {
+    let sql = r#"
+        SELECT column_name::TEXT
+        FROM information_schema.columns
+        WHERE table_schema = 'actor'
+            AND table_name = 'hours_of_operation'
+            AND column_name <> 'id'
+        ORDER BY ordinal_position
+    "#;
+
+    let columns: Vec<String> = tx
+        .query(sql, &[])
+        .map_err(|e| db_err("Error reading hours columns", e))?
+        .iter()
+        .map(|r| r.get(0))
+        .collect();
+
+    let cols = columns.join(", ");
+
+    let sql = format!(
+        r#"
+        INSERT INTO actor.hours_of_operation (id, {cols})
+        SELECT $2, {cols} FROM actor.hours_of_operation WHERE id = $1
+        "#
+    );
+
+    tx.execute(sql.as_str(), &[&template, &org])
+        .map(|count| count > 0)
+        .map_err(|e| db_err("Error copying hours", e))
+}
+
+/// Copy the template's own settings.  Returns the number copied.
+fn copy_settings(
+    tx: &mut pg::Transaction,
+    ops: &BootstrapOptions,
+    template: i32,
+    org: i32,
+) -> Result<u64, String> {
+    let patterns: Vec<String> = ops
+        .exclude_settings
+        .iter()
+        .map(|p| {
+            format!(
+                "{}%",
+                p.replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            )
+        })
+        .collect();
+
+    let sql = r#"
+        INSERT INTO actor.org_unit_setting (org_unit, name, value)
+        SELECT $2, name, value
+        FROM actor.org_unit_setting
+        WHERE org_unit = $1
+            AND NOT name LIKE ANY($3::TEXT[])
+    "#;
+
+    tx.execute(sql, &[&template, &org, &patterns])
+        .map_err(|e| db_err("Error copying settings", e))
+}
+
+fn bootstrap(con: &mut DatabaseConnection, ops: &BootstrapOptions) -> Result<(), String> {
+    let spec = read_spec(&ops.spec_file)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let (template, template_parent, template_type) = org_unit(&mut tx, &ops.template)?;
+
+    let parent = match spec.parent.as_deref() {
+        Some(p) => org_unit(&mut tx, p)?.0,
+        None => match template_parent {
+            Some(p) => p,
+            None => return Err("The template has no parent; parent required".to_string()),
+        },
+    };
+
+    let ou_type: i32 = match spec.ou_type.as_deref() {
+        Some(t) => {
+            let sql = "SELECT id FROM actor.org_unit_type WHERE name = $1";
+            match tx.query_opt(sql, &[&t]) {
+                Ok(Some(row)) => row.get("id"),
+                Ok(None) => return Err(format!("No such org unit type: {t}")),
+                Err(e) => return Err(db_err("Error looking up org unit type", e)),
+            }
+        }
+        None => template_type,
+    };
+
+    // The new type must sit directly below the parent's type.
+    let sql = r#"
+        SELECT aout.name, aout.parent = parent.ou_type AS fits
+        FROM actor.org_unit_type aout, actor.org_unit parent
+        WHERE aout.id = $1 AND parent.id = $2
+    "#;
+
+    let row = tx
+        .query_one(sql, &[&ou_type, &parent])
+        .map_err(|e| db_err("Error checking org unit type", e))?;
+
+    if !row.get::<_, Option<bool>>("fits").unwrap_or(false) {
+        return Err(format!(
+            "Org unit type {} does not belong below the parent org unit",
+            row.get::<_, String>("name")
+        ));
+    }
+
+    let sql = r#"
+        INSERT INTO actor.org_unit (parent_ou, ou_type, shortname, name, email, phone, opac_visible)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id
+    "#;
+
+    let org: i32 = tx
+        .query_one(
+            sql,
+            &[
+                &parent,
+                &ou_type,
+                &spec.shortname,
+                &spec.name,
+                &spec.email,
+                &spec.phone,
+                &spec.opac_visible,
+            ],
+        )
+        .map_err(|e| db_err("Error creating org unit", e))?
+        .get("id");
+
+    audit::count("org_units", 1);
+    println!("Created org unit {} ({org})", spec.shortname);
+
+    // Identical addresses are shared by each column they fill.
+    let mut added: Vec<(&Value, i32)> = Vec::new();
+
+    for (column, address_type, address) in &spec.addresses {
+        let existing = added.iter().find(|(a, _)| *a == address).map(|(_, id)| *id);
+
+        let id = match existing {
+            Some(id) => {
+                let sql = format!("UPDATE actor.org_unit SET {column} = $2 WHERE id = $1");
+                tx.execute(sql.as_str(), &[&org, &id])
+                    .map_err(|e| db_err(&format!("Error setting {column}"), e))?;
+                id
+            }
+            None => {
+                let id = add_address(&mut tx, org, column, address_type, address)?;
+                added.push((address, id));
+                id
+            }
+        };
+
+        println!("Set {column} to address {id}");
+    }
+
+    if !ops.no_hours {
+        match copy_hours(&mut tx, template, org)? {
+            true => println!("Copied hours of operation from {}", ops.template),
+            false => println!("{} has no hours of operation to copy", ops.template),
+        }
+    }
+
+    if !ops.no_settings {
+        let count = copy_settings(&mut tx, ops, template, org)?;
+        audit::count("settings", count as u64);
+        println!("Copied {count} settings from {}", ops.template);
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    if ops.dry_run.enabled() {
+        info!("Dry run; nothing was saved");
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(bootstrap(&mut connection, &options))
+}
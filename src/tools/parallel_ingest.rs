@@ -0,0 +1,1444 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::log as eglog;
+use crate::metrics;
+use crate::signals::{self, Shutdown};
+use log::{debug, error, info, warn};
+use postgres as pg;
+use postgres::error::SqlState;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(not(feature = "rayon"))]
+use threadpool::ThreadPool;
+
+#[derive(Debug, Clone)]
+struct IngestOptions {
+    do_browse: bool,
+    do_attrs: bool,
+    do_search: bool,
+    do_facets: bool,
+    do_display: bool,
+    rebuild_rmsr: bool,
+    min_id: usize,
+    max_id: usize,
+    newest_first: bool,
+    /// Process the records in most demand first.
+    prioritize: Option<Priority>,
+    /// Batches of IDs read ahead of the parallel workers.  Defaults
+    /// to twice the pass's thread count.
+    queue_depth: Option<usize>,
+    /// Times to retry records which hit a deadlock in a parallel
+    /// pass before reporting them as errors.
+    deadlock_retries: u32,
+    /// Thread and batch size settings by phase name.
+    phases: HashMap<&'static str, PhaseConfig>,
+    attrs: Vec<String>,
+    sql_file: Option<String>,
+    /// Only records in this record bucket.
+    bucket: Option<i64>,
+    /// Only records whose ID modulo the count matches, for splitting a
+    /// run across hosts: (index, count), index zero-based.
+    partition: Option<(u32, u32)>,
+    /// Checked between records, so a signal stops every thread after
+    /// its current record.
+    shutdown: Shutdown,
+    lockfile: Option<String>,
+    /// Reingests each record, and the hash and batch log rows, in a
+    /// transaction which is rolled back.
+    dry_run: DryRun,
+    /// Name under which completed batches and record errors are
+    /// logged to egutil.ingest_batch and egutil.ingest_error.
+    run_name: Option<String>,
+    /// Hash of the attribute definitions and --attr list, set with
+    /// --skip-unchanged.  Records whose MARC hashes with it to the
+    /// value stored at their last attribute reingest are skipped.
+    attr_fingerprint: Option<String>,
+}
+
+/// Demand measures for --prioritize.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Priority {
+    /// Open holds on the record or its volumes or copies.
+    Holds,
+    /// Circulations of the record's copies in the last year.
+    Circ,
+}
+
+impl Priority {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "holds" => Ok(Priority::Holds),
+            "circ" => Ok(Priority::Circ),
+            _ => Err(format!(
+                "Invalid --prioritize {value}; expected holds or circ"
+            )),
+        }
+    }
+
+    /// Query of (record, demand) for records with any demand.
+    fn demand_sql(&self) -> &'static str {
+        match self {
+            Priority::Holds => {
+                "SELECT rhrr.bib_record AS record, COUNT(*) AS demand \
+                FROM reporter.hold_request_record rhrr \
+                JOIN action.hold_request ahr ON ahr.id = rhrr.id \
+                WHERE ahr.cancel_time IS NULL AND ahr.fulfillment_time IS NULL \
+                GROUP BY 1"
+            }
+            Priority::Circ => {
+                "SELECT acn.record, COUNT(*) AS demand \
+                FROM action.circulation circ \
+                JOIN asset.copy acp ON acp.id = circ.target_copy \
+                JOIN asset.call_number acn ON acn.id = acp.call_number \
+                WHERE circ.xact_start > NOW() - '1 year'::INTERVAL \
+                GROUP BY 1"
+            }
+        }
+    }
+}
+
+/// Ingest phases which accept --PHASE-threads and --PHASE-batch-size.
+const PHASES: &[&str] = &["browse", "search", "rmsr", "attrs", "facets", "display"];
+
+/// Phases which default to a single thread.  Browse entries are
+/// shared between records, so parallel browse ingest contends on
+/// them, and parallel search ingest can deadlock:
+/// https://bugs.launchpad.net/evergreen/+bug/1931737
+const SERIAL_PHASES: &[&str] = &["browse", "search", "rmsr"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PhaseConfig {
+    threads: usize,
+    batch_size: usize,
+}
+
+/// What one parallel pass over the records runs for each batch.
+/// Phases with the same settings share a pass.
+#[derive(Debug, Clone)]
+struct Pass {
+    /// Stage name for bookkeeping, e.g. attrs+facets.
+    stage: String,
+    config: PhaseConfig,
+    attrs: bool,
+    facets: bool,
+    display: bool,
+    /// Per-record SQL for browse, search or rmsr.
+    record_sql: Option<&'static str>,
+}
+
+/// Bookkeeping for one batch of one stage.  Rows are buffered as the
+/// batch runs and written with one statement per table at the end, so
+/// logging costs about the same at 1 thread or 50.
+#[derive(Default)]
+struct BatchLog {
+    records: Vec<i64>,
+    error_records: Vec<i64>,
+    errors: Vec<String>,
+    /// Hold deadlocked records for retry instead of reporting them.
+    retry_deadlocks: bool,
+    deadlocked: Vec<i64>,
+}
+
+impl BatchLog {
+    fn error(&mut self, id: i64, e: &pg::Error) {
+        if self.retry_deadlocks && e.code() == Some(&SqlState::T_R_DEADLOCK_DETECTED) {
+            debug!("Deadlock on record {id}; will retry");
+            self.deadlocked.push(id);
+            return;
+        }
+
+        self.fail(id, &e.to_string());
+    }
+
+    fn fail(&mut self, id: i64, message: &str) {
+        metrics::ERRORS.inc();
+        error!("Error processing record: {id} {message}");
+        self.error_records.push(id);
+        self.errors.push(message.to_string());
+    }
+
+    /// Write and clear the buffered rows.  Bookkeeping failures are
+    /// logged without stopping the ingest.
+    fn flush(&mut self, options: &IngestOptions, connection: &mut DatabaseConnection, stage: &str) {
+        let run = match &options.run_name {
+            Some(r) => r,
+            None => return,
+        };
+
+        if self.records.is_empty() {
+            return;
+        }
+
+        let start = Instant::now();
+
+        if let Err(e) = self.write(options.dry_run, connection, run, stage) {
+            error!("Cannot log {stage} batch for run {run}: {e}");
+        }
+
+        debug!(
+            "Logged {} records and {} errors in {:.3}s",
+            self.records.len(),
+            self.errors.len(),
+            start.elapsed().as_secs_f64()
+        );
+
+        *self = BatchLog::default();
+    }
+
+    fn write(
+        &self,
+        dry_run: DryRun,
+        connection: &mut DatabaseConnection,
+        run: &str,
+        stage: &str,
+    ) -> Result<(), String> {
+        let mut tx = dry_run.transaction(connection.client())?;
+
+        tx.execute(
+            "INSERT INTO egutil.ingest_batch (run, stage, records, errors) VALUES ($1, $2, $3, $4)",
+            &[&run, &stage, &self.records, &(self.errors.len() as i32)],
+        )
+        .map_err(|e| e.to_string())?;
+
+        if !self.errors.is_empty() {
+            let sql = r#"
+                INSERT INTO egutil.ingest_error (run, stage, record, error)
+                SELECT $1, $2, * FROM UNNEST($3::BIGINT[], $4::TEXT[])
+            "#;
+
+            tx.execute(sql, &[&run, &stage, &self.error_records, &self.errors])
+                .map_err(|e| e.to_string())?;
+        }
+
+        dry_run.finish(tx)
+    }
+}
+
+/// Read command line options and setup our database connection.
+fn init() -> (IngestOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "sql-file", "SQL Query File", "QUERY_FILE");
+    opts.optopt(
+        "",
+        "bucket",
+        "Only Records in This Record Bucket",
+        "BUCKET_ID",
+    );
+
+    opts.optopt("", "max-threads", "Max Worker Threads", "MAX_THREADS");
+    opts.optopt(
+        "",
+        "batch-size",
+        "Number of Records to Process per Batch",
+        "BATCH_SIZE",
+    );
+    opts.optopt(
+        "",
+        "queue-depth",
+        "Batches of IDs to Read Ahead of Workers",
+        "BATCHES",
+    );
+    cli::append_id_range(&mut opts);
+    opts.optopt(
+        "",
+        "partition",
+        "Process Share I of N of Record IDs, for Multi-Host Runs",
+        "I/N",
+    );
+    opts.optmulti(
+        "",
+        "attr",
+        "Reingest Specific Attribute, Repetable",
+        "RECORD_ATTR",
+    );
+
+    opts.optflag("", "do-browse", "Update Browse");
+    opts.optflag("", "do-attrs", "Update Record Attributes");
+    opts.optflag("", "do-search", "Update Search Indexes");
+    opts.optflag("", "do-facets", "Update Facets");
+    opts.optflag("", "do-display", "Update Display Fields");
+    opts.optflag("", "newest-first", "Update Records Newest to Oldest");
+    opts.optopt(
+        "",
+        "prioritize",
+        "Update Records with the Most Holds or Circs First",
+        "holds|circ",
+    );
+    opts.optflag("", "rebuild-rmsr", "Rebuild Reporter Simple Record");
+    opts.optflag(
+        "",
+        "skip-unchanged",
+        "Skip Attribute Reingest for Records Unchanged Since Last Run",
+    );
+
+    for phase in PHASES {
+        opts.optopt(
+            "",
+            &format!("{phase}-threads"),
+            &format!("Threads for {phase}"),
+            "THREADS",
+        );
+        opts.optopt(
+            "",
+            &format!("{phase}-batch-size"),
+            &format!("Batch Size for {phase}"),
+            "BATCH_SIZE",
+        );
+    }
+
+    opts.optopt(
+        "",
+        "deadlock-retries",
+        "Retries for Deadlocked Records, Default 3",
+        "COUNT",
+    );
+
+    opts.optopt(
+        "",
+        "run-name",
+        "Log Batches and Errors Under This Name; Reuse to Resume",
+        "NAME",
+    );
+
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, || println!("{}", opts.usage("Usage: ")));
+
+    let partition = match params.opt_str("partition").map(|p| parse_partition(&p)) {
+        Some(Ok(p)) => Some(p),
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+
+    let prioritize = match params.opt_str("prioritize").map(|p| Priority::parse(&p)) {
+        Some(Ok(p)) => Some(p),
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+
+    if params.opt_present("bucket") && params.opt_present("sql-file") {
+        eprintln!("--bucket and --sql-file cannot be combined");
+        std::process::exit(2);
+    }
+
+    let max_threads = params.opt_get_default("max-threads", 5).unwrap();
+    let batch_size = params.opt_get_default("batch-size", 100).unwrap();
+
+    let mut phases = HashMap::new();
+    for phase in PHASES {
+        let threads = match SERIAL_PHASES.contains(phase) {
+            true => 1,
+            false => max_threads,
+        };
+
+        let config = PhaseConfig {
+            threads: params
+                .opt_get_default(&format!("{phase}-threads"), threads)
+                .unwrap()
+                .max(1),
+            batch_size: params
+                .opt_get_default(&format!("{phase}-batch-size"), batch_size)
+                .unwrap()
+                .max(1),
+        };
+
+        phases.insert(*phase, config);
+    }
+
+    let ingest_ops = IngestOptions {
+        do_browse: params.opt_present("do-browse"),
+        do_attrs: params.opt_present("do-attrs"),
+        do_search: params.opt_present("do-search"),
+        do_facets: params.opt_present("do-facets"),
+        do_display: params.opt_present("do-display"),
+        min_id: params.opt_get_default("min-id", 0).unwrap(),
+        max_id: params.opt_get_default("max-id", 0).unwrap(),
+        newest_first: params.opt_present("newest-first"),
+        prioritize,
+        rebuild_rmsr: params.opt_present("rebuild-rmsr"),
+        queue_depth: params.opt_get("queue-depth").unwrap(),
+        deadlock_retries: params.opt_get_default("deadlock-retries", 3).unwrap(),
+        phases,
+        attrs: params.opt_strs("attr"),
+        sql_file: params.opt_get("sql-file").unwrap(),
+        bucket: params.opt_get("bucket").unwrap(),
+        partition,
+        shutdown: signals::install().unwrap(),
+        lockfile: params.opt_str("lockfile"),
+        dry_run: DryRun::from_params(&params),
+        run_name: params.opt_str("run-name"),
+        // Computed once connected.
+        attr_fingerprint: match params.opt_present("skip-unchanged") {
+            true => Some(String::new()),
+            false => None,
+        },
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (ingest_ops, connection)
+}
+
+/// I/N, where I is 1 through N.
+fn parse_partition(value: &str) -> Result<(u32, u32), String> {
+    let err = || format!("Invalid --partition {value}; expected I/N, e.g. 2/4");
+
+    let (index, count) = value.split_once('/').ok_or_else(err)?;
+
+    let index: u32 = index.trim().parse().map_err(|_| err())?;
+    let count: u32 = count.trim().parse().map_err(|_| err())?;
+
+    if index == 0 || index > count {
+        return Err(err());
+    }
+
+    Ok((index - 1, count))
+}
+
+fn create_sql(options: &IngestOptions) -> String {
+    if let Some(ref fname) = options.sql_file {
+        let sql = fs::read_to_string(fname).unwrap();
+
+        let sql = match options.partition {
+            // Order is kept through the subquery.
+            Some((index, count)) => format!(
+                "SELECT q.* FROM ({}) q WHERE q.id % {count} = {index}",
+                sql.trim().trim_end_matches(';')
+            ),
+            None => sql,
+        };
+
+        return match options.prioritize {
+            Some(priority) => format!(
+                "SELECT q.* FROM ({}) q LEFT JOIN ({}) demand ON demand.record = q.id \
+                ORDER BY COALESCE(demand.demand, 0) DESC, q.id",
+                sql.trim().trim_end_matches(';'),
+                priority.demand_sql()
+            ),
+            None => sql,
+        };
+    }
+
+    let mut select = "SELECT id FROM biblio.record_entry".to_string();
+    let mut filter = format!("WHERE NOT deleted AND id > {}", options.min_id);
+
+    if options.max_id > 0 {
+        filter += &format!(" AND id < {}", options.max_id);
+    }
+
+    if let Some(bucket) = options.bucket {
+        filter += &format!(
+            " AND id IN (SELECT target_biblio_record_entry \
+            FROM container.biblio_record_entry_bucket_item WHERE bucket = {bucket})"
+        );
+    }
+
+    if let Some((index, count)) = options.partition {
+        filter += &format!(" AND id % {count} = {index}");
+    }
+
+    let order_by = if options.newest_first {
+        "ORDER BY create_date DESC, id DESC"
+    } else {
+        "ORDER BY id"
+    };
+
+    let order_by = match options.prioritize {
+        Some(priority) => {
+            select += &format!(
+                " LEFT JOIN ({}) demand ON demand.record = id",
+                priority.demand_sql()
+            );
+            order_by.replace("ORDER BY", "ORDER BY COALESCE(demand.demand, 0) DESC,")
+        }
+        None => order_by.to_string(),
+    };
+
+    format!("{select} {filter} {order_by}")
+}
+
+fn get_record_ids(connection: &mut DatabaseConnection, sql: &str) -> Vec<i64> {
+    let mut ids = Vec::new();
+
+    for row in connection.client().query(sql, &[]).unwrap() {
+        let id: i64 = row.get("id");
+        ids.push(id);
+    }
+
+    info!("Found {} record IDs to process", ids.len());
+
+    ids
+}
+
+fn create_hash_table(connection: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.ingest_hash (
+            record      BIGINT NOT NULL,
+            stage       TEXT NOT NULL,
+            marc_hash   TEXT NOT NULL,
+            ingest_time TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            PRIMARY KEY (record, stage)
+        );
+    "#;
+
+    match connection.client().batch_execute(sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot create egutil.ingest_hash: {e}")),
+    }
+}
+
+/// Hash of everything besides the MARC which affects attribute
+/// reingest: the attribute and coded value definitions and --attr.
+fn attr_fingerprint(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+) -> Result<String, String> {
+    let sql = r#"
+        SELECT MD5(
+            COALESCE((
+                SELECT STRING_AGG(crad::TEXT, '|' ORDER BY crad.name)
+                FROM config.record_attr_definition crad
+            ), '') ||
+            COALESCE((
+                SELECT STRING_AGG(ccvm::TEXT, '|' ORDER BY ccvm.id)
+                FROM config.coded_value_map ccvm
+            ), '') ||
+            $1
+        ) AS fingerprint
+    "#;
+
+    let attrs = options.attrs.join(",");
+
+    match connection.client().query_one(sql, &[&attrs]) {
+        Ok(row) => Ok(row.get("fingerprint")),
+        Err(e) => Err(format!("Cannot hash attribute definitions: {e}")),
+    }
+}
+
+fn create_log_tables(connection: &mut DatabaseConnection) -> Result<(), String> {
+    let sql = r#"
+        CREATE SCHEMA IF NOT EXISTS egutil;
+        CREATE TABLE IF NOT EXISTS egutil.ingest_batch (
+            id            BIGSERIAL PRIMARY KEY,
+            run           TEXT NOT NULL,
+            stage         TEXT NOT NULL,
+            records       BIGINT[] NOT NULL,
+            errors        INTEGER NOT NULL,
+            complete_time TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        CREATE INDEX IF NOT EXISTS ingest_batch_run_idx
+            ON egutil.ingest_batch (run, stage);
+        CREATE TABLE IF NOT EXISTS egutil.ingest_error (
+            id          BIGSERIAL PRIMARY KEY,
+            run         TEXT NOT NULL,
+            stage       TEXT NOT NULL,
+            record      BIGINT NOT NULL,
+            error       TEXT NOT NULL,
+            create_time TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+    "#;
+
+    match connection.client().batch_execute(sql) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot create ingest log tables: {e}")),
+    }
+}
+
+/// IDs already processed by a stage of the current run.
+fn completed_ids(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    stage: &str,
+) -> HashSet<i64> {
+    let run = match &options.run_name {
+        Some(r) => r,
+        None => return HashSet::new(),
+    };
+
+    let sql = r#"
+        SELECT DISTINCT UNNEST(records) AS id
+        FROM egutil.ingest_batch
+        WHERE run = $1 AND stage = $2
+    "#;
+
+    let done: HashSet<i64> = connection
+        .client()
+        .query(sql, &[run, &stage])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    if !done.is_empty() {
+        info!(
+            "Run {run} already completed {} records for {stage}",
+            done.len()
+        );
+    }
+
+    done
+}
+
+/// IDs not yet processed by a stage of the current run.
+fn remaining_ids(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    stage: &str,
+    ids: &[i64],
+) -> Vec<i64> {
+    let done = completed_ids(options, connection, stage);
+
+    ids.iter()
+        .filter(|id| !done.contains(id))
+        .copied()
+        .collect()
+}
+
+/// Read batches of IDs from a cursor over the record query and
+/// queue them for the workers.  The channel is bounded, so this
+/// blocks while the workers are --queue-depth batches behind.
+fn produce_ids(
+    options: IngestOptions,
+    mut connection: DatabaseConnection,
+    sql: String,
+    batch_size: usize,
+    done: HashSet<i64>,
+    sender: SyncSender<Vec<i64>>,
+) {
+    let result = read_ids(&options, &mut connection, &sql, batch_size, &done, &sender);
+
+    if let Err(e) = result {
+        error!("Cannot read record IDs: {e}");
+        // Stop the workers and report the ingest as incomplete.
+        options.shutdown.request();
+    }
+
+    connection.disconnect();
+}
+
+fn read_ids(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    sql: &str,
+    batch_size: usize,
+    done: &HashSet<i64>,
+    sender: &SyncSender<Vec<i64>>,
+) -> Result<(), String> {
+    connection.connect()?;
+
+    let mut tx = match connection.client().transaction() {
+        Ok(t) => t,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let declare = format!("DECLARE ingest_ids NO SCROLL CURSOR FOR {sql}");
+    if let Err(e) = tx.batch_execute(&declare) {
+        return Err(e.to_string());
+    }
+
+    let fetch = format!("FETCH FORWARD {batch_size} FROM ingest_ids");
+    let mut count = 0;
+
+    while !options.shutdown.requested() {
+        let rows = match tx.query(fetch.as_str(), &[]) {
+            Ok(r) => r,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        if rows.is_empty() {
+            break;
+        }
+
+        let batch: Vec<i64> = rows
+            .iter()
+            .map(|row| row.get::<_, i64>("id"))
+            .filter(|id| !done.contains(id))
+            .collect();
+
+        if batch.is_empty() {
+            continue;
+        }
+
+        count += batch.len();
+
+        // Fails once every worker has stopped.
+        if sender.send(batch).is_err() {
+            break;
+        }
+    }
+
+    info!("Queued {count} record IDs");
+
+    // The cursor closes as the read-only transaction rolls back.
+    Ok(())
+}
+
+impl IngestOptions {
+    fn phase(&self, name: &str) -> PhaseConfig {
+        self.phases[name]
+    }
+}
+
+const BROWSE_SQL: &str = r#"
+    SELECT metabib.reingest_metabib_field_entries(
+        bib_id := $1,
+        skip_browse  := FALSE,
+        skip_facet   := TRUE,
+        skip_search  := TRUE,
+        skip_display := TRUE
+    )
+"#;
+
+const SEARCH_SQL: &str = r#"
+    SELECT metabib.reingest_metabib_field_entries(
+        bib_id := $1,
+        skip_facet := TRUE,
+        skip_browse := TRUE,
+        skip_search := FALSE,
+        skip_display := TRUE
+    )
+"#;
+
+const RMSR_SQL: &str = r#"SELECT reporter.simple_rec_update($1)"#;
+
+fn ingest_records(options: &IngestOptions, connection: &mut DatabaseConnection, sql: &str) {
+    let serial = [
+        (options.do_browse, "browse", BROWSE_SQL),
+        (options.rebuild_rmsr, "rmsr", RMSR_SQL),
+        (options.do_search, "search", SEARCH_SQL),
+    ];
+
+    // Single-threaded phases work from the full ID list.
+    let mut ids: Option<Vec<i64>> = None;
+
+    for (requested, phase, record_sql) in serial {
+        if !requested || options.shutdown.requested() {
+            continue;
+        }
+
+        let config = options.phase(phase);
+
+        if config.threads == 1 {
+            let ids = ids.get_or_insert_with(|| get_record_ids(connection, sql));
+            let ids = remaining_ids(options, connection, phase, ids);
+            run_serialized_updates(
+                options,
+                connection,
+                &ids,
+                phase,
+                config.batch_size,
+                record_sql,
+            );
+        } else {
+            let pass = Pass {
+                stage: phase.to_string(),
+                config,
+                attrs: false,
+                facets: false,
+                display: false,
+                record_sql: Some(record_sql),
+            };
+            run_pass(options, connection, sql, &pass);
+        }
+    }
+
+    // Remaining phases can be run in parallel, fed from a cursor
+    // instead of the full ID list.
+
+    let parallel = [
+        (options.do_attrs, "attrs"),
+        (options.do_facets, "facets"),
+        (options.do_display, "display"),
+    ];
+
+    let mut passes: Vec<Pass> = Vec::new();
+
+    for (requested, phase) in parallel {
+        if !requested {
+            continue;
+        }
+
+        let config = options.phase(phase);
+
+        let pass = match passes.last_mut() {
+            Some(p) if p.config == config => {
+                p.stage += &format!("+{phase}");
+                p
+            }
+            _ => {
+                passes.push(Pass {
+                    stage: phase.to_string(),
+                    config,
+                    attrs: false,
+                    facets: false,
+                    display: false,
+                    record_sql: None,
+                });
+                passes.last_mut().unwrap()
+            }
+        };
+
+        match phase {
+            "attrs" => pass.attrs = true,
+            "facets" => pass.facets = true,
+            _ => pass.display = true,
+        }
+    }
+
+    for pass in passes {
+        if !options.shutdown.requested() {
+            run_pass(options, connection, sql, &pass);
+        }
+    }
+}
+
+/// Stream IDs to pass.config.threads workers.
+fn run_pass(options: &IngestOptions, connection: &mut DatabaseConnection, sql: &str, pass: &Pass) {
+    info!(
+        "Starting {} with {} threads and batches of {}",
+        pass.stage, pass.config.threads, pass.config.batch_size
+    );
+
+    let done = completed_ids(options, connection, &pass.stage);
+
+    let depth = options.queue_depth.unwrap_or(pass.config.threads * 2);
+    let (sender, receiver) = mpsc::sync_channel(depth);
+
+    let producer = {
+        let ops = options.clone();
+        let con = connection.partial_clone();
+        let sql = sql.to_string();
+        let batch_size = pass.config.batch_size;
+        thread::spawn(move || produce_ids(ops, con, sql, batch_size, done, sender))
+    };
+
+    run_parallel(options, connection, receiver, pass);
+
+    producer.join().ok();
+}
+
+/// Run batches on a fixed pool of threads, each taking the next
+/// queued batch as it finishes the last.
+#[cfg(not(feature = "rayon"))]
+fn run_parallel(
+    options: &IngestOptions,
+    connection: &DatabaseConnection,
+    receiver: Receiver<Vec<i64>>,
+    pass: &Pass,
+) {
+    use std::sync::{Arc, Mutex};
+
+    let pool = ThreadPool::new(pass.config.threads);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for _ in 0..pass.config.threads {
+        let ops = options.clone();
+        let pass = pass.clone();
+        let mut con = connection.partial_clone();
+        let receiver = receiver.clone();
+
+        pool.execute(move || {
+            // One connection per thread, for every batch it runs.
+            if let Err(e) = con.connect() {
+                error!("Worker cannot start: {e}");
+                return;
+            }
+
+            loop {
+                let batch = match receiver.lock().unwrap().recv() {
+                    Ok(b) => b,
+                    Err(_) => break, // Producer is done.
+                };
+
+                if ops.shutdown.requested() {
+                    break;
+                }
+
+                process_batch(&ops, &mut con, &pass, batch);
+            }
+
+            con.disconnect();
+        });
+    }
+
+    // Without this, the producer would block on a full queue once
+    // the workers exit.
+    drop(receiver);
+
+    pool.join();
+}
+
+/// Run batches on rayon's work-stealing pool.  Idle threads take
+/// batches from busy ones, so a few slow records do not leave the
+/// rest of the pool waiting.
+#[cfg(feature = "rayon")]
+fn run_parallel(
+    options: &IngestOptions,
+    connection: &DatabaseConnection,
+    receiver: Receiver<Vec<i64>>,
+    pass: &Pass,
+) {
+    use rayon::iter::ParallelBridge;
+    use rayon::prelude::*;
+    use std::sync::Mutex;
+
+    let pool = match rayon::ThreadPoolBuilder::new()
+        .num_threads(pass.config.threads)
+        .thread_name(|n| format!("ingest-{n}"))
+        .build()
+    {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Cannot start thread pool: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    // One connection per pool thread, connected on its first batch
+    // and reused after.  Each thread only locks its own.
+    let connections: Vec<Mutex<DatabaseConnection>> = (0..pass.config.threads)
+        .map(|_| Mutex::new(connection.partial_clone()))
+        .collect();
+
+    pool.install(|| {
+        receiver.into_iter().par_bridge().for_each(|batch| {
+            if options.shutdown.requested() {
+                return;
+            }
+
+            let idx = rayon::current_thread_index().unwrap_or(0);
+            let mut con = connections[idx].lock().unwrap();
+
+            process_batch(options, &mut con, pass, batch);
+        });
+    });
+}
+
+/// Process one batch on the worker's connection, which stays open
+/// for the life of the thread.
+fn process_batch(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    pass: &Pass,
+    ids: Vec<i64>,
+) {
+    let idlen = ids.len();
+    let start = Instant::now();
+
+    eglog::set_batch(Some(format!("{}..{}", ids[0], ids[idlen - 1])));
+
+    info!("Processing {idlen} records");
+
+    if let Err(e) = connection.ensure_connected() {
+        metrics::ERRORS.inc();
+        error!("Skipping batch: {e}");
+        eglog::clear_context();
+        return;
+    }
+
+    let mut log = BatchLog {
+        retry_deadlocks: options.deadlock_retries > 0,
+        ..Default::default()
+    };
+
+    let done = run_actions(options, connection, pass, &ids, &mut log);
+
+    retry_deadlocked(options, connection, pass, idlen, &mut log);
+
+    log.records = ids[..done].to_vec();
+    log.flush(options, connection, &pass.stage);
+
+    metrics::BATCH_SECONDS.observe_since(start);
+
+    // Pool threads are reused for later batches.
+    eglog::clear_context();
+}
+
+/// Run each action of the pass on ids.  Records are complete once
+/// every action has run, and each action stops early on shutdown, so
+/// this returns the number of records processed by all of them.
+fn run_actions(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    pass: &Pass,
+    ids: &[i64],
+    log: &mut BatchLog,
+) -> usize {
+    let mut done = ids.len();
+
+    if let Some(sql) = pass.record_sql {
+        done = done.min(run_record_sql(options, connection, ids, sql, log));
+    }
+
+    if pass.attrs {
+        done = done.min(reingest_attributes(options, connection, ids, log));
+    }
+
+    if pass.facets || pass.display {
+        done = done.min(reingest_field_entries(
+            options,
+            connection,
+            ids,
+            pass.facets,
+            pass.display,
+            log,
+        ));
+    }
+
+    done
+}
+
+/// Rerun records which deadlocked, after a jittered backoff and in
+/// progressively smaller batches, so competing threads are less likely
+/// to collide again.  Records still deadlocking after the last retry
+/// are reported as errors.
+fn retry_deadlocked(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    pass: &Pass,
+    batch_size: usize,
+    log: &mut BatchLog,
+) {
+    let mut retry = std::mem::take(&mut log.deadlocked);
+    retry.sort();
+    retry.dedup();
+
+    let mut size = batch_size;
+    let mut attempt = 0;
+
+    while !retry.is_empty() && attempt < options.deadlock_retries {
+        attempt += 1;
+        size = (size / 2).max(1);
+
+        let delay = backoff(attempt);
+        warn!(
+            "{} records deadlocked; retry {attempt} of {} in {}ms",
+            retry.len(),
+            options.deadlock_retries,
+            delay.as_millis()
+        );
+
+        if !options.shutdown.sleep(delay) {
+            break;
+        }
+
+        for chunk in retry.chunks(size) {
+            run_actions(options, connection, pass, chunk, log);
+        }
+
+        retry = std::mem::take(&mut log.deadlocked);
+        retry.sort();
+        retry.dedup();
+    }
+
+    for id in retry {
+        log.fail(id, &format!("Deadlock detected after {attempt} retries"));
+    }
+}
+
+/// 200ms doubling per attempt, scaled by a random 50-150%.
+fn backoff(attempt: u32) -> Duration {
+    let base = 200 * 2u64.pow(attempt.saturating_sub(1).min(8));
+
+    // Clock nanoseconds vary enough between threads for jitter.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+
+    Duration::from_millis(base / 2 + (nanos as u64 % base.max(1)))
+}
+
+/// Execute the provided SQL on all records, chopped into batches.
+fn run_serialized_updates(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    ids: &[i64],
+    stage: &str,
+    batch_size: usize,
+    sql: &str,
+) {
+    // We can't create the statement until we are connected.
+    let mut stmt: Option<pg::Statement> = None;
+    let mut log = BatchLog::default();
+
+    for (counter, id) in ids.iter().enumerate() {
+        if options.shutdown.requested() {
+            break;
+        }
+
+        if counter.is_multiple_of(batch_size) {
+            log.flush(options, connection, stage);
+            connection.disconnect();
+            connection.connect().unwrap();
+            stmt = Some(connection.client().prepare(sql).unwrap());
+            info!("{stage} has processed {counter} records");
+        }
+
+        let result = metrics::DB_QUERY_SECONDS.time(|| {
+            options.dry_run.isolate(connection.client(), |c| {
+                c.query(stmt.as_ref().unwrap(), &[id])
+            })
+        });
+
+        metrics::RECORDS_PROCESSED.inc();
+        log.records.push(*id);
+
+        if let Err(e) = result {
+            log.error(*id, &e);
+        }
+    }
+
+    log.flush(options, connection, stage);
+}
+
+/// Run per-record SQL for browse, search or rmsr on one batch of a
+/// parallel pass.  Returns the number of records processed before any
+/// shutdown.
+fn run_record_sql(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    ids: &[i64],
+    sql: &str,
+    log: &mut BatchLog,
+) -> usize {
+    let stmt = connection.client().prepare(sql).unwrap();
+
+    let mut count = 0;
+    for id in ids {
+        if options.shutdown.requested() {
+            break;
+        }
+
+        eglog::set_record(Some(*id));
+
+        let result = metrics::DB_QUERY_SECONDS.time(|| {
+            options
+                .dry_run
+                .isolate(connection.client(), |c| c.query(&stmt, &[id]))
+        });
+
+        metrics::RECORDS_PROCESSED.inc();
+        count += 1;
+
+        if let Err(e) = result {
+            log.error(*id, &e);
+        }
+    }
+
+    eglog::set_record(None);
+
+    count
+}
+
+/// Returns the number of records processed before any shutdown.
+fn reingest_field_entries(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    ids: &[i64],
+    facets: bool,
+    display: bool,
+    log: &mut BatchLog,
+) -> usize {
+    debug!("Batch starting reingest_field_entries()");
+
+    let sql = r#"
+        SELECT metabib.reingest_metabib_field_entries(
+            bib_id := $1,
+            skip_facet := $2,
+            skip_browse := TRUE,
+            skip_search := TRUE,
+            skip_display := $3
+        )
+    "#;
+
+    let stmt = connection.client().prepare(sql).unwrap();
+
+    let mut count = 0;
+    for id in ids {
+        if options.shutdown.requested() {
+            break;
+        }
+
+        eglog::set_record(Some(*id));
+
+        let result = metrics::DB_QUERY_SECONDS.time(|| {
+            options.dry_run.isolate(connection.client(), |c| {
+                c.query(&stmt, &[id, &!facets, &!display])
+            })
+        });
+
+        metrics::RECORDS_PROCESSED.inc();
+        count += 1;
+
+        if let Err(e) = result {
+            log.error(*id, &e);
+        }
+    }
+
+    eglog::set_record(None);
+
+    count
+}
+
+/// Returns the number of records processed before any shutdown.
+///
+/// With --skip-unchanged, records whose MARC and attribute definitions
+/// hash the same as at their last attribute reingest are skipped and
+/// count as processed.
+fn reingest_attributes(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    ids: &[i64],
+    log: &mut BatchLog,
+) -> usize {
+    let fingerprint = match &options.attr_fingerprint {
+        Some(f) => f,
+        None => return reingest_attributes_batch(options, connection, ids, log),
+    };
+
+    let changed = match changed_records(connection, ids, fingerprint) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Cannot compare record hashes; reingesting all: {e}");
+            return reingest_attributes_batch(options, connection, ids, log);
+        }
+    };
+
+    debug!("Skipping {} unchanged records", ids.len() - changed.len());
+
+    let (todo, hashes): (Vec<i64>, Vec<String>) = changed.into_iter().unzip();
+
+    let count = reingest_attributes_batch(options, connection, &todo, log);
+
+    // Failed records keep their old hash, so the next run retries them.
+    let (records, hashes): (Vec<i64>, Vec<String>) = todo[..count]
+        .iter()
+        .zip(hashes)
+        .filter(|(id, _)| !log.error_records.contains(id) && !log.deadlocked.contains(id))
+        .map(|(id, hash)| (*id, hash))
+        .unzip();
+
+    if let Err(e) = store_hashes(options, connection, &records, &hashes) {
+        error!("Cannot store record hashes: {e}");
+    }
+
+    // Skipped records are only known complete once the rest are.
+    match count == todo.len() {
+        true => ids.len(),
+        false => 0,
+    }
+}
+
+/// IDs and current hashes of records whose hash differs from the one
+/// stored at their last attribute reingest.
+fn changed_records(
+    connection: &mut DatabaseConnection,
+    ids: &[i64],
+    fingerprint: &str,
+) -> Result<Vec<(i64, String)>, pg::Error> {
+    let sql = r#"
+        SELECT bre.id, MD5(bre.marc || $2) AS marc_hash
+        FROM biblio.record_entry bre
+        LEFT JOIN egutil.ingest_hash hash
+            ON hash.record = bre.id AND hash.stage = 'attrs'
+        WHERE bre.id = ANY($1)
+            AND hash.marc_hash IS DISTINCT FROM MD5(bre.marc || $2)
+        ORDER BY bre.id
+    "#;
+
+    let rows = connection.client().query(sql, &[&ids, &fingerprint])?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("id"), row.get("marc_hash")))
+        .collect())
+}
+
+fn store_hashes(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    records: &[i64],
+    hashes: &[String],
+) -> Result<u64, pg::Error> {
+    if records.is_empty() {
+        return Ok(0);
+    }
+
+    let sql = r#"
+        INSERT INTO egutil.ingest_hash (record, stage, marc_hash)
+        SELECT record, 'attrs', marc_hash
+        FROM UNNEST($1::BIGINT[], $2::TEXT[]) AS u (record, marc_hash)
+        ON CONFLICT (record, stage) DO UPDATE
+            SET marc_hash = EXCLUDED.marc_hash, ingest_time = NOW()
+    "#;
+
+    options.dry_run.isolate(connection.client(), |c| {
+        c.execute(sql, &[&records, &hashes])
+    })
+}
+
+/// The whole batch is sent as one multi-statement simple query, so
+/// there is one round trip per batch instead of one per record.  The
+/// statements run in one implicit transaction, so if any record fails
+/// the batch is rolled back and rerun a record at a time to find the
+/// failures.
+fn reingest_attributes_batch(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    ids: &[i64],
+    log: &mut BatchLog,
+) -> usize {
+    debug!("Batch starting reingest_attributes()");
+
+    if options.shutdown.requested() {
+        return 0;
+    }
+
+    let attrs = match options.attrs.is_empty() {
+        true => String::new(),
+        false => {
+            let list: Vec<String> = options
+                .attrs
+                .iter()
+                .map(|a| format!("'{}'", a.replace('\'', "''")))
+                .collect();
+            format!(", ARRAY[{}]::TEXT[]", list.join(","))
+        }
+    };
+
+    let sql: String = ids
+        .iter()
+        .map(|id| {
+            format!(
+                "SELECT metabib.reingest_record_attributes(id{attrs}) \
+                FROM biblio.record_entry WHERE id = {id};\n"
+            )
+        })
+        .collect();
+
+    let result = metrics::DB_QUERY_SECONDS.time(|| {
+        options
+            .dry_run
+            .isolate(connection.client(), |c| c.batch_execute(&sql))
+    });
+
+    match result {
+        Ok(_) => {
+            metrics::RECORDS_PROCESSED.add(ids.len() as u64);
+            ids.len()
+        }
+        Err(e) => {
+            warn!("Batch attribute reingest failed; retrying records singly: {e}");
+            reingest_attributes_singly(options, connection, ids, log)
+        }
+    }
+}
+
+fn reingest_attributes_singly(
+    options: &IngestOptions,
+    connection: &mut DatabaseConnection,
+    ids: &[i64],
+    log: &mut BatchLog,
+) -> usize {
+    let has_attr_filter = !options.attrs.is_empty();
+
+    let mut sql = r#"
+        SELECT metabib.reingest_record_attributes($1)
+        FROM biblio.record_entry
+        WHERE id = $2
+    "#;
+
+    if has_attr_filter {
+        sql = r#"
+            SELECT metabib.reingest_record_attributes($1, $3)
+            FROM biblio.record_entry
+            WHERE id = $2
+        "#;
+    }
+
+    let client = connection.client();
+    let stmt = client.prepare(sql).unwrap();
+
+    let mut count = 0;
+    for id in ids {
+        if options.shutdown.requested() {
+            break;
+        }
+
+        eglog::set_record(Some(*id));
+        let result = metrics::DB_QUERY_SECONDS.time(|| {
+            options.dry_run.isolate(client, |c| match has_attr_filter {
+                false => c.query(&stmt, &[id, id]),
+                _ => c.query(&stmt, &[id, id, &options.attrs.as_slice()]),
+            })
+        });
+
+        metrics::RECORDS_PROCESSED.inc();
+        count += 1;
+
+        if let Err(e) = result {
+            log.error(*id, &e);
+        }
+    }
+
+    eglog::set_record(None);
+
+    count
+}
+
+fn run(options: &mut IngestOptions, connection: &mut DatabaseConnection) -> Result<(), String> {
+    connection.connect()?;
+
+    if options.run_name.is_some() {
+        create_log_tables(connection)?;
+    }
+
+    if options.attr_fingerprint.is_some() {
+        create_hash_table(connection)?;
+        options.attr_fingerprint = Some(attr_fingerprint(options, connection)?);
+    }
+
+    let sql = create_sql(options);
+
+    ingest_records(options, connection, &sql);
+
+    audit::count("records", metrics::RECORDS_PROCESSED.get());
+    audit::count("errors", metrics::ERRORS.get());
+
+    if options.shutdown.requested() {
+        return Err("Shutdown requested; ingest is incomplete".to_string());
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (mut options, mut connection) = init();
+
+    let _lock = match options.lockfile.as_deref().map(lockfile::acquire) {
+        Some(Err(e)) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+        lock => lock,
+    };
+
+    audit::finish(run(&mut options, &mut connection))
+}
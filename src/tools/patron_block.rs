@@ -0,0 +1,472 @@
+use crate::audit;
+use crate::cli;
+use crate::date::{Interval, Timestamp};
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use std::collections::HashSet;
+use std::{fs, process};
+
+const CHANGE_COLUMNS: &[&str] = &[
+    "patron",
+    "barcode",
+    "action",
+    "penalty",
+    "org_unit",
+    "stop_date",
+    "note",
+];
+
+struct BlockOptions {
+    penalty: String,
+    remove: bool,
+    barcode_file: Option<String>,
+    query_file: Option<String>,
+    org_unit: Option<String>,
+    note: Option<String>,
+    expire: Option<String>,
+    staff: i32,
+    change_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// A patron named in the input.
+struct Patron {
+    id: i32,
+    barcode: Option<String>,
+    home_ou: i32,
+}
+
+#[derive(Default)]
+struct Summary {
+    patrons: usize,
+    applied: usize,
+    removed: usize,
+    unchanged: usize,
+    not_found: usize,
+}
+
+fn read_options() -> (BlockOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "penalty", "Standing Penalty Name", "NAME");
+    opts.optflag("", "remove", "Remove the Penalty");
+    opts.optopt("", "barcode-file", "File of Patron Barcodes", "FILE");
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+    opts.optopt("", "org-unit", "Penalty Org Unit", "SHORTNAME");
+    opts.optopt("", "note", "Penalty Note", "TEXT");
+    opts.optopt("", "expire", "Penalty Stop Date", "DATE_OR_INTERVAL");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "change-file", "Change Log Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    if !params.opt_present("barcode-file") && !params.opt_present("query-file") {
+        eprintln!("--barcode-file or --query-file is required");
+        process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        BlockOptions {
+            penalty: cli::required(&params, "penalty"),
+            remove: params.opt_present("remove"),
+            barcode_file: params.opt_str("barcode-file"),
+            query_file: params.opt_str("query-file"),
+            org_unit: params.opt_str("org-unit"),
+            note: params.opt_str("note"),
+            expire: params.opt_str("expire"),
+            staff: cli::required(&params, "staff"),
+            change_file: params.opt_str("change-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin patron-block -- --penalty PATRON_EXCEEDS_FINES \
+        --barcode-file abusers.txt --note "E-resource abuse" --expire "90 days" --staff 1
+    cargo run --bin patron-block -- --penalty PATRON_IN_COLLECTIONS --remove \
+        --query-file paid-up.sql --staff 1 --change-file removed.csv
+
+Applies or removes a standing penalty (block) for a list of patrons,
+logging every change.
+
+Patrons are listed one barcode per line in --barcode-file, or selected
+by --query-file, or both.
+
+Applied penalties are set at --org-unit, or each patron's home library,
+with the --note and --expire stop date.  Patrons who already have the
+penalty at that org unit are left alone.
+
+Removed penalties are ended, by setting their stop date to now, as the
+staff client does, at --org-unit or at every org unit.
+
+Options
+
+    --penalty
+        config.standing_penalty name.  Required.
+
+    --remove
+        Remove the penalty instead of applying it.
+
+    --barcode-file
+        File of patron card barcodes, one per line.
+
+    --query-file
+        Path to a file containing an SQL query.  The query must
+        produce rows with an "id" column of actor.usr IDs.
+
+    --org-unit
+        Org unit shortname the penalty applies at.  Defaults to the
+        patron's home library when applying, and every org unit when
+        removing.
+
+    --note
+        Note stored with applied penalties.
+
+    --expire
+        Stop date for applied penalties, as a date or timestamp, or
+        an interval from now, e.g. "90 days".
+
+    --staff
+        Staff user recorded as the penalty creator.  Required.
+
+    --change-file
+        Write the change log to this file.  Otherwise, writes to
+        STDOUT.  Files ending in .tsv or .xlsx are written as TSV
+        or a spreadsheet.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Stop date from a timestamp or an interval from now.
+fn stop_date(value: &str) -> Result<Timestamp, String> {
+    if let Ok(ts) = Timestamp::parse(value) {
+        return Ok(ts);
+    }
+
+    match Interval::parse(value) {
+        Ok(i) if i.approx_seconds() > 0 => Ok(Timestamp::now().add(&i)),
+        _ => Err(format!(
+            "Invalid --expire: {value}.  Expected a date or a future interval"
+        )),
+    }
+}
+
+fn read_barcodes(path: &str) -> Result<Vec<String>, String> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(s
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()),
+        Err(e) => Err(format!("Cannot read {path}: {e}")),
+    }
+}
+
+fn load_patrons(
+    tx: &mut pg::Transaction,
+    ops: &BlockOptions,
+    summary: &mut Summary,
+) -> Result<Vec<Patron>, String> {
+    let mut patrons = Vec::new();
+    let mut seen = HashSet::new();
+
+    if let Some(ref path) = ops.barcode_file {
+        let sql = r#"
+            SELECT au.id, au.home_ou
+            FROM actor.card ac
+                JOIN actor.usr au ON au.id = ac.usr
+            WHERE ac.barcode = $1 AND NOT au.deleted
+        "#;
+
+        for barcode in read_barcodes(path)? {
+            let row = tx
+                .query_opt(sql, &[&barcode])
+                .map_err(|e| db_err("Error loading patron", e))?;
+
+            let row = match row {
+                Some(r) => r,
+                None => {
+                    warn!("No patron found for {barcode}");
+                    summary.not_found += 1;
+                    continue;
+                }
+            };
+
+            let id: i32 = row.get("id");
+            if seen.insert(id) {
+                patrons.push(Patron {
+                    id,
+                    barcode: Some(barcode),
+                    home_ou: row.get("home_ou"),
+                });
+            }
+        }
+    }
+
+    if let Some(ref path) = ops.query_file {
+        let query = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot read {path}: {e}")),
+        };
+
+        let sql = format!(
+            r#"
+            SELECT au.id, au.home_ou, ac.barcode
+            FROM actor.usr au
+                LEFT JOIN actor.card ac ON ac.id = au.card
+            WHERE NOT au.deleted AND au.id IN (SELECT q.id FROM ({query}) q)
+            ORDER BY au.id
+            "#
+        );
+
+        let rows = tx
+            .query(sql.as_str(), &[])
+            .map_err(|e| db_err("Error running patron query", e))?;
+
+        for row in rows {
+            let id: i32 = row.get("id");
+            if seen.insert(id) {
+                patrons.push(Patron {
+                    id,
+                    barcode: row.get("barcode"),
+                    home_ou: row.get("home_ou"),
+                });
+            }
+        }
+    }
+
+    Ok(patrons)
+}
+
+fn penalty_id(tx: &mut pg::Transaction, name: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM config.standing_penalty WHERE name = $1";
+
+    match tx.query_opt(sql, &[&name]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such standing penalty: {name}")),
+        Err(e) => Err(db_err("Error loading standing penalty", e)),
+    }
+}
+
+fn org_id(tx: &mut pg::Transaction, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error loading org unit", e)),
+    }
+}
+
+/// Apply the penalty unless the patron already has it at the org
+/// unit.  Returns true if it was added.
+fn apply(
+    tx: &mut pg::Transaction,
+    ops: &BlockOptions,
+    penalty: i32,
+    patron: &Patron,
+    org_unit: i32,
+    stop: Option<&String>,
+) -> Result<bool, String> {
+    let sql = r#"
+        SELECT 1 FROM actor.usr_standing_penalty
+        WHERE usr = $1 AND standing_penalty = $2 AND org_unit = $3
+            AND (stop_date IS NULL OR stop_date > NOW())
+    "#;
+
+    let existing = tx
+        .query_opt(sql, &[&patron.id, &penalty, &org_unit])
+        .map_err(|e| db_err("Error loading penalties", e))?;
+
+    if existing.is_some() {
+        return Ok(false);
+    }
+
+    let sql = r#"
+        INSERT INTO actor.usr_standing_penalty
+            (usr, standing_penalty, org_unit, staff, note, stop_date)
+        VALUES ($1, $2, $3, $4, $5, $6::TEXT::TIMESTAMPTZ)
+    "#;
+
+    tx.execute(
+        sql,
+        &[
+            &patron.id, &penalty, &org_unit, &ops.staff, &ops.note, &stop,
+        ],
+    )
+    .map_err(|e| db_err(&format!("Error applying penalty to {}", patron.id), e))?;
+
+    Ok(true)
+}
+
+/// End the patron's active penalties, returning the org units they
+/// applied at.
+fn remove(
+    tx: &mut pg::Transaction,
+    penalty: i32,
+    patron: &Patron,
+    org_unit: Option<i32>,
+) -> Result<Vec<i32>, String> {
+    let sql = r#"
+        UPDATE actor.usr_standing_penalty SET stop_date = NOW()
+        WHERE usr = $1 AND standing_penalty = $2
+            AND ($3::INT IS NULL OR org_unit = $3::INT)
+            AND (stop_date IS NULL OR stop_date > NOW())
+        RETURNING org_unit
+    "#;
+
+    let rows = tx
+        .query(sql, &[&patron.id, &penalty, &org_unit])
+        .map_err(|e| db_err(&format!("Error removing penalty from {}", patron.id), e))?;
+
+    Ok(rows.iter().map(|r| r.get("org_unit")).collect())
+}
+
+fn org_shortname(tx: &mut pg::Transaction, id: i32) -> Result<String, String> {
+    let sql = "SELECT shortname FROM actor.org_unit WHERE id = $1";
+
+    match tx.query_one(sql, &[&id]) {
+        Ok(row) => Ok(row.get("shortname")),
+        Err(e) => Err(db_err("Error loading org unit", e)),
+    }
+}
+
+fn run(con: &mut DatabaseConnection, ops: &BlockOptions) -> Result<(), String> {
+    let stop = match &ops.expire {
+        Some(e) => Some(stop_date(e)?.to_iso8601()),
+        None => None,
+    };
+
+    let mut writer = TableWriter::for_path(ops.change_file.as_deref(), Format::Csv)?;
+    writer.write_header(CHANGE_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let penalty = penalty_id(&mut tx, &ops.penalty)?;
+
+    let org_unit = match &ops.org_unit {
+        Some(o) => Some(org_id(&mut tx, o)?),
+        None => None,
+    };
+
+    let mut summary = Summary::default();
+    let patrons = load_patrons(&mut tx, ops, &mut summary)?;
+
+    summary.patrons = patrons.len();
+    info!("Processing {} patrons", patrons.len());
+
+    for patron in &patrons {
+        let (action, orgs) = if ops.remove {
+            ("removed", remove(&mut tx, penalty, patron, org_unit)?)
+        } else {
+            let org = org_unit.unwrap_or(patron.home_ou);
+            match apply(&mut tx, ops, penalty, patron, org, stop.as_ref())? {
+                true => ("applied", vec![org]),
+                false => ("unchanged", vec![org]),
+            }
+        };
+
+        if action == "removed" && orgs.is_empty() {
+            summary.unchanged += 1;
+            continue;
+        }
+
+        for org in orgs {
+            let shortname = org_shortname(&mut tx, org)?;
+
+            match action {
+                "applied" => summary.applied += 1,
+                "removed" => summary.removed += 1,
+                _ => summary.unchanged += 1,
+            }
+
+            if action != "unchanged" {
+                info!(
+                    "Patron {} {action} {} at {shortname}",
+                    patron.id, ops.penalty
+                );
+            }
+
+            let (stop, note) = match action {
+                "applied" => (stop.as_ref(), ops.note.as_ref()),
+                _ => (None, None),
+            };
+
+            writer.write_row(&[
+                Cell::from(patron.id),
+                Cell::from(patron.barcode.as_ref()),
+                Cell::from(action),
+                Cell::from(&ops.penalty),
+                Cell::from(shortname),
+                Cell::from(stop),
+                Cell::from(note),
+            ])?;
+        }
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("patrons", summary.patrons as u64);
+    audit::count("applied", summary.applied as u64);
+    audit::count("removed", summary.removed as u64);
+
+    info!(
+        "{}{} patrons: {} penalties applied, {} removed, {} unchanged, {} barcodes not found",
+        if ops.dry_run.enabled() {
+            "Dry run; "
+        } else {
+            ""
+        },
+        summary.patrons,
+        summary.applied,
+        summary.removed,
+        summary.unchanged,
+        summary.not_found
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(run(&mut connection, &options))
+}
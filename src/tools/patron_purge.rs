@@ -0,0 +1,295 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::signals;
+use log::{error, info};
+use std::time::{Duration, Instant};
+
+struct PurgeOptions {
+    expired_days: i32,
+    dest_usr: Option<i32>,
+    org_unit: Option<i32>,
+    mark_deleted: bool,
+    batch_size: i64,
+    sleep: u64,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (PurgeOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "expired-days", "Days Since Expiration", "DAYS");
+    opts.optopt(
+        "",
+        "dest-usr",
+        "User Receiving Purged Patron Data",
+        "USER_ID",
+    );
+    opts.optopt("", "org-unit", "Limit to Patrons Homed Here", "ORG_ID");
+    opts.optflag("", "mark-deleted", "Mark Deleted Instead of Anonymizing");
+    opts.optopt("", "batch-size", "Patrons per Transaction", "BATCH_SIZE");
+    opts.optopt("", "sleep", "Milliseconds to Pause Between Batches", "MS");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        PurgeOptions {
+            expired_days: params.opt_get_default("expired-days", 1095).unwrap(),
+            dest_usr: params.opt_get("dest-usr").unwrap(),
+            org_unit: params.opt_get("org-unit").unwrap(),
+            mark_deleted: params.opt_present("mark-deleted"),
+            batch_size: params.opt_get_default("batch-size", 100).unwrap(),
+            sleep: params.opt_get_default("sleep", 0).unwrap(),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin patron-purge -- --expired-days 1095 --dest-usr 1 --dry-run
+
+Purges patrons whose accounts expired more than the requested number
+of days ago.  Counts of eligible patrons per home org unit are always
+reported before any changes are made.
+
+A patron is eligible when they are not already deleted, and have no
+open transactions, no balance, no active holds, and no staff working
+locations.
+
+By default, eligible patrons are purged with actor.usr_delete(),
+which removes their personal data and transfers anything they own
+to the --dest-usr account.
+
+Options
+
+    --expired-days
+        Purge patrons expired more than this many days.
+        Defaults to 1095 (3 years).
+
+    --dest-usr
+        ID of the user which takes ownership of data created by
+        purged patrons.  Required unless --mark-deleted.
+
+    --org-unit
+        Limit to patrons whose home org unit is this org unit or
+        one of its descendants.
+
+    --mark-deleted
+        Set the deleted flag on eligible patrons instead of purging
+        their data.
+
+    --batch-size
+        Number of patrons purged per transaction.  Defaults to 100.
+
+    --sleep
+        Milliseconds to pause between batches to reduce load.
+
+{}
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+/// Shared filter for eligible patrons.
+///
+/// $1 expired days, $2 org unit (or NULL), $3 destination user (or NULL).
+const ELIGIBLE_WHERE: &str = r#"
+    WHERE NOT au.deleted
+        AND au.expire_date < NOW() - ($1::INT * '1 day'::INTERVAL)
+        AND ($2::INT IS NULL OR au.home_ou IN (
+            SELECT id FROM actor.org_unit_descendants($2::INT)
+        ))
+        AND ($3::INT IS NULL OR au.id <> $3::INT)
+        AND NOT EXISTS (
+            SELECT 1 FROM money.billable_xact mbx
+            WHERE mbx.usr = au.id AND mbx.xact_finish IS NULL
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM money.usr_summary mus
+            WHERE mus.usr = au.id AND mus.balance_owed <> 0
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM action.hold_request ahr
+            WHERE ahr.usr = au.id
+                AND ahr.fulfillment_time IS NULL
+                AND ahr.cancel_time IS NULL
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM permission.usr_work_ou_map puwom
+            WHERE puwom.usr = au.id
+        )
+"#;
+
+fn report_counts(con: &mut DatabaseConnection, ops: &PurgeOptions) -> Result<i64, String> {
+    let sql = format!(
+        r#"
+        SELECT aou.shortname, COUNT(*) AS count
+        FROM actor.usr au
+        JOIN actor.org_unit aou ON aou.id = au.home_ou
+        {ELIGIBLE_WHERE}
+        GROUP BY aou.shortname
+        ORDER BY aou.shortname
+        "#
+    );
+
+    let rows = match con
+        .client()
+        .query(&sql, &[&ops.expired_days, &ops.org_unit, &ops.dest_usr])
+    {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error counting patrons: {e}")),
+    };
+
+    let mut total = 0;
+
+    println!("{:<20} {:>10}", "Org Unit", "Patrons");
+    for row in rows {
+        let shortname: String = row.get("shortname");
+        let count: i64 = row.get("count");
+        total += count;
+        println!("{:<20} {:>10}", shortname, count);
+    }
+    println!("{:<20} {:>10}", "Total", total);
+
+    Ok(total)
+}
+
+fn purge(con: &mut DatabaseConnection, ops: &PurgeOptions) -> Result<(), String> {
+    if !ops.mark_deleted && ops.dest_usr.is_none() {
+        return Err("--dest-usr is required to purge patrons".to_string());
+    }
+
+    con.connect()?;
+
+    let total = report_counts(con, ops)?;
+
+    if total == 0 {
+        con.disconnect();
+        return Ok(());
+    }
+
+    let select = format!(
+        "SELECT au.id FROM actor.usr au {ELIGIBLE_WHERE} AND au.id > $4 ORDER BY au.id LIMIT $5"
+    );
+
+    let stmt = match con.client().prepare(&select) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error preparing patron query: {e}")),
+    };
+
+    let action = match ops.mark_deleted {
+        true => "UPDATE actor.usr SET deleted = TRUE WHERE id = $1",
+        false => "SELECT actor.usr_delete($1::INT, $2::INT)",
+    };
+
+    let shutdown = signals::install()?;
+
+    let start = Instant::now();
+    let mut last_id: i32 = 0;
+    let mut purged = 0;
+
+    loop {
+        // Committed batches stay purged.
+        if shutdown.requested() {
+            return Err(format!(
+                "Shutdown requested after purging {purged} patron(s)"
+            ));
+        }
+
+        let rows = match con.client().query(
+            &stmt,
+            &[
+                &ops.expired_days,
+                &ops.org_unit,
+                &ops.dest_usr,
+                &last_id,
+                &ops.batch_size,
+            ],
+        ) {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Error finding patrons: {e}")),
+        };
+
+        if rows.is_empty() {
+            break;
+        }
+
+        let ids: Vec<i32> = rows.iter().map(|r| r.get("id")).collect();
+        last_id = *ids.last().unwrap();
+
+        let mut tx = ops.dry_run.transaction(con.client())?;
+
+        for id in &ids {
+            let result = match ops.mark_deleted {
+                true => tx.execute(action, &[id]).map(|_| ()),
+                false => tx.query(action, &[id, &ops.dest_usr]).map(|_| ()),
+            };
+
+            if let Err(e) = result {
+                error!("Error purging patron {id}: {e}");
+                return Err(format!("Batch rolled back after error on patron {id}"));
+            }
+        }
+
+        ops.dry_run.finish(tx)?;
+
+        purged += ids.len();
+        info!("Purged {purged} of {total} patrons");
+
+        if ops.sleep > 0 {
+            shutdown.sleep(Duration::from_millis(ops.sleep));
+        }
+    }
+
+    con.disconnect();
+
+    audit::count("patrons", purged as u64);
+
+    println!(
+        "{} {purged} patron(s) in {:.1}s",
+        ops.dry_run.past("Purged"),
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(purge(&mut connection, &options))
+}
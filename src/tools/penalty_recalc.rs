@@ -0,0 +1,234 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use crate::penalty;
+use crate::signals;
+use log::{error, info};
+use std::time::{Duration, Instant};
+
+struct RecalcOptions {
+    users: Vec<i32>,
+    org_unit: Option<i32>,
+    batch_size: i64,
+    sleep: u64,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (RecalcOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "usr", "Recalculate This Patron", "USER_ID");
+    opts.optopt("", "org-unit", "Limit to Patrons Homed Here", "ORG_ID");
+    opts.optopt("", "batch-size", "Patrons per Transaction", "BATCH_SIZE");
+    opts.optopt("", "sleep", "Milliseconds to Pause Between Batches", "MS");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let users = params
+        .opt_strs("usr")
+        .iter()
+        .map(|u| {
+            u.parse()
+                .unwrap_or_else(|_| panic!("Invalid --usr value: {u}"))
+        })
+        .collect();
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        RecalcOptions {
+            users,
+            org_unit: params.opt_get("org-unit").unwrap(),
+            batch_size: params.opt_get_default("batch-size", 100).unwrap(),
+            sleep: params.opt_get_default("sleep", 0).unwrap(),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin penalty-recalc -- --org-unit 4 --dry-run
+
+Recalculates system standing penalties, e.g. PATRON_EXCEEDS_FINES
+and PATRON_EXCEEDS_OVERDUE_COUNT, for non-deleted patrons, as after a
+change to permission.grp_penalty_threshold.  Penalties are applied
+where the patron reaches a threshold and removed where they no
+longer do.  Staff-applied penalties and notes are not touched.
+
+Every change is reported as CSV on STDOUT: usr, penalty, org_unit,
+change (applied or removed).
+
+Options
+
+    --usr
+        Recalculate this patron.  Repeatable.  Otherwise, every
+        patron is recalculated.
+
+    --org-unit
+        Limit to patrons whose home library is this org unit or one
+        of its descendants.
+
+    --batch-size
+        Number of patrons recalculated per transaction.
+        Defaults to 100.
+
+    --sleep
+        Milliseconds to pause between batches to reduce load.
+
+{}
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn recalc(con: &mut DatabaseConnection, ops: &RecalcOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let select = r#"
+        SELECT au.id
+        FROM actor.usr au
+        WHERE NOT au.deleted
+            AND (CARDINALITY($1::INT[]) = 0 OR au.id = ANY($1::INT[]))
+            AND ($2::INT IS NULL OR au.home_ou IN (
+                SELECT id FROM actor.org_unit_descendants($2::INT)
+            ))
+            AND au.id > $3
+        ORDER BY au.id
+        LIMIT $4
+    "#;
+
+    let stmt = match con.client().prepare(select) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error preparing patron query: {e}")),
+    };
+
+    let shutdown = signals::install()?;
+
+    let start = Instant::now();
+    let mut last_id: i32 = 0;
+    let mut checked = 0;
+    let mut applied = 0;
+    let mut removed = 0;
+
+    println!("usr,penalty,org_unit,change");
+
+    loop {
+        // Committed batches stay recalculated.
+        if shutdown.requested() {
+            return Err(format!(
+                "Shutdown requested after recalculating {checked} patron(s)"
+            ));
+        }
+
+        let rows = match con.client().query(
+            &stmt,
+            &[&ops.users, &ops.org_unit, &last_id, &ops.batch_size],
+        ) {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Error finding patrons: {e}")),
+        };
+
+        if rows.is_empty() {
+            break;
+        }
+
+        let ids: Vec<i32> = rows.iter().map(|r| r.get("id")).collect();
+        last_id = *ids.last().unwrap();
+
+        let mut tx = ops.dry_run.transaction(con.client())?;
+
+        for id in &ids {
+            let changes = match penalty::recalculate(&mut tx, *id) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("{e}");
+                    return Err(format!("Batch rolled back after error on patron {id}"));
+                }
+            };
+
+            for change in changes {
+                let action = match change.applied {
+                    true => {
+                        applied += 1;
+                        "applied"
+                    }
+                    false => {
+                        removed += 1;
+                        "removed"
+                    }
+                };
+
+                println!(
+                    "{},{},{},{action}",
+                    change.usr, change.kind, change.org_unit
+                );
+            }
+        }
+
+        ops.dry_run.finish(tx)?;
+
+        checked += ids.len();
+        info!("Recalculated {checked} patrons");
+
+        if ops.sleep > 0 {
+            shutdown.sleep(Duration::from_millis(ops.sleep));
+        }
+    }
+
+    con.disconnect();
+
+    let verb = if ops.dry_run.enabled() {
+        "would be "
+    } else {
+        ""
+    };
+
+    audit::count("patrons", checked as u64);
+    audit::count("applied", applied as u64);
+    audit::count("removed", removed as u64);
+
+    info!(
+        "{checked} patron(s) in {:.1}s: {applied} penalties {verb}applied, {removed} {verb}removed",
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(recalc(&mut connection, &options))
+}
@@ -0,0 +1,515 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::info;
+use postgres as pg;
+use std::collections::BTreeMap;
+use std::process;
+
+/// Each group paired with itself and each of its ancestors.
+const ANCESTRY_CTE: &str = r#"
+    ancestry AS (
+        SELECT id AS grp, id AS ancestor, parent
+        FROM permission.grp_tree
+        UNION ALL
+        SELECT a.grp, pgt.id, pgt.parent
+        FROM ancestry a
+            JOIN permission.grp_tree pgt ON pgt.id = a.parent
+    )
+"#;
+
+/// Each user's profile and secondary groups.
+const MEMBERSHIP_CTE: &str = r#"
+    membership AS (
+        SELECT au.id AS usr, au.profile AS grp, 'profile' AS source
+        FROM actor.usr au
+        UNION
+        SELECT pugm.usr, pugm.grp, 'secondary'
+        FROM permission.usr_grp_map pugm
+    )
+"#;
+
+/// Columns shared by the user listings.
+const USER_COLUMNS: &str = r#"
+    au.id AS usr,
+    au.usrname,
+    ac.barcode,
+    CONCAT_WS(', ', au.family_name, au.first_given_name) AS name,
+    home.shortname AS home_ou,
+    au.active,
+    work.shortname AS work_ou
+"#;
+
+/// Joins for USER_COLUMNS on a u.usr column, limited to active,
+/// undeleted users unless $1, working at or below $2 when set.
+const USER_JOINS: &str = r#"
+    JOIN actor.usr au ON au.id = u.usr
+    JOIN actor.org_unit home ON home.id = au.home_ou
+    LEFT JOIN actor.card ac ON ac.id = au.card
+    LEFT JOIN permission.usr_work_ou_map puwom ON puwom.usr = au.id
+    LEFT JOIN actor.org_unit work ON work.id = puwom.work_ou
+"#;
+
+const USER_FILTER: &str = r#"
+    NOT au.deleted
+    AND ($1 OR au.active)
+    AND ($2::INT IS NULL OR puwom.work_ou IN (
+        SELECT id FROM actor.org_unit_descendants($2::INT)))
+"#;
+
+const PERM_COLUMNS: &[&str] = &[
+    "usr",
+    "usrname",
+    "barcode",
+    "name",
+    "home_ou",
+    "active",
+    "work_ou",
+    "perm",
+    "source",
+    "member_group",
+    "granting_group",
+    "depth",
+    "scope_ou",
+    "grantable",
+];
+
+const GROUP_COLUMNS: &[&str] = &[
+    "usr",
+    "usrname",
+    "barcode",
+    "name",
+    "home_ou",
+    "active",
+    "work_ou",
+    "source",
+    "member_group",
+];
+
+struct AuditOptions {
+    perms: Vec<String>,
+    group: Option<String>,
+    diff: Vec<String>,
+    org_unit: Option<String>,
+    include_inactive: bool,
+    no_everything: bool,
+    all: bool,
+    out_file: Option<String>,
+}
+
+fn read_options() -> (AuditOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "perm", "Permission Codes to Audit", "CODES");
+    opts.optopt("", "group", "Permission Group to Audit", "GROUP");
+    opts.optmulti("", "diff", "Permission Groups to Compare", "GROUP");
+    opts.optopt("", "org-unit", "Limit to Users Working Here", "SHORTNAME");
+    opts.optflag("", "include-inactive", "Include Inactive Users");
+    opts.optflag("", "no-everything", "Omit EVERYTHING Holders");
+    opts.optflag("", "all", "Include Identical Permissions in Diffs");
+    opts.optopt("", "out-file", "Output File", "FILE");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let perms: Vec<String> = params
+        .opt_strs("perm")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    let group = params.opt_str("group");
+    let diff = params.opt_strs("diff");
+
+    let modes = [!perms.is_empty(), group.is_some(), !diff.is_empty()];
+    if modes.iter().filter(|m| **m).count() != 1 {
+        eprintln!("One of --perm, --group or --diff is required");
+        process::exit(2);
+    }
+
+    if !diff.is_empty() && diff.len() != 2 {
+        eprintln!("--diff requires exactly two groups");
+        process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        AuditOptions {
+            perms,
+            group,
+            diff,
+            org_unit: params.opt_str("org-unit"),
+            include_inactive: params.opt_present("include-inactive"),
+            no_everything: params.opt_present("no-everything"),
+            all: params.opt_present("all"),
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin perm-audit -- --perm UPDATE_USER,DELETE_USER --org-unit BR1
+    cargo run --bin perm-audit -- --group "Circulation Administrator"
+    cargo run --bin perm-audit -- --diff "Cataloger" --diff "Cat1"
+
+Audits who holds permissions, for security reviews.
+
+Modes
+
+    --perm
+        Lists each user holding the permissions, directly or through
+        a profile or secondary group and any of its ancestors, once
+        per working location, with the org unit where the grant
+        applies.  Holders of EVERYTHING are included unless
+        --no-everything is given.
+
+    --group
+        Lists each user in the group or a group below it, as a
+        profile or secondary group, once per working location.
+
+    --diff
+        Compares the effective permissions of two groups, including
+        those inherited from ancestor groups.  Each permission held
+        by only one group, or at a different depth or grantability,
+        is reported.  Where a group inherits a permission more than
+        once, the broadest depth applies.
+
+Users without working locations are listed with an empty work_ou.
+Their permissions apply nowhere until one is added.
+
+Options
+
+    --perm
+        Permission codes.  Repeatable or comma separated.
+
+    --group
+        Permission group name.
+
+    --diff
+        Permission group name.  Given twice.
+
+    --org-unit
+        Only list users working at or below this org unit.
+
+    --include-inactive
+        Include inactive users.  Deleted users are never included.
+
+    --no-everything
+        Omit users holding a --perm only through EVERYTHING.
+
+    --all
+        Include permissions identical in both --diff groups.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn org_id(con: &mut DatabaseConnection, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    match con.client().query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error looking up org unit", e)),
+    }
+}
+
+fn group_id(con: &mut DatabaseConnection, name: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM permission.grp_tree WHERE name = $1";
+
+    match con.client().query_opt(sql, &[&name]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such permission group: {name}")),
+        Err(e) => Err(db_err("Error looking up permission group", e)),
+    }
+}
+
+fn user_cells(row: &pg::Row) -> Vec<Cell> {
+    vec![
+        Cell::from(row.get::<_, i32>("usr")),
+        Cell::from(row.get::<_, String>("usrname")),
+        Cell::from(row.get::<_, Option<String>>("barcode")),
+        Cell::from(row.get::<_, String>("name")),
+        Cell::from(row.get::<_, String>("home_ou")),
+        Cell::from(row.get::<_, bool>("active")),
+        Cell::from(row.get::<_, Option<String>>("work_ou")),
+    ]
+}
+
+/// List users holding any of the permissions.
+fn audit_perms(
+    con: &mut DatabaseConnection,
+    ops: &AuditOptions,
+    org: Option<i32>,
+) -> Result<(), String> {
+    let mut codes = ops.perms.clone();
+    if !ops.no_everything {
+        codes.push("EVERYTHING".to_string());
+    }
+
+    let sql = format!(
+        r#"
+        WITH RECURSIVE {ANCESTRY_CTE}, {MEMBERSHIP_CTE},
+        grants AS (
+            SELECT m.usr, m.source, m.grp AS member_group,
+                a.ancestor AS granting_group,
+                pgpm.perm, pgpm.depth, pgpm.grantable
+            FROM membership m
+                JOIN ancestry a ON a.grp = m.grp
+                JOIN permission.grp_perm_map pgpm ON pgpm.grp = a.ancestor
+            UNION ALL
+            SELECT pupm.usr, 'user', NULL, NULL,
+                pupm.perm, pupm.depth, pupm.grantable
+            FROM permission.usr_perm_map pupm
+        )
+        SELECT {USER_COLUMNS},
+            pl.code AS perm,
+            u.source,
+            mg.name AS member_group,
+            gg.name AS granting_group,
+            u.depth,
+            scope.shortname AS scope_ou,
+            u.grantable
+        FROM grants u
+            JOIN permission.perm_list pl ON pl.id = u.perm
+            {USER_JOINS}
+            LEFT JOIN permission.grp_tree mg ON mg.id = u.member_group
+            LEFT JOIN permission.grp_tree gg ON gg.id = u.granting_group
+            LEFT JOIN LATERAL (
+                SELECT aou.shortname
+                FROM actor.org_unit_ancestors(puwom.work_ou) aou
+                    JOIN actor.org_unit_type aout ON aout.id = aou.ou_type
+                WHERE aout.depth = u.depth
+            ) scope ON TRUE
+        WHERE pl.code = ANY($3) AND {USER_FILTER}
+        ORDER BY au.usrname, pl.code, work.shortname, mg.name, gg.name
+        "#
+    );
+
+    let rows = match con
+        .client()
+        .query(sql.as_str(), &[&ops.include_inactive, &org, &codes])
+    {
+        Ok(r) => r,
+        Err(e) => return Err(db_err("Error querying permission holders", e)),
+    };
+
+    info!("Found {} permission grant(s)", rows.len());
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(PERM_COLUMNS)?;
+
+    for row in rows {
+        let mut cells = user_cells(&row);
+
+        cells.push(Cell::from(row.get::<_, String>("perm")));
+        cells.push(Cell::from(row.get::<_, String>("source")));
+        cells.push(Cell::from(row.get::<_, Option<String>>("member_group")));
+        cells.push(Cell::from(row.get::<_, Option<String>>("granting_group")));
+        cells.push(Cell::from(row.get::<_, i32>("depth")));
+        cells.push(Cell::from(row.get::<_, Option<String>>("scope_ou")));
+        cells.push(Cell::from(row.get::<_, bool>("grantable")));
+
+        writer.write_row(&cells)?;
+    }
+
+    writer.finish()
+}
+
+/// List users in a group or any group below it.
+fn audit_group(
+    con: &mut DatabaseConnection,
+    ops: &AuditOptions,
+    org: Option<i32>,
+    group: &str,
+) -> Result<(), String> {
+    let grp = group_id(con, group)?;
+
+    let sql = format!(
+        r#"
+        WITH RECURSIVE {ANCESTRY_CTE}, {MEMBERSHIP_CTE}
+        SELECT {USER_COLUMNS},
+            u.source,
+            mg.name AS member_group
+        FROM membership u
+            JOIN ancestry a ON a.grp = u.grp AND a.ancestor = $3
+            JOIN permission.grp_tree mg ON mg.id = u.grp
+            {USER_JOINS}
+        WHERE {USER_FILTER}
+        ORDER BY au.usrname, work.shortname, mg.name
+        "#
+    );
+
+    let rows = match con
+        .client()
+        .query(sql.as_str(), &[&ops.include_inactive, &org, &grp])
+    {
+        Ok(r) => r,
+        Err(e) => return Err(db_err("Error querying group members", e)),
+    };
+
+    info!("Found {} group membership(s)", rows.len());
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(GROUP_COLUMNS)?;
+
+    for row in rows {
+        let mut cells = user_cells(&row);
+
+        cells.push(Cell::from(row.get::<_, String>("source")));
+        cells.push(Cell::from(row.get::<_, String>("member_group")));
+
+        writer.write_row(&cells)?;
+    }
+
+    writer.finish()
+}
+
+/// A group's effective permissions as code => (depth, grantable).
+fn group_perms(
+    con: &mut DatabaseConnection,
+    group: &str,
+) -> Result<BTreeMap<String, (i32, bool)>, String> {
+    let grp = group_id(con, group)?;
+
+    let sql = format!(
+        r#"
+        WITH RECURSIVE {ANCESTRY_CTE}
+        SELECT pl.code,
+            MIN(pgpm.depth)::INT AS depth,
+            BOOL_OR(pgpm.grantable) AS grantable
+        FROM ancestry a
+            JOIN permission.grp_perm_map pgpm ON pgpm.grp = a.ancestor
+            JOIN permission.perm_list pl ON pl.id = pgpm.perm
+        WHERE a.grp = $1
+        GROUP BY pl.code
+        "#
+    );
+
+    let rows = match con.client().query(sql.as_str(), &[&grp]) {
+        Ok(r) => r,
+        Err(e) => return Err(db_err("Error querying group permissions", e)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("code"), (row.get("depth"), row.get("grantable"))))
+        .collect())
+}
+
+/// Compare the effective permissions of two groups.
+fn diff_groups(con: &mut DatabaseConnection, ops: &AuditOptions) -> Result<(), String> {
+    let (left_name, right_name) = (&ops.diff[0], &ops.diff[1]);
+
+    let left = group_perms(con, left_name)?;
+    let right = group_perms(con, right_name)?;
+
+    info!(
+        "{left_name} holds {} permission(s); {right_name} holds {}",
+        left.len(),
+        right.len()
+    );
+
+    let mut codes: Vec<&String> = left.keys().chain(right.keys()).collect();
+    codes.sort();
+    codes.dedup();
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(&[
+        "perm",
+        "difference",
+        "left_depth",
+        "left_grantable",
+        "right_depth",
+        "right_grantable",
+    ])?;
+
+    let mut differences = 0;
+
+    for code in codes {
+        let l = left.get(code);
+        let r = right.get(code);
+
+        let difference = match (l, r) {
+            (Some(_), None) => "left_only",
+            (None, Some(_)) => "right_only",
+            (Some(l), Some(r)) if l.0 != r.0 => "depth",
+            (Some(l), Some(r)) if l.1 != r.1 => "grantable",
+            _ => "same",
+        };
+
+        if difference == "same" {
+            if !ops.all {
+                continue;
+            }
+        } else {
+            differences += 1;
+        }
+
+        writer.write_row(&[
+            Cell::from(code),
+            Cell::from(difference),
+            Cell::from(l.map(|p| p.0)),
+            Cell::from(l.map(|p| p.1)),
+            Cell::from(r.map(|p| p.0)),
+            Cell::from(r.map(|p| p.1)),
+        ])?;
+    }
+
+    info!("Found {differences} difference(s)");
+
+    writer.finish()
+}
+
+fn audit(con: &mut DatabaseConnection, ops: &AuditOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let org = match ops.org_unit.as_deref() {
+        Some(s) => Some(org_id(con, s)?),
+        None => None,
+    };
+
+    if !ops.perms.is_empty() {
+        audit_perms(con, ops, org)?;
+    } else if let Some(ref group) = ops.group {
+        audit_group(con, ops, org, group)?;
+    } else {
+        diff_groups(con, ops)?;
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit(&mut connection, &options)
+}
@@ -0,0 +1,210 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::reports::{self, Report};
+use crate::tabular::{Format, TableWriter};
+use log::info;
+use std::collections::HashMap;
+use std::process;
+
+struct ReportOptions {
+    command: String,
+    name: Option<String>,
+    values: HashMap<String, String>,
+    out_file: Option<String>,
+}
+
+fn read_options() -> (ReportOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "param", "Report Parameter", "NAME=VALUE");
+    opts.optopt("", "out-file", "Output File", "FILE");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let mut free = params.free.iter();
+
+    let command = match free.next() {
+        Some(c) => c.to_string(),
+        None => {
+            eprintln!("A command is required: list, show, run or check");
+            process::exit(2);
+        }
+    };
+
+    let name = free.next().map(|n| n.to_string());
+
+    if matches!(command.as_str(), "show" | "run") && name.is_none() {
+        eprintln!("{command} requires a report name");
+        process::exit(2);
+    }
+
+    let mut values = HashMap::new();
+    for param in params.opt_strs("param") {
+        match param.split_once('=') {
+            Some((k, v)) => values.insert(k.trim().to_string(), v.trim().to_string()),
+            None => {
+                eprintln!("Invalid --param, expected NAME=VALUE: {param}");
+                process::exit(2);
+            }
+        };
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ReportOptions {
+            command,
+            name,
+            values,
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin report -- list
+    cargo run --bin report -- show items-no-circs
+    cargo run --bin report -- run items-no-circs --param org=BR1 --param since=2024-01-01 \
+        --out-file weeding.xlsx
+    cargo run --bin report -- check
+
+Runs canned operational reports to CSV, TSV or a spreadsheet.
+
+Commands
+
+    list
+        List the reports.
+
+    show NAME
+        Describe a report and its parameters.
+
+    run NAME
+        Run a report.
+
+    check
+        Plan every report with its default parameters without
+        reading rows, e.g. against a load-fixtures scratch database
+        or after an Evergreen upgrade, and report any that fail.
+
+Options
+
+    --param
+        Report parameter as NAME=VALUE.  Repeatable.  Parameters not
+        given use the report's default, or are not applied.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn find(name: &str) -> Result<&'static Report, String> {
+    match reports::find(name) {
+        Some(r) => Ok(r),
+        None => Err(format!("No such report: {name}")),
+    }
+}
+
+fn show(report: &Report) {
+    println!("{}\n\n    {}\n", report.name, report.description);
+
+    if report.params.is_empty() {
+        println!("No parameters");
+        return;
+    }
+
+    println!("Parameters\n");
+    for param in report.params {
+        println!("    {}\n        {}", param.name, param.description);
+        if let Some(d) = param.default {
+            println!("        Defaults to {d}");
+        }
+        println!();
+    }
+}
+
+fn run(con: &mut DatabaseConnection, ops: &ReportOptions, report: &Report) -> Result<(), String> {
+    con.connect()?;
+
+    let (columns, rows) = report.run(con.client(), &ops.values)?;
+
+    con.disconnect();
+
+    let labels: Vec<&str> = columns.iter().map(|c| c.as_str()).collect();
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(&labels)?;
+
+    for row in &rows {
+        writer.write_row(row)?;
+    }
+
+    writer.finish()?;
+
+    info!("Report {} returned {} rows", report.name, rows.len());
+
+    Ok(())
+}
+
+fn check(con: &mut DatabaseConnection) -> Result<(), String> {
+    con.connect()?;
+
+    let mut failed = 0;
+
+    for report in reports::REPORTS {
+        match report.check(con.client()) {
+            Ok(()) => println!("ok      {}", report.name),
+            Err(e) => {
+                println!("FAILED  {}: {e}", report.name);
+                failed += 1;
+            }
+        }
+    }
+
+    con.disconnect();
+
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{n} of {} reports failed", reports::REPORTS.len())),
+    }
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let name = options.name.as_deref().unwrap_or("");
+
+    match options.command.as_str() {
+        "list" => {
+            for report in reports::REPORTS {
+                println!("{:<24}{}", report.name, report.description);
+            }
+            Ok(())
+        }
+        "show" => {
+            show(find(name)?);
+            Ok(())
+        }
+        "run" => run(&mut connection, &options, find(name)?),
+        "check" => check(&mut connection),
+        other => Err(format!("Unknown command: {other}")),
+    }
+}
@@ -0,0 +1,924 @@
+use crate::audit;
+use crate::cli;
+use crate::daemon;
+use crate::date::Timestamp;
+use crate::db::DatabaseConnection;
+use crate::email::{self, Email, Mailer, SmtpConfig};
+use crate::signals::{self, Shutdown};
+use crate::tabular::{Cell, Format, TableWriter};
+use crate::template::Renderer;
+use crate::xml;
+use log::{debug, error, info};
+use postgres as pg;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How often a running report checks for shutdown.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct RunnerOptions {
+    output_dir: String,
+    concurrency: usize,
+    timeout: u64,
+    daemon: bool,
+    sleep: u64,
+    base_url: Option<String>,
+    sendmail: String,
+    email_from: String,
+    email_template: Option<String>,
+    smtp: Option<SmtpConfig>,
+}
+
+/// A claimed reporter.schedule entry plus its report and template.
+struct Job {
+    schedule_id: i32,
+    report_id: i32,
+    template_id: i32,
+    name: String,
+    email: Option<String>,
+    csv: bool,
+    html: bool,
+    excel: bool,
+    report_data: Value,
+    template_data: Value,
+}
+
+/// How schedule owners are told a report finished.
+struct Notifier {
+    template: Option<Renderer>,
+    /// Sendmail is used without an SMTP relay.
+    mailer: Option<Mailer>,
+}
+
+/// Query results as text, with column labels from the template.
+struct ReportData {
+    labels: Vec<String>,
+    rows: Vec<Vec<Option<String>>>,
+}
+
+fn read_options() -> (RunnerOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "output-dir", "Report Output Directory", "DIR");
+    opts.optopt("", "concurrency", "Reports to Run at Once", "COUNT");
+    opts.optopt("", "timeout", "Query Timeout Seconds", "SECONDS");
+    opts.optflag("", "daemon", "Keep Running and Poll for Reports");
+    opts.optopt("", "sleep", "Seconds Between Polls", "SECONDS");
+    opts.optopt("", "base-url", "URL of the Output Directory", "URL");
+    opts.optopt("", "sendmail", "Sendmail Command", "PATH");
+    opts.optopt("", "email-from", "Notification Sender", "ADDRESS");
+    opts.optopt("", "email-template", "Notification Body Template", "FILE");
+
+    email::append_options(&mut opts);
+    daemon::append_options(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let smtp = match email::config_from_options(&params) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        RunnerOptions {
+            output_dir: cli::required(&params, "output-dir"),
+            concurrency: params.opt_get_default("concurrency", 2).unwrap(),
+            timeout: params.opt_get_default("timeout", 3600).unwrap(),
+            daemon: params.opt_present("daemon"),
+            sleep: params.opt_get_default("sleep", 10).unwrap(),
+            base_url: params.opt_str("base-url"),
+            sendmail: params
+                .opt_get_default("sendmail", "/usr/sbin/sendmail".to_string())
+                .unwrap(),
+            email_from: params
+                .opt_get_default("email-from", "evergreen@localhost".to_string())
+                .unwrap(),
+            email_template: params.opt_str("email-template"),
+            smtp,
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin report-runner -- --output-dir /openils/var/web/reporter \
+        --base-url https://example.org/reporter --daemon
+
+Runs pending reporter.schedule entries whose run time has arrived.
+
+The SQL for each report is built from its template and report
+parameters.  Output files are written to
+OUTPUT_DIR/TEMPLATE_ID/REPORT_ID/SCHEDULE_ID/report-data.{{csv,html,xlsx}}
+according to the formats requested by the schedule.
+
+Schedules are marked started, then complete or failed with the error
+text.  Recurring reports are rescheduled after each run, and the
+schedule's email address is notified via sendmail when set.
+
+On SIGINT or SIGTERM, running report queries are cancelled and their
+schedules returned to the queue for the next run.  Reports whose
+queries have finished are written and completed first.
+
+Options
+
+    --output-dir
+        Directory where report output is written.
+
+    --concurrency
+        Number of reports to run at once.  Defaults to 2.
+
+    --timeout
+        Cancel report queries running longer than this many seconds.
+        Defaults to 3600.
+
+    --daemon
+        Keep running, checking for new reports every --sleep seconds.
+        Otherwise, exit once no pending reports remain.
+
+    --sleep
+        Seconds between checks in --daemon mode.  Defaults to 10.
+
+    --base-url
+        URL of --output-dir, used for links in notification emails.
+
+    --sendmail
+        Command used to send notifications without --smtp-host.
+        Defaults to /usr/sbin/sendmail.
+
+    --email-from
+        Notification sender address.
+
+    --email-template
+        Tera template file for the notification body.  Values are
+        report.id, report.name, report.template_id, schedule_id,
+        link, error (unset on success) and finish_time, e.g.
+        {{{{ finish_time | date(format="%B %e, %Y %H:%M") }}}}.
+
+    --smtp-host
+        Send notifications through this SMTP relay instead of
+        --sendmail.
+
+    --smtp-port
+        Defaults to 25, 587 or 465 for each --smtp-security mode.
+
+    --smtp-security
+        none, starttls or tls.  Defaults to starttls.
+
+    --smtp-user
+    --smtp-password
+        SMTP login.  The password may instead be set in the
+        EGUTIL_SMTP_PASSWORD environment variable.
+
+    --smtp-retries
+        Retries for temporary delivery failures.  Defaults to 3.
+
+    --smtp-batch-size
+    --smtp-batch-pause
+        Pause --smtp-batch-pause seconds after every
+        --smtp-batch-size messages.
+
+    --audit-file
+        Append a JSON record of this run, its options and its report
+        counts to this file.  A --daemon run is recorded as it stops.
+
+    --audit-db
+        Record this run, its options and its report counts in
+        egutil.audit_log.
+
+    --detach
+        Run in the background, usually with --daemon.  Log to
+        --log-file or syslog, since stderr is closed.
+
+    --pidfile
+        Write the process ID to this file, and refuse to start
+        while the process it names is running.  SIGHUP restarts
+        in place and SIGTERM stops the runner.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+// --- SQL generation ---
+
+/// Double-quote an identifier, refusing anything unusual.
+fn ident(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match valid {
+        true => Ok(format!("\"{s}\"")),
+        false => Err(format!("Invalid identifier in template: {s}")),
+    }
+}
+
+/// Schema-qualified table name, each part quoted.
+fn table_name(s: &str) -> Result<String, String> {
+    let parts: Result<Vec<String>, String> = s.split('.').map(ident).collect();
+    Ok(parts?.join("."))
+}
+
+fn literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn value_literal(v: &Value) -> Result<String, String> {
+    match v {
+        Value::Null => Ok("NULL".to_string()),
+        Value::Bool(b) => Ok(b.to_string().to_uppercase()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::String(s) => Ok(literal(s)),
+        _ => Err(format!("Unsupported parameter value: {v}")),
+    }
+}
+
+/// Builds a report query from template and report data, following the
+/// conventions of the Clark reporter's SQL builder.
+struct SqlBuilder<'a> {
+    params: &'a Value,
+}
+
+impl SqlBuilder<'_> {
+    /// Replace "::PARAM" placeholders with the report's values.
+    fn resolve<'v>(&'v self, v: &'v Value) -> Result<&'v Value, String> {
+        if let Some(s) = v.as_str() {
+            if let Some(name) = s.strip_prefix("::") {
+                return match self.params.get(name) {
+                    Some(p) => Ok(p),
+                    None => Err(format!("Report has no value for parameter {name}")),
+                };
+            }
+        }
+        Ok(v)
+    }
+
+    fn is_aggregate(col: &Value) -> bool {
+        matches!(
+            col["column"]["transform"].as_str().unwrap_or("Bare"),
+            "count" | "count_distinct" | "sum" | "average" | "min" | "max" | "first" | "last"
+        )
+    }
+
+    fn column(&self, col: &Value) -> Result<String, String> {
+        let relation = ident(col["relation"].as_str().unwrap_or(""))?;
+        let colname = ident(col["column"]["colname"].as_str().unwrap_or(""))?;
+        let c = format!("{relation}.{colname}");
+
+        let expr = match col["column"]["transform"].as_str().unwrap_or("Bare") {
+            "Bare" => c,
+            "count" => format!("COUNT({c})"),
+            "count_distinct" => format!("COUNT(DISTINCT {c})"),
+            "sum" => format!("SUM({c})"),
+            "average" => format!("AVG({c})"),
+            "min" => format!("MIN({c})"),
+            "max" => format!("MAX({c})"),
+            "first" => format!("FIRST({c})"),
+            "last" => format!("LAST({c})"),
+            "lower" => format!("LOWER({c})"),
+            "upper" => format!("UPPER({c})"),
+            "first5" => format!("SUBSTRING({c}, 1, 5)"),
+            "first_word" => format!("SPLIT_PART({c}, ' ', 1)"),
+            "date" => format!("DATE({c})"),
+            "age" => format!("AGE(NOW(), {c}::TIMESTAMPTZ)"),
+            "day_name" => format!("TO_CHAR({c}, 'Day')"),
+            "month_name" => format!("TO_CHAR({c}, 'Month')"),
+            "dow" => format!("EXTRACT(DOW FROM {c})"),
+            "dom" => format!("EXTRACT(DAY FROM {c})"),
+            "doy" => format!("EXTRACT(DOY FROM {c})"),
+            "woy" => format!("EXTRACT(WEEK FROM {c})"),
+            "moy" => format!("EXTRACT(MONTH FROM {c})"),
+            "qoy" => format!("EXTRACT(QUARTER FROM {c})"),
+            "hod" => format!("EXTRACT(HOUR FROM {c})"),
+            "year_trunc" => format!("EXTRACT(YEAR FROM {c})"),
+            "month_trunc" => format!("TO_CHAR({c}, 'YYYY-MM')"),
+            "date_trunc" => format!("TO_CHAR({c}, 'YYYY-MM-DD')"),
+            "hour_trunc" => format!("TO_CHAR({c}, 'YYYY-MM-DD HH24')"),
+            "quarter" => format!("TO_CHAR({c}, 'YYYY-\"Q\"Q')"),
+            "months_ago" => format!(
+                "(EXTRACT(YEAR FROM AGE(NOW(), {c})) * 12 + EXTRACT(MONTH FROM AGE(NOW(), {c})))"
+            ),
+            t => return Err(format!("Unsupported column transform: {t}")),
+        };
+
+        Ok(expr)
+    }
+
+    fn condition(&self, cond: &Value) -> Result<String, String> {
+        let col = self.column(cond)?;
+
+        let (op, value) = match cond["condition"].as_object().and_then(|o| o.iter().next()) {
+            Some((op, v)) => (op.to_lowercase(), self.resolve(v)?),
+            None => return Err("Filter has no condition".to_string()),
+        };
+
+        let list = |v: &Value| -> Result<String, String> {
+            let items = match v.as_array() {
+                Some(a) => a,
+                None => return value_literal(v),
+            };
+            let lits: Result<Vec<String>, String> = items.iter().map(value_literal).collect();
+            Ok(lits?.join(", "))
+        };
+
+        let sql = match op.as_str() {
+            "=" | "!=" | "<>" | ">" | "<" | ">=" | "<=" | "like" | "ilike" => {
+                format!("{col} {} {}", op.to_uppercase(), value_literal(value)?)
+            }
+            "in" | "not in" => format!("{col} {} ({})", op.to_uppercase(), list(value)?),
+            "between" | "not between" => match value.as_array() {
+                Some(a) if a.len() == 2 => format!(
+                    "{col} {} {} AND {}",
+                    op.to_uppercase(),
+                    value_literal(&a[0])?,
+                    value_literal(&a[1])?
+                ),
+                _ => return Err(format!("{op} requires two values")),
+            },
+            "is" => format!("{col} IS NULL"),
+            "is not" => format!("{col} IS NOT NULL"),
+            _ => return Err(format!("Unsupported filter operator: {op}")),
+        };
+
+        Ok(sql)
+    }
+
+    fn joins(&self, node: &Value, sql: &mut String) -> Result<(), String> {
+        let alias = ident(node["alias"].as_str().unwrap_or(""))?;
+
+        let joins = match node["join"].as_object() {
+            Some(j) => j,
+            None => return Ok(()),
+        };
+
+        for (key, join) in joins {
+            // Join keys take the form LEFT_COLUMN-CLASS-RELTYPE.
+            let left_col = ident(key.split('-').next().unwrap_or(""))?;
+            let right_col = ident(join["key"].as_str().unwrap_or(""))?;
+            let table = table_name(join["table"].as_str().unwrap_or(""))?;
+            let join_alias = ident(join["alias"].as_str().unwrap_or(""))?;
+
+            let kind = match join["type"].as_str().unwrap_or("inner") {
+                "left" => "LEFT",
+                "right" => "RIGHT",
+                _ => "INNER",
+            };
+
+            *sql += &format!(
+                "\n    {kind} JOIN {table} AS {join_alias} ON ({alias}.{left_col} = {join_alias}.{right_col})"
+            );
+
+            self.joins(join, sql)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the SQL and the column labels.
+    fn build(&self, template: &Value) -> Result<(String, Vec<String>), String> {
+        let select = match template["select"].as_array() {
+            Some(s) if !s.is_empty() => s,
+            _ => return Err("Template selects no columns".to_string()),
+        };
+
+        let mut columns = Vec::new();
+        let mut labels = Vec::new();
+        let mut group_by = Vec::new();
+        let has_aggregate = select.iter().any(Self::is_aggregate);
+
+        for (idx, col) in select.iter().enumerate() {
+            columns.push(self.column(col)?);
+
+            let label = col["alias"]
+                .as_str()
+                .or_else(|| col["column"]["colname"].as_str())
+                .unwrap_or("");
+            labels.push(label.to_string());
+
+            if has_aggregate && !Self::is_aggregate(col) {
+                group_by.push((idx + 1).to_string());
+            }
+        }
+
+        let from = &template["from"];
+        let mut sql = format!(
+            "SELECT {}\nFROM {} AS {}",
+            columns.join(", "),
+            table_name(from["table"].as_str().unwrap_or(""))?,
+            ident(from["alias"].as_str().unwrap_or(""))?
+        );
+
+        self.joins(from, &mut sql)?;
+
+        let clause = |key: &str| -> Result<Vec<String>, String> {
+            match template[key].as_array() {
+                Some(conds) => conds.iter().map(|c| self.condition(c)).collect(),
+                None => Ok(Vec::new()),
+            }
+        };
+
+        let wheres = clause("where")?;
+        if !wheres.is_empty() {
+            sql += &format!("\nWHERE {}", wheres.join("\n    AND "));
+        }
+
+        if !group_by.is_empty() {
+            sql += &format!("\nGROUP BY {}", group_by.join(", "));
+        }
+
+        let havings = clause("having")?;
+        if !havings.is_empty() {
+            sql += &format!("\nHAVING {}", havings.join("\n    AND "));
+        }
+
+        let order: Vec<String> = (1..=select.len()).map(|i| i.to_string()).collect();
+        sql += &format!("\nORDER BY {}", order.join(", "));
+
+        Ok((sql, labels))
+    }
+}
+
+// --- Output ---
+
+/// Write report data as CSV or a spreadsheet.  Query values arrive
+/// as text, so numeric values are detected to keep spreadsheet math
+/// working.
+fn write_table(path: &Path, format: Format, data: &ReportData) -> Result<(), String> {
+    let mut writer = TableWriter::create(&path.to_string_lossy(), format)?;
+
+    let labels: Vec<&str> = data.labels.iter().map(|l| l.as_str()).collect();
+    writer.write_header(&labels)?;
+
+    for row in &data.rows {
+        let cells: Vec<Cell> = row
+            .iter()
+            .map(|v| match v {
+                Some(v) if format == Format::Xlsx => Cell::infer(v),
+                Some(v) => Cell::from(v),
+                None => Cell::Empty,
+            })
+            .collect();
+        writer.write_row(&cells)?;
+    }
+
+    writer.finish()
+}
+
+fn write_html(path: &Path, name: &str, data: &ReportData) -> Result<(), String> {
+    let title = xml::escape(name);
+
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\"/>\n<title>{title}</title>\n</head>\n<body>\n<h2>{title}</h2>\n<table border=\"1\">\n<tr>"
+    );
+
+    for label in &data.labels {
+        out += &format!("<th>{}</th>", xml::escape(label));
+    }
+    out += "</tr>\n";
+
+    for row in &data.rows {
+        out += "<tr>";
+        for value in row {
+            out += &format!("<td>{}</td>", xml::escape(value.as_deref().unwrap_or("")));
+        }
+        out += "</tr>\n";
+    }
+
+    out += "</table>\n</body>\n</html>\n";
+
+    match fs::write(path, out) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot write {}: {e}", path.display())),
+    }
+}
+
+// --- Scheduling ---
+
+/// Claim the next due schedule entry so no other runner picks it up.
+fn claim_next(con: &mut DatabaseConnection) -> Result<Option<Job>, String> {
+    let sql = r#"
+        UPDATE reporter.schedule SET start_time = NOW()
+        WHERE id = (
+            SELECT id FROM reporter.schedule
+            WHERE start_time IS NULL AND run_time <= NOW()
+            ORDER BY run_time, id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id
+    "#;
+
+    let schedule_id: i32 = match con.client().query_opt(sql, &[]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(db_err("Error claiming report", e)),
+    };
+
+    let sql = r#"
+        SELECT
+            s.email, s.csv_format, s.html_format, s.excel_format,
+            r.id AS report_id, r.name, r.data AS report_data,
+            t.id AS template_id, t.data AS template_data
+        FROM reporter.schedule s
+        JOIN reporter.report r ON r.id = s.report
+        JOIN reporter.template t ON t.id = r.template
+        WHERE s.id = $1
+    "#;
+
+    let row = match con.client().query_one(sql, &[&schedule_id]) {
+        Ok(r) => r,
+        Err(e) => return Err(db_err("Error loading report", e)),
+    };
+
+    let parse = |col: &str| -> Value {
+        let text: Option<String> = row.get(col);
+        text.and_then(|t| serde_json::from_str(&t).ok())
+            .unwrap_or(Value::Null)
+    };
+
+    Ok(Some(Job {
+        schedule_id,
+        report_id: row.get("report_id"),
+        template_id: row.get("template_id"),
+        name: row.get("name"),
+        email: row.get("email"),
+        csv: row.get::<&str, Option<bool>>("csv_format").unwrap_or(true),
+        html: row.get::<&str, Option<bool>>("html_format").unwrap_or(true),
+        excel: row
+            .get::<&str, Option<bool>>("excel_format")
+            .unwrap_or(false),
+        report_data: parse("report_data"),
+        template_data: parse("template_data"),
+    }))
+}
+
+fn query_report(
+    con: &mut DatabaseConnection,
+    ops: &RunnerOptions,
+    job: &Job,
+) -> Result<ReportData, String> {
+    let builder = SqlBuilder {
+        params: &job.report_data,
+    };
+
+    let (sql, labels) = builder.build(&job.template_data)?;
+
+    debug!("Report {} SQL:\n{sql}", job.schedule_id);
+
+    let timeout = format!("SET statement_timeout = '{}s'", ops.timeout);
+    if let Err(e) = con.client().batch_execute(&timeout) {
+        return Err(db_err("Cannot set statement timeout", e));
+    }
+
+    // The simple query protocol returns every value as text.
+    let result = con.client().simple_query(&sql);
+
+    con.client().batch_execute("RESET statement_timeout").ok();
+
+    let messages = match result {
+        Ok(m) => m,
+        Err(e) => return Err(db_err("Report query failed", e)),
+    };
+
+    let mut rows = Vec::new();
+
+    for msg in messages {
+        if let pg::SimpleQueryMessage::Row(row) = msg {
+            let values: Vec<Option<String>> = (0..row.len())
+                .map(|i| row.get(i).map(|v| v.to_string()))
+                .collect();
+            rows.push(values);
+        }
+    }
+
+    Ok(ReportData { labels, rows })
+}
+
+fn output_dir(ops: &RunnerOptions, job: &Job) -> PathBuf {
+    Path::new(&ops.output_dir)
+        .join(job.template_id.to_string())
+        .join(job.report_id.to_string())
+        .join(job.schedule_id.to_string())
+}
+
+fn run_job(con: &mut DatabaseConnection, ops: &RunnerOptions, job: &Job) -> Result<(), String> {
+    let data = query_report(con, ops, job)?;
+
+    let dir = output_dir(ops, job);
+    if let Err(e) = fs::create_dir_all(&dir) {
+        return Err(format!("Cannot create {}: {e}", dir.display()));
+    }
+
+    if job.csv {
+        write_table(&dir.join("report-data.csv"), Format::Csv, &data)?;
+    }
+
+    if job.html {
+        write_html(&dir.join("report-data.html"), &job.name, &data)?;
+    }
+
+    if job.excel {
+        write_table(&dir.join("report-data.xlsx"), Format::Xlsx, &data)?;
+    }
+
+    info!(
+        "Report {} '{}' complete with {} rows",
+        job.schedule_id,
+        job.name,
+        data.rows.len()
+    );
+
+    Ok(())
+}
+
+fn notify(
+    ops: &RunnerOptions,
+    job: &Job,
+    error: Option<&str>,
+    notifier: &Notifier,
+) -> Result<(), String> {
+    let to = match job.email.as_deref() {
+        Some(e) if !e.trim().is_empty() => e.trim(),
+        _ => return Ok(()),
+    };
+
+    let link = match ops.base_url {
+        Some(ref url) => format!(
+            "{}/{}/{}/{}/",
+            url.trim_end_matches('/'),
+            job.template_id,
+            job.report_id,
+            job.schedule_id
+        ),
+        None => output_dir(ops, job).display().to_string(),
+    };
+
+    let body = match (&notifier.template, error) {
+        (Some(renderer), _) => {
+            let data = json!({
+                "report": {
+                    "id": job.report_id,
+                    "name": job.name,
+                    "template_id": job.template_id,
+                },
+                "schedule_id": job.schedule_id,
+                "link": link,
+                "error": error,
+                "finish_time": Timestamp::now().to_iso8601(),
+            });
+            renderer.render("email", &data)?
+        }
+        (None, Some(e)) => format!("Report '{}' failed:\n\n{e}\n", job.name),
+        (None, None) => format!("Report '{}' is ready:\n\n{link}\n", job.name),
+    };
+
+    let subject = format!(
+        "Report {}: {}",
+        if error.is_some() {
+            "failed"
+        } else {
+            "complete"
+        },
+        job.name
+    );
+
+    if let Some(mailer) = &notifier.mailer {
+        let email = Email {
+            from: ops.email_from.to_string(),
+            to: to.split(',').map(|a| a.trim().to_string()).collect(),
+            reply_to: None,
+            subject,
+            body,
+        };
+        return mailer.send(&email);
+    }
+
+    let message = format!(
+        "From: {}\nTo: {to}\nSubject: {subject}\n\n{body}",
+        ops.email_from
+    );
+
+    let mut child = match Command::new(&ops.sendmail)
+        .arg("-t")
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Cannot run {}: {e}", ops.sendmail)),
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(message.as_bytes()) {
+            return Err(format!("Error sending notification: {e}"));
+        }
+    }
+
+    match child.wait() {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(format!("{} exited with {s}", ops.sendmail)),
+        Err(e) => Err(format!("Error sending notification: {e}")),
+    }
+}
+
+fn finish_job(con: &mut DatabaseConnection, job: &Job, error: Option<&str>) -> Result<(), String> {
+    let sql = r#"
+        UPDATE reporter.schedule SET
+            complete_time = CASE WHEN $2::TEXT IS NULL THEN NOW() ELSE NULL END,
+            error_code = CASE WHEN $2::TEXT IS NULL THEN NULL ELSE 1 END,
+            error_text = $2
+        WHERE id = $1
+    "#;
+
+    if let Err(e) = con.client().execute(sql, &[&job.schedule_id, &error]) {
+        return Err(db_err("Error marking report complete", e));
+    }
+
+    let sql = r#"
+        INSERT INTO reporter.schedule (
+            report, folder, runner, run_time, email,
+            csv_format, html_format, excel_format,
+            chart_pie, chart_bar, chart_line
+        )
+        SELECT
+            s.report, s.folder, s.runner, s.run_time + r.recurrence, s.email,
+            s.csv_format, s.html_format, s.excel_format,
+            s.chart_pie, s.chart_bar, s.chart_line
+        FROM reporter.schedule s
+        JOIN reporter.report r ON r.id = s.report
+        WHERE s.id = $1 AND r.recur AND r.recurrence IS NOT NULL
+    "#;
+
+    if let Err(e) = con.client().execute(sql, &[&job.schedule_id]) {
+        return Err(db_err("Error rescheduling report", e));
+    }
+
+    Ok(())
+}
+
+/// Put a claimed schedule entry back, so the next run picks it up.
+fn release_job(con: &mut DatabaseConnection, job: &Job) -> Result<(), String> {
+    let sql = "UPDATE reporter.schedule SET start_time = NULL WHERE id = $1";
+
+    match con.client().execute(sql, &[&job.schedule_id]) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(db_err("Error releasing report", e)),
+    }
+}
+
+/// Run the job, cancelling its query if shutdown is requested.
+fn run_cancellable(
+    con: &mut DatabaseConnection,
+    ops: &RunnerOptions,
+    job: &Job,
+    shutdown: &Shutdown,
+) -> Result<(), String> {
+    let token = con.client().cancel_token();
+    let done = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                if !shutdown.sleep(CANCEL_POLL_INTERVAL) {
+                    // A cancel arriving between queries is ignored.
+                    if let Err(e) = token.cancel_query(pg::NoTls) {
+                        error!("Cannot cancel report {}: {e}", job.schedule_id);
+                    }
+                    return;
+                }
+            }
+        });
+
+        let result = run_job(con, ops, job);
+        done.store(true, Ordering::Relaxed);
+        result
+    })
+}
+
+fn worker(
+    mut con: DatabaseConnection,
+    ops: &RunnerOptions,
+    notifier: &Notifier,
+    shutdown: &Shutdown,
+) -> Result<(), String> {
+    con.connect()?;
+
+    while !shutdown.requested() {
+        let job = match claim_next(&mut con)? {
+            Some(j) => j,
+            None if ops.daemon => {
+                shutdown.sleep(Duration::from_secs(ops.sleep));
+                continue;
+            }
+            None => break,
+        };
+
+        info!("Running report {} '{}'", job.schedule_id, job.name);
+
+        let result = run_cancellable(&mut con, ops, &job, shutdown);
+
+        if result.is_err() && shutdown.requested() {
+            info!(
+                "Report {} interrupted; returning it to the queue",
+                job.schedule_id
+            );
+            release_job(&mut con, &job)?;
+            audit::count("released", 1);
+            break;
+        }
+
+        let error = result.err();
+
+        if let Some(ref e) = error {
+            error!("Report {} failed: {e}", job.schedule_id);
+        }
+
+        finish_job(&mut con, &job, error.as_deref())?;
+
+        match error {
+            Some(_) => audit::count("failed", 1),
+            None => audit::count("completed", 1),
+        }
+
+        if let Err(e) = notify(ops, &job, error.as_deref(), notifier) {
+            error!("Report {}: {e}", job.schedule_id);
+        }
+    }
+
+    con.disconnect();
+
+    Ok(())
+}
+
+fn run(con: &mut DatabaseConnection, ops: &RunnerOptions) -> Result<(), String> {
+    let template = match &ops.email_template {
+        Some(path) => {
+            let mut renderer = Renderer::new();
+            renderer.add_template_file("email", path)?;
+            Some(renderer)
+        }
+        None => None,
+    };
+
+    let mailer = match &ops.smtp {
+        Some(config) => Some(Mailer::new(config.clone())?),
+        None => None,
+    };
+
+    let notifier = &Notifier { template, mailer };
+    let shutdown = &signals::install()?;
+
+    let results: Vec<Result<(), String>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..ops.concurrency.max(1))
+            .map(|_| {
+                let worker_con = con.partial_clone();
+                s.spawn(move || worker(worker_con, ops, notifier, shutdown))
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or(Err("Worker panicked".to_string())))
+            .collect()
+    });
+
+    if shutdown.requested() {
+        info!("Shutting down");
+    }
+
+    results.into_iter().collect()
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(run(&mut connection, &options))
+}
@@ -0,0 +1,167 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::snapshot;
+use log::{info, warn};
+use std::process;
+
+struct RollbackOptions {
+    run_id: Option<i64>,
+    snapshot_file: Option<String>,
+    list: bool,
+    dry_run: DryRun,
+}
+
+fn read_options() -> (RollbackOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "run-id", "Snapshot Run to Roll Back", "RUN_ID");
+    opts.optopt("", "snapshot-file", "Snapshot File to Roll Back", "FILE");
+    opts.optflag("", "list", "List Snapshot Runs");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let options = RollbackOptions {
+        run_id: params.opt_get("run-id").unwrap(),
+        snapshot_file: params.opt_str("snapshot-file"),
+        list: params.opt_present("list"),
+        dry_run: DryRun::from_params(&params),
+    };
+
+    let sources = [options.run_id.is_some(), options.snapshot_file.is_some()];
+    if !options.list && sources.iter().filter(|s| **s).count() != 1 {
+        eprintln!("One of --run-id, --snapshot-file or --list is required");
+        process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (options, connection)
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin rollback -- --list
+    cargo run --bin rollback -- --run-id 42 --dry-run
+    cargo run --bin rollback -- --snapshot-file marc-modify.snap
+
+Restores the rows saved by a tool run with --snapshot or
+--snapshot-file, undoing its changes.  Only the columns the tool
+changed are restored, in one transaction.  Rows saved more than once
+get their earliest image.  Rows changed again since the run are
+restored all the same, and rows deleted since are reported and
+skipped.
+
+A run in egutil.snapshot_run is marked rolled back, and cannot be
+rolled back again.
+
+Options
+
+    --run-id
+        Roll back this egutil.snapshot_run.
+
+    --snapshot-file
+        Roll back the rows saved to this file.
+
+    --list
+        List snapshot runs, with their row counts, and exit.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn list(con: &mut DatabaseConnection) -> Result<(), String> {
+    snapshot::create_tables(con.client())?;
+
+    println!("run,tool,create_time,rows,rollback_time");
+
+    for run in snapshot::runs(con.client())? {
+        println!(
+            "{},{},{},{},{}",
+            run.id,
+            run.tool,
+            run.create_time,
+            run.rows,
+            run.rollback_time.unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+fn rollback(con: &mut DatabaseConnection, ops: &RollbackOptions) -> Result<(), String> {
+    con.connect()?;
+
+    if ops.list {
+        list(con)?;
+        con.disconnect();
+        return Ok(());
+    }
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let images = match (ops.run_id, &ops.snapshot_file) {
+        (Some(run), _) => snapshot::read_run(&mut tx, run)?,
+        (None, Some(path)) => snapshot::read_file(path)?,
+        (None, None) => Vec::new(),
+    };
+
+    info!("Restoring {} rows", images.len());
+
+    let mut restored = 0;
+    let mut missing = 0;
+
+    for image in &images {
+        match snapshot::restore(&mut tx, image)? {
+            true => restored += 1,
+            false => {
+                warn!("{} row {} no longer exists", image.table, image.data["id"]);
+                missing += 1;
+            }
+        }
+    }
+
+    if let Some(run) = ops.run_id {
+        snapshot::mark_rolled_back(&mut tx, run)?;
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    con.disconnect();
+
+    audit::count("restored", restored);
+    audit::count("missing", missing);
+
+    info!(
+        "{} {restored} rows; {missing} no longer exist",
+        ops.dry_run.past("Restored")
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(rollback(&mut connection, &options))
+}
@@ -0,0 +1,584 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::http;
+use crate::tabular::{Cell, Format, TableWriter};
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use postgres::fallible_iterator::FallibleIterator;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process;
+
+const REPORTS: &[&str] = &["terms", "zero", "facets"];
+
+const MONTHS: &[&str] = &[
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+struct StatsOptions {
+    start_date: String,
+    end_date: String,
+    access_logs: Vec<String>,
+    query_file: Option<String>,
+    search_path: String,
+    report: String,
+    limit: usize,
+    json: bool,
+    out_file: Option<String>,
+}
+
+/// One search, from a log line or an audit row.
+struct Search {
+    terms: String,
+    qtype: String,
+    /// Result count, when the source records it.
+    hits: Option<i64>,
+    /// Facet and filter selections, as (facet, value).
+    facets: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+struct TermCounts {
+    searches: i64,
+    /// Searches with a known result count.
+    counted: i64,
+    hits: i64,
+    zero_hits: i64,
+}
+
+#[derive(Default)]
+struct Summary {
+    searches: usize,
+    skipped: usize,
+    /// (terms, qtype) counts.
+    terms: HashMap<(String, String), TermCounts>,
+    /// (facet, value) uses.
+    facets: HashMap<(String, String), i64>,
+}
+
+fn read_options() -> (StatsOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "start-date", "First Day, YYYY-MM-DD", "DATE");
+    opts.optopt("", "end-date", "Last Day, YYYY-MM-DD", "DATE");
+    opts.optmulti("", "access-log", "Web Server Access Log", "FILE");
+    opts.optopt(
+        "",
+        "query-file",
+        "Search Audit SQL Query File",
+        "QUERY_FILE",
+    );
+    opts.optopt("", "search-path", "Search Results URL Path", "PATH");
+    opts.optopt("", "report", "terms, zero or facets", "REPORT");
+    opts.optopt("", "limit", "Rows to Report", "COUNT");
+    opts.optflag("", "json", "Output JSON Instead of CSV");
+    opts.optopt("", "out-file", "Output File", "FILE");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let access_logs = params.opt_strs("access-log");
+    let query_file = params.opt_str("query-file");
+
+    if access_logs.is_empty() && query_file.is_none() {
+        eprintln!("--access-log or --query-file is required");
+        process::exit(2);
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        StatsOptions {
+            start_date: cli::required(&params, "start-date"),
+            end_date: cli::required(&params, "end-date"),
+            access_logs,
+            query_file,
+            search_path: params
+                .opt_get_default("search-path", "/eg/opac/results".to_string())
+                .unwrap(),
+            report: params
+                .opt_get_default("report", "terms".to_string())
+                .unwrap(),
+            limit: params.opt_get_default("limit", 100).unwrap(),
+            json: params.opt_present("json"),
+            out_file: params.opt_str("out-file"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin search-stats -- --start-date 2026-09-01 --end-date 2026-09-30 \
+        --access-log /var/log/apache2/access.log --access-log /var/log/apache2/access.log.1.gz
+    cargo run --bin search-stats -- --start-date 2026-09-01 --end-date 2026-09-30 \
+        --query-file search-audit.sql --report zero --json
+
+Summarizes catalog searches within a date range into top search
+terms, zero-hit terms and facet usage, for the web team.
+
+Searches are read from web server access logs in the common or
+combined format, optionally gzipped, and from a search audit table
+where one is enabled.
+
+Access logs
+
+    Requests for --search-path are searches.  The query parameter
+    holds the terms, qtype the search class, and facet and fi:
+    parameters the facets and filters selected.  Requests for later
+    result pages are skipped.  Logs do not record result counts, so
+    searches from logs never appear as zero-hit.
+
+Audit queries
+
+    --query-file holds an SQL query producing a search_time and a
+    query column, and optionally qtype, hits and facets columns.
+    facets holds one facet per line, e.g. subject|topic[Cats].  Rows
+    outside the date range are skipped.
+
+Terms are compared case-insensitively with whitespace collapsed.
+
+Reports
+
+    terms
+        The most searched terms, with search counts, average hits
+        and zero-hit counts.  This is the default.
+
+    zero
+        The most searched terms with no results.
+
+    facets
+        The most used facets and filters, with their values.
+
+Options
+
+    --start-date
+    --end-date
+        Inclusive date range, YYYY-MM-DD.  Required.
+
+    --access-log
+        Web server access log.  Repeatable.  Files ending in .gz are
+        decompressed.
+
+    --query-file
+        Path to a file containing an SQL search audit query.
+
+    --search-path
+        URL path of search results.  Defaults to /eg/opac/results.
+
+    --report
+        terms, zero or facets.  Defaults to terms.
+
+    --limit
+        Number of rows to report.  Defaults to 100.  0 reports all.
+
+    --json
+        Write a JSON array of objects instead of CSV.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+/// Lower case with whitespace collapsed.
+fn normalize_terms(terms: &str) -> String {
+    terms
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Split a facet like subject|topic[Cats] into its name and value.
+fn split_facet(facet: &str) -> (String, String) {
+    match facet
+        .trim()
+        .strip_suffix(']')
+        .and_then(|f| f.split_once('['))
+    {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (facet.trim().to_string(), String::new()),
+    }
+}
+
+/// YYYY-MM-DD of a log timestamp like 10/Oct/2026:13:55:36 -0700.
+fn log_date(stamp: &str) -> Option<String> {
+    let mut parts = stamp.splitn(3, '/');
+    let day = parts.next()?;
+    let month = parts.next()?;
+    let year = parts.next()?.get(..4)?;
+
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+
+    Some(format!("{year}-{month:02}-{day:0>2}"))
+}
+
+/// The search in an access log line, when it is one in the range.
+fn parse_log_line(ops: &StatsOptions, line: &str) -> Option<Search> {
+    let stamp = line.split_once('[')?.1.split_once(']')?.0;
+    let date = log_date(stamp)?;
+
+    if date < ops.start_date || date > ops.end_date {
+        return None;
+    }
+
+    // "GET /eg/opac/results?query=cats HTTP/1.1"
+    let request = line.split_once('"')?.1.split_once('"')?.0;
+    let target = request.split_whitespace().nth(1)?;
+    let (path, query) = target.split_once('?')?;
+
+    if path != ops.search_path {
+        return None;
+    }
+
+    let mut search = Search {
+        terms: String::new(),
+        qtype: String::new(),
+        hits: None,
+        facets: Vec::new(),
+    };
+
+    for (name, value) in http::parse_params(query) {
+        match name.as_str() {
+            "query" => search.terms = normalize_terms(&value),
+            "qtype" => search.qtype = value,
+            "page" if value != "0" && !value.is_empty() => return None,
+            "facet" => search.facets.push(split_facet(&value)),
+            _ if name.starts_with("fi:") && !value.is_empty() => {
+                search.facets.push((name, value));
+            }
+            _ => {}
+        }
+    }
+
+    Some(search)
+}
+
+fn open_log(path: &str) -> Result<Box<dyn BufRead>, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Cannot open {path}: {e}")),
+    };
+
+    let reader: Box<dyn Read> = match path.ends_with(".gz") {
+        true => Box::new(GzDecoder::new(file)),
+        false => Box::new(file),
+    };
+
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+fn add_search(summary: &mut Summary, search: Search) {
+    if search.terms.is_empty() && search.facets.is_empty() {
+        summary.skipped += 1;
+        return;
+    }
+
+    summary.searches += 1;
+
+    for facet in search.facets {
+        *summary.facets.entry(facet).or_default() += 1;
+    }
+
+    if search.terms.is_empty() {
+        return;
+    }
+
+    let counts = summary
+        .terms
+        .entry((search.terms, search.qtype))
+        .or_default();
+
+    counts.searches += 1;
+
+    if let Some(hits) = search.hits {
+        counts.counted += 1;
+        counts.hits += hits;
+        if hits == 0 {
+            counts.zero_hits += 1;
+        }
+    }
+}
+
+fn read_logs(ops: &StatsOptions, summary: &mut Summary) -> Result<(), String> {
+    for path in &ops.access_logs {
+        let reader = open_log(path)?;
+        let before = summary.searches;
+
+        for line in reader.lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => {
+                    // Usually a truncated log being rotated.
+                    warn!("Error reading {path}: {e}");
+                    break;
+                }
+            };
+
+            if let Some(search) = parse_log_line(ops, &line) {
+                add_search(summary, search);
+            }
+        }
+
+        info!("Read {} searches from {path}", summary.searches - before);
+    }
+
+    Ok(())
+}
+
+fn read_audit(
+    con: &mut DatabaseConnection,
+    ops: &StatsOptions,
+    summary: &mut Summary,
+) -> Result<(), String> {
+    let fname = match ops.query_file {
+        Some(ref f) => f,
+        None => return Ok(()),
+    };
+
+    let query = match fs::read_to_string(fname) {
+        Ok(q) => q,
+        Err(e) => return Err(format!("Cannot read {fname}: {e}")),
+    };
+
+    let sql = format!(
+        r#"
+        SELECT q.*
+        FROM ({query}) q
+        WHERE q.search_time >= $1::TEXT::DATE
+            AND q.search_time < $2::TEXT::DATE + 1
+        "#
+    );
+
+    con.connect()?;
+
+    let params: [&(dyn postgres::types::ToSql + Sync); 2] = [&ops.start_date, &ops.end_date];
+
+    let mut rows = match con.client().query_raw(sql.as_str(), params) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error running audit query: {e}")),
+    };
+
+    let before = summary.searches;
+
+    loop {
+        let row = match rows.next() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => return Err(format!("Error reading audit rows: {e}")),
+        };
+
+        let has = |name: &str| row.columns().iter().any(|c| c.name() == name);
+
+        let terms: Option<String> = row.get("query");
+        let qtype: Option<String> = match has("qtype") {
+            true => row.get("qtype"),
+            false => None,
+        };
+        let facets: Option<String> = match has("facets") {
+            true => row.get("facets"),
+            false => None,
+        };
+
+        let search = Search {
+            terms: normalize_terms(&terms.unwrap_or_default()),
+            qtype: qtype.unwrap_or_default(),
+            hits: match has("hits") {
+                true => row.get::<_, Option<i32>>("hits").map(|h| h as i64),
+                false => None,
+            },
+            facets: facets
+                .unwrap_or_default()
+                .lines()
+                .filter(|f| !f.trim().is_empty())
+                .map(split_facet)
+                .collect(),
+        };
+
+        add_search(summary, search);
+    }
+
+    con.disconnect();
+
+    info!(
+        "Read {} searches from the audit query",
+        summary.searches - before
+    );
+
+    Ok(())
+}
+
+/// Report labels and rows, most frequent first.
+fn report_rows(ops: &StatsOptions, summary: &Summary) -> (Vec<&'static str>, Vec<Vec<Cell>>) {
+    let limit = match ops.limit {
+        0 => usize::MAX,
+        n => n,
+    };
+
+    match ops.report.as_str() {
+        "facets" => {
+            let mut facets: Vec<(&(String, String), &i64)> = summary.facets.iter().collect();
+            facets.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+            let rows = facets
+                .into_iter()
+                .take(limit)
+                .map(|((facet, value), uses)| {
+                    vec![Cell::from(facet), Cell::from(value), Cell::from(*uses)]
+                })
+                .collect();
+
+            (vec!["facet", "value", "uses"], rows)
+        }
+        "zero" => {
+            let mut terms: Vec<(&(String, String), &TermCounts)> = summary
+                .terms
+                .iter()
+                .filter(|(_, c)| c.zero_hits > 0)
+                .collect();
+            terms.sort_by(|a, b| b.1.zero_hits.cmp(&a.1.zero_hits).then(a.0.cmp(b.0)));
+
+            let rows = terms
+                .into_iter()
+                .take(limit)
+                .map(|((terms, qtype), counts)| {
+                    vec![
+                        Cell::from(terms),
+                        Cell::from(qtype),
+                        Cell::from(counts.zero_hits),
+                        Cell::from(counts.searches),
+                    ]
+                })
+                .collect();
+
+            (
+                vec!["terms", "qtype", "zero_hit_searches", "searches"],
+                rows,
+            )
+        }
+        _ => {
+            let mut terms: Vec<(&(String, String), &TermCounts)> = summary.terms.iter().collect();
+            terms.sort_by(|a, b| b.1.searches.cmp(&a.1.searches).then(a.0.cmp(b.0)));
+
+            let rows = terms
+                .into_iter()
+                .take(limit)
+                .map(|((terms, qtype), counts)| {
+                    let avg = match counts.counted {
+                        0 => None,
+                        n => Some((counts.hits as f64 / n as f64 * 10.0).round() / 10.0),
+                    };
+
+                    vec![
+                        Cell::from(terms),
+                        Cell::from(qtype),
+                        Cell::from(counts.searches),
+                        Cell::from(avg),
+                        Cell::from(counts.zero_hits),
+                    ]
+                })
+                .collect();
+
+            (
+                vec!["terms", "qtype", "searches", "avg_hits", "zero_hits"],
+                rows,
+            )
+        }
+    }
+}
+
+fn cell_json(cell: &Cell) -> serde_json::Value {
+    match cell {
+        Cell::Empty => serde_json::Value::Null,
+        Cell::Integer(n) => json!(n),
+        Cell::Number(n) => json!(n),
+        Cell::Text(s) => json!(s),
+        _ => serde_json::Value::Null,
+    }
+}
+
+fn write_json(ops: &StatsOptions, labels: &[&str], rows: &[Vec<Cell>]) -> Result<(), String> {
+    let list: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let mut obj = json!({});
+            for (label, cell) in labels.iter().zip(row) {
+                obj[*label] = cell_json(cell);
+            }
+            obj
+        })
+        .collect();
+
+    let text =
+        serde_json::to_string_pretty(&serde_json::Value::Array(list)).unwrap_or_default() + "\n";
+
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(f) => match File::create(f) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {f}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    match writer.write_all(text.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing output: {e}")),
+    }
+}
+
+fn summarize(con: &mut DatabaseConnection, ops: &StatsOptions) -> Result<(), String> {
+    if !REPORTS.contains(&ops.report.as_str()) {
+        return Err(format!("Unknown report: {}", ops.report));
+    }
+
+    let mut summary = Summary::default();
+
+    read_logs(ops, &mut summary)?;
+    read_audit(con, ops, &mut summary)?;
+
+    info!(
+        "Counted {} searches for {} distinct terms; skipped {} without terms or facets",
+        summary.searches,
+        summary.terms.len(),
+        summary.skipped
+    );
+
+    let (labels, rows) = report_rows(ops, &summary);
+
+    if ops.json {
+        return write_json(ops, &labels, &rows);
+    }
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(&labels)?;
+
+    for row in &rows {
+        writer.write_row(row)?;
+    }
+
+    writer.finish()
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    summarize(&mut connection, &options)
+}
@@ -0,0 +1,409 @@
+use crate::audit;
+use crate::barcode::{CheckDigit, Generator};
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{debug, info};
+use postgres as pg;
+
+const REPORT_COLUMNS: &[&str] = &[
+    "item",
+    "subscription",
+    "record",
+    "holding_lib",
+    "issue",
+    "barcode",
+    "unit",
+    "result",
+];
+
+struct ItemOptions {
+    org_unit: Option<String>,
+    subscriptions: Vec<i32>,
+    receive_expected: bool,
+    prefix: String,
+    width: usize,
+    check: Option<CheckDigit>,
+    staff: i32,
+    report_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// An arrived issue or part at one distribution, lacking a unit.
+struct PendingItem {
+    id: i32,
+    subscription: i32,
+    record: i64,
+    holding_lib: String,
+    holding_lib_id: i32,
+    issue: String,
+    sort_key: String,
+    call_number: Option<i64>,
+    template: Option<i32>,
+}
+
+fn read_options() -> (ItemOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "org-unit", "Limit to Holding Org Unit", "SHORTNAME");
+    opts.optmulti("", "subscription", "Subscription IDs", "IDS");
+    opts.optflag("", "receive-expected", "Receive Published Expected Items");
+    opts.optopt("", "prefix", "Generated Barcode Prefix", "PREFIX");
+    opts.optopt("", "width", "Sequence Width", "WIDTH");
+    opts.optopt("", "check", "Check Digit: mod10, mod43, codabar", "CHECK");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "report-file", "Report Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let check = params
+        .opt_str("check")
+        .map(|s| CheckDigit::from_name(&s).unwrap_or_else(|| panic!("Invalid --check value: {s}")));
+
+    let subscriptions = params
+        .opt_strs("subscription")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().expect("Invalid --subscription"))
+        .collect();
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ItemOptions {
+            org_unit: params.opt_str("org-unit"),
+            subscriptions,
+            receive_expected: params.opt_present("receive-expected"),
+            prefix: cli::required(&params, "prefix"),
+            width: params.opt_get_default("width", 8).unwrap(),
+            check,
+            staff: cli::required(&params, "staff"),
+            report_file: params.opt_str("report-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin serial-items -- --prefix 3123400 --check codabar --staff 1 --dry-run
+    cargo run --bin serial-items -- --org-unit BR1 --receive-expected --prefix SER --staff 1
+
+Creates barcoded units for serial issues and standing order parts
+which have arrived, so receiving staff need not create each item by
+hand.
+
+Items (serial.item) marked Received without a unit get one, and with
+--receive-expected, so do Expected items whose issuance has been
+published.  Each unit is created on its distribution's receive call
+number, with values from its receive unit template and the
+distribution's holding library as the default circulating library.
+The issuance label becomes the unit's contents, and the publication
+date its sort key.  Items are then marked Received.
+
+Distributions without a receive call number or template are
+skipped.  Every item is written to the report with its new barcode
+and unit ID, or the reason it was skipped.  Each item is created in
+its own savepoint, so failures leave the rest in place.
+
+Barcodes are PREFIX + zero-padded sequence + optional check digit, as
+with barcode-tool, continuing after the highest one in use.
+
+Options
+
+    --org-unit
+        Only process distributions held by this org unit and its
+        descendants.
+
+    --subscription
+        Only process these subscription IDs.  Repeatable or comma
+        separated.
+
+    --receive-expected
+        Also receive Expected items whose issuance date published
+        has passed.
+
+    --prefix
+        Generated barcode prefix.  Required.
+
+    --width
+        Barcode sequence width.  Defaults to 8.
+
+    --check
+        Barcode check digit: mod10, mod43 or codabar.  By default,
+        barcodes have no check digit.
+
+    --staff
+        Staff user recorded as creator and editor.  Required.
+
+    --report-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn org_id(tx: &mut pg::Transaction, shortname: &str) -> Result<i32, String> {
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => Ok(row.get("id")),
+        Ok(None) => Err(format!("No such org unit: {shortname}")),
+        Err(e) => Err(db_err("Error looking up org unit", e)),
+    }
+}
+
+fn pending_items(
+    tx: &mut pg::Transaction,
+    ops: &ItemOptions,
+    org_id: Option<i32>,
+) -> Result<Vec<PendingItem>, String> {
+    let sql = r#"
+        SELECT
+            sitem.id,
+            ssub.id AS subscription,
+            ssub.record_entry AS record,
+            aou.shortname AS holding_lib,
+            sdist.holding_lib AS holding_lib_id,
+            siss.label AS issue,
+            COALESCE(TO_CHAR(siss.date_published, 'YYYY-MM-DD'), '') AS sort_key,
+            sdist.receive_call_number,
+            sdist.receive_unit_template
+        FROM serial.item sitem
+            JOIN serial.issuance siss ON siss.id = sitem.issuance
+            JOIN serial.stream sstr ON sstr.id = sitem.stream
+            JOIN serial.distribution sdist ON sdist.id = sstr.distribution
+            JOIN serial.subscription ssub ON ssub.id = sdist.subscription
+            JOIN actor.org_unit aou ON aou.id = sdist.holding_lib
+        WHERE sitem.unit IS NULL
+            AND (
+                sitem.status = 'Received'
+                OR ($1 AND sitem.status = 'Expected' AND siss.date_published <= NOW())
+            )
+            AND ($2::INT IS NULL OR sdist.holding_lib IN (
+                SELECT id FROM actor.org_unit_descendants($2::INT)
+            ))
+            AND (CARDINALITY($3::INT[]) = 0 OR ssub.id = ANY($3))
+        ORDER BY ssub.id, siss.date_published, sitem.id
+    "#;
+
+    let rows = tx
+        .query(sql, &[&ops.receive_expected, &org_id, &ops.subscriptions])
+        .map_err(|e| db_err("Error selecting items", e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| PendingItem {
+            id: row.get("id"),
+            subscription: row.get("subscription"),
+            record: row.get("record"),
+            holding_lib: row.get("holding_lib"),
+            holding_lib_id: row.get("holding_lib_id"),
+            issue: row.get("issue"),
+            sort_key: row.get("sort_key"),
+            call_number: row.get("receive_call_number"),
+            template: row.get("receive_unit_template"),
+        })
+        .collect())
+}
+
+/// Create the item's unit from its distribution's template and link
+/// it to the item.  Returns the unit ID.
+fn create_unit(
+    tx: &mut pg::Transaction,
+    ops: &ItemOptions,
+    item: &PendingItem,
+    call_number: i64,
+    template: i32,
+    barcode: &str,
+) -> Result<i64, String> {
+    let sql = r#"
+        INSERT INTO serial.unit (
+            creator, editor, call_number, barcode, circ_lib, status,
+            location, loan_duration, fine_level, age_protect, circulate,
+            deposit, ref, holdable, deposit_amount, price, circ_modifier,
+            circ_as_type, opac_visible, mint_condition, sort_key,
+            summary_contents, detailed_contents
+        )
+        SELECT
+            $1::INT, $1::INT, $2, $3, COALESCE(act.circ_lib, $4),
+            COALESCE(act.status, 0), COALESCE(act.location, 1),
+            COALESCE(act.loan_duration, 2), COALESCE(act.fine_level, 2),
+            act.age_protect, COALESCE(act.circulate, TRUE),
+            COALESCE(act.deposit, FALSE), COALESCE(act.ref, FALSE),
+            COALESCE(act.holdable, TRUE), COALESCE(act.deposit_amount, 0),
+            act.price, act.circ_modifier, act.circ_as_type,
+            COALESCE(act.opac_visible, TRUE),
+            COALESCE(act.mint_condition, TRUE), $5, $6, $6
+        FROM asset.copy_template act
+        WHERE act.id = $7
+        RETURNING id
+    "#;
+
+    let row = tx
+        .query_opt(
+            sql,
+            &[
+                &ops.staff,
+                &call_number,
+                &barcode,
+                &item.holding_lib_id,
+                &item.sort_key,
+                &item.issue,
+                &template,
+            ],
+        )
+        .map_err(|e| db_err("Error creating unit", e))?;
+
+    let unit: i64 = match row {
+        Some(r) => r.get("id"),
+        None => return Err(format!("No such copy template: {template}")),
+    };
+
+    let sql = r#"
+        UPDATE serial.item
+        SET unit = $2::BIGINT, status = 'Received',
+            date_received = COALESCE(date_received, NOW()),
+            editor = $3, edit_date = NOW()
+        WHERE id = $1
+    "#;
+
+    tx.execute(sql, &[&item.id, &unit, &ops.staff])
+        .map_err(|e| db_err("Error updating item", e))?;
+
+    Ok(unit)
+}
+
+fn generate(con: &mut DatabaseConnection, ops: &ItemOptions) -> Result<(), String> {
+    let mut generator = Generator::new(&ops.prefix, ops.width, ops.check, 1)?;
+
+    let mut writer = TableWriter::for_path(ops.report_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let org_id = match ops.org_unit {
+        Some(ref s) => Some(org_id(&mut tx, s)?),
+        None => None,
+    };
+
+    generator.resume(&mut tx)?;
+
+    let items = pending_items(&mut tx, ops, org_id)?;
+    info!("Found {} items needing units", items.len());
+
+    let mut created = 0;
+
+    for item in &items {
+        let (call_number, template) = match (item.call_number, item.template) {
+            (Some(c), Some(t)) => (c, t),
+            (None, _) => {
+                write_row(&mut writer, item, None, None, "No receive call number")?;
+                continue;
+            }
+            (_, None) => {
+                write_row(&mut writer, item, None, None, "No receive unit template")?;
+                continue;
+            }
+        };
+
+        let barcode = match generator.generate(&mut tx, 1)?.pop() {
+            Some(b) => b,
+            None => return Err("No barcode generated".to_string()),
+        };
+
+        let mut sp = tx
+            .savepoint("serial_unit")
+            .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+        match create_unit(&mut sp, ops, item, call_number, template, &barcode) {
+            Ok(unit) => {
+                sp.commit()
+                    .map_err(|e| db_err("Cannot release savepoint", e))?;
+
+                debug!("Created unit {unit} {barcode} for item {}", item.id);
+                created += 1;
+
+                write_row(&mut writer, item, Some(&barcode), Some(unit), "Created")?;
+            }
+            Err(e) => {
+                sp.rollback()
+                    .map_err(|e| db_err("Cannot roll back savepoint", e))?;
+
+                write_row(&mut writer, item, None, None, &e)?;
+            }
+        }
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("units", created as u64);
+
+    info!(
+        "{} {created} of {} units",
+        ops.dry_run.past("Created"),
+        items.len()
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+fn write_row(
+    writer: &mut TableWriter,
+    item: &PendingItem,
+    barcode: Option<&str>,
+    unit: Option<i64>,
+    result: &str,
+) -> Result<(), String> {
+    writer.write_row(&[
+        Cell::from(item.id),
+        Cell::from(item.subscription),
+        Cell::from(item.record),
+        Cell::from(&item.holding_lib),
+        Cell::from(&item.issue),
+        Cell::from(barcode),
+        Cell::from(unit),
+        Cell::from(result),
+    ])
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(generate(&mut connection, &options))
+}
@@ -0,0 +1,355 @@
+use crate::callnumber::{self, Scheme};
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::info;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+/// Text output column widths, in characters.
+const CALL_NUMBER_WIDTH: usize = 30;
+const BARCODE_WIDTH: usize = 16;
+const STATUS_WIDTH: usize = 14;
+const TITLE_WIDTH: usize = 50;
+
+struct ShelfOptions {
+    org_unit: i32,
+    locations: Vec<String>,
+    start: Option<String>,
+    end: Option<String>,
+    /// None for printable text.
+    format: Option<Format>,
+    out_file: Option<String>,
+}
+
+struct Item {
+    location: String,
+    call_number: String,
+    barcode: String,
+    status: String,
+    title: String,
+    author: String,
+    circ_count: i64,
+    last_circ: Option<String>,
+    /// Location, prefix, call number, suffix and copy number keys.
+    sortkey: (String, String, String, String, i32),
+}
+
+fn read_options() -> (ShelfOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "org-unit", "Circulating Library", "ORG_ID");
+    opts.optmulti("", "location", "Copy Location Name, Repeatable", "NAME");
+    opts.optopt("", "start", "First Call Number", "CALL_NUMBER");
+    opts.optopt("", "end", "Last Call Number", "CALL_NUMBER");
+    opts.optopt("", "out-file", "Output File", "FILE");
+    opts.optopt("", "format", "text, csv, tsv or xlsx", "FORMAT");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let org_unit = match params.opt_get("org-unit").unwrap() {
+        Some(o) => o,
+        None => {
+            eprintln!("--org-unit is required");
+            std::process::exit(2);
+        }
+    };
+
+    let out_file = params.opt_str("out-file");
+
+    let format = match params.opt_str("format").as_deref() {
+        Some("text") => None,
+        Some(f) => match Format::parse(f) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(2);
+            }
+        },
+        None => out_file.as_deref().and_then(Format::from_path),
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ShelfOptions {
+            org_unit,
+            locations: params.opt_strs("location"),
+            start: params.opt_str("start"),
+            end: params.opt_str("end"),
+            format,
+            out_file,
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin shelflist -- --org-unit 4 --location Stacks --start QA76 --end QA77
+    cargo run --bin shelflist -- --org-unit 4 --location Juvenile --out-file weeding.csv
+
+Lists the items at a library in shelf order, for weeding and inventory.
+
+Items are sorted by copy location, then call number prefix, call
+number, suffix and copy number.  Call numbers sort by the scheme of
+their classification (asset.call_number_class normalizer), so e.g.
+QA76.9 sorts before QA350.
+
+Text output is grouped by location, with call number, barcode,
+status, circulation count and title columns.  CSV, TSV and spreadsheet
+output have columns location, call_number, barcode, status, title,
+author, circ_count and last_circ.
+
+Options
+
+    --org-unit
+        Circulating library ID.  Required.
+
+    --location
+        Only items in the copy location with this name.  Repeatable.
+        Defaults to all locations.
+
+    --start
+    --end
+        Inclusive call number range, compared in each item's scheme.
+        --end includes call numbers it begins, so --end QA76 includes
+        QA76.73.
+
+    --format
+        text, csv, tsv or xlsx.  Defaults to the --out-file extension,
+        or text.
+
+    --out-file
+        Write output to this file.  Otherwise, writes to STDOUT.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+const ITEM_SQL: &str = r#"
+    SELECT
+        acpl.name AS location,
+        acn.label,
+        acnc.normalizer,
+        COALESCE(acnp.label, '') AS prefix,
+        COALESCE(acns.label, '') AS suffix,
+        COALESCE(acp.copy_number, 0) AS copy_number,
+        acp.barcode,
+        ccs.name AS status,
+        COALESCE(rmsr.title, acp.dummy_title, '') AS title,
+        COALESCE(rmsr.author, acp.dummy_author, '') AS author,
+        circs.circ_count,
+        TO_CHAR(circs.last_circ, 'YYYY-MM-DD') AS last_circ
+    FROM asset.copy acp
+        JOIN asset.call_number acn ON acn.id = acp.call_number
+        JOIN asset.copy_location acpl ON acpl.id = acp.location
+        JOIN config.copy_status ccs ON ccs.id = acp.status
+        LEFT JOIN asset.call_number_class acnc ON acnc.id = acn.label_class
+        LEFT JOIN asset.call_number_prefix acnp ON acnp.id = acn.prefix AND acnp.id > -1
+        LEFT JOIN asset.call_number_suffix acns ON acns.id = acn.suffix AND acns.id > -1
+        LEFT JOIN reporter.materialized_simple_record rmsr ON rmsr.id = acn.record
+        JOIN LATERAL (
+            SELECT COUNT(*) AS circ_count, MAX(circ.xact_start) AS last_circ
+            FROM action.all_circulation circ
+            WHERE circ.target_copy = acp.id
+        ) circs ON TRUE
+    WHERE NOT acp.deleted
+        AND acp.circ_lib = $1
+        AND (CARDINALITY($2::TEXT[]) = 0 OR LOWER(acpl.name) = ANY($2))
+"#;
+
+/// True if the sort key is within --start and --end.
+fn in_range(ops: &ShelfOptions, scheme: Scheme, key: &str) -> bool {
+    if let Some(start) = &ops.start {
+        if key < scheme.sortkey(start).as_str() {
+            return false;
+        }
+    }
+
+    if let Some(end) = &ops.end {
+        let end = scheme.sortkey(end);
+        if key > end.as_str() && !key.starts_with(&end) {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn load_items(con: &mut DatabaseConnection, ops: &ShelfOptions) -> Result<Vec<Item>, String> {
+    let locations: Vec<String> = ops.locations.iter().map(|l| l.to_lowercase()).collect();
+
+    let rows = match con.client().query(ITEM_SQL, &[&ops.org_unit, &locations]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error loading items: {e}")),
+    };
+
+    let mut items = Vec::new();
+
+    for row in rows {
+        let label: String = row.get("label");
+        let normalizer: Option<String> = row.get("normalizer");
+        let prefix: String = row.get("prefix");
+        let suffix: String = row.get("suffix");
+        let location: String = row.get("location");
+
+        let scheme = Scheme::from_normalizer(normalizer.as_deref());
+        let key = scheme.sortkey(&label);
+
+        if !in_range(ops, scheme, &key) {
+            continue;
+        }
+
+        let sortkey = (
+            location.to_lowercase(),
+            callnumber::generic_sortkey(&prefix),
+            key,
+            callnumber::generic_sortkey(&suffix),
+            row.get("copy_number"),
+        );
+
+        items.push(Item {
+            location,
+            call_number: [prefix, label, suffix]
+                .iter()
+                .filter(|s| !s.is_empty())
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(" "),
+            barcode: row.get("barcode"),
+            status: row.get("status"),
+            title: row.get("title"),
+            author: row.get("author"),
+            circ_count: row.get("circ_count"),
+            last_circ: row.get("last_circ"),
+            sortkey,
+        });
+    }
+
+    items.sort_by(|a, b| a.sortkey.cmp(&b.sortkey).then(a.barcode.cmp(&b.barcode)));
+
+    Ok(items)
+}
+
+/// Pad or truncate to a column width.
+fn column(value: &str, width: usize) -> String {
+    let value: String = value.chars().take(width).collect();
+    format!("{value:<width$}")
+}
+
+fn write_text(ops: &ShelfOptions, items: &[Item]) -> Result<(), String> {
+    let mut writer: Box<dyn Write> = match &ops.out_file {
+        Some(path) => match File::create(path) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {path}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let mut text = String::new();
+    let mut location = None;
+
+    for item in items {
+        if location != Some(&item.location) {
+            if location.is_some() {
+                text += "\n";
+            }
+            location = Some(&item.location);
+
+            text += &format!("{}\n\n", item.location);
+            text += &format!(
+                "{} {} {} {:>6}  Title\n",
+                column("Call Number", CALL_NUMBER_WIDTH),
+                column("Barcode", BARCODE_WIDTH),
+                column("Status", STATUS_WIDTH),
+                "Circs"
+            );
+        }
+
+        text += &format!(
+            "{} {} {} {:>6}  {}\n",
+            column(&item.call_number, CALL_NUMBER_WIDTH),
+            column(&item.barcode, BARCODE_WIDTH),
+            column(&item.status, STATUS_WIDTH),
+            item.circ_count,
+            item.title.chars().take(TITLE_WIDTH).collect::<String>()
+        );
+    }
+
+    match writer
+        .write_all(text.as_bytes())
+        .and_then(|_| writer.flush())
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing output: {e}")),
+    }
+}
+
+fn write_table(ops: &ShelfOptions, format: Format, items: &[Item]) -> Result<(), String> {
+    let mut writer = match &ops.out_file {
+        Some(path) => TableWriter::create(path, format)?,
+        None => TableWriter::stdout(format),
+    };
+
+    writer.write_header(&[
+        "location",
+        "call_number",
+        "barcode",
+        "status",
+        "title",
+        "author",
+        "circ_count",
+        "last_circ",
+    ])?;
+
+    for item in items {
+        writer.write_row(&[
+            Cell::from(&item.location),
+            Cell::from(&item.call_number),
+            Cell::from(&item.barcode),
+            Cell::from(&item.status),
+            Cell::from(&item.title),
+            Cell::from(&item.author),
+            Cell::from(item.circ_count),
+            Cell::from(item.last_circ.as_ref()),
+        ])?;
+    }
+
+    writer.finish()
+}
+
+fn list(con: &mut DatabaseConnection, ops: &ShelfOptions) -> Result<(), String> {
+    con.connect()?;
+    let items = load_items(con, ops)?;
+    con.disconnect();
+
+    match ops.format {
+        Some(format) => write_table(ops, format, &items)?,
+        None => write_text(ops, &items)?,
+    }
+
+    info!("Listed {} items", items.len());
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    list(&mut connection, &options)
+}
@@ -0,0 +1,571 @@
+use crate::cli;
+use crate::date::UtcTime;
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, thread};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MessageType {
+    PatronStatus,
+    Checkout,
+    Checkin,
+}
+
+impl MessageType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "patron-status" => Some(MessageType::PatronStatus),
+            "checkout" => Some(MessageType::Checkout),
+            "checkin" => Some(MessageType::Checkin),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            MessageType::PatronStatus => "patron-status",
+            MessageType::Checkout => "checkout",
+            MessageType::Checkin => "checkin",
+        }
+    }
+
+    /// Response message code for each request type.
+    fn response_code(&self) -> &'static str {
+        match self {
+            MessageType::PatronStatus => "24",
+            MessageType::Checkout => "12",
+            MessageType::Checkin => "10",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct BenchOptions {
+    host: String,
+    connections: usize,
+    requests: usize,
+    duration: Option<u64>,
+    mix: Vec<(MessageType, u32)>,
+    sip_user: Option<String>,
+    sip_password: Option<String>,
+    location: String,
+    institution: String,
+    terminal_password: String,
+    patron_password: String,
+    patrons: Vec<String>,
+    items: Vec<String>,
+    checksums: bool,
+    timeout: u64,
+}
+
+/// Latency samples and error counts collected by each connection.
+#[derive(Default)]
+struct Stats {
+    latencies: HashMap<MessageType, Vec<Duration>>,
+    /// Responses reporting a failed transaction, e.g. checkout denied.
+    failures: HashMap<MessageType, usize>,
+    /// Network errors and unexpected responses.
+    errors: HashMap<MessageType, usize>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        for (k, mut v) in other.latencies {
+            self.latencies.entry(k).or_default().append(&mut v);
+        }
+        for (k, v) in other.failures {
+            *self.failures.entry(k).or_default() += v;
+        }
+        for (k, v) in other.errors {
+            *self.errors.entry(k).or_default() += v;
+        }
+    }
+}
+
+fn read_lines(filename: &str) -> Result<Vec<String>, String> {
+    match fs::read_to_string(filename) {
+        Ok(s) => Ok(s
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()),
+        Err(e) => Err(format!("Cannot read {filename}: {e}")),
+    }
+}
+
+fn parse_mix(mix: &str) -> Result<Vec<(MessageType, u32)>, String> {
+    let mut parsed = Vec::new();
+
+    for part in mix.split(',') {
+        let (name, weight) = match part.split_once('=') {
+            Some((n, w)) => (n.trim(), w.trim()),
+            None => (part.trim(), "1"),
+        };
+
+        let mtype = match MessageType::from_name(name) {
+            Some(t) => t,
+            None => return Err(format!("Unknown message type in --mix: {name}")),
+        };
+
+        let weight = match weight.parse::<u32>() {
+            Ok(w) => w,
+            Err(_) => return Err(format!("Invalid weight in --mix: {part}")),
+        };
+
+        if weight > 0 {
+            parsed.push((mtype, weight));
+        }
+    }
+
+    if parsed.is_empty() {
+        return Err("--mix contains no messages".to_string());
+    }
+
+    Ok(parsed)
+}
+
+fn read_options() -> Result<BenchOptions, String> {
+    let mut opts = cli::options();
+
+    opts.optopt("", "sip-host", "SIP Server", "HOST:PORT");
+    opts.optopt("", "connections", "Concurrent Connections", "COUNT");
+    opts.optopt("", "requests", "Requests per Connection", "COUNT");
+    opts.optopt("", "duration", "Run for this many Seconds", "SECONDS");
+    opts.optopt("", "mix", "Message Mix", "TYPE=WEIGHT,...");
+    opts.optopt("", "sip-user", "SIP Login User", "USER");
+    opts.optopt("", "sip-password", "SIP Login Password", "PASSWORD");
+    opts.optopt("", "location", "SIP Location Code", "LOCATION");
+    opts.optopt("", "institution", "SIP Institution", "INSTITUTION");
+    opts.optopt("", "terminal-password", "Terminal Password", "PASSWORD");
+    opts.optopt("", "patron-password", "Patron Password", "PASSWORD");
+    opts.optopt("", "patron-file", "File of Patron Barcodes", "PATRON_FILE");
+    opts.optopt("", "item-file", "File of Item Barcodes", "ITEM_FILE");
+    opts.optopt("", "timeout", "Response Timeout Seconds", "TIMEOUT");
+    opts.optflag("", "checksums", "Send Sequence Numbers and Checksums");
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let mix = parse_mix(
+        &params
+            .opt_get_default("mix", "patron-status=50,checkout=25,checkin=25".to_string())
+            .unwrap(),
+    )?;
+
+    let patrons = match params.opt_str("patron-file") {
+        Some(f) => read_lines(&f)?,
+        None => Vec::new(),
+    };
+
+    let items = match params.opt_str("item-file") {
+        Some(f) => read_lines(&f)?,
+        None => Vec::new(),
+    };
+
+    for (mtype, _) in &mix {
+        if patrons.is_empty() && *mtype != MessageType::Checkin {
+            return Err(format!("--patron-file is required for {}", mtype.name()));
+        }
+        if items.is_empty() && *mtype != MessageType::PatronStatus {
+            return Err(format!("--item-file is required for {}", mtype.name()));
+        }
+    }
+
+    Ok(BenchOptions {
+        mix,
+        patrons,
+        items,
+        host: params
+            .opt_get_default("sip-host", "127.0.0.1:6001".to_string())
+            .unwrap(),
+        connections: params.opt_get_default("connections", 10).unwrap(),
+        requests: params.opt_get_default("requests", 100).unwrap(),
+        duration: params.opt_get("duration").unwrap(),
+        sip_user: params.opt_str("sip-user"),
+        sip_password: params.opt_str("sip-password"),
+        location: params.opt_get_default("location", String::new()).unwrap(),
+        institution: params
+            .opt_get_default("institution", "sample".to_string())
+            .unwrap(),
+        terminal_password: params
+            .opt_get_default("terminal-password", String::new())
+            .unwrap(),
+        patron_password: params
+            .opt_get_default("patron-password", String::new())
+            .unwrap(),
+        checksums: params.opt_present("checksums"),
+        timeout: params.opt_get_default("timeout", 30).unwrap(),
+    })
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin sip2-bench -- --sip-host 127.0.0.1:6001 \
+        --sip-user sipuser --sip-password sippass --connections 50 \
+        --patron-file patrons.txt --item-file items.txt
+
+Options
+
+    --sip-host
+        SIP server host and port.  Defaults to 127.0.0.1:6001.
+
+    --connections
+        Number of concurrent SIP connections.  Defaults to 10.
+
+    --requests
+        Number of requests sent per connection.  Defaults to 100.
+
+    --duration
+        Send requests for this many seconds instead of a fixed
+        number of requests.
+
+    --mix
+        Comma-separated list of message types and relative weights.
+        Supported types are patron-status, checkout, and checkin.
+        Defaults to patron-status=50,checkout=25,checkin=25
+
+    --sip-user
+    --sip-password
+    --location
+        Login (93) credentials and location code.  No login message
+        is sent when --sip-user is not set.
+
+    --institution
+    --terminal-password
+    --patron-password
+        Values for the AO, AC, and AD message fields.
+
+    --patron-file
+    --item-file
+        Files containing one patron or item barcode per line.
+        Barcodes are selected at random for each request.
+
+    --checksums
+        Include sequence numbers and checksums in each message.
+
+    --timeout
+        Seconds to wait for each response.  Defaults to 30.
+
+{}
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+/// Dependency-free xorshift generator; we only need a varied mix,
+/// not quality randomness.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn pick<'a>(&mut self, list: &'a [String]) -> &'a str {
+        &list[(self.next() % list.len() as u64) as usize]
+    }
+}
+
+fn sip_date() -> String {
+    let now = UtcTime::now();
+    format!(
+        "{:04}{:02}{:02}    {:02}{:02}{:02}",
+        now.year, now.month, now.day, now.hour, now.minute, now.second
+    )
+}
+
+fn checksum(msg: &str) -> String {
+    let sum: u32 = msg.bytes().map(|b| b as u32).sum();
+    format!("{:04X}", (!sum).wrapping_add(1) & 0xffff)
+}
+
+struct SipConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    sequence: u8,
+    checksums: bool,
+}
+
+impl SipConnection {
+    fn connect(options: &BenchOptions) -> Result<Self, String> {
+        let stream = match TcpStream::connect(&options.host) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Cannot connect to {}: {e}", options.host)),
+        };
+
+        stream
+            .set_read_timeout(Some(Duration::from_secs(options.timeout)))
+            .ok();
+
+        let writer = match stream.try_clone() {
+            Ok(w) => w,
+            Err(e) => return Err(format!("Cannot clone socket: {e}")),
+        };
+
+        let mut con = SipConnection {
+            reader: BufReader::new(stream),
+            writer,
+            sequence: 0,
+            checksums: options.checksums,
+        };
+
+        if let Some(ref user) = options.sip_user {
+            let msg = format!(
+                "9300CN{}|CO{}|CP{}|",
+                user,
+                options.sip_password.as_deref().unwrap_or(""),
+                options.location
+            );
+
+            let resp = con.send(&msg)?;
+            if !resp.starts_with("941") {
+                return Err(format!("SIP login failed: {resp}"));
+            }
+        }
+
+        Ok(con)
+    }
+
+    /// Send a message and return the response minus the terminator.
+    fn send(&mut self, msg: &str) -> Result<String, String> {
+        let mut msg = msg.to_string();
+
+        if self.checksums {
+            msg += &format!("AY{}AZ", self.sequence);
+            msg += &checksum(&msg);
+            self.sequence = (self.sequence + 1) % 10;
+        }
+
+        msg.push('\r');
+
+        if let Err(e) = self.writer.write_all(msg.as_bytes()) {
+            return Err(format!("Error sending message: {e}"));
+        }
+
+        let mut buf = Vec::new();
+        match self.reader.read_until(b'\r', &mut buf) {
+            Ok(0) => Err("Server closed the connection".to_string()),
+            Ok(_) => Ok(String::from_utf8_lossy(&buf)
+                .trim_end_matches(['\r', '\n'])
+                .to_string()),
+            Err(e) => Err(format!("Error reading response: {e}")),
+        }
+    }
+}
+
+fn build_message(options: &BenchOptions, mtype: MessageType, rng: &mut Rng) -> String {
+    let date = sip_date();
+    let inst = &options.institution;
+    let term_pw = &options.terminal_password;
+
+    match mtype {
+        MessageType::PatronStatus => format!(
+            "23001{date}AO{inst}|AA{}|AC{term_pw}|AD{}|",
+            rng.pick(&options.patrons),
+            options.patron_password
+        ),
+        MessageType::Checkout => format!(
+            "11YN{date}{:18}AO{inst}|AA{}|AB{}|AC{term_pw}|",
+            "",
+            rng.pick(&options.patrons),
+            rng.pick(&options.items)
+        ),
+        MessageType::Checkin => format!(
+            "09N{date}{date}AP{}|AO{inst}|AB{}|AC{term_pw}|",
+            options.location,
+            rng.pick(&options.items)
+        ),
+    }
+}
+
+/// True if the response indicates the transaction succeeded.
+fn response_ok(mtype: MessageType, resp: &str) -> bool {
+    match mtype {
+        // Patron status has no ok flag; any well-formed response counts.
+        MessageType::PatronStatus => true,
+        MessageType::Checkout | MessageType::Checkin => resp.as_bytes().get(2) == Some(&b'1'),
+    }
+}
+
+fn pick_type(mix: &[(MessageType, u32)], rng: &mut Rng) -> MessageType {
+    let total: u32 = mix.iter().map(|(_, w)| w).sum();
+    let mut roll = (rng.next() % total as u64) as u32;
+
+    for (mtype, weight) in mix {
+        if roll < *weight {
+            return *mtype;
+        }
+        roll -= weight;
+    }
+
+    mix[0].0
+}
+
+fn run_connection(options: &BenchOptions, index: usize, start: Instant) -> Stats {
+    let mut stats = Stats::default();
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+        ^ (index as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15);
+
+    let mut rng = Rng::new(seed);
+
+    let mut con = match SipConnection::connect(options) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Connection {index}: {e}");
+            return stats;
+        }
+    };
+
+    let mut sent = 0;
+
+    loop {
+        match options.duration {
+            Some(secs) => {
+                if start.elapsed() >= Duration::from_secs(secs) {
+                    break;
+                }
+            }
+            None => {
+                if sent >= options.requests {
+                    break;
+                }
+            }
+        }
+
+        sent += 1;
+
+        let mtype = pick_type(&options.mix, &mut rng);
+        let msg = build_message(options, mtype, &mut rng);
+
+        let then = Instant::now();
+        let result = con.send(&msg);
+        let elapsed = then.elapsed();
+
+        match result {
+            Ok(resp) if resp.starts_with(mtype.response_code()) => {
+                stats.latencies.entry(mtype).or_default().push(elapsed);
+                if !response_ok(mtype, &resp) {
+                    debug!("Connection {index}: {} failed: {resp}", mtype.name());
+                    *stats.failures.entry(mtype).or_default() += 1;
+                }
+            }
+            Ok(resp) => {
+                error!("Connection {index}: unexpected response: {resp}");
+                *stats.errors.entry(mtype).or_default() += 1;
+            }
+            Err(e) => {
+                error!("Connection {index}: {e}");
+                *stats.errors.entry(mtype).or_default() += 1;
+
+                con = match SipConnection::connect(options) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Connection {index}: {e}");
+                        return stats;
+                    }
+                };
+            }
+        }
+    }
+
+    stats
+}
+
+fn percentile(sorted: &[Duration], pct: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let idx = ((sorted.len() as f64 * pct / 100.0).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[idx].as_secs_f64() * 1000.0
+}
+
+fn report(options: &BenchOptions, mut stats: Stats, elapsed: Duration) {
+    println!(
+        "\n{:<15} {:>8} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "message", "count", "failed", "errors", "p50 ms", "p90 ms", "p95 ms", "p99 ms", "max ms"
+    );
+
+    let mut total = 0;
+
+    for (mtype, _) in &options.mix {
+        let mut lats = stats.latencies.remove(mtype).unwrap_or_default();
+        lats.sort();
+        total += lats.len();
+
+        println!(
+            "{:<15} {:>8} {:>8} {:>8} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+            mtype.name(),
+            lats.len(),
+            stats.failures.get(mtype).unwrap_or(&0),
+            stats.errors.get(mtype).unwrap_or(&0),
+            percentile(&lats, 50.0),
+            percentile(&lats, 90.0),
+            percentile(&lats, 95.0),
+            percentile(&lats, 99.0),
+            percentile(&lats, 100.0),
+        );
+    }
+
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "\n{} responses in {:.1}s across {} connections ({:.1} req/s)\n",
+        total,
+        secs,
+        options.connections,
+        if secs > 0.0 { total as f64 / secs } else { 0.0 }
+    );
+}
+
+pub fn main() -> Result<(), String> {
+    let options = Arc::new(read_options()?);
+
+    info!(
+        "Starting {} connection(s) to {}",
+        options.connections, options.host
+    );
+
+    let totals = Arc::new(Mutex::new(Stats::default()));
+    let start = Instant::now();
+    let mut handles = Vec::new();
+
+    for index in 0..options.connections {
+        let ops = options.clone();
+        let totals = totals.clone();
+        handles.push(thread::spawn(move || {
+            let stats = run_connection(&ops, index, start);
+            totals.lock().unwrap().merge(stats);
+        }));
+    }
+
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("Connection thread panicked");
+        }
+    }
+
+    let elapsed = start.elapsed();
+    let stats = std::mem::take(&mut *totals.lock().unwrap());
+
+    report(&options, stats, elapsed);
+
+    Ok(())
+}
@@ -0,0 +1,320 @@
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::lockfile;
+use crate::xml::escape;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use std::io::prelude::*;
+use std::{fs, path::Path, process};
+
+const SITEMAP_NS: &str = "http://www.sitemaps.org/schemas/sitemap/0.9";
+
+/// Sitemap protocol limit on URLs per file.
+const MAX_URLS_PER_FILE: usize = 50000;
+
+struct SitemapOptions {
+    out_dir: String,
+    url_template: String,
+    base_url: String,
+    prefix: String,
+    records_per_file: usize,
+    org_unit: Option<String>,
+    lockfile: Option<String>,
+}
+
+fn read_options() -> (SitemapOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "out-dir", "Output Directory", "OUT_DIR");
+    opts.optopt("", "url-template", "Record URL Template", "URL_TEMPLATE");
+    opts.optopt("", "base-url", "URL of the Output Directory", "BASE_URL");
+    opts.optopt("", "prefix", "Sitemap File Name Prefix", "PREFIX");
+    opts.optopt("", "records-per-file", "Records per Sitemap File", "COUNT");
+    opts.optopt("", "org-unit", "Limit to Holdings at Org Unit", "SHORTNAME");
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let url_template = match params.opt_str("url-template") {
+        Some(t) => t,
+        None => {
+            eprintln!("--url-template is required");
+            process::exit(2);
+        }
+    };
+
+    let base_url = match params.opt_str("base-url") {
+        Some(u) => u.trim_end_matches('/').to_string(),
+        None => {
+            eprintln!("--base-url is required");
+            process::exit(2);
+        }
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    let records_per_file: usize = params
+        .opt_get_default("records-per-file", MAX_URLS_PER_FILE)
+        .unwrap();
+
+    (
+        SitemapOptions {
+            url_template,
+            base_url,
+            records_per_file: records_per_file.clamp(1, MAX_URLS_PER_FILE),
+            out_dir: params.opt_get_default("out-dir", ".".to_string()).unwrap(),
+            prefix: params
+                .opt_get_default("prefix", "sitemap".to_string())
+                .unwrap(),
+            org_unit: params.opt_str("org-unit"),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin sitemap-gen -- --out-dir /openils/var/web/sitemaps \
+        --base-url https://catalog.example.org/sitemaps \
+        --url-template 'https://catalog.example.org/eg/opac/record/{{id}}'
+
+Options
+
+    --out-dir
+        Directory where the sitemap files are written.  Defaults to
+        the current directory.
+
+    --url-template
+        URL for each record.  {{id}} is replaced with the record ID.
+
+    --base-url
+        Public URL of the output directory, used to build the
+        sitemap index.
+
+    --prefix
+        Sitemap file name prefix.  Defaults to "sitemap", producing
+        sitemap-00001.xml.gz ... and sitemap-index.xml
+
+    --records-per-file
+        Number of records per sitemap file.  Defaults to (and may
+        not exceed) {MAX_URLS_PER_FILE}.
+
+    --org-unit
+        Only include records with OPAC-visible holdings at this org
+        unit or its descendants.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+/// Non-deleted records which have some OPAC-visible holdings or come
+/// from a transcendent bib source.
+fn create_sql(ops: &SitemapOptions) -> String {
+    let org_filter = match ops.org_unit {
+        Some(_) => {
+            "AND acn.owning_lib IN (
+                SELECT id FROM actor.org_unit_descendants(
+                    (SELECT id FROM actor.org_unit WHERE shortname = $1)
+                )
+            )"
+        }
+        None => "",
+    };
+
+    format!(
+        r#"
+        SELECT bre.id, TO_CHAR(bre.edit_date, 'YYYY-MM-DD') AS lastmod
+        FROM biblio.record_entry bre
+        LEFT JOIN config.bib_source cbs ON cbs.id = bre.source
+        WHERE NOT bre.deleted AND bre.id > 0 AND (
+            COALESCE(cbs.transcendant, FALSE)
+            OR EXISTS (
+                SELECT 1
+                FROM asset.call_number acn
+                JOIN asset.copy acp ON acp.call_number = acn.id
+                JOIN asset.copy_location acpl ON acpl.id = acp.location
+                JOIN config.copy_status ccs ON ccs.id = acp.status
+                WHERE acn.record = bre.id
+                    AND NOT acn.deleted
+                    AND NOT acp.deleted
+                    AND acp.opac_visible
+                    AND acpl.opac_visible
+                    AND ccs.opac_visible
+                    {org_filter}
+            )
+        )
+        ORDER BY bre.id
+    "#
+    )
+}
+
+struct SitemapWriter {
+    encoder: GzEncoder<fs::File>,
+    count: usize,
+}
+
+impl SitemapWriter {
+    fn create(path: &Path) -> Result<Self, String> {
+        let file = match fs::File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Cannot create {}: {e}", path.display())),
+        };
+
+        let mut writer = SitemapWriter {
+            encoder: GzEncoder::new(file, Compression::default()),
+            count: 0,
+        };
+
+        writer.write(&format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"{SITEMAP_NS}\">\n"
+        ))?;
+
+        Ok(writer)
+    }
+
+    fn write(&mut self, s: &str) -> Result<(), String> {
+        match self.encoder.write_all(s.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error writing sitemap: {e}")),
+        }
+    }
+
+    fn add_url(&mut self, url: &str, lastmod: Option<&str>) -> Result<(), String> {
+        let mut entry = format!("<url><loc>{}</loc>", escape(url));
+        if let Some(lm) = lastmod {
+            entry += &format!("<lastmod>{lm}</lastmod>");
+        }
+        entry += "</url>\n";
+
+        self.count += 1;
+        self.write(&entry)
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        self.write("</urlset>\n")?;
+        match self.encoder.finish() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error finishing sitemap: {e}")),
+        }
+    }
+}
+
+fn generate(con: &mut DatabaseConnection, ops: &SitemapOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let sql = create_sql(ops);
+
+    let rows = match ops.org_unit {
+        Some(ref ou) => con.client().query(&sql[..], &[ou]),
+        None => con.client().query(&sql[..], &[]),
+    };
+
+    let rows = match rows {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error querying records: {e}")),
+    };
+
+    info!("Found {} records for the sitemap", rows.len());
+
+    let out_dir = Path::new(&ops.out_dir);
+    let mut files: Vec<(String, Option<String>)> = Vec::new();
+    let mut writer: Option<SitemapWriter> = None;
+    let mut newest: Option<String> = None;
+
+    for row in rows {
+        let id: i64 = row.get("id");
+        let lastmod: Option<String> = row.get("lastmod");
+
+        if writer.is_none() {
+            let name = format!("{}-{:05}.xml.gz", ops.prefix, files.len() + 1);
+            writer = Some(SitemapWriter::create(&out_dir.join(&name))?);
+            files.push((name, None));
+            newest = None;
+        }
+
+        let w = writer.as_mut().unwrap();
+
+        let url = ops.url_template.replace("{id}", &id.to_string());
+        w.add_url(&url, lastmod.as_deref())?;
+
+        if lastmod > newest {
+            newest = lastmod;
+        }
+
+        if w.count >= ops.records_per_file {
+            writer.take().unwrap().finish()?;
+            files.last_mut().unwrap().1 = newest.take();
+        }
+    }
+
+    if let Some(w) = writer {
+        w.finish()?;
+        files.last_mut().unwrap().1 = newest.take();
+    }
+
+    con.disconnect();
+
+    write_index(ops, &files)
+}
+
+fn write_index(ops: &SitemapOptions, files: &[(String, Option<String>)]) -> Result<(), String> {
+    let path = Path::new(&ops.out_dir).join(format!("{}-index.xml", ops.prefix));
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sitemapindex xmlns=\"{SITEMAP_NS}\">\n"
+    );
+
+    for (name, lastmod) in files {
+        xml += &format!(
+            "<sitemap><loc>{}</loc>",
+            escape(&format!("{}/{}", ops.base_url, name))
+        );
+        if let Some(lm) = lastmod {
+            xml += &format!("<lastmod>{lm}</lastmod>");
+        }
+        xml += "</sitemap>\n";
+    }
+
+    xml += "</sitemapindex>\n";
+
+    if let Err(e) = fs::write(&path, xml) {
+        return Err(format!("Cannot write {}: {e}", path.display()));
+    }
+
+    info!(
+        "Wrote {} sitemap file(s) and index {}",
+        files.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    generate(&mut connection, &options)
+}
@@ -0,0 +1,679 @@
+use crate::cli;
+use crate::daemon;
+use crate::db::{self, DatabaseConnection};
+use crate::http::{self, Request, Response};
+use crate::xml::{escape, strip_declaration};
+use log::{error, info};
+use postgres as pg;
+use std::sync::{Arc, Mutex};
+
+const SRU_NS: &str = "http://www.loc.gov/zing/srw/";
+const DIAG_NS: &str = "http://www.loc.gov/zing/srw/diagnostic/";
+const EXPLAIN_NS: &str = "http://explain.z3950.org/dtd/2.0/";
+const MARCXML_SCHEMA: &str = "info:srw/schema/1/marcxml-v1.1";
+const SRU_VERSION: &str = "1.2";
+
+/// CQL index names mapped to the metabib field entry table they search.
+/// An index of None means the search is against record IDs.
+const INDEXES: &[(&str, Option<&str>)] = &[
+    ("cql.anywhere", Some("keyword")),
+    ("cql.serverchoice", Some("keyword")),
+    ("keyword", Some("keyword")),
+    ("dc.title", Some("title")),
+    ("title", Some("title")),
+    ("dc.creator", Some("author")),
+    ("dc.author", Some("author")),
+    ("author", Some("author")),
+    ("dc.subject", Some("subject")),
+    ("subject", Some("subject")),
+    ("series", Some("series")),
+    ("bath.isbn", Some("identifier")),
+    ("bath.issn", Some("identifier")),
+    ("isbn", Some("identifier")),
+    ("issn", Some("identifier")),
+    ("identifier", Some("identifier")),
+    ("rec.id", None),
+];
+
+#[derive(Clone)]
+struct ServerOptions {
+    address: String,
+    max_threads: usize,
+    default_records: i64,
+    max_records: i64,
+    host_name: String,
+    port: u16,
+    database_name: String,
+}
+
+/// SRU diagnostic, returned inside the response body.
+struct Diagnostic {
+    code: u32,
+    message: &'static str,
+    details: String,
+}
+
+impl Diagnostic {
+    fn new(code: u32, message: &'static str, details: &str) -> Self {
+        Diagnostic {
+            code,
+            message,
+            details: details.to_string(),
+        }
+    }
+
+    fn to_xml(&self) -> String {
+        format!(
+            r#"<diagnostics><diagnostic xmlns="{DIAG_NS}"><uri>info:srw/diagnostic/1/{}</uri><message>{}</message><details>{}</details></diagnostic></diagnostics>"#,
+            self.code,
+            self.message,
+            escape(&self.details)
+        )
+    }
+}
+
+impl From<pg::Error> for Diagnostic {
+    fn from(e: pg::Error) -> Self {
+        Diagnostic::new(1, "General system error", &format!("{e}"))
+    }
+}
+
+// CQL ---------------------------------------------------------------------
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    Quoted(String),
+    Relation(String),
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\r' | '\n' => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            if let Some(n) = chars.next() {
+                                s.push(n);
+                            }
+                        }
+                        Some('"') => break,
+                        Some(n) => s.push(n),
+                        None => {
+                            return Err(Diagnostic::new(
+                                10,
+                                "Query syntax error",
+                                "Unterminated quote",
+                            ))
+                        }
+                    }
+                }
+                tokens.push(Token::Quoted(s));
+            }
+            '=' | '<' | '>' => {
+                let mut r = c.to_string();
+                while let Some(n) = chars.peek() {
+                    if *n == '=' || *n == '>' {
+                        r.push(*n);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Relation(r));
+            }
+            _ => {
+                let mut s = c.to_string();
+                while let Some(n) = chars.peek() {
+                    if n.is_whitespace() || "()=<>\"".contains(*n) {
+                        break;
+                    }
+                    s.push(*n);
+                    chars.next();
+                }
+                tokens.push(Token::Word(s));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Compiles a CQL query into an SQL condition on biblio.record_entry
+/// (aliased as bre) plus query parameters.
+struct CqlCompiler {
+    tokens: Vec<Token>,
+    pos: usize,
+    params: Vec<String>,
+}
+
+impl CqlCompiler {
+    fn compile(query: &str) -> Result<(String, Vec<String>), Diagnostic> {
+        let mut compiler = CqlCompiler {
+            tokens: tokenize(query)?,
+            pos: 0,
+            params: Vec::new(),
+        };
+
+        if compiler.tokens.is_empty() {
+            return Err(Diagnostic::new(10, "Query syntax error", "Empty query"));
+        }
+
+        let sql = compiler.parse_query()?;
+
+        if compiler.pos < compiler.tokens.len() {
+            return Err(Diagnostic::new(
+                10,
+                "Query syntax error",
+                "Unexpected trailing content",
+            ));
+        }
+
+        Ok((sql, compiler.params))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn boolean(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Word(w)) => match w.to_lowercase().as_str() {
+                "and" => Some("AND"),
+                "or" => Some("OR"),
+                "not" => Some("AND NOT"),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<String, Diagnostic> {
+        let mut sql = self.parse_clause()?;
+
+        while let Some(op) = self.boolean() {
+            self.pos += 1;
+            let right = self.parse_clause()?;
+            sql = format!("({sql} {op} {right})");
+        }
+
+        Ok(sql)
+    }
+
+    fn parse_clause(&mut self) -> Result<String, Diagnostic> {
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let sql = self.parse_query()?;
+            if self.next() != Some(&Token::Close) {
+                return Err(Diagnostic::new(10, "Query syntax error", "Missing ')'"));
+            }
+            return Ok(sql);
+        }
+
+        let first = match self.next() {
+            Some(Token::Word(w)) => w.to_string(),
+            Some(Token::Quoted(q)) => q.to_string(),
+            _ => {
+                return Err(Diagnostic::new(
+                    10,
+                    "Query syntax error",
+                    "Expected a search term",
+                ))
+            }
+        };
+
+        // index relation term
+        let relation = match self.peek() {
+            Some(Token::Relation(r)) => Some(r.to_string()),
+            Some(Token::Word(w)) if ["any", "all", "adj"].contains(&w.to_lowercase().as_str()) => {
+                match self.tokens.get(self.pos + 1) {
+                    // "any" followed by a boolean or the end is itself a term.
+                    Some(Token::Word(_)) | Some(Token::Quoted(_)) => Some(w.to_lowercase()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        let relation = match relation {
+            Some(r) => {
+                self.pos += 1;
+                r
+            }
+            None => return self.term_sql("cql.serverchoice", "=", &first),
+        };
+
+        let term = match self.next() {
+            Some(Token::Word(w)) => w.to_string(),
+            Some(Token::Quoted(q)) => q.to_string(),
+            _ => {
+                return Err(Diagnostic::new(
+                    10,
+                    "Query syntax error",
+                    "Expected a search term",
+                ))
+            }
+        };
+
+        self.term_sql(&first.to_lowercase(), &relation, &term)
+    }
+
+    fn add_param(&mut self, value: &str) -> usize {
+        self.params.push(value.to_string());
+        self.params.len()
+    }
+
+    fn term_sql(&mut self, index: &str, relation: &str, term: &str) -> Result<String, Diagnostic> {
+        let class = match INDEXES.iter().find(|(name, _)| *name == index) {
+            Some((_, class)) => *class,
+            None => return Err(Diagnostic::new(16, "Unsupported index", index)),
+        };
+
+        let class = match class {
+            Some(c) => c,
+            None => {
+                if relation != "=" && relation != "==" {
+                    return Err(Diagnostic::new(19, "Unsupported relation", relation));
+                }
+                if term.parse::<i64>().is_err() {
+                    return Err(Diagnostic::new(
+                        36,
+                        "Term in invalid format for index or relation",
+                        term,
+                    ));
+                }
+                let p = self.add_param(term);
+                return Ok(format!("bre.id = ${p}::TEXT::BIGINT"));
+            }
+        };
+
+        let tsquery = match relation {
+            "=" | "all" => {
+                let p = self.add_param(term);
+                format!("PLAINTO_TSQUERY('simple', ${p}::TEXT)")
+            }
+            "any" => {
+                let mut parts = Vec::new();
+                for word in term.split_whitespace() {
+                    let p = self.add_param(word);
+                    parts.push(format!("PLAINTO_TSQUERY('simple', ${p}::TEXT)"));
+                }
+                if parts.is_empty() {
+                    return Err(Diagnostic::new(27, "Empty term unsupported", ""));
+                }
+                format!("({})", parts.join(" || "))
+            }
+            "adj" | "==" => {
+                let p = self.add_param(term);
+                format!("PHRASETO_TSQUERY('simple', ${p}::TEXT)")
+            }
+            _ => return Err(Diagnostic::new(19, "Unsupported relation", relation)),
+        };
+
+        Ok(format!(
+            "bre.id IN (SELECT source FROM metabib.{class}_field_entry WHERE index_vector @@ {tsquery})"
+        ))
+    }
+}
+
+// Server ------------------------------------------------------------------
+
+fn read_options() -> (ServerOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "listen", "Listen Address", "ADDRESS:PORT");
+    opts.optopt("", "max-threads", "Max Worker Threads", "MAX_THREADS");
+    opts.optopt("", "default-records", "Default maximumRecords", "COUNT");
+    opts.optopt("", "max-records", "Largest maximumRecords Allowed", "COUNT");
+    opts.optopt("", "explain-host", "Host Name for Explain", "HOST");
+    opts.optopt("", "explain-port", "Port for Explain", "PORT");
+    opts.optopt("", "explain-database", "Database Path for Explain", "PATH");
+
+    daemon::append_options(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ServerOptions {
+            address: params
+                .opt_get_default("listen", "127.0.0.1:8081".to_string())
+                .unwrap(),
+            max_threads: params.opt_get_default("max-threads", 5).unwrap(),
+            default_records: params.opt_get_default("default-records", 10).unwrap(),
+            max_records: params.opt_get_default("max-records", 100).unwrap(),
+            host_name: params
+                .opt_get_default("explain-host", "localhost".to_string())
+                .unwrap(),
+            port: params.opt_get_default("explain-port", 8081).unwrap(),
+            database_name: params
+                .opt_get_default("explain-database", "sru".to_string())
+                .unwrap(),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin sru-server -- --listen 0.0.0.0:8081
+
+    curl 'http://localhost:8081/sru?operation=searchRetrieve&version=1.2&query=dc.title=dogs'
+
+Options
+
+    --listen
+        Address and port to listen on.  Defaults to 127.0.0.1:8081.
+
+    --max-threads
+        Maximum number of requests to process in parallel.
+
+    --default-records
+        Number of records returned when maximumRecords is not
+        provided.  Defaults to 10.
+
+    --max-records
+        Largest maximumRecords value honored.  Defaults to 100.
+
+    --explain-host
+    --explain-port
+    --explain-database
+        Server location details reported in the explain response.
+
+    --detach
+        Run in the background.  Log to --log-file or syslog, since
+        stderr is closed.
+
+    --pidfile
+        Write the process ID to this file, and refuse to start
+        while the process it names is running.  SIGHUP restarts
+        in place and SIGTERM stops the server.
+
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+Supported Indexes
+
+    cql.anywhere, keyword, dc.title, dc.creator, dc.subject, series,
+    bath.isbn, bath.issn, identifier, rec.id
+
+    Relations =, all, any, adj, and == are supported along with the
+    and, or, and not boolean operators.
+
+    "#,
+        cli::COMMON_HELP
+    );
+}
+
+fn explain(options: &ServerOptions) -> String {
+    let mut indexes = String::new();
+    for (name, _) in INDEXES {
+        let (set, index) = match name.split_once('.') {
+            Some((s, i)) => (s, i),
+            None => ("local", *name),
+        };
+        indexes += &format!(
+            r#"<index><title>{name}</title><map><name set="{set}">{index}</name></map></index>"#
+        );
+    }
+
+    format!(
+        r#"<explainResponse xmlns="{SRU_NS}">
+<version>{SRU_VERSION}</version>
+<record>
+<recordSchema>http://explain.z3950.org/dtd/2.0/</recordSchema>
+<recordPacking>xml</recordPacking>
+<recordData>
+<explain xmlns="{EXPLAIN_NS}">
+<serverInfo protocol="SRU" version="{SRU_VERSION}"><host>{}</host><port>{}</port><database>{}</database></serverInfo>
+<indexInfo>{indexes}</indexInfo>
+<schemaInfo><schema identifier="{MARCXML_SCHEMA}" name="marcxml"><title>MARCXML</title></schema></schemaInfo>
+<configInfo><default type="numberOfRecords">{}</default><setting type="maximumRecords">{}</setting></configInfo>
+</explain>
+</recordData>
+</record>
+</explainResponse>
+"#,
+        escape(&options.host_name),
+        options.port,
+        escape(&options.database_name),
+        options.default_records,
+        options.max_records,
+    )
+}
+
+fn search_retrieve(
+    con: &mut DatabaseConnection,
+    options: &ServerOptions,
+    request: &Request,
+) -> Result<String, Diagnostic> {
+    let query = match request.param("query") {
+        Some(q) => q,
+        None => {
+            return Err(Diagnostic::new(
+                7,
+                "Mandatory parameter not supplied",
+                "query",
+            ))
+        }
+    };
+
+    let schema = request.param("recordSchema").unwrap_or("marcxml");
+    if schema != "marcxml" && schema != MARCXML_SCHEMA {
+        return Err(Diagnostic::new(66, "Unknown schema for retrieval", schema));
+    }
+
+    let packing = request.param("recordPacking").unwrap_or("xml");
+    if packing != "xml" && packing != "string" {
+        return Err(Diagnostic::new(71, "Unsupported record packing", packing));
+    }
+
+    let start: i64 = match request.param("startRecord").unwrap_or("1").parse() {
+        Ok(s) if s > 0 => s,
+        _ => {
+            return Err(Diagnostic::new(
+                6,
+                "Unsupported parameter value",
+                "startRecord",
+            ))
+        }
+    };
+
+    let maximum: i64 = match request.param("maximumRecords") {
+        Some(m) => match m.parse::<i64>() {
+            Ok(m) if m >= 0 => m.min(options.max_records),
+            _ => {
+                return Err(Diagnostic::new(
+                    6,
+                    "Unsupported parameter value",
+                    "maximumRecords",
+                ))
+            }
+        },
+        None => options.default_records,
+    };
+
+    let (filter, params) = CqlCompiler::compile(query)?;
+
+    let sql_params: Vec<&(dyn pg::types::ToSql + Sync)> = params
+        .iter()
+        .map(|p| p as &(dyn pg::types::ToSql + Sync))
+        .collect();
+
+    let count_sql = format!(
+        "SELECT COUNT(*) AS count FROM biblio.record_entry bre WHERE NOT bre.deleted AND bre.id > 0 AND {filter}"
+    );
+
+    let count: i64 = con
+        .client()
+        .query_one(&count_sql[..], &sql_params)?
+        .get("count");
+
+    let mut xml = format!(
+        "<searchRetrieveResponse xmlns=\"{SRU_NS}\">\n<version>{SRU_VERSION}</version>\n<numberOfRecords>{count}</numberOfRecords>\n"
+    );
+
+    if count > 0 && start > count {
+        return Err(Diagnostic::new(
+            61,
+            "First record position out of range",
+            &start.to_string(),
+        ));
+    }
+
+    if count > 0 && maximum > 0 {
+        let sql = format!(
+            r#"
+            SELECT bre.marc
+            FROM biblio.record_entry bre
+            WHERE NOT bre.deleted AND bre.id > 0 AND {filter}
+            ORDER BY bre.id
+            OFFSET {} LIMIT {}
+            "#,
+            start - 1,
+            maximum
+        );
+
+        xml += "<records>\n";
+
+        let mut position = start;
+        for row in con.client().query(&sql[..], &sql_params)? {
+            let marc: &str = row.get("marc");
+
+            let data = match packing {
+                "xml" => strip_declaration(marc).to_string(),
+                _ => escape(strip_declaration(marc)),
+            };
+
+            xml += &format!(
+                "<record><recordSchema>{MARCXML_SCHEMA}</recordSchema><recordPacking>{packing}</recordPacking><recordData>{data}</recordData><recordPosition>{position}</recordPosition></record>\n"
+            );
+
+            position += 1;
+        }
+
+        xml += "</records>\n";
+
+        if position <= count {
+            xml += &format!("<nextRecordPosition>{position}</nextRecordPosition>\n");
+        }
+    }
+
+    xml += &format!(
+        "<echoedSearchRetrieveRequest><version>{SRU_VERSION}</version><query>{}</query><startRecord>{start}</startRecord><maximumRecords>{maximum}</maximumRecords><recordPacking>{}</recordPacking><recordSchema>{}</recordSchema></echoedSearchRetrieveRequest>\n",
+        escape(query),
+        escape(packing),
+        escape(schema),
+    );
+
+    xml += "</searchRetrieveResponse>\n";
+
+    Ok(xml)
+}
+
+/// Run the search using this thread's database connection, connecting
+/// first if necessary.
+fn with_connection<F>(template: &Mutex<DatabaseConnection>, f: F) -> Result<String, Diagnostic>
+where
+    F: FnOnce(&mut DatabaseConnection) -> Result<String, Diagnostic>,
+{
+    let result = db::with_thread_connection(template, f)
+        .unwrap_or_else(|e| Err(Diagnostic::new(1, "General system error", &e)));
+
+    if let Err(ref d) = result {
+        if d.code == 1 {
+            // Reconnect on the next request in case our connection is bad.
+            error!("{}", d.details);
+            db::reset_thread_connection();
+        }
+    }
+
+    result
+}
+
+fn handle_request(
+    template: &Mutex<DatabaseConnection>,
+    options: &ServerOptions,
+    request: &Request,
+) -> Response {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+
+    let operation = match request.param("operation") {
+        Some(o) => o,
+        None if request.param("query").is_some() => "searchRetrieve",
+        None => "explain",
+    };
+
+    if let Some(version) = request.param("version") {
+        if version != "1.1" && version != SRU_VERSION {
+            let diag = Diagnostic::new(5, "Unsupported version", version);
+            xml += &format!(
+                "<searchRetrieveResponse xmlns=\"{SRU_NS}\"><version>{SRU_VERSION}</version>{}</searchRetrieveResponse>\n",
+                diag.to_xml()
+            );
+            return Response::xml(xml);
+        }
+    }
+
+    match operation {
+        "explain" => xml += &explain(options),
+        "searchRetrieve" => {
+            match with_connection(template, |con| search_retrieve(con, options, request)) {
+                Ok(body) => xml += &body,
+                Err(diag) => {
+                    xml += &format!(
+                        "<searchRetrieveResponse xmlns=\"{SRU_NS}\"><version>{SRU_VERSION}</version><numberOfRecords>0</numberOfRecords>{}</searchRetrieveResponse>\n",
+                        diag.to_xml()
+                    );
+                }
+            }
+        }
+        _ => {
+            let diag = Diagnostic::new(4, "Unsupported operation", operation);
+            xml += &format!(
+                "<searchRetrieveResponse xmlns=\"{SRU_NS}\"><version>{SRU_VERSION}</version>{}</searchRetrieveResponse>\n",
+                diag.to_xml()
+            );
+        }
+    }
+
+    Response::xml(xml)
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, connection) = read_options();
+
+    info!("SRU server listening on {}", options.address);
+
+    let template = Arc::new(Mutex::new(connection));
+    let ops = options.clone();
+
+    http::serve(&options.address, options.max_threads, move |request| {
+        handle_request(&template, &ops, request)
+    })
+}
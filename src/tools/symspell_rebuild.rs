@@ -0,0 +1,437 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::lockfile;
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::sync::mpsc;
+use std::time::Instant;
+use threadpool::ThreadPool;
+
+const CLASSES: &[&str] = &[
+    "keyword",
+    "title",
+    "author",
+    "subject",
+    "series",
+    "identifier",
+];
+
+struct RebuildOptions {
+    classes: Vec<String>,
+    max_threads: usize,
+    batch_size: i64,
+    include_phrases: bool,
+    dry_run: DryRun,
+    lockfile: Option<String>,
+}
+
+/// Dictionary values for one prefix key, indexed like CLASSES.
+#[derive(Default)]
+struct DictEntry {
+    counts: [i64; 6],
+    suggestions: [Vec<String>; 6],
+}
+
+/// Output of one batch: (class index, prefix key, count, suggestions)
+type BatchEntries = Vec<(usize, String, i64, Vec<String>)>;
+
+fn read_options() -> (RebuildOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optmulti("", "class", "Search Class to Rebuild, Repeatable", "CLASS");
+    opts.optopt("", "max-threads", "Max Worker Threads", "MAX_THREADS");
+    opts.optopt("", "batch-size", "Field Entries per Batch", "BATCH_SIZE");
+    opts.optflag("", "include-phrases", "Include Phrase Suggestions");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    cli::append_lockfile(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let mut classes = params.opt_strs("class");
+    if classes.is_empty() {
+        classes = CLASSES.iter().map(|c| c.to_string()).collect();
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        RebuildOptions {
+            classes,
+            max_threads: params.opt_get_default("max-threads", 5).unwrap(),
+            batch_size: params.opt_get_default("batch-size", 10000).unwrap(),
+            include_phrases: params.opt_present("include-phrases"),
+            dry_run: DryRun::from_params(&params),
+            lockfile: params.opt_str("lockfile"),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin symspell-rebuild -- --max-threads 8
+
+Regenerates the search suggestion dictionary (search.symspell_dictionary)
+from the metabib field entries.
+
+Entries are generated in parallel batches with
+search.symspell_build_entries(), merged in memory, then loaded in a
+single transaction, replacing the existing values for each rebuilt
+class.
+
+Options
+
+    --class
+        Search class to rebuild.  Repeatable.  Defaults to all of:
+        keyword, title, author, subject, series, identifier.
+
+    --max-threads
+        Number of parallel database workers.  Defaults to 5.
+
+    --batch-size
+        Number of field entries processed per batch.  Defaults to 10000.
+
+    --include-phrases
+        Generate phrase suggestions as well as single words.
+
+{}
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::LOCKFILE_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn build_batch(
+    mut con: DatabaseConnection,
+    class_idx: usize,
+    low: i64,
+    high: i64,
+    include_phrases: bool,
+) -> Result<BatchEntries, String> {
+    let class = CLASSES[class_idx];
+
+    con.connect()?;
+
+    let sql = format!(
+        r#"
+        SELECT s.prefix_key, s.{class}_count AS count, s.{class}_suggestions AS suggestions
+        FROM metabib.{class}_field_entry fe,
+            LATERAL search.symspell_build_entries(fe.value, '{class}', NULL, $3) s
+        WHERE fe.id >= $1 AND fe.id < $2
+        "#
+    );
+
+    let rows = match con.client().query(&sql, &[&low, &high, &include_phrases]) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error building {class} entries {low}..{high}: {e}")),
+    };
+
+    con.disconnect();
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let count: Option<i32> = row.get("count");
+            let suggestions: Option<Vec<String>> = row.get("suggestions");
+            (
+                class_idx,
+                row.get("prefix_key"),
+                count.unwrap_or(0) as i64,
+                suggestions.unwrap_or_default(),
+            )
+        })
+        .collect())
+}
+
+fn build_entries(
+    con: &mut DatabaseConnection,
+    ops: &RebuildOptions,
+    dictionary: &mut HashMap<String, DictEntry>,
+) -> Result<(), String> {
+    let pool = ThreadPool::new(ops.max_threads);
+    let (sender, receiver) = mpsc::channel();
+    let mut batches = 0;
+
+    for class in &ops.classes {
+        let class_idx = match CLASSES.iter().position(|c| c == class) {
+            Some(i) => i,
+            None => return Err(format!("Unknown search class: {class}")),
+        };
+
+        let sql = format!(
+            "SELECT COALESCE(MIN(id), 0) AS min, COALESCE(MAX(id), 0) AS max FROM metabib.{class}_field_entry"
+        );
+
+        let (min, max): (i64, i64) = match con.client().query_one(sql.as_str(), &[]) {
+            Ok(row) => (row.get("min"), row.get("max")),
+            Err(e) => return Err(format!("Error reading {class} entry range: {e}")),
+        };
+
+        let mut low = min;
+        while low <= max {
+            let high = low + ops.batch_size;
+            let worker_con = con.partial_clone();
+            let tx = sender.clone();
+            let phrases = ops.include_phrases;
+
+            pool.execute(move || {
+                let result = build_batch(worker_con, class_idx, low, high, phrases);
+                tx.send(result).ok();
+            });
+
+            batches += 1;
+            low = high;
+        }
+
+        info!("Queued {class} entries {min}..{max}");
+    }
+
+    // Drop our sender so the receiver ends once the workers finish.
+    drop(sender);
+
+    let start = Instant::now();
+    let mut done = 0;
+    let mut failed = false;
+
+    for result in receiver {
+        done += 1;
+
+        let entries = match result {
+            Ok(e) => e,
+            Err(e) => {
+                error!("{e}");
+                failed = true;
+                continue;
+            }
+        };
+
+        for (class_idx, key, count, suggestions) in entries {
+            let entry = dictionary.entry(key).or_default();
+            entry.counts[class_idx] += count;
+
+            let list = &mut entry.suggestions[class_idx];
+            for s in suggestions {
+                if !list.contains(&s) {
+                    list.push(s);
+                }
+            }
+        }
+
+        if done % 10 == 0 || done == batches {
+            let elapsed = start.elapsed().as_secs_f64();
+            let remaining = elapsed / done as f64 * (batches - done) as f64;
+            info!(
+                "Completed {done}/{batches} batches, {} keys, about {:.0}s remaining",
+                dictionary.len(),
+                remaining
+            );
+        }
+    }
+
+    pool.join();
+
+    if failed {
+        return Err("One or more batches failed; dictionary not loaded".to_string());
+    }
+
+    Ok(())
+}
+
+/// Escape a value for COPY text format.
+fn copy_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Postgres array literal, or NULL for empty lists.
+fn array_literal(items: &[String]) -> String {
+    if items.is_empty() {
+        return "\\N".to_string();
+    }
+
+    let quoted: Vec<String> = items
+        .iter()
+        .map(|s| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+
+    copy_escape(&format!("{{{}}}", quoted.join(",")))
+}
+
+fn load_dictionary(
+    con: &mut DatabaseConnection,
+    ops: &RebuildOptions,
+    dictionary: &HashMap<String, DictEntry>,
+) -> Result<(), String> {
+    let counts: Vec<String> = CLASSES.iter().map(|c| format!("{c}_count")).collect();
+    let suggestions: Vec<String> = CLASSES.iter().map(|c| format!("{c}_suggestions")).collect();
+    let columns = format!(
+        "prefix_key, {}, {}",
+        counts.join(", "),
+        suggestions.join(", ")
+    );
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let sql = format!(
+        r#"
+        CREATE TEMP TABLE symspell_load ON COMMIT DROP AS
+        SELECT {columns} FROM search.symspell_dictionary LIMIT 0
+        "#
+    );
+
+    if let Err(e) = tx.batch_execute(&sql) {
+        return Err(format!("Cannot create load table: {e}"));
+    }
+
+    let mut writer = match tx.copy_in(&format!("COPY symspell_load ({columns}) FROM STDIN")) {
+        Ok(w) => w,
+        Err(e) => return Err(format!("Cannot start COPY: {e}")),
+    };
+
+    let idxs: Vec<usize> = ops
+        .classes
+        .iter()
+        .filter_map(|c| CLASSES.iter().position(|x| x == c))
+        .collect();
+
+    for (key, entry) in dictionary {
+        let mut line = copy_escape(key);
+
+        for count in entry.counts {
+            line += &format!("\t{count}");
+        }
+
+        for list in &entry.suggestions {
+            line += "\t";
+            line += &array_literal(list);
+        }
+
+        line += "\n";
+
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            return Err(format!("Error writing dictionary rows: {e}"));
+        }
+    }
+
+    if let Err(e) = writer.finish() {
+        return Err(format!("Error loading dictionary rows: {e}"));
+    }
+
+    info!("Loaded {} keys; merging into dictionary", dictionary.len());
+
+    // Clear the rebuilt classes then merge in the new values,
+    // leaving other classes untouched.
+    let resets: Vec<String> = idxs
+        .iter()
+        .map(|i| format!("{} = 0, {} = NULL", counts[*i], suggestions[*i]))
+        .collect();
+
+    let updates: Vec<String> = idxs
+        .iter()
+        .flat_map(|i| {
+            [
+                format!("{0} = EXCLUDED.{0}", counts[*i]),
+                format!("{0} = EXCLUDED.{0}", suggestions[*i]),
+            ]
+        })
+        .collect();
+
+    let empty: Vec<String> = counts
+        .iter()
+        .map(|c| format!("{c} = 0"))
+        .chain(suggestions.iter().map(|s| format!("{s} IS NULL")))
+        .collect();
+
+    let sqls = [
+        format!(
+            "UPDATE search.symspell_dictionary SET {}",
+            resets.join(", ")
+        ),
+        format!(
+            r#"
+            INSERT INTO search.symspell_dictionary ({columns})
+            SELECT {columns} FROM symspell_load
+            ON CONFLICT (prefix_key) DO UPDATE SET {}
+            "#,
+            updates.join(", ")
+        ),
+        format!(
+            "DELETE FROM search.symspell_dictionary WHERE {}",
+            empty.join(" AND ")
+        ),
+    ];
+
+    for sql in sqls {
+        debug!("{sql}");
+        if let Err(e) = tx.batch_execute(&sql) {
+            return Err(format!("Error updating dictionary: {e}"));
+        }
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    Ok(())
+}
+
+fn rebuild(con: &mut DatabaseConnection, ops: &RebuildOptions) -> Result<(), String> {
+    let start = Instant::now();
+
+    con.connect()?;
+
+    let mut dictionary = HashMap::new();
+    build_entries(con, ops, &mut dictionary)?;
+
+    info!(
+        "Built {} dictionary keys in {:.1}s",
+        dictionary.len(),
+        start.elapsed().as_secs_f64()
+    );
+
+    load_dictionary(con, ops, &dictionary)?;
+
+    audit::count("keys", dictionary.len() as u64);
+
+    info!(
+        "Dictionary {} in {:.1}s",
+        ops.dry_run.past("rebuilt"),
+        start.elapsed().as_secs_f64()
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let _lock = match &options.lockfile {
+        Some(path) => Some(lockfile::acquire(path)?),
+        None => None,
+    };
+
+    audit::finish(rebuild(&mut connection, &options))
+}
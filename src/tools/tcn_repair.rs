@@ -0,0 +1,357 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use postgres::fallible_iterator::FallibleIterator;
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+
+const MAP_COLUMNS: &[&str] = &["record", "old_tcn", "new_tcn", "reason"];
+
+/// How replacement TCNs are made.
+enum Strategy {
+    /// The record ID.
+    Id,
+    /// The old TCN with -2, -3, etc. appended.
+    Suffix,
+    /// A prefix followed by the record ID.
+    Prefix(String),
+}
+
+impl Strategy {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "id" => Ok(Strategy::Id),
+            "suffix" => Ok(Strategy::Suffix),
+            _ => match value.strip_prefix("prefix:") {
+                Some(p) if !p.is_empty() => Ok(Strategy::Prefix(p.to_string())),
+                _ => Err(format!("Invalid --strategy: {value}")),
+            },
+        }
+    }
+}
+
+struct RepairOptions {
+    strategy: String,
+    keep_newest: bool,
+    pattern: String,
+    max_length: Option<usize>,
+    staff: i32,
+    map_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// A record whose TCN is replaced.
+struct Fix {
+    record: i64,
+    old_tcn: String,
+    new_tcn: String,
+    reason: String,
+}
+
+fn read_options() -> (RepairOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "strategy", "Renumbering Strategy", "STRATEGY");
+    opts.optflag("", "keep-newest", "Newest Duplicate Keeps the TCN");
+    opts.optopt("", "pattern", "Valid TCN Pattern", "REGEX");
+    opts.optopt("", "max-length", "Maximum TCN Length", "LENGTH");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "map-file", "Mapping Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        RepairOptions {
+            strategy: params
+                .opt_get_default("strategy", "suffix".to_string())
+                .unwrap(),
+            keep_newest: params.opt_present("keep-newest"),
+            pattern: params
+                .opt_get_default("pattern", r"^\S+$".to_string())
+                .unwrap(),
+            max_length: params.opt_get("max-length").unwrap(),
+            staff: cli::required(&params, "staff"),
+            map_file: params.opt_str("map-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin tcn-repair -- --staff 1 --dry-run --map-file tcn-fixes.csv
+    cargo run --bin tcn-repair -- --strategy prefix:EG --max-length 20 --staff 1
+
+Finds duplicate and malformed TCNs among live bib records and gives
+the records new, unique TCNs, as needed before union catalog syncs.
+
+Of records sharing a TCN, the oldest (lowest ID) keeps it, or the
+newest with --keep-newest.  A TCN is malformed when it does not match
+--pattern or is longer than --max-length.
+
+Every change is written to the mapping file, with columns record,
+old_tcn, new_tcn and reason.  Changes are made in one transaction.
+Evergreen copies the new TCN into the record's 901 $a.
+
+Strategies
+
+    suffix
+        The old TCN with -2, -3, etc. appended.  Malformed TCNs are
+        replaced with the record ID.  This is the default.
+
+    id
+        The record ID.
+
+    prefix:PREFIX
+        The prefix followed by the record ID, e.g. prefix:EG.
+
+Replacements which are already in use get -2, -3, etc. appended.
+
+Options
+
+    --strategy
+        Renumbering strategy.  Defaults to suffix.
+
+    --keep-newest
+        The newest record sharing a TCN keeps it.
+
+    --pattern
+        Regular expression valid TCNs match.  Defaults to ^\S+$, any
+        value without spaces.
+
+    --max-length
+        TCNs longer than this are malformed.
+
+    --staff
+        Staff user recorded as record editor.  Required.
+
+    --map-file
+        Write the mapping to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+/// Record IDs by TCN, for live records.
+fn load_tcns(con: &mut DatabaseConnection) -> Result<BTreeMap<String, Vec<i64>>, String> {
+    let sql = r#"
+        SELECT id, COALESCE(tcn_value, '') AS tcn_value
+        FROM biblio.record_entry
+        WHERE NOT deleted AND id > 0
+        ORDER BY id
+    "#;
+
+    let params: [&(dyn postgres::types::ToSql + Sync); 0] = [];
+
+    let mut rows = match con.client().query_raw(sql, params) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Error selecting records: {e}")),
+    };
+
+    let mut tcns: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+
+    loop {
+        let row = match rows.next() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => return Err(format!("Error reading records: {e}")),
+        };
+
+        tcns.entry(row.get("tcn_value"))
+            .or_default()
+            .push(row.get("id"));
+    }
+
+    Ok(tcns)
+}
+
+/// A replacement TCN not yet in use.
+fn new_tcn(
+    strategy: &Strategy,
+    record: i64,
+    old: &str,
+    malformed: bool,
+    used: &HashSet<String>,
+) -> String {
+    let base = match strategy {
+        Strategy::Id => record.to_string(),
+        Strategy::Prefix(p) => format!("{p}{record}"),
+        Strategy::Suffix if malformed => record.to_string(),
+        Strategy::Suffix => old.to_string(),
+    };
+
+    // Suffixed TCNs start at -2, the original being the first.
+    let try_base = malformed || !matches!(strategy, Strategy::Suffix);
+
+    if try_base && !used.contains(&base) {
+        return base;
+    }
+
+    let mut n = 2;
+    loop {
+        let value = format!("{base}-{n}");
+        if !used.contains(&value) {
+            return value;
+        }
+        n += 1;
+    }
+}
+
+fn find_fixes(
+    ops: &RepairOptions,
+    strategy: &Strategy,
+    tcns: &BTreeMap<String, Vec<i64>>,
+) -> Result<Vec<Fix>, String> {
+    let pattern = match Regex::new(&ops.pattern) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Invalid --pattern {}: {e}", ops.pattern)),
+    };
+
+    let mut used: HashSet<String> = tcns.keys().cloned().collect();
+    let mut fixes = Vec::new();
+
+    for (tcn, ids) in tcns {
+        let malformed = !pattern.is_match(tcn)
+            || ops
+                .max_length
+                .map(|m| tcn.chars().count() > m)
+                .unwrap_or(false);
+
+        // Malformed TCNs are replaced for every record.  Otherwise,
+        // one duplicate keeps the TCN.
+        let keeper = match (malformed, ops.keep_newest) {
+            (true, _) => None,
+            (false, true) => ids.last().copied(),
+            (false, false) => ids.first().copied(),
+        };
+
+        for id in ids {
+            if Some(*id) == keeper {
+                continue;
+            }
+
+            let value = new_tcn(strategy, *id, tcn, malformed, &used);
+            used.insert(value.to_string());
+
+            let reason = match keeper {
+                Some(k) => format!("Duplicate of record {k}"),
+                None => "Malformed".to_string(),
+            };
+
+            fixes.push(Fix {
+                record: *id,
+                old_tcn: tcn.to_string(),
+                new_tcn: value,
+                reason,
+            });
+        }
+    }
+
+    fixes.sort_by_key(|f| f.record);
+
+    Ok(fixes)
+}
+
+fn repair(con: &mut DatabaseConnection, ops: &RepairOptions) -> Result<(), String> {
+    let strategy = Strategy::parse(&ops.strategy)?;
+
+    con.connect()?;
+
+    let tcns = load_tcns(con)?;
+    let fixes = find_fixes(ops, &strategy, &tcns)?;
+
+    let duplicates = tcns.values().filter(|ids| ids.len() > 1).count();
+    info!(
+        "Found {duplicates} duplicated TCNs; {} records need new TCNs",
+        fixes.len()
+    );
+
+    let mut writer = TableWriter::for_path(ops.map_file.as_deref(), Format::Csv)?;
+    writer.write_header(MAP_COLUMNS)?;
+
+    for fix in &fixes {
+        writer.write_row(&[
+            Cell::from(fix.record),
+            Cell::from(&fix.old_tcn),
+            Cell::from(&fix.new_tcn),
+            Cell::from(&fix.reason),
+        ])?;
+    }
+
+    writer.finish()?;
+
+    if fixes.is_empty() {
+        con.disconnect();
+        return Ok(());
+    }
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let sql = r#"
+        UPDATE biblio.record_entry
+        SET tcn_value = $2, editor = $3, edit_date = NOW()
+        WHERE id = $1 AND COALESCE(tcn_value, '') = $4
+    "#;
+
+    let mut renumbered = 0;
+
+    for fix in &fixes {
+        let count = tx
+            .execute(sql, &[&fix.record, &fix.new_tcn, &ops.staff, &fix.old_tcn])
+            .map_err(|e| db_err(&format!("Error updating record {}", fix.record), e))?;
+
+        if count == 0 {
+            warn!("Record {} changed during the repair; skipped", fix.record);
+        } else {
+            renumbered += 1;
+        }
+    }
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("records", renumbered as u64);
+
+    info!("{} {renumbered} records", ops.dry_run.past("Renumbered"));
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(repair(&mut connection, &options))
+}
@@ -0,0 +1,669 @@
+use crate::audit;
+use crate::cli;
+use crate::date::{Interval, UtcTime};
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::info;
+use postgres as pg;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// config.copy_status "In transit"
+const IN_TRANSIT: i32 = 6;
+
+const REPORT_COLUMNS: &[&str] = &[
+    "library",
+    "problem",
+    "transit",
+    "hold",
+    "copy",
+    "barcode",
+    "copy_status",
+    "source",
+    "dest",
+    "send_time",
+    "age_days",
+    "action",
+];
+
+struct TransitOptions {
+    policy_file: Option<String>,
+    stale_age: Option<String>,
+    org_unit: Option<String>,
+    staff: Option<i32>,
+    out_file: Option<String>,
+    out_dir: Option<String>,
+    dry_run: DryRun,
+}
+
+/// What to do about problem transits for a destination library.
+#[derive(Clone)]
+struct Policy {
+    stale_age: String,
+    /// Problems whose transits are canceled.
+    cancel: Vec<String>,
+    cancel_status: i32,
+    /// Transits open this long are canceled and their copies marked
+    /// missing_status.
+    missing_age: Option<String>,
+    missing_status: i32,
+    /// Reset copies In transit with no open transit to cancel_status.
+    reset_orphans: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            stale_age: "30 days".to_string(),
+            cancel: Vec::new(),
+            cancel_status: 7,
+            missing_age: None,
+            missing_status: 4,
+            reset_orphans: false,
+        }
+    }
+}
+
+impl Policy {
+    /// A copy of this policy with values from a JSON object.
+    fn with_json(&self, json: &Value) -> Result<Policy, String> {
+        let mut policy = self.clone();
+
+        if let Some(age) = json["stale_age"].as_str() {
+            Interval::parse(age)?;
+            policy.stale_age = age.to_string();
+        }
+
+        if let Some(list) = json["cancel"].as_array() {
+            policy.cancel = Vec::new();
+            for problem in list {
+                match problem.as_str() {
+                    Some(p) if PROBLEMS.contains(&p) => policy.cancel.push(p.to_string()),
+                    _ => return Err(format!("Invalid cancel problem: {problem}")),
+                }
+            }
+        }
+
+        if let Some(age) = json["missing_age"].as_str() {
+            Interval::parse(age)?;
+            policy.missing_age = Some(age.to_string());
+        }
+
+        if let Some(s) = json["cancel_status"].as_i64() {
+            policy.cancel_status = s as i32;
+        }
+
+        if let Some(s) = json["missing_status"].as_i64() {
+            policy.missing_status = s as i32;
+        }
+
+        if let Some(r) = json["reset_orphans"].as_bool() {
+            policy.reset_orphans = r;
+        }
+
+        Ok(policy)
+    }
+
+    fn has_actions(&self) -> bool {
+        !self.cancel.is_empty() || self.missing_age.is_some() || self.reset_orphans
+    }
+}
+
+/// Problems a transit may have.
+const PROBLEMS: &[&str] = &["stale", "no_hold", "status"];
+
+/// Default policy, and overrides by destination library shortname.
+struct Policies {
+    default: Policy,
+    orgs: HashMap<String, Policy>,
+}
+
+impl Policies {
+    fn for_org(&self, shortname: &str) -> &Policy {
+        self.orgs.get(shortname).unwrap_or(&self.default)
+    }
+
+    fn has_actions(&self) -> bool {
+        self.default.has_actions() || self.orgs.values().any(|p| p.has_actions())
+    }
+}
+
+/// One report row.
+struct Finding {
+    library: String,
+    problems: Vec<&'static str>,
+    transit: Option<i32>,
+    hold: Option<i32>,
+    copy: i64,
+    barcode: String,
+    copy_status: String,
+    source: Option<String>,
+    dest: Option<String>,
+    send_time: Option<String>,
+    age_days: Option<i64>,
+    action: Option<Action>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Cancel,
+    Missing,
+    Reset,
+}
+
+impl Action {
+    fn label(&self) -> &'static str {
+        match self {
+            Action::Cancel => "cancel",
+            Action::Missing => "missing",
+            Action::Reset => "reset",
+        }
+    }
+}
+
+fn read_options() -> (TransitOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "policy", "Transit Policy File", "POLICY_FILE");
+    opts.optopt("", "stale-age", "Stale Transit Age", "INTERVAL");
+    opts.optopt(
+        "",
+        "org-unit",
+        "Limit to Org Unit and Descendants",
+        "SHORTNAME",
+    );
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "out-file", "Report Output File", "FILE");
+    opts.optopt("", "out-dir", "Per-Library Report Directory", "DIR");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        TransitOptions {
+            policy_file: params.opt_str("policy"),
+            stale_age: params.opt_str("stale-age"),
+            org_unit: params.opt_str("org-unit"),
+            staff: params.opt_get("staff").unwrap(),
+            out_file: params.opt_str("out-file"),
+            out_dir: params.opt_str("out-dir"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin transit-report -- --stale-age "45 days" --out-dir /tmp/transits
+    cargo run --bin transit-report -- --policy transits.json --staff 1 --dry-run
+
+Finds problem transits and reports them per destination library:
+
+    stale    Open transit sent longer ago than the stale age.
+    no_hold  Open hold transit whose hold was canceled, filled,
+             deleted or retargeted to another copy.
+    status   Open transit for a copy no longer In transit.
+    orphan   Copy In transit with no open transit, reported for
+             its circulating library.
+
+Policy
+
+With --policy, problem transits are canceled or aged as the policy
+says, in place of the quarterly hand-run cleanup SQL.  Libraries may
+override any default:
+
+    {{
+        "stale_age": "30 days",
+        "cancel": ["no_hold", "status"],
+        "cancel_status": 7,
+        "missing_age": "90 days",
+        "missing_status": 4,
+        "reset_orphans": true,
+        "orgs": {{
+            "BR1": {{"missing_age": "180 days"}}
+        }}
+    }}
+
+Canceled transits have their copy, if still In transit, set to
+cancel_status (default 7, Reshelving).  Transits open longer than
+missing_age are canceled and their copy set to missing_status
+(default 4, Missing).  Orphaned copies are set to cancel_status with
+reset_orphans.  Without a policy, nothing is changed.
+
+Options
+
+    --policy
+        Transit policy file.
+
+    --stale-age
+        Postgres interval after which an open transit is stale.
+        Overrides the policy.  Defaults to "30 days".
+
+    --org-unit
+        Only report transits to (and orphaned copies at) this org
+        unit and its descendants.
+
+    --staff
+        Staff user recorded as copy editor.  Required for policies
+        with actions.
+
+    --out-file
+        Write the report to this file.  Otherwise, writes to STDOUT.
+        Files ending in .tsv or .xlsx are written as TSV or a
+        spreadsheet.
+
+    --out-dir
+        Write one report per library to this directory, named
+        transits-LIBRARY-YYYYMMDD.csv, in place of --out-file.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn read_policies(ops: &TransitOptions) -> Result<Policies, String> {
+    let mut policies = Policies {
+        default: Policy::default(),
+        orgs: HashMap::new(),
+    };
+
+    if let Some(ref path) = ops.policy_file {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Cannot read {path}: {e}")),
+        };
+
+        let json: Value = match serde_json::from_str(&text) {
+            Ok(j) => j,
+            Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+        };
+
+        policies.default = policies.default.with_json(&json)?;
+
+        if let Some(orgs) = json["orgs"].as_object() {
+            for (shortname, org) in orgs {
+                let policy = policies.default.with_json(org)?;
+                policies.orgs.insert(shortname.to_string(), policy);
+            }
+        }
+    }
+
+    if let Some(ref age) = ops.stale_age {
+        Interval::parse(age)?;
+        policies.default.stale_age = age.to_string();
+        for policy in policies.orgs.values_mut() {
+            policy.stale_age = age.to_string();
+        }
+    }
+
+    Ok(policies)
+}
+
+fn seconds(interval: &str) -> i64 {
+    // Validated when the policy was read.
+    Interval::parse(interval)
+        .map(|i| i.approx_seconds())
+        .unwrap_or(0)
+}
+
+/// Limits $1 org unit, or everything when NULL.
+fn org_filter(column: &str) -> String {
+    format!("($1::INT IS NULL OR {column} IN (SELECT id FROM actor.org_unit_descendants($1::INT)))")
+}
+
+fn open_transits(
+    tx: &mut pg::Transaction,
+    policies: &Policies,
+    org_unit: Option<i32>,
+) -> Result<Vec<Finding>, String> {
+    let sql = format!(
+        r#"
+        SELECT
+            atc.id AS transit,
+            htc.hold,
+            atc.target_copy,
+            acp.barcode,
+            acp.status,
+            ccs.name AS copy_status,
+            src.shortname AS source,
+            dst.shortname AS dest,
+            atc.source_send_time::TEXT AS send_time,
+            EXTRACT(EPOCH FROM NOW() - atc.source_send_time)::BIGINT AS age,
+            (htc.id IS NOT NULL AND (
+                ahr.id IS NULL
+                OR ahr.cancel_time IS NOT NULL
+                OR ahr.fulfillment_time IS NOT NULL
+                OR ahr.current_copy IS DISTINCT FROM atc.target_copy
+            )) AS no_hold
+        FROM action.transit_copy atc
+            JOIN asset.copy acp ON acp.id = atc.target_copy
+            JOIN config.copy_status ccs ON ccs.id = acp.status
+            JOIN actor.org_unit src ON src.id = atc.source
+            JOIN actor.org_unit dst ON dst.id = atc.dest
+            LEFT JOIN action.hold_transit_copy htc ON htc.id = atc.id
+            LEFT JOIN action.hold_request ahr ON ahr.id = htc.hold
+        WHERE atc.dest_recv_time IS NULL
+            AND atc.cancel_time IS NULL
+            AND {}
+        ORDER BY dst.shortname, atc.source_send_time
+        "#,
+        org_filter("atc.dest")
+    );
+
+    let rows = tx
+        .query(sql.as_str(), &[&org_unit])
+        .map_err(|e| db_err("Error loading transits", e))?;
+
+    let mut findings = Vec::new();
+
+    for row in rows {
+        let dest: String = row.get("dest");
+        let policy = policies.for_org(&dest);
+        let age: i64 = row.get::<_, Option<i64>>("age").unwrap_or(0);
+        let status: i32 = row.get("status");
+
+        let mut problems = Vec::new();
+
+        if age > seconds(&policy.stale_age) {
+            problems.push("stale");
+        }
+        if row.get::<_, bool>("no_hold") {
+            problems.push("no_hold");
+        }
+        if status != IN_TRANSIT {
+            problems.push("status");
+        }
+
+        if problems.is_empty() {
+            continue;
+        }
+
+        let aged = match &policy.missing_age {
+            Some(a) => age > seconds(a),
+            None => false,
+        };
+
+        let action = if aged {
+            Some(Action::Missing)
+        } else if problems
+            .iter()
+            .any(|p| policy.cancel.iter().any(|c| c == p))
+        {
+            Some(Action::Cancel)
+        } else {
+            None
+        };
+
+        findings.push(Finding {
+            library: dest.to_string(),
+            problems,
+            transit: Some(row.get("transit")),
+            hold: row.get("hold"),
+            copy: row.get("target_copy"),
+            barcode: row.get("barcode"),
+            copy_status: row.get("copy_status"),
+            source: Some(row.get("source")),
+            dest: Some(dest),
+            send_time: row.get("send_time"),
+            age_days: Some(age / 86400),
+            action,
+        });
+    }
+
+    Ok(findings)
+}
+
+/// Copies In transit with no open transit.
+fn orphans(
+    tx: &mut pg::Transaction,
+    policies: &Policies,
+    org_unit: Option<i32>,
+) -> Result<Vec<Finding>, String> {
+    let sql = format!(
+        r#"
+        SELECT acp.id, acp.barcode, ccs.name AS copy_status, aou.shortname AS library
+        FROM asset.copy acp
+            JOIN config.copy_status ccs ON ccs.id = acp.status
+            JOIN actor.org_unit aou ON aou.id = acp.circ_lib
+        WHERE acp.status = {IN_TRANSIT}
+            AND NOT acp.deleted
+            AND {}
+            AND NOT EXISTS (
+                SELECT 1 FROM action.transit_copy atc
+                WHERE atc.target_copy = acp.id
+                    AND atc.dest_recv_time IS NULL
+                    AND atc.cancel_time IS NULL
+            )
+        ORDER BY aou.shortname, acp.id
+        "#,
+        org_filter("acp.circ_lib")
+    );
+
+    let rows = tx
+        .query(sql.as_str(), &[&org_unit])
+        .map_err(|e| db_err("Error loading orphaned copies", e))?;
+
+    let mut findings = Vec::new();
+
+    for row in rows {
+        let library: String = row.get("library");
+        let action = match policies.for_org(&library).reset_orphans {
+            true => Some(Action::Reset),
+            false => None,
+        };
+
+        findings.push(Finding {
+            library,
+            problems: vec!["orphan"],
+            transit: None,
+            hold: None,
+            copy: row.get("id"),
+            barcode: row.get("barcode"),
+            copy_status: row.get("copy_status"),
+            source: None,
+            dest: None,
+            send_time: None,
+            age_days: None,
+            action,
+        });
+    }
+
+    Ok(findings)
+}
+
+fn set_copy_status(
+    tx: &mut pg::Transaction,
+    copy: i64,
+    status: i32,
+    staff: i32,
+    only_in_transit: bool,
+) -> Result<(), String> {
+    let mut sql = String::from(
+        "UPDATE asset.copy SET status = $2, editor = $3::INT, edit_date = NOW() WHERE id = $1",
+    );
+
+    if only_in_transit {
+        sql += &format!(" AND status = {IN_TRANSIT}");
+    }
+
+    tx.execute(sql.as_str(), &[&copy, &status, &staff])
+        .map_err(|e| db_err(&format!("Error updating copy {copy}"), e))?;
+
+    Ok(())
+}
+
+fn apply(
+    tx: &mut pg::Transaction,
+    policies: &Policies,
+    staff: i32,
+    finding: &Finding,
+) -> Result<(), String> {
+    let action = match finding.action {
+        Some(a) => a,
+        None => return Ok(()),
+    };
+
+    let policy = policies.for_org(&finding.library);
+
+    if let Some(transit) = finding.transit {
+        tx.execute(
+            "UPDATE action.transit_copy SET cancel_time = NOW() WHERE id = $1",
+            &[&transit],
+        )
+        .map_err(|e| db_err(&format!("Error canceling transit {transit}"), e))?;
+    }
+
+    match action {
+        Action::Cancel => set_copy_status(tx, finding.copy, policy.cancel_status, staff, true),
+        Action::Reset => set_copy_status(tx, finding.copy, policy.cancel_status, staff, true),
+        Action::Missing => set_copy_status(tx, finding.copy, policy.missing_status, staff, false),
+    }
+}
+
+fn write_findings(writer: &mut TableWriter, findings: &[&Finding]) -> Result<(), String> {
+    writer.write_header(REPORT_COLUMNS)?;
+
+    for f in findings {
+        writer.write_row(&[
+            Cell::from(&f.library),
+            Cell::from(f.problems.join(" ")),
+            Cell::from(f.transit),
+            Cell::from(f.hold),
+            Cell::from(f.copy),
+            Cell::from(&f.barcode),
+            Cell::from(&f.copy_status),
+            Cell::from(f.source.as_ref()),
+            Cell::from(f.dest.as_ref()),
+            Cell::from(f.send_time.as_ref()),
+            Cell::from(f.age_days),
+            Cell::from(f.action.map(|a| a.label())),
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn write_report(ops: &TransitOptions, findings: &[Finding]) -> Result<(), String> {
+    let dir = match &ops.out_dir {
+        Some(d) => d,
+        None => {
+            let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+            write_findings(&mut writer, &findings.iter().collect::<Vec<_>>())?;
+            return writer.finish();
+        }
+    };
+
+    let mut by_library: BTreeMap<&str, Vec<&Finding>> = BTreeMap::new();
+    for finding in findings {
+        by_library
+            .entry(finding.library.as_str())
+            .or_default()
+            .push(finding);
+    }
+
+    let now = UtcTime::now();
+    let date = format!("{:04}{:02}{:02}", now.year, now.month, now.day);
+
+    for (library, list) in by_library {
+        let path = Path::new(dir).join(format!("transits-{library}-{date}.csv"));
+        let path = path.to_string_lossy();
+
+        let mut writer = TableWriter::create(&path, Format::Csv)?;
+        write_findings(&mut writer, &list)?;
+        writer.finish()?;
+
+        info!("{library}: {} problems written to {path}", list.len());
+    }
+
+    Ok(())
+}
+
+fn run(con: &mut DatabaseConnection, ops: &TransitOptions) -> Result<(), String> {
+    let policies = read_policies(ops)?;
+
+    let staff = match (policies.has_actions(), ops.staff) {
+        (true, Some(s)) => Some(s),
+        (true, None) => return Err("--staff is required for policies with actions".to_string()),
+        (false, _) => None,
+    };
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    let org_unit: Option<i32> = match &ops.org_unit {
+        Some(shortname) => {
+            let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+            match tx.query_opt(sql, &[&shortname]) {
+                Ok(Some(row)) => Some(row.get("id")),
+                Ok(None) => return Err(format!("No such org unit: {shortname}")),
+                Err(e) => return Err(db_err("Error loading org unit", e)),
+            }
+        }
+        None => None,
+    };
+
+    let mut findings = open_transits(&mut tx, &policies, org_unit)?;
+    findings.extend(orphans(&mut tx, &policies, org_unit)?);
+
+    let mut actions = 0;
+
+    if let Some(staff) = staff {
+        for finding in &findings {
+            if finding.action.is_some() {
+                apply(&mut tx, &policies, staff, finding)?;
+                actions += 1;
+            }
+        }
+    }
+
+    write_report(ops, &findings)?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("findings", findings.len() as u64);
+    audit::count("actions", actions as u64);
+
+    info!(
+        "Found {} problem transits and copies; {} {actions} policy actions",
+        findings.len(),
+        ops.dry_run.past("applied"),
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(run(&mut connection, &options))
+}
@@ -0,0 +1,640 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::marc::{self, LocatedUri};
+use crate::tabular::{Cell, Format, TableWriter};
+use crate::xml;
+use log::{info, warn};
+use marcutil::{Field, Record, Subfield};
+use postgres as pg;
+use std::collections::BTreeMap;
+use std::fs;
+
+const REPORT_COLUMNS: &[&str] = &["record", "line", "action", "old_url", "new_url", "owner"];
+
+struct UriOptions {
+    map_file: String,
+    min_id: i64,
+    max_id: i64,
+    query_file: Option<String>,
+    staff: i32,
+    out_file: Option<String>,
+    dry_run: DryRun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Add,
+    Update,
+    Remove,
+}
+
+/// Records a mapping row applies to.
+#[derive(Debug, Clone)]
+enum Target {
+    Record(i64),
+    Tcn(String),
+    Isbn(String),
+    Oclc(String),
+    /// Every record in the record set with the URL.
+    All,
+}
+
+/// One row of the mapping file.
+struct Mapping {
+    line: usize,
+    action: Action,
+    target: Target,
+    url: Option<String>,
+    old_url: Option<String>,
+    label: Option<String>,
+    note: Option<String>,
+    owner: Option<String>,
+}
+
+/// One change to a record, for the report.
+struct Change {
+    line: usize,
+    action: &'static str,
+    old_url: Option<String>,
+    new_url: Option<String>,
+    owner: String,
+}
+
+fn read_options() -> (UriOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "map-file", "CSV URI Mapping File", "MAP_FILE");
+    cli::append_id_range(&mut opts);
+    opts.optopt("", "query-file", "SQL Query File", "QUERY_FILE");
+    opts.optopt("", "staff", "Staff User ID", "USER_ID");
+    opts.optopt("", "out-file", "Change Report File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        UriOptions {
+            map_file: cli::required(&params, "map-file"),
+            min_id: params.opt_get_default("min-id", -1).unwrap(),
+            max_id: params.opt_get_default("max-id", -1).unwrap(),
+            query_file: params.opt_get("query-file").unwrap(),
+            staff: cli::required(&params, "staff"),
+            out_file: params.opt_str("out-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin uri-manage -- --map-file uris.csv --staff 1 --dry-run
+    cargo run --bin uri-manage -- --map-file migrate.csv --max-id 500000 --staff 1
+
+Adds, updates and removes 856 located URIs on bib records from a CSV
+mapping, in place of exporting and editing records elsewhere.  Located
+URIs are 856s with indicators 4 and 0 or 1 and the owning org unit
+shortname in $9.  Evergreen updates asset.uri as records are saved.
+
+The mapping has a header row naming its columns:
+
+    action
+        add, update or remove.  Defaults to add.
+
+    record, tcn, isbn, oclc
+        The record ID, or a match point, selecting the records a row
+        applies to.  Rows with none of these apply to every record
+        in the record set which has the URL, e.g. for migrations.
+
+    url
+        URL to add, the new URL for update, or the URL to remove.
+
+    old_url
+        URL replaced by update.
+
+    label, note
+        856 $y and $z.  Set on add, and replaced on update when
+        given.
+
+    owner
+        Owning org unit shortname.  Required for add.  Limits update
+        and remove to URIs with this owner.
+
+URLs to match, old_url for update and url for remove, ending in *
+match every URL starting with the rest.  On update, a url also ending
+in * replaces just that prefix, e.g.
+
+    action,old_url,url
+    update,http://old.example.com/*,https://new.example.com/*
+
+Adding a URL the record already has for the owner updates its label
+and note.  All changes are made in one transaction.
+
+Options
+
+    --map-file
+        CSV URI mapping.  Required.
+
+    --min-id
+    --max-id
+        Limit the record set to records with IDs in this range.
+
+    --query-file
+        Path to a file containing an SQL query producing the record
+        set, as rows with "id" and "marc" columns.
+
+    --staff
+        Staff user recorded as record editor.  Required.
+
+    --out-file
+        Write the change report to this file.  Otherwise, writes to
+        STDOUT.  Files ending in .tsv or .xlsx are written as TSV or
+        a spreadsheet.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn read_mappings(path: &str) -> Result<Vec<Mapping>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let cols = [
+        col("action"),
+        col("record"),
+        col("tcn"),
+        col("isbn"),
+        col("oclc"),
+        col("url"),
+        col("old_url"),
+        col("label"),
+        col("note"),
+        col("owner"),
+    ];
+
+    let mut mappings = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let [action, record, tcn, isbn, oclc, url, old_url, label, note, owner] = cols.map(|col| {
+            col.and_then(|c| fields.get(c))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        });
+
+        let line = idx + 1;
+
+        let action = match action.as_deref().map(|a| a.to_lowercase()).as_deref() {
+            None | Some("add") => Action::Add,
+            Some("update") => Action::Update,
+            Some("remove") => Action::Remove,
+            Some(a) => return Err(format!("{path} line {line}: invalid action {a}")),
+        };
+
+        let target = if let Some(r) = record {
+            match r.parse::<i64>() {
+                Ok(id) => Target::Record(id),
+                Err(_) => return Err(format!("{path} line {line}: invalid record {r}")),
+            }
+        } else if let Some(t) = tcn {
+            Target::Tcn(t)
+        } else if let Some(i) = isbn {
+            Target::Isbn(i)
+        } else if let Some(o) = oclc {
+            Target::Oclc(o)
+        } else {
+            Target::All
+        };
+
+        let problem = match action {
+            Action::Add if url.is_none() || owner.is_none() => Some("add requires url and owner"),
+            Action::Update if old_url.is_none() => Some("update requires old_url"),
+            Action::Remove if url.is_none() => Some("remove requires url"),
+            _ => None,
+        };
+
+        if let Some(p) = problem {
+            return Err(format!("{path} line {line}: {p}"));
+        }
+
+        mappings.push(Mapping {
+            line,
+            action,
+            target,
+            url,
+            old_url,
+            label,
+            note,
+            owner,
+        });
+    }
+
+    Ok(mappings)
+}
+
+/// True if the URL matches the pattern, which may end in *.
+fn url_matches(pattern: &str, url: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => url.starts_with(prefix),
+        None => pattern == url,
+    }
+}
+
+/// The URL after an update from old_pattern to new_pattern.
+fn updated_url(old_pattern: &str, new_pattern: &str, url: &str) -> String {
+    match (old_pattern.strip_suffix('*'), new_pattern.strip_suffix('*')) {
+        (Some(old), Some(new)) => format!("{new}{}", &url[old.len()..]),
+        _ => new_pattern.to_string(),
+    }
+}
+
+fn create_sql(ops: &UriOptions) -> Result<String, String> {
+    if let Some(ref fname) = ops.query_file {
+        return match fs::read_to_string(fname) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(format!("Cannot read {fname}: {e}")),
+        };
+    }
+
+    let mut sql =
+        String::from("SELECT id, marc FROM biblio.record_entry WHERE NOT deleted AND id > 0");
+
+    if ops.min_id > -1 {
+        sql += &format!(" AND id >= {}", ops.min_id);
+    }
+
+    if ops.max_id > -1 {
+        sql += &format!(" AND id <= {}", ops.max_id);
+    }
+
+    Ok(sql + " ORDER BY id")
+}
+
+/// IDs of the records a mapping row applies to.
+fn target_records(
+    tx: &mut pg::Transaction,
+    record_set: &str,
+    mapping: &Mapping,
+) -> Result<Vec<i64>, String> {
+    let (sql, value) = match &mapping.target {
+        Target::Record(id) => {
+            let sql = "SELECT id FROM biblio.record_entry WHERE id = $1 AND NOT deleted";
+            let rows = tx
+                .query(sql, &[id])
+                .map_err(|e| db_err("Error loading record", e))?;
+            return Ok(rows.iter().map(|r| r.get("id")).collect());
+        }
+        Target::Tcn(tcn) => (
+            "SELECT id FROM biblio.record_entry WHERE tcn_value = $1 AND NOT deleted".to_string(),
+            tcn.to_string(),
+        ),
+        Target::Isbn(isbn) => (
+            r#"
+                SELECT DISTINCT mfr.record AS id
+                FROM metabib.real_full_rec mfr
+                    JOIN biblio.record_entry bre ON bre.id = mfr.record
+                WHERE mfr.tag = '020' AND mfr.subfield = 'a'
+                    AND mfr.value LIKE $1 AND NOT bre.deleted
+            "#
+            .to_string(),
+            format!("{}%", isbn.replace('-', "").to_lowercase()),
+        ),
+        // 035 values are NACO normalized, e.g. "ocolc ocm00012345".
+        Target::Oclc(oclc) => (
+            r#"
+                SELECT DISTINCT mfr.record AS id
+                FROM metabib.real_full_rec mfr
+                    JOIN biblio.record_entry bre ON bre.id = mfr.record
+                WHERE mfr.tag = '035' AND mfr.subfield = 'a'
+                    AND mfr.value ~ $1 AND NOT bre.deleted
+            "#
+            .to_string(),
+            format!(
+                r"^ocolc\s*[a-z]*0*{}$",
+                oclc.trim_start_matches(|c: char| !c.is_ascii_digit())
+                    .trim_start_matches('0')
+            ),
+        ),
+        Target::All => {
+            // The URL being updated or removed, as it appears in the
+            // stored MARCXML.
+            let url = match mapping.action {
+                Action::Update => mapping.old_url.as_deref(),
+                _ => mapping.url.as_deref(),
+            };
+            let text = xml::escape(url.unwrap_or("").trim_end_matches('*'));
+
+            (
+                format!(
+                    "SELECT q.id FROM ({record_set}) q WHERE STRPOS(q.marc, $1) > 0 ORDER BY q.id"
+                ),
+                text,
+            )
+        }
+    };
+
+    let rows = tx.query(sql.as_str(), &[&value]).map_err(|e| {
+        db_err(
+            &format!("Error finding records for line {}", mapping.line),
+            e,
+        )
+    })?;
+
+    Ok(rows.iter().map(|r| r.get("id")).collect())
+}
+
+/// Set the first subfield with the code, adding one if needed.
+/// Returns true if the field changed.
+fn set_subfield(field: &mut Field, code: &str, value: &str) -> bool {
+    if let Some(sf) = field.subfields.iter_mut().find(|sf| sf.code == code) {
+        if sf.content == value {
+            return false;
+        }
+        sf.content = value.to_string();
+        return true;
+    }
+
+    // Keep $9 last, as Evergreen writes it.
+    let pos = field
+        .subfields
+        .iter()
+        .position(|sf| sf.code == "9")
+        .unwrap_or(field.subfields.len());
+
+    field.subfields.insert(
+        pos,
+        Subfield {
+            code: code.to_string(),
+            content: value.to_string(),
+        },
+    );
+
+    true
+}
+
+/// Set the label and note given in the mapping.
+fn set_label(field: &mut Field, mapping: &Mapping) -> bool {
+    let mut changed = false;
+
+    for (code, value) in [("y", &mapping.label), ("z", &mapping.note)] {
+        if let Some(v) = value {
+            changed |= set_subfield(field, code, v);
+        }
+    }
+
+    changed
+}
+
+/// Apply one mapping row to a record.
+fn apply(record: &mut Record, mapping: &Mapping) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    let owner_matches = |uri: &LocatedUri| match &mapping.owner {
+        Some(o) => o == &uri.owner,
+        None => true,
+    };
+
+    match mapping.action {
+        Action::Add => {
+            // Guaranteed by read_mappings.
+            let url = mapping.url.as_deref().unwrap_or("");
+            let owner = mapping.owner.as_deref().unwrap_or("");
+
+            let existing = record.fields.iter_mut().find(|f| {
+                LocatedUri::from_field(f)
+                    .map(|u| u.url == url && u.owner == owner)
+                    .unwrap_or(false)
+            });
+
+            if let Some(field) = existing {
+                if set_label(field, mapping) {
+                    changes.push(Change {
+                        line: mapping.line,
+                        action: "relabel",
+                        old_url: Some(url.to_string()),
+                        new_url: Some(url.to_string()),
+                        owner: owner.to_string(),
+                    });
+                }
+
+                return changes;
+            }
+
+            let uri = LocatedUri {
+                url: url.to_string(),
+                label: mapping.label.clone(),
+                note: mapping.note.clone(),
+                owner: owner.to_string(),
+            };
+
+            marc::insert_field(record, uri.to_field());
+
+            changes.push(Change {
+                line: mapping.line,
+                action: "add",
+                old_url: None,
+                new_url: Some(uri.url),
+                owner: uri.owner,
+            });
+        }
+
+        Action::Update => {
+            let old_pattern = mapping.old_url.as_deref().unwrap_or("");
+
+            for field in record.fields.iter_mut() {
+                let uri = match LocatedUri::from_field(field) {
+                    Some(u) if url_matches(old_pattern, &u.url) && owner_matches(&u) => u,
+                    _ => continue,
+                };
+
+                let new_url = match &mapping.url {
+                    Some(pattern) => updated_url(old_pattern, pattern, &uri.url),
+                    None => uri.url.to_string(),
+                };
+
+                let moved = set_subfield(field, "u", &new_url);
+                let relabeled = set_label(field, mapping);
+
+                if moved || relabeled {
+                    changes.push(Change {
+                        line: mapping.line,
+                        action: "update",
+                        old_url: Some(uri.url),
+                        new_url: Some(new_url),
+                        owner: uri.owner,
+                    });
+                }
+            }
+        }
+
+        Action::Remove => {
+            let pattern = mapping.url.as_deref().unwrap_or("");
+
+            record.fields.retain(|field| {
+                let uri = match LocatedUri::from_field(field) {
+                    Some(u) if url_matches(pattern, &u.url) && owner_matches(&u) => u,
+                    _ => return true,
+                };
+
+                changes.push(Change {
+                    line: mapping.line,
+                    action: "remove",
+                    old_url: Some(uri.url),
+                    new_url: None,
+                    owner: uri.owner,
+                });
+
+                false
+            });
+        }
+    }
+
+    changes
+}
+
+fn manage(con: &mut DatabaseConnection, ops: &UriOptions) -> Result<(), String> {
+    let mappings = read_mappings(&ops.map_file)?;
+    let record_set = create_sql(ops)?;
+
+    info!("Read {} URI mappings", mappings.len());
+
+    let mut writer = TableWriter::for_path(ops.out_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    // Rows for each record, applied in file order.
+    let mut records: BTreeMap<i64, Vec<&Mapping>> = BTreeMap::new();
+
+    for mapping in &mappings {
+        let ids = target_records(&mut tx, &record_set, mapping)?;
+
+        if ids.is_empty() {
+            warn!("Line {}: no matching records", mapping.line);
+        }
+
+        for id in ids {
+            records.entry(id).or_default().push(mapping);
+        }
+    }
+
+    let select = "SELECT marc FROM biblio.record_entry WHERE id = $1";
+
+    let update = r#"
+        UPDATE biblio.record_entry
+        SET marc = $1, editor = $2, edit_date = NOW()
+        WHERE id = $3
+    "#;
+
+    let mut modified = 0;
+    let mut total = 0;
+
+    for (id, list) in &records {
+        let row = tx
+            .query_one(select, &[id])
+            .map_err(|e| db_err(&format!("Error loading record {id}"), e))?;
+
+        let mut record = match Record::from_xml(row.get("marc")).next() {
+            Some(r) => r,
+            None => {
+                warn!("Record {id} cannot be parsed; skipping");
+                continue;
+            }
+        };
+
+        let mut changes = Vec::new();
+        for mapping in list {
+            changes.extend(apply(&mut record, mapping));
+        }
+
+        if changes.is_empty() {
+            continue;
+        }
+
+        for change in &changes {
+            writer.write_row(&[
+                Cell::from(*id),
+                Cell::from(change.line),
+                Cell::from(change.action),
+                Cell::from(change.old_url.as_ref()),
+                Cell::from(change.new_url.as_ref()),
+                Cell::from(&change.owner),
+            ])?;
+        }
+
+        total += changes.len();
+        modified += 1;
+
+        tx.execute(update, &[&record.to_xml()?, &ops.staff, id])
+            .map_err(|e| db_err(&format!("Error updating record {id}"), e))?;
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("changes", total as u64);
+    audit::count("records", modified as u64);
+
+    info!(
+        "{} {total} URI changes to {modified} records",
+        ops.dry_run.past("Made")
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    audit::finish(manage(&mut connection, &options))
+}
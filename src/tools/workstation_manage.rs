@@ -0,0 +1,494 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::tabular::{Cell, Format, TableWriter};
+use log::{info, warn};
+use postgres as pg;
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+
+const ACTIONS: &[&str] = &["register", "rename", "purge"];
+
+const REPORT_COLUMNS: &[&str] = &[
+    "line",
+    "action",
+    "name",
+    "org_unit",
+    "new_name",
+    "workstation",
+    "result",
+];
+
+struct ManageOptions {
+    import_file: Option<String>,
+    action: Option<String>,
+    org_unit: Option<String>,
+    prefix: bool,
+    report_file: Option<String>,
+    dry_run: DryRun,
+}
+
+/// One line of the import file.
+struct Change {
+    line: usize,
+    action: String,
+    name: String,
+    org_unit: Option<String>,
+    new_name: Option<String>,
+}
+
+fn read_options() -> (ManageOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "import-file", "Workstation CSV File", "FILE");
+    opts.optopt("", "action", "Default Action", "ACTION");
+    opts.optopt("", "org-unit", "Default Org Unit", "SHORTNAME");
+    opts.optflag("", "prefix", "Prefix Names with the Org Unit Shortname");
+    opts.optflag("", "export", "Export Workstations as CSV");
+    opts.optopt("", "report-file", "Report Output File", "FILE");
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let import_file = params.opt_str("import-file");
+    let export = params.opt_present("export");
+
+    if import_file.is_some() == export {
+        eprintln!("One of --import-file or --export is required");
+        process::exit(2);
+    }
+
+    let action = params.opt_str("action");
+    if let Some(ref a) = action {
+        if !ACTIONS.contains(&a.as_str()) {
+            eprintln!("Unknown action: {a}");
+            process::exit(2);
+        }
+    }
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        ManageOptions {
+            import_file,
+            action,
+            org_unit: params.opt_str("org-unit"),
+            prefix: params.opt_present("prefix"),
+            report_file: params.opt_str("report-file"),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin workstation-manage -- --import-file lab-machines.csv --org-unit BR1 \
+        --action register --prefix --dry-run
+    cargo run --bin workstation-manage -- --export --org-unit SYS1 --report-file workstations.csv
+
+Registers, renames and purges workstations in bulk from a CSV file,
+e.g. after reimaging a lab of staff machines.
+
+Import files are CSV with a header row and these columns:
+
+    action
+        register, rename or purge.  Defaults to --action.
+
+    name
+        Workstation name.  Required.
+
+    org_unit
+        Owning org unit shortname.  Defaults to --org-unit.  Required
+        to register.  When given to rename or purge, the workstation
+        must belong to this org unit.
+
+    new_name
+        New workstation name.  Required to rename.
+
+Registering a name already registered to the same org unit is
+reported and skipped.  Workstations still referenced, e.g. by
+circulations or payments, cannot be purged; rename them instead.
+
+Each line is processed in its own savepoint, so failures leave the
+rest in place.  Every line is written to the report with its result.
+
+--export writes the workstations owned at or below --org-unit in the
+import format, with action purge, as a starting point for an edited
+import file.
+
+Options
+
+    --import-file
+        Path to the workstation CSV file.
+
+    --action
+        Action for lines without one: register, rename or purge.
+
+    --org-unit
+        Org unit for lines without one, and the org unit to export.
+        Defaults to all org units for --export.
+
+    --prefix
+        Prefix names and new names with the org unit shortname and a
+        hyphen, as the staff client does, unless already prefixed.
+
+    --export
+        Export workstations instead of importing.
+
+    --report-file
+        Write the report or export to this file.  Otherwise, writes
+        to STDOUT.
+
+{}
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options.  PG environment vars are used
+        as defaults when available.
+
+{}
+    "#,
+        cli::DRY_RUN_HELP,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+fn db_err(context: &str, e: pg::Error) -> String {
+    format!("{context}: {e}")
+}
+
+fn read_import(path: &str, ops: &ManageOptions) -> Result<Vec<Change>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let mut lines = text.lines().enumerate();
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, h)) => h.split(',').map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let col = |name: &str| header.iter().position(|h| h == name);
+
+    let name_col = match col("name") {
+        Some(c) => c,
+        None => return Err(format!("{path} requires a name column")),
+    };
+    let action_col = col("action");
+    let org_col = col("org_unit");
+    let new_name_col = col("new_name");
+
+    let mut changes = Vec::new();
+
+    for (idx, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|f| f.trim().trim_matches('"'))
+            .collect();
+
+        let field = |col: Option<usize>| {
+            col.and_then(|c| fields.get(c))
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+
+        let name = match field(Some(name_col)) {
+            Some(n) => n,
+            None => {
+                warn!("Line {}: name required: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        let action = match field(action_col).or(ops.action.clone()) {
+            Some(a) => a.to_lowercase(),
+            None => {
+                warn!("Line {}: action required: {line}", idx + 1);
+                continue;
+            }
+        };
+
+        changes.push(Change {
+            line: idx + 1,
+            action,
+            name,
+            org_unit: field(org_col).or(ops.org_unit.clone()),
+            new_name: field(new_name_col),
+        });
+    }
+
+    Ok(changes)
+}
+
+fn org_id(
+    tx: &mut pg::Transaction,
+    cache: &mut HashMap<String, i32>,
+    shortname: &str,
+) -> Result<i32, String> {
+    if let Some(id) = cache.get(shortname) {
+        return Ok(*id);
+    }
+
+    let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+
+    let id = match tx.query_opt(sql, &[&shortname]) {
+        Ok(Some(row)) => row.get("id"),
+        Ok(None) => return Err(format!("No such org unit: {shortname}")),
+        Err(e) => return Err(db_err("Error looking up org unit", e)),
+    };
+
+    cache.insert(shortname.to_string(), id);
+
+    Ok(id)
+}
+
+/// The name with the org unit prefix, when --prefix is used.
+fn prefixed(ops: &ManageOptions, org_unit: Option<&str>, name: &str) -> String {
+    match org_unit {
+        Some(short) if ops.prefix && !name.starts_with(&format!("{short}-")) => {
+            format!("{short}-{name}")
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// ID and owning org unit of a registered workstation.
+fn find_workstation(tx: &mut pg::Transaction, name: &str) -> Result<Option<(i32, i32)>, String> {
+    let sql = "SELECT id, owning_lib FROM actor.workstation WHERE name = $1";
+
+    match tx.query_opt(sql, &[&name]) {
+        Ok(row) => Ok(row.map(|r| (r.get("id"), r.get("owning_lib")))),
+        Err(e) => Err(db_err("Error looking up workstation", e)),
+    }
+}
+
+/// Apply one change.  Returns the workstation ID and the result.
+fn apply(
+    tx: &mut pg::Transaction,
+    ops: &ManageOptions,
+    orgs: &mut HashMap<String, i32>,
+    change: &Change,
+) -> Result<(Option<i32>, String), String> {
+    let org = match change.org_unit.as_deref() {
+        Some(s) => Some(org_id(tx, orgs, s)?),
+        None => None,
+    };
+
+    let name = prefixed(ops, change.org_unit.as_deref(), &change.name);
+    let existing = find_workstation(tx, &name)?;
+
+    if change.action == "register" {
+        let org = match org {
+            Some(o) => o,
+            None => return Err("org_unit required to register".to_string()),
+        };
+
+        if let Some((id, owner)) = existing {
+            return match owner == org {
+                true => Ok((Some(id), "Already registered".to_string())),
+                false => Err(format!("{name} is registered to another org unit")),
+            };
+        }
+
+        let sql = "INSERT INTO actor.workstation (name, owning_lib) VALUES ($1, $2) RETURNING id";
+
+        return match tx.query_one(sql, &[&name, &org]) {
+            Ok(row) => Ok((Some(row.get("id")), "Registered".to_string())),
+            Err(e) => Err(db_err("Error registering workstation", e)),
+        };
+    }
+
+    let (id, owner) = match existing {
+        Some(w) => w,
+        None => return Err(format!("No such workstation: {name}")),
+    };
+
+    if org.is_some_and(|o| o != owner) {
+        return Err(format!("{name} is registered to another org unit"));
+    }
+
+    if change.action == "rename" {
+        let new_name = match change.new_name.as_deref() {
+            Some(n) => prefixed(ops, change.org_unit.as_deref(), n),
+            None => return Err("new_name required to rename".to_string()),
+        };
+
+        if find_workstation(tx, &new_name)?.is_some() {
+            return Err(format!("{new_name} is already registered"));
+        }
+
+        let sql = "UPDATE actor.workstation SET name = $2 WHERE id = $1";
+
+        return match tx.execute(sql, &[&id, &new_name]) {
+            Ok(_) => Ok((Some(id), "Renamed".to_string())),
+            Err(e) => Err(db_err("Error renaming workstation", e)),
+        };
+    }
+
+    let sql = "DELETE FROM actor.workstation WHERE id = $1";
+
+    match tx.execute(sql, &[&id]) {
+        Ok(_) => Ok((Some(id), "Purged".to_string())),
+        Err(e) => Err(db_err("Workstation in use", e)),
+    }
+}
+
+fn import(con: &mut DatabaseConnection, ops: &ManageOptions, path: &str) -> Result<(), String> {
+    let changes = read_import(path, ops)?;
+
+    let mut writer = TableWriter::for_path(ops.report_file.as_deref(), Format::Csv)?;
+    writer.write_header(REPORT_COLUMNS)?;
+
+    con.connect()?;
+
+    let mut tx = ops.dry_run.transaction(con.client())?;
+
+    // Report references to purged workstations on the delete, not
+    // on the final commit.
+    tx.execute("SET CONSTRAINTS ALL IMMEDIATE", &[])
+        .map_err(|e| db_err("Cannot set constraints", e))?;
+
+    let mut orgs = HashMap::new();
+    let mut applied = 0;
+
+    for change in &changes {
+        let result = match ACTIONS.contains(&change.action.as_str()) {
+            true => {
+                let mut sp = tx
+                    .savepoint("workstation")
+                    .map_err(|e| db_err("Cannot create savepoint", e))?;
+
+                let result = apply(&mut sp, ops, &mut orgs, change);
+
+                match result {
+                    Ok(_) => sp
+                        .commit()
+                        .map_err(|e| db_err("Cannot release savepoint", e))?,
+                    Err(_) => sp
+                        .rollback()
+                        .map_err(|e| db_err("Cannot roll back savepoint", e))?,
+                }
+
+                result
+            }
+            false => Err(format!("Unknown action: {}", change.action)),
+        };
+
+        let (id, result) = match result {
+            Ok((id, r)) => {
+                if r != "Already registered" {
+                    applied += 1;
+                }
+                (id, r)
+            }
+            Err(e) => {
+                warn!("Line {}: {e}", change.line);
+                (None, e)
+            }
+        };
+
+        writer.write_row(&[
+            Cell::from(change.line),
+            Cell::from(&change.action),
+            Cell::from(&change.name),
+            Cell::from(change.org_unit.as_deref()),
+            Cell::from(change.new_name.as_deref()),
+            Cell::from(id),
+            Cell::from(result),
+        ])?;
+    }
+
+    writer.finish()?;
+
+    ops.dry_run.finish(tx)?;
+
+    audit::count("changes", applied as u64);
+
+    info!(
+        "{} {applied} of {} workstation changes",
+        ops.dry_run.past("Applied"),
+        changes.len()
+    );
+
+    con.disconnect();
+
+    Ok(())
+}
+
+fn export(con: &mut DatabaseConnection, ops: &ManageOptions) -> Result<(), String> {
+    con.connect()?;
+
+    let org = match ops.org_unit.as_deref() {
+        Some(s) => {
+            let sql = "SELECT id FROM actor.org_unit WHERE shortname = $1";
+            match con.client().query_opt(sql, &[&s]) {
+                Ok(Some(row)) => Some(row.get::<_, i32>("id")),
+                Ok(None) => return Err(format!("No such org unit: {s}")),
+                Err(e) => return Err(db_err("Error looking up org unit", e)),
+            }
+        }
+        None => None,
+    };
+
+    let sql = r#"
+        SELECT aw.name, aou.shortname
+        FROM actor.workstation aw
+            JOIN actor.org_unit aou ON aou.id = aw.owning_lib
+        WHERE ($1::INT IS NULL OR aw.owning_lib IN (
+            SELECT id FROM actor.org_unit_descendants($1::INT)))
+        ORDER BY aou.shortname, aw.name
+    "#;
+
+    let rows = match con.client().query(sql, &[&org]) {
+        Ok(r) => r,
+        Err(e) => return Err(db_err("Error querying workstations", e)),
+    };
+
+    let mut writer = TableWriter::for_path(ops.report_file.as_deref(), Format::Csv)?;
+    writer.write_header(&["action", "name", "org_unit", "new_name"])?;
+
+    for row in &rows {
+        writer.write_row(&[
+            Cell::from("purge"),
+            Cell::from(row.get::<_, String>("name")),
+            Cell::from(row.get::<_, String>("shortname")),
+            Cell::Empty,
+        ])?;
+    }
+
+    writer.finish()?;
+
+    info!("Exported {} workstations", rows.len());
+
+    con.disconnect();
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, mut connection) = read_options();
+
+    let result = match options.import_file {
+        Some(ref path) => import(&mut connection, &options, path),
+        None => export(&mut connection, &options),
+    };
+
+    audit::finish(result)
+}
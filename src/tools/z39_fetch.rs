@@ -0,0 +1,345 @@
+use crate::audit;
+use crate::cli;
+use crate::db::DatabaseConnection;
+use crate::dryrun::DryRun;
+use crate::z3950::{Connection, Target};
+use log::{debug, error, info};
+use marcutil::Record;
+use std::io::prelude::*;
+use std::time::Duration;
+use std::{fs, io, process};
+
+const XML_COLLECTION_HEADER: &str = r#"<collection xmlns="http://www.loc.gov/MARC21/slim">"#;
+const XML_COLLECTION_FOOTER: &str = "</collection>";
+
+struct FetchOptions {
+    target: Target,
+    id_file: String,
+    use_attribute: i64,
+    id_type: String,
+    max_hits: i64,
+    to_xml: bool,
+    out_file: Option<String>,
+    queue: Option<i64>,
+    dry_run: DryRun,
+}
+
+fn read_options() -> (FetchOptions, DatabaseConnection) {
+    let mut opts = cli::database_options();
+
+    opts.optopt("", "target", "Z39.50 Target", "HOST:PORT/DATABASE");
+    opts.optopt("", "z-user", "Z39.50 User", "USER");
+    opts.optopt("", "z-password", "Z39.50 Password", "PASSWORD");
+    opts.optopt("", "id-file", "File of Identifiers", "ID_FILE");
+    opts.optopt("", "id-type", "Identifier Type", "isbn|issn|oclc|lccn");
+    opts.optopt("", "use-attribute", "Bib-1 Use Attribute", "USE_ATTR");
+    opts.optopt("", "max-hits", "Max Records per Identifier", "MAX_HITS");
+    opts.optopt("", "timeout", "Network Timeout Seconds", "TIMEOUT");
+    opts.optopt("", "out-file", "Output File", "OUTPUT_FILE");
+    opts.optopt("", "queue", "Vandelay Bib Queue ID", "QUEUE_ID");
+    opts.optflag("", "to-xml", "Write MARC XML");
+
+    cli::append_dry_run(&mut opts);
+    cli::append_audit(&mut opts);
+
+    let params = cli::parse_or_exit(&opts, print_help);
+
+    let target = match params.opt_str("target") {
+        Some(t) => t,
+        None => {
+            eprintln!("--target is required");
+            process::exit(2);
+        }
+    };
+
+    let id_file = match params.opt_str("id-file") {
+        Some(f) => f,
+        None => {
+            eprintln!("--id-file is required");
+            process::exit(2);
+        }
+    };
+
+    let mut target = match Target::parse(&target) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(2);
+        }
+    };
+
+    target.user = params.opt_str("z-user");
+    target.password = params.opt_str("z-password");
+    target.timeout = Duration::from_secs(params.opt_get_default("timeout", 30).unwrap());
+
+    let id_type = params
+        .opt_get_default("id-type", "isbn".to_string())
+        .unwrap();
+
+    let use_attribute = match params.opt_get::<i64>("use-attribute").unwrap() {
+        Some(a) => a,
+        None => match id_type.as_str() {
+            "isbn" => 7,
+            "issn" => 8,
+            "lccn" => 9,
+            "oclc" => 1211,
+            _ => {
+                eprintln!("Unsupported --id-type: {id_type}");
+                process::exit(2);
+            }
+        },
+    };
+
+    let connection = DatabaseConnection::new_from_options(&params);
+
+    (
+        FetchOptions {
+            target,
+            id_file,
+            id_type,
+            use_attribute,
+            max_hits: params.opt_get_default("max-hits", 1).unwrap(),
+            to_xml: params.opt_present("to-xml"),
+            out_file: params.opt_str("out-file"),
+            queue: params.opt_get("queue").unwrap(),
+            dry_run: DryRun::from_params(&params),
+        },
+        connection,
+    )
+}
+
+fn print_help() {
+    println!(
+        r#"
+
+Synopsis
+
+    cargo run --bin z39-fetch -- --target z3950.loc.gov:7090/Voyager \
+        --id-file /tmp/isbns.txt --out-file /tmp/records.mrc
+
+Options
+
+    --target
+        Z39.50 target as HOST:PORT/DATABASE.  Port defaults to 210.
+
+    --z-user
+    --z-password
+        Credentials for targets which require authentication.
+
+    --id-file
+        File containing one identifier per line.
+
+    --id-type
+        One of isbn (default), issn, oclc, or lccn.
+
+    --use-attribute
+        Override the Bib-1 use attribute implied by --id-type.
+
+    --max-hits
+        Maximum number of records to retrieve per identifier.
+        Defaults to 1.
+
+    --timeout
+        Network read/write timeout in seconds.  Defaults to 30.
+
+    --out-file
+        Write records to this file.  Otherwise, writes to STDOUT
+        unless --queue is used.
+
+    --to-xml
+        Write MARC XML instead of binary MARC.
+
+    --queue
+        Add retrieved records to this Vandelay bib queue instead of
+        writing them to a file.
+
+    --dry-run
+        With --queue, add the records, then roll them back, reporting
+        what a real run would do.  The run holds one transaction
+        throughout.  Without --queue, nothing is written.
+
+{}
+    --db-host
+    --db-port
+    --db-user
+    --db-name
+        Database connection options, used with --queue.
+
+{}
+    "#,
+        cli::AUDIT_HELP,
+        cli::COMMON_HELP
+    );
+}
+
+/// Remove punctuation, qualifiers, and prefixes from identifiers.
+fn clean_identifier(id_type: &str, value: &str) -> Option<String> {
+    let value = value.trim();
+
+    let cleaned: String = match id_type {
+        "isbn" | "issn" => value
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+            .collect(),
+        "oclc" => value.chars().filter(|c| c.is_ascii_digit()).collect(),
+        _ => value.to_string(),
+    };
+
+    match cleaned.is_empty() {
+        true => None,
+        false => Some(cleaned),
+    }
+}
+
+enum Destination {
+    Writer(Box<dyn Write>),
+    Queue(DatabaseConnection, i64),
+}
+
+fn store_record(dest: &mut Destination, bytes: &[u8], to_xml: bool) -> Result<(), String> {
+    match dest {
+        Destination::Writer(ref mut w) if !to_xml => {
+            if let Err(e) = w.write_all(bytes) {
+                return Err(format!("Error writing bytes: {e}"));
+            }
+        }
+        Destination::Writer(ref mut w) => {
+            let xml = Record::from_binary(bytes)?.to_xml()?;
+            if let Err(e) = w.write_all(xml.as_bytes()) {
+                return Err(format!("Error writing bytes: {e}"));
+            }
+        }
+        Destination::Queue(ref mut con, queue) => {
+            let xml = Record::from_binary(bytes)?.to_xml()?;
+            let sql = "INSERT INTO vandelay.queued_bib_record (queue, marc) VALUES ($1, $2)";
+            if let Err(e) = con.client().execute(sql, &[queue, &xml]) {
+                return Err(format!("Error adding record to queue: {e}"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn fetch(ops: &FetchOptions, dest: &mut Destination) -> Result<(), String> {
+    let ids = match fs::read_to_string(&ops.id_file) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Cannot read {}: {e}", ops.id_file)),
+    };
+
+    let mut client: Option<Connection> = None;
+    let mut found = 0;
+    let mut missing = 0;
+
+    for line in ids.lines() {
+        let term = match clean_identifier(&ops.id_type, line) {
+            Some(t) => t,
+            None => continue,
+        };
+
+        if client.is_none() {
+            client = Some(Connection::connect(&ops.target)?);
+        }
+
+        let z = client.as_mut().unwrap();
+
+        let hits = match z.search(ops.use_attribute, &term) {
+            Ok(h) => h,
+            Err(e) => {
+                // Reconnect for the next search in case we lost sync.
+                error!("{e}");
+                client = None;
+                continue;
+            }
+        };
+
+        debug!("{term} returned {hits} hit(s)");
+
+        if hits == 0 {
+            info!("No records found for {term}");
+            missing += 1;
+            continue;
+        }
+
+        let records = match z.present(1, hits.min(ops.max_hits)) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("{e}");
+                client = None;
+                continue;
+            }
+        };
+
+        for bytes in records {
+            if let Err(e) = store_record(dest, &bytes, ops.to_xml) {
+                error!("Error storing record for {term}: {e}");
+                continue;
+            }
+            found += 1;
+        }
+    }
+
+    audit::count("records", found);
+    audit::count("missing", missing);
+
+    info!("Retrieved {found} record(s); {missing} identifier(s) had no matches");
+
+    Ok(())
+}
+
+fn store_raw(dest: &mut Destination, s: &str) -> Result<(), String> {
+    if let Destination::Writer(ref mut w) = dest {
+        if let Err(e) = w.write_all(s.as_bytes()) {
+            return Err(format!("Error writing bytes: {e}"));
+        }
+    }
+    Ok(())
+}
+
+fn run(options: &FetchOptions, mut connection: DatabaseConnection) -> Result<(), String> {
+    if let Some(queue) = options.queue {
+        connection.connect()?;
+        options.dry_run.begin(connection.client())?;
+
+        let mut dest = Destination::Queue(connection, queue);
+        fetch(options, &mut dest)?;
+
+        if let Destination::Queue(ref mut con, _) = dest {
+            options.dry_run.end(con.client())?;
+        }
+
+        return Ok(());
+    }
+
+    let writer: Box<dyn Write> = match &options.out_file {
+        _ if options.dry_run.enabled() => Box::new(io::sink()),
+        Some(fname) => match fs::File::create(fname) {
+            Ok(f) => Box::new(f),
+            Err(e) => return Err(format!("Cannot create {fname}: {e}")),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let mut dest = Destination::Writer(writer);
+
+    if options.to_xml {
+        store_raw(&mut dest, XML_COLLECTION_HEADER)?;
+    }
+
+    fetch(options, &mut dest)?;
+
+    if options.to_xml {
+        store_raw(&mut dest, XML_COLLECTION_FOOTER)?;
+    }
+
+    Ok(())
+}
+
+pub fn main() -> Result<(), String> {
+    let (options, connection) = read_options();
+
+    audit::finish(run(&options, connection))
+}
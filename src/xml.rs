@@ -0,0 +1,29 @@
+//! XML output helpers.
+
+/// Escape text for inclusion in XML element content or attribute values.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Remove any leading XML declaration so a stored document can be
+/// embedded within another.
+pub fn strip_declaration(xml: &str) -> &str {
+    let trimmed = xml.trim_start();
+    if trimmed.starts_with("<?xml") {
+        if let Some(end) = trimmed.find("?>") {
+            return trimmed[end + 2..].trim_start();
+        }
+    }
+    trimmed
+}
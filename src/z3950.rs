@@ -0,0 +1,802 @@
+//! Z39.50 client for remote bib targets.
+//!
+//! Targets are configured by name in a JSON file, or given inline as
+//! HOST:PORT/DATABASE.  A Pool keeps initialized connections per target
+//! for reuse, and runs searches on worker threads so callers can query
+//! several targets at once.
+//!
+//! ```text
+//! {
+//!     "targets": [
+//!         {"name": "loc", "host": "z3950.loc.gov", "port": 7090, "database": "Voyager"},
+//!         {"name": "oclc", "host": "zcat.oclc.org", "port": 210, "database": "OLUCWorldCat",
+//!             "user": "100000000", "password": "secret", "timeout": 60}
+//!     ]
+//! }
+//! ```
+//!
+//! Only the search and present services are supported, with Bib-1
+//! use attribute searches and records in USMARC, UNIMARC or MARCXML.
+use log::{debug, warn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use threadpool::ThreadPool;
+
+pub const DEFAULT_PORT: u16 = 210;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Idle connections kept per target.
+const MAX_IDLE: usize = 4;
+
+/// 1.2.840.10003.3.1
+const OID_BIB1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x13, 0x03, 0x01];
+/// 1.2.840.10003.5.10
+const OID_USMARC: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x13, 0x05, 0x0a];
+/// 1.2.840.10003.5.1
+const OID_UNIMARC: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x13, 0x05, 0x01];
+/// 1.2.840.10003.5.109.10
+const OID_XML: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x13, 0x05, 0x6d, 0x0a];
+
+const RESULT_SET_NAME: &str = "default";
+
+/// Preferred record syntax.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Usmarc,
+    Unimarc,
+    Xml,
+}
+
+impl Syntax {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "usmarc" | "marc21" => Ok(Syntax::Usmarc),
+            "unimarc" => Ok(Syntax::Unimarc),
+            "xml" | "marcxml" => Ok(Syntax::Xml),
+            _ => Err(format!("Unsupported record syntax: {name}")),
+        }
+    }
+
+    fn oid(&self) -> &'static [u8] {
+        match self {
+            Syntax::Usmarc => OID_USMARC,
+            Syntax::Unimarc => OID_UNIMARC,
+            Syntax::Xml => OID_XML,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub database: String,
+    pub syntax: Syntax,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Network read/write timeout.
+    pub timeout: Duration,
+}
+
+impl Target {
+    /// HOST:PORT/DATABASE.  The port defaults to 210 and the database
+    /// to Default.  The target is named for the full value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (hostport, database) = match value.split_once('/') {
+            Some((h, d)) => (h, d),
+            None => (value, "Default"),
+        };
+
+        let (host, port) = match hostport.split_once(':') {
+            Some((h, p)) => match p.parse::<u16>() {
+                Ok(p) => (h, p),
+                Err(_) => return Err(format!("Invalid Z39.50 target port: {value}")),
+            },
+            None => (hostport, DEFAULT_PORT),
+        };
+
+        if host.is_empty() {
+            return Err(format!("Invalid Z39.50 target: {value}"));
+        }
+
+        Ok(Target {
+            name: value.to_string(),
+            host: host.to_string(),
+            port,
+            database: database.to_string(),
+            syntax: Syntax::Usmarc,
+            user: None,
+            password: None,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// One entry from a targets file.  name and host are required.
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let name = match value["name"].as_str() {
+            Some(n) => n,
+            None => return Err(format!("Z39.50 target requires a name: {value}")),
+        };
+
+        let host = match value["host"].as_str() {
+            Some(h) => h,
+            None => return Err(format!("Z39.50 target {name} requires a host")),
+        };
+
+        let port = match value["port"].as_u64() {
+            Some(p) if p <= u16::MAX as u64 => p as u16,
+            Some(p) => return Err(format!("Invalid port for Z39.50 target {name}: {p}")),
+            None => DEFAULT_PORT,
+        };
+
+        let syntax = match value["syntax"].as_str() {
+            Some(s) => Syntax::parse(s)?,
+            None => Syntax::Usmarc,
+        };
+
+        let timeout = match value["timeout"].as_u64() {
+            Some(t) => Duration::from_secs(t),
+            None => DEFAULT_TIMEOUT,
+        };
+
+        Ok(Target {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+            database: value["database"].as_str().unwrap_or("Default").to_string(),
+            syntax,
+            user: value["user"].as_str().map(|s| s.to_string()),
+            password: value["password"].as_str().map(|s| s.to_string()),
+            timeout,
+        })
+    }
+}
+
+/// Targets from a JSON file with a "targets" list.
+pub fn read_targets(path: &str) -> Result<Vec<Target>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Cannot read {path}: {e}")),
+    };
+
+    let config: Value = match serde_json::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Cannot parse {path}: {e}")),
+    };
+
+    let list = match config["targets"].as_array() {
+        Some(l) => l,
+        None => return Err(format!("{path} has no targets list")),
+    };
+
+    list.iter().map(Target::from_json).collect()
+}
+
+// BER encoding ----------------------------------------------------------
+
+const CLASS_UNIVERSAL: u8 = 0x00;
+const CLASS_CONTEXT: u8 = 0x80;
+
+fn encode_tag(class: u8, constructed: bool, tag: u32) -> Vec<u8> {
+    let first = class | if constructed { 0x20 } else { 0x00 };
+
+    if tag < 31 {
+        return vec![first | tag as u8];
+    }
+
+    let mut bytes = vec![first | 0x1f];
+    let mut digits = Vec::new();
+    let mut t = tag;
+
+    while t > 0 {
+        digits.push((t & 0x7f) as u8);
+        t >>= 7;
+    }
+
+    for (i, d) in digits.iter().enumerate().rev() {
+        bytes.push(if i > 0 { d | 0x80 } else { *d });
+    }
+
+    bytes
+}
+
+fn encode_length(len: usize) -> Vec<u8> {
+    if len < 128 {
+        return vec![len as u8];
+    }
+
+    let bytes: Vec<u8> = len
+        .to_be_bytes()
+        .iter()
+        .skip_while(|b| **b == 0)
+        .copied()
+        .collect();
+
+    let mut out = vec![0x80 | bytes.len() as u8];
+    out.extend(bytes);
+    out
+}
+
+fn tlv(class: u8, constructed: bool, tag: u32, content: &[u8]) -> Vec<u8> {
+    let mut out = encode_tag(class, constructed, tag);
+    out.extend(encode_length(content.len()));
+    out.extend_from_slice(content);
+    out
+}
+
+/// Constructed context-specific element.
+fn ctx_seq(tag: u32, parts: &[Vec<u8>]) -> Vec<u8> {
+    tlv(CLASS_CONTEXT, true, tag, &parts.concat())
+}
+
+fn ctx_bytes(tag: u32, content: &[u8]) -> Vec<u8> {
+    tlv(CLASS_CONTEXT, false, tag, content)
+}
+
+fn ctx_str(tag: u32, s: &str) -> Vec<u8> {
+    ctx_bytes(tag, s.as_bytes())
+}
+
+fn ctx_bool(tag: u32, b: bool) -> Vec<u8> {
+    ctx_bytes(tag, &[if b { 0xff } else { 0x00 }])
+}
+
+fn ctx_int(tag: u32, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+
+    // Minimal two's complement encoding.
+    while start < 7
+        && ((bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+
+    ctx_bytes(tag, &bytes[start..])
+}
+
+fn oid(value: &[u8]) -> Vec<u8> {
+    tlv(CLASS_UNIVERSAL, false, 6, value)
+}
+
+// BER decoding ----------------------------------------------------------
+
+struct Ber {
+    class: u8,
+    tag: u32,
+    content: Vec<u8>,
+}
+
+impl Ber {
+    fn is(&self, class: u8, tag: u32) -> bool {
+        self.class == class && self.tag == tag
+    }
+
+    /// Parse our content as a series of BER elements.
+    fn children(&self) -> Result<Vec<Ber>, String> {
+        let mut children = Vec::new();
+        let mut reader = io::Cursor::new(&self.content);
+
+        while (reader.position() as usize) < self.content.len() {
+            children.push(read_ber(&mut reader)?);
+        }
+
+        Ok(children)
+    }
+
+    fn child(&self, class: u8, tag: u32) -> Result<Option<Ber>, String> {
+        Ok(self.children()?.into_iter().find(|c| c.is(class, tag)))
+    }
+
+    fn as_int(&self) -> i64 {
+        let mut value: i64 = match self.content.first() {
+            Some(b) if b & 0x80 != 0 => -1,
+            _ => 0,
+        };
+
+        for b in &self.content {
+            value = (value << 8) | *b as i64;
+        }
+
+        value
+    }
+
+    fn as_bool(&self) -> bool {
+        self.content.iter().any(|b| *b != 0)
+    }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8, String> {
+    let mut buf = [0u8; 1];
+    match reader.read_exact(&mut buf) {
+        Ok(_) => Ok(buf[0]),
+        Err(e) => Err(format!("Error reading from target: {e}")),
+    }
+}
+
+/// Read a single BER element.  Only definite lengths are supported.
+fn read_ber<R: Read>(reader: &mut R) -> Result<Ber, String> {
+    let first = read_byte(reader)?;
+    let class = first & 0xc0;
+    let mut tag = (first & 0x1f) as u32;
+
+    if tag == 0x1f {
+        tag = 0;
+        loop {
+            let b = read_byte(reader)?;
+            tag = (tag << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut len = read_byte(reader)? as usize;
+
+    if len == 0x80 {
+        return Err("Indefinite BER lengths are not supported".to_string());
+    }
+
+    if len & 0x80 != 0 {
+        let count = len & 0x7f;
+        len = 0;
+        for _ in 0..count {
+            len = (len << 8) | read_byte(reader)? as usize;
+        }
+    }
+
+    let mut content = vec![0u8; len];
+    if let Err(e) = reader.read_exact(&mut content) {
+        return Err(format!("Error reading from target: {e}"));
+    }
+
+    Ok(Ber {
+        class,
+        tag,
+        content,
+    })
+}
+
+// Z39.50 ----------------------------------------------------------------
+
+/// An initialized session with one target.
+pub struct Connection {
+    target: Target,
+    stream: TcpStream,
+}
+
+impl Connection {
+    pub fn connect(target: &Target) -> Result<Self, String> {
+        let stream = match TcpStream::connect((target.host.as_str(), target.port)) {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(format!(
+                    "Cannot connect to {}:{}: {e}",
+                    target.host, target.port
+                ))
+            }
+        };
+
+        stream.set_read_timeout(Some(target.timeout)).ok();
+        stream.set_write_timeout(Some(target.timeout)).ok();
+
+        let mut con = Connection {
+            target: target.clone(),
+            stream,
+        };
+
+        con.init()?;
+
+        debug!("Connected to Z39.50 target {}", target.name);
+
+        Ok(con)
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn send(&mut self, pdu: &[u8]) -> Result<Ber, String> {
+        if let Err(e) = self.stream.write_all(pdu) {
+            return Err(format!("Error writing to target: {e}"));
+        }
+        read_ber(&mut self.stream)
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        let mut parts = vec![
+            // Protocol versions 1, 2, and 3
+            ctx_bytes(3, &[0x05, 0xe0]),
+            // Options: search, present
+            ctx_bytes(4, &[0x06, 0xc0]),
+            ctx_int(5, 1024 * 1024),
+            ctx_int(6, 1024 * 1024),
+        ];
+
+        if let Some(ref user) = self.target.user {
+            let mut idpass = vec![ctx_str(1, user)];
+            if let Some(ref pass) = self.target.password {
+                idpass.push(ctx_str(2, pass));
+            }
+            let seq = tlv(CLASS_UNIVERSAL, true, 16, &idpass.concat());
+            parts.push(ctx_seq(7, &[seq]));
+        }
+
+        parts.push(ctx_str(110, "egutil"));
+        parts.push(ctx_str(111, "egutil"));
+        parts.push(ctx_str(112, env!("CARGO_PKG_VERSION")));
+
+        let response = self.send(&ctx_seq(20, &parts))?;
+
+        if !response.is(CLASS_CONTEXT, 21) {
+            return Err(format!("Unexpected init response tag {}", response.tag));
+        }
+
+        match response.child(CLASS_CONTEXT, 12)? {
+            Some(r) if r.as_bool() => Ok(()),
+            _ => Err(format!(
+                "Target {} rejected the init request",
+                self.target.name
+            )),
+        }
+    }
+
+    /// Search for a single term using the provided Bib-1 use attribute,
+    /// replacing the previous result set.
+    ///
+    /// Returns the number of hits.
+    pub fn search(&mut self, use_attr: i64, term: &str) -> Result<i64, String> {
+        let attr = tlv(
+            CLASS_UNIVERSAL,
+            true,
+            16,
+            &[ctx_int(120, 1), ctx_int(121, use_attr)].concat(),
+        );
+
+        let apt = ctx_seq(102, &[ctx_seq(44, &[attr]), ctx_str(45, term)]);
+        let rpn = ctx_seq(1, &[oid(OID_BIB1), ctx_seq(0, &[apt])]);
+
+        let parts = vec![
+            ctx_int(13, 0),
+            ctx_int(14, 1),
+            ctx_int(15, 0),
+            ctx_bool(16, true),
+            ctx_str(17, RESULT_SET_NAME),
+            ctx_seq(18, &[ctx_str(105, &self.target.database)]),
+            ctx_bytes(104, self.target.syntax.oid()),
+            ctx_seq(21, &[rpn]),
+        ];
+
+        let response = self.send(&ctx_seq(22, &parts))?;
+
+        if !response.is(CLASS_CONTEXT, 23) {
+            return Err(format!("Unexpected search response tag {}", response.tag));
+        }
+
+        let status = response.child(CLASS_CONTEXT, 22)?;
+        if !status.map(|s| s.as_bool()).unwrap_or(false) {
+            return Err(format!("Search failed for term {term}"));
+        }
+
+        match response.child(CLASS_CONTEXT, 23)? {
+            Some(count) => Ok(count.as_int()),
+            None => Ok(0),
+        }
+    }
+
+    /// Retrieve up to 'count' records from the result set, starting
+    /// at 'start' (1-based), as raw bytes in the target's syntax.
+    pub fn present(&mut self, start: i64, count: i64) -> Result<Vec<Vec<u8>>, String> {
+        let parts = vec![
+            ctx_str(31, RESULT_SET_NAME),
+            ctx_int(30, start),
+            ctx_int(29, count),
+            ctx_seq(19, &[ctx_str(0, "F")]),
+            ctx_bytes(104, self.target.syntax.oid()),
+        ];
+
+        let response = self.send(&ctx_seq(24, &parts))?;
+
+        if !response.is(CLASS_CONTEXT, 25) {
+            return Err(format!("Unexpected present response tag {}", response.tag));
+        }
+
+        if response.child(CLASS_CONTEXT, 130)?.is_some() {
+            return Err("Target returned a diagnostic for present request".to_string());
+        }
+
+        let mut records = Vec::new();
+
+        let list = match response.child(CLASS_CONTEXT, 28)? {
+            Some(l) => l,
+            None => return Ok(records),
+        };
+
+        // NamePlusRecord -> record [1] -> retrievalRecord [1] EXTERNAL
+        // -> octet-aligned [1]
+        for npr in list.children()? {
+            let record = match npr.child(CLASS_CONTEXT, 1)? {
+                Some(r) => r,
+                None => continue,
+            };
+
+            let external = match record.child(CLASS_CONTEXT, 1)? {
+                Some(e) => e,
+                None => {
+                    warn!("Target returned a surrogate diagnostic for a record");
+                    continue;
+                }
+            };
+
+            if let Some(octets) = external.child(CLASS_CONTEXT, 1)? {
+                records.push(octets.content);
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+// Pooling ---------------------------------------------------------------
+
+/// Records found by a search.
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub target: String,
+    pub term: String,
+    /// Total hits, which may exceed the records retrieved.
+    pub hits: i64,
+    pub records: Vec<Vec<u8>>,
+}
+
+/// The result of a search running on a worker thread.
+pub struct Pending<T> {
+    receiver: mpsc::Receiver<Result<T, String>>,
+}
+
+impl<T> Pending<T> {
+    /// Block until the result is ready.
+    pub fn wait(self) -> Result<T, String> {
+        match self.receiver.recv() {
+            Ok(r) => r,
+            Err(_) => Err("Z39.50 worker thread exited".to_string()),
+        }
+    }
+
+    /// The result, if ready.
+    pub fn try_wait(&self) -> Option<Result<T, String>> {
+        match self.receiver.try_recv() {
+            Ok(r) => Some(r),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Some(Err("Z39.50 worker thread exited".to_string()))
+            }
+        }
+    }
+}
+
+struct PoolInner {
+    targets: HashMap<String, Target>,
+    idle: Mutex<HashMap<String, Vec<Connection>>>,
+}
+
+/// Connections to configured targets.  Clones share connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+    workers: ThreadPool,
+}
+
+impl Pool {
+    /// Run up to max_threads searches at once.
+    pub fn new(targets: Vec<Target>, max_threads: usize) -> Self {
+        let targets = targets
+            .into_iter()
+            .map(|t| (t.name.to_string(), t))
+            .collect();
+
+        Pool {
+            inner: Arc::new(PoolInner {
+                targets,
+                idle: Mutex::new(HashMap::new()),
+            }),
+            workers: ThreadPool::new(max_threads.max(1)),
+        }
+    }
+
+    /// Configured target names, sorted.
+    pub fn target_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.inner.targets.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    pub fn target(&self, name: &str) -> Option<&Target> {
+        self.inner.targets.get(name)
+    }
+
+    /// An idle connection to the target, or a new one.  The connection
+    /// returns to the pool when dropped.
+    pub fn get(&self, name: &str) -> Result<PooledConnection, String> {
+        let idle = self
+            .inner
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(name)
+            .and_then(|list| list.pop());
+
+        if let Some(con) = idle {
+            return Ok(PooledConnection {
+                con: Some(con),
+                pool: self.inner.clone(),
+                reused: true,
+            });
+        }
+
+        self.connect(name)
+    }
+
+    fn connect(&self, name: &str) -> Result<PooledConnection, String> {
+        let target = match self.inner.targets.get(name) {
+            Some(t) => t,
+            None => return Err(format!("No such Z39.50 target: {name}")),
+        };
+
+        Ok(PooledConnection {
+            con: Some(Connection::connect(target)?),
+            pool: self.inner.clone(),
+            reused: false,
+        })
+    }
+
+    /// Search the target and present up to max_records of the hits.
+    ///
+    /// An idle connection the target has since closed is replaced and
+    /// the search retried.  Connections which fail are dropped rather
+    /// than returned to the pool, since they may be out of sync.
+    pub fn search(
+        &self,
+        name: &str,
+        use_attr: i64,
+        term: &str,
+        max_records: i64,
+    ) -> Result<SearchResult, String> {
+        let mut con = self.get(name)?;
+
+        let result = match search_present(&mut con, use_attr, term, max_records) {
+            Err(e) if con.reused => {
+                debug!("Reconnecting to Z39.50 target {name}: {e}");
+                con.discard();
+                con = self.connect(name)?;
+                search_present(&mut con, use_attr, term, max_records)
+            }
+            r => r,
+        };
+
+        match result {
+            Ok((hits, records)) => Ok(SearchResult {
+                target: name.to_string(),
+                term: term.to_string(),
+                hits,
+                records,
+            }),
+            Err(e) => {
+                con.discard();
+                Err(e)
+            }
+        }
+    }
+
+    /// Like search, but run on a worker thread.
+    pub fn search_async(
+        &self,
+        name: &str,
+        use_attr: i64,
+        term: &str,
+        max_records: i64,
+    ) -> Pending<SearchResult> {
+        let (sender, receiver) = mpsc::channel();
+
+        let pool = self.clone();
+        let name = name.to_string();
+        let term = term.to_string();
+
+        self.workers.execute(move || {
+            let result = pool.search(&name, use_attr, &term, max_records);
+            // The caller may have stopped waiting.
+            sender.send(result).ok();
+        });
+
+        Pending { receiver }
+    }
+
+    /// Search every configured target for the term at once.  Results
+    /// are in target name order.
+    pub fn search_all(
+        &self,
+        use_attr: i64,
+        term: &str,
+        max_records: i64,
+    ) -> Vec<Result<SearchResult, String>> {
+        let pending: Vec<Pending<SearchResult>> = self
+            .target_names()
+            .iter()
+            .map(|name| self.search_async(name, use_attr, term, max_records))
+            .collect();
+
+        pending.into_iter().map(|p| p.wait()).collect()
+    }
+}
+
+fn search_present(
+    con: &mut Connection,
+    use_attr: i64,
+    term: &str,
+    max_records: i64,
+) -> Result<(i64, Vec<Vec<u8>>), String> {
+    let hits = con.search(use_attr, term)?;
+
+    let records = match hits.min(max_records) {
+        0 => Vec::new(),
+        count => con.present(1, count)?,
+    };
+
+    Ok((hits, records))
+}
+
+/// A connection borrowed from a Pool.
+pub struct PooledConnection {
+    con: Option<Connection>,
+    pool: Arc<PoolInner>,
+    /// True if the connection was idle in the pool, where the target
+    /// may have timed it out.
+    reused: bool,
+}
+
+impl PooledConnection {
+    /// Close the connection instead of returning it to the pool.
+    pub fn discard(&mut self) {
+        self.con = None;
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        // Only discard() clears the connection, after which callers
+        // replace or drop us.
+        self.con.as_ref().expect("Connection discarded")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.con.as_mut().expect("Connection discarded")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let con = match self.con.take() {
+            Some(c) => c,
+            None => return,
+        };
+
+        let mut idle = self.pool.idle.lock().unwrap();
+        let list = idle.entry(con.target.name.to_string()).or_default();
+
+        if list.len() < MAX_IDLE {
+            list.push(con);
+        }
+    }
+}